@@ -5,18 +5,28 @@
 #[macro_use]
 extern crate log;
 
+use futures::future;
+use futures::pin_mut;
 use libc::gid_t;
+use log::Level;
 use nix::unistd::Gid;
 use simplelog::{Config as LogConfig, LevelFilter, TermLogger, TerminalMode};
 use snafu::{ensure, ResultExt};
 use std::env;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::str::FromStr;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 
 use apiserver::serve;
+use apiserver::server::{
+    ApplierConfig, AuditLog, AuthConfig, InputLimits, Listener, RequestLogConfig, ShutdownHook,
+};
 
 const DEFAULT_BIND_PATH: &str = "/run/api.sock";
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
 
 type Result<T> = std::result::Result<T, error::Error>;
 
@@ -34,30 +44,78 @@ mod error {
 
         #[snafu(display("Logger setup error: {}", source))]
         Logger { source: simplelog::TermLogError },
+
+        #[snafu(display("Unable to listen for signals: {}", source))]
+        Signal { source: std::io::Error },
+
+        #[snafu(display("Unable to read API token from {}: {}", path.display(), source))]
+        TokenFile {
+            path: std::path::PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("API token file {} is empty", path.display()))]
+        EmptyTokenFile { path: std::path::PathBuf },
     }
 }
 
 /// Stores user-supplied arguments.
 struct Args {
+    api_token_file: Option<PathBuf>,
+    applier: ApplierConfig,
+    audit_log: Option<AuditLog>,
     datastore_path: String,
+    drain_timeout: Duration,
+    limits: InputLimits,
+    listener: Listener,
     log_level: LevelFilter,
-    socket_gid: Option<Gid>,
-    socket_path: String,
+    request_log: RequestLogConfig,
+    write_gid: Option<gid_t>,
 }
 
 /// Informs the user about proper usage of the program and exits.
 fn usage() -> ! {
     let program_name = env::args().next().unwrap_or_else(|| "program".to_string());
+    let request_log = RequestLogConfig::default();
+    let limits = InputLimits::default();
     eprintln!(
         r"Usage: {}
             --datastore-path PATH
             [ --socket-path PATH ]
             [ --socket-gid GROUP_ID ]
+            [ --listen-address HOST:PORT ]
+            [ --config-applier PATH ]
+            [ --config-applier-arg ARG ... ]
+            [ --config-applier-keys-only ]
+            [ --audit-log PATH ]
             [ --no-color ]
             [ --log-level trace|debug|info|warn|error ]
+            [ --request-log-target TARGET ]
+            [ --request-log-level trace|debug|info|warn|error ]
+            [ --slow-request-ms MILLISECONDS ]
+            [ --max-body-bytes BYTES ]
+            [ --max-keys COUNT ]
+            [ --drain-timeout-secs SECONDS ]
+            [ --write-gid GROUP_ID ]
+            [ --api-token-file PATH ]
 
-    Socket path defaults to {}",
-        program_name, DEFAULT_BIND_PATH
+    Socket path defaults to {}
+    Config applier defaults to {}
+    Requests are logged to target '{}' at level {}, and as a warning if slower than {} ms
+    Settings requests are limited to {} bytes and {} keys
+    On SIGTERM or SIGINT, in-flight requests get {} seconds to finish
+    --listen-address serves over TCP instead of a socket, for development only
+    --write-gid or --api-token-file limit changes to root, processes in the given group, and TCP
+      callers sending the token in the X-Api-Token header; otherwise anyone can make changes",
+        program_name,
+        DEFAULT_BIND_PATH,
+        ApplierConfig::default().program.display(),
+        request_log.target,
+        request_log.level,
+        request_log.slow_threshold.as_millis(),
+        limits.max_body_bytes,
+        limits.max_keys,
+        DEFAULT_DRAIN_TIMEOUT_SECS,
     );
     process::exit(2);
 }
@@ -68,12 +126,33 @@ fn usage_msg<S: AsRef<str>>(msg: S) -> ! {
     usage();
 }
 
+/// Parses the next argument as a count for the given option.
+fn count_arg<I: Iterator<Item = String>>(iter: &mut I, option: &str) -> usize {
+    let count_str = iter
+        .next()
+        .unwrap_or_else(|| usage_msg(format!("Did not give argument to {}", option)));
+    count_str.parse::<usize>().unwrap_or_else(|e| {
+        usage_msg(format!(
+            "Invalid number '{}' given to {}: {}",
+            count_str, option, e
+        ))
+    })
+}
+
 /// Parses user arguments into an Args structure.
 fn parse_args(args: env::Args) -> Args {
+    let mut api_token_file = None;
+    let mut applier = ApplierConfig::default();
+    let mut audit_log = None;
     let mut datastore_path = None;
+    let mut drain_timeout = Duration::from_secs(DEFAULT_DRAIN_TIMEOUT_SECS);
+    let mut listen_address = None;
     let mut log_level = None;
+    let mut request_log = RequestLogConfig::default();
+    let mut limits = InputLimits::default();
     let mut socket_gid = None;
     let mut socket_path = None;
+    let mut write_gid = None;
 
     let mut iter = args.skip(1);
     while let Some(arg) = iter.next() {
@@ -94,6 +173,43 @@ fn parse_args(args: env::Args) -> Args {
                 }));
             }
 
+            "--request-log-target" => {
+                request_log.target = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --request-log-target"))
+            }
+
+            "--request-log-level" => {
+                let level_str = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --request-log-level"));
+                request_log.level = Level::from_str(&level_str).unwrap_or_else(|_| {
+                    usage_msg(format!("Invalid request log level '{}'", level_str))
+                });
+            }
+
+            "--slow-request-ms" => {
+                let ms_str = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --slow-request-ms"));
+                let ms = ms_str.parse::<u64>().unwrap_or_else(|e| {
+                    usage_msg(format!(
+                        "Invalid milliseconds '{}' given to --slow-request-ms: {}",
+                        ms_str, e
+                    ))
+                });
+                request_log.slow_threshold = Duration::from_millis(ms);
+            }
+
+            "--max-body-bytes" => limits.max_body_bytes = count_arg(&mut iter, "--max-body-bytes"),
+
+            "--max-keys" => limits.max_keys = count_arg(&mut iter, "--max-keys"),
+
+            "--drain-timeout-secs" => {
+                let secs = count_arg(&mut iter, "--drain-timeout-secs");
+                drain_timeout = Duration::from_secs(secs as u64);
+            }
+
             "--socket-path" => {
                 socket_path = Some(
                     iter.next()
@@ -114,15 +230,86 @@ fn parse_args(args: env::Args) -> Args {
                 socket_gid = Some(Gid::from_raw(gid));
             }
 
+            "--write-gid" => {
+                let gid_str = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --write-gid"));
+                write_gid = Some(gid_str.parse::<gid_t>().unwrap_or_else(|e| {
+                    usage_msg(format!(
+                        "Invalid group ID '{}' given to --write-gid: {}",
+                        gid_str, e
+                    ))
+                }));
+            }
+
+            "--api-token-file" => {
+                api_token_file = Some(
+                    iter.next()
+                        .unwrap_or_else(|| usage_msg("Did not give argument to --api-token-file"))
+                        .into(),
+                )
+            }
+
+            "--listen-address" => {
+                listen_address = Some(
+                    iter.next()
+                        .unwrap_or_else(|| usage_msg("Did not give argument to --listen-address")),
+                )
+            }
+
+            "--config-applier" => {
+                applier.program = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --config-applier"))
+                    .into()
+            }
+
+            "--config-applier-arg" => applier.args.push(
+                iter.next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --config-applier-arg")),
+            ),
+
+            "--config-applier-keys-only" => applier.keys_only = true,
+
+            "--audit-log" => {
+                audit_log = Some(AuditLog {
+                    path: iter
+                        .next()
+                        .unwrap_or_else(|| usage_msg("Did not give argument to --audit-log"))
+                        .into(),
+                })
+            }
+
             _ => usage(),
         }
     }
 
+    let listener = match listen_address {
+        Some(address) => {
+            if socket_path.is_some() || socket_gid.is_some() {
+                usage_msg("--listen-address can't be used with --socket-path or --socket-gid");
+            }
+            Listener::Tcp { address }
+        }
+        None => Listener::Socket {
+            path: socket_path
+                .unwrap_or_else(|| DEFAULT_BIND_PATH.to_string())
+                .into(),
+            gid: socket_gid,
+        },
+    };
+
     Args {
-        socket_gid,
+        api_token_file,
+        applier,
+        audit_log,
+        listener,
         datastore_path: datastore_path.unwrap_or_else(|| usage()),
+        drain_timeout,
+        limits,
         log_level: log_level.unwrap_or_else(|| LevelFilter::Info),
-        socket_path: socket_path.unwrap_or_else(|| DEFAULT_BIND_PATH.to_string()),
+        request_log,
+        write_gid,
     }
 }
 
@@ -140,29 +327,75 @@ async fn run() -> Result<()> {
         error::NonexistentDatastore
     );
 
-    // Each request makes its own handle to the datastore; there's no locking or
-    // synchronization yet.  Therefore, only use 1 thread for safety.
+    // Requests are synchronized on the shared datastore, so more threads would be safe, but the
+    // API sees little traffic, and one thread keeps requests strictly in order.
     let threads = 1;
 
     let threads_suffix = match threads {
         n if n > 1 => "s",
         _ => "",
     };
+    let location = match &args.listener {
+        Listener::Socket { path, .. } => path.display().to_string(),
+        Listener::Tcp { address } => address.clone(),
+    };
     info!(
         "Starting server at {} with {} thread{} and datastore at {}",
-        &args.socket_path, threads, threads_suffix, &args.datastore_path,
+        location, threads, threads_suffix, &args.datastore_path,
     );
 
+    let token = match &args.api_token_file {
+        Some(path) => {
+            let token = fs::read_to_string(path)
+                .context(error::TokenFile { path })?
+                .trim()
+                .to_string();
+            // An empty token would let through requests that send an empty token header
+            ensure!(!token.is_empty(), error::EmptyTokenFile { path });
+            Some(token)
+        }
+        None => None,
+    };
+    let auth = if args.write_gid.is_some() || token.is_some() {
+        AuthConfig::writes_restricted(args.write_gid, token)
+    } else {
+        AuthConfig::default()
+    };
+
+    let shutdown = ShutdownHook::new(args.drain_timeout);
+    shutdown_on_signal(shutdown.clone())?;
+
     serve(
-        &args.socket_path,
+        args.listener,
         &args.datastore_path,
         threads,
-        args.socket_gid,
+        args.request_log,
+        args.limits,
+        args.applier,
+        args.audit_log,
+        auth,
+        shutdown,
     )
     .await
     .context(error::Server)
 }
 
+/// Starts a task that shuts the server down gracefully on SIGTERM, which is how the system
+/// stops us, or SIGINT, for interactive use.
+fn shutdown_on_signal(shutdown: ShutdownHook) -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate()).context(error::Signal)?;
+    let mut interrupt = signal(SignalKind::interrupt()).context(error::Signal)?;
+    actix_rt::spawn(async move {
+        let terminated = terminate.recv();
+        let interrupted = interrupt.recv();
+        pin_mut!(terminated, interrupted);
+        future::select(terminated, interrupted).await;
+        info!("Received signal, shutting down");
+        shutdown.shutdown();
+    });
+    Ok(())
+}
+
 // Returning a Result from main makes it print a Debug representation of the error, but with Snafu
 // we have nice Display representations of the error, so we wrap "main" (run) and print any error.
 // https://github.com/shepmaster/snafu/issues/110
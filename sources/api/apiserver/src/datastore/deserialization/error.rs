@@ -11,8 +11,18 @@ pub enum Error {
     #[snafu(display("Error during deserialization: {}", msg))]
     Message { msg: String },
 
-    #[snafu(display("Error deserializing scalar value: {}", source))]
-    DeserializeScalar { source: ScalarError },
+    #[snafu(display(
+        "Error deserializing scalar value of key '{}', '{}': {}",
+        key,
+        value,
+        source
+    ))]
+    DeserializeScalar {
+        key: String,
+        /// The start of the value, shortened to keep errors readable.
+        value: String,
+        source: ScalarError,
+    },
 
     #[snafu(display(
         "Data store deserializer must be used on a struct, or you must give a prefix"
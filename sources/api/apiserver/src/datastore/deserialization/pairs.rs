@@ -39,7 +39,10 @@ use std::collections::{HashMap, HashSet, VecDeque};
 use std::hash::Hash;
 
 use super::{error, Error, Result};
-use crate::datastore::{deserializer_for_scalar, Key, KeyType, ScalarDeserializer};
+use crate::datastore::{deserializer_for_scalar, Key, KeyType};
+
+/// The number of characters of a value to include in deserialization errors.
+const VALUE_SNIPPET_CHARS: usize = 64;
 
 /// This is the primary interface to deserialization.  We turn the input map into the requested
 /// output type, assuming all non-Option fields are provided, etc.
@@ -107,11 +110,23 @@ where
 /// key name and a deserializer for it on each iteration, i.e. for each field.  Based on whether
 /// the key name has a dot, we know if we need to recurse again or just deserialize a final value,
 /// which we represent as the two arms of the enum.
+///
+/// Scalars keep their full key and value so errors can say which data failed.
 enum ValueDeserializer<'de, K, S, BH> {
-    Scalar(ScalarDeserializer<'de>),
+    Scalar { key: Key, value: &'de str },
     Compound(CompoundDeserializer<'de, K, S, BH>),
 }
 
+/// Returns the start of the given value for use in error messages.
+fn snippet(value: &str) -> String {
+    let mut chars = value.chars();
+    let mut snippet: String = chars.by_ref().take(VALUE_SNIPPET_CHARS).collect();
+    if chars.next().is_some() {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
 impl<'de, K, S, BH> serde::de::Deserializer<'de> for ValueDeserializer<'de, K, S, BH>
 where
     K: Borrow<Key> + Eq + Hash,
@@ -127,11 +142,14 @@ where
         V: Visitor<'de>,
     {
         match self {
-            ValueDeserializer::Scalar(mut scalar_deserializer) => {
+            ValueDeserializer::Scalar { key, value } => {
                 trace!("Handing off to scalar deserializer for deserialize_any");
-                scalar_deserializer
+                deserializer_for_scalar(value)
                     .deserialize_any(visitor)
-                    .context(error::DeserializeScalar)
+                    .context(error::DeserializeScalar {
+                        key: key.name(),
+                        value: snippet(value),
+                    })
             }
             ValueDeserializer::Compound(compound_deserializer) => {
                 compound_deserializer.deserialize_map(visitor)
@@ -146,11 +164,14 @@ where
         V: Visitor<'de>,
     {
         match self {
-            ValueDeserializer::Scalar(mut scalar_deserializer) => {
+            ValueDeserializer::Scalar { key, value } => {
                 trace!("Handing off to scalar deserializer for deserialize_option");
-                scalar_deserializer
+                deserializer_for_scalar(value)
                     .deserialize_option(visitor)
-                    .context(error::DeserializeScalar)
+                    .context(error::DeserializeScalar {
+                        key: key.name(),
+                        value: snippet(value),
+                    })
             }
             ValueDeserializer::Compound(compound_deserializer) => {
                 compound_deserializer.deserialize_option(visitor)
@@ -359,7 +380,10 @@ where
                 let val = self.map.get(&path)?;
                 Some((
                     struct_name,
-                    ValueDeserializer::Scalar(deserializer_for_scalar(val.as_ref())),
+                    ValueDeserializer::Scalar {
+                        key: path,
+                        value: val.as_ref(),
+                    },
                 ))
             }
         })))
@@ -519,4 +543,25 @@ mod test {
         });
         bad.unwrap_err();
     }
+
+    #[test]
+    fn bad_value_error_names_key() {
+        let long = format!("\"{}\"", "x".repeat(100));
+        let bad: Result<A, Error> = from_map(&hashmap! {
+            key!("a.id") => "1".to_string(),
+            key!("a.name") => "\"it's my name\"".to_string(),
+            key!("a.list") => "[1,2, 3, 4]".to_string(),
+            key!("a.map.a") => "\"answer is always map\"".to_string(),
+            key!("a.nested.a") => "\"quite nested\"".to_string(),
+            key!("a.nested.b") => long.clone(),
+        });
+        match bad.unwrap_err() {
+            Error::DeserializeScalar { key, value, .. } => {
+                assert_eq!(key, "a.nested.b");
+                assert!(value.ends_with("..."));
+                assert!(long.starts_with(value.trim_end_matches("...")));
+            }
+            e => panic!("Unexpected error: {}", e),
+        }
+    }
 }
@@ -1,3 +1,4 @@
+use model::key_name;
 use snafu::Snafu;
 use std::io;
 use std::path::PathBuf;
@@ -35,9 +36,25 @@ pub enum Error {
     #[snafu(display("Data store logic error: {}", msg))]
     Internal { msg: String },
 
+    #[snafu(display("Failed to lock data store at {}: {}", path.display(), source))]
+    Lock { path: PathBuf, source: nix::Error },
+
+    #[snafu(display("Failed to set modification time of {}: {}", path.display(), source))]
+    SetMtime { path: PathBuf, source: nix::Error },
+
+    #[snafu(display("Data store at {} was opened read-only", path.display()))]
+    ReadOnly { path: PathBuf },
+
     #[snafu(display("Data store integrity violation at {}: {}", path.display(), msg))]
     Corruption { msg: String, path: PathBuf },
 
+    #[snafu(display(
+        "Data store integrity violation: value of key '{}' at {} doesn't match its checksum",
+        key,
+        path.display()
+    ))]
+    ChecksumMismatch { key: String, path: PathBuf },
+
     #[snafu(display("Error building data store path: {}", source))]
     Path { source: std::path::StripPrefixError },
 
@@ -54,11 +71,52 @@ pub enum Error {
     ))]
     ListedMetaNotPresent { meta_key: String, data_key: String },
 
+    #[snafu(display("Keys not pending in transaction '{}': {}", transaction, keys.join(", ")))]
+    KeysNotPending {
+        transaction: String,
+        keys: Vec<String>,
+    },
+
     #[snafu(display("Key name '{}' has invalid format: {}", name, msg))]
     InvalidKey { name: String, msg: String },
 
     #[snafu(display("Key name beyond maximum length {}: {}", name, max))]
     KeyTooLong { name: String, max: usize },
+
+    #[snafu(display("Key name '{}' has an empty segment", name))]
+    EmptyKeySegment { name: String },
+
+    #[snafu(display(
+        "Key name '{}' has segment '{}' beyond maximum segment length {}",
+        name,
+        segment,
+        max
+    ))]
+    KeySegmentTooLong {
+        name: String,
+        segment: String,
+        max: usize,
+    },
+
+    #[snafu(display(
+        "Metadata key name '{}' cannot contain the key separator, even quoted",
+        name
+    ))]
+    MetadataKeySeparator { name: String },
+}
+
+// Key name errors keep their own variants here, so callers can still tell them apart.
+impl From<key_name::Error> for Error {
+    fn from(e: key_name::Error) -> Self {
+        match e {
+            key_name::Error::InvalidKey { name, msg } => Error::InvalidKey { name, msg },
+            key_name::Error::KeyTooLong { name, max } => Error::KeyTooLong { name, max },
+            key_name::Error::EmptyKeySegment { name } => Error::EmptyKeySegment { name },
+            key_name::Error::KeySegmentTooLong { name, segment, max } => {
+                Error::KeySegmentTooLong { name, segment, max }
+            }
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -3,20 +3,40 @@
 //!
 //! Data is kept in files with paths resembling the keys, e.g. a/b/c for a.b.c, and metadata is
 //! kept in a suffixed file next to the data, e.g. a/b/c.meta for metadata "meta" about a.b.c
-
+//!
+//! Values are written with a trailing newline so the files are easier to read and edit by hand,
+//! and a single trailing newline is removed when reading.
+//!
+//! Each data key's file has a hidden sidecar file holding a SHA-256 checksum of its value, e.g.
+//! a/b/.c.sha256 for a.b.c, so that we can detect values corrupted on disk.  The value and its
+//! checksum are written separately, value first, so a checksum file older than its value means
+//! the value was changed without it, by an interrupted write or by hand, and isn't verified.
+
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::{TimeSpec, TimeValLike};
 use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use ring::digest::{digest, SHA256};
 use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
 use std::path::{self, Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
 use walkdir::{DirEntry, WalkDir};
 
-use super::key::{Key, KeyType};
-use super::{error, Committed, DataStore, Result};
+use super::key::{Key, KeyType, KEY_SEPARATOR};
+use super::{
+    error, hidden_from_listing, Committed, DataStore, Result, SNAPSHOTS_SEGMENT, UNSET_VALUE,
+};
 
 const METADATA_KEY_PREFIX: &str = ".";
 
+// Added to the end of each value we write, and removed (once) from each value we read.
+const VALUE_TERMINATOR: char = '\n';
+
 // This describes the set of characters we encode when making the filesystem path for a given key.
 // Any non-ASCII characters, plus these ones, will be encoded.
 // We start off very strict (anything not alphanumeric) and remove characters we'll allow.
@@ -24,18 +44,198 @@ const METADATA_KEY_PREFIX: &str = ".";
 // allowed in a Key.
 const ENCODE_CHARACTERS: &AsciiSet = &NON_ALPHANUMERIC.remove(b'_').remove(b'-');
 
+// Added to the name of a data key's file, after a leading dot, to make the name of its checksum
+// file.  The leading dot means the checksum file is never mistaken for a key.
+const CHECKSUM_SUFFIX: &str = ".sha256";
+
+// The name of the file at the root of the data store that we lock to coordinate access.
+const LOCK_FILE_NAME: &str = ".lock";
+
+/// Controls whether a FilesystemDataStore takes advisory locks on the data store directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Take a shared lock for reads and an exclusive lock for writes, so separate processes (or
+    /// separate FilesystemDataStore instances) using the same directory don't interfere.
+    Advisory,
+    /// Don't lock; only safe if nothing else uses the data store directory at the same time.
+    Disabled,
+}
+
+/// Controls whether a FilesystemDataStore verifies the checksums of data keys when reading them.
+/// Checksums are always written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumMode {
+    /// Return a ChecksumMismatch error if a value doesn't match its checksum.
+    Verify,
+    /// Don't check values when reading them, for callers that can't afford the extra read and
+    /// hash.  `verify_all` can still be used to check the whole data store.
+    Skip,
+}
+
 #[derive(Debug)]
 pub struct FilesystemDataStore {
     live_path: PathBuf,
     pending_base_path: PathBuf,
+    lock_path: PathBuf,
+    lock_mode: LockMode,
+    checksum_mode: ChecksumMode,
+    // If set, all mutating operations are rejected, and we don't open anything for writing.
+    read_only: bool,
+    // Shared with outstanding LockGuards so they can release the lock when dropped.
+    lock_state: Arc<Mutex<LockState>>,
+}
+
+/// Tracks the advisory lock held by a FilesystemDataStore.  Operations can call one another, so
+/// we count holders and only take the file lock for the first, and release it after the last.
+#[derive(Debug, Default)]
+struct LockState {
+    file: Option<fs::File>,
+    holders: usize,
+    // Whether the file lock we hold is exclusive, rather than shared.
+    exclusive: bool,
+}
+
+/// Releases our hold on the data store lock when dropped.
+struct LockGuard {
+    state: Option<Arc<Mutex<LockState>>>,
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Some(state) = &self.state {
+            let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.holders = state.holders.saturating_sub(1);
+            if state.holders == 0 {
+                // Closing the file releases the flock.
+                state.file = None;
+            }
+        }
+    }
 }
 
 impl FilesystemDataStore {
+    /// Creates a FilesystemDataStore at the given path that uses advisory locking.
     pub fn new<P: AsRef<Path>>(base_path: P) -> FilesystemDataStore {
+        Self::new_with_locking(base_path, LockMode::Advisory)
+    }
+
+    /// Creates a FilesystemDataStore at the given path with the given locking behavior.
+    pub fn new_with_locking<P: AsRef<Path>>(
+        base_path: P,
+        lock_mode: LockMode,
+    ) -> FilesystemDataStore {
         FilesystemDataStore {
             live_path: base_path.as_ref().join("live"),
             pending_base_path: base_path.as_ref().join("pending"),
+            lock_path: base_path.as_ref().join(LOCK_FILE_NAME),
+            lock_mode,
+            checksum_mode: ChecksumMode::Verify,
+            read_only: false,
+            lock_state: Arc::new(Mutex::new(LockState::default())),
+        }
+    }
+
+    /// Creates a FilesystemDataStore at the given path that uses advisory locking, with the given
+    /// checksum verification behavior.
+    pub fn new_with_checksums<P: AsRef<Path>>(
+        base_path: P,
+        checksum_mode: ChecksumMode,
+    ) -> FilesystemDataStore {
+        FilesystemDataStore {
+            checksum_mode,
+            ..Self::new(base_path)
+        }
+    }
+
+    /// Creates a FilesystemDataStore at the given path that can only be read.  Any operation that
+    /// would change the data store returns a ReadOnly error instead, and files (including the lock
+    /// file) are only opened for reading.
+    pub fn open_read_only<P: AsRef<Path>>(base_path: P) -> FilesystemDataStore {
+        FilesystemDataStore {
+            read_only: true,
+            ..Self::new(base_path)
+        }
+    }
+
+    /// Returns an error if this data store was opened read-only.
+    fn check_writable(&self) -> Result<()> {
+        ensure!(
+            !self.read_only,
+            error::ReadOnly {
+                path: self.live_path.parent().unwrap_or(&self.live_path)
+            }
+        );
+        Ok(())
+    }
+
+    /// Takes the advisory lock on the data store, shared for reads or exclusive for writes,
+    /// returning a guard that releases it when dropped.  If we already hold the lock, for example
+    /// because a commit is reading keys, we reuse it.  Asking for an exclusive lock while we only
+    /// hold a shared one is an error; flock can't upgrade without briefly releasing the lock,
+    /// which would let another writer change what the holder of the shared lock is reading.
+    fn lock(&self, exclusive: bool) -> Result<LockGuard> {
+        if self.lock_mode == LockMode::Disabled {
+            return Ok(LockGuard { state: None });
+        }
+
+        let mut state = self
+            .lock_state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if state.holders > 0 {
+            ensure!(
+                !exclusive || state.exclusive,
+                error::Internal {
+                    msg: "exclusive lock requested while holding a shared lock",
+                }
+            );
+        } else {
+            let file = if self.read_only {
+                // We can't create the lock file without writing to the data store.  If it doesn't
+                // exist, no locking user has written to the data store yet, so there's nothing
+                // we could conflict with.
+                match fs::File::open(&self.lock_path) {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        return Ok(LockGuard { state: None });
+                    }
+                    Err(e) => {
+                        return Err(e).context(error::Io {
+                            path: &self.lock_path,
+                        })
+                    }
+                }
+            } else {
+                let dirname = self.lock_path.parent().with_context(|| error::Internal {
+                    msg: format!("Lock path without parent: {}", self.lock_path.display()),
+                })?;
+                fs::create_dir_all(dirname).context(error::Io { path: dirname })?;
+
+                fs::OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .open(&self.lock_path)
+                    .context(error::Io {
+                        path: &self.lock_path,
+                    })?
+            };
+            let arg = if exclusive {
+                FlockArg::LockExclusive
+            } else {
+                FlockArg::LockShared
+            };
+            trace!("Taking {:?} on {}", arg, self.lock_path.display());
+            flock(file.as_raw_fd(), arg).context(error::Lock {
+                path: &self.lock_path,
+            })?;
+            state.file = Some(file);
+            state.exclusive = exclusive;
         }
+        state.holders += 1;
+
+        Ok(LockGuard {
+            state: Some(Arc::clone(&self.lock_state)),
+        })
     }
 
     /// Returns the appropriate filesystem path for pending or live data.
@@ -49,10 +249,23 @@ impl FilesystemDataStore {
         }
     }
 
+    /// Returns the transaction name for pending data, or "live" otherwise, for use in messages.
+    fn transaction_name(&self, committed: &Committed) -> String {
+        match committed {
+            Committed::Pending { tx } => tx.clone(),
+            Committed::Live => "live".to_string(),
+        }
+    }
+
     /// Returns the appropriate path on the filesystem for the given data key.
     fn data_path(&self, key: &Key, committed: &Committed) -> Result<PathBuf> {
         let base_path = self.base_path(committed);
+        Self::data_path_under(&base_path, key)
+    }
 
+    /// Returns the path on the filesystem for the given data key underneath the given base
+    /// path.  This lets callers handling many keys find the base path only once.
+    fn data_path_under(base_path: &Path, key: &Key) -> Result<PathBuf> {
         // Encode key segments so they're filesystem-safe
         let encoded: Vec<_> = key.segments().iter().map(encode_path_component).collect();
         // Join segments with filesystem separator to get path underneath data store
@@ -66,7 +279,7 @@ impl FilesystemDataStore {
 
         // Confirm no path traversal outside of base
         ensure!(
-            path != *base_path && path.starts_with(base_path),
+            path != base_path && path.starts_with(base_path),
             error::PathTraversal { name: key.name() }
         );
 
@@ -118,12 +331,15 @@ impl FilesystemDataStore {
     {
         let path = path.as_ref();
 
-        // Remove the file.  If it doesn't exist, we're still OK.
-        match fs::remove_file(path) {
-            Ok(()) => {}
-            Err(e) => {
-                if e.kind() != io::ErrorKind::NotFound {
-                    return Err(e).context(error::DeleteKey { path });
+        // Remove the file, and its checksum file if it has one.  If they don't exist, we're still
+        // OK.
+        for path in &[path.to_path_buf(), checksum_path(path)?] {
+            match fs::remove_file(path) {
+                Ok(()) => {}
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(e).context(error::DeleteKey { path });
+                    }
                 }
             }
         }
@@ -164,6 +380,127 @@ impl FilesystemDataStore {
                 }
             }
         }
+
+        // If that was the last thing in a transaction, remove the transaction directory so it's
+        // no longer listed.  If anything else is pending, this fails, which is fine.
+        if let Committed::Pending { .. } = committed {
+            let _ = fs::remove_dir(&base);
+        }
+        Ok(())
+    }
+
+    /// Reads the value of a data key, verifying it against its checksum unless checksums are
+    /// being skipped.
+    fn read_data_file(&self, key: &Key, base: &Path, path: &Path) -> Result<Option<String>> {
+        let value = match read_file_bytes(key, base, path)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if self.checksum_mode == ChecksumMode::Verify {
+            verify_checksum(key, base, path, &value)?;
+        }
+        value_from_bytes(key, value).map(Some)
+    }
+
+    /// Writes the value of a data key, followed by its checksum.  If we're interrupted between the
+    /// two, the old checksum file is older than the new value, so it's ignored; see
+    /// verify_checksum.
+    fn write_data_file<S: AsRef<str>>(&self, base: &Path, path: PathBuf, value: S) -> Result<()> {
+        let value = value.as_ref();
+        let checksum_path = checksum_path(&path)?;
+        write_file_mkdir(base, path, value)?;
+        write_file_mkdir(base, checksum_path, checksum(value.as_bytes()))
+    }
+
+    /// Sets the modification time of the given data key's file, so a commit can preserve the time
+    /// a key was written to the transaction.
+    fn set_key_mtime(&self, key: &Key, committed: &Committed, mtime: SystemTime) -> Result<()> {
+        let path = self.data_path(key, committed)?;
+        check_no_symlinks(&self.base_path(committed), &path)?;
+        let since_epoch =
+            mtime
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .with_context(|| error::Internal {
+                    msg: format!("Modification time of key {} before the epoch", key),
+                })?;
+        let time = TimeSpec::nanoseconds(since_epoch.as_nanos() as i64);
+        utimensat(None, &path, &time, &time, UtimensatFlags::NoFollowSymlink)
+            .context(error::SetMtime { path })
+    }
+
+    /// Checks every data key under the given committed state against its checksum, regardless of
+    /// the checksum mode, and returns the keys whose values don't match.  Keys without a current
+    /// checksum file, for example those written before we had checksums, aren't considered
+    /// corrupt.
+    pub fn verify_all(&self, committed: &Committed) -> Result<HashSet<Key>> {
+        let _lock = self.lock(false)?;
+        let base = self.base_path(committed);
+        let mut corrupted = HashSet::new();
+        // Snapshots are only listed on request, but we want to check them too.
+        let mut keys = self.list_populated_keys("", committed)?;
+        keys.extend(self.list_populated_keys(SNAPSHOTS_SEGMENT, committed)?);
+        for key in keys {
+            let path = self.data_path(&key, committed)?;
+            // The key could have been removed by someone not using locking; nothing to check.
+            let value = match read_file_bytes(&key, &base, &path)? {
+                Some(value) => value,
+                None => continue,
+            };
+            match verify_checksum(&key, &base, &path, &value) {
+                Ok(()) => {}
+                Err(error::Error::ChecksumMismatch { .. }) => {
+                    warn!("Checksum mismatch for key {} at {}", key, path.display());
+                    corrupted.insert(key);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(corrupted)
+    }
+
+    /// Removes any empty directories under the base path for the given committed state, for
+    /// example directories of keys that were removed, or of services that no longer exist.  The
+    /// base path itself is never removed.
+    ///
+    /// This is called automatically after commits and batch key removals, but can also be used
+    /// for maintenance.
+    pub fn prune_empty_dirs(&mut self, committed: &Committed) -> Result<()> {
+        self.check_writable()?;
+        let _lock = self.lock(true)?;
+        let base = self.base_path(committed);
+
+        // Visit directory contents before the directory itself, so that by the time we see a
+        // directory, any empty subdirectories have already been removed.  Symlinks aren't
+        // followed, and are never seen as directories.
+        let walker = WalkDir::new(&base).min_depth(1).contents_first(true);
+        trace!("Pruning empty directories under {}", base.display());
+
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    if let Some(io_error) = e.io_error() {
+                        // If the base doesn't exist, there's nothing to prune.
+                        if io_error.kind() == io::ErrorKind::NotFound {
+                            break;
+                        }
+                    }
+                    return Err(e).context(error::ListKeys);
+                }
+            };
+            if !entry.file_type().is_dir() {
+                continue;
+            }
+
+            let path = entry.path();
+            let mut contents = fs::read_dir(path).context(error::Io { path })?;
+            if contents.next().is_none() {
+                trace!("Removing empty directory {}", path.display());
+                fs::remove_dir(path).context(error::Io { path })?;
+            }
+        }
+
         Ok(())
     }
 }
@@ -197,11 +534,63 @@ where
         })
 }
 
+/// Returns the remainder of the given string after the given prefix, if it starts with the
+/// prefix; otherwise returns None.
+fn strip_str_prefix<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    if s.starts_with(prefix) {
+        Some(&s[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+/// Makes sure no component of the given path underneath the given base is a symlink, so a link
+/// planted in the data store can't make us read or write outside of it.  Components that don't
+/// exist yet are fine.
+fn check_no_symlinks(base: &Path, path: &Path) -> Result<()> {
+    let relative = path.strip_prefix(base).context(error::Path)?;
+    let mut current = base.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        match fs::symlink_metadata(&current) {
+            Ok(metadata) => ensure!(
+                !metadata.file_type().is_symlink(),
+                error::Corruption {
+                    msg: "Symlink found in data store",
+                    path: &current,
+                }
+            ),
+            // Nothing below here exists either, so there can't be any links.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).context(error::Io { path: &current }),
+        }
+    }
+    Ok(())
+}
+
 /// Helper for reading a key from the filesystem.  Returns Ok(None) if the file doesn't exist
-/// rather than erroring.
-fn read_file_for_key(key: &Key, path: &Path) -> Result<Option<String>> {
-    match fs::read_to_string(path) {
-        Ok(s) => Ok(Some(s)),
+/// rather than erroring.  Refuses to read through symlinks underneath the given base path.
+///
+/// We write values with a trailing newline, and people editing files by hand often add one, so we
+/// remove a single trailing newline if present.  Any others are part of the value.
+fn read_file_for_key(key: &Key, base: &Path, path: &Path) -> Result<Option<String>> {
+    match read_file_bytes(key, base, path)? {
+        Some(value) => value_from_bytes(key, value).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Reads the raw bytes of a value, without the trailing newline, as described in
+/// read_file_for_key.  This lets us check a value's checksum before trying to interpret it.
+fn read_file_bytes(key: &Key, base: &Path, path: &Path) -> Result<Option<Vec<u8>>> {
+    check_no_symlinks(base, path)?;
+    match fs::read(path) {
+        Ok(mut value) => {
+            if value.last() == Some(&(VALUE_TERMINATOR as u8)) {
+                value.pop();
+            }
+            Ok(Some(value))
+        }
         Err(e) => {
             if e.kind() == io::ErrorKind::NotFound {
                 return Ok(None);
@@ -212,9 +601,60 @@ fn read_file_for_key(key: &Key, path: &Path) -> Result<Option<String>> {
     }
 }
 
+/// Converts the raw bytes of a value to a String, or errors if they're not valid UTF-8.
+fn value_from_bytes(key: &Key, value: Vec<u8>) -> Result<String> {
+    String::from_utf8(value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        .context(error::KeyRead { key: key.name() })
+}
+
+/// Returns the hex-encoded SHA-256 checksum of the given data.
+fn checksum(data: &[u8]) -> String {
+    hex::encode(digest(&SHA256, data))
+}
+
+/// Checks the given value of the key at the given path against its checksum file, returning a
+/// ChecksumMismatch error if they don't match.  If there's no checksum file, the value was written
+/// before we had checksums and can't be checked, so we accept it.  Likewise if the checksum file
+/// is older than the value, meaning the value was written since, by hand or by a write that was
+/// interrupted before it could update the checksum.
+fn verify_checksum(key: &Key, base: &Path, path: &Path, value: &[u8]) -> Result<()> {
+    let checksum_path = checksum_path(path)?;
+    let expected = match read_file_bytes(key, base, &checksum_path)? {
+        Some(expected) => expected,
+        None => {
+            trace!("No checksum for key {} at {}", key, path.display());
+            return Ok(());
+        }
+    };
+    if modified(&checksum_path)? < modified(path)? {
+        trace!("Checksum for key {} older than {}", key, path.display());
+        return Ok(());
+    }
+    ensure!(
+        expected == checksum(value).as_bytes(),
+        error::ChecksumMismatch {
+            key: key.name(),
+            path,
+        }
+    );
+    Ok(())
+}
+
+/// Returns the modification time of the file at the given path, without following symlinks.
+fn modified(path: &Path) -> Result<SystemTime> {
+    fs::symlink_metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .context(error::Io { path })
+}
+
 /// Helper for writing a file that makes the directory tree beforehand, so we can handle
-/// arbitrarily dotted keys without needing to create fixed structure first.
-fn write_file_mkdir<S: AsRef<str>>(path: PathBuf, data: S) -> Result<()> {
+/// arbitrarily dotted keys without needing to create fixed structure first.  Refuses to write
+/// through or over symlinks underneath the given base path.  The data is followed by a newline,
+/// which read_file_for_key removes.
+fn write_file_mkdir<S: AsRef<str>>(base: &Path, path: PathBuf, data: S) -> Result<()> {
+    check_no_symlinks(base, &path)?;
+
     // create key prefix directory if necessary
     let dirname = path.parent().with_context(|| error::Internal {
         msg: format!(
@@ -224,7 +664,46 @@ fn write_file_mkdir<S: AsRef<str>>(path: PathBuf, data: S) -> Result<()> {
     })?;
     fs::create_dir_all(dirname).context(error::Io { path: dirname })?;
 
-    fs::write(&path, data.as_ref().as_bytes()).context(error::Io { path: &path })
+    // Write to a temporary file in the same directory and rename it into place, so a crash or
+    // full disk can't leave a partial value under the real name.  The leading dot means the
+    // temporary file is never mistaken for a key.
+    let tmp_path = temp_path(&path)?;
+    {
+        let mut file = fs::File::create(&tmp_path).context(error::Io { path: &tmp_path })?;
+        let mut data = data.as_ref().to_string();
+        data.push(VALUE_TERMINATOR);
+        file.write_all(data.as_bytes())
+            .context(error::Io { path: &tmp_path })?;
+        file.sync_all().context(error::Io { path: &tmp_path })?;
+    }
+    fs::rename(&tmp_path, &path).context(error::Io { path: &path })?;
+
+    // Sync the directory so the rename itself is durable.
+    fs::File::open(dirname)
+        .and_then(|dir| dir.sync_all())
+        .context(error::Io { path: dirname })
+}
+
+/// Returns the path of the checksum file for the data key file at the given path.
+fn checksum_path(path: &Path) -> Result<PathBuf> {
+    let file_name = path.file_name().with_context(|| error::Internal {
+        msg: format!("Given data path without file name: {}", path.display()),
+    })?;
+    let mut checksum_name = std::ffi::OsString::from(".");
+    checksum_name.push(file_name);
+    checksum_name.push(CHECKSUM_SUFFIX);
+    Ok(path.with_file_name(checksum_name))
+}
+
+/// Returns the path of the temporary file used while atomically writing the given path.
+fn temp_path(path: &Path) -> Result<PathBuf> {
+    let file_name = path.file_name().with_context(|| error::Internal {
+        msg: format!("Given path to write without file name: {}", path.display()),
+    })?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+    Ok(path.with_file_name(tmp_name))
 }
 
 /// KeyPath represents the filesystem path to a data or metadata key, relative to the base path of
@@ -254,6 +733,15 @@ impl KeyPath {
         entry: &DirEntry,
         strip_path_prefix: P,
     ) -> Result<Option<KeyPath>> {
+        // We don't create links, and following them could take us outside the data store.
+        ensure!(
+            !entry.path_is_symlink(),
+            error::Corruption {
+                msg: "Symlink found in data store",
+                path: entry.path(),
+            }
+        );
+
         if !entry.file_type().is_file() {
             trace!("Skipping non-file entry: {}", entry.path().display());
             return Ok(None);
@@ -306,6 +794,34 @@ impl KeyPath {
     }
 }
 
+/// Returns the directory from which we should start walking the filesystem to find keys
+/// starting with the given prefix.
+///
+/// Any complete key segments in the prefix, i.e. anything before the final separator, must
+/// correspond to directories, so we can start the walk there.  The final segment of the prefix
+/// may be partial ("services.fo" should find "services.foo") so it's left to the caller's
+/// filtering.  If the prefix doesn't contain a complete segment, or can't be parsed as a key,
+/// we fall back to walking from the base path.
+fn walk_start_path(
+    datastore: &FilesystemDataStore,
+    prefix: &str,
+    committed: &Committed,
+) -> Result<PathBuf> {
+    let base = datastore.base_path(committed);
+
+    let complete = match prefix.rfind(KEY_SEPARATOR) {
+        Some(idx) => &prefix[..idx],
+        None => return Ok(base),
+    };
+
+    // If the separator was inside quotes, or the prefix is otherwise not a valid key name, we
+    // can't be sure how it maps to the filesystem, so we do a full walk.
+    match Key::new(KeyType::Data, complete) {
+        Ok(key) => datastore.data_path(&key, committed),
+        Err(_) => Ok(base),
+    }
+}
+
 /// Helper to walk through the filesystem to find populated keys of the given type, starting with
 /// the given prefix.  Each item in the returned set is a KeyPath representing a data or metadata
 /// key.
@@ -342,16 +858,34 @@ fn find_populated_key_paths<S: AsRef<str>>(
         }
     }
 
-    // Walk through the filesystem.
-    let walker = WalkDir::new(&base)
+    // If the prefix includes any complete key segments, we can start our walk at the directory
+    // representing those segments, rather than walking the whole data store.
+    let walk_start = walk_start_path(datastore, prefix.as_ref(), committed)?;
+    if !walk_start.exists() {
+        trace!(
+            "Returning empty list because prefix path doesn't exist: {}",
+            walk_start.display()
+        );
+        return Ok(HashSet::new());
+    }
+    // WalkDir follows a link at the starting point, so make sure there isn't one on the way.
+    check_no_symlinks(&base, &walk_start)?;
+
+    // Walk through the filesystem.  Don't descend into the reserved snapshots area unless it was
+    // requested; its keys would be discarded below anyway.
+    let snapshots_path = base.join(SNAPSHOTS_SEGMENT);
+    let skip_snapshots = !prefix.as_ref().starts_with(SNAPSHOTS_SEGMENT);
+    let walker = WalkDir::new(&walk_start)
         .follow_links(false) // shouldn't be links...
-        .same_file_system(true); // shouldn't be filesystems to cross...
+        .same_file_system(true) // shouldn't be filesystems to cross...
+        .into_iter()
+        .filter_entry(|entry| !(skip_snapshots && entry.path() == snapshots_path));
 
     let mut key_paths = HashSet::new();
     trace!(
         "Starting walk of filesystem to list {:?} key paths under {}",
         key_type,
-        base.display()
+        walk_start.display()
     );
 
     // For anything we find, confirm it matches the user's filters, and add it to results.
@@ -366,6 +900,8 @@ fn find_populated_key_paths<S: AsRef<str>>(
                     prefix.as_ref()
                 );
                 continue;
+            } else if hidden_from_listing(&kp.data_key, prefix.as_ref()) {
+                continue;
             } else if kp.key_type() != key_type {
                 continue;
             }
@@ -378,9 +914,9 @@ fn find_populated_key_paths<S: AsRef<str>>(
     Ok(key_paths)
 }
 
-// TODO: maybe add/strip single newline at end, so file is easier to read
 impl DataStore for FilesystemDataStore {
     fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool> {
+        let _lock = self.lock(false)?;
         let path = self.data_path(key, committed)?;
 
         Ok(path.exists())
@@ -393,6 +929,7 @@ impl DataStore for FilesystemDataStore {
         prefix: S,
         committed: &Committed,
     ) -> Result<HashSet<Key>> {
+        let _lock = self.lock(false)?;
         let key_paths = find_populated_key_paths(self, KeyType::Data, prefix, committed)?;
         let keys = key_paths.into_iter().map(|kp| kp.data_key).collect();
         Ok(keys)
@@ -405,18 +942,20 @@ impl DataStore for FilesystemDataStore {
     /// Returns a mapping of the data keys to the set of populated metadata keys for each.
     ///
     /// Note: The data keys do not need to be populated themselves; sometimes metadata is used
-    /// to help generate the data, for example.  (Committed status is then irrelevant, too.)
+    /// to help generate the data, for example.
     fn list_populated_metadata<S1, S2>(
         &self,
         prefix: S1,
         metadata_key_name: &Option<S2>,
+        committed: &Committed,
     ) -> Result<HashMap<Key, HashSet<Key>>>
     where
         S1: AsRef<str>,
         S2: AsRef<str>,
     {
+        let _lock = self.lock(false)?;
         // Find metadata key paths on disk
-        let key_paths = find_populated_key_paths(self, KeyType::Meta, prefix, &Committed::Live)?;
+        let key_paths = find_populated_key_paths(self, KeyType::Meta, prefix, committed)?;
 
         // For each file on disk, check the user's conditions, and add it to our output
         let mut result = HashMap::new();
@@ -442,23 +981,74 @@ impl DataStore for FilesystemDataStore {
     }
 
     fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
+        let _lock = self.lock(false)?;
         let path = self.data_path(key, committed)?;
-        read_file_for_key(&key, &path)
+        self.read_data_file(&key, &self.base_path(committed), &path)
+    }
+
+    /// We find the base path once and read each key's file directly, rather than going through
+    /// get_key for each key.
+    fn get_keys(
+        &self,
+        keys: &HashSet<Key>,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, Option<String>>> {
+        let _lock = self.lock(false)?;
+        let base_path = self.base_path(committed);
+
+        let mut result = HashMap::with_capacity(keys.len());
+        for key in keys {
+            let path = Self::data_path_under(&base_path, key)?;
+            let value = self.read_data_file(&key, &base_path, &path)?;
+            result.insert(key.clone(), value);
+        }
+
+        let missing = result.values().filter(|v| v.is_none()).count();
+        if missing > 0 {
+            trace!("{} of {} requested keys not populated", missing, keys.len());
+        }
+        Ok(result)
     }
 
     fn set_key<S: AsRef<str>>(&mut self, key: &Key, value: S, committed: &Committed) -> Result<()> {
+        self.check_writable()?;
+        let _lock = self.lock(true)?;
         let path = self.data_path(key, committed)?;
-        write_file_mkdir(path, value)
+        self.write_data_file(&self.base_path(committed), path, value)
     }
 
     fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()> {
+        self.check_writable()?;
+        let _lock = self.lock(true)?;
         let path = self.data_path(key, committed)?;
         self.delete_key_path(path, committed)
     }
 
-    fn get_metadata_raw(&self, metadata_key: &Key, data_key: &Key) -> Result<Option<String>> {
-        let path = self.metadata_path(metadata_key, data_key, &Committed::Live)?;
-        read_file_for_key(&metadata_key, &path)
+    /// We use the modification time of the key's file.
+    fn get_key_mtime(&self, key: &Key, committed: &Committed) -> Result<Option<SystemTime>> {
+        let _lock = self.lock(false)?;
+        let path = self.data_path(key, committed)?;
+        check_no_symlinks(&self.base_path(committed), &path)?;
+        match fs::metadata(&path) {
+            Ok(metadata) => Ok(Some(metadata.modified().context(error::Io { path })?)),
+            Err(e) => {
+                if e.kind() == io::ErrorKind::NotFound {
+                    return Ok(None);
+                }
+                Err(e).context(error::Io { path })
+            }
+        }
+    }
+
+    fn get_metadata_raw(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        let _lock = self.lock(false)?;
+        let path = self.metadata_path(metadata_key, data_key, committed)?;
+        read_file_for_key(&metadata_key, &self.base_path(committed), &path)
     }
 
     fn set_metadata<S: AsRef<str>>(
@@ -466,52 +1056,239 @@ impl DataStore for FilesystemDataStore {
         metadata_key: &Key,
         data_key: &Key,
         value: S,
+        committed: &Committed,
     ) -> Result<()> {
-        let path = self.metadata_path(metadata_key, data_key, &Committed::Live)?;
-        write_file_mkdir(path, value)
+        self.check_writable()?;
+        let _lock = self.lock(true)?;
+        let path = self.metadata_path(metadata_key, data_key, committed)?;
+        write_file_mkdir(&self.base_path(committed), path, value)
     }
 
-    fn unset_metadata(&mut self, metadata_key: &Key, data_key: &Key) -> Result<()> {
-        let path = self.metadata_path(metadata_key, data_key, &Committed::Live)?;
-        self.delete_key_path(path, &Committed::Live)
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let _lock = self.lock(true)?;
+        let path = self.metadata_path(metadata_key, data_key, committed)?;
+        self.delete_key_path(path, committed)
     }
 
-    /// We commit by copying pending keys to live, then removing pending.  Something smarter (lock,
-    /// atomic flip, etc.) will be required to make the server concurrent.
+    /// Metadata is stored in files next to the data key's file, named with the data key's final
+    /// segment plus the metadata prefix, so we list the data key's directory and pick out any
+    /// files matching that pattern.
+    fn list_metadata(&self, data_key: &Key, committed: &Committed) -> Result<HashSet<Key>> {
+        let _lock = self.lock(false)?;
+        let data_path = self.data_path(data_key, committed)?;
+        let dirname = data_path.parent().with_context(|| error::Internal {
+            msg: format!("Data key path without parent: {}", data_path.display()),
+        })?;
+        let last_segment = data_key.segments().last().context(error::Internal {
+            msg: "data key with no segments",
+        })?;
+        let file_prefix = encode_path_component(last_segment) + METADATA_KEY_PREFIX;
+
+        let entries = match fs::read_dir(dirname) {
+            Ok(entries) => entries,
+            Err(e) => {
+                // No directory means no keys, and therefore no metadata.
+                if e.kind() == io::ErrorKind::NotFound {
+                    return Ok(HashSet::new());
+                }
+                return Err(e).context(error::Io { path: dirname });
+            }
+        };
+
+        let mut result = HashSet::new();
+        for entry in entries {
+            let entry = entry.context(error::Io { path: dirname })?;
+            let path = entry.path();
+            let file_type = entry.file_type().context(error::Io { path: &path })?;
+            if !file_type.is_file() {
+                continue;
+            }
+
+            // The file name should be valid UTF-8, encoded by encode_path_component, or the data
+            // store has been corrupted.
+            let file_name = entry.file_name();
+            let file_name = file_name.to_str().context(error::Corruption {
+                msg: "Non-UTF8 path",
+                path: &path,
+            })?;
+
+            if let Some(encoded_meta) = strip_str_prefix(file_name, &file_prefix) {
+                let meta_name = decode_path_component(encoded_meta, &path)?;
+                let meta_key = Key::from_segments(KeyType::Meta, &[meta_name])?;
+                trace!("Found metadata '{}' for key {}", meta_key, data_key);
+                result.insert(meta_key);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// We hold the exclusive lock for the whole batch so other users of the data store don't see
+    /// it partially written.
+    fn set_keys<S>(&mut self, pairs: &HashMap<Key, S>, committed: &Committed) -> Result<()>
+    where
+        S: AsRef<str>,
+    {
+        self.check_writable()?;
+        let _lock = self.lock(true)?;
+        for (key, value) in pairs {
+            trace!("Setting data key {}", key.name());
+            self.set_key(key, value, committed)?;
+        }
+        Ok(())
+    }
+
+    /// We hold the exclusive lock for the whole batch so other users of the data store don't see
+    /// it partially removed.
+    fn unset_keys(&mut self, keys: &HashSet<Key>, committed: &Committed) -> Result<()> {
+        self.check_writable()?;
+        let _lock = self.lock(true)?;
+        for key in keys {
+            trace!("Unsetting data key {}", key.name());
+            self.unset_key(key, committed)?;
+        }
+        self.prune_empty_dirs(committed)
+    }
+
+    /// We commit by copying pending keys to live, then removing pending.  The exclusive lock is
+    /// held throughout, so other users of the data store see either all or none of the commit.
     fn commit_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
     where
         S: Into<String> + AsRef<str>,
     {
+        self.check_writable()?;
+        let _lock = self.lock(true)?;
         let pending = Committed::Pending {
             tx: transaction.into(),
         };
         // Get data for changed keys
         let pending_data = self.get_prefix("settings.", &pending)?;
+        // Get any metadata that was staged alongside the data
+        let pending_metadata = self.get_metadata_prefix("", &None as &Option<&str>, &pending)?;
 
         // Nothing to do if no keys are present in pending
-        if pending_data.is_empty() {
+        if pending_data.is_empty() && pending_metadata.is_empty() {
             return Ok(Default::default());
         }
 
         // Save Keys for return value
         let pending_keys: HashSet<Key> = pending_data.keys().cloned().collect();
 
+        // Separate keys to be removed from keys to be set
+        let (unset_data, set_data): (HashMap<Key, String>, HashMap<Key, String>) = pending_data
+            .into_iter()
+            .partition(|(_key, value)| value == UNSET_VALUE);
+
+        // Save the time each key was written to pending, so we can keep it in live
+        let mut pending_mtimes = HashMap::with_capacity(set_data.len());
+        for key in set_data.keys() {
+            if let Some(mtime) = self.get_key_mtime(key, &pending)? {
+                pending_mtimes.insert(key, mtime);
+            }
+        }
+
         // Apply changes to live
         debug!("Writing pending keys to live");
-        self.set_keys(&pending_data, &Committed::Live)?;
+        self.set_keys(&set_data, &Committed::Live)?;
+        for (key, mtime) in pending_mtimes {
+            self.set_key_mtime(key, &Committed::Live, mtime)?;
+        }
+        if !unset_data.is_empty() {
+            debug!("Removing pending unset keys from live");
+            let unset_keys = unset_data.into_iter().map(|(key, _value)| key).collect();
+            self.unset_keys(&unset_keys, &Committed::Live)?;
+        }
+        debug!("Writing pending metadata to live");
+        for (data_key, metadata) in pending_metadata {
+            for (metadata_key, value) in metadata {
+                self.set_metadata(&metadata_key, &data_key, value, &Committed::Live)?;
+            }
+        }
 
         // Remove pending
         debug!("Removing old pending keys");
         let path = self.base_path(&pending);
         fs::remove_dir_all(&path).context(error::Io { path })?;
+        self.prune_empty_dirs(&Committed::Live)?;
 
         Ok(pending_keys)
     }
 
+    /// We commit individual keys by copying each from pending to live, along with any pending
+    /// metadata, and then removing them from pending.
+    fn commit_keys<S>(&mut self, transaction: S, keys: &HashSet<Key>) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.check_writable()?;
+        let _lock = self.lock(true)?;
+        let pending = Committed::Pending {
+            tx: transaction.into(),
+        };
+
+        // Make sure all requested keys are pending before changing anything
+        let pending_data = self.get_keys(keys, &pending)?;
+        let mut missing: Vec<String> = pending_data
+            .iter()
+            .filter(|(_, value)| value.is_none())
+            .map(|(key, _)| key.name().to_string())
+            .collect();
+        missing.sort();
+        ensure!(
+            missing.is_empty(),
+            error::KeysNotPending {
+                transaction: self.transaction_name(&pending),
+                keys: missing,
+            }
+        );
+
+        for (key, value) in pending_data {
+            // We confirmed above that all values are present.
+            let value = value.context(error::Internal {
+                msg: format!("Pending key '{}' disappeared during commit", key),
+            })?;
+
+            if value == UNSET_VALUE {
+                debug!("Removing pending unset key {} from live", key);
+                self.unset_key(&key, &Committed::Live)?;
+            } else {
+                debug!("Writing pending key {} to live", key);
+                let mtime = self.get_key_mtime(&key, &pending)?;
+                self.set_key(&key, value, &Committed::Live)?;
+                if let Some(mtime) = mtime {
+                    self.set_key_mtime(&key, &Committed::Live, mtime)?;
+                }
+            }
+            for metadata_key in self.list_metadata(&key, &pending)? {
+                if let Some(md) = self.get_metadata_raw(&metadata_key, &key, &pending)? {
+                    self.set_metadata(&metadata_key, &key, md, &Committed::Live)?;
+                }
+                self.unset_metadata(&metadata_key, &key, &pending)?;
+            }
+            self.unset_key(&key, &pending)?;
+        }
+
+        // Remove the transaction directory if that was everything in it.  If there are other
+        // pending changes, the directory isn't empty, and this fails, which is fine.
+        self.prune_empty_dirs(&pending)?;
+        self.prune_empty_dirs(&Committed::Live)?;
+        let _ = fs::remove_dir(self.base_path(&pending));
+
+        Ok(keys.clone())
+    }
+
     fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
     where
         S: Into<String> + AsRef<str>,
     {
+        self.check_writable()?;
+        let _lock = self.lock(true)?;
         let pending = Committed::Pending {
             tx: transaction.into(),
         };
@@ -538,6 +1315,7 @@ impl DataStore for FilesystemDataStore {
     /// We store transactions as subdirectories of the pending data store, so to list them we list
     /// the names of the subdirectories.
     fn list_transactions(&self) -> Result<HashSet<String>> {
+        let _lock = self.lock(false)?;
         // Any directory under pending should be a transaction name.
         let walker = WalkDir::new(&self.pending_base_path)
             .min_depth(1)
@@ -577,11 +1355,64 @@ impl DataStore for FilesystemDataStore {
 
         Ok(transactions)
     }
+
+    /// The live directory is created when the data store is first populated, so if it's missing,
+    /// the data store hasn't been set up.
+    fn check_available(&self) -> Result<()> {
+        let metadata = fs::metadata(&self.live_path).context(error::Io {
+            path: &self.live_path,
+        })?;
+        ensure!(
+            metadata.is_dir(),
+            error::Corruption {
+                msg: "Live data store is not a directory",
+                path: &self.live_path,
+            }
+        );
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use super::super::test_helpers::run_conformance_tests;
     use super::*;
+    use maplit::{hashmap, hashset};
+    use std::cell::Cell;
+    use std::os::unix::fs::symlink;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn conformance() {
+        let dir = tempfile::tempdir().unwrap();
+        let count = Cell::new(0);
+        run_conformance_tests(|| {
+            // Each data store gets its own directory, with a live directory like storewolf makes
+            count.set(count.get() + 1);
+            let path = dir.path().join(count.get().to_string());
+            fs::create_dir_all(path.join("live")).unwrap();
+            FilesystemDataStore::new(path)
+        });
+    }
+
+    #[test]
+    fn check_available() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new(dir.path());
+        assert!(f.check_available().is_err());
+
+        let key = Key::new(KeyType::Data, "a.b.c").unwrap();
+        f.set_key(&key, "\"hi\"", &Committed::Live).unwrap();
+        f.check_available().unwrap();
+
+        // Something other than a directory doesn't count
+        let file_dir = tempfile::tempdir().unwrap();
+        fs::write(file_dir.path().join("live"), "not a directory").unwrap();
+        assert!(FilesystemDataStore::new(file_dir.path())
+            .check_available()
+            .is_err());
+    }
 
     #[test]
     fn data_path() {
@@ -622,6 +1453,678 @@ mod test {
         assert_eq!(live.into_os_string(), "/base/live/a/b/c.my-metadata");
     }
 
+    #[test]
+    fn list_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new(dir.path());
+        let data_key = Key::new(KeyType::Data, "a.b.c").unwrap();
+        let md1 = Key::new(KeyType::Meta, "my-metadata").unwrap();
+        let md2 = Key::new(KeyType::Meta, "other").unwrap();
+
+        // No directory yet, so no metadata
+        assert!(f
+            .list_metadata(&data_key, &Committed::Live)
+            .unwrap()
+            .is_empty());
+
+        let live = &Committed::Live;
+        f.set_key(&data_key, "\"value\"", live).unwrap();
+        f.set_metadata(&md1, &data_key, "\"md1\"", live).unwrap();
+        f.set_metadata(&md2, &data_key, "\"md2\"", live).unwrap();
+        // Metadata on a sibling and on the parent shouldn't be listed
+        let sibling = Key::new(KeyType::Data, "a.b.cc").unwrap();
+        let parent = Key::new(KeyType::Data, "a.b").unwrap();
+        f.set_metadata(&md1, &sibling, "\"sibling\"", live).unwrap();
+        f.set_metadata(&md1, &parent, "\"parent\"", live).unwrap();
+
+        let expected: HashSet<Key> = vec![md1.clone(), md2].into_iter().collect();
+        assert_eq!(f.list_metadata(&data_key, live).unwrap(), expected);
+
+        f.unset_metadata(&md1, &data_key, live).unwrap();
+        assert_eq!(f.list_metadata(&data_key, live).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn walk_start_path_works() {
+        let f = FilesystemDataStore::new("/base");
+        let live = &Committed::Live;
+        let start = |prefix| walk_start_path(&f, prefix, live).unwrap().into_os_string();
+
+        // Complete segments map to a directory
+        assert_eq!(start("services."), "/base/live/services");
+        assert_eq!(start("services.foo"), "/base/live/services");
+        assert_eq!(start("services.foo.bar"), "/base/live/services/foo");
+        assert_eq!(start("a.\"b.c\".d"), "/base/live/a/b%2Ec");
+
+        // Partial segments, or separators we can't interpret, need a full walk
+        assert_eq!(start(""), "/base/live");
+        assert_eq!(start("sett"), "/base/live");
+        assert_eq!(start("a.\"b.c"), "/base/live");
+        assert_eq!(start("a..b"), "/base/live");
+    }
+
+    #[test]
+    fn list_populated_keys_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new(dir.path());
+        let live = &Committed::Live;
+
+        let names = &[
+            "services.foo.a",
+            "services.foo.b",
+            "services.foobar.c",
+            "services.bar.d",
+            "settings.x",
+        ];
+        for name in names {
+            let key = Key::new(KeyType::Data, name).unwrap();
+            f.set_key(&key, "\"value\"", live).unwrap();
+        }
+        let list = |prefix: &str| -> HashSet<String> {
+            f.list_populated_keys(prefix, live)
+                .unwrap()
+                .into_iter()
+                .map(|k| k.name().to_string())
+                .collect()
+        };
+        let set = |names: &[&str]| -> HashSet<String> {
+            names.iter().map(|name| name.to_string()).collect()
+        };
+
+        // Prefixes ending on a segment boundary
+        assert_eq!(
+            list("services.foo."),
+            set(&["services.foo.a", "services.foo.b"])
+        );
+        assert_eq!(list("services.foo.a"), set(&["services.foo.a"]));
+        assert_eq!(list("services.bar."), set(&["services.bar.d"]));
+        assert_eq!(list("services.baz."), set(&[]));
+
+        // Partial segments still match everything starting with the prefix
+        assert_eq!(
+            list("services.foo"),
+            set(&["services.foo.a", "services.foo.b", "services.foobar.c"])
+        );
+        assert_eq!(list("sett"), set(&["settings.x"]));
+        assert_eq!(list(""), set(&names[..]));
+    }
+
+    #[test]
+    fn get_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new(dir.path());
+        let k1 = Key::new(KeyType::Data, "a.b").unwrap();
+        let k2 = Key::new(KeyType::Data, "a.c").unwrap();
+        let missing = Key::new(KeyType::Data, "a.d").unwrap();
+        f.set_key(&k1, "\"b\"", &Committed::Live).unwrap();
+        f.set_key(&k2, "\"c\"", &Committed::Live).unwrap();
+
+        let keys = vec![k1.clone(), k2.clone(), missing.clone()]
+            .into_iter()
+            .collect();
+        let values = f.get_keys(&keys, &Committed::Live).unwrap();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[&k1], Some("\"b\"".to_string()));
+        assert_eq!(values[&k2], Some("\"c\"".to_string()));
+        assert_eq!(values[&missing], None);
+    }
+
+    #[test]
+    fn commit_pending_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new(dir.path());
+        let data_key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let md_key = Key::new(KeyType::Meta, "my-metadata").unwrap();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let live = &Committed::Live;
+
+        f.set_key(&data_key, "\"value\"", &pending).unwrap();
+        f.set_metadata(&md_key, &data_key, "\"md\"", &pending)
+            .unwrap();
+
+        // Pending metadata is invisible to live reads until commit
+        assert_eq!(
+            f.get_metadata_raw(&md_key, &data_key, &pending).unwrap(),
+            Some("\"md\"".to_string())
+        );
+        assert_eq!(f.get_metadata_raw(&md_key, &data_key, live).unwrap(), None);
+
+        f.commit_transaction(tx).unwrap();
+        assert_eq!(
+            f.get_metadata_raw(&md_key, &data_key, live).unwrap(),
+            Some("\"md\"".to_string())
+        );
+        assert_eq!(
+            f.get_metadata_raw(&md_key, &data_key, &pending).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn transactions_are_isolated() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new(dir.path());
+        let key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let tx1 = Committed::Pending { tx: "tx1".into() };
+        let tx2 = Committed::Pending { tx: "tx/2".into() };
+        f.set_key(&key, "\"one\"", &tx1).unwrap();
+        f.set_key(&key, "\"two\"", &tx2).unwrap();
+
+        let transactions = f.list_transactions().unwrap();
+        assert_eq!(transactions.len(), 2);
+        assert!(transactions.contains("tx1"));
+        assert!(transactions.contains("tx/2"));
+
+        // Committing one transaction leaves the other pending
+        f.commit_transaction("tx1").unwrap();
+        assert_eq!(
+            f.get_key(&key, &Committed::Live).unwrap(),
+            Some("\"one\"".to_string())
+        );
+        assert_eq!(f.get_key(&key, &tx2).unwrap(), Some("\"two\"".to_string()));
+        let transactions = f.list_transactions().unwrap();
+        assert_eq!(transactions.into_iter().collect::<Vec<_>>(), vec!["tx/2"]);
+    }
+
+    #[test]
+    fn delete_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new(dir.path());
+        let key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+
+        // Nothing pending yet
+        assert!(f.delete_transaction(tx).unwrap().is_empty());
+
+        f.set_key(&key, "\"value\"", &pending).unwrap();
+        let deleted = f.delete_transaction(tx).unwrap();
+        assert_eq!(deleted.into_iter().collect::<Vec<_>>(), vec![key.clone()]);
+        assert!(!f.key_populated(&key, &pending).unwrap());
+        assert!(f.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn commit_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new(dir.path());
+        let k1 = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let k2 = Key::new(KeyType::Data, "settings.a.c").unwrap();
+        let md_key = Key::new(KeyType::Meta, "my-metadata").unwrap();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let live = &Committed::Live;
+        f.set_key(&k1, "\"b\"", &pending).unwrap();
+        f.set_key(&k2, "\"c\"", &pending).unwrap();
+        f.set_metadata(&md_key, &k1, "\"md\"", &pending).unwrap();
+
+        // Asking for a key that isn't pending fails, and commits nothing
+        let bogus = Key::new(KeyType::Data, "settings.a.d").unwrap();
+        let keys = vec![k1.clone(), bogus].into_iter().collect();
+        f.commit_keys(tx, &keys).unwrap_err();
+        assert!(!f.key_populated(&k1, live).unwrap());
+
+        // Committing one key (and its metadata) leaves the other pending
+        let keys = vec![k1.clone()].into_iter().collect();
+        assert_eq!(f.commit_keys(tx, &keys).unwrap(), keys);
+        assert!(f.key_populated(&k1, live).unwrap());
+        assert!(!f.key_populated(&k1, &pending).unwrap());
+        assert_eq!(
+            f.get_metadata_raw(&md_key, &k1, live).unwrap(),
+            Some("\"md\"".to_string())
+        );
+        assert!(f.key_populated(&k2, &pending).unwrap());
+        assert!(!f.key_populated(&k2, live).unwrap());
+
+        // Committing the last key removes the transaction
+        let keys = vec![k2.clone()].into_iter().collect();
+        f.commit_keys(tx, &keys).unwrap();
+        assert!(f.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn write_is_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new(dir.path());
+        let key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let md_key = Key::new(KeyType::Meta, "my-metadata").unwrap();
+        let live = &Committed::Live;
+        f.set_key(&key, "\"old\"", live).unwrap();
+        f.set_metadata(&md_key, &key, "\"md\"", live).unwrap();
+
+        // No temporary files are left behind after a successful write
+        let path = f.data_path(&key, live).unwrap();
+        let tmp = temp_path(&path).unwrap();
+        assert!(!tmp.exists());
+        let md_path = f.metadata_path(&md_key, &key, live).unwrap();
+        assert!(!temp_path(&md_path).unwrap().exists());
+
+        // Simulate a write that died partway through; the partial value isn't visible under the
+        // real name, and isn't mistaken for a key.
+        fs::write(&tmp, "\"ne").unwrap();
+        assert_eq!(f.get_key(&key, live).unwrap(), Some("\"old\"".to_string()));
+        let keys = f.list_populated_keys("settings.", live).unwrap();
+        assert_eq!(keys.into_iter().collect::<Vec<_>>(), vec![key.clone()]);
+        assert_eq!(
+            f.list_metadata(&key, live)
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![md_key]
+        );
+
+        // The next write replaces the stale temporary file
+        f.set_key(&key, "\"new\"", live).unwrap();
+        assert_eq!(f.get_key(&key, live).unwrap(), Some("\"new\"".to_string()));
+        assert!(!tmp.exists());
+    }
+
+    #[test]
+    fn concurrent_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let threads = 4;
+        let keys_per_thread = 20;
+        // Large values make torn writes more likely to be noticed
+        let value_for = |t: usize, i: usize| format!("\"{}\"", format!("{}-{}", t, i).repeat(500));
+
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                // Each thread gets its own instance, like a separate process would
+                let mut f = FilesystemDataStore::new(dir.path());
+                thread::spawn(move || {
+                    let tx = format!("tx{}", t);
+                    let pending = Committed::Pending { tx: tx.clone() };
+                    for i in 0..keys_per_thread {
+                        let key =
+                            Key::new(KeyType::Data, format!("settings.t{}.k{}", t, i)).unwrap();
+                        f.set_key(&key, value_for(t, i), &pending).unwrap();
+                        if i % 5 == 4 {
+                            f.commit_transaction(tx.as_str()).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let f = FilesystemDataStore::new(dir.path());
+        let live = f.get_prefix("settings.", &Committed::Live).unwrap();
+        assert_eq!(live.len(), threads * keys_per_thread);
+        for t in 0..threads {
+            for i in 0..keys_per_thread {
+                let key = Key::new(KeyType::Data, format!("settings.t{}.k{}", t, i)).unwrap();
+                assert_eq!(live.get(&key), Some(&value_for(t, i)));
+            }
+        }
+        assert!(f.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn locking_disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new_with_locking(dir.path(), LockMode::Disabled);
+        let key = Key::new(KeyType::Data, "settings.a").unwrap();
+        f.set_key(&key, "\"a\"", &Committed::Live).unwrap();
+        assert!(!dir.path().join(LOCK_FILE_NAME).exists());
+
+        let mut f = FilesystemDataStore::new(dir.path());
+        f.set_key(&key, "\"b\"", &Committed::Live).unwrap();
+        assert!(dir.path().join(LOCK_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn symlinks_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let outside_file = outside.path().join("secret");
+        fs::write(&outside_file, "\"secret\"").unwrap();
+
+        let mut f = FilesystemDataStore::new(dir.path());
+        let live = &Committed::Live;
+        let real = Key::new(KeyType::Data, "settings.real").unwrap();
+        f.set_key(&real, "\"real\"", live).unwrap();
+
+        // A link to a file outside the data store
+        let file_link = Key::new(KeyType::Data, "settings.file-link").unwrap();
+        symlink(&outside_file, f.data_path(&file_link, live).unwrap()).unwrap();
+        f.get_key(&file_link, live).unwrap_err();
+        f.set_key(&file_link, "\"overwritten\"", live).unwrap_err();
+        f.list_populated_keys("settings.", live).unwrap_err();
+        fs::remove_file(f.data_path(&file_link, live).unwrap()).unwrap();
+
+        // A link to a directory outside the data store
+        let dir_link = Key::new(KeyType::Data, "settings.dir-link.secret").unwrap();
+        let link_path = f.data_path(&dir_link, live).unwrap();
+        symlink(outside.path(), link_path.parent().unwrap()).unwrap();
+        f.get_key(&dir_link, live).unwrap_err();
+        f.set_key(&dir_link, "\"overwritten\"", live).unwrap_err();
+        let md_key = Key::new(KeyType::Meta, "my-metadata").unwrap();
+        f.set_metadata(&md_key, &dir_link, "\"md\"", live)
+            .unwrap_err();
+        f.list_populated_keys("settings.dir-link.", live)
+            .unwrap_err();
+
+        // Nothing outside was changed
+        assert_eq!(fs::read_to_string(&outside_file).unwrap(), "\"secret\"");
+        assert_eq!(fs::read_dir(outside.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let live = &Committed::Live;
+        let pending = &Committed::Pending { tx: "tx".into() };
+        let key = Key::new(KeyType::Data, "settings.a").unwrap();
+        let md_key = Key::new(KeyType::Meta, "my-metadata").unwrap();
+
+        let mut f = FilesystemDataStore::new(dir.path());
+        f.set_key(&key, "\"live\"", live).unwrap();
+        f.set_key(&key, "\"pending\"", pending).unwrap();
+        f.set_metadata(&md_key, &key, "\"md\"", live).unwrap();
+
+        let mut r = FilesystemDataStore::open_read_only(dir.path());
+        assert_eq!(r.get_key(&key, live).unwrap(), Some("\"live\"".into()));
+        assert_eq!(
+            r.get_key(&key, pending).unwrap(),
+            Some("\"pending\"".into())
+        );
+        assert_eq!(
+            r.get_metadata(&md_key, &key, live).unwrap(),
+            Some("\"md\"".into())
+        );
+        assert_eq!(
+            r.list_populated_keys("", live).unwrap(),
+            hashset!(key.clone())
+        );
+        assert_eq!(r.list_transactions().unwrap(), hashset!("tx".to_string()));
+
+        let assert_read_only = |result: Result<()>| match result {
+            Err(error::Error::ReadOnly { .. }) => {}
+            other => panic!("Expected ReadOnly error, got {:?}", other),
+        };
+        assert_read_only(r.set_key(&key, "\"new\"", live));
+        assert_read_only(r.unset_key(&key, live));
+        assert_read_only(r.set_metadata(&md_key, &key, "\"new\"", live));
+        assert_read_only(r.unset_metadata(&md_key, &key, live));
+        assert_read_only(r.set_keys(&hashmap!(key.clone() => "\"new\""), live));
+        assert_read_only(r.unset_keys(&hashset!(key.clone()), live));
+        assert_read_only(r.commit_transaction("tx").map(|_| ()));
+        assert_read_only(r.commit_keys("tx", &hashset!(key.clone())).map(|_| ()));
+        assert_read_only(r.delete_transaction("tx").map(|_| ()));
+
+        // Nothing changed
+        assert_eq!(f.get_key(&key, live).unwrap(), Some("\"live\"".into()));
+        assert_eq!(
+            f.get_key(&key, pending).unwrap(),
+            Some("\"pending\"".into())
+        );
+        assert_eq!(
+            f.get_metadata(&md_key, &key, live).unwrap(),
+            Some("\"md\"".into())
+        );
+    }
+
+    #[test]
+    fn lock_reentry() {
+        let dir = tempfile::tempdir().unwrap();
+        let f = FilesystemDataStore::new(dir.path());
+
+        // An exclusive lock covers nested shared and exclusive requests
+        {
+            let _outer = f.lock(true).unwrap();
+            let _shared = f.lock(false).unwrap();
+            let _exclusive = f.lock(true).unwrap();
+        }
+
+        // A shared lock can't be upgraded from inside
+        {
+            let _outer = f.lock(false).unwrap();
+            let _shared = f.lock(false).unwrap();
+            match f.lock(true) {
+                Err(error::Error::Internal { .. }) => {}
+                Err(e) => panic!("Unexpected error: {}", e),
+                Ok(_) => panic!("Upgraded shared lock"),
+            }
+        }
+
+        // Once released, the lock can be taken exclusively again
+        let _exclusive = f.lock(true).unwrap();
+    }
+
+    #[test]
+    fn read_only_without_lock_file() {
+        // A read-only data store can be read before anything has created the lock file
+        let dir = tempfile::tempdir().unwrap();
+        let key = Key::new(KeyType::Data, "settings.a").unwrap();
+        let r = FilesystemDataStore::open_read_only(dir.path());
+        assert_eq!(r.get_key(&key, &Committed::Live).unwrap(), None);
+        assert!(!dir.path().join(LOCK_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn trailing_newline() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new(dir.path());
+        let live = &Committed::Live;
+        let key = Key::new(KeyType::Data, "settings.a").unwrap();
+        let md_key = Key::new(KeyType::Meta, "my-metadata").unwrap();
+
+        // Values are written with a newline, which is removed when reading
+        f.set_key(&key, "\"value\"", live).unwrap();
+        let path = f.data_path(&key, live).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "\"value\"\n");
+        assert_eq!(f.get_key(&key, live).unwrap(), Some("\"value\"".into()));
+        f.set_metadata(&md_key, &key, "\"md\"", live).unwrap();
+        let md_path = f.metadata_path(&md_key, &key, live).unwrap();
+        assert_eq!(fs::read_to_string(&md_path).unwrap(), "\"md\"\n");
+        assert_eq!(
+            f.get_metadata_raw(&md_key, &key, live).unwrap(),
+            Some("\"md\"".into())
+        );
+
+        // Values with embedded newlines, escaped or raw, round-trip unchanged
+        for value in &[
+            "\"a\\nb\\n\"",
+            "\"a\nb\"",
+            "\"ends with escape\\n\"",
+            "raw\n",
+            "raw\n\n",
+            "\n",
+            "",
+        ] {
+            f.set_key(&key, value, live).unwrap();
+            assert_eq!(f.get_key(&key, live).unwrap().as_deref(), Some(*value));
+            f.set_metadata(&md_key, &key, value, live).unwrap();
+            assert_eq!(
+                f.get_metadata_raw(&md_key, &key, live).unwrap().as_deref(),
+                Some(*value)
+            );
+        }
+
+        // Files written by hand, with or without a newline, read the same.  (Without a checksum,
+        // since we can't expect people to update it; see checksums_ignored_when_stale.)
+        fs::remove_file(checksum_path(&path).unwrap()).unwrap();
+        fs::write(&path, "\"by hand\"\n").unwrap();
+        assert_eq!(f.get_key(&key, live).unwrap(), Some("\"by hand\"".into()));
+        fs::write(&path, "\"by hand\"").unwrap();
+        assert_eq!(f.get_key(&key, live).unwrap(), Some("\"by hand\"".into()));
+    }
+
+    /// Returns any directories under the given path that have nothing in them.
+    fn empty_dirs(path: &Path) -> Vec<PathBuf> {
+        WalkDir::new(path)
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_dir())
+            .filter(|entry| fs::read_dir(entry.path()).unwrap().next().is_none())
+            .map(|entry| entry.path().to_path_buf())
+            .collect()
+    }
+
+    #[test]
+    fn prune_empty_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new(dir.path());
+        let live = &Committed::Live;
+        let tx = "tx";
+        let pending = &Committed::Pending { tx: tx.into() };
+        let k1 = Key::new(KeyType::Data, "settings.a.b.c").unwrap();
+        let k2 = Key::new(KeyType::Data, "settings.a.d").unwrap();
+        let k3 = Key::new(KeyType::Data, "services.old.configuration-files").unwrap();
+        for key in &[&k1, &k2, &k3] {
+            f.set_key(key, "\"x\"", live).unwrap();
+            f.set_key(key, "\"y\"", pending).unwrap();
+        }
+
+        // Simulate directories left behind by older code or interrupted operations
+        fs::create_dir_all(f.live_path.join("settings/leftover/deeper")).unwrap();
+        fs::create_dir_all(f.base_path(pending).join("settings/leftover")).unwrap();
+        assert!(!empty_dirs(dir.path()).is_empty());
+
+        // Removing keys prunes their directories and any other empty ones
+        f.unset_keys(&vec![k1.clone(), k3.clone()].into_iter().collect(), live)
+            .unwrap();
+        assert!(empty_dirs(&f.live_path).is_empty());
+        assert!(f.live_path.join("settings/a").exists());
+        assert!(!f.live_path.join("settings/a/b").exists());
+        assert!(!f.live_path.join("services").exists());
+        assert_eq!(f.get_key(&k2, live).unwrap(), Some("\"x\"".to_string()));
+
+        // Committing leaves no empty directories in live or pending
+        fs::create_dir_all(f.live_path.join("settings/leftover")).unwrap();
+        f.commit_keys(tx, &vec![k1.clone()].into_iter().collect())
+            .unwrap();
+        assert!(empty_dirs(dir.path()).is_empty());
+        f.commit_transaction(tx).unwrap();
+        assert!(empty_dirs(&f.live_path).is_empty());
+        assert!(!f.base_path(pending).exists());
+
+        // The base itself is never removed, even if empty
+        f.unset_keys(&vec![k1, k2, k3].into_iter().collect(), live)
+            .unwrap();
+        assert!(f.live_path.exists());
+        assert_eq!(empty_dirs(&f.live_path), vec![f.live_path.clone()]);
+    }
+
+    #[test]
+    fn checksums() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new(dir.path());
+        let live = &Committed::Live;
+        let k1 = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let k2 = Key::new(KeyType::Data, "settings.a.c").unwrap();
+        let k3 = Key::new(KeyType::Data, "settings.d").unwrap();
+        for key in &[&k1, &k2, &k3] {
+            f.set_key(key, "\"value\"", live).unwrap();
+        }
+
+        // A checksum is written next to each value, and isn't mistaken for a key
+        let path = f.data_path(&k1, live).unwrap();
+        assert_eq!(
+            fs::read_to_string(checksum_path(&path).unwrap()).unwrap(),
+            format!("{}\n", checksum(b"\"value\""))
+        );
+        assert_eq!(
+            f.list_populated_keys("", live).unwrap(),
+            hashset!(k1.clone(), k2.clone(), k3.clone())
+        );
+        assert!(f.verify_all(live).unwrap().is_empty());
+
+        // Corrupted values are detected when read, and by verify_all
+        rot(&path, b"\"valux\"\n");
+        let path3 = f.data_path(&k3, live).unwrap();
+        rot(&path3, b"\xff\xfe");
+        match f.get_key(&k1, live) {
+            Err(error::Error::ChecksumMismatch { key, .. }) => assert_eq!(key, k1.name().as_str()),
+            other => panic!("Expected ChecksumMismatch, got {:?}", other),
+        }
+        f.get_keys(&hashset!(k1.clone(), k2.clone()), live)
+            .unwrap_err();
+        match f.get_key(&k3, live) {
+            Err(error::Error::ChecksumMismatch { .. }) => {}
+            other => panic!("Expected ChecksumMismatch, got {:?}", other),
+        }
+        assert_eq!(f.get_key(&k2, live).unwrap(), Some("\"value\"".to_string()));
+        assert_eq!(
+            f.verify_all(live).unwrap(),
+            hashset!(k1.clone(), k3.clone())
+        );
+
+        // Verification can be skipped, except by verify_all
+        let skip = FilesystemDataStore::new_with_checksums(dir.path(), ChecksumMode::Skip);
+        assert_eq!(
+            skip.get_key(&k1, live).unwrap(),
+            Some("\"valux\"".to_string())
+        );
+        assert_eq!(skip.verify_all(live).unwrap().len(), 2);
+
+        // Writing a new value fixes the checksum, and removing the key removes the checksum
+        f.set_key(&k1, "\"new\"", live).unwrap();
+        assert_eq!(f.get_key(&k1, live).unwrap(), Some("\"new\"".to_string()));
+        f.unset_key(&k3, live).unwrap();
+        assert!(!checksum_path(&path3).unwrap().exists());
+        assert!(f.verify_all(live).unwrap().is_empty());
+
+        // Values without a checksum, e.g. from before we had checksums, can still be read
+        fs::remove_file(checksum_path(&path).unwrap()).unwrap();
+        assert_eq!(f.get_key(&k1, live).unwrap(), Some("\"new\"".to_string()));
+        assert!(f.verify_all(live).unwrap().is_empty());
+    }
+
+    /// Replaces the contents of the file at the given path without changing its modification time,
+    /// like bit rot would.
+    fn rot(path: &Path, data: &[u8]) {
+        let mtime = modified(path).unwrap();
+        fs::write(path, data).unwrap();
+        set_mtime(path, mtime);
+    }
+
+    fn set_mtime(path: &Path, mtime: SystemTime) {
+        let since_epoch = mtime.duration_since(UNIX_EPOCH).unwrap();
+        let time = TimeSpec::nanoseconds(since_epoch.as_nanos() as i64);
+        utimensat(None, path, &time, &time, UtimensatFlags::NoFollowSymlink).unwrap();
+    }
+
+    #[test]
+    fn checksums_ignored_when_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut f = FilesystemDataStore::new(dir.path());
+        let live = &Committed::Live;
+        let key = Key::new(KeyType::Data, "settings.a").unwrap();
+        f.set_key(&key, "\"old\"", live).unwrap();
+        let path = f.data_path(&key, live).unwrap();
+        let checksum_path = checksum_path(&path).unwrap();
+        let written = modified(&checksum_path).unwrap();
+
+        // A write interrupted after replacing the value, but before its checksum, leaves the old
+        // checksum behind; the new value is used without verification.
+        write_file_mkdir(&f.live_path, path.clone(), "\"torn\"").unwrap();
+        set_mtime(&path, written + Duration::from_secs(1));
+        assert_eq!(f.get_key(&key, live).unwrap(), Some("\"torn\"".to_string()));
+        assert!(f.verify_all(live).unwrap().is_empty());
+
+        // Same for a value edited by hand
+        fs::write(&path, "\"by hand\"\n").unwrap();
+        set_mtime(&path, written + Duration::from_secs(2));
+        assert_eq!(
+            f.get_key(&key, live).unwrap(),
+            Some("\"by hand\"".to_string())
+        );
+        assert!(f.verify_all(live).unwrap().is_empty());
+
+        // Once the checksum is current again, it's verified
+        f.set_key(&key, "\"new\"", live).unwrap();
+        set_mtime(&path, written);
+        rot(&path, b"\"nex\"\n");
+        match f.get_key(&key, live) {
+            Err(error::Error::ChecksumMismatch { .. }) => {}
+            other => panic!("Expected ChecksumMismatch, got {:?}", other),
+        }
+    }
+
     #[test]
     fn encode_path_component_works() {
         assert_eq!(encode_path_component("a-b_42"), "a-b_42");
@@ -1,5 +1,6 @@
 // Note: this only allows reading and writing UTF-8 keys and values; is that OK?
 
+use model::key_name;
 use serde::{Serialize, Serializer};
 use snafu::ensure;
 use std::fmt;
@@ -7,13 +8,9 @@ use std::hash::{Hash, Hasher};
 
 use super::{error, Result};
 
-pub const KEY_SEPARATOR: char = '.';
-// String refs are more convenient for some Rust functions
-pub const KEY_SEPARATOR_STR: &str = ".";
-
-/// Maximum key name length matches the maximum filename length of 255; if we need to have longer
-/// keys (up to 4096) we could make prefixes not count against this limit.
-const MAX_KEY_NAME_LENGTH: usize = 255;
+pub use model::key_name::{
+    KEY_SEPARATOR, KEY_SEPARATOR_STR, MAX_KEY_NAME_LENGTH, MAX_KEY_SEGMENT_LENGTH,
+};
 
 /// KeyType represents whether we want to check a Key as a data key or metadata key.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -195,14 +192,7 @@ impl Key {
         S2: AsRef<str>,
     {
         let name = name.as_ref();
-
-        ensure!(
-            name.len() <= MAX_KEY_NAME_LENGTH,
-            error::KeyTooLong {
-                name,
-                max: MAX_KEY_NAME_LENGTH,
-            }
-        );
+        key_name::check_name_segments(name, segments)?;
 
         match key_type {
             KeyType::Data => {
@@ -222,21 +212,19 @@ impl Key {
                         msg: "meta keys may only have one segment",
                     }
                 );
+                // Metadata is stored on the filesystem next to its data key, after the
+                // separator, so a metadata key containing the separator, even quoted, can't be
+                // told apart from the data key when listing.
+                ensure!(
+                    !segments[0].as_ref().contains(KEY_SEPARATOR),
+                    error::MetadataKeySeparator { name }
+                );
             }
         }
 
         Ok(())
     }
 
-    /// Determines whether a character is acceptable within a segment of a key name.  This is
-    /// separate from quoting; if a character isn't valid, it isn't valid quoted, either.
-    fn valid_character(c: char) -> bool {
-        match c {
-            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '/' => true,
-            _ => false,
-        }
-    }
-
     /// Given a key name, returns a list of its name segments, separated by KEY_SEPARATOR.
     /// Respects quoting of segments so they can contain dots.
     ///
@@ -245,77 +233,7 @@ impl Key {
     /// * "a.b".c -> ["a.b", "c"]
     fn parse_name_segments<S: AsRef<str>>(name: S) -> Result<Vec<String>> {
         let name = name.as_ref();
-
-        ensure!(
-            !name.is_empty(),
-            error::InvalidKey {
-                name,
-                msg: "cannot be empty",
-            }
-        );
-
-        // The full list of name segments we're going to return.
-        let mut segments = Vec::new();
-        // The current name segment we're checking.
-        let mut segment = String::new();
-        // Track whether we're inside a quoted section of the key name
-        let mut in_quotes = false;
-
-        // Walk through each character, looking for quotes or separators to update state
-        for c in name.chars() {
-            if c == '"' {
-                // Quotes don't go into the name segments, so we just flip the flag.
-                in_quotes = !in_quotes;
-            } else if c == KEY_SEPARATOR {
-                if in_quotes {
-                    // If we see a separator inside quotes, it's just like any other character.
-                    segment.push(c);
-                } else {
-                    // If we see a separator outside quotes, it should be ending a segment.
-                    // Segments can't be empty.
-                    ensure!(
-                        !segment.is_empty(),
-                        error::InvalidKey {
-                            name,
-                            msg: "empty key segment",
-                        }
-                    );
-                    // Save the segment we just saw and start a new one.
-                    segments.push(segment);
-                    segment = String::new();
-                }
-            } else {
-                // Not a special character; make sure it's a valid part of a name segment.
-                if Self::valid_character(c) {
-                    segment.push(c);
-                } else {
-                    return error::InvalidKey {
-                        name,
-                        msg: format!("invalid character in key: '{}'", c),
-                    }
-                    .fail();
-                }
-            }
-        }
-
-        ensure!(
-            !in_quotes,
-            error::InvalidKey {
-                name,
-                msg: "unbalanced quotes",
-            }
-        );
-        ensure!(
-            !segment.is_empty(),
-            error::InvalidKey {
-                name,
-                msg: "ends with separator",
-            }
-        );
-
-        // Push final segment (keys don't end with a dot, which is when we normally push)
-        segments.push(segment);
-
+        let segments = key_name::parse_name_segments(name)?;
         trace!("Parsed key name '{}' to segments {:?}", name, segments);
         Ok(segments)
     }
@@ -330,7 +248,7 @@ impl Key {
         for segment in segments.iter() {
             for chr in segment.chars() {
                 ensure!(
-                    chr == KEY_SEPARATOR || Self::valid_character(chr),
+                    chr == KEY_SEPARATOR || key_name::valid_character(chr),
                     error::InvalidKey {
                         // Give an understandable key name in the error, even if it's invalid
                         name: segments.join("."),
@@ -401,7 +319,8 @@ impl Hash for Key {
 
 #[cfg(test)]
 mod test {
-    use super::{Key, KeyType, MAX_KEY_NAME_LENGTH};
+    use super::{Key, KeyType, MAX_KEY_NAME_LENGTH, MAX_KEY_SEGMENT_LENGTH};
+    use crate::datastore::Error;
 
     // Helper macro for testing conditions that apply to both data and metadata keys
     macro_rules! data_and_meta {
@@ -457,14 +376,101 @@ mod test {
         data_and_meta!(|t| assert!(Key::new(t, "a-b_c").is_ok()));
     }
 
+    // Builds a dotted key name of the given length out of maximum-length segments.
+    fn long_key_name(len: usize) -> String {
+        let mut name = String::new();
+        while name.len() < len {
+            if !name.is_empty() {
+                name.push('.');
+            }
+            let remaining = len - name.len();
+            name.push_str(&"a".repeat(remaining.min(MAX_KEY_SEGMENT_LENGTH)));
+        }
+        name
+    }
+
     #[test]
     fn long_key_ok() {
-        data_and_meta!(|t| assert!(Key::new(t, "a".repeat(MAX_KEY_NAME_LENGTH)).is_ok()));
+        let name = long_key_name(MAX_KEY_NAME_LENGTH);
+        assert_eq!(name.len(), MAX_KEY_NAME_LENGTH);
+        assert!(Key::new(KeyType::Data, name).is_ok());
+        data_and_meta!(|t| assert!(Key::new(t, "a".repeat(MAX_KEY_SEGMENT_LENGTH)).is_ok()));
     }
 
     #[test]
     fn key_too_long() {
-        data_and_meta!(|t| assert!(Key::new(t, "a".repeat(MAX_KEY_NAME_LENGTH + 1)).is_err()));
+        let name = long_key_name(MAX_KEY_NAME_LENGTH + 1);
+        match Key::new(KeyType::Data, name) {
+            Err(Error::KeyTooLong { .. }) => {}
+            other => panic!("expected KeyTooLong, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn key_segment_too_long() {
+        data_and_meta!(
+            |t| match Key::new(t, "a".repeat(MAX_KEY_SEGMENT_LENGTH + 1)) {
+                Err(Error::KeySegmentTooLong { .. }) => {}
+                other => panic!("expected KeySegmentTooLong, got {:?}", other),
+            }
+        );
+        let long_segment = "b".repeat(MAX_KEY_SEGMENT_LENGTH + 1);
+        assert!(Key::from_segments(KeyType::Data, &["a", long_segment.as_str()]).is_err());
+    }
+
+    #[test]
+    fn key_names() {
+        // (key type, name, accepted)
+        let cases = &[
+            (KeyType::Data, "a", true),
+            (KeyType::Data, "settings.motd", true),
+            (KeyType::Data, "a-b_c.D9", true),
+            (KeyType::Data, "a.\"b.c\".d", true),
+            (KeyType::Data, "a.\"b/c\".d", true),
+            (KeyType::Data, "a/b", true),
+            (KeyType::Data, "", false),
+            (KeyType::Data, ".", false),
+            (KeyType::Data, "a..b", false),
+            (KeyType::Data, ".a", false),
+            (KeyType::Data, "a.", false),
+            (KeyType::Data, "a.\"\".b", false),
+            (KeyType::Data, "a.\"b", false),
+            (KeyType::Data, "a b", false),
+            (KeyType::Data, "a\\b", false),
+            (KeyType::Data, "caf\u{e9}", false),
+            (KeyType::Data, "\u{1f600}", false),
+            (KeyType::Data, "a.\u{2024}.b", false),
+            (KeyType::Meta, "affected-services", true),
+            (KeyType::Meta, "a/b", true),
+            (KeyType::Meta, "a.b", false),
+            (KeyType::Meta, "\"a.b\"", false),
+            (KeyType::Meta, "caf\u{e9}", false),
+        ];
+        for (key_type, name, accepted) in cases {
+            assert_eq!(
+                Key::new(*key_type, name).is_ok(),
+                *accepted,
+                "{:?} key '{}'",
+                key_type,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn key_error_variants() {
+        match Key::new(KeyType::Data, "a..b") {
+            Err(Error::EmptyKeySegment { .. }) => {}
+            other => panic!("expected EmptyKeySegment, got {:?}", other),
+        }
+        match Key::from_segments(KeyType::Data, &["a", "", "b"]) {
+            Err(Error::EmptyKeySegment { .. }) => {}
+            other => panic!("expected EmptyKeySegment, got {:?}", other),
+        }
+        match Key::from_segments(KeyType::Meta, &["a.b"]) {
+            Err(Error::MetadataKeySeparator { .. }) => {}
+            other => panic!("expected MetadataKeySeparator, got {:?}", other),
+        }
     }
 
     #[test]
@@ -578,7 +584,7 @@ mod test {
 
     #[test]
     fn append_key_err() {
-        let long_key = Key::new(KeyType::Data, "a".repeat(MAX_KEY_NAME_LENGTH)).unwrap();
+        let long_key = Key::new(KeyType::Data, long_key_name(MAX_KEY_NAME_LENGTH)).unwrap();
         let key2 = Key::new(KeyType::Data, "b").unwrap();
         long_key.append_key(&key2).unwrap_err();
     }
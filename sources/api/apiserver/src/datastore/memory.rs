@@ -1,11 +1,14 @@
 //! In-memory datastore for use in testing other modules.
 //!
-//! Mimics some of the decisions made for FilesystemDataStore, e.g. metadata being committed
-//! immediately.
+//! Mimics some of the decisions made for FilesystemDataStore, e.g. metadata being stored per
+//! transaction and committed along with data.
 
 use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
 
-use super::{Committed, DataStore, Key, Result};
+use snafu::ensure;
+
+use super::{error, hidden_from_listing, Committed, DataStore, Key, Result, UNSET_VALUE};
 
 #[derive(Debug)]
 pub struct MemoryDataStore {
@@ -16,6 +19,11 @@ pub struct MemoryDataStore {
     // Map of data keys to their metadata, which in turn is a mapping of metadata keys to
     // arbitrary (string/serialized) values.
     metadata: HashMap<Key, HashMap<Key, String>>,
+    // Transaction name -> (data key -> (metadata key -> value))
+    pending_metadata: HashMap<String, HashMap<Key, HashMap<Key, String>>>,
+    // Time each data key was last set, for live data and each transaction.
+    live_mtimes: HashMap<Key, SystemTime>,
+    pending_mtimes: HashMap<String, HashMap<Key, SystemTime>>,
 }
 
 impl MemoryDataStore {
@@ -24,6 +32,16 @@ impl MemoryDataStore {
             pending: HashMap::new(),
             live: HashMap::new(),
             metadata: HashMap::new(),
+            pending_metadata: HashMap::new(),
+            live_mtimes: HashMap::new(),
+            pending_mtimes: HashMap::new(),
+        }
+    }
+
+    fn mtimes_mut(&mut self, committed: &Committed) -> &mut HashMap<Key, SystemTime> {
+        match committed {
+            Committed::Live => &mut self.live_mtimes,
+            Committed::Pending { tx } => self.pending_mtimes.entry(tx.clone()).or_default(),
         }
     }
 
@@ -40,6 +58,23 @@ impl MemoryDataStore {
             Committed::Pending { tx } => self.pending.entry(tx.clone()).or_default(),
         }
     }
+
+    fn metadataset(&self, committed: &Committed) -> Option<&HashMap<Key, HashMap<Key, String>>> {
+        match committed {
+            Committed::Live => Some(&self.metadata),
+            Committed::Pending { tx } => self.pending_metadata.get(tx),
+        }
+    }
+
+    fn metadataset_mut(
+        &mut self,
+        committed: &Committed,
+    ) -> &mut HashMap<Key, HashMap<Key, String>> {
+        match committed {
+            Committed::Live => &mut self.metadata,
+            Committed::Pending { tx } => self.pending_metadata.entry(tx.clone()).or_default(),
+        }
+    }
 }
 
 impl DataStore for MemoryDataStore {
@@ -54,6 +89,7 @@ impl DataStore for MemoryDataStore {
             .keys()
             // Make sure the data keys start with the given prefix.
             .filter(|k| k.name().starts_with(prefix.as_ref()))
+            .filter(|k| !hidden_from_listing(k, prefix.as_ref()))
             .cloned()
             .collect())
     }
@@ -62,15 +98,20 @@ impl DataStore for MemoryDataStore {
         &self,
         prefix: S1,
         metadata_key_name: &Option<S2>,
+        committed: &Committed,
     ) -> Result<HashMap<Key, HashSet<Key>>>
     where
         S1: AsRef<str>,
         S2: AsRef<str>,
     {
+        let empty = HashMap::new();
+        let metadataset = self.metadataset(committed).unwrap_or(&empty);
         let mut result = HashMap::new();
-        for (data_key, meta_map) in self.metadata.iter() {
+        for (data_key, meta_map) in metadataset.iter() {
             // Confirm data key matches requested prefix.
-            if !data_key.name().starts_with(prefix.as_ref()) {
+            if !data_key.name().starts_with(prefix.as_ref())
+                || hidden_from_listing(data_key, prefix.as_ref())
+            {
                 continue;
             }
 
@@ -102,22 +143,39 @@ impl DataStore for MemoryDataStore {
     fn set_key<S: AsRef<str>>(&mut self, key: &Key, value: S, committed: &Committed) -> Result<()> {
         self.dataset_mut(committed)
             .insert(key.clone(), value.as_ref().to_owned());
+        self.mtimes_mut(committed).insert(key.clone(), SystemTime::now());
         Ok(())
     }
 
     fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()> {
         self.dataset_mut(committed).remove(key);
+        self.mtimes_mut(committed).remove(key);
         Ok(())
     }
 
+    fn get_key_mtime(&self, key: &Key, committed: &Committed) -> Result<Option<SystemTime>> {
+        let mtimes = match committed {
+            Committed::Live => Some(&self.live_mtimes),
+            Committed::Pending { tx } => self.pending_mtimes.get(tx),
+        };
+        Ok(mtimes.and_then(|mtimes| mtimes.get(key)).copied())
+    }
+
     fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool> {
         let empty = HashMap::new();
         let dataset = self.dataset(committed).unwrap_or(&empty);
         Ok(dataset.contains_key(key))
     }
 
-    fn get_metadata_raw(&self, metadata_key: &Key, data_key: &Key) -> Result<Option<String>> {
-        let metadata_for_data = self.metadata.get(data_key);
+    fn get_metadata_raw(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        let metadata_for_data = self
+            .metadataset(committed)
+            .and_then(|metadataset| metadataset.get(data_key));
         // If we have a metadata entry for this data key, then we can try fetching the requested
         // metadata key, otherwise we'll return early with Ok(None).
         let result = metadata_for_data.and_then(|m| m.get(metadata_key));
@@ -129,10 +187,11 @@ impl DataStore for MemoryDataStore {
         metadata_key: &Key,
         data_key: &Key,
         value: S,
+        committed: &Committed,
     ) -> Result<()> {
         // If we don't already have a metadata entry for this data key, insert one.
         let metadata_for_data = self
-            .metadata
+            .metadataset_mut(committed)
             // Clone data key because we want the HashMap key type to be Key, not &Key, and we
             // can't pass ownership because we only have a reference from our parameters.
             .entry(data_key.clone())
@@ -142,22 +201,57 @@ impl DataStore for MemoryDataStore {
         Ok(())
     }
 
-    fn unset_metadata(&mut self, metadata_key: &Key, data_key: &Key) -> Result<()> {
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
         // If we have any metadata for this data key, remove the given metadata key.
-        if let Some(metadata_for_data) = self.metadata.get_mut(data_key) {
+        if let Some(metadata_for_data) = self.metadataset_mut(committed).get_mut(data_key) {
             metadata_for_data.remove(metadata_key);
         }
         Ok(())
     }
 
+    fn list_metadata(&self, data_key: &Key, committed: &Committed) -> Result<HashSet<Key>> {
+        Ok(self
+            .metadataset(committed)
+            .and_then(|metadataset| metadataset.get(data_key))
+            .map(|m| m.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
     fn commit_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
     where
         S: Into<String> + AsRef<str>,
     {
+        // Apply any pending metadata for this transaction to live
+        if let Some(pending_metadata) = self.pending_metadata.remove(transaction.as_ref()) {
+            for (data_key, metadata) in pending_metadata {
+                for (metadata_key, value) in metadata {
+                    self.set_metadata(&metadata_key, &data_key, value, &Committed::Live)?;
+                }
+            }
+        }
+
         // Remove anything pending for this transaction
         if let Some(pending) = self.pending.remove(transaction.as_ref()) {
-            // Apply pending changes to live
-            self.set_keys(&pending, &Committed::Live)?;
+            // Apply pending changes to live, keeping the time they were written to pending
+            let mut pending_mtimes = self
+                .pending_mtimes
+                .remove(transaction.as_ref())
+                .unwrap_or_default();
+            for (key, value) in &pending {
+                if value == UNSET_VALUE {
+                    self.unset_key(key, &Committed::Live)?;
+                } else {
+                    self.set_key(key, value, &Committed::Live)?;
+                    if let Some(mtime) = pending_mtimes.remove(key) {
+                        self.live_mtimes.insert(key.clone(), mtime);
+                    }
+                }
+            }
             // Return keys that were committed
             Ok(pending.keys().cloned().collect())
         } else {
@@ -165,10 +259,84 @@ impl DataStore for MemoryDataStore {
         }
     }
 
+    fn commit_keys<S>(&mut self, transaction: S, keys: &HashSet<Key>) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        let transaction = transaction.as_ref();
+        let empty = HashMap::new();
+        let pending = self.pending.get(transaction).unwrap_or(&empty);
+
+        let mut missing: Vec<String> = keys
+            .iter()
+            .filter(|k| !pending.contains_key(k))
+            .map(|k| k.name().to_string())
+            .collect();
+        missing.sort();
+        ensure!(
+            missing.is_empty(),
+            error::KeysNotPending {
+                transaction,
+                keys: missing,
+            }
+        );
+
+        for key in keys {
+            // Move the data, and any metadata for it, from pending to live.
+            if let Some(value) = self
+                .pending
+                .get_mut(transaction)
+                .and_then(|pending| pending.remove(key))
+            {
+                if value == UNSET_VALUE {
+                    self.unset_key(key, &Committed::Live)?;
+                } else {
+                    self.live.insert(key.clone(), value);
+                }
+            }
+            if let Some(mtime) = self
+                .pending_mtimes
+                .get_mut(transaction)
+                .and_then(|pending_mtimes| pending_mtimes.remove(key))
+            {
+                if self.live.contains_key(key) {
+                    self.live_mtimes.insert(key.clone(), mtime);
+                }
+            }
+            if let Some(metadata) = self
+                .pending_metadata
+                .get_mut(transaction)
+                .and_then(|pending_metadata| pending_metadata.remove(key))
+            {
+                for (metadata_key, value) in metadata {
+                    self.set_metadata(&metadata_key, key, value, &Committed::Live)?;
+                }
+            }
+        }
+
+        // Don't leave behind an empty transaction.
+        if self.pending.get(transaction).map_or(false, |p| p.is_empty()) {
+            self.pending.remove(transaction);
+            self.pending_mtimes.remove(transaction);
+        }
+        if self
+            .pending_metadata
+            .get(transaction)
+            .map_or(false, |p| p.is_empty())
+        {
+            self.pending_metadata.remove(transaction);
+        }
+
+        Ok(keys.clone())
+    }
+
     fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
     where
         S: Into<String> + AsRef<str>,
     {
+        self.pending_metadata.remove(transaction.as_ref());
+        self.pending_mtimes.remove(transaction.as_ref());
+
         // Remove anything pending for this transaction
         if let Some(pending) = self.pending.remove(transaction.as_ref()) {
             // Return the old pending keys
@@ -179,16 +347,36 @@ impl DataStore for MemoryDataStore {
     }
 
     fn list_transactions(&self) -> Result<HashSet<String>> {
-        Ok(self.pending.keys().cloned().collect())
+        // Unsetting pending keys can leave behind empty maps; those aren't transactions.
+        let data_transactions = self
+            .pending
+            .iter()
+            .filter(|(_, data)| !data.is_empty())
+            .map(|(tx, _)| tx);
+        let metadata_transactions = self
+            .pending_metadata
+            .iter()
+            .filter(|(_, metadata)| metadata.values().any(|m| !m.is_empty()))
+            .map(|(tx, _)| tx);
+        Ok(data_transactions
+            .chain(metadata_transactions)
+            .cloned()
+            .collect())
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::super::{Committed, DataStore, Key, KeyType};
+    use super::super::test_helpers::run_conformance_tests;
     use super::MemoryDataStore;
     use maplit::hashset;
 
+    #[test]
+    fn conformance() {
+        run_conformance_tests(MemoryDataStore::new);
+    }
+
     #[test]
     fn get_set_unset() {
         let mut m = MemoryDataStore::new();
@@ -199,14 +387,23 @@ mod test {
 
         let mdkey = Key::new(KeyType::Meta, "testmd").unwrap();
         let md = "mdval";
-        m.set_metadata(&mdkey, &k, md).unwrap();
+        m.set_metadata(&mdkey, &k, md, &Committed::Live).unwrap();
         assert_eq!(
-            m.get_metadata_raw(&mdkey, &k).unwrap(),
+            m.get_metadata_raw(&mdkey, &k, &Committed::Live).unwrap(),
             Some(md.to_string())
         );
 
-        m.unset_metadata(&mdkey, &k).unwrap();
-        assert_eq!(m.get_metadata_raw(&mdkey, &k).unwrap(), None);
+        assert_eq!(
+            m.list_metadata(&k, &Committed::Live).unwrap(),
+            hashset!(mdkey.clone())
+        );
+
+        m.unset_metadata(&mdkey, &k, &Committed::Live).unwrap();
+        assert_eq!(
+            m.get_metadata_raw(&mdkey, &k, &Committed::Live).unwrap(),
+            None
+        );
+        assert!(m.list_metadata(&k, &Committed::Live).unwrap().is_empty());
 
         m.unset_key(&k, &Committed::Live).unwrap();
         assert_eq!(m.get_key(&k, &Committed::Live).unwrap(), None);
@@ -248,6 +445,81 @@ mod test {
         assert!(m.key_populated(&k, &Committed::Live).unwrap());
     }
 
+    #[test]
+    fn transactions_are_isolated() {
+        let mut m = MemoryDataStore::new();
+        let k = Key::new(KeyType::Data, "settings.a").unwrap();
+        let tx1 = Committed::Pending { tx: "tx1".into() };
+        let tx2 = Committed::Pending { tx: "tx2".into() };
+        m.set_key(&k, "one", &tx1).unwrap();
+        m.set_key(&k, "two", &tx2).unwrap();
+        assert_eq!(
+            m.list_transactions().unwrap(),
+            hashset!("tx1".to_string(), "tx2".to_string())
+        );
+
+        m.commit_transaction("tx1").unwrap();
+        assert_eq!(
+            m.get_key(&k, &Committed::Live).unwrap(),
+            Some("one".to_string())
+        );
+        assert_eq!(m.get_key(&k, &tx2).unwrap(), Some("two".to_string()));
+        assert_eq!(m.list_transactions().unwrap(), hashset!("tx2".to_string()));
+    }
+
+    #[test]
+    fn commit_metadata() {
+        let mut m = MemoryDataStore::new();
+        let k = Key::new(KeyType::Data, "settings.a.b.c").unwrap();
+        let mdkey = Key::new(KeyType::Meta, "testmd").unwrap();
+        let md = "mdval";
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        m.set_metadata(&mdkey, &k, md, &pending).unwrap();
+
+        // Pending metadata is invisible to live reads until commit
+        assert_eq!(
+            m.get_metadata_raw(&mdkey, &k, &pending).unwrap(),
+            Some(md.to_string())
+        );
+        assert_eq!(
+            m.get_metadata_raw(&mdkey, &k, &Committed::Live).unwrap(),
+            None
+        );
+
+        m.commit_transaction(tx).unwrap();
+        assert_eq!(m.get_metadata_raw(&mdkey, &k, &pending).unwrap(), None);
+        assert_eq!(
+            m.get_metadata_raw(&mdkey, &k, &Committed::Live).unwrap(),
+            Some(md.to_string())
+        );
+    }
+
+    #[test]
+    fn commit_keys() {
+        let mut m = MemoryDataStore::new();
+        let k1 = Key::new(KeyType::Data, "settings.a").unwrap();
+        let k2 = Key::new(KeyType::Data, "settings.b").unwrap();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        m.set_key(&k1, "value1", &pending).unwrap();
+        m.set_key(&k2, "value2", &pending).unwrap();
+
+        // Asking for a key that isn't pending fails, and commits nothing
+        let bogus = Key::new(KeyType::Data, "settings.c").unwrap();
+        m.commit_keys(tx, &hashset!(k1.clone(), bogus)).unwrap_err();
+        assert!(m.key_populated(&k1, &pending).unwrap());
+        assert!(!m.key_populated(&k1, &Committed::Live).unwrap());
+
+        // Committing one key leaves the other pending
+        let committed = m.commit_keys(tx, &hashset!(k1.clone())).unwrap();
+        assert_eq!(committed, hashset!(k1.clone()));
+        assert!(m.key_populated(&k1, &Committed::Live).unwrap());
+        assert!(!m.key_populated(&k1, &pending).unwrap());
+        assert!(m.key_populated(&k2, &pending).unwrap());
+        assert!(!m.key_populated(&k2, &Committed::Live).unwrap());
+    }
+
     #[test]
     fn delete_transaction() {
         let mut m = MemoryDataStore::new();
@@ -15,14 +15,17 @@ pub mod filesystem;
 pub mod key;
 pub mod memory;
 pub mod serialization;
+#[cfg(test)]
+mod test_helpers;
 
 pub use error::{Error, Result};
-pub use filesystem::FilesystemDataStore;
+pub use filesystem::{ChecksumMode, FilesystemDataStore, LockMode};
 pub use key::{Key, KeyType, KEY_SEPARATOR, KEY_SEPARATOR_STR};
 
 use serde::{Deserialize, Serialize};
 use snafu::OptionExt;
 use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
 
 /// Committed represents whether we want to look at pending (uncommitted) or live (committed) data
 /// in the datastore.
@@ -35,11 +38,30 @@ pub enum Committed {
     },
 }
 
+/// A pending data key with this value is removed from live when its transaction is committed,
+/// rather than being set.  This lets us stage deletions alongside other changes.  It's the
+/// serialized form of a null value, which the model treats the same as an absent value.
+pub const UNSET_VALUE: &str = "null";
+
+/// Data keys under this top-level segment are reserved for copies of settings, e.g.
+/// "snapshots.before-upgrade.settings.motd".  They're only listed when the requested prefix
+/// starts with this segment, so listing everything, or every key starting with "s", doesn't
+/// include them.
+pub const SNAPSHOTS_SEGMENT: &str = "snapshots";
+
+/// Returns whether the given data key is in a reserved area that isn't included in listings of
+/// keys starting with the given prefix.  See SNAPSHOTS_SEGMENT.
+fn hidden_from_listing(key: &Key, prefix: &str) -> bool {
+    key.segments().first().map(String::as_str) == Some(SNAPSHOTS_SEGMENT)
+        && !prefix.starts_with(SNAPSHOTS_SEGMENT)
+}
+
 pub trait DataStore {
     /// Returns whether a key is present (has a value) in the datastore.
     fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool>;
     /// Returns a list of the populated data keys in the datastore whose names start with the given
-    /// prefix.
+    /// prefix.  Keys in the reserved snapshots area are only included if the prefix asks for
+    /// them; see SNAPSHOTS_SEGMENT.
     fn list_populated_keys<S: AsRef<str>>(
         &self,
         prefix: S,
@@ -54,6 +76,7 @@ pub trait DataStore {
         &self,
         prefix: S1,
         metadata_key_name: &Option<S2>,
+        committed: &Committed,
     ) -> Result<HashMap<Key, HashSet<Key>>>
     where
         S1: AsRef<str>,
@@ -61,16 +84,43 @@ pub trait DataStore {
 
     /// Retrieve the value for a single data key from the datastore.
     fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>>;
+    /// Retrieve the values for multiple data keys from the datastore.  Each requested key is
+    /// present in the returned map; its value is None if the key isn't populated.
+    ///
+    /// Implementers can replace the default implementation if there's a faster way than getting
+    /// each key individually.
+    fn get_keys(
+        &self,
+        keys: &HashSet<Key>,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, Option<String>>> {
+        let mut result = HashMap::with_capacity(keys.len());
+        for key in keys {
+            trace!("Pulling value from datastore for key: {}", key);
+            result.insert(key.clone(), self.get_key(key, committed)?);
+        }
+        Ok(result)
+    }
     /// Set the value of a single data key in the datastore.
     fn set_key<S: AsRef<str>>(&mut self, key: &Key, value: S, committed: &Committed) -> Result<()>;
     /// Removes the given data key from the datastore.  If we succeeded, we return Ok(()); if
     /// the key didn't exist, we also return Ok(()); we return Err only if we failed to check
     /// or remove the key.
     fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()>;
+    /// Returns the time the given data key was last written, or None if it's not populated.
+    ///
+    /// Committing a transaction preserves the time each key was written to the transaction,
+    /// rather than using the time of the commit.
+    fn get_key_mtime(&self, key: &Key, committed: &Committed) -> Result<Option<SystemTime>>;
 
     /// Retrieve the value for a single metadata key from the datastore.  Values will inherit from
     /// earlier in the tree, if more specific values are not found later.
-    fn get_metadata(&self, metadata_key: &Key, data_key: &Key) -> Result<Option<String>> {
+    fn get_metadata(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
         let mut result = Ok(None);
         let mut current_path = Vec::new();
 
@@ -82,7 +132,7 @@ pub trait DataStore {
                 unreachable!("Prefix of Key failed to make Key: {:?}", current_path)
             });
 
-            if let Some(md) = self.get_metadata_raw(metadata_key, &data_key)? {
+            if let Some(md) = self.get_metadata_raw(metadata_key, &data_key, committed)? {
                 result = Ok(Some(md));
             }
         }
@@ -91,25 +141,51 @@ pub trait DataStore {
 
     /// Retrieve the value for a single metadata key from the datastore, without taking into
     /// account inheritance of metadata from earlier in the tree.
-    fn get_metadata_raw(&self, metadata_key: &Key, data_key: &Key) -> Result<Option<String>>;
+    fn get_metadata_raw(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>>;
     /// Set the value of a single metadata key in the datastore.
     fn set_metadata<S: AsRef<str>>(
         &mut self,
         metadata_key: &Key,
         data_key: &Key,
         value: S,
+        committed: &Committed,
     ) -> Result<()>;
     /// Removes the given metadata key from the given data key in the datastore.  If we
     /// succeeded, we return Ok(()); if the data or metadata key didn't exist, we also return
     /// Ok(()); we return Err only if we failed to check or remove the key.
-    fn unset_metadata(&mut self, metadata_key: &Key, data_key: &Key) -> Result<()>;
-
-    /// Applies pending changes from the given transaction to the live datastore.  Returns the
-    /// list of changed keys.
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()>;
+    /// Returns the names of all metadata keys set directly on the given data key.  Metadata
+    /// inherited from earlier in the tree is not included.  If the data key has no metadata, we
+    /// return Ok with an empty set.
+    fn list_metadata(&self, data_key: &Key, committed: &Committed) -> Result<HashSet<Key>>;
+
+    /// Applies pending changes from the given transaction to the live datastore, including any
+    /// pending metadata.  Pending keys set to UNSET_VALUE are removed from live.  Returns the list
+    /// of changed data keys, including removed keys.
     fn commit_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
     where
         S: Into<String> + AsRef<str>;
 
+    /// Applies pending changes for only the given keys from the given transaction to the live
+    /// datastore, leaving any other changes pending in the transaction.  Pending metadata for the
+    /// given keys is committed with them.  Returns the list of committed keys.
+    ///
+    /// Fails without committing anything if any of the given keys aren't pending in the
+    /// transaction.
+    fn commit_keys<S>(&mut self, transaction: S, keys: &HashSet<Key>) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>;
+
     /// Remove the given pending transaction from the datastore.  Returns the list of removed
     /// keys.  If the transaction doesn't exist, will return Ok with an empty list.
     fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
@@ -119,6 +195,14 @@ pub trait DataStore {
     /// Returns a list of the names of any pending transactions in the data store.
     fn list_transactions(&self) -> Result<HashSet<String>>;
 
+    /// Checks that the data store is set up and usable, e.g. that its storage exists, so problems
+    /// can be reported before a request runs into them.
+    ///
+    /// The default implementation has nothing to check.
+    fn check_available(&self) -> Result<()> {
+        Ok(())
+    }
+
     /// Set multiple data keys at once in the data store.
     ///
     /// Implementers can replace the default implementation if there's a faster way than setting
@@ -160,13 +244,9 @@ pub trait DataStore {
         }
 
         let mut result = HashMap::new();
-        for key in keys {
+        for (key, value) in self.get_keys(&keys, committed)? {
             // Already confirmed key via listing keys, so an error is more serious.
-            trace!("Pulling value from datastore for key: {}", key);
-            let value = self
-                .get_key(&key, committed)?
-                .context(error::ListedKeyNotPresent { key: key.name() })?;
-
+            let value = value.context(error::ListedKeyNotPresent { key: key.name() })?;
             result.insert(key, value);
         }
         Ok(result)
@@ -180,12 +260,13 @@ pub trait DataStore {
         &self,
         find_prefix: S1,
         metadata_key_name: &Option<S2>,
+        committed: &Committed,
     ) -> Result<HashMap<Key, HashMap<Key, String>>>
     where
         S1: AsRef<str>,
         S2: AsRef<str>,
     {
-        let meta_map = self.list_populated_metadata(&find_prefix, metadata_key_name)?;
+        let meta_map = self.list_populated_metadata(&find_prefix, metadata_key_name, committed)?;
         trace!("Found populated metadata: {:?}", meta_map);
         if meta_map.is_empty() {
             return Ok(HashMap::new());
@@ -208,7 +289,7 @@ pub trait DataStore {
                     meta_key,
                     &data_key
                 );
-                let value = self.get_metadata(&meta_key, &data_key)?.context(
+                let value = self.get_metadata(&meta_key, &data_key, committed)?.context(
                     error::ListedMetaNotPresent {
                         meta_key: meta_key.name(),
                         data_key: data_key.name(),
@@ -310,14 +391,19 @@ mod test {
         let grandchild = Key::new(KeyType::Data, "a.b.c").unwrap();
 
         // Set metadata on parent
-        m.set_metadata(&meta, &parent, "value").unwrap();
+        m.set_metadata(&meta, &parent, "value", &Committed::Live)
+            .unwrap();
         // Metadata shows up on grandchild...
         assert_eq!(
-            m.get_metadata(&meta, &grandchild).unwrap(),
+            m.get_metadata(&meta, &grandchild, &Committed::Live).unwrap(),
             Some("value".to_string())
         );
         // ...but only through inheritance, not directly.
-        assert_eq!(m.get_metadata_raw(&meta, &grandchild).unwrap(), None);
+        assert_eq!(
+            m.get_metadata_raw(&meta, &grandchild, &Committed::Live)
+                .unwrap(),
+            None
+        );
     }
 
     #[test]
@@ -339,6 +425,22 @@ mod test {
         );
     }
 
+    #[test]
+    fn get_keys() {
+        let mut m = MemoryDataStore::new();
+        let k1 = Key::new(KeyType::Data, "x.1").unwrap();
+        let k2 = Key::new(KeyType::Data, "x.2").unwrap();
+        let missing = Key::new(KeyType::Data, "x.3").unwrap();
+        m.set_key(&k1, "x1", &Committed::Live).unwrap();
+        m.set_key(&k2, "x2", &Committed::Live).unwrap();
+
+        assert_eq!(
+            m.get_keys(&hashset!(k1.clone(), missing.clone()), &Committed::Live)
+                .unwrap(),
+            hashmap!(k1 => Some("x1".to_string()), missing => None)
+        );
+    }
+
     #[test]
     fn get_metadata_prefix() {
         let mut m = MemoryDataStore::new();
@@ -353,20 +455,22 @@ mod test {
         let mk1 = Key::new(KeyType::Meta, "metatest1").unwrap();
         let mk2 = Key::new(KeyType::Meta, "metatest2").unwrap();
         let mk3 = Key::new(KeyType::Meta, "metatest3").unwrap();
-        m.set_metadata(&mk1, &k1, "41").unwrap();
-        m.set_metadata(&mk2, &k2, "42").unwrap();
-        m.set_metadata(&mk3, &k3, "43").unwrap();
+        m.set_metadata(&mk1, &k1, "41", &Committed::Live).unwrap();
+        m.set_metadata(&mk2, &k2, "42", &Committed::Live).unwrap();
+        m.set_metadata(&mk3, &k3, "43", &Committed::Live).unwrap();
 
         // Check all metadata
         assert_eq!(
-            m.get_metadata_prefix("x.", &None as &Option<&str>).unwrap(),
+            m.get_metadata_prefix("x.", &None as &Option<&str>, &Committed::Live)
+                .unwrap(),
             hashmap!(k1 => hashmap!(mk1 => "41".to_string()),
                      k2.clone() => hashmap!(mk2.clone() => "42".to_string()))
         );
 
         // Check metadata matching a given name
         assert_eq!(
-            m.get_metadata_prefix("x.", &Some("metatest2")).unwrap(),
+            m.get_metadata_prefix("x.", &Some("metatest2"), &Committed::Live)
+                .unwrap(),
             hashmap!(k2 => hashmap!(mk2 => "42".to_string()))
         );
     }
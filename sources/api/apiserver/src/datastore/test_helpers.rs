@@ -0,0 +1,426 @@
+//! This module contains a conformance test suite that any DataStore implementation should pass,
+//! so that the implementations don't drift apart in behavior.
+//!
+//! Implementations call `run_conformance_tests` from their own tests with a factory that returns
+//! a new, empty data store each time it's called.
+
+use maplit::{hashmap, hashset};
+use std::collections::HashSet;
+use std::thread;
+use std::time::Duration;
+
+use super::{Committed, DataStore, Key, KeyType, UNSET_VALUE};
+
+/// Runs the full conformance suite against data stores made by the given factory.  Each test gets
+/// a fresh data store.
+pub(crate) fn run_conformance_tests<D, F>(factory: F)
+where
+    D: DataStore,
+    F: Fn() -> D,
+{
+    set_get_unset(factory());
+    overwrite(factory());
+    empty_value(factory());
+    get_keys(factory());
+    list_prefixes(factory());
+    metadata(factory());
+    commit(factory());
+    commit_metadata(factory());
+    commit_keys(factory());
+    delete_transaction(factory());
+    empty_transactions(factory());
+    mtimes(factory());
+    commit_unset(factory());
+    reserved_snapshots(factory());
+}
+
+fn data_key(name: &str) -> Key {
+    Key::new(KeyType::Data, name).unwrap()
+}
+
+fn meta_key(name: &str) -> Key {
+    Key::new(KeyType::Meta, name).unwrap()
+}
+
+fn pending(tx: &str) -> Committed {
+    Committed::Pending { tx: tx.into() }
+}
+
+fn set_get_unset<D: DataStore>(mut ds: D) {
+    let k = data_key("settings.a.b");
+    for committed in &[Committed::Live, pending("tx")] {
+        assert!(!ds.key_populated(&k, committed).unwrap());
+        assert_eq!(ds.get_key(&k, committed).unwrap(), None);
+
+        ds.set_key(&k, "\"value\"", committed).unwrap();
+        assert!(ds.key_populated(&k, committed).unwrap());
+        assert_eq!(
+            ds.get_key(&k, committed).unwrap(),
+            Some("\"value\"".to_string())
+        );
+
+        ds.unset_key(&k, committed).unwrap();
+        assert!(!ds.key_populated(&k, committed).unwrap());
+        assert_eq!(ds.get_key(&k, committed).unwrap(), None);
+
+        // Unsetting a missing key is OK
+        ds.unset_key(&k, committed).unwrap();
+    }
+
+    // Pending and live are separate
+    ds.set_key(&k, "\"pending\"", &pending("tx")).unwrap();
+    assert!(!ds.key_populated(&k, &Committed::Live).unwrap());
+    assert!(!ds.key_populated(&k, &pending("other")).unwrap());
+}
+
+fn overwrite<D: DataStore>(mut ds: D) {
+    let k = data_key("settings.a");
+    let live = &Committed::Live;
+    ds.set_key(&k, "\"first\"", live).unwrap();
+    ds.set_key(&k, "\"second\"", live).unwrap();
+    assert_eq!(
+        ds.get_key(&k, live).unwrap(),
+        Some("\"second\"".to_string())
+    );
+    assert_eq!(ds.list_populated_keys("", live).unwrap(), hashset!(k));
+}
+
+fn empty_value<D: DataStore>(mut ds: D) {
+    let k = data_key("settings.a");
+    let live = &Committed::Live;
+    ds.set_key(&k, "", live).unwrap();
+    assert!(ds.key_populated(&k, live).unwrap());
+    assert_eq!(ds.get_key(&k, live).unwrap(), Some("".to_string()));
+}
+
+fn get_keys<D: DataStore>(mut ds: D) {
+    let k1 = data_key("settings.a");
+    let k2 = data_key("settings.b");
+    let live = &Committed::Live;
+    ds.set_key(&k1, "\"a\"", live).unwrap();
+
+    let keys = hashset!(k1.clone(), k2.clone());
+    assert_eq!(
+        ds.get_keys(&keys, live).unwrap(),
+        hashmap!(k1 => Some("\"a\"".to_string()), k2 => None)
+    );
+    assert!(ds.get_keys(&HashSet::new(), live).unwrap().is_empty());
+}
+
+fn list_prefixes<D: DataStore>(mut ds: D) {
+    let live = &Committed::Live;
+    let motd = data_key("settings.motd");
+    let motd_extra = data_key("settings.motd-extra");
+    let inner = data_key("settings.nested.inner");
+    let service = data_key("services.foo.restart-commands");
+    for key in &[&motd, &motd_extra, &inner, &service] {
+        ds.set_key(key, "\"x\"", live).unwrap();
+    }
+
+    // Empty prefix finds everything
+    assert_eq!(
+        ds.list_populated_keys("", live).unwrap(),
+        hashset!(
+            motd.clone(),
+            motd_extra.clone(),
+            inner.clone(),
+            service.clone()
+        )
+    );
+    // Whole segments
+    assert_eq!(
+        ds.list_populated_keys("settings.", live).unwrap(),
+        hashset!(motd.clone(), motd_extra.clone(), inner.clone())
+    );
+    assert_eq!(
+        ds.list_populated_keys("settings.nested", live).unwrap(),
+        hashset!(inner)
+    );
+    // Exact key, which is also a prefix of another key
+    assert_eq!(
+        ds.list_populated_keys("settings.motd", live).unwrap(),
+        hashset!(motd.clone(), motd_extra.clone())
+    );
+    // Partial segment
+    assert_eq!(
+        ds.list_populated_keys("settings.mo", live).unwrap(),
+        hashset!(motd, motd_extra)
+    );
+    assert_eq!(
+        ds.list_populated_keys("serv", live).unwrap(),
+        hashset!(service)
+    );
+    // No matches
+    assert!(ds.list_populated_keys("bogus", live).unwrap().is_empty());
+    assert!(ds
+        .list_populated_keys("settings.bogus.", live)
+        .unwrap()
+        .is_empty());
+    // Nothing pending
+    assert!(ds
+        .list_populated_keys("", &pending("tx"))
+        .unwrap()
+        .is_empty());
+}
+
+fn metadata<D: DataStore>(mut ds: D) {
+    let k = data_key("settings.a.b");
+    let parent = data_key("settings.a");
+    let mk1 = meta_key("meta1");
+    let mk2 = meta_key("meta2");
+    for committed in &[Committed::Live, pending("tx")] {
+        assert_eq!(ds.get_metadata_raw(&mk1, &k, committed).unwrap(), None);
+        assert!(ds.list_metadata(&k, committed).unwrap().is_empty());
+
+        // Metadata doesn't require the data key to be populated
+        ds.set_metadata(&mk1, &k, "\"m1\"", committed).unwrap();
+        ds.set_metadata(&mk2, &k, "\"m2\"", committed).unwrap();
+        ds.set_metadata(&mk1, &parent, "\"parent\"", committed)
+            .unwrap();
+        assert!(!ds.key_populated(&k, committed).unwrap());
+        assert_eq!(
+            ds.get_metadata_raw(&mk1, &k, committed).unwrap(),
+            Some("\"m1\"".to_string())
+        );
+        assert_eq!(
+            ds.list_metadata(&k, committed).unwrap(),
+            hashset!(mk1.clone(), mk2.clone())
+        );
+        assert_eq!(
+            ds.list_populated_metadata("settings.a.", &None as &Option<&str>, committed)
+                .unwrap(),
+            hashmap!(k.clone() => hashset!(mk1.clone(), mk2.clone()))
+        );
+        assert_eq!(
+            ds.list_populated_metadata("", &Some("meta1"), committed)
+                .unwrap(),
+            hashmap!(k.clone() => hashset!(mk1.clone()), parent.clone() => hashset!(mk1.clone()))
+        );
+
+        ds.unset_metadata(&mk1, &k, committed).unwrap();
+        assert_eq!(ds.get_metadata_raw(&mk1, &k, committed).unwrap(), None);
+        assert_eq!(
+            ds.list_metadata(&k, committed).unwrap(),
+            hashset!(mk2.clone())
+        );
+        // Unsetting missing metadata is OK
+        ds.unset_metadata(&mk1, &k, committed).unwrap();
+    }
+
+    // Metadata is inherited from parent keys
+    let live = &Committed::Live;
+    let child = data_key("settings.a.c");
+    assert_eq!(
+        ds.get_metadata(&mk1, &child, live).unwrap(),
+        Some("\"parent\"".to_string())
+    );
+}
+
+fn commit<D: DataStore>(mut ds: D) {
+    let k1 = data_key("settings.a");
+    let k2 = data_key("settings.b.c");
+    let live = &Committed::Live;
+    ds.set_key(&k1, "\"old\"", live).unwrap();
+    ds.set_key(&k1, "\"new\"", &pending("tx")).unwrap();
+    ds.set_key(&k2, "\"b\"", &pending("tx")).unwrap();
+    ds.set_key(&k2, "\"other\"", &pending("other")).unwrap();
+    assert_eq!(
+        ds.list_transactions().unwrap(),
+        hashset!("tx".to_string(), "other".to_string())
+    );
+
+    assert_eq!(
+        ds.commit_transaction("tx").unwrap(),
+        hashset!(k1.clone(), k2.clone())
+    );
+    assert_eq!(ds.get_key(&k1, live).unwrap(), Some("\"new\"".to_string()));
+    assert_eq!(ds.get_key(&k2, live).unwrap(), Some("\"b\"".to_string()));
+    assert!(ds
+        .list_populated_keys("", &pending("tx"))
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        ds.list_transactions().unwrap(),
+        hashset!("other".to_string())
+    );
+
+    // Committing an empty or missing transaction does nothing
+    assert!(ds.commit_transaction("tx").unwrap().is_empty());
+    assert_eq!(ds.get_key(&k2, live).unwrap(), Some("\"b\"".to_string()));
+}
+
+fn commit_metadata<D: DataStore>(mut ds: D) {
+    let k = data_key("settings.a");
+    let mk = meta_key("meta");
+    ds.set_key(&k, "\"a\"", &pending("tx")).unwrap();
+    ds.set_metadata(&mk, &k, "\"m\"", &pending("tx")).unwrap();
+
+    ds.commit_transaction("tx").unwrap();
+    assert_eq!(
+        ds.get_metadata_raw(&mk, &k, &Committed::Live).unwrap(),
+        Some("\"m\"".to_string())
+    );
+    assert_eq!(ds.get_metadata_raw(&mk, &k, &pending("tx")).unwrap(), None);
+    assert!(ds.list_transactions().unwrap().is_empty());
+}
+
+fn commit_keys<D: DataStore>(mut ds: D) {
+    let k1 = data_key("settings.a");
+    let k2 = data_key("settings.b");
+    let live = &Committed::Live;
+    ds.set_key(&k1, "\"a\"", &pending("tx")).unwrap();
+    ds.set_key(&k2, "\"b\"", &pending("tx")).unwrap();
+
+    // Keys that aren't pending are rejected, and nothing is committed
+    let keys = hashset!(k1.clone(), data_key("settings.c"));
+    ds.commit_keys("tx", &keys).unwrap_err();
+    assert!(!ds.key_populated(&k1, live).unwrap());
+
+    let keys = hashset!(k1.clone());
+    assert_eq!(ds.commit_keys("tx", &keys).unwrap(), keys);
+    assert!(ds.key_populated(&k1, live).unwrap());
+    assert!(!ds.key_populated(&k1, &pending("tx")).unwrap());
+    assert!(ds.key_populated(&k2, &pending("tx")).unwrap());
+    assert!(!ds.key_populated(&k2, live).unwrap());
+
+    // Committing the last key finishes the transaction
+    ds.commit_keys("tx", &hashset!(k2)).unwrap();
+    assert!(ds.list_transactions().unwrap().is_empty());
+}
+
+fn delete_transaction<D: DataStore>(mut ds: D) {
+    let k = data_key("settings.a");
+    let mk = meta_key("meta");
+    ds.set_key(&k, "\"a\"", &pending("tx")).unwrap();
+    ds.set_metadata(&mk, &k, "\"m\"", &pending("tx")).unwrap();
+
+    assert_eq!(ds.delete_transaction("tx").unwrap(), hashset!(k.clone()));
+    assert!(!ds.key_populated(&k, &pending("tx")).unwrap());
+    assert_eq!(ds.get_metadata_raw(&mk, &k, &pending("tx")).unwrap(), None);
+    assert!(!ds.key_populated(&k, &Committed::Live).unwrap());
+    assert!(ds.list_transactions().unwrap().is_empty());
+
+    // Deleting a missing transaction is OK
+    assert!(ds.delete_transaction("tx").unwrap().is_empty());
+}
+
+fn empty_transactions<D: DataStore>(mut ds: D) {
+    let k = data_key("settings.a");
+    let mk = meta_key("meta");
+
+    // Unsetting things that aren't pending doesn't create a transaction
+    ds.unset_key(&k, &pending("tx")).unwrap();
+    ds.unset_metadata(&mk, &k, &pending("tx")).unwrap();
+    assert!(ds.list_transactions().unwrap().is_empty());
+
+    // Removing everything from a transaction removes the transaction
+    ds.set_key(&k, "\"a\"", &pending("tx")).unwrap();
+    ds.set_metadata(&mk, &k, "\"m\"", &pending("tx")).unwrap();
+    ds.unset_key(&k, &pending("tx")).unwrap();
+    ds.unset_metadata(&mk, &k, &pending("tx")).unwrap();
+    assert!(ds.list_transactions().unwrap().is_empty());
+}
+
+fn mtimes<D: DataStore>(mut ds: D) {
+    let k1 = data_key("settings.a");
+    let k2 = data_key("settings.b");
+    let live = &Committed::Live;
+    let tx = pending("tx");
+    assert_eq!(ds.get_key_mtime(&k1, live).unwrap(), None);
+
+    ds.set_key(&k1, "\"live\"", live).unwrap();
+    let live_mtime = ds.get_key_mtime(&k1, live).unwrap().unwrap();
+    assert_eq!(ds.get_key_mtime(&k1, &tx).unwrap(), None);
+
+    // Pending writes have their own times, and don't change live
+    thread::sleep(Duration::from_millis(10));
+    ds.set_key(&k1, "\"pending\"", &tx).unwrap();
+    ds.set_key(&k2, "\"pending\"", &tx).unwrap();
+    let pending_mtime1 = ds.get_key_mtime(&k1, &tx).unwrap().unwrap();
+    let pending_mtime2 = ds.get_key_mtime(&k2, &tx).unwrap().unwrap();
+    assert!(pending_mtime1 > live_mtime);
+    assert_eq!(ds.get_key_mtime(&k1, live).unwrap(), Some(live_mtime));
+
+    // Commits keep the time the key was written to the transaction, not the commit time
+    thread::sleep(Duration::from_millis(10));
+    ds.commit_keys("tx", &hashset!(k1.clone())).unwrap();
+    assert_eq!(ds.get_key_mtime(&k1, live).unwrap(), Some(pending_mtime1));
+    ds.commit_transaction("tx").unwrap();
+    assert_eq!(ds.get_key_mtime(&k2, live).unwrap(), Some(pending_mtime2));
+    assert_eq!(ds.get_key_mtime(&k2, &tx).unwrap(), None);
+
+    // Unset keys have no time
+    ds.unset_key(&k1, live).unwrap();
+    assert_eq!(ds.get_key_mtime(&k1, live).unwrap(), None);
+}
+
+fn commit_unset<D: DataStore>(mut ds: D) {
+    let k1 = data_key("settings.a");
+    let k2 = data_key("settings.b.c");
+    let k3 = data_key("settings.d");
+    let missing = data_key("settings.missing");
+    let live = &Committed::Live;
+    let tx = pending("tx");
+    for key in &[&k1, &k2, &k3] {
+        ds.set_key(key, "\"live\"", live).unwrap();
+    }
+
+    // Unsets are pending until committed, and can be mixed with sets
+    ds.set_key(&k1, UNSET_VALUE, &tx).unwrap();
+    ds.set_key(&k2, UNSET_VALUE, &tx).unwrap();
+    ds.set_key(&k3, "\"new\"", &tx).unwrap();
+    ds.set_key(&missing, UNSET_VALUE, &tx).unwrap();
+    assert!(ds.key_populated(&k1, live).unwrap());
+
+    // Committing removes the keys from live, and reports them as changed; unsetting a key that
+    // isn't live is fine
+    ds.commit_keys("tx", &hashset!(k1.clone())).unwrap();
+    assert!(!ds.key_populated(&k1, live).unwrap());
+    assert_eq!(ds.get_key_mtime(&k1, live).unwrap(), None);
+    assert_eq!(
+        ds.commit_transaction("tx").unwrap(),
+        hashset!(k2.clone(), k3.clone(), missing.clone())
+    );
+    assert_eq!(
+        ds.list_populated_keys("", live).unwrap(),
+        hashset!(k3.clone())
+    );
+    assert_eq!(ds.get_key(&k3, live).unwrap(), Some("\"new\"".to_string()));
+    assert_eq!(ds.get_key(&missing, live).unwrap(), None);
+    assert!(ds.list_transactions().unwrap().is_empty());
+}
+
+fn reserved_snapshots<D: DataStore>(mut ds: D) {
+    let live = &Committed::Live;
+    let motd = data_key("settings.motd");
+    let copy = data_key("snapshots.one.settings.motd");
+    let snapshot = data_key("snapshots.one");
+    let time = meta_key("snapshot-time");
+    ds.set_key(&motd, "\"x\"", live).unwrap();
+    ds.set_key(&copy, "\"x\"", live).unwrap();
+    ds.set_metadata(&time, &snapshot, "1", live).unwrap();
+
+    // Snapshots aren't listed unless the prefix asks for them
+    assert_eq!(
+        ds.list_populated_keys("", live).unwrap(),
+        hashset!(motd.clone())
+    );
+    assert_eq!(
+        ds.list_populated_keys("s", live).unwrap(),
+        hashset!(motd.clone())
+    );
+    assert!(ds
+        .list_populated_metadata("", &None as &Option<&str>, live)
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        ds.list_populated_keys("snapshots.", live).unwrap(),
+        hashset!(copy.clone())
+    );
+    assert_eq!(
+        ds.list_populated_metadata("snapshots.", &None as &Option<&str>, live)
+            .unwrap(),
+        hashmap!(snapshot => hashset!(time))
+    );
+    assert_eq!(ds.get_key(&copy, live).unwrap(), Some("\"x\"".to_string()));
+}
@@ -6,6 +6,8 @@ It's intended to be the primary way to read and modify OS settings, to update se
 
 The server listens to HTTP requests on a Unix-domain socket.
 There is no built-in authentication - local access to the socket should be limited to processes and containers that should be able to configure the system.
+The server creates the socket's directory if needed, and replaces a socket left behind by an earlier run.
+For development on hosts without Unix-domain sockets, `--listen-address` serves over TCP instead, but then any local process can use the API.
 Remote access should only be allowed through an authenticated control channel such as SSH or SSM.
 
 # Design
@@ -17,13 +19,41 @@ The interface is documented in [OpenAPI format](https://swagger.io/docs/specific
 
 The Settings APIs are particularly important.
 You can GET settings from the `/settings` endpoint.
+If a setting's stored value can't be read, GET `/settings` fails; to see the rest of the settings anyway, add `lenient=true`, which returns the readable settings under `settings` and lists the unreadable ones, with why, under `skipped`.
+To read just one setting, GET `/settings/value?name=settings.motd`, which returns its value, like `"hello"`, and also accepts `state=pending`.
+GET `/settings/keys` lists the names of populated settings without their values, optionally under a `prefix`; with `depth=1` it lists only the top-level groups, like `settings.ntp`.
+To save a copy of the live settings, for example before making risky changes, POST to `/settings/snapshots?name=before-change`; GET `/settings/snapshots` lists them, and only the 10 most recent are kept.
+POST to `/settings/snapshots/restore?name=before-change` stages the snapshot's settings in a transaction, `default` unless you give `tx`, so committing it returns live settings to how they were.
 You can also PATCH changes to the `/settings` endpoint.
 Settings are stored as a pending transaction until a commit API is called.
 Pending settings can be retrieved from `/tx` to see what will change.
+They're also available as a resource at `/settings/pending`, which can be deleted to discard them, and `/settings/pending/diff` compares them to the live settings.
+GET `/settings` also accepts `state=pending` to read pending settings the same ways as live ones, including by `keys` or `prefix`.
+Both `keys` and `prefix` take comma-separated lists, and can be given together, as in `/settings?keys=settings.motd&prefix=ntp,updates`.
+To get only some fields of the settings, give their dotted paths in `fields`, as in `/settings?fields=motd,host-containers.admin.enabled`.
+To react to changes without polling, GET `/settings/events` for a stream of [server-sent events](https://html.spec.whatwg.org/multipage/server-sent-events.html), one for each commit that changes settings.
+GET `/settings/generation` returns the settings generation, as in `{"generation": 5}`, which increases with each commit that changes settings; reading it before and after other requests tells a client whether settings changed in between.
 
 Upon making a `/tx/commit` POST call, the pending transaction is made live.
 Upon making an `/tx/apply` POST call, an external settings applier tool is called to apply the changes to the system and restart services as necessary.
 There's also `/tx/commit_and_apply` to do both, which is the most common case.
+To change settings in one request, POST them to `/settings/apply`, which stages them, commits just those settings, and waits for the applier, responding with a report of the committed keys, the affected services, and whether the apply succeeded or was rolled back.
+GET `/settings` responses have an ETag header, which a client can send back in If-None-Match to skip fetching unchanged settings, or in If-Match when changing or committing settings to make sure nothing changed since it read them.
+`/health` responds as long as the server is up, and `/ready` checks that the data store is usable, responding 503 if it isn't.
+`/schema` returns an [OpenAPI](https://swagger.io/specification/) description of the API, as JSON, with schemas for settings, services, and configuration files generated from the model, so clients can generate bindings from it.
+Any request can add `pretty=true` to get its JSON response pretty-printed.
+For debugging, GET `/debug/datastore/key?name=settings.motd` returns what the data store holds for a key without deserializing it, so it works even when the value is corrupt; routes under `/debug` are unstable, and only root and callers with the API token may use them.
+Error responses have a JSON body with a stable `code` identifying the kind of error, like `SETTINGS_NOT_FOUND` or `DATASTORE_CORRUPT`, along with a `message` and any structured `details`.
+Each request is logged with its method, path, status, latency, and the number of settings keys it read or wrote, under an ID that's returned in the `X-Request-Id` response header; slow requests are also logged as a warning.
+Settings input is limited in size, and in the number of keys it can set, to protect the server; see `--max-body-bytes` and `--max-keys`.
+GET `/debug/datastore/dump` returns all settings, services, and configuration files, with their metadata under a `metadata` section, as one JSON document, for debugging and backup.
+POSTing such a document back to `/debug/datastore/dump?state=pending` loads it into a transaction, all or nothing, to be committed as usual; keys that are already set are only replaced with `overwrite=true`.
+GET `/debug/datastore/mtimes` returns each setting's value with the time it was last written, to help find out when settings changed.
+If the server is started with `--audit-log`, each committed change is also recorded in that file as a line of JSON with the old and new values.
+On SIGTERM or SIGINT, the server stops accepting connections and refuses new changes, and gives in-flight requests `--drain-timeout-secs` to finish; a commit that has started always finishes before the server exits.
+Access to the socket is limited by its permissions; to also limit who can make changes, start the server with `--write-gid`, which lets only root and processes in that group use PATCH, POST, PUT, or DELETE, based on the peer credentials of their connection.
+Other callers get a 403 response with code `FORBIDDEN`.
+With `--listen-address`, `--api-token-file` gives a token that TCP callers send in the `X-Api-Token` header to make changes.
 
 If you don't specify a transaction, the "default" transaction is used, so you usually don't have to think about it.
 If you want to group changes into transactions yourself, you can add a `tx` parameter to the APIs mentioned above.
@@ -61,7 +91,8 @@ The `datastore::deserialization` module provides code to deserialize datastore-a
 # Current limitations
 
 * Data store locking is coarse; read requests can happen in parallel, but a write request will block everything else.
-* There's no support for rolling back commits.
+* Commits are only rolled back automatically when applying them fails; there's no support for rolling back commits on request.
+  The data store isn't locked while the applier runs, so if another request changes the committed keys in the meantime, the rollback is skipped and reported as failed.
 * There are no metrics.
 * `datastore::serialization` can't handle complex types under lists; it assumes lists can be serialized as scalars.
 
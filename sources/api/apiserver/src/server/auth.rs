@@ -0,0 +1,474 @@
+//! The auth module decides whether the caller of a request may use the route it's requesting,
+//! before any handler runs.  Callers on the Unix-domain socket are identified by the peer
+//! credentials of their connection, and TCP callers by a shared secret in the X-Api-Token header.
+//!
+//! Which callers may use which routes is set by an AuthConfig.  The default config has no rules,
+//! so everything is allowed, as before authorization existed; access is then only limited by the
+//! socket's file permissions.  The exception is the unstable routes under /debug, which expose raw
+//! data store contents; whatever the config, they're only allowed for root and TCP callers with
+//! the token.
+
+use super::error;
+use actix_rt::net::UnixStream;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::Method;
+use actix_web::HttpMessage;
+use futures::future;
+use log::warn;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// The request header holding the shared secret for TCP callers.
+pub(crate) const TOKEN_HEADER: &str = "x-api-token";
+
+/// Routes under this path are for debugging, and only allowed for privileged callers.
+const DEBUG_PATH: &str = "/debug";
+
+/// Which callers may use which routes.
+#[derive(Debug, Clone, Default)]
+pub struct AuthConfig {
+    /// Rules are checked in order, and the first whose methods and path match the request
+    /// decides whether it's allowed.  Requests that match no rule are allowed.
+    pub rules: Vec<AuthRule>,
+    /// The shared secret TCP callers send to identify themselves.  Without one, TCP callers
+    /// are only allowed to use routes that no rule covers.
+    pub token: Option<String>,
+}
+
+impl AuthConfig {
+    /// Returns a config that lets anyone read, but only lets root, members of the given group,
+    /// and TCP callers with the token change settings, commit, or otherwise write.
+    pub fn writes_restricted(gid: Option<u32>, token: Option<String>) -> Self {
+        Self {
+            rules: vec![AuthRule {
+                methods: vec![Method::PATCH, Method::POST, Method::PUT, Method::DELETE],
+                path_prefix: "/".to_string(),
+                allow: Allow {
+                    uids: vec![0],
+                    gids: gid.into_iter().collect(),
+                    token: true,
+                },
+            }],
+            token,
+        }
+    }
+}
+
+/// A rule saying who may use a set of routes.
+#[derive(Debug, Clone)]
+pub struct AuthRule {
+    /// The methods the rule covers; if empty, it covers all methods.
+    pub methods: Vec<Method>,
+    /// The rule covers this path and any path under it, so "/tx" covers "/tx/commit".
+    pub path_prefix: String,
+    /// The callers allowed to use the covered routes.
+    pub allow: Allow,
+}
+
+/// The callers allowed by a rule.
+#[derive(Debug, Clone, Default)]
+pub struct Allow {
+    /// Processes running as any of these users.
+    pub uids: Vec<u32>,
+    /// Processes running with any of these primary groups.
+    pub gids: Vec<u32>,
+    /// TCP callers that send the configured token.
+    pub token: bool,
+}
+
+impl AuthRule {
+    fn covers(&self, method: &Method, path: &str) -> bool {
+        let method_matches = self.methods.is_empty() || self.methods.contains(method);
+        let prefix = self.path_prefix.trim_end_matches('/');
+        let path_matches = path == prefix || path.starts_with(&format!("{}/", prefix));
+        method_matches && path_matches
+    }
+}
+
+impl Allow {
+    fn allows(&self, caller: &Caller) -> bool {
+        match caller {
+            Caller::Process(creds) => {
+                self.uids.contains(&creds.uid) || self.gids.contains(&creds.gid)
+            }
+            Caller::Token => self.token,
+            Caller::Unknown => false,
+        }
+    }
+}
+
+impl AuthConfig {
+    /// Returns whether the caller may make a request with the given method and path.
+    fn allows(&self, caller: &Caller, method: &Method, path: &str) -> bool {
+        let debug = AuthRule {
+            methods: vec![],
+            path_prefix: DEBUG_PATH.to_string(),
+            allow: Allow {
+                uids: vec![0],
+                gids: vec![],
+                token: true,
+            },
+        };
+        if debug.covers(method, path) {
+            return debug.allow.allows(caller);
+        }
+
+        match self.rules.iter().find(|rule| rule.covers(method, path)) {
+            Some(rule) => rule.allow.allows(caller),
+            None => true,
+        }
+    }
+}
+
+/// The user and group of the process on the other end of a Unix-domain socket connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct PeerCredentials {
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+}
+
+/// The peer credentials of a socket connection, recorded in each request's extensions when the
+/// connection is accepted.  They're None if we couldn't get them.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SocketPeer(pub(crate) Option<PeerCredentials>);
+
+/// Returns the peer credentials of a socket connection, or None if we can't get them, in which
+/// case its requests are treated as coming from an unknown caller.
+pub(crate) fn peer_credentials(io: &UnixStream) -> Option<PeerCredentials> {
+    match io.peer_cred() {
+        Ok(cred) => Some(PeerCredentials {
+            uid: cred.uid,
+            gid: cred.gid,
+        }),
+        Err(e) => {
+            warn!("Unable to get peer credentials of connection: {}", e);
+            None
+        }
+    }
+}
+
+/// Who sent a request, as far as we can tell.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Caller {
+    /// A local process, identified by the peer credentials of its connection.
+    Process(PeerCredentials),
+    /// A TCP caller that sent the configured token.
+    Token,
+    /// A caller we can't identify.
+    Unknown,
+}
+
+/// Finds out who sent a request.  The server uses ConnectionCredentials; tests can fake it.
+pub(crate) trait CredentialSource {
+    fn caller(&self, req: &ServiceRequest) -> Caller;
+}
+
+/// Identifies callers by the peer credentials recorded for their socket connection, or by the
+/// token they send over TCP.
+pub(crate) struct ConnectionCredentials {
+    token: Option<String>,
+}
+
+impl ConnectionCredentials {
+    pub(crate) fn new(token: Option<String>) -> Self {
+        Self { token }
+    }
+}
+
+impl CredentialSource for ConnectionCredentials {
+    fn caller(&self, req: &ServiceRequest) -> Caller {
+        if let Some(SocketPeer(Some(creds))) = req.extensions().get::<SocketPeer>() {
+            return Caller::Process(*creds);
+        }
+        let given = req.headers().get(TOKEN_HEADER).map(|v| v.as_bytes());
+        match (&self.token, given) {
+            (Some(token), Some(given))
+                if ring::constant_time::verify_slices_are_equal(token.as_bytes(), given)
+                    .is_ok() =>
+            {
+                Caller::Token
+            }
+            _ => Caller::Unknown,
+        }
+    }
+}
+
+/// Middleware that refuses requests the AuthConfig doesn't allow from their caller.
+pub(crate) struct Authorizer<C> {
+    config: Rc<AuthConfig>,
+    source: Rc<C>,
+}
+
+impl<C> Authorizer<C> {
+    pub(crate) fn new(config: AuthConfig, source: C) -> Self {
+        Self {
+            config: Rc::new(config),
+            source: Rc::new(source),
+        }
+    }
+}
+
+impl<S, B, C> Transform<S> for Authorizer<C>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+    C: CredentialSource,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = AuthorizerService<S, C>;
+    type Future = future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ready(Ok(AuthorizerService {
+            service,
+            config: self.config.clone(),
+            source: self.source.clone(),
+        }))
+    }
+}
+
+/// The service created by Authorizer for each worker.
+pub(crate) struct AuthorizerService<S, C> {
+    service: S,
+    config: Rc<AuthConfig>,
+    source: Rc<C>,
+}
+
+impl<S, B, C> Service for AuthorizerService<S, C>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+    C: CredentialSource,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let caller = self.source.caller(&req);
+        // Check the path the router will match, with percent-encoding decoded, rather than the
+        // raw path; otherwise "/%64ebug" would reach the debug routes without being checked as
+        // one of them.
+        let path = req.match_info().path().to_string();
+        if !self.config.allows(&caller, req.method(), &path) {
+            let e = error::Error::Forbidden {
+                method: req.method().to_string(),
+                path,
+            };
+            return Box::pin(future::ready(Ok(req.error_response(e))));
+        }
+        Box::pin(self.service.call(req))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+
+    /// Says every request came from the same caller.
+    struct FakeCredentials(Caller);
+
+    impl CredentialSource for FakeCredentials {
+        fn caller(&self, _req: &ServiceRequest) -> Caller {
+            self.0.clone()
+        }
+    }
+
+    fn process(uid: u32, gid: u32) -> Caller {
+        Caller::Process(PeerCredentials { uid, gid })
+    }
+
+    /// Returns the status of a GET of settings, a PATCH of settings, and a commit, made by the
+    /// given caller under the given config.
+    async fn statuses(config: AuthConfig, caller: Caller) -> Vec<StatusCode> {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Authorizer::new(config, FakeCredentials(caller)))
+                .route(
+                    "/settings",
+                    web::get().to(|| async { HttpResponse::Ok().finish() }),
+                )
+                .route(
+                    "/settings",
+                    web::patch().to(|| async { HttpResponse::NoContent().finish() }),
+                )
+                .route(
+                    "/tx/commit",
+                    web::post().to(|| async { HttpResponse::Ok().finish() }),
+                ),
+        )
+        .await;
+
+        let mut statuses = Vec::new();
+        for req in vec![
+            test::TestRequest::get().uri("/settings"),
+            test::TestRequest::patch().uri("/settings"),
+            test::TestRequest::post().uri("/tx/commit"),
+        ] {
+            let response = test::call_service(&mut app, req.to_request()).await;
+            statuses.push(response.status());
+        }
+        statuses
+    }
+
+    const OK: StatusCode = StatusCode::OK;
+    const NO_CONTENT: StatusCode = StatusCode::NO_CONTENT;
+    const FORBIDDEN: StatusCode = StatusCode::FORBIDDEN;
+
+    #[actix_rt::test]
+    async fn default_allows_everything() {
+        for caller in vec![process(1000, 1000), Caller::Unknown] {
+            assert_eq!(
+                statuses(AuthConfig::default(), caller).await,
+                vec![OK, NO_CONTENT, OK]
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn writes_restricted() {
+        let config = AuthConfig::writes_restricted(Some(274), Some("secret".to_string()));
+        for caller in vec![process(0, 0), process(1000, 274), Caller::Token] {
+            assert_eq!(
+                statuses(config.clone(), caller.clone()).await,
+                vec![OK, NO_CONTENT, OK],
+                "{:?}",
+                caller
+            );
+        }
+        for caller in vec![process(1000, 1000), Caller::Unknown] {
+            assert_eq!(
+                statuses(config.clone(), caller.clone()).await,
+                vec![OK, FORBIDDEN, FORBIDDEN],
+                "{:?}",
+                caller
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn first_matching_rule_decides() {
+        // Commits are limited to root, even though the group can PATCH
+        let mut config = AuthConfig::writes_restricted(Some(274), None);
+        config.rules.insert(
+            0,
+            AuthRule {
+                methods: vec![],
+                path_prefix: "/tx/".to_string(),
+                allow: Allow {
+                    uids: vec![0],
+                    ..Default::default()
+                },
+            },
+        );
+        assert_eq!(
+            statuses(config.clone(), process(1000, 274)).await,
+            vec![OK, NO_CONTENT, FORBIDDEN]
+        );
+        assert_eq!(
+            statuses(config, process(0, 0)).await,
+            vec![OK, NO_CONTENT, OK]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn debug_routes_privileged() {
+        // Rules that allow everyone don't apply to debug routes
+        let config = AuthConfig {
+            rules: vec![AuthRule {
+                methods: vec![],
+                path_prefix: "/".to_string(),
+                allow: Allow {
+                    uids: vec![1000],
+                    ..Default::default()
+                },
+            }],
+            token: None,
+        };
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Authorizer::new(
+                    config,
+                    FakeCredentials(process(1000, 1000)),
+                ))
+                .route(
+                    "/debug/datastore/key",
+                    web::get().to(|| async { HttpResponse::Ok().finish() }),
+                ),
+        )
+        .await;
+        for uri in &["/debug/datastore/key", "/%64ebug/datastore/key"] {
+            let req = test::TestRequest::get().uri(uri);
+            let response = test::call_service(&mut app, req.to_request()).await;
+            assert_eq!(response.status(), FORBIDDEN, "{}", uri);
+        }
+
+        for (caller, allowed) in vec![
+            (process(0, 0), true),
+            (Caller::Token, true),
+            (process(1000, 0), false),
+            (Caller::Unknown, false),
+        ] {
+            for config in vec![
+                AuthConfig::default(),
+                AuthConfig::writes_restricted(Some(1000), None),
+            ] {
+                assert_eq!(
+                    config.allows(&caller, &Method::GET, "/debug/datastore/key"),
+                    allowed,
+                    "{:?}",
+                    caller
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rule_paths() {
+        let rule = AuthRule {
+            methods: vec![Method::POST],
+            path_prefix: "/tx".to_string(),
+            allow: Allow::default(),
+        };
+        assert!(rule.covers(&Method::POST, "/tx"));
+        assert!(rule.covers(&Method::POST, "/tx/commit"));
+        assert!(!rule.covers(&Method::POST, "/txt"));
+        assert!(!rule.covers(&Method::GET, "/tx/commit"));
+    }
+
+    #[test]
+    fn token_identifies_tcp_callers() {
+        let source = ConnectionCredentials::new(Some("secret".to_string()));
+        let caller = |token: Option<&str>| {
+            let mut req = test::TestRequest::default();
+            if let Some(token) = token {
+                req = req.header(TOKEN_HEADER, token);
+            }
+            source.caller(&req.to_srv_request())
+        };
+        assert_eq!(caller(Some("secret")), Caller::Token);
+        assert_eq!(caller(Some("wrong")), Caller::Unknown);
+        assert_eq!(caller(None), Caller::Unknown);
+
+        // Socket peer credentials take precedence
+        let req = test::TestRequest::default().to_srv_request();
+        req.extensions_mut()
+            .insert(SocketPeer(Some(PeerCredentials { uid: 0, gid: 0 })));
+        assert_eq!(source.caller(&req), process(0, 0));
+    }
+}
@@ -2,18 +2,30 @@
 //! controller in the MVC model.
 
 use bottlerocket_release::BottlerocketRelease;
+use regex::Regex;
+use ring::digest::{self, SHA256};
 use serde::de::DeserializeOwned;
+use serde::Serialize;
 use snafu::{ensure, OptionExt, ResultExt};
-use std::collections::{HashMap, HashSet};
-use std::io::Write;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::path::PathBuf;
 use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::datastore::deserialization::{from_map, from_map_with_prefix};
-use crate::datastore::serialization::to_pairs;
+use crate::datastore::deserialization::{self, from_map, from_map_with_prefix};
+use crate::datastore::serialization::{to_pairs, to_pairs_with_prefix};
 use crate::datastore::{
-    deserialize_scalar, Committed, DataStore, Key, KeyType, ScalarError, Value,
+    deserialize_scalar, serialize_scalar, Committed, DataStore, Key, KeyType, ScalarError, Value,
+    SNAPSHOTS_SEGMENT, UNSET_VALUE,
 };
 use crate::server::error::{self, Result};
+use crate::server::schema;
+use crate::server::unknown_fields::{self, UnknownSetting};
 use model::{ConfigurationFiles, Services, Settings};
 
 /// List the open transactions from the data store.
@@ -40,6 +52,68 @@ where
         .map(|maybe_settings| maybe_settings.unwrap_or_else(Settings::default))
 }
 
+/// Compares the settings pending in the given transaction to live, returning each pending key
+/// mapped to its (live, pending) values.  The live value is None if the key is newly set, and the
+/// pending value is None if the key is staged for removal.
+///
+/// If include_unchanged is false, keys pending with the same value they have in live are left
+/// out; otherwise they're included, and can be recognized by their equal values.
+pub(crate) fn get_pending_diff<D: DataStore>(
+    datastore: &D,
+    transaction: &str,
+    include_unchanged: bool,
+) -> Result<HashMap<Key, (Option<Value>, Option<Value>)>> {
+    let pending = Committed::Pending {
+        tx: transaction.into(),
+    };
+    let pending_data = datastore
+        .get_prefix("settings.", &pending)
+        .context(error::DataStore { op: "get_prefix" })?;
+    let keys = pending_data.keys().cloned().collect();
+    let live_data = datastore
+        .get_keys(&keys, &Committed::Live)
+        .context(error::DataStore { op: "get_keys" })?;
+
+    let parse = |key: &Key, value_str: &str| -> Result<Value> {
+        deserialize_scalar::<_, ScalarError>(value_str)
+            .context(error::InvalidData { key: key.name() })
+    };
+
+    let mut result = HashMap::with_capacity(pending_data.len());
+    for (key, pending_str) in pending_data {
+        let live_value = match live_data.get(&key) {
+            Some(Some(live_str)) => Some(parse(&key, live_str)?),
+            _ => None,
+        };
+        let pending_value = if pending_str == UNSET_VALUE {
+            None
+        } else {
+            Some(parse(&key, &pending_str)?)
+        };
+
+        if !include_unchanged && live_value == pending_value {
+            trace!("Pending key {} is unchanged from live", key);
+            continue;
+        }
+        result.insert(key, (live_value, pending_value));
+    }
+    Ok(result)
+}
+
+/// Returns every setting pending in the given transaction, by key name, with its live value and
+/// the value it has pending.  Unlike dry_run_commit, settings pending with the value they already
+/// have are included, so this shows everything staged in the transaction.
+pub(crate) fn get_pending_changes<D: DataStore>(
+    datastore: &D,
+    transaction: &str,
+) -> Result<BTreeMap<String, PendingChange>> {
+    let diff = get_pending_diff(datastore, transaction, true)?;
+    Ok(diff
+        .into_iter()
+        .map(|(key, (old, new))| (key.name().to_string(), PendingChange { old, new }))
+        .collect())
+}
+
 /// Deletes the transaction from the data store, removing any uncommitted settings under that
 /// transaction name.
 pub(crate) fn delete_transaction<D: DataStore>(
@@ -61,17 +135,169 @@ pub(crate) fn get_settings<D: DataStore>(datastore: &D, committed: &Committed) -
         .context(error::MissingData { prefix: "settings" })?
 }
 
-/// Build a Settings based on the data in the datastore that begins with the given prefix.
-pub(crate) fn get_settings_prefix<D: DataStore, S: AsRef<str>>(
+/// Returns an entity tag (ETag) for the live settings, which changes whenever any setting does.
+/// Clients can use it to avoid fetching settings that haven't changed, or to make changes only if
+/// nothing changed since they read the settings.
+pub(crate) fn get_settings_etag<D: DataStore>(datastore: &D) -> Result<String> {
+    let data = datastore
+        .get_prefix("settings.", &Committed::Live)
+        .context(error::DataStore { op: "get_prefix" })?;
+    Ok(content_etag(&data))
+}
+
+/// Build a Settings based on the live data in the datastore, along with its ETag (see
+/// get_settings_etag) and the number of keys it was built from, reading the data only once.
+/// Errors if no settings are found.
+pub(crate) fn get_settings_and_etag<D: DataStore>(
+    datastore: &D,
+) -> Result<(Settings, String, usize)> {
+    let (settings, data) = get_settings_data(datastore, &Committed::Live)?;
+    Ok((settings, content_etag(&data), data.len()))
+}
+
+/// Build a Settings based on the data in the datastore, like get_settings, along with the number
+/// of keys it was built from.  Errors if no settings are found.
+pub(crate) fn get_settings_and_count<D: DataStore>(
     datastore: &D,
-    prefix: S,
     committed: &Committed,
-) -> Result<Settings> {
-    let prefix = "settings.".to_string() + prefix.as_ref();
-    get_prefix(datastore, committed, &prefix, None)
-        .transpose()
-        // None is OK here - they could ask for a prefix we don't have
-        .unwrap_or_else(|| Ok(Settings::default()))
+) -> Result<(Settings, usize)> {
+    let (settings, data) = get_settings_data(datastore, committed)?;
+    Ok((settings, data.len()))
+}
+
+/// Returns the Settings built from all settings data in the datastore, along with the data.
+fn get_settings_data<D: DataStore>(
+    datastore: &D,
+    committed: &Committed,
+) -> Result<(Settings, HashMap<Key, String>)> {
+    let data = datastore
+        .get_prefix("settings.", committed)
+        .context(error::DataStore { op: "get_prefix" })?;
+    ensure!(!data.is_empty(), error::MissingData { prefix: "settings" });
+
+    let settings =
+        from_map_with_prefix(None, &data).context(error::Deserialization { given: "settings." })?;
+    Ok((settings, data))
+}
+
+/// Returns a strong ETag for the given data: a quoted SHA-256 hash of its keys and serialized
+/// values.  The pairs are hashed in key order, so the result doesn't depend on map ordering.
+fn content_etag(data: &HashMap<Key, String>) -> String {
+    let sorted: BTreeMap<&str, &str> = data
+        .iter()
+        .map(|(key, value)| (key.name().as_str(), value.as_str()))
+        .collect();
+
+    let mut context = digest::Context::new(&SHA256);
+    for (name, value) in sorted {
+        // Including the lengths keeps the boundaries between names and values unambiguous.
+        for part in &[name, value] {
+            context.update(&(part.len() as u64).to_be_bytes());
+            context.update(part.as_bytes());
+        }
+    }
+    format!("\"{}\"", hex::encode(context.finish()))
+}
+
+/// Build a Settings based on the data in the datastore that begins with any of the given
+/// prefixes, which don't include "settings.".  If there's no such data, the Settings are empty.
+/// Returns the Settings with the number of keys they were built from.
+pub(crate) fn get_settings_prefixes<D: DataStore>(
+    datastore: &D,
+    prefixes: &HashSet<&str>,
+    committed: &Committed,
+) -> Result<(Settings, usize)> {
+    let data = get_prefixes_data(datastore, prefixes, committed)?;
+    Ok((settings_from_data(&data, "given prefixes")?, data.len()))
+}
+
+/// Build a Settings based on the data in the datastore for the given keys, along with the data
+/// that begins with any of the given prefixes.  `missing` applies to the keys as in
+/// get_settings_keys.  Returns the Settings with the number of keys they were built from.
+pub(crate) fn get_settings_keys_and_prefixes<D: DataStore>(
+    datastore: &D,
+    keys: &HashSet<&str>,
+    prefixes: &HashSet<&str>,
+    missing: MissingKeyBehavior,
+    committed: &Committed,
+) -> Result<(Settings, usize)> {
+    let mut data = get_keys_data(datastore, keys, missing, committed)?;
+    data.extend(get_prefixes_data(datastore, prefixes, committed)?);
+    Ok((
+        settings_from_data(&data, "given keys and prefixes")?,
+        data.len(),
+    ))
+}
+
+/// Returns the settings data that begins with any of the given prefixes, which don't include
+/// "settings.".  Each key is only fetched once, even if the prefixes overlap.
+fn get_prefixes_data<D: DataStore>(
+    datastore: &D,
+    prefixes: &HashSet<&str>,
+    committed: &Committed,
+) -> Result<HashMap<Key, String>> {
+    let mut data = HashMap::new();
+    for prefix in distinct_prefixes(prefixes) {
+        let prefix = "settings.".to_string() + prefix;
+        let found = datastore
+            .get_prefix(&prefix, committed)
+            .with_context(|| error::DataStore {
+                op: format!("get_prefix '{}' for {:?}", prefix, committed),
+            })?;
+        data.extend(found);
+    }
+    Ok(data)
+}
+
+/// Returns the given prefixes, leaving out any that start with another of the prefixes, since
+/// the shorter prefix already covers them.
+fn distinct_prefixes<'a>(prefixes: &HashSet<&'a str>) -> Vec<&'a str> {
+    let mut sorted: Vec<&str> = prefixes.iter().copied().collect();
+    // Sorting puts each prefix before any longer prefixes that start with it.
+    sorted.sort();
+    let mut distinct: Vec<&str> = Vec::new();
+    for prefix in sorted {
+        if !distinct.iter().any(|shorter| prefix.starts_with(shorter)) {
+            distinct.push(prefix);
+        }
+    }
+    distinct
+}
+
+/// Deserializes settings data, which may be empty, into Settings.
+fn settings_from_data(data: &HashMap<Key, String>, given: &str) -> Result<Settings> {
+    // Empty data is OK here - they could ask for settings we don't have
+    if data.is_empty() {
+        return Ok(Settings::default());
+    }
+    from_map(data).context(error::Deserialization { given })
+}
+
+/// Gets the value of each populated setting along with the time it was last written, keyed by
+/// the full setting name, e.g. "settings.motd".  Useful for debugging when settings changed.
+pub(crate) fn get_settings_with_mtimes<D: DataStore>(
+    datastore: &D,
+    committed: &Committed,
+) -> Result<HashMap<String, (Value, SystemTime)>> {
+    let data = datastore
+        .get_prefix("settings.", committed)
+        .context(error::DataStore { op: "get_prefix" })?;
+
+    let mut result = HashMap::with_capacity(data.len());
+    for (key, value_str) in data {
+        let value: Value = deserialize_scalar::<_, ScalarError>(&value_str)
+            .context(error::InvalidData { key: key.name() })?;
+        let mtime = datastore
+            .get_key_mtime(&key, committed)
+            .context(error::DataStore {
+                op: "get_key_mtime",
+            })?;
+        // The key could have been removed since we read it; then there's nothing to report.
+        if let Some(mtime) = mtime {
+            result.insert(key.name().to_string(), (value, mtime));
+        }
+    }
+    Ok(result)
 }
 
 // The "os" APIs don't deal with the data store at all, they just read a release field.
@@ -108,6 +334,163 @@ pub(crate) fn get_configuration_files<D: DataStore>(datastore: &D) -> Result<Con
     })?
 }
 
+/// A problem with the references between services and configuration files in the data store.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum ConsistencyError {
+    /// A service lists a configuration file that doesn't exist.
+    MissingConfigurationFile { service: String, file: String },
+    /// A configuration file doesn't say where its template is.
+    MissingTemplatePath { file: String },
+    /// A configuration file isn't used by any service, so it's never rendered.  This is only a
+    /// warning, since the file may be unused on purpose.
+    UnusedConfigurationFile { file: String },
+}
+
+impl ConsistencyError {
+    /// Returns true if the problem doesn't stop settings from being applied.
+    pub(crate) fn is_warning(&self) -> bool {
+        match self {
+            ConsistencyError::UnusedConfigurationFile { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ConsistencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsistencyError::MissingConfigurationFile { service, file } => write!(
+                f,
+                "Service '{}' uses configuration file '{}', which doesn't exist",
+                service, file
+            ),
+            ConsistencyError::MissingTemplatePath { file } => {
+                write!(f, "Configuration file '{}' has no template path", file)
+            }
+            ConsistencyError::UnusedConfigurationFile { file } => {
+                write!(f, "Configuration file '{}' isn't used by any service", file)
+            }
+        }
+    }
+}
+
+/// Cross-checks the services and configuration files in the data store, returning every problem
+/// found, in a consistent order, rather than stopping at the first.  These problems otherwise
+/// only show up when settings are applied and a configuration file can't be rendered.
+pub(crate) fn validate_model_consistency<D: DataStore>(
+    datastore: &D,
+) -> Result<Vec<ConsistencyError>> {
+    let services = get_services(datastore)?;
+    let configuration_files = get_configuration_files(datastore)?;
+
+    let mut problems = Vec::new();
+    let mut used = HashSet::new();
+    for (service_name, service) in &services {
+        for file in &service.configuration_files {
+            let file: &str = file.as_ref();
+            if configuration_files.contains_key(file) {
+                used.insert(file);
+            } else {
+                problems.push(ConsistencyError::MissingConfigurationFile {
+                    service: service_name.clone(),
+                    file: file.to_string(),
+                });
+            }
+        }
+    }
+
+    for (file_name, file) in &configuration_files {
+        let template_path: &str = file.template_path.as_ref();
+        if template_path.trim().is_empty() {
+            problems.push(ConsistencyError::MissingTemplatePath {
+                file: file_name.clone(),
+            });
+        }
+        if !used.contains(file_name.as_str()) {
+            problems.push(ConsistencyError::UnusedConfigurationFile {
+                file: file_name.clone(),
+            });
+        }
+    }
+
+    problems.sort();
+    Ok(problems)
+}
+
+/// Returns the names of the given services in the order they should be restarted, so that each
+/// service comes after the services named in its restart-after list.  Services are otherwise
+/// kept in name order.  Names in restart-after that aren't among the given services are ignored,
+/// since only the order of the services being restarted matters.  Returns a RestartCycle error
+/// if services depend on each other in a loop.
+pub(crate) fn resolve_restart_order(services: &Services) -> Result<Vec<String>> {
+    let mut order = Vec::with_capacity(services.len());
+    let mut done = HashSet::new();
+    let mut path = Vec::new();
+    for name in services.keys() {
+        visit_restart_after(services, name, &mut done, &mut path, &mut order)?;
+    }
+    Ok(order)
+}
+
+/// Returns the named services in the order they should be restarted, following the restart-after
+/// lists of live services; see resolve_restart_order.  Services that aren't defined in the data
+/// store have no restart-after list, so they come first, in name order.
+fn restart_order<D: DataStore>(datastore: &D, names: &HashSet<String>) -> Result<Vec<String>> {
+    let mut services: Services = get_prefix(
+        datastore,
+        &Committed::Live,
+        "services.",
+        Some("services".to_string()),
+    )?
+    .unwrap_or_default();
+    services.retain(|name, _| names.contains(name));
+    let mut order: Vec<String> = names
+        .iter()
+        .filter(|name| !services.contains_key(*name))
+        .cloned()
+        .collect();
+    order.sort();
+    order.extend(resolve_restart_order(&services)?);
+    Ok(order)
+}
+
+/// Depth-first helper for resolve_restart_order; adds the named service to `order` after the
+/// services it restarts after.  `path` holds the services we're in the middle of visiting, so we
+/// can tell when we've looped back to one of them.
+fn visit_restart_after<'a>(
+    services: &'a Services,
+    name: &'a str,
+    done: &mut HashSet<&'a str>,
+    path: &mut Vec<&'a str>,
+    order: &mut Vec<String>,
+) -> Result<()> {
+    if done.contains(name) {
+        return Ok(());
+    }
+    if let Some(start) = path.iter().position(|&visiting| visiting == name) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(name);
+        return error::RestartCycle {
+            cycle: cycle.join(" -> "),
+        }
+        .fail();
+    }
+
+    path.push(name);
+    if let Some(after) = services.get(name).and_then(|s| s.restart_after.as_ref()) {
+        for dependency in after {
+            if services.contains_key(dependency) {
+                visit_restart_after(services, dependency, done, path, order)?;
+            }
+        }
+    }
+    path.pop();
+
+    done.insert(name);
+    order.push(name.to_string());
+    Ok(())
+}
+
 /// Helper to get data from the datastore, starting with the given find_prefix, and deserialize it
 /// into the desired type.  map_prefix should be the prefix to remove if you're deserializing into
 /// a map; see docs on from_map_with_prefix.  Returns Err if we couldn't pull expected data;
@@ -137,34 +520,235 @@ where
     from_map_with_prefix(map_prefix, &data).context(error::Deserialization { given: find_prefix })
 }
 
-/// Build a Settings based on the data in the datastore for the given keys.
+/// The result of a lenient deserialization: whatever could be deserialized, plus the keys that
+/// were left out because their values couldn't be.
+#[derive(Debug)]
+pub(crate) struct Lenient<T> {
+    /// The deserialized data, or None if there were no usable keys.
+    pub(crate) value: Option<T>,
+    /// Each key that was skipped, and why.
+    pub(crate) skipped: Vec<(Key, deserialization::Error)>,
+}
+
+/// Like get_prefix, but keys whose values can't be deserialized are skipped and reported rather
+/// than failing the whole request, so you can see everything that's wrong with the data store.
+/// Structural problems that can't be blamed on a single value, like a missing required field,
+/// are still returned as errors.
+fn get_prefix_lenient<D, T, S>(
+    datastore: &D,
+    committed: &Committed,
+    find_prefix: S,
+    map_prefix: Option<String>,
+) -> Result<Lenient<T>>
+where
+    D: DataStore,
+    T: DeserializeOwned,
+    S: AsRef<str>,
+{
+    let find_prefix = find_prefix.as_ref();
+
+    let mut data = datastore
+        .get_prefix(find_prefix, committed)
+        .with_context(|| error::DataStore {
+            op: format!("get_prefix '{}' for {:?}", find_prefix, committed),
+        })?;
+
+    // Each failure names a bad key; remove it and try again until the rest deserializes.
+    let mut skipped = Vec::new();
+    while !data.is_empty() {
+        let e = match from_map_with_prefix(map_prefix.clone(), &data) {
+            Ok(value) => {
+                return Ok(Lenient {
+                    value: Some(value),
+                    skipped,
+                })
+            }
+            Err(e) => e,
+        };
+        let bad_key = match &e {
+            deserialization::Error::DeserializeScalar { key, .. } => Key::new(KeyType::Data, key)
+                .context(error::NewKey {
+                key_type: "data",
+                name: key,
+            })?,
+            _ => return Err(e).context(error::Deserialization { given: find_prefix }),
+        };
+        // If the key isn't in our data we'd never make progress, so treat it as fatal.
+        if data.remove(&bad_key).is_none() {
+            return Err(e).context(error::Deserialization { given: find_prefix });
+        }
+        warn!(
+            "Skipping key '{}' that failed to deserialize: {}",
+            bad_key, e
+        );
+        skipped.push((bad_key, e));
+    }
+
+    Ok(Lenient {
+        value: None,
+        skipped,
+    })
+}
+
+/// Build a Settings from whatever settings in the data store can be deserialized, along with the
+/// settings keys that were skipped because their values couldn't be.  Unlike get_settings, this
+/// doesn't fail because of individual bad values, so it can be used to diagnose them.
+pub(crate) fn get_settings_lenient<D: DataStore>(
+    datastore: &D,
+    committed: &Committed,
+) -> Result<Lenient<Settings>> {
+    get_prefix_lenient(datastore, committed, "settings.", None)
+}
+
+/// Gets the value of a single setting, e.g. "settings.motd", without building a whole Settings.
+/// Returns Ok(None) if the setting isn't set, or is staged for removal in pending.  The key must
+/// be a single setting; requesting a key with settings under it, like "settings.ntp", is an
+/// error, and those should be requested by prefix instead.
+pub(crate) fn get_setting<D: DataStore>(
+    datastore: &D,
+    key_str: &str,
+    committed: &Committed,
+) -> Result<Option<Value>> {
+    ensure!(
+        key_str.starts_with("settings."),
+        error::NonSettingsKey { name: key_str }
+    );
+    let key = Key::new(KeyType::Data, key_str).context(error::NewKey {
+        key_type: "data",
+        name: key_str,
+    })?;
+
+    let value_str = match datastore
+        .get_key(&key, committed)
+        .context(error::DataStore { op: "get_key" })?
+    {
+        Some(value_str) => value_str,
+        None => {
+            let under = datastore
+                .list_populated_keys(format!("{}.", key_str), committed)
+                .context(error::DataStore {
+                    op: "list_populated_keys",
+                })?;
+            ensure!(under.is_empty(), error::SettingIsPrefix { key: key_str });
+            return Ok(None);
+        }
+    };
+
+    let value: Value = deserialize_scalar::<_, ScalarError>(&value_str)
+        .context(error::InvalidData { key: key.name() })?;
+    if value.is_null() {
+        trace!("Setting {} is staged for removal", key);
+        return Ok(None);
+    }
+    Ok(Some(value))
+}
+
+/// Lists the names of populated settings keys under the given prefix, or all settings keys if no
+/// prefix is given, without reading their values.  The prefix must start with "settings.".
+///
+/// If `depth` is given, names are cut off after that many segments under "settings" and
+/// de-duplicated, so a depth of 1 gives the top-level groups, like "settings.ntp".
+pub(crate) fn list_settings_keys<D: DataStore>(
+    datastore: &D,
+    prefix: Option<&str>,
+    depth: Option<usize>,
+    committed: &Committed,
+) -> Result<BTreeSet<String>> {
+    let prefix = prefix.unwrap_or("settings.");
+    ensure!(
+        prefix.starts_with("settings."),
+        error::NonSettingsKey { name: prefix }
+    );
+
+    let keys = datastore
+        .list_populated_keys(prefix, committed)
+        .context(error::DataStore {
+            op: "list_populated_keys",
+        })?;
+
+    let depth = match depth {
+        Some(depth) => depth,
+        None => return Ok(keys.iter().map(|k| k.name().to_string()).collect()),
+    };
+    let mut result = BTreeSet::new();
+    for key in keys {
+        // Include the "settings" segment itself
+        let segments = key.segments();
+        let keep = &segments[..segments.len().min(depth + 1)];
+        let truncated = Key::from_segments(KeyType::Data, keep).context(error::NewKey {
+            key_type: "data",
+            name: keep.join("."),
+        })?;
+        result.insert(truncated.name().to_string());
+    }
+    Ok(result)
+}
+
+/// How get_settings_keys should handle requested keys that aren't populated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MissingKeyBehavior {
+    /// Leave the key out of the returned Settings.
+    Skip,
+    /// Fail with an error listing every requested key that isn't populated.
+    Error,
+}
+
+/// Build a Settings based on the data in the datastore for the given keys.  Requested keys that
+/// aren't valid key names are always an error; keys that aren't populated are handled according
+/// to `missing`.  Returns the Settings with the number of keys they were built from.
 pub(crate) fn get_settings_keys<D: DataStore>(
     datastore: &D,
     keys: &HashSet<&str>,
+    missing: MissingKeyBehavior,
     committed: &Committed,
-) -> Result<Settings> {
-    let mut data = HashMap::new();
+) -> Result<(Settings, usize)> {
+    let data = get_keys_data(datastore, keys, missing, committed)?;
+    let settings = from_map(&data).context(error::Deserialization {
+        given: "given keys",
+    })?;
+    Ok((settings, data.len()))
+}
+
+/// Returns the settings data for the given keys; see get_settings_keys.
+fn get_keys_data<D: DataStore>(
+    datastore: &D,
+    keys: &HashSet<&str>,
+    missing: MissingKeyBehavior,
+    committed: &Committed,
+) -> Result<HashMap<Key, String>> {
+    let mut query = HashSet::new();
     for key_str in keys {
-        trace!("Pulling value from datastore for key: {}", key_str);
         let key = Key::new(KeyType::Data, &key_str).context(error::NewKey {
             key_type: "data",
             name: *key_str,
         })?;
-        let value = match datastore
-            .get_key(&key, committed)
-            .context(error::DataStore { op: "get_key" })?
-        {
-            Some(v) => v,
-            // TODO: confirm we want to skip requested keys if not populated, or error
-            None => continue,
-        };
-        data.insert(key, value);
+        query.insert(key);
     }
 
-    let settings = from_map(&data).context(error::Deserialization {
-        given: "given keys",
-    })?;
-    Ok(settings)
+    trace!("Pulling values from datastore for keys: {:?}", query);
+    let values = datastore
+        .get_keys(&query, committed)
+        .context(error::DataStore { op: "get_keys" })?;
+
+    if missing == MissingKeyBehavior::Error {
+        let mut missing_keys: Vec<&str> = values
+            .iter()
+            .filter(|(_, value)| value.is_none())
+            .map(|(key, _)| key.name().as_str())
+            .collect();
+        missing_keys.sort();
+        ensure!(
+            missing_keys.is_empty(),
+            error::MissingKeys {
+                keys: missing_keys.join(", ")
+            }
+        );
+    }
+
+    Ok(values
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|v| (key, v)))
+        .collect())
 }
 
 /// Build a collection of Service items with the given names using data from the datastore.
@@ -194,17 +778,17 @@ pub(crate) fn get_configuration_files_names<D: DataStore>(
 /// Helper to get data from the datastore for a collection of requested items under a given prefix.  For
 /// example, a collection of Service items under "services" that have the requested names.
 /// Returns Err if we couldn't pull expected data, including the case where a name was specified
-/// for which we have no data.
+/// for which we have no data.  The items are returned in name order.
 fn get_map_from_prefix<D: DataStore, T>(
     datastore: &D,
     prefix: String,
     names: &HashSet<&str>,
     committed: &Committed,
-) -> Result<HashMap<String, T>>
+) -> Result<BTreeMap<String, T>>
 where
     T: DeserializeOwned,
 {
-    let mut result = HashMap::new();
+    let mut result = BTreeMap::new();
     for &name in names {
         let item_prefix = prefix.clone() + name;
 
@@ -229,344 +813,4636 @@ where
     Ok(result)
 }
 
-/// Given a Settings, takes any Some values and updates them in the datastore.
-pub(crate) fn set_settings<D: DataStore>(
-    datastore: &mut D,
-    settings: &Settings,
-    transaction: &str,
-) -> Result<()> {
-    trace!("Serializing Settings to write to data store");
-    let pairs = to_pairs(settings).context(error::DataStoreSerialization { given: "Settings" })?;
-    let pending = Committed::Pending {
-        tx: transaction.into(),
-    };
-    datastore
-        .set_keys(&pairs, &pending)
-        .context(error::DataStore { op: "set_keys" })
+/// The formats accepted for settings input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    Json,
+    Toml,
 }
 
-// This is not as nice as get_settings, which uses Serializer/Deserializer to properly use the
-// data model and check types.
-/// Gets the value of a metadata key for the requested list of data keys.
-pub(crate) fn get_metadata_for_data_keys<D: DataStore, S: AsRef<str>>(
-    datastore: &D,
-    md_key_str: S,
-    data_key_strs: &HashSet<&str>,
-) -> Result<HashMap<String, Value>> {
-    trace!("Getting metadata '{}'", md_key_str.as_ref());
-    let md_key = Key::new(KeyType::Meta, md_key_str.as_ref()).context(error::NewKey {
-        key_type: "meta",
-        name: md_key_str.as_ref(),
-    })?;
+impl Format {
+    /// Returns the name of the format, for messages.
+    fn name(self) -> &'static str {
+        match self {
+            Format::Json => "JSON",
+            Format::Toml => "TOML",
+        }
+    }
 
-    let mut result = HashMap::new();
-    for data_key_str in data_key_strs {
-        trace!("Pulling metadata from datastore for key: {}", data_key_str);
-        let data_key = Key::new(KeyType::Data, data_key_str).context(error::NewKey {
-            key_type: "data",
-            name: *data_key_str,
-        })?;
-        let value_str = match datastore.get_metadata(&md_key, &data_key) {
-            Ok(Some(v)) => v,
-            // TODO: confirm we want to skip requested keys if not populated, or error
-            Ok(None) => continue,
-            // May want to make it possible to receive an error if a metadata key doesn't
-            // exist, but to start, we expect to request metadata for multiple keys and not all
-            // of them will necessarily have the metadata.
-            Err(_) => continue,
-        };
-        trace!("Deserializing scalar from metadata");
-        let value: Value = deserialize_scalar::<_, ScalarError>(&value_str)
-            .context(error::InvalidMetadata { key: md_key.name() })?;
-        result.insert(data_key.to_string(), value);
+    /// Guesses the format of the given input; JSON settings are always an object, so anything
+    /// that doesn't start with "{" is treated as TOML.
+    fn detect(input: &str) -> Self {
+        if input.trim_start().starts_with('{') {
+            Format::Json
+        } else {
+            Format::Toml
+        }
     }
+}
 
-    Ok(result)
+/// How settings input treats fields that aren't part of the settings model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum UnknownFields {
+    /// Fail, naming every unrecognized setting, so typos don't go unnoticed.  This is the default,
+    /// and what the API uses.
+    Reject,
+    /// Drop them and use the rest of the input; for internal callers whose input is intentionally
+    /// a superset of the model.
+    Ignore,
 }
 
-/// Gets the value of a metadata key everywhere it's found in the data store.  Returns a mapping
-/// of data key to the metadata value associated with the requested key.
-pub(crate) fn get_metadata_for_all_data_keys<D: DataStore, S: AsRef<str>>(
-    datastore: &D,
+/// Settings given by the user, along with the settings the user explicitly set to null, which
+/// should be removed, following JSON Merge Patch (RFC 7386) semantics.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct SettingsInput {
+    pub(crate) settings: Settings,
+    /// Data keys, e.g. "settings.motd", that were given as null.
+    pub(crate) unset: HashSet<Key>,
+}
+
+impl From<Settings> for SettingsInput {
+    fn from(settings: Settings) -> Self {
+        Self {
+            settings,
+            unset: HashSet::new(),
+        }
+    }
+}
+
+/// Parses user input into Settings, detecting whether it's JSON or TOML.  The settings can be
+/// given bare, e.g. {"motd": "hi"}, or inside an outer "settings" table, as in user data, e.g.
+/// {"settings": {"motd": "hi"}}.  In JSON, settings given as null are returned separately so
+/// they can be removed; TOML has no null.  Settings that aren't in the model are rejected.
+pub(crate) fn settings_input<S: AsRef<str>>(input: S) -> Result<SettingsInput> {
+    let input = input.as_ref();
+    settings_input_format(input, Format::detect(input))
+}
+
+/// Parses user input in the given format into Settings; see settings_input.
+pub(crate) fn settings_input_format<S: AsRef<str>>(
+    input: S,
+    format: Format,
+) -> Result<SettingsInput> {
+    settings_input_with(input, format, UnknownFields::Reject)
+}
+
+/// Parses user input in the given format into Settings, treating settings that aren't in the
+/// model as requested; see settings_input.
+pub(crate) fn settings_input_with<S: AsRef<str>>(
+    input: S,
+    format: Format,
+    unknown_fields: UnknownFields,
+) -> Result<SettingsInput> {
+    let input = input.as_ref();
+    ensure!(
+        !input.trim().is_empty(),
+        error::EmptyInput { input: "settings" }
+    );
+
+    match format {
+        Format::Json => {
+            let mut value: Value = serde_json::from_str(input).context(error::SettingsJson)?;
+            let wrapped = value
+                .as_object_mut()
+                .filter(|map| map.len() == 1)
+                .and_then(|map| map.get_mut("settings"))
+                .filter(|inner| inner.is_object());
+            if let Some(inner) = wrapped {
+                value = inner.take();
+            }
+
+            // Unknown settings are found before nulls are taken out, so a null can't be used to
+            // remove a key that isn't in the model.
+            let unknown = unknown_fields::find::<Settings>(&value);
+            check_unknown_fields(&unknown, unknown_fields, format)?;
+            for setting in unknown {
+                unknown_fields::remove_from_json(&mut value, &setting.path);
+            }
+
+            let mut unset = HashSet::new();
+            if let Value::Object(map) = &mut value {
+                take_nulls(map, &mut vec!["settings".to_string()], &mut unset)?;
+            }
+            let settings = serde_json::from_value(value).context(error::SettingsJson)?;
+            Ok(SettingsInput { settings, unset })
+        }
+        Format::Toml => {
+            let mut value: toml::Value = toml::from_str(input).context(error::SettingsToml)?;
+            let wrapped = value
+                .as_table_mut()
+                .filter(|table| table.len() == 1)
+                .and_then(|table| table.get_mut("settings"))
+                .filter(|inner| inner.is_table());
+            if let Some(inner) = wrapped {
+                value = mem::replace(inner, toml::Value::Table(Default::default()));
+            }
+
+            let unknown = unknown_fields::find::<Settings>(&unknown_fields::toml_to_json(&value));
+            check_unknown_fields(&unknown, unknown_fields, format)?;
+            for setting in unknown {
+                unknown_fields::remove_from_toml(&mut value, &setting.path);
+            }
+            let settings: Settings = value.try_into().context(error::SettingsToml)?;
+            Ok(settings.into())
+        }
+    }
+}
+
+/// Returns an UnknownSettings error listing the given settings, unless there are none or we were
+/// asked to ignore them.
+fn check_unknown_fields(
+    unknown: &[UnknownSetting],
+    unknown_fields: UnknownFields,
+    format: Format,
+) -> Result<()> {
+    ensure!(
+        unknown.is_empty() || unknown_fields == UnknownFields::Ignore,
+        error::UnknownSettings {
+            format: format.name(),
+            settings: unknown
+                .iter()
+                .map(|setting| setting.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    );
+    Ok(())
+}
+
+/// Removes null values from the given JSON object and any objects nested inside it, adding the
+/// data key of each to `unset`.  Objects left empty by this are removed too, so a struct whose
+/// only given settings were null isn't set at all.  `path` holds the key segments leading to
+/// `map`.  Returns whether anything was removed from `map`.
+fn take_nulls(
+    map: &mut serde_json::Map<String, Value>,
+    path: &mut Vec<String>,
+    unset: &mut HashSet<Key>,
+) -> Result<bool> {
+    let nulls: Vec<String> = map
+        .iter()
+        .filter(|(_, v)| v.is_null())
+        .map(|(k, _)| k.clone())
+        .collect();
+    let mut removed = !nulls.is_empty();
+    for name in nulls {
+        map.remove(&name);
+        path.push(name);
+        let key = Key::from_segments(KeyType::Data, &path[..]).context(error::NewKey {
+            key_type: "data",
+            name: path.join("."),
+        })?;
+        path.pop();
+        unset.insert(key);
+    }
+
+    let mut emptied = Vec::new();
+    for (name, value) in map.iter_mut() {
+        if let Value::Object(inner) = value {
+            path.push(name.clone());
+            if take_nulls(inner, path, unset)? {
+                removed = true;
+                if inner.is_empty() {
+                    emptied.push(name.clone());
+                }
+            }
+            path.pop();
+        }
+    }
+    for name in emptied {
+        map.remove(&name);
+    }
+    Ok(removed)
+}
+
+/// How set_settings treats existing settings in the sections it's given.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SetBehavior {
+    /// Only the given settings change; others stay as they are, including other entries in the
+    /// same maps, e.g. other host containers.  Scalar and list values replace existing ones.
+    Merge,
+    /// Each map of settings given, like "host-containers" or "kubernetes.node-labels", replaces
+    /// the existing map; entries in it that aren't given are removed.  Other settings are merged.
+    Replace,
+}
+
+/// Given settings input, takes any Some values and updates them in the datastore, and stages
+/// removal of any keys given as null.  Existing settings in the same sections are kept or
+/// removed based on `behavior`.  The settings are validated first, and nothing is written if
+/// they're invalid or any of them are immutable, or if the input has more than `max_keys` keys.
+/// Returns the keys written, including those staged for removal.
+pub(crate) fn set_settings<D: DataStore>(
+    datastore: &mut D,
+    input: &SettingsInput,
+    behavior: SetBehavior,
+    transaction: &str,
+    max_keys: usize,
+) -> Result<HashSet<Key>> {
+    validate_settings(&*datastore, &input.settings)?;
+
+    trace!("Serializing Settings to write to data store");
+    let mut pairs =
+        to_pairs(&input.settings).context(error::DataStoreSerialization { given: "Settings" })?;
+    // Nested input can turn into many keys, so we check the count after serializing.
+    let count = pairs.len() + input.unset.len();
+    ensure!(
+        count <= max_keys,
+        error::TooManyKeys {
+            count,
+            limit: max_keys
+        }
+    );
+    let pending = Committed::Pending {
+        tx: transaction.into(),
+    };
+
+    let mut unset = input.unset.clone();
+    if behavior == SetBehavior::Replace {
+        // Remove anything in the given maps that isn't being set; other settings, even in the
+        // same section, are left alone.
+        let maps: Vec<Key> = schema::settings_map_keys()?
+            .into_iter()
+            .filter(|map| {
+                pairs
+                    .keys()
+                    .any(|key| key.starts_with_segments(map.segments()))
+            })
+            .collect();
+        for map in &maps {
+            for key in populated_under(&*datastore, map, &pending)? {
+                if !pairs.contains_key(&key) {
+                    unset.insert(key);
+                }
+            }
+        }
+    }
+    for key in unset_pairs(&*datastore, &unset, &pending)? {
+        pairs.insert(key, UNSET_VALUE.to_string());
+    }
+    check_mutable(&*datastore, pairs.keys(), transaction)?;
+
+    datastore
+        .set_keys(&pairs, &pending)
+        .context(error::DataStore { op: "set_keys" })?;
+    Ok(pairs.into_iter().map(|(key, _)| key).collect())
+}
+
+/// Finds the keys to stage for removal to remove the given keys.  A key with populated keys under
+/// it in live or in the given transaction, like "settings.ntp", is a whole section of settings,
+/// so the keys under it are removed.  Otherwise the key itself is removed; removing a key that
+/// isn't set is OK.
+fn unset_pairs<D: DataStore>(
+    datastore: &D,
+    keys: &HashSet<Key>,
+    pending: &Committed,
+) -> Result<HashSet<Key>> {
+    let mut result = HashSet::new();
+    for key in keys {
+        let under = populated_under(datastore, key, pending)?;
+        if under.is_empty() {
+            result.insert(key.clone());
+        } else {
+            result.extend(under);
+        }
+    }
+    Ok(result)
+}
+
+/// Returns the data keys under the given key, e.g. "settings.ntp.time-servers" under
+/// "settings.ntp", that are populated in live or in the given pending transaction.
+fn populated_under<D: DataStore>(
+    datastore: &D,
+    key: &Key,
+    pending: &Committed,
+) -> Result<HashSet<Key>> {
+    let prefix = format!("{}.", key.name());
+    let mut result = HashSet::new();
+    for committed in &[Committed::Live, pending.clone()] {
+        let populated =
+            datastore
+                .list_populated_keys(&prefix, committed)
+                .context(error::DataStore {
+                    op: "list_populated_keys",
+                })?;
+        result.extend(populated);
+    }
+    Ok(result)
+}
+
+/// Metadata marking settings that can't be changed through the API, e.g. because they're
+/// provisioned at boot.  A value of true makes the key, and any keys under it, immutable.
+const IMMUTABLE_METADATA: &str = "immutable";
+
+/// Checks live metadata and the metadata pending in the given transaction for the given keys,
+/// returning an ImmutableKeys error naming all of them that are marked immutable in either.
+fn check_mutable<'a, D, I>(datastore: &D, keys: I, transaction: &str) -> Result<()>
+where
+    D: DataStore,
+    I: IntoIterator<Item = &'a Key>,
+{
+    let immutable_key = Key::new(KeyType::Meta, IMMUTABLE_METADATA).context(error::NewKey {
+        key_type: "meta",
+        name: IMMUTABLE_METADATA,
+    })?;
+    let pending = Committed::Pending {
+        tx: transaction.into(),
+    };
+
+    let mut immutable = Vec::new();
+    for key in keys {
+        for committed in &[Committed::Live, pending.clone()] {
+            let value_str = datastore
+                .get_metadata(&immutable_key, key, committed)
+                .context(error::DataStore { op: "get_metadata" })?;
+            if let Some(value_str) = value_str {
+                let is_immutable: bool =
+                    serde_json::from_str(&value_str).context(error::InvalidMetadata {
+                        key: IMMUTABLE_METADATA,
+                    })?;
+                if is_immutable {
+                    immutable.push(key.name().as_str());
+                    break;
+                }
+            }
+        }
+    }
+
+    immutable.sort();
+    ensure!(
+        immutable.is_empty(),
+        error::ImmutableKeys {
+            keys: immutable.join(", ")
+        }
+    );
+    Ok(())
+}
+
+/// Metadata listing the values a setting may have, as a JSON array, e.g. ["a", "b"].
+const ALLOWED_VALUES_METADATA: &str = "allowed-values";
+/// Metadata giving a regular expression that a string setting must match in full.
+const ALLOWED_PATTERN_METADATA: &str = "allowed-pattern";
+
+/// Checks that the given Settings can be stored and read back, and that each value meets any
+/// constraints given in live metadata on its key, like allowed-values or allowed-pattern.
+/// Constraints are inherited like other metadata, so a constraint on "settings.a" applies to
+/// "settings.a.b" too.  Returns an InvalidSetting error naming the first bad key.
+pub(crate) fn validate_settings<D: DataStore>(datastore: &D, settings: &Settings) -> Result<()> {
+    trace!("Validating Settings");
+    let pairs = to_pairs(settings).context(error::DataStoreSerialization { given: "Settings" })?;
+    let read_back: deserialization::Result<Settings> = from_map(&pairs);
+    if let Err(e) = read_back {
+        return error::InvalidSetting {
+            key: "settings",
+            reason: format!("can't be read back after serializing: {}", e),
+        }
+        .fail();
+    }
+
+    let allowed_values_key =
+        Key::new(KeyType::Meta, ALLOWED_VALUES_METADATA).context(error::NewKey {
+            key_type: "meta",
+            name: ALLOWED_VALUES_METADATA,
+        })?;
+    let allowed_pattern_key =
+        Key::new(KeyType::Meta, ALLOWED_PATTERN_METADATA).context(error::NewKey {
+            key_type: "meta",
+            name: ALLOWED_PATTERN_METADATA,
+        })?;
+
+    for (key, value_str) in &pairs {
+        let value: Value = deserialize_scalar::<_, ScalarError>(value_str)
+            .context(error::InvalidData { key: key.name() })?;
+
+        if let Some(allowed_str) = datastore
+            .get_metadata(&allowed_values_key, key, &Committed::Live)
+            .context(error::DataStore { op: "get_metadata" })?
+        {
+            let allowed: Vec<Value> =
+                serde_json::from_str(&allowed_str).context(error::InvalidMetadata {
+                    key: ALLOWED_VALUES_METADATA,
+                })?;
+            ensure!(
+                allowed.contains(&value),
+                error::InvalidSetting {
+                    key: key.name(),
+                    reason: format!("{} is not one of the allowed values {}", value, allowed_str),
+                }
+            );
+        }
+
+        if let Some(pattern_str) = datastore
+            .get_metadata(&allowed_pattern_key, key, &Committed::Live)
+            .context(error::DataStore { op: "get_metadata" })?
+        {
+            let pattern: String =
+                serde_json::from_str(&pattern_str).context(error::InvalidMetadata {
+                    key: ALLOWED_PATTERN_METADATA,
+                })?;
+            // Anchor the pattern so it has to match the whole value.
+            let regex =
+                Regex::new(&format!("^(?:{})$", pattern)).context(error::InvalidPattern {
+                    key: key.name(),
+                    pattern: pattern.as_str(),
+                })?;
+            let matches = value.as_str().map_or(false, |s| regex.is_match(s));
+            ensure!(
+                matches,
+                error::InvalidSetting {
+                    key: key.name(),
+                    reason: format!("{} doesn't match the allowed pattern '{}'", value, pattern),
+                }
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Stages removal of the given settings keys in the given transaction.  When the transaction is
+/// committed, the keys are removed from live, and reported as changed so that configuration is
+/// rerendered.  Removing a key that isn't set is OK, and removing a key with keys under it, like
+/// "settings.ntp", removes those keys.  Only settings keys can be removed.
+pub(crate) fn delete_settings_keys<D: DataStore>(
+    datastore: &mut D,
+    keys: &HashSet<&str>,
+    transaction: &str,
+) -> Result<()> {
+    let mut data_keys = HashSet::new();
+    for key_str in keys {
+        ensure!(
+            key_str.starts_with("settings."),
+            error::DeleteNonSettings { name: *key_str }
+        );
+        let key = Key::new(KeyType::Data, &key_str).context(error::NewKey {
+            key_type: "data",
+            name: *key_str,
+        })?;
+        data_keys.insert(key);
+    }
+    let pending = Committed::Pending {
+        tx: transaction.into(),
+    };
+    let pairs: HashMap<Key, &str> = unset_pairs(&*datastore, &data_keys, &pending)?
+        .into_iter()
+        .map(|key| (key, UNSET_VALUE))
+        .collect();
+    check_mutable(&*datastore, pairs.keys(), transaction)?;
+
+    trace!("Staging removal of keys: {:?}", pairs.keys());
+    datastore
+        .set_keys(&pairs, &pending)
+        .context(error::DataStore { op: "set_keys" })
+}
+
+// This is not as nice as get_settings, which uses Serializer/Deserializer to properly use the
+// data model and check types.
+/// Gets the value of a metadata key for the requested list of data keys.
+pub(crate) fn get_metadata_for_data_keys<D: DataStore, S: AsRef<str>>(
+    datastore: &D,
+    md_key_str: S,
+    data_key_strs: &HashSet<&str>,
+) -> Result<HashMap<String, Value>> {
+    trace!("Getting metadata '{}'", md_key_str.as_ref());
+    let md_key = Key::new(KeyType::Meta, md_key_str.as_ref()).context(error::NewKey {
+        key_type: "meta",
+        name: md_key_str.as_ref(),
+    })?;
+
+    let mut result = HashMap::new();
+    for data_key_str in data_key_strs {
+        trace!("Pulling metadata from datastore for key: {}", data_key_str);
+        let data_key = Key::new(KeyType::Data, data_key_str).context(error::NewKey {
+            key_type: "data",
+            name: *data_key_str,
+        })?;
+        let value_str = match datastore.get_metadata(&md_key, &data_key, &Committed::Live) {
+            Ok(Some(v)) => v,
+            // TODO: confirm we want to skip requested keys if not populated, or error
+            Ok(None) => continue,
+            // May want to make it possible to receive an error if a metadata key doesn't
+            // exist, but to start, we expect to request metadata for multiple keys and not all
+            // of them will necessarily have the metadata.
+            Err(_) => continue,
+        };
+        trace!("Deserializing scalar from metadata");
+        let value: Value = deserialize_scalar::<_, ScalarError>(&value_str)
+            .context(error::InvalidMetadata { key: md_key.name() })?;
+        result.insert(data_key.to_string(), value);
+    }
+
+    Ok(result)
+}
+
+/// Gets all metadata set on each of the requested data keys.  Returns a mapping of data key to
+/// a mapping of metadata key to value.  Data keys without any metadata are not included.
+pub(crate) fn get_all_metadata_for_keys<D: DataStore>(
+    datastore: &D,
+    data_key_strs: &HashSet<&str>,
+) -> Result<HashMap<String, HashMap<String, Value>>> {
+    let mut result = HashMap::new();
+    for data_key_str in data_key_strs {
+        trace!("Listing metadata in datastore for key: {}", data_key_str);
+        let data_key = Key::new(KeyType::Data, data_key_str).context(error::NewKey {
+            key_type: "data",
+            name: *data_key_str,
+        })?;
+        let md_keys = datastore
+            .list_metadata(&data_key, &Committed::Live)
+            .context(error::DataStore {
+                op: "list_metadata",
+            })?;
+
+        let mut metadata = HashMap::new();
+        for md_key in md_keys {
+            // Already confirmed key via listing keys, so an error is more serious.
+            let value_str = datastore
+                .get_metadata_raw(&md_key, &data_key, &Committed::Live)
+                .context(error::DataStore {
+                    op: "get_metadata_raw",
+                })?
+                .context(error::ListedKeyNotPresent {
+                    key: md_key.name().as_str(),
+                })?;
+            trace!("Deserializing scalar from metadata");
+            let value: Value = deserialize_scalar::<_, ScalarError>(&value_str)
+                .context(error::InvalidMetadata { key: md_key.name() })?;
+            metadata.insert(md_key.name().to_string(), value);
+        }
+
+        if !metadata.is_empty() {
+            result.insert(data_key.to_string(), metadata);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Removes the given metadata key from each of the requested data keys.  Data keys that don't
+/// have the metadata are skipped, so this is safe to call with keys whose metadata is unknown.
+pub(crate) fn unset_metadata_for_data_keys<D: DataStore, S: AsRef<str>>(
+    datastore: &mut D,
+    md_key_str: S,
+    data_key_strs: &HashSet<&str>,
+) -> Result<()> {
+    trace!("Removing metadata '{}'", md_key_str.as_ref());
+    let md_key = Key::new(KeyType::Meta, md_key_str.as_ref()).context(error::NewKey {
+        key_type: "meta",
+        name: md_key_str.as_ref(),
+    })?;
+
+    for data_key_str in data_key_strs {
+        trace!("Removing metadata from datastore for key: {}", data_key_str);
+        let data_key = Key::new(KeyType::Data, data_key_str).context(error::NewKey {
+            key_type: "data",
+            name: *data_key_str,
+        })?;
+        datastore
+            .unset_metadata(&md_key, &data_key, &Committed::Live)
+            .context(error::DataStore {
+                op: "unset_metadata",
+            })?;
+    }
+
+    Ok(())
+}
+
+/// Gets the value of a metadata key everywhere it's found in the data store.  Returns a mapping
+/// of data key to the metadata value associated with the requested key.
+pub(crate) fn get_metadata_for_all_data_keys<D: DataStore, S: AsRef<str>>(
+    datastore: &D,
     md_key_str: S,
 ) -> Result<HashMap<String, Value>> {
     trace!("Getting metadata '{}'", md_key_str.as_ref());
     let meta_map = datastore
-        .get_metadata_prefix("", &Some(md_key_str))
+        .get_metadata_prefix("", &Some(md_key_str), &Committed::Live)
         .context(error::DataStore {
             op: "get_metadata_prefix",
         })?;
 
-    let mut result = HashMap::new();
-    for (data_key, metadata) in meta_map {
-        for (meta_key, value_str) in metadata {
-            trace!("Deserializing scalar from metadata");
-            let value: Value = deserialize_scalar::<_, ScalarError>(&value_str).context(
-                error::InvalidMetadata {
-                    key: meta_key.name(),
+    let mut result = HashMap::new();
+    for (data_key, metadata) in meta_map {
+        for (meta_key, value_str) in metadata {
+            trace!("Deserializing scalar from metadata");
+            let value: Value = deserialize_scalar::<_, ScalarError>(&value_str).context(
+                error::InvalidMetadata {
+                    key: meta_key.name(),
+                },
+            )?;
+            result.insert(data_key.to_string(), value);
+        }
+    }
+    Ok(result)
+}
+
+/// Gets the value of a metadata key for each populated data key under the given prefix, e.g.
+/// "settings.ntp.", or all data keys if the prefix is empty.  Metadata is inherited as in
+/// get_metadata_for_data_keys, and data keys without the metadata are left out.
+pub(crate) fn get_metadata_for_prefix<D, S1, S2>(
+    datastore: &D,
+    md_key_str: S1,
+    prefix: S2,
+) -> Result<HashMap<String, Value>>
+where
+    D: DataStore,
+    S1: AsRef<str>,
+    S2: AsRef<str>,
+{
+    trace!(
+        "Getting metadata '{}' for keys under '{}'",
+        md_key_str.as_ref(),
+        prefix.as_ref()
+    );
+    let md_key = Key::new(KeyType::Meta, md_key_str.as_ref()).context(error::NewKey {
+        key_type: "meta",
+        name: md_key_str.as_ref(),
+    })?;
+    let data_keys = datastore
+        .list_populated_keys(prefix, &Committed::Live)
+        .context(error::DataStore {
+            op: "list_populated_keys",
+        })?;
+
+    let mut result = HashMap::new();
+    for data_key in data_keys {
+        let value_str = datastore
+            .get_metadata(&md_key, &data_key, &Committed::Live)
+            .context(error::DataStore { op: "get_metadata" })?;
+        let value_str = match value_str {
+            Some(v) => v,
+            None => continue,
+        };
+        trace!("Deserializing scalar from metadata");
+        let value: Value = deserialize_scalar::<_, ScalarError>(&value_str)
+            .context(error::InvalidMetadata { key: md_key.name() })?;
+        result.insert(data_key.to_string(), value);
+    }
+    Ok(result)
+}
+
+/// What the data store holds for a data key, uninterpreted, from `get_raw_key`.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct RawKey {
+    pub(crate) name: String,
+    /// The serialized value exactly as stored, or None if the key isn't populated.
+    pub(crate) value: Option<String>,
+    /// The names of the metadata set for the key, sorted.
+    pub(crate) metadata: Vec<String>,
+    /// Whether the key is populated in the pending transaction.
+    pub(crate) pending: bool,
+}
+
+/// Returns the stored value of the given data key in the given state, the names of its metadata,
+/// and whether it's pending in the given transaction, for debugging.  Nothing is deserialized, so
+/// this works even when the stored value is corrupt and nothing else can read it.
+pub(crate) fn get_raw_key<D: DataStore>(
+    datastore: &D,
+    name: &str,
+    committed: &Committed,
+    transaction: &str,
+) -> Result<RawKey> {
+    let key = Key::new(KeyType::Data, name).context(error::NewKey {
+        key_type: "data",
+        name,
+    })?;
+    let value = datastore
+        .get_key(&key, committed)
+        .context(error::DataStore { op: "get_key" })?;
+    let mut metadata: Vec<String> = datastore
+        .list_metadata(&key, committed)
+        .context(error::DataStore {
+            op: "list_metadata",
+        })?
+        .iter()
+        .map(|md_key| md_key.name().clone())
+        .collect();
+    metadata.sort();
+    let pending = datastore
+        .key_populated(
+            &key,
+            &Committed::Pending {
+                tx: transaction.into(),
+            },
+        )
+        .context(error::DataStore {
+            op: "key_populated",
+        })?;
+
+    Ok(RawKey {
+        name: key.name().clone(),
+        value,
+        metadata,
+        pending,
+    })
+}
+
+/// A record of a change to a setting, written to the audit log as a line of JSON.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct AuditEntry {
+    /// When the change was committed, in seconds since the Unix epoch.
+    pub(crate) time: u64,
+    pub(crate) key: String,
+    /// The live value before the change, or None if it wasn't set.
+    pub(crate) old_value: Option<Value>,
+    /// The live value after the change, or None if it was removed.
+    pub(crate) new_value: Option<Value>,
+    /// The settings generation after the change.
+    pub(crate) generation: u64,
+}
+
+/// An append-only file recording committed settings changes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditLog {
+    pub path: PathBuf,
+}
+
+impl AuditLog {
+    /// Appends the given entries to the audit log.  The file is opened in append mode for each
+    /// write, rather than held open, so it can be rotated out from under us.
+    pub(crate) fn append(&self, entries: &[AuditEntry]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .context(error::AuditLogWrite { path: &self.path })?;
+        write_audit_entries(&mut file, entries).context(error::AuditLogWrite { path: &self.path })
+    }
+}
+
+/// Writes the given entries to the given writer as lines of JSON.  The lines are written in a
+/// single write so entries from one commit aren't interleaved with others.
+pub(crate) fn write_audit_entries<W: Write>(
+    writer: &mut W,
+    entries: &[AuditEntry],
+) -> io::Result<()> {
+    let mut lines = String::new();
+    for entry in entries {
+        lines.push_str(&serde_json::to_string(entry)?);
+        lines.push('\n');
+    }
+    writer.write_all(lines.as_bytes())?;
+    writer.flush()
+}
+
+/// The data keys committed from a transaction, split by whether their live values changed.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct CommittedKeys {
+    /// Keys whose live values changed, including keys that were removed.
+    pub(crate) changed: HashSet<Key>,
+    /// Keys committed with the value they already had in live, or removed when they weren't set.
+    pub(crate) unchanged: HashSet<Key>,
+    /// The settings generation after the commit; see get_generation.
+    pub(crate) generation: u64,
+    /// An entry for each committed settings key, in key order, for the audit log.
+    pub(crate) audit: Vec<AuditEntry>,
+}
+
+impl CommittedKeys {
+    /// Returns true if nothing was committed.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.unchanged.is_empty()
+    }
+
+    /// Returns the number of keys committed, whether or not they changed.
+    pub(crate) fn len(&self) -> usize {
+        self.changed.len() + self.unchanged.len()
+    }
+
+    /// Splits the keys returned by a data store commit using the set of keys that were found to
+    /// be unchanged before the commit, and makes audit entries for them using `diff`, the
+    /// pending diff from before the commit.
+    fn split(
+        committed: HashSet<Key>,
+        unchanged: &HashSet<Key>,
+        generation: u64,
+        mut diff: HashMap<Key, (Option<Value>, Option<Value>)>,
+    ) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut audit: Vec<AuditEntry> = committed
+            .iter()
+            .filter_map(|key| {
+                diff.remove(key).map(|(old_value, new_value)| AuditEntry {
+                    time,
+                    key: key.name().to_string(),
+                    old_value,
+                    new_value,
+                    generation,
+                })
+            })
+            .collect();
+        audit.sort_by(|a, b| a.key.cmp(&b.key));
+
+        let (unchanged, changed) = committed
+            .into_iter()
+            .partition(|key| unchanged.contains(key));
+        Self {
+            changed,
+            unchanged,
+            generation,
+            audit,
+        }
+    }
+}
+
+/// Makes live any pending settings in the datastore, returning the committed keys, split by
+/// whether their values changed.
+pub(crate) fn commit_transaction<D>(datastore: &mut D, transaction: &str) -> Result<CommittedKeys>
+where
+    D: DataStore,
+{
+    validate_transaction(&*datastore, transaction)?;
+    let pending = Committed::Pending {
+        tx: transaction.into(),
+    };
+    let pending_keys = datastore
+        .list_populated_keys("", &pending)
+        .context(error::DataStore {
+            op: "list_populated_keys",
+        })?;
+    let unchanged = unchanged_keys(&*datastore, &pending_keys, &pending)?;
+    let diff = get_pending_diff(&*datastore, transaction, true)?;
+
+    // Stage the new generation in the transaction, so it goes live in the same commit as the
+    // settings; otherwise, a failure in between could leave changed settings at an old generation.
+    let changed = pending_keys.iter().any(|key| !unchanged.contains(key));
+    let generation = stage_generation(datastore, &pending, changed)?;
+    let committed = with_commit_marker(datastore, transaction, |datastore| {
+        datastore
+            .commit_transaction(transaction)
+            .context(error::DataStore { op: "commit" })
+    });
+    let committed = match committed {
+        Ok(committed) => committed,
+        Err(e) => {
+            // Don't leave the staged generation behind to be committed after other changes.
+            if changed {
+                let (md_key, data_key) = generation_keys()?;
+                if let Err(unset_err) = datastore.unset_metadata(&md_key, &data_key, &pending) {
+                    warn!("Failed to remove staged settings generation: {}", unset_err);
+                }
+            }
+            return Err(e);
+        }
+    };
+    Ok(CommittedKeys::split(
+        committed, &unchanged, generation, diff,
+    ))
+}
+
+/// The data key whose live metadata holds the API server's own state, like the settings
+/// generation and the commit marker.  It's outside "settings", so the state isn't inherited by
+/// settings or listed with their metadata, and it's left out of dumps.
+const SERVER_STATE_KEY: &str = "apiserver";
+
+/// Returns the data key that holds the server's own state; see SERVER_STATE_KEY.
+fn server_state_key() -> Result<Key> {
+    Key::new(KeyType::Data, SERVER_STATE_KEY).context(error::NewKey {
+        key_type: "data",
+        name: SERVER_STATE_KEY,
+    })
+}
+
+/// The metadata key, set on the server state key in live, that holds the settings generation.
+const GENERATION_METADATA: &str = "settings-generation";
+
+/// Returns the settings generation, a number that increases each time live settings change, so
+/// clients can cheaply tell whether anything changed since they last looked.  A data store that
+/// has never had changes committed is at generation 0.
+pub(crate) fn get_generation<D: DataStore>(datastore: &D) -> Result<u64> {
+    let (md_key, data_key) = generation_keys()?;
+    let value_str = datastore
+        .get_metadata_raw(&md_key, &data_key, &Committed::Live)
+        .context(error::DataStore {
+            op: "get_metadata_raw",
+        })?;
+    match value_str {
+        Some(value_str) => {
+            deserialize_scalar::<_, ScalarError>(&value_str).context(error::InvalidMetadata {
+                key: GENERATION_METADATA,
+            })
+        }
+        None => Ok(0),
+    }
+}
+
+/// Increments the settings generation, returning the new generation.
+fn bump_generation<D: DataStore>(datastore: &mut D) -> Result<u64> {
+    let generation = get_generation(&*datastore)? + 1;
+    let (md_key, data_key) = generation_keys()?;
+    datastore
+        .set_metadata(&md_key, &data_key, generation.to_string(), &Committed::Live)
+        .context(error::DataStore { op: "set_metadata" })?;
+    trace!("Settings generation is now {}", generation);
+    Ok(generation)
+}
+
+/// If `changed`, stages the next settings generation in the given pending transaction, so that
+/// committing the transaction also bumps the generation, and returns it.  Otherwise, returns the
+/// current generation.
+fn stage_generation<D: DataStore>(
+    datastore: &mut D,
+    pending: &Committed,
+    changed: bool,
+) -> Result<u64> {
+    let generation = get_generation(&*datastore)?;
+    if !changed {
+        return Ok(generation);
+    }
+    let (md_key, data_key) = generation_keys()?;
+    datastore
+        .set_metadata(&md_key, &data_key, (generation + 1).to_string(), pending)
+        .context(error::DataStore { op: "set_metadata" })?;
+    Ok(generation + 1)
+}
+
+/// Bumps the settings generation if any of the committed keys changed, returning the current
+/// generation.  This is for commits of only some keys, which don't carry the transaction's other
+/// metadata to live, so the generation can't be staged with them like in commit_transaction.
+fn update_generation<D: DataStore>(
+    datastore: &mut D,
+    committed: &HashSet<Key>,
+    unchanged: &HashSet<Key>,
+) -> Result<u64> {
+    if committed.iter().any(|key| !unchanged.contains(key)) {
+        bump_generation(datastore)
+    } else {
+        get_generation(&*datastore)
+    }
+}
+
+/// Returns the metadata key and data key that hold the settings generation.
+fn generation_keys() -> Result<(Key, Key)> {
+    let md_key = Key::new(KeyType::Meta, GENERATION_METADATA).context(error::NewKey {
+        key_type: "meta",
+        name: GENERATION_METADATA,
+    })?;
+    Ok((md_key, server_state_key()?))
+}
+
+/// The metadata key, set on the server state key in live while a transaction is being
+/// committed, that holds the name of the transaction.  If the server stops partway through a
+/// commit, the marker stays behind, so we can tell live settings may have only part of it.
+const COMMIT_MARKER_METADATA: &str = "commit-in-progress";
+
+/// Runs the given commit with the commit marker set, so it can be detected if it's interrupted;
+/// see get_interrupted_commit.  The marker is removed once the commit returns, whether or not it
+/// succeeded, since a failed commit has already reported its error to the caller.
+fn with_commit_marker<D, T, F>(datastore: &mut D, transaction: &str, commit: F) -> Result<T>
+where
+    D: DataStore,
+    F: FnOnce(&mut D) -> Result<T>,
+{
+    let (md_key, data_key) = commit_marker_keys()?;
+    let value =
+        serialize_scalar::<_, ScalarError>(&transaction).context(error::InvalidMetadata {
+            key: COMMIT_MARKER_METADATA,
+        })?;
+    datastore
+        .set_metadata(&md_key, &data_key, value, &Committed::Live)
+        .context(error::DataStore { op: "set_metadata" })?;
+
+    let result = commit(datastore);
+    let cleared = datastore
+        .unset_metadata(&md_key, &data_key, &Committed::Live)
+        .context(error::DataStore {
+            op: "unset_metadata",
+        });
+    let committed = result?;
+    cleared?;
+    Ok(committed)
+}
+
+/// Returns the name of the transaction whose commit was interrupted, leaving live settings with
+/// only part of it, or None if the last commit finished.
+pub(crate) fn get_interrupted_commit<D: DataStore>(datastore: &D) -> Result<Option<String>> {
+    let (md_key, data_key) = commit_marker_keys()?;
+    let value_str = datastore
+        .get_metadata_raw(&md_key, &data_key, &Committed::Live)
+        .context(error::DataStore {
+            op: "get_metadata_raw",
+        })?;
+    match value_str {
+        Some(value_str) => deserialize_scalar::<_, ScalarError>(&value_str)
+            .map(Some)
+            .context(error::InvalidMetadata {
+                key: COMMIT_MARKER_METADATA,
+            }),
+        None => Ok(None),
+    }
+}
+
+/// Returns the metadata key and data key that hold the commit marker.
+fn commit_marker_keys() -> Result<(Key, Key)> {
+    let md_key = Key::new(KeyType::Meta, COMMIT_MARKER_METADATA).context(error::NewKey {
+        key_type: "meta",
+        name: COMMIT_MARKER_METADATA,
+    })?;
+    Ok((md_key, server_state_key()?))
+}
+
+/// The result of one of the checks run by `check_readiness`.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct ReadinessCheck {
+    pub(crate) name: &'static str,
+    pub(crate) ok: bool,
+    /// Why the check failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+/// Whether the data store is ready to serve requests, from `check_readiness`.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct Readiness {
+    /// True if every check passed.
+    pub(crate) ready: bool,
+    pub(crate) checks: Vec<ReadinessCheck>,
+}
+
+/// Checks whether the data store is ready to serve requests: it's available, the live settings,
+/// services, and configuration files can be read, and no commit was interrupted.  Every check is
+/// run, even after one fails, so all problems are reported at once.
+pub(crate) fn check_readiness<D: DataStore>(datastore: &D) -> Readiness {
+    let check = |name, result: Result<()>| ReadinessCheck {
+        name,
+        ok: result.is_ok(),
+        error: result.err().map(|e| e.to_string()),
+    };
+
+    let checks = vec![
+        check(
+            "datastore",
+            datastore.check_available().context(error::DataStore {
+                op: "check_available",
+            }),
+        ),
+        check(
+            "settings",
+            get_settings(datastore, &Committed::Live).map(|_| ()),
+        ),
+        check("services", get_services(datastore).map(|_| ())),
+        check(
+            "configuration-files",
+            get_configuration_files(datastore).map(|_| ()),
+        ),
+        check(
+            "commit",
+            get_interrupted_commit(datastore).and_then(|interrupted| match interrupted {
+                Some(transaction) => error::InterruptedCommit { transaction }.fail(),
+                None => Ok(()),
+            }),
+        ),
+    ];
+
+    Readiness {
+        ready: checks.iter().all(|check| check.ok),
+        checks,
+    }
+}
+
+/// Metadata on a snapshot's data key, e.g. "snapshots.before-upgrade", giving the time it was
+/// created in milliseconds since the Unix epoch.
+const SNAPSHOT_TIME_METADATA: &str = "snapshot-time";
+
+/// The number of settings snapshots kept by default; older ones are removed automatically.
+pub(crate) const DEFAULT_SNAPSHOT_RETENTION: usize = 10;
+
+/// A saved copy of the live settings.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct SnapshotInfo {
+    pub(crate) name: String,
+    /// When the snapshot was created, in milliseconds since the Unix epoch.
+    pub(crate) created_millis: u64,
+}
+
+/// Returns the data key under which the named snapshot is stored.
+fn snapshot_key(name: &str) -> Result<Key> {
+    Key::from_segments(KeyType::Data, &[SNAPSHOTS_SEGMENT, name]).context(error::NewKey {
+        key_type: "data",
+        name,
+    })
+}
+
+/// Returns the metadata key holding each snapshot's creation time.
+fn snapshot_time_key() -> Result<Key> {
+    Key::new(KeyType::Meta, SNAPSHOT_TIME_METADATA).context(error::NewKey {
+        key_type: "meta",
+        name: SNAPSHOT_TIME_METADATA,
+    })
+}
+
+/// Copies the current live settings into a snapshot with the given name, which must not already
+/// exist.  After the snapshot is created, the oldest snapshots are removed so that at most
+/// `retention` remain, though the new snapshot is always kept.
+pub(crate) fn create_snapshot<D: DataStore>(
+    datastore: &mut D,
+    name: &str,
+    retention: usize,
+) -> Result<SnapshotInfo> {
+    let base = snapshot_key(name)?;
+    let existing = list_snapshots(&*datastore)?;
+    ensure!(
+        !existing.iter().any(|snapshot| snapshot.name == name),
+        error::SnapshotExists { name }
+    );
+
+    let live = datastore
+        .get_prefix("settings.", &Committed::Live)
+        .context(error::DataStore { op: "get_prefix" })?;
+    let mut pairs = HashMap::with_capacity(live.len());
+    for (key, value) in live {
+        let mut segments = base.segments().clone();
+        segments.extend(key.segments().iter().cloned());
+        let snapshot_key = Key::from_segments(KeyType::Data, &segments).context(error::NewKey {
+            key_type: "data",
+            name: segments.join("."),
+        })?;
+        pairs.insert(snapshot_key, value);
+    }
+    datastore
+        .set_keys(&pairs, &Committed::Live)
+        .context(error::DataStore { op: "set_keys" })?;
+
+    // Make sure creation times are strictly increasing, even if the clock isn't, so the order
+    // of snapshots is always clear.
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let newest = existing.last().map(|s| s.created_millis + 1).unwrap_or(0);
+    let snapshot = SnapshotInfo {
+        name: name.to_string(),
+        created_millis: now.max(newest),
+    };
+    datastore
+        .set_metadata(
+            &snapshot_time_key()?,
+            &base,
+            snapshot.created_millis.to_string(),
+            &Committed::Live,
+        )
+        .context(error::DataStore { op: "set_metadata" })?;
+    debug!(
+        "Created settings snapshot '{}' of {} keys",
+        name,
+        pairs.len()
+    );
+
+    let retention = retention.max(1);
+    if existing.len() + 1 > retention {
+        for old in &existing[..existing.len() + 1 - retention] {
+            debug!("Removing settings snapshot '{}' past retention", old.name);
+            delete_snapshot(datastore, &old.name)?;
+        }
+    }
+
+    Ok(snapshot)
+}
+
+/// Lists the settings snapshots in the data store, oldest first.
+pub(crate) fn list_snapshots<D: DataStore>(datastore: &D) -> Result<Vec<SnapshotInfo>> {
+    let prefix = format!("{}.", SNAPSHOTS_SEGMENT);
+    let times = datastore
+        .get_metadata_prefix(&prefix, &Some(SNAPSHOT_TIME_METADATA), &Committed::Live)
+        .context(error::DataStore {
+            op: "get_metadata_prefix",
+        })?;
+
+    let mut snapshots = Vec::with_capacity(times.len());
+    for (data_key, metadata) in times {
+        // Snapshot data keys are exactly two segments; other keys are the copied settings.
+        let name = match data_key.segments().as_slice() {
+            [_, name] => name.clone(),
+            _ => continue,
+        };
+        for value_str in metadata.values() {
+            let created_millis = deserialize_scalar::<_, ScalarError>(value_str).context(
+                error::InvalidMetadata {
+                    key: SNAPSHOT_TIME_METADATA,
+                },
+            )?;
+            snapshots.push(SnapshotInfo {
+                name: name.clone(),
+                created_millis,
+            });
+        }
+    }
+    snapshots.sort_by(|a, b| {
+        a.created_millis
+            .cmp(&b.created_millis)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    Ok(snapshots)
+}
+
+/// Removes the named snapshot from the data store, which is an error if it doesn't exist.
+pub(crate) fn delete_snapshot<D: DataStore>(datastore: &mut D, name: &str) -> Result<()> {
+    ensure!(
+        list_snapshots(&*datastore)?
+            .iter()
+            .any(|snapshot| snapshot.name == name),
+        error::SnapshotNotFound { name }
+    );
+    let base = snapshot_key(name)?;
+    let keys = datastore
+        .list_populated_keys(format!("{}.", base.name()), &Committed::Live)
+        .context(error::DataStore {
+            op: "list_populated_keys",
+        })?;
+    datastore
+        .unset_keys(&keys, &Committed::Live)
+        .context(error::DataStore { op: "unset_keys" })?;
+    let mut names = HashSet::new();
+    names.insert(base.name().as_str());
+    unset_metadata_for_data_keys(datastore, SNAPSHOT_TIME_METADATA, &names)
+}
+
+/// Stages the settings from the named snapshot in the given transaction, so that committing the
+/// transaction returns live settings to their state when the snapshot was taken.  Only
+/// differences are staged: settings whose values differ from the snapshot are set, and settings
+/// that weren't in the snapshot are staged for removal.  A setting's value is the one pending in
+/// the transaction, if any, since that's what committing it would make live.  Returns the staged
+/// keys.
+pub(crate) fn restore_snapshot<D: DataStore>(
+    datastore: &mut D,
+    name: &str,
+    transaction: &str,
+) -> Result<HashSet<Key>> {
+    ensure!(
+        list_snapshots(&*datastore)?
+            .iter()
+            .any(|snapshot| snapshot.name == name),
+        error::SnapshotNotFound { name }
+    );
+    let base = snapshot_key(name)?;
+    let pending = Committed::Pending {
+        tx: transaction.into(),
+    };
+
+    let snapshot_data = datastore
+        .get_prefix(format!("{}.", base.name()), &Committed::Live)
+        .context(error::DataStore { op: "get_prefix" })?;
+    let mut snapshot = HashMap::with_capacity(snapshot_data.len());
+    for (key, value) in snapshot_data {
+        let segments = &key.segments()[base.segments().len()..];
+        let setting = Key::from_segments(KeyType::Data, segments).context(error::NewKey {
+            key_type: "data",
+            name: segments.join("."),
+        })?;
+        snapshot.insert(setting, value);
+    }
+
+    let live = datastore
+        .get_prefix("settings.", &Committed::Live)
+        .context(error::DataStore { op: "get_prefix" })?;
+    let pending_data = datastore
+        .get_prefix("settings.", &pending)
+        .context(error::DataStore { op: "get_prefix" })?;
+    // The value committing the transaction would leave, or None if the key would be unset
+    let effective = |key: &Key| match pending_data.get(key) {
+        Some(value) if value == UNSET_VALUE => None,
+        Some(value) => Some(value),
+        None => live.get(key),
+    };
+    let mut pairs = HashMap::new();
+    for (key, value) in &snapshot {
+        if effective(key) != Some(value) {
+            pairs.insert(key.clone(), value.clone());
+        }
+    }
+    // Remove anything set since the snapshot, including changes already pending
+    for key in live.keys().chain(pending_data.keys()) {
+        if !snapshot.contains_key(key) {
+            pairs.insert(key.clone(), UNSET_VALUE.to_string());
+        }
+    }
+    check_mutable(&*datastore, pairs.keys(), transaction)?;
+
+    datastore
+        .set_keys(&pairs, &pending)
+        .context(error::DataStore { op: "set_keys" })?;
+    Ok(pairs.into_iter().map(|(key, _)| key).collect())
+}
+
+/// Returns the given keys whose values in the given pending transaction match live, meaning
+/// committing them won't change anything.  A key pending removal is unchanged if it isn't set in
+/// live.
+///
+/// Values are compared as serialized strings, which is cheap, but means values that are
+/// semantically equal and serialized differently, e.g. by someone writing to the data store
+/// directly, count as changed.  That's safe; at worst, we apply changes we didn't need to.
+fn unchanged_keys<D: DataStore>(
+    datastore: &D,
+    keys: &HashSet<Key>,
+    pending: &Committed,
+) -> Result<HashSet<Key>> {
+    let pending_values = datastore
+        .get_keys(keys, pending)
+        .context(error::DataStore { op: "get_keys" })?;
+    let live_values = datastore
+        .get_keys(keys, &Committed::Live)
+        .context(error::DataStore { op: "get_keys" })?;
+
+    let mut unchanged = HashSet::new();
+    for (key, pending_value) in pending_values {
+        let live_value = live_values.get(&key).and_then(|v| v.as_ref());
+        let same = match (pending_value.as_ref(), live_value) {
+            (Some(pending), None) => pending == UNSET_VALUE,
+            (Some(pending), Some(live)) => pending == live,
+            // Not actually pending, so committing it won't change anything
+            (None, _) => true,
+        };
+        if same {
+            trace!("Pending key {} is unchanged from live", key);
+            unchanged.insert(key);
+        }
+    }
+    Ok(unchanged)
+}
+
+/// Validates the settings pending in the given transaction before they're committed, in case
+/// they were written without going through set_settings, or constraints have changed since.
+fn validate_transaction<D: DataStore>(datastore: &D, transaction: &str) -> Result<()> {
+    let settings = get_transaction(datastore, transaction)?;
+    validate_settings(datastore, &settings)
+}
+
+/// Makes live any pending changes to the given keys in the given transaction, leaving other
+/// pending changes in place.  If no keys are given, commits the whole transaction.
+pub(crate) fn commit_transaction_keys<D>(
+    datastore: &mut D,
+    transaction: &str,
+    keys: Option<&HashSet<&str>>,
+) -> Result<CommittedKeys>
+where
+    D: DataStore,
+{
+    let keys = match keys {
+        Some(keys) => keys,
+        None => return commit_transaction(datastore, transaction),
+    };
+
+    let mut data_keys = HashSet::new();
+    for key_str in keys {
+        let key = Key::new(KeyType::Data, key_str).context(error::NewKey {
+            key_type: "data",
+            name: *key_str,
+        })?;
+        data_keys.insert(key);
+    }
+
+    // Check up front so we can tell the caller which keys weren't pending.
+    let pending = Committed::Pending {
+        tx: transaction.into(),
+    };
+    let mut missing = Vec::new();
+    for key in &data_keys {
+        let populated = datastore
+            .key_populated(key, &pending)
+            .context(error::DataStore {
+                op: "key_populated",
+            })?;
+        if !populated {
+            missing.push(key.name().to_string());
+        }
+    }
+    missing.sort();
+    ensure!(
+        missing.is_empty(),
+        error::KeysNotPending {
+            transaction,
+            keys: missing.join(", "),
+        }
+    );
+
+    validate_transaction(&*datastore, transaction)?;
+    let unchanged = unchanged_keys(&*datastore, &data_keys, &pending)?;
+    let diff = get_pending_diff(&*datastore, transaction, true)?;
+    let committed = with_commit_marker(datastore, transaction, |datastore| {
+        datastore
+            .commit_keys(transaction, &data_keys)
+            .context(error::DataStore { op: "commit_keys" })
+    })?;
+    let generation = update_generation(datastore, &committed, &unchanged)?;
+    Ok(CommittedKeys::split(
+        committed, &unchanged, generation, diff,
+    ))
+}
+
+/// A pending change to a data key, as reported by `dry_run_commit` and `get_pending_changes`.  A
+/// value of None means the key is unset, or would be unset by the commit.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct PendingChange {
+    pub(crate) old: Option<Value>,
+    pub(crate) new: Option<Value>,
+}
+
+/// A preview of what committing a transaction would do, from `dry_run_commit`.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct DryRunReport {
+    /// Pending data keys whose values differ from live, by key name.
+    pub(crate) changes: HashMap<String, PendingChange>,
+    /// Services affected by the changes, which would be restarted when they're applied.
+    pub(crate) services: Services,
+    /// Configuration files of the affected services, which would be rewritten.
+    pub(crate) configuration_files: ConfigurationFiles,
+}
+
+/// Metadata on settings naming the services that need to be restarted when they change.
+const AFFECTED_SERVICES_METADATA: &str = "affected-services";
+
+/// Returns the services affected by changes to the given data keys, according to their
+/// "affected-services" metadata.  A key without its own metadata uses that of its nearest parent
+/// that has some, so metadata on "settings.ntp" covers "settings.ntp.time-servers".  Keys with no
+/// metadata at any level don't affect any services.
+pub(crate) fn get_affected_services<D: DataStore>(
+    datastore: &D,
+    keys: &HashSet<Key>,
+) -> Result<HashSet<String>> {
+    let md_key = Key::new(KeyType::Meta, AFFECTED_SERVICES_METADATA).context(error::NewKey {
+        key_type: "meta",
+        name: AFFECTED_SERVICES_METADATA,
+    })?;
+
+    let mut services = HashSet::new();
+    for key in keys {
+        // get_metadata walks up the key's parents for us, and returns the most specific value
+        let value_str = datastore
+            .get_metadata(&md_key, key, &Committed::Live)
+            .context(error::DataStore { op: "get_metadata" })?;
+        if let Some(value_str) = value_str {
+            let names: Vec<String> = deserialize_scalar::<_, ScalarError>(&value_str).context(
+                error::InvalidMetadata {
+                    key: AFFECTED_SERVICES_METADATA,
+                },
+            )?;
+            trace!("Key {} affects services: {:?}", key, names);
+            services.extend(names);
+        }
+    }
+    Ok(services)
+}
+
+/// Reports the changes committing the given transaction would make, the services affected by
+/// those changes according to their "affected-services" metadata, and the configuration files
+/// of those services.  Nothing is written to the data store.
+pub(crate) fn dry_run_commit<D: DataStore>(
+    datastore: &D,
+    transaction: &str,
+) -> Result<DryRunReport> {
+    let diff = get_pending_diff(datastore, transaction, false)?;
+
+    let changed_keys = diff.keys().cloned().collect();
+    let service_names = get_affected_services(datastore, &changed_keys)?;
+    let service_names = service_names.iter().map(|s| s.as_str()).collect();
+    let services = get_services_names(datastore, &service_names, &Committed::Live)?;
+
+    let file_names = services
+        .values()
+        .flat_map(|service| service.configuration_files.iter().map(AsRef::<str>::as_ref))
+        .collect();
+    let configuration_files =
+        get_configuration_files_names(datastore, &file_names, &Committed::Live)?;
+
+    let changes = diff
+        .into_iter()
+        .map(|(key, (old, new))| (key.name().to_string(), PendingChange { old, new }))
+        .collect();
+
+    Ok(DryRunReport {
+        changes,
+        services,
+        configuration_files,
+    })
+}
+
+/// The prefixes of data keys included in a dump of the data store.
+const DUMP_PREFIXES: &[&str] = &["settings.", "services.", "configuration-files."];
+
+/// The name of the section of a dump that holds metadata, keyed by data key name.
+const DUMP_METADATA_SECTION: &str = "metadata";
+
+/// Builds a single JSON document holding all data and metadata in the given part of the data
+/// store, for debugging and backup.  Data keys are nested to mirror the key hierarchy, e.g.
+/// {"settings": {"motd": "hi"}}, and metadata is under a "metadata" section keyed by the full data
+/// key name, e.g. {"metadata": {"settings.motd": {"affected-services": ["motd"]}}}.
+pub(crate) fn dump_all<D: DataStore>(datastore: &D, committed: &Committed) -> Result<Value> {
+    let mut dump = serde_json::Map::new();
+
+    for prefix in DUMP_PREFIXES {
+        let data = datastore
+            .get_prefix(prefix, committed)
+            .context(error::DataStore { op: "get_prefix" })?;
+        for (key, value_str) in data {
+            trace!("Deserializing scalar from key {} for dump", key);
+            let value: Value = deserialize_scalar::<_, ScalarError>(&value_str)
+                .context(error::InvalidData { key: key.name() })?;
+            insert_nested(&mut dump, &key, value)?;
+        }
+    }
+
+    let metadata_keys = datastore
+        .list_populated_metadata("", &None as &Option<&str>, committed)
+        .context(error::DataStore {
+            op: "list_populated_metadata",
+        })?;
+    let state_key = server_state_key()?;
+    let mut metadata_section = serde_json::Map::new();
+    for (data_key, md_keys) in metadata_keys {
+        // The server's own state isn't data store content
+        if data_key == state_key {
+            continue;
+        }
+        let mut metadata = serde_json::Map::new();
+        for md_key in md_keys {
+            // Already confirmed key via listing keys, so an error is more serious.
+            let value_str = datastore
+                .get_metadata_raw(&md_key, &data_key, committed)
+                .context(error::DataStore {
+                    op: "get_metadata_raw",
+                })?
+                .context(error::ListedKeyNotPresent {
+                    key: md_key.name().as_str(),
+                })?;
+            let value: Value = deserialize_scalar::<_, ScalarError>(&value_str)
+                .context(error::InvalidMetadata { key: md_key.name() })?;
+            metadata.insert(md_key.name().to_string(), value);
+        }
+        metadata_section.insert(data_key.name().to_string(), Value::Object(metadata));
+    }
+    if !metadata_section.is_empty() {
+        dump.insert(
+            DUMP_METADATA_SECTION.to_string(),
+            Value::Object(metadata_section),
+        );
+    }
+
+    Ok(Value::Object(dump))
+}
+
+/// Loads a JSON document in the format produced by `dump_all`, or the format of the defaults
+/// TOML where metadata is nested like data, into the given part of the data store.  Returns the
+/// data keys that were written.
+///
+/// Data is checked against the model before anything is written.  If any key in the document is
+/// already populated, nothing is written unless `overwrite` is true.  If a write fails partway,
+/// we restore the previous values so the document is loaded entirely or not at all.
+///
+/// Note: metadata values that are themselves objects can't be told apart from nesting, so they
+/// aren't supported.
+pub(crate) fn load_dump<D: DataStore>(
+    datastore: &mut D,
+    dump: Value,
+    committed: &Committed,
+    overwrite: bool,
+) -> Result<HashSet<Key>> {
+    let mut dump = match dump {
+        Value::Object(map) => map,
+        _ => {
+            return error::DumpFormat {
+                msg: "dump must be a JSON object",
+            }
+            .fail()
+        }
+    };
+
+    // Pull out metadata, which is keyed differently than data.
+    let mut metadata = Vec::new();
+    if let Some(metadata_val) = dump.remove(DUMP_METADATA_SECTION) {
+        collect_dump_metadata(&[], &metadata_val, &mut metadata)?;
+    }
+    // Loading the server's own state could make it lose track of the generation or of commits.
+    let state_key = server_state_key()?;
+    ensure!(
+        metadata
+            .iter()
+            .all(|(data_key, _, _)| *data_key != state_key),
+        error::DumpFormat {
+            msg: format!("metadata for '{}' is reserved", SERVER_STATE_KEY),
+        }
+    );
+
+    // Check each section against the model, and flatten it into data store keys.
+    let mut pairs = HashMap::new();
+    for (section, value) in dump {
+        let section_pairs = match section.as_str() {
+            "settings" => {
+                let settings: Settings =
+                    serde_json::from_value(value).context(error::DumpDeserialization {
+                        section: "settings",
+                    })?;
+                to_pairs(&settings)
+            }
+            "services" => {
+                let services: Services =
+                    serde_json::from_value(value).context(error::DumpDeserialization {
+                        section: "services",
+                    })?;
+                to_pairs_with_prefix("services", &services)
+            }
+            "configuration-files" => {
+                let files: ConfigurationFiles =
+                    serde_json::from_value(value).context(error::DumpDeserialization {
+                        section: "configuration-files",
+                    })?;
+                to_pairs_with_prefix("configuration-files", &files)
+            }
+            _ => {
+                return error::DumpFormat {
+                    msg: format!("unknown section '{}'", section),
+                }
+                .fail()
+            }
+        }
+        .context(error::DataStoreSerialization {
+            given: section.as_str(),
+        })?;
+        pairs.extend(section_pairs);
+    }
+
+    // Find what's there now, so we can check for conflicts and restore it if a write fails.
+    let data_keys: HashSet<Key> = pairs.keys().cloned().collect();
+    let previous_data = datastore
+        .get_keys(&data_keys, committed)
+        .context(error::DataStore { op: "get_keys" })?;
+    let mut previous_metadata = Vec::with_capacity(metadata.len());
+    for (data_key, md_key, _) in &metadata {
+        let previous = datastore
+            .get_metadata_raw(md_key, data_key, committed)
+            .context(error::DataStore {
+                op: "get_metadata_raw",
+            })?;
+        previous_metadata.push((data_key.clone(), md_key.clone(), previous));
+    }
+
+    if !overwrite {
+        let mut conflicts: Vec<String> = previous_data
+            .iter()
+            .filter(|(_, value)| value.is_some())
+            .map(|(key, _)| key.to_string())
+            .chain(
+                previous_metadata
+                    .iter()
+                    .filter(|(_, _, value)| value.is_some())
+                    .map(|(data_key, md_key, _)| format!("{} metadata {}", data_key, md_key)),
+            )
+            .collect();
+        conflicts.sort();
+        ensure!(
+            conflicts.is_empty(),
+            error::DumpConflicts {
+                keys: conflicts.join(", "),
+            }
+        );
+    }
+
+    let result = write_dump(datastore, &pairs, &metadata, committed);
+    if result.is_err() {
+        error!("Failed to load dump, restoring previous values");
+        restore_dump(datastore, previous_data, previous_metadata, committed);
+    }
+    result?;
+
+    Ok(data_keys)
+}
+
+/// Walks the metadata section of a dump, collecting (data key, metadata key, serialized value).
+/// Object keys are data key names, possibly dotted, so both flat and nested forms work; the
+/// first non-object value we find is a metadata value.
+fn collect_dump_metadata(
+    segments: &[String],
+    value: &Value,
+    output: &mut Vec<(Key, Key, String)>,
+) -> Result<()> {
+    let map = value.as_object().context(error::DumpFormat {
+        msg: "metadata must be a JSON object",
+    })?;
+
+    for (name, value) in map {
+        if value.is_object() {
+            let key = Key::new(KeyType::Data, name).context(error::NewKey {
+                key_type: "data",
+                name,
+            })?;
+            let mut child_segments = segments.to_vec();
+            child_segments.extend(key.segments().iter().cloned());
+            collect_dump_metadata(&child_segments, value, output)?;
+        } else {
+            let data_key = Key::from_segments(KeyType::Data, segments).context(error::NewKey {
+                key_type: "data",
+                name: segments.join("."),
+            })?;
+            let md_key = Key::new(KeyType::Meta, name).context(error::NewKey {
+                key_type: "meta",
+                name,
+            })?;
+            let value_str = serialize_scalar::<_, ScalarError>(value)
+                .context(error::InvalidMetadata { key: name.as_str() })?;
+            output.push((data_key, md_key, value_str));
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the data and metadata from a dump to the data store.
+fn write_dump<D: DataStore>(
+    datastore: &mut D,
+    pairs: &HashMap<Key, String>,
+    metadata: &[(Key, Key, String)],
+    committed: &Committed,
+) -> Result<()> {
+    datastore
+        .set_keys(pairs, committed)
+        .context(error::DataStore { op: "set_keys" })?;
+    for (data_key, md_key, value) in metadata {
+        datastore
+            .set_metadata(md_key, data_key, value, committed)
+            .context(error::DataStore { op: "set_metadata" })?;
+    }
+    Ok(())
+}
+
+/// Puts back the given data and metadata values after a failed load, removing anything that
+/// wasn't there before.  We're already handling a failure, so we log any further errors rather
+/// than returning them.
+fn restore_dump<D: DataStore>(
+    datastore: &mut D,
+    previous_data: HashMap<Key, Option<String>>,
+    previous_metadata: Vec<(Key, Key, Option<String>)>,
+    committed: &Committed,
+) {
+    for (key, value) in previous_data {
+        let restored = match value {
+            Some(value) => datastore.set_key(&key, value, committed),
+            None => datastore.unset_key(&key, committed),
+        };
+        if let Err(e) = restored {
+            error!("Failed to restore key '{}': {}", key, e);
+        }
+    }
+    for (data_key, md_key, value) in previous_metadata {
+        let restored = match value {
+            Some(value) => datastore.set_metadata(&md_key, &data_key, value, committed),
+            None => datastore.unset_metadata(&md_key, &data_key, committed),
+        };
+        if let Err(e) = restored {
+            error!(
+                "Failed to restore metadata '{}' for key '{}': {}",
+                md_key, data_key, e
+            );
+        }
+    }
+}
+
+/// Inserts the given value into the given map, nested according to the segments of the key.
+fn insert_nested(map: &mut serde_json::Map<String, Value>, key: &Key, value: Value) -> Result<()> {
+    let segments = key.segments();
+    // Keys always have at least one segment.
+    let (last, parents) = segments.split_last().context(error::DumpConflict {
+        key: key.name().as_str(),
+    })?;
+
+    let mut current = map;
+    for segment in parents {
+        let entry = current
+            .entry(segment.clone())
+            .or_insert_with(|| Value::Object(serde_json::Map::new()));
+        // A key can't be both a value and a prefix of other keys.
+        current = entry.as_object_mut().context(error::DumpConflict {
+            key: key.name().as_str(),
+        })?;
+    }
+    ensure!(
+        !current.contains_key(last),
+        error::DumpConflict {
+            key: key.name().as_str(),
+        }
+    );
+    current.insert(last.clone(), value);
+    Ok(())
+}
+
+/// The config applier launched by `apply_changes` unless another is configured.
+const DEFAULT_CONFIG_APPLIER: &str = "/usr/bin/thar-be-settings";
+
+/// The number of lines from the end of the config applier's stderr that we include in errors.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// How often we check whether a config applier we're waiting on has exited.
+const APPLIER_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The command used by `apply_changes` to apply committed settings to the system.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApplierConfig {
+    /// The config applier program to run.
+    pub program: PathBuf,
+    /// Arguments given to the program before any that `apply_changes` adds.
+    pub args: Vec<String>,
+    /// If true, the program is only sent a JSON array of the changed keys, for appliers that
+    /// predate the object that also includes the affected services.
+    pub keys_only: bool,
+}
+
+impl Default for ApplierConfig {
+    fn default() -> Self {
+        Self {
+            program: PathBuf::from(DEFAULT_CONFIG_APPLIER),
+            args: Vec::new(),
+            keys_only: false,
+        }
+    }
+}
+
+/// What the config applier is sent on stdin to apply specific changes.
+#[derive(Debug, Serialize)]
+struct ApplierInput<'a> {
+    /// The changed keys.
+    keys: Vec<&'a str>,
+    /// The services affected by the changed keys, according to their metadata, in the order they
+    /// should be restarted.
+    services: Vec<&'a str>,
+}
+
+/// Determines whether `apply_changes` waits for the config applier to finish.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ApplyMode {
+    /// Start the config applier and leave it to run in the background; failures are only logged.
+    Background,
+    /// Wait up to the given time for the config applier to finish, returning an error if it
+    /// fails or doesn't finish in time.
+    Wait { timeout: Duration },
+}
+
+/// Launches the config applier to make appropriate changes to the system based on any settings
+/// that have been committed.  Can be called after a commit, with the keys that changed in that
+/// commit, or called on its own to reset configuration state with all known keys.
+///
+/// If `keys_limit` is Some, gives those keys, and the services they affect, to the applier so only
+/// changes relevant to those keys are made.  Otherwise, tells the applier to apply changes for
+/// all known keys, and restart all services, with `--all --restart`.
+///
+/// The applier's stdout and stderr are sent to our log.  With `ApplyMode::Wait`, a failure of
+/// the applier is returned as an error; with `ApplyMode::Background` it's only logged.
+pub(crate) fn apply_changes<D, S>(
+    datastore: &D,
+    applier: &ApplierConfig,
+    keys_limit: Option<&HashSet<S>>,
+    mode: ApplyMode,
+) -> Result<()>
+where
+    D: DataStore,
+    S: AsRef<str>,
+{
+    prepare_applier(datastore, applier, keys_limit)?.run(mode)
+}
+
+/// A config applier run prepared by `prepare_applier`.  It doesn't need the data store, so it
+/// can be run after the data store lock is released.
+#[derive(Debug, Clone)]
+pub(crate) struct ApplierRun {
+    applier: ApplierConfig,
+    extra_args: Vec<&'static str>,
+    input: Option<String>,
+}
+
+impl ApplierRun {
+    /// Starts the config applier and either waits for it or leaves it to run in the background,
+    /// based on `mode`.
+    pub(crate) fn run(&self, mode: ApplyMode) -> Result<()> {
+        if self.input.is_some() {
+            debug!(
+                "Launching {} to apply changes",
+                self.applier.program.display()
+            );
+        } else {
+            debug!(
+                "Launching {} to apply any and all changes",
+                self.applier.program.display()
+            );
+        }
+        run_applier(&self.applier, &self.extra_args, self.input.as_deref(), mode)
+    }
+}
+
+/// Prepares the config applier run for `apply_changes`, reading what the applier needs to know
+/// about the given keys, or all keys if `keys_limit` is None, from the data store.
+pub(crate) fn prepare_applier<D, S>(
+    datastore: &D,
+    applier: &ApplierConfig,
+    keys_limit: Option<&HashSet<S>>,
+) -> Result<ApplierRun>
+where
+    D: DataStore,
+    S: AsRef<str>,
+{
+    if let Some(keys_limit) = keys_limit {
+        let mut keys: Vec<&str> = keys_limit.iter().map(|s| s.as_ref()).collect();
+        keys.sort();
+
+        // Prepare input to config applier; it uses the changed keys to update the right config
+        let cmd_input = if applier.keys_only {
+            trace!("Serializing the commit's changed keys: {:?}", keys);
+            serde_json::to_string(&keys)
+        } else {
+            let mut data_keys = HashSet::with_capacity(keys.len());
+            for key_str in &keys {
+                data_keys.insert(Key::new(KeyType::Data, key_str).context(error::NewKey {
+                    key_type: "data",
+                    name: *key_str,
+                })?);
+            }
+            let affected = get_affected_services(datastore, &data_keys)?;
+            let services = restart_order(datastore, &affected)?;
+            let services: Vec<&str> = services.iter().map(String::as_str).collect();
+
+            trace!(
+                "Serializing the commit's changed keys {:?} and affected services {:?}",
+                keys,
+                services
+            );
+            serde_json::to_string(&ApplierInput { keys, services })
+        }
+        .context(error::CommandSerialization {
+            given: "commit's changed keys",
+        })?;
+
+        Ok(ApplierRun {
+            applier: applier.clone(),
+            extra_args: Vec::new(),
+            input: Some(cmd_input),
+        })
+    } else {
+        Ok(ApplierRun {
+            applier: applier.clone(),
+            extra_args: vec!["--all", "--restart"],
+            input: None,
+        })
+    }
+}
+
+/// Starts the given config applier with its configured arguments followed by `extra_args`,
+/// sending it `input` on stdin if given, and either waits for it or leaves it to run in the
+/// background, based on `mode`.
+fn run_applier(
+    applier: &ApplierConfig,
+    extra_args: &[&str],
+    input: Option<&str>,
+    mode: ApplyMode,
+) -> Result<()> {
+    let mut child = Command::new(&applier.program)
+        .args(&applier.args)
+        .args(extra_args)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(error::ConfigApplierStart)?;
+
+    // Send input to config applier, then close stdin so it knows we're done
+    if let Some(input) = input {
+        trace!("Sending input to config applier");
+        let mut stdin = child.stdin.take().context(error::ConfigApplierStdin)?;
+        stdin
+            .write_all(input.as_bytes())
+            .context(error::ConfigApplierWrite)?;
+    }
+
+    // Forward the applier's output to our log as it arrives
+    let stdout = child.stdout.take().map(|stdout| {
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().filter_map(|l| l.ok()) {
+                debug!("Config applier: {}", line);
+            }
+        })
+    });
+    let stderr = child.stderr.take().map(|stderr| {
+        thread::spawn(move || {
+            let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+            for line in BufReader::new(stderr).lines().filter_map(|l| l.ok()) {
+                error!("Config applier: {}", line);
+                if tail.len() == STDERR_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(line);
+            }
+            tail.into_iter().collect::<Vec<_>>().join("\n")
+        })
+    });
+
+    match mode {
+        ApplyMode::Background => {
+            // Leave config applier to run in the background, but reap it so we can log failures
+            thread::spawn(move || match child.wait() {
+                Ok(status) if !status.success() => error!("Config applier failed: {}", status),
+                Ok(_) => debug!("Config applier finished successfully"),
+                Err(e) => error!("Unable to wait for config applier: {}", e),
+            });
+            Ok(())
+        }
+        ApplyMode::Wait { timeout } => {
+            let start = Instant::now();
+            let status = loop {
+                if let Some(status) = child.try_wait().context(error::ConfigApplierWait)? {
+                    break status;
+                }
+                if start.elapsed() >= timeout {
+                    // Don't leave a hung applier behind; errors here just mean it already exited
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return error::ConfigApplierTimeout {
+                        seconds: timeout.as_secs(),
+                    }
+                    .fail();
+                }
+                thread::sleep(APPLIER_POLL_INTERVAL);
+            };
+
+            // The applier has exited, so its output pipes are closed and these finish promptly
+            if let Some(stdout) = stdout {
+                let _ = stdout.join();
+            }
+            let stderr = stderr
+                .and_then(|stderr| stderr.join().ok())
+                .unwrap_or_default();
+
+            ensure!(
+                status.success(),
+                error::ConfigApplierFailed {
+                    status: status.to_string(),
+                    stderr,
+                }
+            );
+            debug!("Config applier finished successfully");
+            Ok(())
+        }
+    }
+}
+
+/// What happened when the config applier ran for a commit made by `commit_for_apply`.
+#[derive(Debug)]
+pub(crate) enum ApplyOutcome {
+    /// The config applier succeeded, so the committed settings are in effect.
+    Applied,
+    /// The config applier failed, and the previous live values were restored.
+    RolledBack { apply_error: error::Error },
+    /// The config applier failed, and restoring the previous live values failed too, so live
+    /// data may be partially restored, or the restore was skipped because live data changed
+    /// while the applier ran.
+    RollbackFailed {
+        apply_error: error::Error,
+        rollback_error: error::Error,
+    },
+}
+
+/// The result of `finish_apply`.
+#[derive(Debug)]
+pub(crate) struct CommitAndApply {
+    /// The data keys that were committed.  If the commit was rolled back, the changed keys have
+    /// since been restored to their previous state.
+    pub(crate) committed: CommittedKeys,
+    pub(crate) outcome: ApplyOutcome,
+}
+
+/// A commit made by `commit_for_apply`, with what's needed to apply it and to roll it back.
+#[derive(Debug)]
+pub(crate) struct PendingApply {
+    transaction: String,
+    committed: CommittedKeys,
+    /// The live values of the changed keys from before the commit.
+    previous: HashMap<Key, Option<String>>,
+    /// The live values of the changed keys right after the commit, so a rollback can tell
+    /// whether they've been changed since.
+    written: HashMap<Key, Option<String>>,
+    /// The config applier run for the changed keys, or the error from preparing it; None if no
+    /// values changed, so there's nothing to apply.
+    run: Option<Result<ApplierRun>>,
+}
+
+impl PendingApply {
+    /// Takes the config applier run for the changed keys, or None if there's nothing to apply.  If
+    /// preparing the run failed, that's returned as the error, which should be given to
+    /// `finish_apply` as the apply result so the commit is rolled back.
+    pub(crate) fn take_run(&mut self) -> Result<Option<ApplierRun>> {
+        self.run.take().transpose()
+    }
+}
+
+/// Commits the given transaction, or only the given keys from it, and prepares the config applier
+/// run for the keys whose values changed.  The applier doesn't need the data store, so it can be
+/// run with `PendingApply::take_run` after releasing the data store lock, which matters because
+/// appliers call back into the API.  Its result is then given to `finish_apply`.
+///
+/// Errors before or during the commit are returned as Err, leaving live data untouched.  Once
+/// the commit has happened, problems preparing the applier are reported by `finish_apply`.
+pub(crate) fn commit_for_apply<D>(
+    datastore: &mut D,
+    transaction: &str,
+    keys: Option<&HashSet<&str>>,
+    applier: &ApplierConfig,
+) -> Result<PendingApply>
+where
+    D: DataStore,
+{
+    // Save the current live values of the keys we're about to commit so we can restore them
+    let pending = Committed::Pending {
+        tx: transaction.into(),
+    };
+    let pending_keys = datastore
+        .list_populated_keys("", &pending)
+        .context(error::DataStore {
+            op: "list_populated_keys",
+        })?;
+    let previous = datastore
+        .get_keys(&pending_keys, &Committed::Live)
+        .context(error::DataStore { op: "get_keys" })?;
+
+    let committed = commit_transaction_keys(datastore, transaction, keys)?;
+    if committed.changed.is_empty() {
+        debug!("No values changed in transaction '{}'", transaction);
+        return Ok(PendingApply {
+            transaction: transaction.to_string(),
+            committed,
+            previous,
+            written: HashMap::new(),
+            run: None,
+        });
+    }
+
+    let written = datastore
+        .get_keys(&committed.changed, &Committed::Live)
+        .context(error::DataStore { op: "get_keys" })?;
+    let key_names = committed.changed.iter().map(|k| k.name()).collect();
+    let run = prepare_applier(&*datastore, applier, Some(&key_names));
+    Ok(PendingApply {
+        transaction: transaction.to_string(),
+        committed,
+        previous,
+        written,
+        run: Some(run),
+    })
+}
+
+/// Completes a commit made by `commit_for_apply`, given the result of running its config applier.
+/// If the applier failed, the committed data keys are returned to their previous live values;
+/// keys that didn't exist before the commit are removed.  The data store lock was released while
+/// the applier ran, so if anything changed the committed keys in the meantime, they're left alone
+/// and the rollback is reported as failed.  The applier isn't run again after a rollback, and
+/// pending metadata committed with the transaction isn't rolled back.
+pub(crate) fn finish_apply<D>(
+    datastore: &mut D,
+    pending: PendingApply,
+    apply_result: Result<()>,
+) -> CommitAndApply
+where
+    D: DataStore,
+{
+    let PendingApply {
+        transaction,
+        mut committed,
+        previous,
+        written,
+        ..
+    } = pending;
+
+    let outcome = match apply_result {
+        Ok(()) => ApplyOutcome::Applied,
+        Err(apply_error) => {
+            error!(
+                "Config applier failed, rolling back transaction '{}': {}",
+                transaction, apply_error
+            );
+            let restored = check_unchanged_since_commit(&*datastore, &written)
+                .and_then(|()| restore_live(datastore, &committed.changed, &previous));
+            match restored {
+                Ok(generation) => {
+                    // Record the rollback in the audit log as a change back to the old values
+                    let reverted: Vec<AuditEntry> = committed
+                        .audit
+                        .iter()
+                        .filter(|entry| entry.old_value != entry.new_value)
+                        .map(|entry| AuditEntry {
+                            time: entry.time,
+                            key: entry.key.clone(),
+                            old_value: entry.new_value.clone(),
+                            new_value: entry.old_value.clone(),
+                            generation,
+                        })
+                        .collect();
+                    committed.audit.extend(reverted);
+                    ApplyOutcome::RolledBack { apply_error }
+                }
+                Err(rollback_error) => {
+                    error!(
+                        "Failed to roll back transaction '{}': {}",
+                        transaction, rollback_error
+                    );
+                    ApplyOutcome::RollbackFailed {
+                        apply_error,
+                        rollback_error,
+                    }
+                }
+            }
+        }
+    };
+
+    CommitAndApply { committed, outcome }
+}
+
+/// Makes sure the live values of the given keys are still the ones in `written`, returning a
+/// RollbackConflict error naming any that have changed.
+fn check_unchanged_since_commit<D: DataStore>(
+    datastore: &D,
+    written: &HashMap<Key, Option<String>>,
+) -> Result<()> {
+    let keys: HashSet<Key> = written.keys().cloned().collect();
+    let current = datastore
+        .get_keys(&keys, &Committed::Live)
+        .context(error::DataStore { op: "get_keys" })?;
+    let mut changed: Vec<&str> = written
+        .iter()
+        .filter(|(key, value)| current.get(*key) != Some(*value))
+        .map(|(key, _)| key.name().as_str())
+        .collect();
+    changed.sort();
+    ensure!(
+        changed.is_empty(),
+        error::RollbackConflict {
+            keys: changed.join(", ")
+        }
+    );
+    Ok(())
+}
+
+/// Returns the given live data keys to the values in `previous`, removing any that had no
+/// previous value.  This changes live settings, so it bumps the settings generation, and returns
+/// the new generation.
+fn restore_live<D: DataStore>(
+    datastore: &mut D,
+    keys: &HashSet<Key>,
+    previous: &HashMap<Key, Option<String>>,
+) -> Result<u64> {
+    let mut restore = HashMap::new();
+    let mut remove = HashSet::new();
+    for key in keys {
+        match previous.get(key) {
+            Some(Some(value)) => {
+                restore.insert(key.clone(), value);
+            }
+            // The commit created the key, so there's nothing to restore; remove it instead
+            _ => {
+                remove.insert(key.clone());
+            }
+        }
+    }
+
+    trace!("Restoring live keys: {:?}", restore.keys());
+    datastore
+        .set_keys(&restore, &Committed::Live)
+        .context(error::DataStore { op: "set_keys" })?;
+    trace!("Removing live keys created by commit: {:?}", remove);
+    datastore
+        .unset_keys(&remove, &Committed::Live)
+        .context(error::DataStore { op: "unset_keys" })?;
+    bump_generation(datastore)
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use super::*;
+    use crate::datastore::memory::MemoryDataStore;
+    use crate::datastore::{Committed, DataStore, Key, KeyType};
+    use maplit::{btreemap, hashmap, hashset};
+    use model::modeled_types::CommandSpec;
+    use model::{ConfigurationFile, Service};
+    use serde_json::json;
+    use std::convert::TryInto;
+
+    /// More keys than any test sets at once.
+    const MAX_KEYS: usize = 100;
+
+    /// Returns a MemoryDataStore with the given data keys set live to the given serialized
+    /// values.
+    fn live_datastore(pairs: &[(&str, &str)]) -> MemoryDataStore {
+        let mut ds = MemoryDataStore::new();
+        for (name, value) in pairs {
+            ds.set_key(
+                &Key::new(KeyType::Data, name).unwrap(),
+                value,
+                &Committed::Live,
+            )
+            .unwrap();
+        }
+        ds
+    }
+
+    #[test]
+    fn get_settings_works() {
+        let mut ds = MemoryDataStore::new();
+        // Set directly with data store
+        ds.set_key(
+            &Key::new(KeyType::Data, "settings.motd").unwrap(),
+            "\"json string\"",
+            &Committed::Live,
+        )
+        .unwrap();
+
+        // Retrieve with helper
+        let settings = get_settings(&ds, &Committed::Live).unwrap();
+        assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
+    }
+
+    #[test]
+    fn settings_etag_tracks_content() {
+        let live = &Committed::Live;
+        let pairs = [
+            ("settings.motd", "\"hi\""),
+            ("settings.aws.region", "\"us-west-2\""),
+            ("settings.updates.seed", "42"),
+        ];
+
+        // The same settings written in a different order have the same ETag
+        let mut forward = live_datastore(&pairs);
+        let reversed: Vec<_> = pairs.iter().rev().cloned().collect();
+        let reverse = live_datastore(&reversed);
+        let etag = get_settings_etag(&forward).unwrap();
+        assert_eq!(get_settings_etag(&reverse).unwrap(), etag);
+        assert!(etag.starts_with('"') && etag.ends_with('"'), "{}", etag);
+
+        // Reading the settings gives the same ETag
+        let (settings, read_etag, count) = get_settings_and_etag(&forward).unwrap();
+        assert_eq!(settings, get_settings(&forward, live).unwrap());
+        assert_eq!(read_etag, etag);
+        assert_eq!(count, pairs.len());
+
+        // Any change to the settings changes it
+        let seed = Key::new(KeyType::Data, "settings.updates.seed").unwrap();
+        forward.set_key(&seed, "43", live).unwrap();
+        assert_ne!(get_settings_etag(&forward).unwrap(), etag);
+
+        // Moving text between a name and its value does too
+        let a = hashmap!(Key::new(KeyType::Data, "settings.ab").unwrap() => "\"c\"".to_string());
+        let b = hashmap!(Key::new(KeyType::Data, "settings.a").unwrap() => "b\"c\"".to_string());
+        assert_ne!(content_etag(&a), content_etag(&b));
+    }
+
+    // Sets a good motd and region, and a seed that can't be deserialized.
+    fn corrupt_settings() -> MemoryDataStore {
+        live_datastore(&[
+            ("settings.motd", "\"hi\""),
+            ("settings.aws.region", "\"us-west-2\""),
+            ("settings.updates.seed", "\"not a number\""),
+        ])
+    }
+
+    #[test]
+    fn get_settings_names_bad_key() {
+        let ds = corrupt_settings();
+        let err = get_settings(&ds, &Committed::Live).unwrap_err().to_string();
+        assert!(err.contains("settings.updates.seed"), "{}", err);
+        assert!(err.contains("not a number"), "{}", err);
+    }
+
+    #[test]
+    fn get_settings_lenient_skips_bad_key() {
+        let ds = corrupt_settings();
+        let lenient = get_settings_lenient(&ds, &Committed::Live).unwrap();
+        let settings = lenient.value.unwrap();
+        assert_eq!(settings.motd, Some("hi".try_into().unwrap()));
+        assert!(settings.aws.is_some());
+        assert!(settings.updates.is_none());
+
+        let skipped: Vec<_> = lenient.skipped.iter().map(|(key, _)| key.name()).collect();
+        assert_eq!(skipped, vec!["settings.updates.seed"]);
+    }
+
+    #[test]
+    fn get_settings_prefixes_works() {
+        let mut ds = MemoryDataStore::new();
+        // Set directly with data store
+        ds.set_key(
+            &Key::new(KeyType::Data, "settings.motd").unwrap(),
+            "\"json string\"",
+            &Committed::Live,
+        )
+        .unwrap();
+
+        // Retrieve with helper
+        let (settings, _) = get_settings_prefixes(&ds, &hashset!(""), &Committed::Live).unwrap();
+        assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
+
+        let (settings, _) = get_settings_prefixes(&ds, &hashset!("mot"), &Committed::Live).unwrap();
+        assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
+
+        let (settings, _) =
+            get_settings_prefixes(&ds, &hashset!("motdxxx"), &Committed::Live).unwrap();
+        assert_eq!(settings.motd, None);
+    }
+
+    #[test]
+    fn get_settings_prefixes_merges() {
+        let ds = live_datastore(&[
+            ("settings.motd", "\"hi\""),
+            ("settings.updates.seed", "42"),
+            ("settings.ntp.time-servers", "[\"https://example.com\"]"),
+        ]);
+
+        let (settings, count) =
+            get_settings_prefixes(&ds, &hashset!("motd", "updates"), &Committed::Live).unwrap();
+        assert_eq!(settings.motd, Some("hi".try_into().unwrap()));
+        assert_eq!(settings.updates.unwrap().seed, Some(42));
+        assert!(settings.ntp.is_none());
+        assert_eq!(count, 2);
+
+        // Overlapping prefixes are each fetched once, and nothing is returned for prefixes
+        // without data
+        assert_eq!(
+            distinct_prefixes(&hashset!("updates.seed", "updates", "up", "motd", "ntp")),
+            vec!["motd", "ntp", "up"]
+        );
+        let (settings, _) = get_settings_prefixes(
+            &ds,
+            &hashset!("updates", "updates.s", "kube"),
+            &Committed::Live,
+        )
+        .unwrap();
+        assert_eq!(settings.updates.unwrap().seed, Some(42));
+        assert!(settings.motd.is_none());
+
+        let (settings, _) =
+            get_settings_prefixes(&ds, &hashset!("kube", "host"), &Committed::Live).unwrap();
+        assert_eq!(settings, Settings::default());
+
+        // Keys and prefixes can be combined
+        let (settings, _) = get_settings_keys_and_prefixes(
+            &ds,
+            &hashset!("settings.motd"),
+            &hashset!("ntp", "updates.seed"),
+            MissingKeyBehavior::Error,
+            &Committed::Live,
+        )
+        .unwrap();
+        assert_eq!(settings.motd, Some("hi".try_into().unwrap()));
+        assert_eq!(settings.updates.unwrap().seed, Some(42));
+        assert!(settings.ntp.is_some());
+    }
+
+    #[test]
+    fn get_settings_keys_works() {
+        let mut ds = MemoryDataStore::new();
+        // Set directly with data store
+        ds.set_key(
+            &Key::new(KeyType::Data, "settings.motd").unwrap(),
+            "\"json string 1\"",
+            &Committed::Live,
+        )
+        .unwrap();
+
+        ds.set_key(
+            &Key::new(KeyType::Data, "settings.ntp.time-servers").unwrap(),
+            "\"json string 2\"",
+            &Committed::Live,
+        )
+        .unwrap();
+
+        // Retrieve with helper
+        let (settings, _) = get_settings_keys(
+            &ds,
+            &hashset!("settings.motd"),
+            MissingKeyBehavior::Skip,
+            &Committed::Live,
+        )
+        .unwrap();
+        assert_eq!(settings.motd, Some("json string 1".try_into().unwrap()));
+        assert_eq!(settings.ntp, None);
+    }
+
+    #[test]
+    fn get_settings_keys_missing() {
+        let mut ds = MemoryDataStore::new();
+        ds.set_key(
+            &Key::new(KeyType::Data, "settings.motd").unwrap(),
+            "\"json string\"",
+            &Committed::Live,
+        )
+        .unwrap();
+        let keys = hashset!("settings.motd", "settings.mtod", "settings.hostnmae");
+
+        // Unpopulated keys are skipped unless we ask for an error
+        let (settings, _) =
+            get_settings_keys(&ds, &keys, MissingKeyBehavior::Skip, &Committed::Live).unwrap();
+        assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
+        match get_settings_keys(&ds, &keys, MissingKeyBehavior::Error, &Committed::Live) {
+            Err(error::Error::MissingKeys { keys }) => {
+                assert_eq!(keys, "settings.hostnmae, settings.mtod")
+            }
+            other => panic!("Expected MissingKeys error, got {:?}", other),
+        }
+        let (settings, _) = get_settings_keys(
+            &ds,
+            &hashset!("settings.motd"),
+            MissingKeyBehavior::Error,
+            &Committed::Live,
+        )
+        .unwrap();
+        assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
+
+        // Invalid key names are always an error
+        for missing in &[MissingKeyBehavior::Skip, MissingKeyBehavior::Error] {
+            match get_settings_keys(&ds, &hashset!("settings..motd"), *missing, &Committed::Live) {
+                Err(error::Error::NewKey { .. }) => {}
+                other => panic!("Expected NewKey error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn get_services_names_works() {
+        let mut ds = MemoryDataStore::new();
+        // Set directly with data store
+        ds.set_key(
+            &Key::new(KeyType::Data, "services.foo.configuration-files").unwrap(),
+            "[\"file1\"]",
+            &Committed::Live,
+        )
+        .unwrap();
+        ds.set_key(
+            &Key::new(KeyType::Data, "services.foo.restart-commands").unwrap(),
+            "[\"echo hi\"]",
+            &Committed::Live,
+        )
+        .unwrap();
+
+        // Retrieve built service
+        let names = hashset!("foo");
+        let services = get_services_names(&ds, &names, &Committed::Live).unwrap();
+        assert_eq!(
+            services,
+            btreemap!("foo".to_string() => Service {
+                configuration_files: vec!["file1".try_into().unwrap()],
+                restart_commands: vec![CommandSpec::Split("echo hi".to_string())],
+                restart_after: None,
+                restart_timeout: None,
+            })
+        );
+    }
+
+    #[test]
+    fn services_are_ordered() {
+        let mut ds = MemoryDataStore::new();
+        for name in &["zeta", "alpha", "mid"] {
+            for (field, value) in &[("configuration-files", "[]"), ("restart-commands", "[]")] {
+                let key = Key::from_segments(KeyType::Data, &["services", name, field]).unwrap();
+                ds.set_key(&key, value, &Committed::Live).unwrap();
+            }
+        }
+
+        for _ in 0..3 {
+            let names: Vec<_> = get_services(&ds).unwrap().keys().cloned().collect();
+            assert_eq!(names, vec!["alpha", "mid", "zeta"]);
+            let names: Vec<_> =
+                get_services_names(&ds, &hashset!("zeta", "mid", "alpha"), &Committed::Live)
+                    .unwrap()
+                    .keys()
+                    .cloned()
+                    .collect();
+            assert_eq!(names, vec!["alpha", "mid", "zeta"]);
+        }
+    }
+
+    /// Builds a Services from (name, restart-after) pairs, with no files or commands.
+    fn restart_services(list: &[(&str, Option<&[&str]>)]) -> Services {
+        list.iter()
+            .map(|(name, after)| {
+                let service = Service {
+                    configuration_files: vec![],
+                    restart_commands: vec![],
+                    restart_after: after.map(|a| a.iter().map(|s| s.to_string()).collect()),
+                    restart_timeout: None,
+                };
+                (name.to_string(), service)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restart_order_respects_restart_after() {
+        let services = restart_services(&[
+            ("alpha", Some(&["kubelet"])),
+            ("containerd", None),
+            ("kubelet", Some(&["containerd", "not-restarting"])),
+            ("zeta", None),
+        ]);
+        assert_eq!(
+            resolve_restart_order(&services).unwrap(),
+            vec!["containerd", "kubelet", "alpha", "zeta"]
+        );
+
+        // Without any restart-after, services restart in name order
+        let services = restart_services(&[("b", None), ("a", None), ("c", None)]);
+        assert_eq!(
+            resolve_restart_order(&services).unwrap(),
+            vec!["a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn restart_order_names_cycle() {
+        let services = restart_services(&[
+            ("a", Some(&["b"])),
+            ("b", Some(&["c"])),
+            ("c", Some(&["a"])),
+            ("d", None),
+        ]);
+        match resolve_restart_order(&services) {
+            Err(error::Error::RestartCycle { cycle }) => assert_eq!(cycle, "a -> b -> c -> a"),
+            other => panic!("Expected restart cycle, got {:?}", other),
+        }
+
+        let services = restart_services(&[("self", Some(&["self"]))]);
+        match resolve_restart_order(&services) {
+            Err(error::Error::RestartCycle { cycle }) => assert_eq!(cycle, "self -> self"),
+            other => panic!("Expected restart cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn get_services_reads_restart_after() {
+        let mut ds = MemoryDataStore::new();
+        for (key, value) in &[
+            ("services.kubelet.configuration-files", "[]"),
+            ("services.kubelet.restart-commands", "[]"),
+            ("services.kubelet.restart-after", "[\"containerd\"]"),
+        ] {
+            ds.set_key(
+                &Key::new(KeyType::Data, key).unwrap(),
+                value,
+                &Committed::Live,
+            )
+            .unwrap();
+        }
+
+        let services = get_services(&ds).unwrap();
+        assert_eq!(
+            services["kubelet"].restart_after,
+            Some(vec!["containerd".to_string()])
+        );
+    }
+
+    #[test]
+    fn get_configuration_files_reads_permissions() {
+        let mut ds = MemoryDataStore::new();
+        for (key, value) in &[
+            ("configuration-files.kubeconfig.path", "\"/etc/kubeconfig\""),
+            (
+                "configuration-files.kubeconfig.template-path",
+                "\"/templates/kubeconfig\"",
+            ),
+            ("configuration-files.kubeconfig.mode", "\"0600\""),
+            ("configuration-files.kubeconfig.user", "\"root\""),
+            ("configuration-files.kubeconfig.group", "\"root\""),
+            ("configuration-files.motd.path", "\"/etc/motd\""),
+            (
+                "configuration-files.motd.template-path",
+                "\"/templates/motd\"",
+            ),
+        ] {
+            ds.set_key(
+                &Key::new(KeyType::Data, key).unwrap(),
+                value,
+                &Committed::Live,
+            )
+            .unwrap();
+        }
+
+        let files = get_configuration_files(&ds).unwrap();
+        let kubeconfig = &files["kubeconfig"];
+        assert_eq!(kubeconfig.mode.as_ref().map(|m| m.mode()), Some(0o600));
+        assert_eq!(
+            kubeconfig.user.as_ref().map(|u| u.to_string()),
+            Some("root".to_string())
+        );
+        assert_eq!(
+            kubeconfig.group.as_ref().map(|g| g.to_string()),
+            Some("root".to_string())
+        );
+
+        // They're optional
+        let motd = &files["motd"];
+        assert!(motd.mode.is_none() && motd.user.is_none() && motd.group.is_none());
+
+        // Bad modes aren't accepted
+        ds.set_key(
+            &Key::new(KeyType::Data, "configuration-files.motd.mode").unwrap(),
+            "\"rw-r--r--\"",
+            &Committed::Live,
+        )
+        .unwrap();
+        get_configuration_files(&ds).unwrap_err();
+    }
+
+    /// Settings with nested tables and arrays for settings_input tests.
+    fn input_test_settings() -> Settings {
+        serde_json::from_value(json!({
+            "motd": "hi",
+            "updates": {"seed": 42},
+            "host-containers": {"admin": {"enabled": true, "superpowered": true}},
+            "ntp": {"time-servers": ["https://a.example.com", "https://b.example.com"]},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn settings_input_json() {
+        let expected = SettingsInput::from(input_test_settings());
+        let bare = r#"{
+            "motd": "hi",
+            "updates": {"seed": 42},
+            "host-containers": {"admin": {"enabled": true, "superpowered": true}},
+            "ntp": {"time-servers": ["https://a.example.com", "https://b.example.com"]}
+        }"#;
+        let wrapped = format!("{{\"settings\": {}}}", bare);
+        assert_eq!(settings_input(bare).unwrap(), expected);
+        assert_eq!(settings_input(wrapped).unwrap(), expected);
+
+        let serialized = serde_json::to_string(&expected.settings).unwrap();
+        assert_eq!(settings_input(serialized).unwrap(), expected);
+    }
+
+    #[test]
+    fn settings_input_toml() {
+        let expected = SettingsInput::from(input_test_settings());
+        let bare = r#"
+            motd = "hi"
+            [updates]
+            seed = 42
+            [host-containers.admin]
+            enabled = true
+            superpowered = true
+            [ntp]
+            time-servers = ["https://a.example.com", "https://b.example.com"]
+        "#;
+        let wrapped = r#"
+            [settings]
+            motd = "hi"
+            [settings.updates]
+            seed = 42
+            [settings.host-containers.admin]
+            enabled = true
+            superpowered = true
+            [settings.ntp]
+            time-servers = ["https://a.example.com", "https://b.example.com"]
+        "#;
+        assert_eq!(settings_input(bare).unwrap(), expected);
+        assert_eq!(settings_input(wrapped).unwrap(), expected);
+
+        let serialized = toml::to_string(&expected.settings).unwrap();
+        assert_eq!(settings_input(serialized).unwrap(), expected);
+    }
+
+    #[test]
+    fn settings_input_errors_name_format() {
+        let err = settings_input(r#"{"motd": "hi", "bogus": 1}"#).unwrap_err();
+        assert!(err.to_string().contains("JSON"), "{}", err);
+
+        let err = settings_input("motd = \"hi\"\nbogus = 1").unwrap_err();
+        assert!(err.to_string().contains("TOML"), "{}", err);
+
+        // A "settings" key that isn't a table isn't stripped
+        let err = settings_input(r#"{"settings": "hi"}"#).unwrap_err();
+        assert!(err.to_string().contains("JSON"), "{}", err);
+
+        // The format can be given explicitly
+        let err = settings_input_format(r#"{"motd": "hi"}"#, Format::Toml).unwrap_err();
+        assert!(err.to_string().contains("TOML"), "{}", err);
+
+        assert!(settings_input(" ").is_err());
+    }
+
+    #[test]
+    fn settings_input_unknown_fields() {
+        let input = r#"{"motdd": "hi", "ntp": {"time-server": []}, "updates": {"seed": 1}}"#;
+        match settings_input(input) {
+            Err(error::Error::UnknownSettings { format, settings }) => {
+                assert_eq!(format, "JSON");
+                assert_eq!(
+                    settings,
+                    "settings.motdd (did you mean 'settings.motd'?), \
+                     settings.ntp.time-server (did you mean 'settings.ntp.time-servers'?)"
+                );
+            }
+            other => panic!("Expected unknown settings, got {:?}", other),
+        }
+
+        // Internal callers can choose to ignore them
+        let lenient = settings_input_with(input, Format::Json, UnknownFields::Ignore).unwrap();
+        assert_eq!(
+            lenient.settings,
+            serde_json::from_value(json!({"ntp": {}, "updates": {"seed": 1}})).unwrap()
+        );
+
+        let input = "bogus = 1\n[updates]\nseed = 1";
+        match settings_input(input) {
+            Err(error::Error::UnknownSettings { format, settings }) => {
+                assert_eq!(format, "TOML");
+                assert_eq!(settings, "settings.bogus");
+            }
+            other => panic!("Expected unknown settings, got {:?}", other),
+        }
+        let lenient = settings_input_with(input, Format::Toml, UnknownFields::Ignore).unwrap();
+        assert_eq!(
+            lenient.settings,
+            serde_json::from_value(json!({"updates": {"seed": 1}})).unwrap()
+        );
+    }
+
+    #[test]
+    fn settings_input_nulls() {
+        let input = settings_input(
+            r#"{"settings": {"motd": null, "ntp": {"time-servers": null}, "updates": {"seed": 1}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            input.settings,
+            serde_json::from_value(json!({"updates": {"seed": 1}})).unwrap()
+        );
+        assert_eq!(
+            input.unset,
+            hashset!(
+                Key::new(KeyType::Data, "settings.motd").unwrap(),
+                Key::new(KeyType::Data, "settings.ntp.time-servers").unwrap(),
+            )
+        );
+
+        // Structs given empty, rather than emptied by nulls, are kept
+        let input = settings_input(r#"{"ntp": {}, "motd": null}"#).unwrap();
+        assert_eq!(
+            input.settings,
+            serde_json::from_value(json!({"ntp": {}})).unwrap()
+        );
+
+        // Nulls of settings that aren't in the model are rejected like any other unknown setting
+        match settings_input(r#"{"ntp": {"time-server": null}, "motd": null}"#) {
+            Err(error::Error::UnknownSettings { settings, .. }) => assert_eq!(
+                settings,
+                "settings.ntp.time-server (did you mean 'settings.ntp.time-servers'?)"
+            ),
+            other => panic!("Expected unknown settings, got {:?}", other),
+        }
+        let lenient = settings_input_with(
+            r#"{"ntp": {"time-server": null}, "motd": null}"#,
+            Format::Json,
+            UnknownFields::Ignore,
+        )
+        .unwrap();
+        assert_eq!(
+            lenient.unset,
+            hashset!(Key::new(KeyType::Data, "settings.motd").unwrap())
+        );
+    }
+
+    #[test]
+    fn set_settings_unsets_nulls() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let live = &Committed::Live;
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let servers = Key::new(KeyType::Data, "settings.ntp.time-servers").unwrap();
+        let seed = Key::new(KeyType::Data, "settings.updates.seed").unwrap();
+        ds.set_key(&motd, "\"hi\"", live).unwrap();
+        ds.set_key(&servers, "[\"https://example.com\"]", live)
+            .unwrap();
+
+        // Null sections remove the keys under them
+        let input =
+            settings_input(r#"{"motd": null, "ntp": null, "updates": {"seed": 1}}"#).unwrap();
+        let written = set_settings(&mut ds, &input, SetBehavior::Merge, tx, MAX_KEYS).unwrap();
+        assert_eq!(
+            written,
+            hashset!(motd.clone(), servers.clone(), seed.clone())
+        );
+        let committed = commit_transaction(&mut ds, tx).unwrap();
+
+        assert_eq!(
+            committed.changed,
+            hashset!(motd.clone(), servers.clone(), seed.clone())
+        );
+        assert_eq!(committed.len(), 3);
+        assert!(!ds.key_populated(&motd, live).unwrap());
+        assert!(!ds.key_populated(&servers, live).unwrap());
+        assert_eq!(ds.get_key(&seed, live).unwrap(), Some("1".to_string()));
+    }
+
+    #[test]
+    fn set_settings_merges_maps() {
+        let live = &Committed::Live;
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let key = |name: &str| Key::new(KeyType::Data, name).unwrap();
+        let admin_source = key("settings.host-containers.admin.source");
+        let admin_enabled = key("settings.host-containers.admin.enabled");
+        let control_enabled = key("settings.host-containers.control.enabled");
+        let new_enabled = key("settings.host-containers.new.enabled");
+        let input: SettingsInput = settings_input(
+            r#"{"host-containers": {"admin": {"enabled": false}, "new": {"enabled": true}}}"#,
+        )
+        .unwrap();
+
+        // Existing entries are either live or pending from an earlier request
+        for existing in &[live, &pending] {
+            let mut ds = MemoryDataStore::new();
+            ds.set_key(&admin_source, "\"https://example.com\"", existing)
+                .unwrap();
+            ds.set_key(&admin_enabled, "true", existing).unwrap();
+            ds.set_key(&control_enabled, "true", existing).unwrap();
+
+            // Merging updates one entry, adds one, and leaves the rest
+            set_settings(&mut ds, &input, SetBehavior::Merge, tx, MAX_KEYS).unwrap();
+            commit_transaction(&mut ds, tx).unwrap();
+            assert_eq!(
+                ds.get_key(&admin_source, live).unwrap(),
+                Some("\"https://example.com\"".to_string())
+            );
+            assert_eq!(
+                ds.get_key(&admin_enabled, live).unwrap(),
+                Some("false".to_string())
+            );
+            assert_eq!(
+                ds.get_key(&control_enabled, live).unwrap(),
+                Some("true".to_string())
+            );
+            assert_eq!(
+                ds.get_key(&new_enabled, live).unwrap(),
+                Some("true".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn set_settings_replaces_sections() {
+        let live = &Committed::Live;
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let key = |name: &str| Key::new(KeyType::Data, name).unwrap();
+        let motd = key("settings.motd");
+        let admin_source = key("settings.host-containers.admin.source");
+        let admin_enabled = key("settings.host-containers.admin.enabled");
+        let control_enabled = key("settings.host-containers.control.enabled");
+        let new_enabled = key("settings.host-containers.new.enabled");
+        let input: SettingsInput = settings_input(
+            r#"{"host-containers": {"admin": {"enabled": false}, "new": {"enabled": true}}}"#,
+        )
+        .unwrap();
+
+        for existing in &[live, &pending] {
+            let mut ds = MemoryDataStore::new();
+            ds.set_key(&motd, "\"hi\"", existing).unwrap();
+            ds.set_key(&admin_source, "\"https://example.com\"", existing)
+                .unwrap();
+            ds.set_key(&admin_enabled, "true", existing).unwrap();
+            ds.set_key(&control_enabled, "true", existing).unwrap();
+
+            // Replacing removes what isn't given in the section, but not in other sections
+            set_settings(&mut ds, &input, SetBehavior::Replace, tx, MAX_KEYS).unwrap();
+            commit_transaction(&mut ds, tx).unwrap();
+            assert!(!ds.key_populated(&admin_source, live).unwrap());
+            assert!(!ds.key_populated(&control_enabled, live).unwrap());
+            assert_eq!(
+                ds.get_key(&admin_enabled, live).unwrap(),
+                Some("false".to_string())
+            );
+            assert_eq!(
+                ds.get_key(&new_enabled, live).unwrap(),
+                Some("true".to_string())
+            );
+            assert_eq!(ds.get_key(&motd, live).unwrap(), Some("\"hi\"".to_string()));
+        }
+    }
+
+    #[test]
+    fn set_settings_replace_keeps_siblings() {
+        let live = &Committed::Live;
+        let tx = "test transaction";
+        let key = |name: &str| Key::new(KeyType::Data, name).unwrap();
+        let metadata_url = key("settings.updates.metadata-base-url");
+        let seed = key("settings.updates.seed");
+        let admin_enabled = key("settings.host-containers.admin.enabled");
+        let new_enabled = key("settings.host-containers.new.enabled");
+        let input: SettingsInput = settings_input(
+            r#"{"host-containers": {"new": {"enabled": true}}, "updates": {"seed": 1}}"#,
+        )
+        .unwrap();
+
+        let mut ds = MemoryDataStore::new();
+        ds.set_key(&metadata_url, "\"https://example.com\"", live)
+            .unwrap();
+        ds.set_key(&seed, "2", live).unwrap();
+        ds.set_key(&admin_enabled, "true", live).unwrap();
+
+        // Only the map given is replaced; other settings in the same request are merged
+        set_settings(&mut ds, &input, SetBehavior::Replace, tx, MAX_KEYS).unwrap();
+        commit_transaction(&mut ds, tx).unwrap();
+        assert!(!ds.key_populated(&admin_enabled, live).unwrap());
+        assert_eq!(
+            ds.get_key(&new_enabled, live).unwrap(),
+            Some("true".to_string())
+        );
+        assert_eq!(ds.get_key(&seed, live).unwrap(), Some("1".to_string()));
+        assert_eq!(
+            ds.get_key(&metadata_url, live).unwrap(),
+            Some("\"https://example.com\"".to_string())
+        );
+    }
+
+    #[test]
+    fn set_settings_works() {
+        let mut settings = Settings::default();
+        settings.motd = Some("tz".try_into().unwrap());
+
+        // Set with helper
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        set_settings(&mut ds, &settings.into(), SetBehavior::Merge, tx, MAX_KEYS).unwrap();
+
+        // Retrieve directly
+        let key = Key::new(KeyType::Data, "settings.motd").unwrap();
+        assert_eq!(
+            Some("\"tz\"".to_string()),
+            ds.get_key(&key, &pending).unwrap()
+        );
+    }
+
+    #[test]
+    fn get_metadata_keys_works() {
+        let mut ds = MemoryDataStore::new();
+        // Set directly with data store
+        for data_key in &["abc", "def", "ghi"] {
+            ds.set_metadata(
+                &Key::new(KeyType::Meta, "my-meta").unwrap(),
+                &Key::new(KeyType::Data, data_key).unwrap(),
+                "\"json string\"",
+                &Committed::Live,
+            )
+            .unwrap();
+        }
+
+        // We'll check a subset by specifying 2 of the 3 keys
+        let expected = hashmap!(
+            "abc".to_string() => "json string".into(),
+            "def".to_string() => "json string".into(),
+        );
+        // Retrieve with helper
+        let actual = get_metadata_for_data_keys(&ds, "my-meta", &hashset!("abc", "def")).unwrap();
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn get_metadata_all_works() {
+        let mut ds = MemoryDataStore::new();
+        // Set directly with data store
+        for data_key in &["abc", "def"] {
+            ds.set_metadata(
+                &Key::new(KeyType::Meta, "my-meta").unwrap(),
+                &Key::new(KeyType::Data, data_key).unwrap(),
+                "\"json string\"",
+                &Committed::Live,
+            )
+            .unwrap();
+        }
+
+        let expected = hashmap!(
+            "abc".to_string() => "json string".into(),
+            "def".to_string() => "json string".into(),
+        );
+        // Retrieve with helper
+        let actual = get_metadata_for_all_data_keys(&ds, "my-meta").unwrap();
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn get_all_metadata_works() {
+        let mut ds = MemoryDataStore::new();
+        let abc = Key::new(KeyType::Data, "abc").unwrap();
+        // Set directly with data store
+        for md_key in &["meta1", "meta2"] {
+            ds.set_metadata(
+                &Key::new(KeyType::Meta, md_key).unwrap(),
+                &abc,
+                "\"json string\"",
+                &Committed::Live,
+            )
+            .unwrap();
+        }
+
+        // "def" has no metadata, so it shouldn't show up
+        let expected = hashmap!(
+            "abc".to_string() => hashmap!(
+                "meta1".to_string() => "json string".into(),
+                "meta2".to_string() => "json string".into(),
+            ),
+        );
+        // Retrieve with helper
+        let actual = get_all_metadata_for_keys(&ds, &hashset!("abc", "def")).unwrap();
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn unset_metadata_keys_works() {
+        let mut ds = MemoryDataStore::new();
+        let md_key = Key::new(KeyType::Meta, "my-meta").unwrap();
+        // Set directly with data store
+        for data_key in &["abc", "def"] {
+            ds.set_metadata(
+                &md_key,
+                &Key::new(KeyType::Data, data_key).unwrap(),
+                "\"json string\"",
+                &Committed::Live,
+            )
+            .unwrap();
+        }
+
+        // Remove with helper; "ghi" has no metadata, which should be fine
+        unset_metadata_for_data_keys(&mut ds, "my-meta", &hashset!("abc", "ghi")).unwrap();
+
+        // Retrieve directly
+        let abc = Key::new(KeyType::Data, "abc").unwrap();
+        let def = Key::new(KeyType::Data, "def").unwrap();
+        assert_eq!(
+            ds.get_metadata_raw(&md_key, &abc, &Committed::Live)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            ds.get_metadata_raw(&md_key, &def, &Committed::Live)
+                .unwrap(),
+            Some("\"json string\"".to_string())
+        );
+    }
+
+    #[test]
+    fn delete_transaction_works() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let key = Key::new(KeyType::Data, "settings.motd").unwrap();
+        ds.set_key(&key, "\"json string\"", &pending).unwrap();
+
+        // Discarding returns the keys that were pending
+        let deleted = delete_transaction(&mut ds, tx).unwrap();
+        assert_eq!(deleted, hashset!(key));
+        assert_eq!(get_transaction(&ds, tx).unwrap(), Settings::default());
+
+        // Nothing left to discard, which is OK
+        let deleted = delete_transaction(&mut ds, tx).unwrap();
+        assert!(deleted.is_empty());
+    }
+
+    #[test]
+    fn commit_works() {
+        // Set directly with data store
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        ds.set_key(
+            &Key::new(KeyType::Data, "settings.motd").unwrap(),
+            "\"json string\"",
+            &pending,
+        )
+        .unwrap();
+
+        // Confirm pending
+        let settings = get_settings(&ds, &pending).unwrap();
+        assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
+        // No live settings yet
+        get_settings(&ds, &Committed::Live).unwrap_err();
+
+        // Commit, pending -> live
+        commit_transaction(&mut ds, tx).unwrap();
+
+        // No more pending settings
+        get_settings(&ds, &pending).unwrap_err();
+        // Confirm live
+        let settings = get_settings(&ds, &Committed::Live).unwrap();
+        assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
+    }
+
+    #[test]
+    fn delete_settings_keys_works() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let servers = Key::new(KeyType::Data, "settings.ntp.time-servers").unwrap();
+        ds.set_key(&motd, "\"json string\"", &Committed::Live)
+            .unwrap();
+        ds.set_key(&servers, "[\"server\"]", &Committed::Live)
+            .unwrap();
+
+        // Deleting a key, including one that isn't set, is staged in pending
+        delete_settings_keys(
+            &mut ds,
+            &hashset!("settings.ntp.time-servers", "settings.updates.seed"),
+            tx,
+        )
+        .unwrap();
+        let settings = get_settings(&ds, &Committed::Live).unwrap();
+        assert!(settings.ntp.is_some());
+        let settings = get_transaction(&ds, tx).unwrap();
+        assert_eq!(settings.ntp.unwrap().time_servers, None);
+        assert_eq!(settings.updates.unwrap().seed, None);
+
+        // Committing removes the key from live and reports it as changed; removing a key that
+        // wasn't set doesn't change anything
+        let committed = commit_transaction(&mut ds, tx).unwrap();
+        assert_eq!(committed.changed, hashset!(servers.clone()));
+        assert_eq!(
+            committed.unchanged,
+            hashset!(Key::new(KeyType::Data, "settings.updates.seed").unwrap())
+        );
+        assert!(!ds.key_populated(&servers, &Committed::Live).unwrap());
+        let settings = get_settings(&ds, &Committed::Live).unwrap();
+        assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
+        assert_eq!(settings.ntp, None);
+
+        // Only settings can be deleted
+        for bad in &[
+            "services.foo",
+            "settingsfoo",
+            "configuration-files.foo.path",
+        ] {
+            match delete_settings_keys(&mut ds, &hashset!(*bad), tx) {
+                Err(error::Error::DeleteNonSettings { .. }) => {}
+                other => panic!("Expected DeleteNonSettings for {}, got {:?}", bad, other),
+            }
+        }
+        assert!(!ds.key_populated(&motd, &pending).unwrap());
+        assert!(ds.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn get_pending_diff_works() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let live = &Committed::Live;
+        let hostname = Key::new(KeyType::Data, "settings.hostname").unwrap();
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let servers = Key::new(KeyType::Data, "settings.ntp.time-servers").unwrap();
+        let timezone = Key::new(KeyType::Data, "settings.timezone").unwrap();
+        ds.set_key(&hostname, "\"old\"", live).unwrap();
+        ds.set_key(&motd, "\"same\"", live).unwrap();
+        ds.set_key(&servers, "[\"a\"]", live).unwrap();
+        ds.set_key(&hostname, "\"new\"", &pending).unwrap();
+        ds.set_key(&motd, "\"same\"", &pending).unwrap();
+        ds.set_key(&timezone, "\"UTC\"", &pending).unwrap();
+        delete_settings_keys(&mut ds, &hashset!("settings.ntp.time-servers"), tx).unwrap();
+
+        let diff = get_pending_diff(&ds, tx, true).unwrap();
+        assert_eq!(
+            diff,
+            hashmap!(
+                hostname.clone() => (Some(json!("old")), Some(json!("new"))),
+                motd.clone() => (Some(json!("same")), Some(json!("same"))),
+                servers.clone() => (Some(json!(["a"])), None),
+                timezone.clone() => (None, Some(json!("UTC"))),
+            )
+        );
+
+        // Unchanged keys can be left out
+        let diff = get_pending_diff(&ds, tx, false).unwrap();
+        assert_eq!(
+            diff.keys().cloned().collect::<HashSet<_>>(),
+            hashset!(hostname, servers, timezone)
+        );
+
+        // No transaction, no diff
+        assert!(get_pending_diff(&ds, "other", true).unwrap().is_empty());
+
+        // The same diff is available by key name
+        let changes = get_pending_changes(&ds, tx).unwrap();
+        assert_eq!(
+            changes.keys().collect::<Vec<_>>(),
+            vec![
+                "settings.hostname",
+                "settings.motd",
+                "settings.ntp.time-servers",
+                "settings.timezone"
+            ]
+        );
+        assert_eq!(
+            changes["settings.ntp.time-servers"],
+            PendingChange {
+                old: Some(json!(["a"])),
+                new: None
+            }
+        );
+    }
+
+    #[test]
+    fn validate_settings_works() {
+        let mut ds = MemoryDataStore::new();
+        let live = &Committed::Live;
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let url = Key::new(KeyType::Data, "settings.updates.metadata-base-url").unwrap();
+        ds.set_metadata(
+            &Key::new(KeyType::Meta, ALLOWED_VALUES_METADATA).unwrap(),
+            &motd,
+            "[\"hi\", \"hello\"]",
+            live,
+        )
+        .unwrap();
+        // Set on a parent key, so it's inherited
+        ds.set_metadata(
+            &Key::new(KeyType::Meta, ALLOWED_PATTERN_METADATA).unwrap(),
+            &Key::new(KeyType::Data, "settings.updates").unwrap(),
+            "\"https://[a-z./]+\"",
+            live,
+        )
+        .unwrap();
+
+        let settings: Settings = serde_json::from_value(json!({
+            "motd": "hi",
+            "updates": {"metadata-base-url": "https://example.com/meta"}
+        }))
+        .unwrap();
+        validate_settings(&ds, &settings).unwrap();
+        set_settings(&mut ds, &settings.into(), SetBehavior::Merge, tx, MAX_KEYS).unwrap();
+        commit_transaction(&mut ds, tx).unwrap();
+
+        // Bad values are rejected with the key and reason, and nothing is written
+        for (input, bad_key) in &[
+            (
+                json!({"motd": "bye", "updates": {"metadata-base-url": "https://example.com/meta"}}),
+                "settings.motd",
+            ),
+            (
+                json!({"motd": "hi", "updates": {"metadata-base-url": "https://example.com/META"}}),
+                "settings.updates.metadata-base-url",
+            ),
+            // The pattern has to match the whole value
+            (
+                json!({"updates": {"metadata-base-url": "https://example.com/meta?a=b"}}),
+                "settings.updates.metadata-base-url",
+            ),
+        ] {
+            let settings: Settings = serde_json::from_value(input.clone()).unwrap();
+            match set_settings(&mut ds, &settings.into(), SetBehavior::Merge, tx, MAX_KEYS) {
+                Err(error::Error::InvalidSetting { key, .. }) => assert_eq!(key, *bad_key),
+                other => panic!("Expected InvalidSetting for {}, got {:?}", input, other),
+            }
+            assert!(!ds.key_populated(&motd, &pending).unwrap());
+            assert!(!ds.key_populated(&url, &pending).unwrap());
+        }
+
+        // Values written to pending some other way are checked at commit
+        ds.set_key(&motd, "\"bye\"", &pending).unwrap();
+        match commit_transaction(&mut ds, tx) {
+            Err(error::Error::InvalidSetting { key, .. }) => assert_eq!(key, "settings.motd"),
+            other => panic!("Expected InvalidSetting, got {:?}", other),
+        }
+        assert_eq!(ds.get_key(&motd, live).unwrap(), Some("\"hi\"".to_string()));
+    }
+
+    #[test]
+    fn set_settings_limits_keys() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let input =
+            settings_input(r#"{"motd": "hi", "ntp": null, "updates": {"seed": 1}}"#).unwrap();
+
+        // Keys being removed count too
+        match set_settings(&mut ds, &input, SetBehavior::Merge, tx, 2) {
+            Err(error::Error::TooManyKeys { count, limit }) => assert_eq!((count, limit), (3, 2)),
+            other => panic!("Expected TooManyKeys, got {:?}", other),
+        }
+        assert!(ds.list_populated_keys("", &pending).unwrap().is_empty());
+
+        assert_eq!(
+            set_settings(&mut ds, &input, SetBehavior::Merge, tx, 3)
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn immutable_settings_rejected() {
+        let mut ds = MemoryDataStore::new();
+        let live = &Committed::Live;
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let immutable = Key::new(KeyType::Meta, IMMUTABLE_METADATA).unwrap();
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let seed = Key::new(KeyType::Data, "settings.updates.seed").unwrap();
+        ds.set_key(&seed, "42", live).unwrap();
+        // Set on a parent key, so it's inherited
+        ds.set_metadata(
+            &immutable,
+            &Key::new(KeyType::Data, "settings.updates").unwrap(),
+            "true",
+            live,
+        )
+        .unwrap();
+        ds.set_metadata(&immutable, &motd, "false", live).unwrap();
+
+        // Mutable keys can be set
+        let settings: Settings = serde_json::from_value(json!({"motd": "hi"})).unwrap();
+        set_settings(&mut ds, &settings.into(), SetBehavior::Merge, tx, MAX_KEYS).unwrap();
+        delete_transaction(&mut ds, tx).unwrap();
+
+        // A write including an immutable key is rejected entirely
+        let settings: Settings =
+            serde_json::from_value(json!({"motd": "hi", "updates": {"seed": 1}})).unwrap();
+        match set_settings(&mut ds, &settings.into(), SetBehavior::Merge, tx, MAX_KEYS) {
+            Err(error::Error::ImmutableKeys { keys }) => assert_eq!(keys, "settings.updates.seed"),
+            other => panic!("Expected ImmutableKeys, got {:?}", other),
+        }
+        assert!(!ds.key_populated(&motd, &pending).unwrap());
+        assert!(!ds.key_populated(&seed, &pending).unwrap());
+
+        // Immutable keys can't be deleted either
+        match delete_settings_keys(
+            &mut ds,
+            &hashset!("settings.motd", "settings.updates.seed"),
+            tx,
+        ) {
+            Err(error::Error::ImmutableKeys { .. }) => {}
+            other => panic!("Expected ImmutableKeys, got {:?}", other),
+        }
+        assert!(ds.list_transactions().unwrap().is_empty());
+        assert_eq!(ds.get_key(&seed, live).unwrap(), Some("42".to_string()));
+
+        // Metadata pending in the transaction counts too
+        ds.set_metadata(&immutable, &motd, "true", &pending)
+            .unwrap();
+        let settings: Settings = serde_json::from_value(json!({"motd": "hi"})).unwrap();
+        match set_settings(&mut ds, &settings.into(), SetBehavior::Merge, tx, MAX_KEYS) {
+            Err(error::Error::ImmutableKeys { keys }) => assert_eq!(keys, "settings.motd"),
+            other => panic!("Expected ImmutableKeys, got {:?}", other),
+        }
+        assert!(!ds.key_populated(&motd, &pending).unwrap());
+    }
+
+    #[test]
+    fn get_metadata_for_prefix_works() {
+        let mut ds = MemoryDataStore::new();
+        let live = &Committed::Live;
+        let md_key = Key::new(KeyType::Meta, "affected-services").unwrap();
+        let servers = Key::new(KeyType::Data, "settings.ntp.time-servers").unwrap();
+        let other = Key::new(KeyType::Data, "settings.ntp.other").unwrap();
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let seed = Key::new(KeyType::Data, "settings.updates.seed").unwrap();
+        for key in &[&servers, &other, &motd, &seed] {
+            ds.set_key(key, "\"x\"", live).unwrap();
+        }
+        ds.set_metadata(&md_key, &servers, "[\"chronyd\"]", live)
+            .unwrap();
+        ds.set_metadata(&md_key, &motd, "[\"motd\"]", live).unwrap();
+        // Inherited by settings.updates.seed
+        ds.set_metadata(
+            &md_key,
+            &Key::new(KeyType::Data, "settings.updates").unwrap(),
+            "[\"updog\"]",
+            live,
+        )
+        .unwrap();
+
+        // Only keys with the metadata are returned
+        assert_eq!(
+            get_metadata_for_prefix(&ds, "affected-services", "settings.ntp.").unwrap(),
+            hashmap!("settings.ntp.time-servers".to_string() => json!(["chronyd"]))
+        );
+        // Empty prefix means all keys
+        assert_eq!(
+            get_metadata_for_prefix(&ds, "affected-services", "").unwrap(),
+            hashmap!(
+                "settings.ntp.time-servers".to_string() => json!(["chronyd"]),
+                "settings.motd".to_string() => json!(["motd"]),
+                "settings.updates.seed".to_string() => json!(["updog"]),
+            )
+        );
+        assert!(
+            get_metadata_for_prefix(&ds, "affected-services", "settings.bogus")
+                .unwrap()
+                .is_empty()
+        );
+        assert!(get_metadata_for_prefix(&ds, "other-metadata", "")
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Returns an ApplierConfig that runs the given shell script; any arguments given by
+    /// apply_changes are available to the script as $0, $1, etc.
+    fn shell_applier(script: &str) -> ApplierConfig {
+        ApplierConfig {
+            program: PathBuf::from("/bin/sh"),
+            args: vec!["-c".to_string(), script.to_string()],
+            keys_only: false,
+        }
+    }
+
+    #[test]
+    fn applier_failure_is_reported() {
+        let applier = shell_applier("cat >/dev/null; echo 'rendering failed' >&2; exit 3");
+        let keys = hashset!("settings.motd");
+        let err = apply_changes(
+            &MemoryDataStore::new(),
+            &applier,
+            Some(&keys),
+            ApplyMode::Wait {
+                timeout: Duration::from_secs(30),
+            },
+        )
+        .unwrap_err();
+        match err {
+            error::Error::ConfigApplierFailed { status, stderr } => {
+                assert!(status.contains('3'), "unexpected status: {}", status);
+                assert_eq!(stderr, "rendering failed");
+            }
+            _ => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn applier_receives_keys_and_services() {
+        let mut ds = MemoryDataStore::new();
+        let affected = Key::new(KeyType::Meta, "affected-services").unwrap();
+        let ntp = Key::new(KeyType::Data, "settings.ntp").unwrap();
+        ds.set_metadata(&affected, &ntp, "[\"chronyd\"]", &Committed::Live)
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("input");
+        let mut applier = ApplierConfig {
+            program: PathBuf::from("/bin/sh"),
+            args: vec![
+                "-c".to_string(),
+                "cat > \"$0\"".to_string(),
+                output.to_str().unwrap().to_string(),
+            ],
+            keys_only: false,
+        };
+        let keys = hashset!("settings.motd", "settings.ntp.time-servers");
+        let wait = ApplyMode::Wait {
+            timeout: Duration::from_secs(30),
+        };
+        apply_changes(&ds, &applier, Some(&keys), wait).unwrap();
+        let input: Value =
+            serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+        assert_eq!(
+            input,
+            json!({
+                "keys": ["settings.motd", "settings.ntp.time-servers"],
+                "services": ["chronyd"],
+            })
+        );
+
+        // Older appliers only get the keys
+        applier.keys_only = true;
+        apply_changes(&ds, &applier, Some(&keys), wait).unwrap();
+        let input: Value =
+            serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+        assert_eq!(input, json!(["settings.motd", "settings.ntp.time-servers"]));
+    }
+
+    #[test]
+    fn applier_receives_services_in_restart_order() {
+        let mut ds = live_datastore(&[
+            ("services.containerd.configuration-files", "[]"),
+            ("services.containerd.restart-commands", "[]"),
+            ("services.agent.configuration-files", "[]"),
+            ("services.agent.restart-commands", "[]"),
+            ("services.agent.restart-after", "[\"containerd\"]"),
+        ]);
+        let affected = Key::new(KeyType::Meta, "affected-services").unwrap();
+        let ntp = Key::new(KeyType::Data, "settings.ntp").unwrap();
+        ds.set_metadata(
+            &affected,
+            &ntp,
+            "[\"agent\", \"containerd\", \"chronyd\"]",
+            &Committed::Live,
+        )
+        .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("input");
+        let applier = ApplierConfig {
+            program: PathBuf::from("/bin/sh"),
+            args: vec![
+                "-c".to_string(),
+                "cat > \"$0\"".to_string(),
+                output.to_str().unwrap().to_string(),
+            ],
+            keys_only: false,
+        };
+        let keys = hashset!("settings.ntp.time-servers");
+        let wait = ApplyMode::Wait {
+            timeout: Duration::from_secs(30),
+        };
+        apply_changes(&ds, &applier, Some(&keys), wait).unwrap();
+        let input: Value =
+            serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+        // chronyd isn't defined, so it has nothing to wait for
+        assert_eq!(input["services"], json!(["chronyd", "containerd", "agent"]));
+    }
+
+    #[test]
+    fn affected_services_inherit_from_parents() {
+        let mut ds = MemoryDataStore::new();
+        let live = &Committed::Live;
+        let affected = Key::new(KeyType::Meta, "affected-services").unwrap();
+        let key = |name: &str| Key::new(KeyType::Data, name).unwrap();
+        ds.set_metadata(&affected, &key("settings.ntp"), "[\"chronyd\"]", live)
+            .unwrap();
+        ds.set_metadata(&affected, &key("settings.updates"), "[\"updog\"]", live)
+            .unwrap();
+        ds.set_metadata(
+            &affected,
+            &key("settings.updates.seed"),
+            "[\"seeder\"]",
+            live,
+        )
+        .unwrap();
+
+        // A leaf with no metadata of its own uses its parent's
+        assert_eq!(
+            get_affected_services(&ds, &hashset!(key("settings.ntp.time-servers"))).unwrap(),
+            hashset!("chronyd".to_string())
+        );
+        // A leaf's own metadata takes precedence over its parent's
+        assert_eq!(
+            get_affected_services(&ds, &hashset!(key("settings.updates.seed"))).unwrap(),
+            hashset!("seeder".to_string())
+        );
+        assert_eq!(
+            get_affected_services(&ds, &hashset!(key("settings.updates.metadata-base-url")))
+                .unwrap(),
+            hashset!("updog".to_string())
+        );
+        // Keys with no metadata anywhere above them affect nothing
+        assert!(get_affected_services(&ds, &hashset!(key("settings.motd")))
+            .unwrap()
+            .is_empty());
+        // Services from all keys are combined
+        assert_eq!(
+            get_affected_services(
+                &ds,
+                &hashset!(
+                    key("settings.ntp.time-servers"),
+                    key("settings.updates.seed")
+                )
+            )
+            .unwrap(),
+            hashset!("chronyd".to_string(), "seeder".to_string())
+        );
+    }
+
+    #[test]
+    fn applier_all_keys() {
+        let applier = shell_applier("test \"$0 $1\" = \"--all --restart\"");
+        apply_changes(
+            &MemoryDataStore::new(),
+            &applier,
+            None as Option<&HashSet<&str>>,
+            ApplyMode::Wait {
+                timeout: Duration::from_secs(30),
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn applier_timeout() {
+        let applier = shell_applier("sleep 30");
+        let err = apply_changes(
+            &MemoryDataStore::new(),
+            &applier,
+            None as Option<&HashSet<&str>>,
+            ApplyMode::Wait {
+                timeout: Duration::from_millis(100),
+            },
+        )
+        .unwrap_err();
+        match err {
+            error::Error::ConfigApplierTimeout { .. } => {}
+            _ => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn applier_failure_ignored_in_background() {
+        let applier = shell_applier("exit 1");
+        apply_changes(
+            &MemoryDataStore::new(),
+            &applier,
+            None as Option<&HashSet<&str>>,
+            ApplyMode::Background,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn commit_reports_unchanged_keys() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let live = &Committed::Live;
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let seed = Key::new(KeyType::Data, "settings.updates.seed").unwrap();
+        ds.set_key(&motd, "\"hi\"", live).unwrap();
+        ds.set_key(&seed, "1", live).unwrap();
+
+        ds.set_key(&motd, "\"hi\"", &pending).unwrap();
+        ds.set_key(&seed, "2", &pending).unwrap();
+        let committed = commit_transaction(&mut ds, tx).unwrap();
+        assert_eq!(committed.changed, hashset!(seed.clone()));
+        assert_eq!(committed.unchanged, hashset!(motd.clone()));
+        assert_eq!(committed.generation, 1);
+        assert_eq!(ds.get_key(&seed, live).unwrap(), Some("2".to_string()));
+    }
+
+    #[test]
+    fn snapshot_restore_stages_differences() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let live = &Committed::Live;
+        let pending = Committed::Pending { tx: tx.into() };
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let seed = Key::new(KeyType::Data, "settings.updates.seed").unwrap();
+        let region = Key::new(KeyType::Data, "settings.aws.region").unwrap();
+        ds.set_key(&motd, "\"before\"", live).unwrap();
+        ds.set_key(&seed, "1", live).unwrap();
+
+        let snapshot = create_snapshot(&mut ds, "one", DEFAULT_SNAPSHOT_RETENTION).unwrap();
+        assert_eq!(list_snapshots(&ds).unwrap(), vec![snapshot]);
+        // The copies don't show up in settings
+        assert_eq!(
+            get_settings(&ds, live).unwrap().motd,
+            Some("before".try_into().unwrap())
+        );
+
+        ds.set_key(&motd, "\"after\"", live).unwrap();
+        ds.set_key(&region, "\"us-west-2\"", live).unwrap();
+        let staged = restore_snapshot(&mut ds, "one", tx).unwrap();
+        assert_eq!(staged, hashset!(motd.clone(), region.clone()));
+        assert_eq!(
+            ds.get_key(&region, &pending).unwrap(),
+            Some(UNSET_VALUE.to_string())
+        );
+
+        commit_transaction(&mut ds, tx).unwrap();
+        assert_eq!(
+            ds.get_prefix("settings.", live).unwrap(),
+            hashmap!(motd => "\"before\"".to_string(), seed => "1".to_string())
+        );
+    }
+
+    #[test]
+    fn snapshot_restore_overrides_pending() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let live = &Committed::Live;
+        let pending = Committed::Pending { tx: tx.into() };
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let seed = Key::new(KeyType::Data, "settings.updates.seed").unwrap();
+        ds.set_key(&motd, "\"before\"", live).unwrap();
+        ds.set_key(&seed, "1", live).unwrap();
+        create_snapshot(&mut ds, "one", DEFAULT_SNAPSHOT_RETENTION).unwrap();
+
+        // Live still matches the snapshot, but the edits pending would change it
+        ds.set_key(&motd, "\"edited\"", &pending).unwrap();
+        ds.set_key(&seed, UNSET_VALUE, &pending).unwrap();
+        let staged = restore_snapshot(&mut ds, "one", tx).unwrap();
+        assert_eq!(staged, hashset!(motd.clone(), seed.clone()));
+
+        commit_transaction(&mut ds, tx).unwrap();
+        assert_eq!(
+            ds.get_prefix("settings.", live).unwrap(),
+            hashmap!(motd => "\"before\"".to_string(), seed => "1".to_string())
+        );
+    }
+
+    #[test]
+    fn snapshots_are_pruned() {
+        let mut ds = MemoryDataStore::new();
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        ds.set_key(&motd, "\"hi\"", &Committed::Live).unwrap();
+        for name in &["one", "two", "three"] {
+            create_snapshot(&mut ds, name, 2).unwrap();
+        }
+
+        let names: Vec<_> = list_snapshots(&ds)
+            .unwrap()
+            .into_iter()
+            .map(|snapshot| snapshot.name)
+            .collect();
+        assert_eq!(names, vec!["two", "three"]);
+        assert!(ds
+            .list_populated_keys("snapshots.one.", &Committed::Live)
+            .unwrap()
+            .is_empty());
+        match restore_snapshot(&mut ds, "one", "tx") {
+            Err(error::Error::SnapshotNotFound { .. }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match create_snapshot(&mut ds, "two", 2) {
+            Err(error::Error::SnapshotExists { .. }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn audit_log_records_commits_in_order() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let seed = Key::new(KeyType::Data, "settings.updates.seed").unwrap();
+        let mut log = Vec::new();
+
+        ds.set_key(&motd, "\"first\"", &pending).unwrap();
+        ds.set_key(&seed, "1", &pending).unwrap();
+        let committed = commit_transaction(&mut ds, tx).unwrap();
+        write_audit_entries(&mut log, &committed.audit).unwrap();
+
+        ds.set_key(&motd, "\"second\"", &pending).unwrap();
+        let committed = commit_transaction(&mut ds, tx).unwrap();
+        write_audit_entries(&mut log, &committed.audit).unwrap();
+
+        let entries: Vec<Value> = String::from_utf8(log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                assert!(entry["time"].as_u64().unwrap() > 0);
+                (
+                    entry["key"].clone(),
+                    entry["old_value"].clone(),
+                    entry["new_value"].clone(),
+                    entry["generation"].clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    json!("settings.motd"),
+                    json!(null),
+                    json!("first"),
+                    json!(1)
+                ),
+                (
+                    json!("settings.updates.seed"),
+                    json!(null),
+                    json!(1),
+                    json!(1)
+                ),
+                (
+                    json!("settings.motd"),
+                    json!("first"),
+                    json!("second"),
+                    json!(2)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn audit_log_appends_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit_log = AuditLog {
+            path: dir.path().join("audit.log"),
+        };
+        let entry = AuditEntry {
+            time: 1,
+            key: "settings.motd".to_string(),
+            old_value: None,
+            new_value: Some(json!("hi")),
+            generation: 1,
+        };
+        audit_log.append(&[entry.clone()]).unwrap();
+        audit_log.append(&[entry]).unwrap();
+
+        let contents = std::fs::read_to_string(&audit_log.path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+    }
+
+    /// Commits the transaction and runs the applier as the server does, in separate steps.
+    fn commit_and_apply(
+        ds: &mut MemoryDataStore,
+        tx: &str,
+        applier: &ApplierConfig,
+    ) -> CommitAndApply {
+        let mut pending = commit_for_apply(ds, tx, None, applier).unwrap();
+        let apply_result = match pending.take_run() {
+            Ok(Some(run)) => run.run(ApplyMode::Wait {
+                timeout: Duration::from_secs(30),
+            }),
+            other => other.map(|_| ()),
+        };
+        finish_apply(ds, pending, apply_result)
+    }
+
+    #[test]
+    fn commit_and_apply_skips_unchanged() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        ds.set_key(&motd, "\"hi\"", &Committed::Live).unwrap();
+        ds.set_key(&motd, "\"hi\"", &pending).unwrap();
+
+        // The applier leaves a marker if it's run
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("applied");
+        let applier = ApplierConfig {
+            program: PathBuf::from("/bin/sh"),
+            args: vec![
+                "-c".to_string(),
+                "touch \"$0\"".to_string(),
+                marker.to_str().unwrap().to_string(),
+            ],
+            keys_only: false,
+        };
+
+        let result = commit_and_apply(&mut ds, tx, &applier);
+        assert!(result.committed.changed.is_empty());
+        assert_eq!(result.committed.unchanged, hashset!(motd));
+        match result.outcome {
+            ApplyOutcome::Applied => {}
+            _ => panic!("Unexpected outcome: {:?}", result.outcome),
+        }
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn commit_and_apply_works() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        ds.set_key(&motd, "\"new\"", &pending).unwrap();
+
+        let applier = shell_applier("cat >/dev/null");
+        let result = commit_and_apply(&mut ds, tx, &applier);
+        assert_eq!(result.committed.changed, hashset!(motd.clone()));
+        match result.outcome {
+            ApplyOutcome::Applied => {}
+            _ => panic!("Unexpected outcome: {:?}", result.outcome),
+        }
+        assert_eq!(
+            ds.get_key(&motd, &Committed::Live).unwrap(),
+            Some("\"new\"".to_string())
+        );
+    }
+
+    #[test]
+    fn commit_and_apply_rolls_back() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let live = &Committed::Live;
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let servers = Key::new(KeyType::Data, "settings.ntp.time-servers").unwrap();
+        ds.set_key(&motd, "\"old\"", live).unwrap();
+        ds.set_key(&motd, "\"new\"", &pending).unwrap();
+        ds.set_key(&servers, "[\"a\"]", &pending).unwrap();
+
+        let applier = shell_applier("cat >/dev/null; exit 1");
+        let result = commit_and_apply(&mut ds, tx, &applier);
+        assert_eq!(
+            result.committed.changed,
+            hashset!(motd.clone(), servers.clone())
+        );
+        match result.outcome {
+            ApplyOutcome::RolledBack {
+                apply_error: error::Error::ConfigApplierFailed { .. },
+            } => {}
+            _ => panic!("Unexpected outcome: {:?}", result.outcome),
+        }
+
+        // Existing key is restored, new key is removed, and the transaction is gone
+        assert_eq!(
+            ds.get_key(&motd, live).unwrap(),
+            Some("\"old\"".to_string())
+        );
+        assert!(!ds.key_populated(&servers, live).unwrap());
+        assert!(ds.list_populated_keys("", &pending).unwrap().is_empty());
+    }
+
+    #[test]
+    fn commit_and_apply_keeps_later_changes() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let live = &Committed::Live;
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        ds.set_key(&motd, "\"old\"", live).unwrap();
+        ds.set_key(&motd, "\"new\"", &pending).unwrap();
+
+        let applier = shell_applier("cat >/dev/null; exit 1");
+        let mut pending_apply = commit_for_apply(&mut ds, tx, None, &applier).unwrap();
+        let run = pending_apply.take_run().unwrap().unwrap();
+        let apply_result = run.run(ApplyMode::Wait {
+            timeout: Duration::from_secs(30),
+        });
+        // Another request changes the key while the applier runs without the lock
+        ds.set_key(&motd, "\"newer\"", live).unwrap();
+
+        let result = finish_apply(&mut ds, pending_apply, apply_result);
+        match result.outcome {
+            ApplyOutcome::RollbackFailed {
+                apply_error: error::Error::ConfigApplierFailed { .. },
+                rollback_error: error::Error::RollbackConflict { keys },
+            } => assert_eq!(keys, "settings.motd"),
+            _ => panic!("Unexpected outcome: {:?}", result.outcome),
+        }
+        assert_eq!(
+            ds.get_key(&motd, live).unwrap(),
+            Some("\"newer\"".to_string())
+        );
+    }
+
+    /// Returns a data store with everything check_readiness looks for.
+    pub(crate) fn ready_datastore() -> MemoryDataStore {
+        live_datastore(&[
+            ("settings.motd", "\"hi\""),
+            ("settings.updates.seed", "42"),
+            ("services.motd.configuration-files", "[\"motd\"]"),
+            ("services.motd.restart-commands", "[]"),
+            ("configuration-files.motd.path", "\"/etc/motd\""),
+            (
+                "configuration-files.motd.template-path",
+                "\"/templates/motd\"",
+            ),
+        ])
+    }
+
+    #[test]
+    fn readiness_checks_pass() {
+        let readiness = check_readiness(&ready_datastore());
+        assert!(readiness.ready, "{:?}", readiness);
+        assert_eq!(
+            readiness
+                .checks
+                .iter()
+                .map(|check| check.name)
+                .collect::<Vec<_>>(),
+            vec![
+                "datastore",
+                "settings",
+                "services",
+                "configuration-files",
+                "commit"
+            ]
+        );
+    }
+
+    #[test]
+    fn readiness_names_failing_check() {
+        let mut ds = ready_datastore();
+        let seed = Key::new(KeyType::Data, "settings.updates.seed").unwrap();
+        ds.set_key(&seed, "\"not a number\"", &Committed::Live)
+            .unwrap();
+
+        let readiness = check_readiness(&ds);
+        assert!(!readiness.ready);
+        let failed: Vec<_> = readiness.checks.iter().filter(|c| !c.ok).collect();
+        assert_eq!(failed.len(), 1, "{:?}", readiness);
+        assert_eq!(failed[0].name, "settings");
+        let error = failed[0].error.as_ref().unwrap();
+        assert!(error.contains("settings.updates.seed"), "{}", error);
+    }
+
+    #[test]
+    fn readiness_detects_interrupted_commit() {
+        let mut ds = ready_datastore();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        ds.set_key(&motd, "\"bye\"", &pending).unwrap();
+
+        // A finished commit leaves no marker behind
+        commit_transaction(&mut ds, tx).unwrap();
+        assert_eq!(get_interrupted_commit(&ds).unwrap(), None);
+        assert!(check_readiness(&ds).ready);
+
+        // A commit that stopped partway does
+        let (md_key, data_key) = commit_marker_keys().unwrap();
+        ds.set_metadata(&md_key, &data_key, "\"stopped\"", &Committed::Live)
+            .unwrap();
+        assert_eq!(
+            get_interrupted_commit(&ds).unwrap(),
+            Some("stopped".to_string())
+        );
+        let readiness = check_readiness(&ds);
+        assert!(!readiness.ready);
+        let commit = readiness
+            .checks
+            .iter()
+            .find(|c| c.name == "commit")
+            .unwrap();
+        assert!(!commit.ok);
+        assert!(commit.error.as_ref().unwrap().contains("stopped"));
+    }
+
+    #[test]
+    fn model_consistency_finds_every_problem() {
+        let ds = live_datastore(&[
+            ("services.motd.configuration-files", "[\"motd\", \"issue\"]"),
+            ("services.motd.restart-commands", "[]"),
+            ("services.ntp.configuration-files", "[\"chrony-cnf\"]"),
+            ("services.ntp.restart-commands", "[]"),
+            ("configuration-files.motd.path", "\"/etc/motd\""),
+            ("configuration-files.motd.template-path", "\"\""),
+            (
+                "configuration-files.chrony-conf.path",
+                "\"/etc/chrony.conf\"",
+            ),
+            (
+                "configuration-files.chrony-conf.template-path",
+                "\"/templates/chrony\"",
+            ),
+        ]);
+
+        let problems = validate_model_consistency(&ds).unwrap();
+        assert_eq!(
+            problems,
+            vec![
+                ConsistencyError::MissingConfigurationFile {
+                    service: "motd".to_string(),
+                    file: "issue".to_string(),
                 },
-            )?;
-            result.insert(data_key.to_string(), value);
-        }
+                ConsistencyError::MissingConfigurationFile {
+                    service: "ntp".to_string(),
+                    file: "chrony-cnf".to_string(),
+                },
+                ConsistencyError::MissingTemplatePath {
+                    file: "motd".to_string(),
+                },
+                ConsistencyError::UnusedConfigurationFile {
+                    file: "chrony-conf".to_string(),
+                },
+            ]
+        );
+        assert!(problems[3].is_warning());
+        assert!(!problems[0].is_warning());
     }
-    Ok(result)
-}
 
-/// Makes live any pending settings in the datastore, returning the changed keys.
-pub(crate) fn commit_transaction<D>(datastore: &mut D, transaction: &str) -> Result<HashSet<Key>>
-where
-    D: DataStore,
-{
-    datastore
-        .commit_transaction(transaction)
-        .context(error::DataStore { op: "commit" })
-}
+    #[test]
+    fn dry_run_commit_works() {
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let live = &Committed::Live;
+        let affected = Key::new(KeyType::Meta, "affected-services").unwrap();
+        let mut ds = live_datastore(&[
+            ("services.motd.configuration-files", "[\"motd\"]"),
+            ("services.motd.restart-commands", "[]"),
+            ("services.chronyd.configuration-files", "[\"chrony-conf\"]"),
+            (
+                "services.chronyd.restart-commands",
+                "[[\"/bin/systemctl\", \"restart\", \"chronyd\"]]",
+            ),
+            ("configuration-files.motd.path", "\"/etc/motd\""),
+            (
+                "configuration-files.motd.template-path",
+                "\"/templates/motd\"",
+            ),
+            (
+                "configuration-files.chrony-conf.path",
+                "\"/etc/chrony.conf\"",
+            ),
+            (
+                "configuration-files.chrony-conf.template-path",
+                "\"/templates/chrony\"",
+            ),
+            ("settings.motd", "\"old\""),
+        ]);
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let ntp = Key::new(KeyType::Data, "settings.ntp").unwrap();
+        ds.set_metadata(&affected, &motd, "[\"motd\"]", live)
+            .unwrap();
+        ds.set_metadata(&affected, &ntp, "[\"chronyd\"]", live)
+            .unwrap();
 
-/// Launches the config applier to make appropriate changes to the system based on any settings
-/// that have been committed.  Can be called after a commit, with the keys that changed in that
-/// commit, or called on its own to reset configuration state with all known keys.
-///
-/// If `keys_limit` is Some, gives those keys to the applier so only changes relevant to those
-/// keys are made.  Otherwise, tells the applier to apply changes for all known keys.
-pub(crate) fn apply_changes<S>(keys_limit: Option<&HashSet<S>>) -> Result<()>
-where
-    S: AsRef<str>,
-{
-    if let Some(keys_limit) = keys_limit {
-        let keys_limit: Vec<&str> = keys_limit.iter().map(|s| s.as_ref()).collect();
-        // Prepare input to config applier; it uses the changed keys to update the right config
-        trace!("Serializing the commit's changed keys: {:?}", keys_limit);
-        let cmd_input =
-            serde_json::to_string(&keys_limit).context(error::CommandSerialization {
-                given: "commit's changed keys",
-            })?;
+        let servers = Key::new(KeyType::Data, "settings.ntp.time-servers").unwrap();
+        ds.set_key(&motd, "\"new\"", &pending).unwrap();
+        ds.set_key(&servers, "[\"a\"]", &pending).unwrap();
 
-        // Start config applier
-        debug!("Launching thar-be-settings to apply changes");
-        let mut cmd = Command::new("/usr/bin/thar-be-settings")
-            .stdin(Stdio::piped())
-            // FIXME where to send output?
-            //.stdout()
-            //.stderr()
-            .spawn()
-            .context(error::ConfigApplierStart)?;
-
-        // Send changed keys to config applier
-        trace!("Sending changed keys");
-        cmd.stdin
-            .as_mut()
-            .context(error::ConfigApplierStdin)?
-            .write_all(cmd_input.as_bytes())
-            .context(error::ConfigApplierWrite)?;
-    } else {
-        // Start config applier
-        debug!("Launching thar-be-settings to apply any and all changes");
-        Command::new("/usr/bin/thar-be-settings")
-            .arg("--all")
-            // FIXME where to send output?
-            //.stdout()
-            //.stderr()
-            .spawn()
-            .context(error::ConfigApplierStart)?;
-    }
-
-    // Leave config applier to run in the background; we can't wait for it
-    Ok(())
-}
+        let report = dry_run_commit(&ds, tx).unwrap();
+        assert_eq!(
+            report.changes,
+            hashmap!(
+                "settings.motd".to_string() => PendingChange {
+                    old: Some(json!("old")),
+                    new: Some(json!("new")),
+                },
+                "settings.ntp.time-servers".to_string() => PendingChange {
+                    old: None,
+                    new: Some(json!(["a"])),
+                },
+            )
+        );
+        assert_eq!(
+            report.services,
+            btreemap!(
+                "motd".to_string() => Service {
+                    configuration_files: vec!["motd".try_into().unwrap()],
+                    restart_commands: vec![],
+                    restart_after: None,
+                    restart_timeout: None,
+                },
+                "chronyd".to_string() => Service {
+                    configuration_files: vec!["chrony-conf".try_into().unwrap()],
+                    restart_commands: vec![CommandSpec::Exec(vec![
+                        "/bin/systemctl".to_string(),
+                        "restart".to_string(),
+                        "chronyd".to_string(),
+                    ])],
+                    restart_after: None,
+                    restart_timeout: None,
+                },
+            )
+        );
+        assert_eq!(
+            report.configuration_files,
+            btreemap!(
+                "motd".to_string() => ConfigurationFile {
+                    path: "/etc/motd".try_into().unwrap(),
+                    template_path: "/templates/motd".try_into().unwrap(),
+                    mode: None,
+                    user: None,
+                    group: None,
+                    check_command: None,
+                    partials: None,
+                },
+                "chrony-conf".to_string() => ConfigurationFile {
+                    path: "/etc/chrony.conf".try_into().unwrap(),
+                    template_path: "/templates/chrony".try_into().unwrap(),
+                    mode: None,
+                    user: None,
+                    group: None,
+                    check_command: None,
+                    partials: None,
+                },
+            )
+        );
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::datastore::memory::MemoryDataStore;
-    use crate::datastore::{Committed, DataStore, Key, KeyType};
-    use maplit::{hashmap, hashset};
-    use model::Service;
-    use std::convert::TryInto;
+        // Nothing was committed
+        assert_eq!(
+            ds.get_key(&motd, live).unwrap(),
+            Some("\"old\"".to_string())
+        );
+        assert!(!ds.key_populated(&servers, live).unwrap());
+        assert!(ds.key_populated(&servers, &pending).unwrap());
+    }
 
     #[test]
-    fn get_settings_works() {
+    fn get_setting_works() {
         let mut ds = MemoryDataStore::new();
-        // Set directly with data store
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let live = &Committed::Live;
         ds.set_key(
             &Key::new(KeyType::Data, "settings.motd").unwrap(),
-            "\"json string\"",
-            &Committed::Live,
+            "\"hi\"",
+            live,
+        )
+        .unwrap();
+        ds.set_key(
+            &Key::new(KeyType::Data, "settings.ntp.time-servers").unwrap(),
+            "[\"https://example.com\"]",
+            live,
         )
         .unwrap();
 
-        // Retrieve with helper
-        let settings = get_settings(&ds, &Committed::Live).unwrap();
-        assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
+        assert_eq!(
+            get_setting(&ds, "settings.motd", live).unwrap(),
+            Some(json!("hi"))
+        );
+        assert_eq!(
+            get_setting(&ds, "settings.ntp.time-servers", live).unwrap(),
+            Some(json!(["https://example.com"]))
+        );
+        assert_eq!(
+            get_setting(&ds, "settings.updates.seed", live).unwrap(),
+            None
+        );
+
+        // Prefixes and non-settings keys are errors
+        match get_setting(&ds, "settings.ntp", live) {
+            Err(error::Error::SettingIsPrefix { key }) => assert_eq!(key, "settings.ntp"),
+            other => panic!("Expected SettingIsPrefix, got {:?}", other),
+        }
+        get_setting(&ds, "services.foo", live).unwrap_err();
+
+        // Keys staged for removal aren't there
+        delete_settings_keys(&mut ds, &hashset!("settings.motd"), tx).unwrap();
+        assert_eq!(get_setting(&ds, "settings.motd", &pending).unwrap(), None);
+    }
+
+    #[test]
+    fn list_settings_keys_works() {
+        let live = &Committed::Live;
+        let ds = live_datastore(&[
+            ("settings.motd", "1"),
+            ("settings.ntp.time-servers", "1"),
+            ("settings.updates.seed", "1"),
+            ("settings.updates.targets-base-url", "1"),
+            ("settings.host-containers.admin.enabled", "1"),
+            ("settings.host-containers.admin.source", "1"),
+            ("settings.host-containers.control.enabled", "1"),
+            ("services.foo.restart-commands", "1"),
+        ]);
+        let names =
+            |names: &[&str]| -> BTreeSet<String> { names.iter().map(|s| s.to_string()).collect() };
+
+        assert_eq!(
+            list_settings_keys(&ds, None, Some(1), live).unwrap(),
+            names(&[
+                "settings.host-containers",
+                "settings.motd",
+                "settings.ntp",
+                "settings.updates",
+            ])
+        );
+        assert_eq!(
+            list_settings_keys(&ds, Some("settings.host-containers."), Some(2), live).unwrap(),
+            names(&[
+                "settings.host-containers.admin",
+                "settings.host-containers.control",
+            ])
+        );
+        assert_eq!(
+            list_settings_keys(&ds, Some("settings.updates."), None, live).unwrap(),
+            names(&["settings.updates.seed", "settings.updates.targets-base-url"])
+        );
+        // Keys shorter than the depth are left alone
+        assert_eq!(
+            list_settings_keys(&ds, None, Some(3), live).unwrap(),
+            names(&[
+                "settings.host-containers.admin.enabled",
+                "settings.host-containers.admin.source",
+                "settings.host-containers.control.enabled",
+                "settings.motd",
+                "settings.ntp.time-servers",
+                "settings.updates.seed",
+                "settings.updates.targets-base-url",
+            ])
+        );
+
+        list_settings_keys(&ds, Some("services."), None, live).unwrap_err();
     }
 
     #[test]
-    fn get_settings_prefix_works() {
+    fn generation_works() {
         let mut ds = MemoryDataStore::new();
-        // Set directly with data store
-        ds.set_key(
-            &Key::new(KeyType::Data, "settings.motd").unwrap(),
-            "\"json string\"",
-            &Committed::Live,
-        )
-        .unwrap();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
 
-        // Retrieve with helper
-        let settings = get_settings_prefix(&ds, "", &Committed::Live).unwrap();
-        assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
+        // A fresh data store is at generation 0
+        assert_eq!(get_generation(&ds).unwrap(), 0);
 
-        let settings = get_settings_prefix(&ds, "mot", &Committed::Live).unwrap();
-        assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
+        ds.set_key(&motd, "\"hi\"", &pending).unwrap();
+        assert_eq!(commit_transaction(&mut ds, tx).unwrap().generation, 1);
+        assert_eq!(get_generation(&ds).unwrap(), 1);
 
-        let settings = get_settings_prefix(&ds, "motdxxx", &Committed::Live).unwrap();
-        assert_eq!(settings.motd, None);
+        // Committing without changes leaves the generation alone
+        ds.set_key(&motd, "\"hi\"", &pending).unwrap();
+        assert_eq!(commit_transaction(&mut ds, tx).unwrap().generation, 1);
+
+        ds.set_key(&motd, "\"bye\"", &pending).unwrap();
+        let keys = hashset!("settings.motd");
+        let committed = commit_transaction_keys(&mut ds, tx, Some(&keys)).unwrap();
+        assert_eq!(committed.generation, 2);
+        assert_eq!(get_generation(&ds).unwrap(), 2);
+
+        // The generation doesn't show up in settings, or in their metadata
+        get_settings(&ds, &Committed::Live).unwrap();
+        let (md_key, _) = generation_keys().unwrap();
+        assert_eq!(
+            ds.get_metadata(&md_key, &motd, &Committed::Live).unwrap(),
+            None
+        );
     }
 
     #[test]
-    fn get_settings_keys_works() {
+    fn generation_commits_with_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut ds = crate::datastore::FilesystemDataStore::new(dir.path());
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+
+        ds.set_key(&motd, "\"hi\"", &pending).unwrap();
+        let committed = commit_transaction(&mut ds, tx).unwrap();
+        assert_eq!(committed.generation, 1);
+        assert_eq!(get_generation(&ds).unwrap(), 1);
+        // The staged generation went live with the settings rather than staying pending
+        assert!(ds.list_transactions().unwrap().is_empty());
+
+        ds.set_key(&motd, "\"bye\"", &pending).unwrap();
+        assert_eq!(commit_transaction(&mut ds, tx).unwrap().generation, 2);
+        assert_eq!(get_generation(&ds).unwrap(), 2);
+    }
+
+    #[test]
+    fn get_settings_with_mtimes_works() {
         let mut ds = MemoryDataStore::new();
-        // Set directly with data store
+        let live = &Committed::Live;
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let hostname = Key::new(KeyType::Data, "settings.hostname").unwrap();
+        let service = Key::new(KeyType::Data, "services.foo.restart-commands").unwrap();
+        ds.set_key(&motd, "\"hi\"", live).unwrap();
+        ds.set_key(&hostname, "\"host\"", live).unwrap();
+        ds.set_key(&service, "[\"echo\"]", live).unwrap();
+
+        let result = get_settings_with_mtimes(&ds, live).unwrap();
+        assert_eq!(result.len(), 2);
+        let (value, mtime) = &result["settings.motd"];
+        assert_eq!(value, &json!("hi"));
+        assert_eq!(Some(*mtime), ds.get_key_mtime(&motd, live).unwrap());
+        let (value, mtime) = &result["settings.hostname"];
+        assert_eq!(value, &json!("host"));
+        assert_eq!(Some(*mtime), ds.get_key_mtime(&hostname, live).unwrap());
+    }
+
+    #[test]
+    fn dump_all_works() {
+        let mut ds = MemoryDataStore::new();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
         ds.set_key(
             &Key::new(KeyType::Data, "settings.motd").unwrap(),
-            "\"json string 1\"",
+            "\"json string\"",
             &Committed::Live,
         )
         .unwrap();
-
         ds.set_key(
             &Key::new(KeyType::Data, "settings.ntp.time-servers").unwrap(),
-            "\"json string 2\"",
+            "[\"a\",\"b\"]",
             &Committed::Live,
         )
         .unwrap();
-
-        // Retrieve with helper
-        let settings =
-            get_settings_keys(&ds, &hashset!("settings.motd"), &Committed::Live).unwrap();
-        assert_eq!(settings.motd, Some("json string 1".try_into().unwrap()));
-        assert_eq!(settings.ntp, None);
-    }
-
-    #[test]
-    fn get_services_names_works() {
-        let mut ds = MemoryDataStore::new();
-        // Set directly with data store
         ds.set_key(
-            &Key::new(KeyType::Data, "services.foo.configuration-files").unwrap(),
-            "[\"file1\"]",
+            &Key::new(KeyType::Data, "services.foo.restart-commands").unwrap(),
+            "[\"echo hi\"]",
             &Committed::Live,
         )
         .unwrap();
-        ds.set_key(
-            &Key::new(KeyType::Data, "services.foo.restart-commands").unwrap(),
-            "[\"echo hi\"]",
+        ds.set_metadata(
+            &Key::new(KeyType::Meta, "affected-services").unwrap(),
+            &Key::new(KeyType::Data, "settings.motd").unwrap(),
+            "[\"motd\"]",
             &Committed::Live,
         )
         .unwrap();
+        ds.set_key(
+            &Key::new(KeyType::Data, "settings.hostname").unwrap(),
+            "\"abc\"",
+            &pending,
+        )
+        .unwrap();
+        // The server's own state is left out
+        bump_generation(&mut ds).unwrap();
 
-        // Retrieve built service
-        let names = hashset!("foo");
-        let services = get_services_names(&ds, &names, &Committed::Live).unwrap();
         assert_eq!(
-            services,
-            hashmap!("foo".to_string() => Service {
-                configuration_files: vec!["file1".try_into().unwrap()],
-                restart_commands: vec!["echo hi".to_string()]
+            dump_all(&ds, &Committed::Live).unwrap(),
+            json!({
+                "settings": {
+                    "motd": "json string",
+                    "ntp": {"time-servers": ["a", "b"]},
+                },
+                "services": {"foo": {"restart-commands": ["echo hi"]}},
+                "metadata": {"settings.motd": {"affected-services": ["motd"]}},
             })
         );
+        assert_eq!(
+            dump_all(&ds, &pending).unwrap(),
+            json!({"settings": {"hostname": "abc"}})
+        );
     }
 
     #[test]
-    fn set_settings_works() {
-        let mut settings = Settings::default();
-        settings.motd = Some("tz".try_into().unwrap());
+    fn load_dump_round_trip() {
+        let dump = json!({
+            "settings": {
+                "motd": "json string",
+                "ntp": {"time-servers": ["a", "b"]},
+            },
+            "services": {"foo": {"configuration-files": [], "restart-commands": ["echo hi"]}},
+            "metadata": {"settings.motd": {"affected-services": ["motd"]}},
+        });
 
-        // Set with helper
         let mut ds = MemoryDataStore::new();
-        let tx = "test transaction";
-        let pending = Committed::Pending { tx: tx.into() };
-        set_settings(&mut ds, &settings, tx).unwrap();
+        let written = load_dump(&mut ds, dump.clone(), &Committed::Live, false).unwrap();
+        assert_eq!(written.len(), 4);
+        assert_eq!(dump_all(&ds, &Committed::Live).unwrap(), dump);
 
-        // Retrieve directly
-        let key = Key::new(KeyType::Data, "settings.motd").unwrap();
-        assert_eq!(
-            Some("\"tz\"".to_string()),
-            ds.get_key(&key, &pending).unwrap()
-        );
+        // Existing keys aren't overwritten unless requested
+        let changed = json!({"settings": {"motd": "changed"}});
+        load_dump(&mut ds, changed.clone(), &Committed::Live, false).unwrap_err();
+        let settings = get_settings(&ds, &Committed::Live).unwrap();
+        assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
+
+        load_dump(&mut ds, changed, &Committed::Live, true).unwrap();
+        let settings = get_settings(&ds, &Committed::Live).unwrap();
+        assert_eq!(settings.motd, Some("changed".try_into().unwrap()));
     }
 
     #[test]
-    fn get_metadata_keys_works() {
-        let mut ds = MemoryDataStore::new();
-        // Set directly with data store
-        for data_key in &["abc", "def", "ghi"] {
-            ds.set_metadata(
-                &Key::new(KeyType::Meta, "my-meta").unwrap(),
-                &Key::new(KeyType::Data, data_key).unwrap(),
-                "\"json string\"",
-            )
-            .unwrap();
-        }
+    fn load_dump_nested_metadata() {
+        // Metadata nested like the defaults file
+        let dump = json!({
+            "metadata": {"settings": {"updates": {
+                "affected-services": ["updog"],
+                "seed": {"setting-generator": "bork seed"},
+            }}},
+        });
 
-        // We'll check a subset by specifying 2 of the 3 keys
-        let expected = hashmap!(
-            "abc".to_string() => "json string".into(),
-            "def".to_string() => "json string".into(),
+        let mut ds = MemoryDataStore::new();
+        load_dump(&mut ds, dump, &Committed::Live, false).unwrap();
+        assert_eq!(
+            dump_all(&ds, &Committed::Live).unwrap(),
+            json!({"metadata": {
+                "settings.updates": {"affected-services": ["updog"]},
+                "settings.updates.seed": {"setting-generator": "bork seed"},
+            }})
         );
-        // Retrieve with helper
-        let actual = get_metadata_for_data_keys(&ds, "my-meta", &hashset!("abc", "def")).unwrap();
-
-        assert_eq!(expected, actual);
     }
 
     #[test]
-    fn get_metadata_all_works() {
+    fn load_dump_validates() {
         let mut ds = MemoryDataStore::new();
-        // Set directly with data store
-        for data_key in &["abc", "def"] {
-            ds.set_metadata(
-                &Key::new(KeyType::Meta, "my-meta").unwrap(),
-                &Key::new(KeyType::Data, data_key).unwrap(),
-                "\"json string\"",
-            )
-            .unwrap();
-        }
+        let pending = Committed::Pending {
+            tx: "test transaction".into(),
+        };
 
-        let expected = hashmap!(
-            "abc".to_string() => "json string".into(),
-            "def".to_string() => "json string".into(),
-        );
-        // Retrieve with helper
-        let actual = get_metadata_for_all_data_keys(&ds, "my-meta").unwrap();
+        // Unknown settings fail the model check, and nothing is written
+        let dump = json!({"settings": {"motd": "hi", "bogus": 1}});
+        load_dump(&mut ds, dump, &pending, false).unwrap_err();
+        // Unknown sections aren't allowed
+        let dump = json!({"settings": {"motd": "hi"}, "bogus": {}});
+        load_dump(&mut ds, dump, &pending, false).unwrap_err();
+        // Must be an object
+        load_dump(&mut ds, json!([1]), &pending, false).unwrap_err();
+        // The server's own state can't be loaded
+        let dump = json!({"metadata": {"apiserver": {"settings-generation": 10}}});
+        load_dump(&mut ds, dump, &pending, false).unwrap_err();
 
-        assert_eq!(expected, actual);
+        assert!(ds.list_populated_keys("", &pending).unwrap().is_empty());
     }
 
     #[test]
-    fn commit_works() {
-        // Set directly with data store
+    fn commit_keys_works() {
         let mut ds = MemoryDataStore::new();
         let tx = "test transaction";
         let pending = Committed::Pending { tx: tx.into() };
-        ds.set_key(
-            &Key::new(KeyType::Data, "settings.motd").unwrap(),
-            "\"json string\"",
-            &pending,
-        )
-        .unwrap();
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let seed = Key::new(KeyType::Data, "settings.updates.seed").unwrap();
+        ds.set_key(&motd, "\"json string\"", &pending).unwrap();
+        ds.set_key(&seed, "42", &pending).unwrap();
 
-        // Confirm pending
-        let settings = get_settings(&ds, &pending).unwrap();
-        assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
-        // No live settings yet
+        // Keys that aren't pending are rejected
+        let keys = hashset!("settings.motd", "settings.timezone");
+        commit_transaction_keys(&mut ds, tx, Some(&keys)).unwrap_err();
         get_settings(&ds, &Committed::Live).unwrap_err();
 
-        // Commit, pending -> live
-        commit_transaction(&mut ds, tx).unwrap();
+        // Commit only motd
+        let keys = hashset!("settings.motd");
+        let committed = commit_transaction_keys(&mut ds, tx, Some(&keys)).unwrap();
+        assert_eq!(committed.changed, hashset!(motd));
 
-        // No more pending settings
-        get_settings(&ds, &pending).unwrap_err();
-        // Confirm live
         let settings = get_settings(&ds, &Committed::Live).unwrap();
         assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
+        assert_eq!(settings.updates, None);
+        let settings = get_settings(&ds, &pending).unwrap();
+        assert_eq!(settings.motd, None);
+        assert_eq!(settings.updates.unwrap().seed, Some(42));
+
+        // No filter commits everything left
+        let committed = commit_transaction_keys(&mut ds, tx, None).unwrap();
+        assert_eq!(committed.changed, hashset!(seed));
     }
 }
@@ -47,17 +47,124 @@ pub enum Error {
     #[snafu(display("Unable to bind to {}: {}", path.display(), source))]
     BindSocket { path: PathBuf, source: io::Error },
 
+    #[snafu(display("Unable to bind to {}: {}", address, source))]
+    BindAddress { address: String, source: io::Error },
+
+    #[snafu(display("Unable to create socket directory {}: {}", path.display(), source))]
+    SocketDirectory { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Refusing to replace {}, which isn't a socket", path.display()))]
+    SocketPathInUse { path: PathBuf },
+
+    #[snafu(display("Unable to remove stale socket {}: {}", path.display(), source))]
+    StaleSocket { path: PathBuf, source: io::Error },
+
     #[snafu(display("Unable to start server: {}", source))]
     ServerStart { source: io::Error },
 
+    #[snafu(display("Server is shutting down, and not accepting changes"))]
+    ShuttingDown,
+
+    #[snafu(display("Not allowed to {} {}", method, path))]
+    Forbidden { method: String, path: String },
+
     #[snafu(display("Tried to commit with no pending changes"))]
     CommitWithNoPending,
 
+    #[snafu(display("Keys not pending in transaction '{}': {}", transaction, keys))]
+    KeysNotPending { transaction: String, keys: String },
+
+    #[snafu(display("Only settings keys can be deleted, not '{}'", name))]
+    DeleteNonSettings { name: String },
+
+    #[snafu(display("A dry run can't be limited to specific keys"))]
+    DryRunWithKeys,
+
+    #[snafu(display("A lenient read can't be limited to specific keys"))]
+    LenientWithKeys,
+
+    #[snafu(display("Key '{}' isn't a setting; settings keys start with 'settings.'", name))]
+    NonSettingsKey { name: String },
+
+    #[snafu(display(
+        "'{}' holds a group of settings rather than a single setting; request it by prefix",
+        key
+    ))]
+    SettingIsPrefix { key: String },
+
+    #[snafu(display("Input was parsed as JSON, but isn't valid settings: {}", source))]
+    SettingsJson { source: serde_json::Error },
+
+    #[snafu(display("Input was parsed as TOML, but isn't valid settings: {}", source))]
+    SettingsToml { source: toml::de::Error },
+
+    #[snafu(display("Input was parsed as {}, but has unrecognized settings: {}", format, settings))]
+    UnknownSettings {
+        format: &'static str,
+        settings: String,
+    },
+
+    #[snafu(display("Header '{}' must be visible ASCII", name))]
+    InvalidHeader { name: String },
+
+    // actix's PayloadError doesn't implement std::error::Error, so we keep its message.
+    #[snafu(display("Unable to read request body: {}", message))]
+    BodyRead { message: String },
+
+    #[snafu(display("Request body is not UTF-8: {}", source))]
+    BodyNotUtf8 { source: std::string::FromUtf8Error },
+
+    #[snafu(display("Request body is larger than the limit of {} bytes", limit))]
+    BodyTooLarge { limit: usize },
+
+    #[snafu(display("Request sets {} keys, more than the limit of {}", count, limit))]
+    TooManyKeys { count: usize, limit: usize },
+
+    #[snafu(display("Settings have changed; the current ETag is {}", etag))]
+    PreconditionFailed { etag: String },
+
+    #[snafu(display("Invalid value for setting '{}': {}", key, reason))]
+    InvalidSetting { key: String, reason: String },
+
+    #[snafu(display("Invalid allowed-pattern '{}' for key '{}': {}", pattern, key, source))]
+    InvalidPattern {
+        key: String,
+        pattern: String,
+        source: regex::Error,
+    },
+
+    #[snafu(display("Keys are immutable and can't be changed: {}", keys))]
+    ImmutableKeys { keys: String },
+
+    #[snafu(display("Requested keys not populated: {}", keys))]
+    MissingKeys { keys: String },
+
+    #[snafu(display("Input '{}' must be 'true' or 'false', got '{}'", input, given))]
+    InvalidBool { input: String, given: String },
+
+    #[snafu(display("Input '{}' must be a non-negative whole number, got '{}'", input, given))]
+    InvalidNumber { input: String, given: String },
+
+    #[snafu(display("Input 'state' must be 'live' or 'pending', got '{}'", given))]
+    InvalidState { given: String },
+
+    #[snafu(display("Input 'fields' has unrecognized settings: {}", fields))]
+    UnknownFieldPaths { fields: String },
+
+    #[snafu(display(
+        "Metadata name '{}' may only contain letters, numbers, '-', and '_'",
+        name
+    ))]
+    InvalidMetadataName { name: String },
+
     #[snafu(display("Unable to get OS release data: {}", source))]
     ReleaseData {
         source: bottlerocket_release::Error,
     },
 
+    #[snafu(display("Unable to parse API description: {}", source))]
+    RoutesDocument { source: serde_yaml::Error },
+
     // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
     // Controller errors
@@ -67,6 +174,9 @@ pub enum Error {
     #[snafu(display("Found no '{}' in datastore", requested))]
     ListKeys { requested: String },
 
+    #[snafu(display("Found no '{}' metadata for the requested keys", name))]
+    MissingMetadata { name: String },
+
     #[snafu(display("Listed key '{}' not found on disk", key))]
     ListedKeyNotPresent { key: String },
 
@@ -101,6 +211,27 @@ pub enum Error {
         source: datastore::Error,
     },
 
+    #[snafu(display("Data key '{}' is not valid JSON: {}", key, source))]
+    InvalidData {
+        key: String,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Key '{}' conflicts with another key in the data store dump", key))]
+    DumpConflict { key: String },
+
+    #[snafu(display("Invalid data store dump: {}", msg))]
+    DumpFormat { msg: String },
+
+    #[snafu(display("Data store dump has invalid '{}': {}", section, source))]
+    DumpDeserialization {
+        section: String,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Data store dump would overwrite existing keys: {}", keys))]
+    DumpConflicts { keys: String },
+
     #[snafu(display("Metadata '{}' is not valid JSON: {}", key, source))]
     InvalidMetadata {
         key: String,
@@ -115,6 +246,59 @@ pub enum Error {
 
     #[snafu(display("Unable to send input to config applier: {}", source))]
     ConfigApplierWrite { source: io::Error },
+
+    #[snafu(display("Unable to wait for config applier: {}", source))]
+    ConfigApplierWait { source: io::Error },
+
+    #[snafu(display("Config applier did not finish within {} seconds", seconds))]
+    ConfigApplierTimeout { seconds: u64 },
+
+    #[snafu(display("Config applier failed with {}: {}", status, stderr))]
+    ConfigApplierFailed { status: String, stderr: String },
+
+    #[snafu(display("Config applier run was canceled before it finished"))]
+    ConfigApplierCanceled {},
+
+    #[snafu(display("Applying settings failed, commit was rolled back: {}", apply_error))]
+    ApplyRolledBack { apply_error: String },
+
+    #[snafu(display(
+        "Applying settings failed ({}), and rolling back the commit also failed: {}",
+        apply_error,
+        rollback_error
+    ))]
+    RollbackFailed {
+        apply_error: String,
+        rollback_error: String,
+    },
+
+    #[snafu(display(
+        "Keys changed while the config applier ran, so they weren't rolled back: {}",
+        keys
+    ))]
+    RollbackConflict { keys: String },
+
+    #[snafu(display(
+        "Settings were committed, but writing audit log '{}' failed: {}",
+        path.display(),
+        source
+    ))]
+    AuditLogWrite { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Settings snapshot '{}' already exists", name))]
+    SnapshotExists { name: String },
+
+    #[snafu(display("Settings snapshot '{}' not found", name))]
+    SnapshotNotFound { name: String },
+
+    #[snafu(display(
+        "Commit of transaction '{}' was interrupted; live settings may have only part of it",
+        transaction
+    ))]
+    InterruptedCommit { transaction: String },
+
+    #[snafu(display("Service restart-after lists form a cycle: {}", cycle))]
+    RestartCycle { cycle: String },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,164 @@
+//! The events module lets clients follow committed settings changes as a stream of server-sent
+//! events, rather than polling.  Commit handlers publish a CommitEvent for each commit that
+//! changes settings, and each client of `/settings/events` gets its own subscription to them.
+//!
+//! Each subscriber has a bounded buffer of events.  If a client reads too slowly and its buffer
+//! fills, the oldest events are dropped, and the client is sent a `lost_events` event with the
+//! count it missed, so it knows to reread the settings it cares about.  Idle streams are sent a
+//! comment every so often so proxies don't close them.
+
+use crate::datastore::Key;
+use actix_rt::time::{interval_at, Instant};
+use actix_web::web::Bytes;
+use futures::future::{self, Either};
+use futures::{pin_mut, stream, Stream};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, RecvError};
+
+/// The number of events buffered for each subscriber.
+const EVENT_BUFFER: usize = 64;
+
+/// How often idle streams are sent a heartbeat comment.
+const HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// A commit that changed settings, as sent to subscribers.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct CommitEvent {
+    /// The settings generation after the commit.
+    pub(crate) generation: u64,
+    /// The keys whose values changed, sorted.
+    pub(crate) changed_keys: Vec<String>,
+    /// The services affected by the changes, sorted.
+    pub(crate) affected_services: Vec<String>,
+}
+
+impl CommitEvent {
+    pub(crate) fn new(generation: u64, changed: &HashSet<Key>, services: HashSet<String>) -> Self {
+        let mut changed_keys: Vec<String> = changed.iter().map(|k| k.name().to_string()).collect();
+        changed_keys.sort();
+        let mut affected_services: Vec<String> = services.into_iter().collect();
+        affected_services.sort();
+        Self {
+            generation,
+            changed_keys,
+            affected_services,
+        }
+    }
+
+    /// Formats the event as a server-sent event, using the generation as its ID.
+    fn to_sse(&self) -> String {
+        // Our fields are all plain strings and numbers, so serialization can't fail.
+        let data = serde_json::to_string(self).unwrap_or_default();
+        format!("id: {}\nevent: commit\ndata: {}\n\n", self.generation, data)
+    }
+}
+
+/// Publishes commit events to subscribers.  This is shared by all server workers.
+pub(crate) struct CommitEvents {
+    sender: broadcast::Sender<CommitEvent>,
+    heartbeat: Duration,
+}
+
+impl Default for CommitEvents {
+    fn default() -> Self {
+        Self::with_heartbeat(HEARTBEAT)
+    }
+}
+
+impl CommitEvents {
+    fn with_heartbeat(heartbeat: Duration) -> Self {
+        // Receivers come from subscribe, so we don't need the initial one.
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { sender, heartbeat }
+    }
+
+    /// Sends the event to current subscribers, if any.
+    pub(crate) fn publish(&self, event: CommitEvent) {
+        // This only fails if there are no subscribers, which is fine; nobody's listening.
+        let _ = self.sender.send(event);
+    }
+
+    /// Returns a stream of server-sent events for commits published from now on, with
+    /// heartbeats while idle.  The stream ends when the server shuts down.
+    pub(crate) fn subscribe(&self) -> impl Stream<Item = Result<Bytes, actix_web::Error>> + Unpin {
+        let receiver = self.sender.subscribe();
+        let heartbeat = interval_at(Instant::now() + self.heartbeat, self.heartbeat);
+
+        Box::pin(stream::unfold(
+            (receiver, heartbeat),
+            |(mut receiver, mut heartbeat)| async move {
+                let chunk = {
+                    let event = receiver.recv();
+                    let tick = heartbeat.tick();
+                    pin_mut!(event, tick);
+                    match future::select(event, tick).await {
+                        Either::Left((Ok(event), _)) => event.to_sse(),
+                        Either::Left((Err(RecvError::Lagged(count)), _)) => {
+                            format!("event: lost_events\ndata: {{\"count\":{}}}\n\n", count)
+                        }
+                        Either::Left((Err(RecvError::Closed), _)) => return None,
+                        Either::Right(_) => ": heartbeat\n\n".to_string(),
+                    }
+                };
+                Some((Ok(Bytes::from(chunk)), (receiver, heartbeat)))
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::datastore::KeyType;
+    use futures::StreamExt;
+    use maplit::hashset;
+
+    fn event(generation: u64) -> CommitEvent {
+        CommitEvent::new(
+            generation,
+            &hashset!(Key::new(KeyType::Data, "settings.motd").unwrap()),
+            hashset!("motd".to_string()),
+        )
+    }
+
+    async fn next_chunk<S>(stream: &mut S) -> String
+    where
+        S: Stream<Item = Result<Bytes, actix_web::Error>> + Unpin,
+    {
+        let chunk = stream.next().await.unwrap().unwrap();
+        String::from_utf8(chunk.to_vec()).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn slow_subscribers_lose_oldest() {
+        let events = CommitEvents::default();
+        let mut stream = events.subscribe();
+        for generation in 1..=EVENT_BUFFER as u64 + 2 {
+            events.publish(event(generation));
+        }
+
+        assert_eq!(
+            next_chunk(&mut stream).await,
+            "event: lost_events\ndata: {\"count\":2}\n\n"
+        );
+        assert!(next_chunk(&mut stream)
+            .await
+            .starts_with("id: 3\nevent: commit\n"));
+    }
+
+    #[actix_rt::test]
+    async fn idle_streams_get_heartbeats() {
+        let events = CommitEvents::with_heartbeat(Duration::from_millis(10));
+        let mut stream = events.subscribe();
+        assert_eq!(next_chunk(&mut stream).await, ": heartbeat\n\n");
+
+        events.publish(event(7));
+        assert_eq!(
+            next_chunk(&mut stream).await,
+            "id: 7\nevent: commit\ndata: {\"generation\":7,\"changed-keys\":[\"settings.motd\"],\"affected-services\":[\"motd\"]}\n\n"
+        );
+    }
+}
@@ -1,27 +1,62 @@
 //! The server module owns the API surface.  It interfaces with the datastore through the
 //! server::controller module.
 
+mod auth;
 mod controller;
 mod error;
+mod events;
+mod request_log;
+mod schema;
+mod shutdown;
+mod unknown_fields;
+mod util;
+pub use auth::{Allow, AuthConfig, AuthRule};
+pub use controller::{ApplierConfig, AuditLog};
 pub use error::Error;
+pub use request_log::RequestLogConfig;
+pub use shutdown::ShutdownHook;
 
-use crate::datastore::{Committed, FilesystemDataStore, Key, Value};
-use actix_web::{error::ResponseError, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use crate::datastore::{Committed, DataStore, FilesystemDataStore, Key, Value};
+use actix_http::{HttpService, Protocol};
+use actix_rt::net::UnixStream;
+use actix_service::{map_config, pipeline_factory};
+use actix_web::dev::{AppConfig, Server};
+use actix_web::{
+    error::{BlockingError, ResponseError},
+    http::{header, StatusCode},
+    web, App, Either, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use auth::{Authorizer, ConnectionCredentials, SocketPeer};
 use bottlerocket_release::BottlerocketRelease;
+use controller::{
+    ApplierRun, ApplyMode, ApplyOutcome, AuditEntry, CommitAndApply, CommittedKeys, DryRunReport,
+    PendingChange, RawKey, SetBehavior,
+};
 use error::Result;
-use futures::future;
-use log::info;
+use events::{CommitEvent, CommitEvents};
+use futures::{future, StreamExt};
+use log::{error, info, warn};
 use model::{ConfigurationFiles, Model, Services, Settings};
 use nix::unistd::{chown, Gid};
-use snafu::{ensure, OptionExt, ResultExt};
-use std::collections::{HashMap, HashSet};
+use request_log::{record_keys, RequestLogger};
+use serde::Serialize;
+use serde_json::json;
+use shutdown::ShutdownGuard;
+use snafu::{ensure, IntoError, NoneError as NoSource, OptionExt, ResultExt};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
-use std::fs::set_permissions;
-use std::fs::Permissions;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::fs::{self, set_permissions, Permissions};
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use util::PrettyJson;
+
+/// How long to wait for settings appliers when a request asks to wait for them.
+const APPLY_TIMEOUT: Duration = Duration::from_secs(300);
 
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
@@ -45,46 +80,174 @@ fn notify_unix_socket_ready() -> Result<()> {
 
 // Router
 
+/// Where the server listens for requests.
+#[derive(Debug, Clone)]
+pub enum Listener {
+    /// A Unix-domain socket at the given path, which is what Bottlerocket uses, so access to the API
+    /// can be limited with file permissions.  The socket is made writable by the given group, if
+    /// any, in addition to its owner.
+    Socket { path: PathBuf, gid: Option<Gid> },
+
+    /// A TCP address, like "127.0.0.1:4242".  Any local process can reach the API this way, so
+    /// it's only meant for development on hosts without Unix-domain sockets.
+    Tcp { address: String },
+}
+
+/// Limits on the input to settings requests, so a client can't make us buffer and parse
+/// arbitrarily large bodies, or write arbitrarily many keys.
+#[derive(Debug, Clone)]
+pub struct InputLimits {
+    /// The largest request body accepted, in bytes.
+    pub max_body_bytes: usize,
+    /// The most settings keys a single request can set.
+    pub max_keys: usize,
+}
+
+impl Default for InputLimits {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 256 * 1024,
+            max_keys: 4096,
+        }
+    }
+}
+
 /// This is the primary interface of the module.  It defines the server and application that actix
 /// spawns for requests.  It creates a shared datastore handle that can be used by handler methods
-/// to interface with the controller.
-pub async fn serve<P1, P2>(
-    socket_path: P1,
-    datastore_path: P2,
+/// to interface with the controller.  Requests are accepted from the given `listener`, and logged
+/// as described by `request_log`; settings input is limited by `limits`.  Settings changes are
+/// applied to the system using the given `applier`, and if `audit_log` is given, committed changes
+/// are recorded there.  Callers may only use the routes `auth` allows them.  The server runs until
+/// `shutdown` is used to stop it.
+#[allow(clippy::too_many_arguments)]
+pub async fn serve<P>(
+    listener: Listener,
+    datastore_path: P,
+    threads: usize,
+    request_log: RequestLogConfig,
+    limits: InputLimits,
+    applier: ApplierConfig,
+    audit_log: Option<AuditLog>,
+    auth: AuthConfig,
+    shutdown: ShutdownHook,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    serve_datastore(
+        listener,
+        FilesystemDataStore::new(datastore_path),
+        threads,
+        request_log,
+        limits,
+        applier,
+        audit_log,
+        auth,
+        shutdown,
+    )
+    .await
+}
+
+/// Runs the server as described for `serve`, using the given data store.  The server always uses
+/// a FilesystemDataStore; tests use others to see or control what the server does with it.
+#[allow(clippy::too_many_arguments)]
+async fn serve_datastore<D>(
+    listener: Listener,
+    datastore: D,
     threads: usize,
-    socket_gid: Option<Gid>,
+    request_log: RequestLogConfig,
+    limits: InputLimits,
+    applier: ApplierConfig,
+    audit_log: Option<AuditLog>,
+    auth: AuthConfig,
+    shutdown: ShutdownHook,
 ) -> Result<()>
 where
-    P1: AsRef<Path>,
-    P2: AsRef<Path>,
+    D: DataStore + Send + Sync + 'static,
 {
-    let shared_datastore = web::Data::new(SharedDataStore {
-        ds: sync::RwLock::new(FilesystemDataStore::new(datastore_path)),
-    });
+    let shared_datastore = web::Data::new(SharedDataStore::new(datastore));
+    let applier = web::Data::new(applier);
+    let limits = web::Data::new(limits);
+    let audit_log = web::Data::new(audit_log);
+    let events = web::Data::new(CommitEvents::default());
+    let started = web::Data::new(StartTime(Instant::now()));
+
+    // The data store was populated before we started, so problems in it can be reported now,
+    // rather than when something tries to use it.
+    {
+        let datastore = shared_datastore.read()?;
+        log_model_consistency(&*datastore);
+    }
 
-    let http_server = HttpServer::new(move || {
+    // The app factory takes its own handle, so we keep one to wait on in-flight commits at
+    // shutdown.
+    let drain_datastore = shared_datastore.clone();
+    let guard_hook = shutdown.clone();
+    let app = move || {
         App::new()
             .app_data(shared_datastore.clone())
+            .app_data(applier.clone())
+            .app_data(limits.clone())
+            .app_data(audit_log.clone())
+            .app_data(events.clone())
+            .app_data(started.clone())
+            .wrap(Authorizer::new(
+                auth.clone(),
+                ConnectionCredentials::new(auth.token.clone()),
+            ))
+            .wrap(ShutdownGuard::new(guard_hook.clone()))
+            .wrap(PrettyJson)
+            .wrap(RequestLogger::new(request_log.clone()))
 
             // Retrieve the full API model; not all data is writable, so we only support GET.
-            .route("/", web::get().to(get_model))
+            .route("/", web::get().to(get_model::<D>))
+
+            // Liveness and readiness, for service managers and orchestrators
+            .route("/health", web::get().to(get_health))
+            .route("/ready", web::get().to(get_ready::<D>))
+
+            // OpenAPI description of the API, with schemas generated from the model
+            .route("/schema", web::get().to(get_schema))
 
             .service(
                 web::scope("/settings")
-                    .route("", web::get().to(get_settings))
-                    .route("", web::patch().to(patch_settings)),
+                    .route("", web::get().to(get_settings::<D>))
+                    .route("", web::patch().to(patch_settings::<D>))
+                    .route("", web::delete().to(delete_settings::<D>))
+                    // Set, commit, and apply settings in one request
+                    .route("/apply", web::post().to(apply_settings::<D>))
+                    // Settings staged in a transaction, and how they differ from live
+                    .route("/pending", web::get().to(get_pending_settings::<D>))
+                    .route("/pending", web::delete().to(discard_pending_settings::<D>))
+                    .route(
+                        "/pending/diff",
+                        web::get().to(get_pending_settings_diff::<D>),
+                    )
+                    // Committed changes, as they happen
+                    .route("/events", web::get().to(get_settings_events))
+                    // A single setting's value
+                    .route("/value", web::get().to(get_setting_value::<D>))
+                    // Names of populated settings, without their values
+                    .route("/keys", web::get().to(get_settings_keys::<D>))
+                    // Saved copies of live settings, which can be restored into a transaction
+                    .route("/snapshots", web::get().to(get_snapshots::<D>))
+                    .route("/snapshots", web::post().to(create_snapshot::<D>))
+                    .route("/snapshots", web::delete().to(delete_snapshot::<D>))
+                    .route("/snapshots/restore", web::post().to(restore_snapshot::<D>))
+                    // How many times live settings have changed
+                    .route("/generation", web::get().to(get_settings_generation::<D>)),
             )
             .service(
                 // Transaction support
                 web::scope("/tx")
-                    .route("/list", web::get().to(get_transaction_list))
-                    .route("", web::get().to(get_transaction))
-                    .route("", web::delete().to(delete_transaction))
-                    .route("/commit", web::post().to(commit_transaction))
-                    .route("/apply", web::post().to(apply_changes))
+                    .route("/list", web::get().to(get_transaction_list::<D>))
+                    .route("", web::get().to(get_transaction::<D>))
+                    .route("", web::delete().to(delete_transaction::<D>))
+                    .route("/commit", web::post().to(commit_transaction::<D>))
+                    .route("/apply", web::post().to(apply_changes::<D>))
                     .route(
                         "/commit_and_apply",
-                        web::post().to(commit_transaction_and_apply),
+                        web::post().to(commit_transaction_and_apply::<D>),
                     ),
             )
             .service(
@@ -93,36 +256,127 @@ where
             )
             .service(
                 web::scope("/metadata")
-                    .route("/affected-services", web::get().to(get_affected_services))
-                    .route("/setting-generators", web::get().to(get_setting_generators))
-                    .route("/templates", web::get().to(get_templates)),
+                    .route("", web::get().to(get_all_metadata::<D>))
+                    .route("/affected-services", web::get().to(get_affected_services::<D>))
+                    .route("/setting-generators", web::get().to(get_setting_generators::<D>))
+                    .route("/templates", web::get().to(get_templates::<D>))
+                    .route("/{md_key}", web::get().to(get_metadata::<D>)),
+            )
+            .service(web::scope("/services").route("", web::get().to(get_services::<D>)))
+            // Unstable routes for debugging, only allowed for privileged callers; see auth
+            .service(
+                web::scope("/debug")
+                    .route("/datastore/key", web::get().to(get_raw_key::<D>))
+                    .route("/datastore/dump", web::get().to(get_dump::<D>))
+                    .route("/datastore/mtimes", web::get().to(get_settings_mtimes::<D>))
+                    .route("/datastore/dump", web::post().to(post_dump::<D>)),
             )
-            .service(web::scope("/services").route("", web::get().to(get_services)))
             .service(
                 web::scope("/configuration-files")
-                    .route("", web::get().to(get_configuration_files)),
+                    .route("", web::get().to(get_configuration_files::<D>)),
             )
-    })
-    .workers(threads)
-    .bind_uds(socket_path.as_ref())
-    .context(error::BindSocket {
-        path: socket_path.as_ref(),
-    })?;
+    };
 
-    // If the socket needs to be chowned to a group to grant further access, that can be passed
-    // as a paramter.
-    if let Some(gid) = socket_gid {
-        chown(socket_path.as_ref(), None, Some(gid)).context(error::SetGroup { gid })?;
-    }
+    let server = match listener {
+        Listener::Socket { path, gid } => {
+            prepare_socket_path(&path)?;
+            let socket = UnixListener::bind(&path).context(error::BindSocket { path: &path })?;
+
+            // If the socket needs to be chowned to a group to grant further access, that can be
+            // passed as a paramter.
+            if let Some(gid) = gid {
+                chown(&path, None, Some(gid)).context(error::SetGroup { gid })?;
+            }
 
-    let mode = 0o0660;
-    let perms = Permissions::from_mode(mode);
-    set_permissions(socket_path.as_ref(), perms).context(error::SetPermissions { mode })?;
+            let mode = 0o0660;
+            let perms = Permissions::from_mode(mode);
+            set_permissions(&path, perms).context(error::SetPermissions { mode })?;
+
+            // HttpServer doesn't let us see connections, so we build the HTTP service ourselves,
+            // recording the peer credentials of each connection for the Authorizer.
+            Server::build()
+                .workers(threads)
+                // Our caller decides when to stop, usually on a signal, through the shutdown hook.
+                .disable_signals()
+                .shutdown_timeout(shutdown.drain_timeout_secs())
+                .listen_uds("apiserver", socket, move || {
+                    pipeline_factory(|io: UnixStream| future::ok((io, Protocol::Http1, None)))
+                        .and_then(
+                            HttpService::build()
+                                .on_connect(|io: &UnixStream| {
+                                    SocketPeer(auth::peer_credentials(io))
+                                })
+                                .finish(map_config(app(), |_| AppConfig::default())),
+                        )
+                })
+                .context(error::BindSocket { path: &path })?
+                .run()
+        }
+        Listener::Tcp { address } => {
+            warn!(
+                "Listening on TCP address {}; any local process can use the API",
+                address
+            );
+            HttpServer::new(app)
+                .workers(threads)
+                .disable_signals()
+                .shutdown_timeout(shutdown.drain_timeout_secs())
+                .bind(&address)
+                .context(error::BindAddress { address: &address })?
+                .run()
+        }
+    };
 
     // Notify system manager the UNIX socket has been initialized, so other service units can proceed
     notify_unix_socket_ready()?;
 
-    http_server.run().await.context(error::ServerStart)
+    shutdown.started(server.clone());
+    let result = server.await.context(error::ServerStart);
+
+    // A commit still running on a worker past the drain timeout holds the data store lock, so
+    // wait for it; exiting now could leave live settings with only part of the commit.
+    drop(drain_datastore.ds.write());
+    shutdown.log_drained();
+    result
+}
+
+/// Makes sure we can bind a Unix-domain socket at the given path: creates its parent directories,
+/// and removes any socket left behind by a previous run of the server.  Anything else at the path
+/// is left alone, and we fail rather than replace it.
+fn prepare_socket_path(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context(error::SocketDirectory { path: parent })?;
+    }
+
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => {
+            ensure!(
+                metadata.file_type().is_socket(),
+                error::SocketPathInUse { path }
+            );
+            info!("Removing stale socket at {}", path.display());
+            fs::remove_file(path).context(error::StaleSocket { path })
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).context(error::StaleSocket { path }),
+    }
+}
+
+/// Logs any problems with the references between services and configuration files in the data
+/// store.  These don't stop the server from starting, since most requests are still useful.
+fn log_model_consistency<D: DataStore>(datastore: &D) {
+    match controller::validate_model_consistency(datastore) {
+        Ok(problems) => {
+            for problem in problems {
+                if problem.is_warning() {
+                    warn!("{}", problem);
+                } else {
+                    error!("{}", problem);
+                }
+            }
+        }
+        Err(e) => error!("Unable to check services and configuration files: {}", e),
+    }
 }
 
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
@@ -130,8 +384,8 @@ where
 // Handler methods called by the router
 
 /// Returns all data in the API model.
-async fn get_model(data: web::Data<SharedDataStore>) -> Result<ModelResponse> {
-    let datastore = data.ds.read().ok().context(error::DataStoreLock)?;
+async fn get_model<D: DataStore>(data: web::Data<SharedDataStore<D>>) -> Result<ModelResponse> {
+    let datastore = data.read()?;
 
     let settings = Some(controller::get_settings(&*datastore, &Committed::Live)?);
     let services = Some(controller::get_services(&*datastore)?);
@@ -142,123 +396,826 @@ async fn get_model(data: web::Data<SharedDataStore>) -> Result<ModelResponse> {
     Ok(ModelResponse(model))
 }
 
+/// Liveness check: if we can respond, the server is up.  Returns the server version and how long
+/// it's been running.
+async fn get_health(started: web::Data<StartTime>) -> Result<HealthResponse> {
+    Ok(HealthResponse(Health {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_seconds: started.0.elapsed().as_secs(),
+    }))
+}
+
+/// Returns the OpenAPI document describing the API.
+async fn get_schema() -> Result<SchemaResponse> {
+    Ok(SchemaResponse(schema::openapi_document()?))
+}
+
+/// Readiness check: returns the result of each check of the data store, with 503 Service
+/// Unavailable if any failed, meaning requests are likely to fail too.
+async fn get_ready<D: DataStore>(data: web::Data<SharedDataStore<D>>) -> Result<HttpResponse> {
+    let datastore = data.read()?;
+    let readiness = controller::check_readiness(&*datastore);
+    for check in readiness.checks.iter().filter(|check| !check.ok) {
+        warn!("Readiness check '{}' failed: {:?}", check.name, check.error);
+    }
+
+    let body = serde_json::to_string(&readiness).context(error::ResponseSerialization)?;
+    let mut response = if readiness.ready {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    Ok(response.content_type("application/json").body(body))
+}
+
 // actix-web doesn't support Query for enums, so we use a HashMap and check for the expected keys
 // ourselves.
 /// Return the live settings from the data store; if 'keys' or 'prefix' are specified in query
-/// parameters, return the subset of matching settings.
-async fn get_settings(
+/// parameters, return the subset of matching settings.  If 'state' is "pending", settings pending
+/// in the given transaction are returned instead of live settings.
+///
+/// The response for live settings has an ETag header identifying them.  If the request's
+/// If-None-Match header lists that ETag, the settings haven't changed since the client last read
+/// them, so we respond 304 Not Modified without a body.
+///
+/// If 'fields' is given, the response only includes those fields of the settings, given as
+/// dotted paths like "ntp.time-servers".  Fields that aren't in the settings model are rejected.
+async fn get_settings<D: DataStore>(
+    req: HttpRequest,
     query: web::Query<HashMap<String, String>>,
-    data: web::Data<SharedDataStore>,
-) -> Result<SettingsResponse> {
-    let datastore = data.ds.read().ok().context(error::DataStoreLock)?;
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<HttpResponse> {
+    let committed = settings_state(&query)?;
+    let fields = fields_param(&query)?;
+    let subset = query.contains_key("keys") || query.contains_key("prefix");
+    let datastore = data.read()?;
 
-    let settings = if let Some(keys_str) = query.get("keys") {
-        let keys = comma_separated("keys", keys_str)?;
-        controller::get_settings_keys(&*datastore, &keys, &Committed::Live)
-    } else if let Some(prefix_str) = query.get("prefix") {
-        if prefix_str.is_empty() {
-            return error::EmptyInput { input: "prefix" }.fail();
+    if bool_param(&query, "lenient")? {
+        ensure!(!subset, error::LenientWithKeys);
+        return lenient_settings(&*datastore, &committed, fields);
+    }
+
+    // ETags identify the live settings, so pending settings are returned without one.
+    let ((settings, count), etag) = match committed {
+        Committed::Live if subset => {
+            let etag = controller::get_settings_etag(&*datastore)?;
+            if etag_requested(&req, header::IF_NONE_MATCH, &etag)? {
+                return Ok(not_modified(&etag));
+            }
+            (query_settings(&query, &*datastore, &committed)?, Some(etag))
+        }
+        Committed::Live => {
+            let (settings, etag, count) = controller::get_settings_and_etag(&*datastore)?;
+            if etag_requested(&req, header::IF_NONE_MATCH, &etag)? {
+                return Ok(not_modified(&etag));
+            }
+            ((settings, count), Some(etag))
+        }
+        Committed::Pending { .. } if subset => {
+            (query_settings(&query, &*datastore, &committed)?, None)
+        }
+        Committed::Pending { .. } => (
+            controller::get_settings_and_count(&*datastore, &committed)?,
+            None,
+        ),
+    };
+    record_keys(&req, count, 0);
+
+    let body = match fields {
+        Some(fields) => {
+            let value = serde_json::to_value(&settings).context(error::ResponseSerialization)?;
+            serde_json::to_string(&util::prune(&value, &fields))
+        }
+        None => serde_json::to_string(&settings),
+    }
+    .context(error::ResponseSerialization)?;
+    let mut response = HttpResponse::Ok();
+    if let Some(etag) = etag {
+        response.header(header::ETAG, etag);
+    }
+    Ok(response.content_type("application/json").body(body))
+}
+
+/// Returns the state of settings requested with the 'state' query parameter: "live", the default,
+/// or "pending", meaning the settings pending in the requested transaction.
+fn settings_state(query: &web::Query<HashMap<String, String>>) -> Result<Committed> {
+    match query.get("state").map(String::as_str) {
+        None | Some("live") => Ok(Committed::Live),
+        Some("pending") => Ok(Committed::Pending {
+            tx: transaction_name(query).to_string(),
+        }),
+        Some(given) => error::InvalidState { given }.fail(),
+    }
+}
+
+/// Returns the settings fields requested with the 'fields' query parameter, as paths of keys
+/// below "settings", or None if it wasn't given.  Fields that aren't in the settings model are
+/// an error listing them, so a typo isn't mistaken for an unset setting.
+fn fields_param(query: &web::Query<HashMap<String, String>>) -> Result<Option<Vec<Vec<String>>>> {
+    let fields_str = match query.get("fields") {
+        Some(fields_str) => fields_str,
+        None => return Ok(None),
+    };
+
+    let mut paths = Vec::new();
+    let mut unknown = Vec::new();
+    for field in comma_separated("fields", fields_str)? {
+        let path: Vec<String> = field.split('.').map(String::from).collect();
+        // Check the field against the model the way we check input, with input of just that field
+        let mut input = Value::Null;
+        for segment in path.iter().rev() {
+            let mut map = serde_json::Map::new();
+            map.insert(segment.clone(), input);
+            input = Value::Object(map);
         }
-        // Note: the prefix should not include "settings."
-        controller::get_settings_prefix(&*datastore, prefix_str, &Committed::Live)
+        if path.iter().any(String::is_empty) || !unknown_fields::find::<Settings>(&input).is_empty()
+        {
+            unknown.push(field);
+        } else {
+            paths.push(path);
+        }
+    }
+    unknown.sort();
+    ensure!(
+        unknown.is_empty(),
+        error::UnknownFieldPaths {
+            fields: unknown.join(", "),
+        }
+    );
+    Ok(Some(paths))
+}
+
+/// Returns the subset of settings requested with 'keys' or 'prefix'; see get_settings.  Each may
+/// be a comma-separated list, and they may be given together, in which case the settings matching
+/// either are returned.  The number of keys the settings were built from is returned with them.
+fn query_settings<D: DataStore>(
+    query: &web::Query<HashMap<String, String>>,
+    datastore: &D,
+    committed: &Committed,
+) -> Result<(Settings, usize)> {
+    let keys = match query.get("keys") {
+        Some(keys_str) => Some(comma_separated("keys", keys_str)?),
+        None => None,
+    };
+    // Note: the prefixes should not include "settings."
+    let prefixes = match query.get("prefix") {
+        Some(prefix_str) => Some(comma_separated("prefix", prefix_str)?),
+        None => None,
+    };
+    let missing = if bool_param(&query, "strict")? {
+        controller::MissingKeyBehavior::Error
     } else {
-        controller::get_settings(&*datastore, &Committed::Live)
-    }?;
+        controller::MissingKeyBehavior::Skip
+    };
 
-    Ok(SettingsResponse(settings))
+    match (keys, prefixes) {
+        (Some(keys), None) => controller::get_settings_keys(datastore, &keys, missing, committed),
+        (None, Some(prefixes)) => {
+            controller::get_settings_prefixes(datastore, &prefixes, committed)
+        }
+        (Some(keys), Some(prefixes)) => controller::get_settings_keys_and_prefixes(
+            datastore, &keys, &prefixes, missing, committed,
+        ),
+        (None, None) => error::MissingInput { input: "prefix" }.fail(),
+    }
+}
+
+/// A setting left out of a lenient read, as returned by lenient_settings.
+#[derive(Debug, PartialEq, Serialize)]
+struct SkippedSetting {
+    key: String,
+    /// Why the setting's value couldn't be read.
+    error: String,
+}
+
+/// The response to GET /settings with 'lenient'.
+#[derive(Debug, PartialEq, Serialize)]
+struct LenientSettings {
+    /// The settings that could be read, or null if none could.
+    settings: Value,
+    skipped: Vec<SkippedSetting>,
+}
+
+/// Returns whatever settings can be read, along with the settings that were skipped because their
+/// values can't be, so one bad value doesn't hide the rest of the settings while debugging it.
+fn lenient_settings<D: DataStore>(
+    datastore: &D,
+    committed: &Committed,
+    fields: Option<Vec<Vec<String>>>,
+) -> Result<HttpResponse> {
+    let lenient = controller::get_settings_lenient(datastore, committed)?;
+    let settings = match lenient.value {
+        Some(settings) => {
+            let value = serde_json::to_value(&settings).context(error::ResponseSerialization)?;
+            match fields {
+                Some(fields) => util::prune(&value, &fields),
+                None => value,
+            }
+        }
+        None => Value::Null,
+    };
+    let skipped = lenient
+        .skipped
+        .iter()
+        .map(|(key, e)| SkippedSetting {
+            key: key.name().to_string(),
+            error: e.to_string(),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(LenientSettings { settings, skipped }))
+}
+
+/// Apply the requested settings, given as JSON or TOML, to the pending data store.  Settings given
+/// as null in JSON are staged for removal.  If 'replace' is true, each map of settings given,
+/// like host-containers, replaces the existing map, rather than being merged into it.  If the
+/// request has an If-Match header, the settings are only staged if it lists the ETag of the live
+/// settings.
+///
+/// Bodies larger than the configured limit are rejected before parsing, as are requests that
+/// would set more keys than the configured limit.
+async fn patch_settings<D: DataStore>(
+    req: HttpRequest,
+    payload: web::Payload,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+    limits: web::Data<InputLimits>,
+) -> Result<HttpResponse> {
+    let body = read_body(&req, payload, limits.max_body_bytes).await?;
+    let input = controller::settings_input(&body)?;
+    let behavior = if bool_param(&query, "replace")? {
+        SetBehavior::Replace
+    } else {
+        SetBehavior::Merge
+    };
+    let transaction = transaction_name(&query);
+    let mut datastore = data.write()?;
+    check_if_match(&req, &*datastore)?;
+    let written = controller::set_settings(
+        &mut *datastore,
+        &input,
+        behavior,
+        transaction,
+        limits.max_keys,
+    )?;
+    record_keys(&req, 0, written.len());
+    Ok(HttpResponse::NoContent().finish()) // 204
+}
+
+/// Stages the settings in the body, as for patch_settings, then commits just those keys from the
+/// transaction and waits for the config applier, so a client can change settings in one request.
+/// Responds with a report of the keys staged and committed, the services affected, and whether
+/// the applier succeeded, and if not, whether the commit was rolled back.
+///
+/// Failures before the commit are returned as errors, like any other request.  Settings that
+/// fail validation are rejected before anything is staged.  If the commit itself fails, the
+/// settings stay staged in the transaction, as after a PATCH.  Once the settings are committed,
+/// applier failures are reported in the response rather than as errors, as are failures to write
+/// the audit log, find the affected services, or publish the commit.
+async fn apply_settings<D: DataStore>(
+    req: HttpRequest,
+    payload: web::Payload,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+    limits: web::Data<InputLimits>,
+    applier: web::Data<ApplierConfig>,
+    audit_log: web::Data<Option<AuditLog>>,
+    events: web::Data<CommitEvents>,
+) -> Result<ApplyReportResponse> {
+    let body = read_body(&req, payload, limits.max_body_bytes).await?;
+    let input = controller::settings_input(&body)?;
+    let transaction = transaction_name(&query);
+    let mut datastore = data.write()?;
+    check_if_match(&req, &*datastore)?;
+
+    let staged = controller::set_settings(
+        &mut *datastore,
+        &input,
+        SetBehavior::Merge,
+        transaction,
+        limits.max_keys,
+    )?;
+    let staged_names = staged.iter().map(|k| k.name().as_str()).collect();
+    let (result, datastore) = commit_and_apply(
+        &data,
+        datastore,
+        transaction,
+        Some(&staged_names),
+        &applier,
+        APPLY_TIMEOUT,
+    )
+    .await?;
+    if result.committed.is_empty() {
+        return error::CommitWithNoPending.fail();
+    }
+    record_keys(&req, 0, result.committed.len());
+    let mut warnings = Vec::new();
+    warnings.extend(write_audit_log(&audit_log, &result.committed.audit));
+
+    let affected_services =
+        match controller::get_affected_services(&*datastore, &result.committed.changed) {
+            Ok(services) => services,
+            Err(e) => {
+                error!("{}", e);
+                warnings.push(format!("Unable to find affected services: {}", e));
+                HashSet::new()
+            }
+        };
+    let apply = match result.outcome {
+        ApplyOutcome::Applied if result.committed.changed.is_empty() => ApplyResult::Unchanged,
+        ApplyOutcome::Applied => {
+            warnings.extend(publish_commit(&events, &*datastore, &result.committed));
+            ApplyResult::Applied
+        }
+        ApplyOutcome::RolledBack { apply_error } => ApplyResult::failed(apply_error, None),
+        ApplyOutcome::RollbackFailed {
+            apply_error,
+            rollback_error,
+        } => ApplyResult::failed(apply_error, Some(rollback_error)),
+    };
+
+    Ok(ApplyReportResponse(ApplyReport {
+        staged_keys: sorted_names(&staged),
+        committed_keys: sorted_names(
+            result
+                .committed
+                .changed
+                .iter()
+                .chain(&result.committed.unchanged),
+        ),
+        changed_keys: sorted_names(&result.committed.changed),
+        affected_services: affected_services.into_iter().collect(),
+        apply,
+        warnings,
+    }))
+}
+
+/// Returns the names of the given keys, sorted, for reports.
+fn sorted_names<'a>(keys: impl IntoIterator<Item = &'a Key>) -> Vec<String> {
+    let mut names: Vec<String> = keys.into_iter().map(|k| k.name().clone()).collect();
+    names.sort();
+    names
+}
+
+/// Reads the request body as a string.  Fails as soon as we know the body is larger than `limit`
+/// bytes, so we never buffer or parse more than that.
+async fn read_body(req: &HttpRequest, mut payload: web::Payload, limit: usize) -> Result<String> {
+    // actix-web makes sure the body matches Content-Length, so a large one can be rejected early.
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    if let Some(length) = length {
+        ensure!(length <= limit, error::BodyTooLarge { limit });
+    }
+
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| {
+            error::BodyRead {
+                message: e.to_string(),
+            }
+            .into_error(NoSource)
+        })?;
+        ensure!(
+            body.len() + chunk.len() <= limit,
+            error::BodyTooLarge { limit }
+        );
+        body.extend_from_slice(&chunk);
+    }
+    String::from_utf8(body.to_vec()).context(error::BodyNotUtf8)
 }
 
-/// Apply the requested settings to the pending data store
-async fn patch_settings(
-    settings: web::Json<Settings>,
+/// Stage removal of the settings given in the 'keys' query parameter in the pending data store
+async fn delete_settings<D: DataStore>(
+    req: HttpRequest,
     query: web::Query<HashMap<String, String>>,
-    data: web::Data<SharedDataStore>,
+    data: web::Data<SharedDataStore<D>>,
 ) -> Result<HttpResponse> {
+    let keys_str = query
+        .get("keys")
+        .context(error::MissingInput { input: "keys" })?;
+    let keys = comma_separated("keys", keys_str)?;
     let transaction = transaction_name(&query);
-    let mut datastore = data.ds.write().ok().context(error::DataStoreLock)?;
-    controller::set_settings(&mut *datastore, &settings, transaction)?;
+    let mut datastore = data.write()?;
+    controller::delete_settings_keys(&mut *datastore, &keys, transaction)?;
+    record_keys(&req, 0, keys.len());
     Ok(HttpResponse::NoContent().finish()) // 204
 }
 
-async fn get_transaction_list(data: web::Data<SharedDataStore>) -> Result<TransactionListResponse> {
-    let datastore = data.ds.read().ok().context(error::DataStoreLock)?;
+/// Get the settings pending in the given transaction, or the "default" transaction if unspecified.
+/// If nothing is pending, the settings are empty.
+async fn get_pending_settings<D: DataStore>(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<SettingsResponse> {
+    let transaction = transaction_name(&query);
+    let datastore = data.read()?;
+    let settings = controller::get_transaction(&*datastore, transaction)?;
+    Ok(SettingsResponse(settings))
+}
+
+/// Discard the settings pending in the given transaction, or the "default" transaction if
+/// unspecified.  Returns the keys that were discarded, which is empty if nothing was pending.
+async fn discard_pending_settings<D: DataStore>(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<ChangedKeysResponse> {
+    let transaction = transaction_name(&query);
+    let mut datastore = data.write()?;
+    let discarded = controller::delete_transaction(&mut *datastore, transaction)?;
+    Ok(ChangedKeysResponse(discarded))
+}
+
+/// Get each setting pending in the given transaction, or the "default" transaction if
+/// unspecified, with its live ("old") and pending ("new") values.
+async fn get_pending_settings_diff<D: DataStore>(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<PendingChangesResponse> {
+    let transaction = transaction_name(&query);
+    let datastore = data.read()?;
+    let changes = controller::get_pending_changes(&*datastore, transaction)?;
+    Ok(PendingChangesResponse(changes))
+}
+
+/// Lists the saved snapshots of live settings, oldest first.
+async fn get_snapshots<D: DataStore>(
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<SnapshotListResponse> {
+    let datastore = data.read()?;
+    let snapshots = controller::list_snapshots(&*datastore)?;
+    Ok(SnapshotListResponse(snapshots))
+}
+
+/// Saves a snapshot of the live settings with the name given in 'name'.  Only the most recent
+/// snapshots are kept, so this may remove the oldest.
+async fn create_snapshot<D: DataStore>(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<SnapshotResponse> {
+    let name = query
+        .get("name")
+        .context(error::MissingInput { input: "name" })?;
+    let mut datastore = data.write()?;
+    let snapshot = controller::create_snapshot(
+        &mut *datastore,
+        name,
+        controller::DEFAULT_SNAPSHOT_RETENTION,
+    )?;
+    Ok(SnapshotResponse(snapshot))
+}
+
+/// Removes the snapshot given in 'name'.
+async fn delete_snapshot<D: DataStore>(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<HttpResponse> {
+    let name = query
+        .get("name")
+        .context(error::MissingInput { input: "name" })?;
+    let mut datastore = data.write()?;
+    controller::delete_snapshot(&mut *datastore, name)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Stages the settings from the snapshot given in 'name' in the given transaction, or the
+/// "default" transaction if unspecified, so committing it returns live settings to the snapshot.
+/// Returns the keys that were staged.
+async fn restore_snapshot<D: DataStore>(
+    req: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<ChangedKeysResponse> {
+    let name = query
+        .get("name")
+        .context(error::MissingInput { input: "name" })?;
+    let transaction = transaction_name(&query);
+    let mut datastore = data.write()?;
+    let staged = controller::restore_snapshot(&mut *datastore, name, transaction)?;
+    record_keys(&req, 0, staged.len());
+    Ok(ChangedKeysResponse(staged))
+}
+
+async fn get_transaction_list<D: DataStore>(
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<TransactionListResponse> {
+    let datastore = data.read()?;
     let data = controller::list_transactions(&*datastore)?;
     Ok(TransactionListResponse(data))
 }
 
 /// Get any pending settings in the given transaction, or the "default" transaction if unspecified.
-async fn get_transaction(
+async fn get_transaction<D: DataStore>(
     query: web::Query<HashMap<String, String>>,
-    data: web::Data<SharedDataStore>,
+    data: web::Data<SharedDataStore<D>>,
 ) -> Result<SettingsResponse> {
     let transaction = transaction_name(&query);
-    let datastore = data.ds.read().ok().context(error::DataStoreLock)?;
+    let datastore = data.read()?;
     let data = controller::get_transaction(&*datastore, transaction)?;
     Ok(SettingsResponse(data))
 }
 
 /// Delete the given transaction, or the "default" transaction if unspecified.
-async fn delete_transaction(
+async fn delete_transaction<D: DataStore>(
     query: web::Query<HashMap<String, String>>,
-    data: web::Data<SharedDataStore>,
+    data: web::Data<SharedDataStore<D>>,
 ) -> Result<ChangedKeysResponse> {
     let transaction = transaction_name(&query);
-    let mut datastore = data.ds.write().ok().context(error::DataStoreLock)?;
+    let mut datastore = data.write()?;
     let deleted = controller::delete_transaction(&mut *datastore, transaction)?;
     Ok(ChangedKeysResponse(deleted))
 }
 
 /// Save settings changes from the given transaction, or the "default" transaction if unspecified,
-/// to the live data store.  Returns the list of changed keys.
-async fn commit_transaction(
+/// to the live data store.  If 'keys' is specified, only those pending keys are saved, and the rest
+/// stay pending.  Returns the list of keys whose values changed; keys committed with the value
+/// they already had aren't included.
+///
+/// If 'dry-run' is true, nothing is saved; instead, returns a report of the changes the commit
+/// would make and the services and configuration files they'd affect.
+///
+/// If the request has an If-Match header, the commit only happens if it lists the ETag of the
+/// live settings, meaning they haven't changed since the client read them.
+async fn commit_transaction<D: DataStore>(
+    req: HttpRequest,
     query: web::Query<HashMap<String, String>>,
-    data: web::Data<SharedDataStore>,
-) -> Result<ChangedKeysResponse> {
+    data: web::Data<SharedDataStore<D>>,
+    audit_log: web::Data<Option<AuditLog>>,
+    events: web::Data<CommitEvents>,
+) -> Result<Either<ChangedKeysResponse, DryRunResponse>> {
     let transaction = transaction_name(&query);
-    let mut datastore = data.ds.write().ok().context(error::DataStoreLock)?;
 
-    let changes = controller::commit_transaction(&mut *datastore, transaction)?;
+    if bool_param(&query, "dry-run")? {
+        ensure!(!query.contains_key("keys"), error::DryRunWithKeys);
+        let datastore = data.read()?;
+        let report = controller::dry_run_commit(&*datastore, transaction)?;
+        return Ok(Either::B(DryRunResponse(report)));
+    }
+
+    let mut datastore = data.write()?;
+    check_if_match(&req, &*datastore)?;
+
+    let changes = if let Some(keys_str) = query.get("keys") {
+        let keys = comma_separated("keys", keys_str)?;
+        controller::commit_transaction_keys(&mut *datastore, transaction, Some(&keys))?
+    } else {
+        controller::commit_transaction(&mut *datastore, transaction)?
+    };
 
     if changes.is_empty() {
         return error::CommitWithNoPending.fail();
     }
+    record_keys(&req, 0, changes.len());
+    write_audit_log(&audit_log, &changes.audit);
+    publish_commit(&events, &*datastore, &changes);
 
-    Ok(ChangedKeysResponse(changes))
+    Ok(Either::A(ChangedKeysResponse(changes.changed)))
 }
 
 /// Starts settings appliers for any changes that have been committed to the data store.  This
-/// updates config files, runs restart commands, etc.
-async fn apply_changes(query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
-    if let Some(keys_str) = query.get("keys") {
-        let keys = comma_separated("keys", keys_str)?;
-        controller::apply_changes(Some(&keys))?;
-    } else {
-        controller::apply_changes(None as Option<&HashSet<&str>>)?;
-    }
+/// updates config files, runs restart commands, etc.  If the "wait" parameter is true, waits for
+/// the appliers to finish and returns an error if they fail.
+async fn apply_changes<D: DataStore>(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+    applier: web::Data<ApplierConfig>,
+) -> Result<HttpResponse> {
+    let mode = apply_mode(&query)?;
+    let run = {
+        let datastore = data.read()?;
+        if let Some(keys_str) = query.get("keys") {
+            let keys = comma_separated("keys", keys_str)?;
+            controller::prepare_applier(&*datastore, &applier, Some(&keys))?
+        } else {
+            controller::prepare_applier(&*datastore, &applier, None as Option<&HashSet<&str>>)?
+        }
+    };
+    // The lock is released first, because the applier calls back into the API
+    run_applier(run, mode).await?;
 
     Ok(HttpResponse::NoContent().json(()))
 }
 
+/// Runs the config applier on the blocking thread pool, so waiting for it doesn't hold up other
+/// requests, including the ones the applier makes to read settings.
+async fn run_applier(run: ApplierRun, mode: ApplyMode) -> Result<()> {
+    web::block(move || run.run(mode))
+        .await
+        .map_err(|e| match e {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => error::ConfigApplierCanceled.into_error(NoSource),
+        })
+}
+
+/// Commits the given transaction, or only the given keys from it, using the data store lock held
+/// in `datastore`, then releases the lock while waiting up to `timeout` for the config applier,
+/// because the applier calls back into the API.  The lock is taken again to finish the commit,
+/// rolling it back if the applier failed, and is returned for the rest of the request.
+async fn commit_and_apply<'a, D: DataStore>(
+    data: &'a SharedDataStore<D>,
+    mut datastore: sync::RwLockWriteGuard<'a, D>,
+    transaction: &str,
+    keys: Option<&HashSet<&str>>,
+    applier: &ApplierConfig,
+    timeout: Duration,
+) -> Result<(CommitAndApply, sync::RwLockWriteGuard<'a, D>)> {
+    let mut pending = controller::commit_for_apply(&mut *datastore, transaction, keys, applier)?;
+    drop(datastore);
+
+    let apply_result = match pending.take_run() {
+        Ok(Some(run)) => run_applier(run, ApplyMode::Wait { timeout }).await,
+        other => other.map(|_| ()),
+    };
+
+    let mut datastore = data.write()?;
+    let result = controller::finish_apply(&mut *datastore, pending, apply_result);
+    Ok((result, datastore))
+}
+
 /// Usually you want to apply settings changes you've committed, so this is a convenience method to
 /// perform both a commit and an apply.  Commits the given transaction, or the "default"
-/// transaction if unspecified.
-async fn commit_transaction_and_apply(
+/// transaction if unspecified.  If the "wait" parameter is true, waits for the appliers to finish,
+/// and if they fail, rolls back the commit and returns an error.  An If-Match header is checked
+/// as for commit_transaction.
+async fn commit_transaction_and_apply<D: DataStore>(
+    req: HttpRequest,
     query: web::Query<HashMap<String, String>>,
-    data: web::Data<SharedDataStore>,
+    data: web::Data<SharedDataStore<D>>,
+    applier: web::Data<ApplierConfig>,
+    audit_log: web::Data<Option<AuditLog>>,
+    events: web::Data<CommitEvents>,
 ) -> Result<ChangedKeysResponse> {
     let transaction = transaction_name(&query);
-    let mut datastore = data.ds.write().ok().context(error::DataStoreLock)?;
+    let mode = apply_mode(&query)?;
+    let mut datastore = data.write()?;
+    check_if_match(&req, &*datastore)?;
+
+    if let ApplyMode::Wait { timeout } = mode {
+        let (result, datastore) =
+            commit_and_apply(&data, datastore, transaction, None, &applier, timeout).await?;
+        if result.committed.is_empty() {
+            return error::CommitWithNoPending.fail();
+        }
+        record_keys(&req, 0, result.committed.len());
+        write_audit_log(&audit_log, &result.committed.audit);
+        return match result.outcome {
+            ApplyOutcome::Applied => {
+                publish_commit(&events, &*datastore, &result.committed);
+                Ok(ChangedKeysResponse(result.committed.changed))
+            }
+            ApplyOutcome::RolledBack { apply_error } => error::ApplyRolledBack {
+                apply_error: apply_error.to_string(),
+            }
+            .fail(),
+            ApplyOutcome::RollbackFailed {
+                apply_error,
+                rollback_error,
+            } => error::RollbackFailed {
+                apply_error: apply_error.to_string(),
+                rollback_error: rollback_error.to_string(),
+            }
+            .fail(),
+        };
+    }
 
     let changes = controller::commit_transaction(&mut *datastore, transaction)?;
 
     if changes.is_empty() {
         return error::CommitWithNoPending.fail();
     }
+    record_keys(&req, 0, changes.len());
+    write_audit_log(&audit_log, &changes.audit);
+
+    // Committing values that were already live doesn't require any changes to the system
+    let applied = if changes.changed.is_empty() {
+        Ok(())
+    } else {
+        let key_names = changes.changed.iter().map(|k| k.name()).collect();
+        controller::apply_changes(&*datastore, &applier, Some(&key_names), mode)
+    };
+    // The changes are live even if the applier couldn't be started, so subscribers hear of them
+    publish_commit(&events, &*datastore, &changes);
+    applied?;
 
-    let key_names = changes.iter().map(|k| k.name()).collect();
-    controller::apply_changes(Some(&key_names))?;
+    Ok(ChangedKeysResponse(changes.changed))
+}
+
+/// Streams an event for each commit that changes settings, as server-sent events, until the client
+/// disconnects.  Each has the new settings generation, the changed keys, and the services they
+/// affect.  See the events module for how slow clients and idle streams are handled.
+async fn get_settings_events(events: web::Data<CommitEvents>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .streaming(events.subscribe())
+}
+
+/// Returns the settings generation, which increases with each commit that changes live settings.
+/// Clients can compare it before and after reading settings to know they read a consistent set.
+async fn get_settings_generation<D: DataStore>(
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<GenerationResponse> {
+    let datastore = data.read()?;
+    let generation = controller::get_generation(&*datastore)?;
+    Ok(GenerationResponse(Generation { generation }))
+}
+
+/// Returns what the data store holds for the data key given in 'name', in the requested 'state',
+/// without deserializing it, so operators can see values that can't otherwise be read, like
+/// corrupt ones.  This is unstable and only for debugging; the auth module limits it to privileged
+/// callers.
+async fn get_raw_key<D: DataStore>(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<RawKeyResponse> {
+    let name = query
+        .get("name")
+        .context(error::MissingInput { input: "name" })?;
+    let committed = settings_state(&query)?;
+    let datastore = data.read()?;
+    Ok(RawKeyResponse(controller::get_raw_key(
+        &*datastore,
+        name,
+        &committed,
+        transaction_name(&query),
+    )?))
+}
+
+/// A setting's value and when it was last written, as returned by get_settings_mtimes.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ModifiedSetting {
+    value: Value,
+    /// When the setting was last written, in milliseconds since the Unix epoch.
+    modified_millis: u64,
+}
+
+/// Returns each populated setting in the requested 'state' with the time it was last written,
+/// keyed by setting name, for debugging when settings changed.  This is unstable and only for
+/// debugging; the auth module limits it to privileged callers.
+async fn get_settings_mtimes<D: DataStore>(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<ModifiedSettingsResponse> {
+    let committed = settings_state(&query)?;
+    let datastore = data.read()?;
+    let settings = controller::get_settings_with_mtimes(&*datastore, &committed)?
+        .into_iter()
+        .map(|(name, (value, mtime))| {
+            let modified_millis = mtime
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0);
+            (
+                name,
+                ModifiedSetting {
+                    value,
+                    modified_millis,
+                },
+            )
+        })
+        .collect();
+    Ok(ModifiedSettingsResponse(settings))
+}
+
+/// Returns all data and metadata in the requested 'state' as one JSON document, for debugging and
+/// backup; see controller::dump_all for the format.  This is unstable and only for debugging; the
+/// auth module limits it to privileged callers.
+async fn get_dump<D: DataStore>(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<DumpResponse> {
+    let committed = settings_state(&query)?;
+    let datastore = data.read()?;
+    Ok(DumpResponse(controller::dump_all(&*datastore, &committed)?))
+}
 
-    Ok(ChangedKeysResponse(changes))
+/// Loads a JSON document in the format returned by get_dump into the requested 'state'; see
+/// controller::load_dump.  Keys that are already populated are only replaced if 'overwrite' is
+/// true.  Loading into a transaction lets the caller review and commit the result as usual;
+/// loading into live doesn't run the config applier.  Responds with the data keys written.  This
+/// is unstable and only for debugging; the auth module limits it to privileged callers.
+async fn post_dump<D: DataStore>(
+    req: HttpRequest,
+    payload: web::Payload,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+    limits: web::Data<InputLimits>,
+) -> Result<ChangedKeysResponse> {
+    let body = read_body(&req, payload, limits.max_body_bytes).await?;
+    let dump: Value = serde_json::from_str(&body).map_err(|e| {
+        error::DumpFormat {
+            msg: format!("not JSON: {}", e),
+        }
+        .into_error(NoSource)
+    })?;
+    let committed = settings_state(&query)?;
+    let overwrite = bool_param(&query, "overwrite")?;
+    let mut datastore = data.write()?;
+    let written = controller::load_dump(&mut *datastore, dump, &committed, overwrite)?;
+    record_keys(&req, 0, written.len());
+    Ok(ChangedKeysResponse(written))
 }
 
 async fn get_os_info() -> Result<BottlerocketReleaseResponse> {
@@ -266,13 +1223,13 @@ async fn get_os_info() -> Result<BottlerocketReleaseResponse> {
 }
 
 /// Get the affected services for a list of data keys
-async fn get_affected_services(
+async fn get_affected_services<D: DataStore>(
     query: web::Query<HashMap<String, String>>,
-    data: web::Data<SharedDataStore>,
+    data: web::Data<SharedDataStore<D>>,
 ) -> Result<MetadataResponse> {
     if let Some(keys_str) = query.get("keys") {
         let data_keys = comma_separated("keys", keys_str)?;
-        let datastore = data.ds.read().ok().context(error::DataStoreLock)?;
+        let datastore = data.read()?;
         let resp =
             controller::get_metadata_for_data_keys(&*datastore, "affected-services", &data_keys)?;
 
@@ -282,21 +1239,40 @@ async fn get_affected_services(
     }
 }
 
+/// Get all metadata set on each of the data keys given in 'keys', as a map of data key to a map of
+/// metadata name to value.  Data keys without any metadata are left out of the response.
+async fn get_all_metadata<D: DataStore>(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<AllMetadataResponse> {
+    let keys_str = query
+        .get("keys")
+        .context(error::MissingInput { input: "keys" })?;
+    let data_keys = comma_separated("keys", keys_str)?;
+    let datastore = data.read()?;
+    Ok(AllMetadataResponse(controller::get_all_metadata_for_keys(
+        &*datastore,
+        &data_keys,
+    )?))
+}
+
 /// Get all settings that have setting-generator metadata
-async fn get_setting_generators(data: web::Data<SharedDataStore>) -> Result<MetadataResponse> {
-    let datastore = data.ds.read().ok().context(error::DataStoreLock)?;
+async fn get_setting_generators<D: DataStore>(
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<MetadataResponse> {
+    let datastore = data.read()?;
     let resp = controller::get_metadata_for_all_data_keys(&*datastore, "setting-generator")?;
     Ok(MetadataResponse(resp))
 }
 
 /// Get the template metadata for a list of data keys
-async fn get_templates(
+async fn get_templates<D: DataStore>(
     query: web::Query<HashMap<String, String>>,
-    data: web::Data<SharedDataStore>,
+    data: web::Data<SharedDataStore<D>>,
 ) -> Result<MetadataResponse> {
     if let Some(keys_str) = query.get("keys") {
         let data_keys = comma_separated("keys", keys_str)?;
-        let datastore = data.ds.read().ok().context(error::DataStoreLock)?;
+        let datastore = data.read()?;
         let resp = controller::get_metadata_for_data_keys(&*datastore, "template", &data_keys)?;
 
         Ok(MetadataResponse(resp))
@@ -305,12 +1281,66 @@ async fn get_templates(
     }
 }
 
+/// Get the value of the given metadata for the data keys given in 'keys', and for each populated
+/// data key starting with any of the given 'prefix' values, e.g. "settings.ntp.".  Data keys
+/// without the metadata are left out of the response, unless 'strict' is true and none of them
+/// have it, which is an error.
+async fn get_metadata<D: DataStore>(
+    md_key: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<MetadataResponse> {
+    let md_key = md_key.into_inner();
+    check_metadata_name(&md_key)?;
+    if !query.contains_key("keys") && !query.contains_key("prefix") {
+        return error::MissingInput { input: "keys" }.fail();
+    }
+
+    let datastore = data.read()?;
+    let mut resp = HashMap::new();
+    if let Some(keys_str) = query.get("keys") {
+        let data_keys = comma_separated("keys", keys_str)?;
+        resp.extend(controller::get_metadata_for_data_keys(
+            &*datastore,
+            &md_key,
+            &data_keys,
+        )?);
+    }
+    if let Some(prefix_str) = query.get("prefix") {
+        for prefix in comma_separated("prefix", prefix_str)? {
+            resp.extend(controller::get_metadata_for_prefix(
+                &*datastore,
+                &md_key,
+                prefix,
+            )?);
+        }
+    }
+
+    ensure!(
+        !resp.is_empty() || !bool_param(&query, "strict")?,
+        error::MissingMetadata { name: md_key }
+    );
+    Ok(MetadataResponse(resp))
+}
+
+/// Makes sure a metadata name from a request is one we're willing to look up.  Metadata names end
+/// up in paths in the data store, so we only accept simple names, even though keys may contain
+/// other characters, like '/'.
+fn check_metadata_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    ensure!(valid, error::InvalidMetadataName { name });
+    Ok(())
+}
+
 /// Get all services, or if 'names' is specified, services with those names
-async fn get_services(
+async fn get_services<D: DataStore>(
     query: web::Query<HashMap<String, String>>,
-    data: web::Data<SharedDataStore>,
+    data: web::Data<SharedDataStore<D>>,
 ) -> Result<ServicesResponse> {
-    let datastore = data.ds.read().ok().context(error::DataStoreLock)?;
+    let datastore = data.read()?;
 
     let resp = if let Some(names_str) = query.get("names") {
         let names = comma_separated("names", names_str)?;
@@ -323,11 +1353,11 @@ async fn get_services(
 }
 
 /// Get all configuration files, or if 'names' is specified, configuration files with those names
-async fn get_configuration_files(
+async fn get_configuration_files<D: DataStore>(
     query: web::Query<HashMap<String, String>>,
-    data: web::Data<SharedDataStore>,
+    data: web::Data<SharedDataStore<D>>,
 ) -> Result<ConfigurationFilesResponse> {
-    let datastore = data.ds.read().ok().context(error::DataStoreLock)?;
+    let datastore = data.read()?;
 
     let resp = if let Some(names_str) = query.get("names") {
         let names = comma_separated("names", names_str)?;
@@ -350,70 +1380,457 @@ fn comma_separated<'a>(key_name: &'static str, input: &'a str) -> Result<HashSet
     Ok(input.split(',').collect())
 }
 
-fn transaction_name(query: &web::Query<HashMap<String, String>>) -> &str {
-    if let Some(name_str) = query.get("tx") {
-        name_str
-    } else {
-        "default"
+/// Returns the value of an optional boolean query parameter, defaulting to false.
+fn bool_param(query: &web::Query<HashMap<String, String>>, name: &'static str) -> Result<bool> {
+    match query.get(name) {
+        Some(value_str) => value_str.parse().ok().context(error::InvalidBool {
+            input: name,
+            given: value_str.as_str(),
+        }),
+        None => Ok(false),
     }
 }
 
-// Can also override `render_response` if we want to change headers, content type, etc.
-impl ResponseError for error::Error {
-    /// Maps our error types to the HTTP error code they should return.
-    fn error_response(&self) -> HttpResponse {
-        use error::Error::*;
-        match self {
-            // 400 Bad Request
-            MissingInput { .. } => HttpResponse::BadRequest(),
-            EmptyInput { .. } => HttpResponse::BadRequest(),
-            NewKey { .. } => HttpResponse::BadRequest(),
-
-            // 404 Not Found
-            MissingData { .. } => HttpResponse::NotFound(),
-            ListKeys { .. } => HttpResponse::NotFound(),
-
-            // 422 Unprocessable Entity
-            CommitWithNoPending => HttpResponse::UnprocessableEntity(),
+/// Tells subscribers to the settings event stream about committed changes.  Commits that didn't
+/// change any values aren't published, since there's nothing to react to.  The changes are live
+/// and applied by now, so if we can't find the affected services, no event is sent, and the
+/// failure is logged and returned as a warning for callers that report on the commit.
+fn publish_commit<D: DataStore>(
+    events: &CommitEvents,
+    datastore: &D,
+    changes: &CommittedKeys,
+) -> Option<String> {
+    if changes.changed.is_empty() {
+        return None;
+    }
+    match controller::get_affected_services(datastore, &changes.changed) {
+        Ok(services) => {
+            events.publish(CommitEvent::new(
+                changes.generation,
+                &changes.changed,
+                services,
+            ));
+            None
+        }
+        Err(e) => {
+            error!("Unable to publish commit {}: {}", changes.generation, e);
+            Some(format!("Unable to publish commit: {}", e))
+        }
+    }
+}
 
-            // 500 Internal Server Error
-            DataStoreLock => HttpResponse::InternalServerError(),
-            ResponseSerialization { .. } => HttpResponse::InternalServerError(),
-            BindSocket { .. } => HttpResponse::InternalServerError(),
-            ServerStart { .. } => HttpResponse::InternalServerError(),
-            ListedKeyNotPresent { .. } => HttpResponse::InternalServerError(),
-            DataStore { .. } => HttpResponse::InternalServerError(),
-            Deserialization { .. } => HttpResponse::InternalServerError(),
-            DataStoreSerialization { .. } => HttpResponse::InternalServerError(),
-            CommandSerialization { .. } => HttpResponse::InternalServerError(),
-            InvalidMetadata { .. } => HttpResponse::InternalServerError(),
-            ConfigApplierStart { .. } => HttpResponse::InternalServerError(),
-            ConfigApplierStdin {} => HttpResponse::InternalServerError(),
-            ConfigApplierWrite { .. } => HttpResponse::InternalServerError(),
-            SystemdNotify { .. } => HttpResponse::InternalServerError(),
-            SystemdNotifyStatus {} => HttpResponse::InternalServerError(),
-            SetPermissions { .. } => HttpResponse::InternalServerError(),
-            SetGroup { .. } => HttpResponse::InternalServerError(),
-            ReleaseData { .. } => HttpResponse::InternalServerError(),
+/// Records committed settings changes in the audit log, if one is configured.  By this point the
+/// changes are live, so a failure can't undo the commit, and mustn't stop the changes from being
+/// published and applied; it's logged instead, and returned as a warning for callers that report
+/// on the commit.
+fn write_audit_log(audit_log: &Option<AuditLog>, entries: &[AuditEntry]) -> Option<String> {
+    let audit_log = audit_log.as_ref()?;
+    match audit_log.append(entries) {
+        Ok(()) => None,
+        Err(e) => {
+            error!("{}", e);
+            Some(e.to_string())
         }
-        .finish()
     }
 }
 
-struct SharedDataStore {
-    ds: sync::RwLock<FilesystemDataStore>,
+/// Returns whether the given conditional request header, If-Match or If-None-Match, lists the
+/// given ETag, or "*" to match any.  Returns false if the header isn't given.  If-None-Match uses
+/// weak comparison, so a client's weak "W/" version of the ETag matches too.
+fn etag_requested(req: &HttpRequest, name: header::HeaderName, etag: &str) -> Result<bool> {
+    let value = match req.headers().get(&name) {
+        Some(value) => value,
+        None => return Ok(false),
+    };
+    let value = value.to_str().ok().context(error::InvalidHeader {
+        name: name.as_str(),
+    })?;
+    let weak = name == header::IF_NONE_MATCH;
+    Ok(value.split(',').map(str::trim).any(|given| {
+        given == "*" || given == etag || (weak && given.starts_with("W/") && &given[2..] == etag)
+    }))
 }
 
-/// Helper macro for implementing the actix-web Responder trait for a type.
-/// $for: the type for which we implement Responder.
-/// $self: just pass "self"  (macro hygiene requires this)
-/// $serialize_expr: the thing to serialize for a response; this is just "self" again if $for
-///    implements Serialize, or is "self.0" for a newtype over something implementing Serialize
-macro_rules! impl_responder_for {
-    ($for:ident, $self:ident, $serialize_expr:expr) => (
-        impl Responder for $for {
-            type Error = error::Error;
-            type Future = future::Ready<Result<HttpResponse>>;
+/// Checks the request's If-Match header, if it has one, against the ETag of the live settings,
+/// so a client can make changes only if the settings haven't changed since it read them.
+fn check_if_match<D: DataStore>(req: &HttpRequest, datastore: &D) -> Result<()> {
+    if req.headers().contains_key(header::IF_MATCH) {
+        let etag = controller::get_settings_etag(datastore)?;
+        ensure!(
+            etag_requested(req, header::IF_MATCH, &etag)?,
+            error::PreconditionFailed { etag }
+        );
+    }
+    Ok(())
+}
+
+/// Builds a 304 Not Modified response for a conditional GET whose ETag matched.
+fn not_modified(etag: &str) -> HttpResponse {
+    HttpResponse::NotModified()
+        .header(header::ETAG, etag)
+        .finish()
+}
+
+/// Determines whether a request wants to wait for settings appliers to finish, based on the
+/// "wait" query parameter.
+fn apply_mode(query: &web::Query<HashMap<String, String>>) -> Result<ApplyMode> {
+    if bool_param(query, "wait")? {
+        Ok(ApplyMode::Wait {
+            timeout: APPLY_TIMEOUT,
+        })
+    } else {
+        Ok(ApplyMode::Background)
+    }
+}
+
+fn transaction_name(query: &web::Query<HashMap<String, String>>) -> &str {
+    if let Some(name_str) = query.get("tx") {
+        name_str
+    } else {
+        "default"
+    }
+}
+
+/// Returns the value of the single setting given in 'name', e.g. "settings.motd", in the requested
+/// 'state', without building the rest of the settings.  Responds 404 if the setting isn't set, and
+/// 400 if 'name' has settings under it, like "settings.ntp"; use GET /settings with 'prefix' for
+/// those.
+async fn get_setting_value<D: DataStore>(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<SettingValueResponse> {
+    let name = query
+        .get("name")
+        .context(error::MissingInput { input: "name" })?;
+    let committed = settings_state(&query)?;
+    let datastore = data.read()?;
+    let value =
+        controller::get_setting(&*datastore, name, &committed)?.context(error::MissingData {
+            prefix: name.as_str(),
+        })?;
+    Ok(SettingValueResponse(value))
+}
+
+/// Returns the names of populated settings under the optional 'prefix', e.g. "settings.ntp", in
+/// the requested 'state'.  With 'depth', names are cut off after that many segments under
+/// "settings", so a depth of 1 lists the top-level groups.
+async fn get_settings_keys<D: DataStore>(
+    query: web::Query<HashMap<String, String>>,
+    data: web::Data<SharedDataStore<D>>,
+) -> Result<SettingsKeysResponse> {
+    let prefix = query.get("prefix").map(String::as_str);
+    let depth = match query.get("depth") {
+        Some(depth_str) => Some(depth_str.parse().ok().context(error::InvalidNumber {
+            input: "depth",
+            given: depth_str.as_str(),
+        })?),
+        None => None,
+    };
+    let committed = settings_state(&query)?;
+    let datastore = data.read()?;
+    let keys = controller::list_settings_keys(&*datastore, prefix, depth, &committed)?;
+    Ok(SettingsKeysResponse(keys))
+}
+
+/// The body of every error response.  `code` is a stable identifier for the kind of error, so
+/// clients can act on it without parsing `message`, which is meant for people and may change.
+/// `details` holds structured data about the error when there's any a client could use.
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    details: Option<Value>,
+}
+
+impl error::Error {
+    /// Maps our error types to the HTTP status they should return and their stable error code.
+    /// Codes are part of the API; don't change existing ones, even if a variant is renamed.
+    fn status_and_code(&self) -> (StatusCode, &'static str) {
+        use error::Error::*;
+        match self {
+            // 400 Bad Request
+            MissingInput { .. } => (StatusCode::BAD_REQUEST, "MISSING_INPUT"),
+            EmptyInput { .. } => (StatusCode::BAD_REQUEST, "EMPTY_INPUT"),
+            MissingKeys { .. } => (StatusCode::BAD_REQUEST, "KEYS_NOT_POPULATED"),
+            DeleteNonSettings { .. } => (StatusCode::BAD_REQUEST, "DELETE_NON_SETTINGS"),
+            DryRunWithKeys => (StatusCode::BAD_REQUEST, "DRY_RUN_WITH_KEYS"),
+            LenientWithKeys => (StatusCode::BAD_REQUEST, "LENIENT_WITH_KEYS"),
+            NonSettingsKey { .. } => (StatusCode::BAD_REQUEST, "NOT_A_SETTING"),
+            SettingIsPrefix { .. } => (StatusCode::BAD_REQUEST, "SETTING_IS_PREFIX"),
+            SettingsJson { .. } => (StatusCode::BAD_REQUEST, "INVALID_SETTINGS_JSON"),
+            SettingsToml { .. } => (StatusCode::BAD_REQUEST, "INVALID_SETTINGS_TOML"),
+            UnknownSettings { .. } => (StatusCode::BAD_REQUEST, "UNKNOWN_SETTINGS"),
+            InvalidSetting { .. } => (StatusCode::BAD_REQUEST, "INVALID_SETTING"),
+            InvalidBool { .. } => (StatusCode::BAD_REQUEST, "INVALID_BOOL"),
+            InvalidNumber { .. } => (StatusCode::BAD_REQUEST, "INVALID_NUMBER"),
+            InvalidState { .. } => (StatusCode::BAD_REQUEST, "INVALID_STATE"),
+            UnknownFieldPaths { .. } => (StatusCode::BAD_REQUEST, "UNKNOWN_FIELDS"),
+            InvalidMetadataName { .. } => (StatusCode::BAD_REQUEST, "INVALID_METADATA_NAME"),
+            NewKey { .. } => (StatusCode::BAD_REQUEST, "INVALID_KEY"),
+            DumpFormat { .. } => (StatusCode::BAD_REQUEST, "INVALID_DUMP"),
+            DumpDeserialization { .. } => (StatusCode::BAD_REQUEST, "INVALID_DUMP"),
+            InvalidHeader { .. } => (StatusCode::BAD_REQUEST, "INVALID_HEADER"),
+            BodyRead { .. } => (StatusCode::BAD_REQUEST, "INVALID_BODY"),
+            BodyNotUtf8 { .. } => (StatusCode::BAD_REQUEST, "INVALID_BODY"),
+
+            // 403 Forbidden
+            ImmutableKeys { .. } => (StatusCode::FORBIDDEN, "IMMUTABLE_KEYS"),
+            Forbidden { .. } => (StatusCode::FORBIDDEN, "FORBIDDEN"),
+
+            // 404 Not Found
+            MissingData { .. } => (StatusCode::NOT_FOUND, "SETTINGS_NOT_FOUND"),
+            ListKeys { .. } => (StatusCode::NOT_FOUND, "KEYS_NOT_FOUND"),
+            SnapshotNotFound { .. } => (StatusCode::NOT_FOUND, "SNAPSHOT_NOT_FOUND"),
+            MissingMetadata { .. } => (StatusCode::NOT_FOUND, "METADATA_NOT_FOUND"),
+
+            // 412 Precondition Failed
+            PreconditionFailed { .. } => (StatusCode::PRECONDITION_FAILED, "ETAG_MISMATCH"),
+
+            // 413 Payload Too Large
+            BodyTooLarge { .. } => (StatusCode::PAYLOAD_TOO_LARGE, "BODY_TOO_LARGE"),
+            TooManyKeys { .. } => (StatusCode::PAYLOAD_TOO_LARGE, "TOO_MANY_KEYS"),
+
+            // 422 Unprocessable Entity
+            CommitWithNoPending => (StatusCode::UNPROCESSABLE_ENTITY, "NO_PENDING_CHANGES"),
+            KeysNotPending { .. } => (StatusCode::UNPROCESSABLE_ENTITY, "KEYS_NOT_PENDING"),
+            DumpConflicts { .. } => (StatusCode::UNPROCESSABLE_ENTITY, "DUMP_CONFLICTS"),
+            SnapshotExists { .. } => (StatusCode::UNPROCESSABLE_ENTITY, "SNAPSHOT_EXISTS"),
+
+            // 500 Internal Server Error
+            DataStoreLock => (StatusCode::INTERNAL_SERVER_ERROR, "DATASTORE_LOCK_POISONED"),
+            ResponseSerialization { .. } => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "RESPONSE_SERIALIZATION")
+            }
+            ListedKeyNotPresent { .. } => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "DATASTORE_INCONSISTENT")
+            }
+            DataStore { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "DATASTORE_ERROR"),
+            Deserialization { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "DATASTORE_CORRUPT"),
+            InvalidData { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "DATASTORE_CORRUPT"),
+            DataStoreSerialization { .. } => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "DATASTORE_SERIALIZATION")
+            }
+            InvalidMetadata { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "METADATA_CORRUPT"),
+            InvalidPattern { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "METADATA_CORRUPT"),
+            DumpConflict { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "DUMP_CONFLICT"),
+            RestartCycle { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "RESTART_CYCLE"),
+            InterruptedCommit { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "INTERRUPTED_COMMIT"),
+            CommandSerialization { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "APPLIER_ERROR"),
+            ConfigApplierStart { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "APPLIER_ERROR"),
+            ConfigApplierStdin {} => (StatusCode::INTERNAL_SERVER_ERROR, "APPLIER_ERROR"),
+            ConfigApplierWrite { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "APPLIER_ERROR"),
+            ConfigApplierWait { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "APPLIER_ERROR"),
+            ConfigApplierTimeout { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "APPLIER_TIMEOUT"),
+            ConfigApplierFailed { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "APPLIER_FAILED"),
+            ConfigApplierCanceled {} => (StatusCode::INTERNAL_SERVER_ERROR, "APPLIER_ERROR"),
+            ApplyRolledBack { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "APPLY_ROLLED_BACK"),
+            RollbackFailed { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "ROLLBACK_FAILED"),
+            RollbackConflict { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "ROLLBACK_FAILED"),
+            AuditLogWrite { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "AUDIT_LOG_WRITE"),
+            ReleaseData { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "OS_RELEASE_UNAVAILABLE"),
+            RoutesDocument { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "SCHEMA_UNAVAILABLE"),
+            // These happen while starting the server, before there are clients to see them.
+            BindSocket { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "SERVER_SETUP"),
+            BindAddress { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "SERVER_SETUP"),
+            SocketDirectory { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "SERVER_SETUP"),
+            SocketPathInUse { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "SERVER_SETUP"),
+            StaleSocket { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "SERVER_SETUP"),
+            ServerStart { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "SERVER_SETUP"),
+            SystemdNotify { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "SERVER_SETUP"),
+            SystemdNotifyStatus {} => (StatusCode::INTERNAL_SERVER_ERROR, "SERVER_SETUP"),
+            SetPermissions { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "SERVER_SETUP"),
+            SetGroup { .. } => (StatusCode::INTERNAL_SERVER_ERROR, "SERVER_SETUP"),
+
+            // 503 Service Unavailable
+            ShuttingDown => (StatusCode::SERVICE_UNAVAILABLE, "SHUTTING_DOWN"),
+        }
+    }
+
+    /// Returns structured data about the error for the `details` of the response, if there's
+    /// any that would help a client fix its request or report the problem.
+    fn details(&self) -> Option<Value> {
+        use error::Error::*;
+        let details = match self {
+            MissingKeys { keys }
+            | ImmutableKeys { keys }
+            | DumpConflicts { keys }
+            | RollbackConflict { keys } => json!({ "keys": keys }),
+            KeysNotPending { transaction, keys } => {
+                json!({ "transaction": transaction, "keys": keys })
+            }
+            UnknownSettings { format, settings } => {
+                json!({ "format": format, "settings": settings })
+            }
+            UnknownFieldPaths { fields } => json!({ "fields": fields }),
+            InvalidSetting { key, reason } => json!({ "key": key, "reason": reason }),
+            NewKey { key_type, name, .. } => json!({ "key-type": key_type, "name": name }),
+            MissingData { prefix } => json!({ "prefix": prefix }),
+            SnapshotExists { name } | SnapshotNotFound { name } => json!({ "name": name }),
+            InvalidMetadataName { name } | MissingMetadata { name } => json!({ "name": name }),
+            Forbidden { method, path } => json!({ "method": method, "path": path }),
+            PreconditionFailed { etag } => json!({ "etag": etag }),
+            BodyTooLarge { limit } => json!({ "limit": limit }),
+            TooManyKeys { count, limit } => json!({ "count": count, "limit": limit }),
+            InterruptedCommit { transaction } => json!({ "transaction": transaction }),
+            ConfigApplierTimeout { seconds } => json!({ "seconds": seconds }),
+            ConfigApplierFailed { status, stderr } => {
+                json!({ "status": status, "stderr": stderr })
+            }
+            RollbackFailed {
+                apply_error,
+                rollback_error,
+            } => json!({ "apply-error": apply_error, "rollback-error": rollback_error }),
+            _ => return None,
+        };
+        Some(details)
+    }
+
+    /// Builds the body of the error response.
+    fn error_body(&self) -> ErrorBody {
+        ErrorBody {
+            code: self.status_and_code().1,
+            message: self.to_string(),
+            details: self.details(),
+        }
+    }
+}
+
+// Can also override `render_response` if we want to change headers, content type, etc.
+impl ResponseError for error::Error {
+    fn status_code(&self) -> StatusCode {
+        self.status_and_code().0
+    }
+
+    /// Responds with our status for the error and a JSON ErrorBody describing it.
+    fn error_response(&self) -> HttpResponse {
+        let body = self.error_body();
+        match serde_json::to_string(&body) {
+            Ok(json) => HttpResponse::build(self.status_code())
+                .content_type("application/json")
+                .body(json),
+            // Not expected for our simple body, but the client should still get the status.
+            Err(e) => {
+                error!("Unable to serialize error response: {}", e);
+                HttpResponse::build(self.status_code()).body(body.message)
+            }
+        }
+    }
+}
+
+/// When the server started, for reporting uptime.
+struct StartTime(Instant);
+
+/// The liveness information returned by get_health.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Health {
+    version: &'static str,
+    uptime_seconds: u64,
+}
+
+/// The settings generation returned by get_settings_generation.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Generation {
+    generation: u64,
+}
+
+/// The report returned by apply_settings.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ApplyReport {
+    /// The keys staged from the request, including any staged for removal.
+    staged_keys: Vec<String>,
+    /// The keys committed, whether or not their live values changed.
+    committed_keys: Vec<String>,
+    /// The committed keys whose live values changed, which the config applier was run for.
+    changed_keys: Vec<String>,
+    /// The services affected by the changed keys.
+    affected_services: BTreeSet<String>,
+    apply: ApplyResult,
+    /// Problems after the commit that didn't stop it, like a failure to write the audit log.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+}
+
+/// How applying the committed settings went, in an ApplyReport.
+#[derive(Debug, Serialize)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+enum ApplyResult {
+    /// The config applier succeeded.
+    Applied,
+    /// No live values changed, so the config applier wasn't run.
+    Unchanged,
+    /// The config applier failed.  If the commit was rolled back, the changed keys have their
+    /// previous live values again; otherwise, live data may be partially restored.
+    Failed {
+        error: String,
+        /// The applier's exit status, if it ran to completion.
+        #[serde(rename = "exit-status")]
+        exit_status: Option<String>,
+        #[serde(rename = "rolled-back")]
+        rolled_back: bool,
+        #[serde(rename = "rollback-error", skip_serializing_if = "Option::is_none")]
+        rollback_error: Option<String>,
+    },
+}
+
+impl ApplyResult {
+    fn failed(apply_error: error::Error, rollback_error: Option<error::Error>) -> Self {
+        let exit_status = match &apply_error {
+            error::Error::ConfigApplierFailed { status, .. } => Some(status.clone()),
+            _ => None,
+        };
+        ApplyResult::Failed {
+            error: apply_error.to_string(),
+            exit_status,
+            rolled_back: rollback_error.is_none(),
+            rollback_error: rollback_error.map(|e| e.to_string()),
+        }
+    }
+}
+
+/// The data store, shared by all server workers, and the lock that keeps concurrent requests from
+/// seeing each other's partial changes.  Handlers that only read take shared access with `read`.
+/// Handlers that change anything take exclusive access with `write`, and hold it for the whole
+/// controller operation, including any checks before the change, so a commit can't capture half
+/// of a PATCH, and a reader never sees torn settings.  The DataStore implementations don't do
+/// any locking themselves.
+///
+/// Handlers shouldn't hold either guard across an `await`, so request bodies are read before
+/// taking the lock.
+struct SharedDataStore<D> {
+    ds: sync::RwLock<D>,
+}
+
+impl<D> SharedDataStore<D> {
+    fn new(datastore: D) -> Self {
+        Self {
+            ds: sync::RwLock::new(datastore),
+        }
+    }
+
+    /// Takes shared access to the data store, for reading.
+    fn read(&self) -> Result<sync::RwLockReadGuard<'_, D>> {
+        self.ds.read().ok().context(error::DataStoreLock)
+    }
+
+    /// Takes exclusive access to the data store, for changes.
+    fn write(&self) -> Result<sync::RwLockWriteGuard<'_, D>> {
+        self.ds.write().ok().context(error::DataStoreLock)
+    }
+}
+
+/// Helper macro for implementing the actix-web Responder trait for a type.
+/// $for: the type for which we implement Responder.
+/// $self: just pass "self"  (macro hygiene requires this)
+/// $serialize_expr: the thing to serialize for a response; this is just "self" again if $for
+///    implements Serialize, or is "self.0" for a newtype over something implementing Serialize
+macro_rules! impl_responder_for {
+    ($for:ident, $self:ident, $serialize_expr:expr) => (
+        impl Responder for $for {
+            type Error = error::Error;
+            type Future = future::Ready<Result<HttpResponse>>;
 
             fn respond_to($self, _req: &HttpRequest) -> Self::Future {
                 let body = match serde_json::to_string(&$serialize_expr) {
@@ -444,6 +1861,9 @@ impl_responder_for!(BottlerocketReleaseResponse, self, self.0);
 struct MetadataResponse(HashMap<String, Value>);
 impl_responder_for!(MetadataResponse, self, self.0);
 
+struct AllMetadataResponse(HashMap<String, HashMap<String, Value>>);
+impl_responder_for!(AllMetadataResponse, self, self.0);
+
 /// This lets us respond from our handler methods with a Services (or Result<Services>)
 struct ServicesResponse(Services);
 impl_responder_for!(ServicesResponse, self, self.0);
@@ -458,3 +1878,2334 @@ impl_responder_for!(ChangedKeysResponse, self, self.0);
 
 struct TransactionListResponse(HashSet<String>);
 impl_responder_for!(TransactionListResponse, self, self.0);
+
+struct DumpResponse(Value);
+impl_responder_for!(DumpResponse, self, self.0);
+
+struct ModifiedSettingsResponse(BTreeMap<String, ModifiedSetting>);
+impl_responder_for!(ModifiedSettingsResponse, self, self.0);
+
+struct DryRunResponse(DryRunReport);
+impl_responder_for!(DryRunResponse, self, self.0);
+
+struct PendingChangesResponse(BTreeMap<String, PendingChange>);
+impl_responder_for!(PendingChangesResponse, self, self.0);
+
+struct HealthResponse(Health);
+impl_responder_for!(HealthResponse, self, self.0);
+
+struct SchemaResponse(Value);
+impl_responder_for!(SchemaResponse, self, self.0);
+
+struct GenerationResponse(Generation);
+impl_responder_for!(GenerationResponse, self, self.0);
+
+struct SettingValueResponse(Value);
+impl_responder_for!(SettingValueResponse, self, self.0);
+
+struct SettingsKeysResponse(BTreeSet<String>);
+impl_responder_for!(SettingsKeysResponse, self, self.0);
+
+struct SnapshotResponse(controller::SnapshotInfo);
+impl_responder_for!(SnapshotResponse, self, self.0);
+
+struct SnapshotListResponse(Vec<controller::SnapshotInfo>);
+impl_responder_for!(SnapshotListResponse, self, self.0);
+
+struct ApplyReportResponse(ApplyReport);
+impl_responder_for!(ApplyReportResponse, self, self.0);
+
+struct RawKeyResponse(RawKey);
+impl_responder_for!(RawKeyResponse, self, self.0);
+
+#[cfg(test)]
+mod test {
+    use super::controller::test::ready_datastore;
+    use super::*;
+    use crate::datastore::memory::MemoryDataStore;
+    use crate::datastore::{self, KeyType};
+    use actix_web::dev::Body;
+    use actix_web::http::Method;
+    use actix_web::test::TestRequest;
+    use maplit::{btreemap, btreeset, hashset};
+    use serde_json::json;
+    use std::convert::TryInto;
+    use std::os::unix::process::ExitStatusExt;
+    use std::sync::{Arc, Barrier};
+    use std::time::SystemTime;
+
+    /// Returns a MemoryDataStore, shared the way handlers expect, with a live motd and some
+    /// settings pending in the default transaction.
+    fn pending_datastore() -> web::Data<SharedDataStore<MemoryDataStore>> {
+        let mut ds = MemoryDataStore::new();
+        let pending = Committed::Pending {
+            tx: "default".into(),
+        };
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let seed = Key::new(KeyType::Data, "settings.updates.seed").unwrap();
+        ds.set_key(&motd, "\"old\"", &Committed::Live).unwrap();
+        ds.set_key(&motd, "\"new\"", &pending).unwrap();
+        ds.set_key(&seed, "42", &pending).unwrap();
+        web::Data::new(SharedDataStore::new(ds))
+    }
+
+    fn query(query_str: &str) -> web::Query<HashMap<String, String>> {
+        web::Query::from_query(query_str).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn pending_settings_resource() {
+        let data = pending_datastore();
+
+        let SettingsResponse(settings) =
+            get_pending_settings(query(""), data.clone()).await.unwrap();
+        assert_eq!(
+            settings,
+            serde_json::from_value(json!({"motd": "new", "updates": {"seed": 42}})).unwrap()
+        );
+
+        let PendingChangesResponse(changes) = get_pending_settings_diff(query(""), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&changes).unwrap(),
+            json!({
+                "settings.motd": {"old": "old", "new": "new"},
+                "settings.updates.seed": {"old": null, "new": 42},
+            })
+        );
+
+        // Discarding returns the keys that were pending, and leaves nothing pending
+        let ChangedKeysResponse(discarded) = discard_pending_settings(query(""), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            discarded,
+            hashset!(
+                Key::new(KeyType::Data, "settings.motd").unwrap(),
+                Key::new(KeyType::Data, "settings.updates.seed").unwrap(),
+            )
+        );
+        let SettingsResponse(settings) =
+            get_pending_settings(query(""), data.clone()).await.unwrap();
+        assert_eq!(settings, Settings::default());
+        let PendingChangesResponse(changes) = get_pending_settings_diff(query(""), data.clone())
+            .await
+            .unwrap();
+        assert!(changes.is_empty());
+
+        // The live settings are untouched
+        let datastore = data.ds.read().unwrap();
+        let live = controller::get_settings(&*datastore, &Committed::Live).unwrap();
+        assert_eq!(live.motd, Some("old".try_into().unwrap()));
+    }
+
+    #[actix_rt::test]
+    async fn pending_settings_never_missing() {
+        let data = pending_datastore();
+        let request = TestRequest::default().to_http_request();
+
+        // A transaction with nothing pending is empty rather than missing
+        let settings = get_pending_settings(query("tx=other"), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(serde_json::to_string(&settings.0).unwrap(), "{}");
+        let response = settings.respond_to(&request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let changes = get_pending_settings_diff(query("tx=other"), data.clone())
+            .await
+            .unwrap();
+        assert!(changes.0.is_empty());
+        let response = changes.respond_to(&request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let discarded = discard_pending_settings(query("tx=other"), data.clone())
+            .await
+            .unwrap();
+        assert!(discarded.0.is_empty());
+        let response = discarded.respond_to(&request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// Returns a request payload with the given body.
+    fn payload(body: &str) -> web::Payload {
+        let (_, payload) = TestRequest::default()
+            .set_payload(body.to_string())
+            .to_http_parts();
+        web::Payload(payload)
+    }
+
+    /// Returns the settings in the body of the given response.
+    fn response_settings(response: &HttpResponse) -> Settings {
+        match response.body().as_ref() {
+            Some(Body::Bytes(bytes)) => serde_json::from_slice(bytes).unwrap(),
+            other => panic!("Unexpected response body: {:?}", other),
+        }
+    }
+
+    /// Returns the ETag header of the given response.
+    fn response_etag(response: &HttpResponse) -> String {
+        response
+            .headers()
+            .get(header::ETAG)
+            .expect("no ETag in response")
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[actix_rt::test]
+    async fn get_settings_not_modified() {
+        let data = pending_datastore();
+
+        let request = TestRequest::default().to_http_request();
+        let response = get_settings(request, query(""), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response_etag(&response);
+
+        // Clients that already have the current settings don't get them again
+        for if_none_match in &[
+            etag.clone(),
+            format!("\"other\", {}", etag),
+            format!("W/{}", etag),
+            "*".to_string(),
+        ] {
+            let request = TestRequest::default()
+                .header(header::IF_NONE_MATCH, if_none_match.as_str())
+                .to_http_request();
+            let response = get_settings(request, query(""), data.clone())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(response_etag(&response), etag);
+        }
+
+        // The ETag covers the live settings, so it applies to subsets of them too
+        let request = TestRequest::default()
+            .header(header::IF_NONE_MATCH, etag.as_str())
+            .to_http_request();
+        let response = get_settings(request, query("prefix=motd"), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // Clients with an old ETag get the settings
+        let request = TestRequest::default()
+            .header(header::IF_NONE_MATCH, "\"other\"")
+            .to_http_request();
+        let response = get_settings(request, query(""), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response_etag(&response), etag);
+    }
+
+    #[actix_rt::test]
+    async fn changes_require_matching_etag() {
+        let data = pending_datastore();
+        let audit_log = web::Data::new(None);
+        let events = web::Data::new(CommitEvents::default());
+        let etag = controller::get_settings_etag(&*data.ds.read().unwrap()).unwrap();
+        let if_match = |value: &str| {
+            TestRequest::default()
+                .header(header::IF_MATCH, value)
+                .to_http_request()
+        };
+        let body = r#"{"motd": "newer"}"#;
+        let limits = web::Data::new(InputLimits::default());
+
+        // A stale ETag means settings changed since the client read them, so nothing happens
+        match patch_settings(
+            if_match("\"stale\""),
+            payload(body),
+            query(""),
+            data.clone(),
+            limits.clone(),
+        )
+        .await
+        {
+            Err(e @ error::Error::PreconditionFailed { .. }) => {
+                assert_eq!(e.error_response().status(), StatusCode::PRECONDITION_FAILED);
+                assert!(e.to_string().contains(&etag), "{}", e);
+            }
+            Err(e) => panic!("Expected precondition failure, got {}", e),
+            Ok(_) => panic!("Settings were changed with a stale ETag"),
+        }
+        match commit_transaction(
+            if_match("\"stale\""),
+            query(""),
+            data.clone(),
+            audit_log.clone(),
+            events.clone(),
+        )
+        .await
+        {
+            Err(error::Error::PreconditionFailed { .. }) => {}
+            Err(e) => panic!("Expected precondition failure, got {}", e),
+            Ok(_) => panic!("Settings were committed with a stale ETag"),
+        }
+        let SettingsResponse(pending) =
+            get_pending_settings(query(""), data.clone()).await.unwrap();
+        assert_eq!(pending.motd, Some("new".try_into().unwrap()));
+
+        // The current ETag lets changes through, after which it's stale
+        let response = patch_settings(
+            if_match(&etag),
+            payload(body),
+            query(""),
+            data.clone(),
+            limits,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        if commit_transaction(
+            if_match(&etag),
+            query(""),
+            data.clone(),
+            audit_log.clone(),
+            events.clone(),
+        )
+        .await
+        .is_err()
+        {
+            panic!("Settings weren't committed with the current ETag");
+        }
+        let datastore = data.ds.read().unwrap();
+        let live = controller::get_settings(&*datastore, &Committed::Live).unwrap();
+        assert_eq!(live.motd, Some("newer".try_into().unwrap()));
+        assert_ne!(controller::get_settings_etag(&*datastore).unwrap(), etag);
+    }
+
+    #[actix_rt::test]
+    async fn commits_are_streamed_as_events() {
+        let data = pending_datastore();
+        {
+            let mut datastore = data.ds.write().unwrap();
+            let md_key = Key::new(KeyType::Meta, "affected-services").unwrap();
+            let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+            datastore
+                .set_metadata(&md_key, &motd, "[\"motd\"]", &Committed::Live)
+                .unwrap();
+        }
+        let audit_log = web::Data::new(None);
+        let events = web::Data::new(CommitEvents::default());
+
+        // The client connects before the commit and holds the stream open
+        let mut response = get_settings_events(events.clone()).await;
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/event-stream"
+        );
+        let mut body = response.take_body();
+
+        let request = TestRequest::default().to_http_request();
+        commit_transaction(request, query(""), data.clone(), audit_log, events)
+            .await
+            .unwrap();
+
+        let chunk = body.next().await.unwrap().unwrap();
+        let chunk = std::str::from_utf8(&chunk).unwrap();
+        let mut lines = chunk.lines();
+        assert_eq!(lines.next(), Some("id: 1"));
+        assert_eq!(lines.next(), Some("event: commit"));
+        let data_line = lines.next().unwrap();
+        assert!(data_line.starts_with("data: "), "{}", data_line);
+        let event: serde_json::Value = serde_json::from_str(&data_line[6..]).unwrap();
+        assert_eq!(
+            event,
+            json!({
+                "generation": 1,
+                "changed-keys": ["settings.motd", "settings.updates.seed"],
+                "affected-services": ["motd"],
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn commit_succeeds_when_publish_fails() {
+        let data = pending_datastore();
+        {
+            // Metadata we can't parse keeps us from finding the affected services
+            let mut datastore = data.ds.write().unwrap();
+            let md_key = Key::new(KeyType::Meta, "affected-services").unwrap();
+            let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+            datastore
+                .set_metadata(&md_key, &motd, "not a list", &Committed::Live)
+                .unwrap();
+        }
+
+        let request = TestRequest::default().to_http_request();
+        let response = commit_transaction(
+            request,
+            query(""),
+            data.clone(),
+            web::Data::new(None),
+            web::Data::new(CommitEvents::default()),
+        )
+        .await;
+        match response {
+            Ok(Either::A(ChangedKeysResponse(changed))) => assert_eq!(changed.len(), 2),
+            Ok(Either::B(_)) => panic!("Commit was a dry run"),
+            Err(e) => panic!("Commit failed: {}", e),
+        }
+        assert_eq!(
+            motd_and_seed(&data),
+            json!({"live": "new", "pending": null, "seed": null})
+        );
+    }
+
+    /// Posts the body to apply_settings, with a config applier that runs the given shell script,
+    /// and returns the report as JSON.
+    async fn post_apply(
+        data: &web::Data<SharedDataStore<MemoryDataStore>>,
+        body: &str,
+        script: &str,
+    ) -> Result<Value> {
+        let applier = ApplierConfig {
+            program: PathBuf::from("/bin/sh"),
+            args: vec!["-c".to_string(), script.to_string()],
+            keys_only: false,
+        };
+        let ApplyReportResponse(report) = apply_settings(
+            TestRequest::default().to_http_request(),
+            payload(body),
+            query(""),
+            data.clone(),
+            web::Data::new(InputLimits::default()),
+            web::Data::new(applier),
+            web::Data::new(None),
+            web::Data::new(CommitEvents::default()),
+        )
+        .await?;
+        Ok(serde_json::to_value(report).unwrap())
+    }
+
+    /// Returns the live and pending motd, and the pending seed.
+    fn motd_and_seed(data: &web::Data<SharedDataStore<MemoryDataStore>>) -> Value {
+        let datastore = data.ds.read().unwrap();
+        let live = controller::get_settings(&*datastore, &Committed::Live).unwrap();
+        let pending = controller::get_settings(
+            &*datastore,
+            &Committed::Pending {
+                tx: "default".into(),
+            },
+        )
+        .unwrap();
+        let pending = serde_json::to_value(pending).unwrap();
+        json!({
+            "live": live.motd,
+            "pending": pending["motd"],
+            "seed": pending["updates"]["seed"],
+        })
+    }
+
+    #[actix_rt::test]
+    async fn apply_settings_commits_request_keys() {
+        let data = pending_datastore();
+        {
+            let mut datastore = data.ds.write().unwrap();
+            let md_key = Key::new(KeyType::Meta, "affected-services").unwrap();
+            let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+            datastore
+                .set_metadata(&md_key, &motd, "[\"motd\"]", &Committed::Live)
+                .unwrap();
+        }
+
+        let report = post_apply(&data, r#"{"motd": "applied"}"#, "cat >/dev/null")
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            json!({
+                "staged-keys": ["settings.motd"],
+                "committed-keys": ["settings.motd"],
+                "changed-keys": ["settings.motd"],
+                "affected-services": ["motd"],
+                "apply": {"result": "applied"},
+            })
+        );
+        // Settings already pending in the transaction stay pending
+        assert_eq!(
+            motd_and_seed(&data),
+            json!({"live": "applied", "pending": null, "seed": 42})
+        );
+
+        // Setting the live value again changes nothing, so there's nothing to apply
+        let report = post_apply(&data, r#"{"motd": "applied"}"#, "exit 1")
+            .await
+            .unwrap();
+        assert_eq!(report["committed-keys"], json!(["settings.motd"]));
+        assert_eq!(report["changed-keys"], json!([]));
+        assert_eq!(report["apply"], json!({"result": "unchanged"}));
+    }
+
+    #[actix_rt::test]
+    async fn apply_settings_reports_rollback() {
+        let data = pending_datastore();
+
+        let report = post_apply(
+            &data,
+            r#"{"motd": "broken"}"#,
+            "cat >/dev/null; echo 'rendering failed' >&2; exit 3",
+        )
+        .await
+        .unwrap();
+        assert_eq!(report["changed-keys"], json!(["settings.motd"]));
+        let apply = &report["apply"];
+        assert_eq!(apply["result"], "failed");
+        // The status is as std displays it, which differs between Rust releases
+        let exit_3 = std::process::ExitStatus::from_raw(3 << 8);
+        assert_eq!(apply["exit-status"], exit_3.to_string());
+        assert_eq!(apply["rolled-back"], true);
+        assert!(apply.get("rollback-error").is_none(), "{}", apply);
+        assert!(
+            apply["error"]
+                .as_str()
+                .unwrap()
+                .contains("rendering failed"),
+            "{}",
+            apply
+        );
+
+        // The commit was undone
+        assert_eq!(
+            motd_and_seed(&data),
+            json!({"live": "old", "pending": null, "seed": 42})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn apply_settings_reports_warnings() {
+        let data = pending_datastore();
+        {
+            // Metadata we can't parse keeps us from finding the affected services
+            let mut datastore = data.ds.write().unwrap();
+            let md_key = Key::new(KeyType::Meta, "affected-services").unwrap();
+            let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+            datastore
+                .set_metadata(&md_key, &motd, "not a list", &Committed::Live)
+                .unwrap();
+        }
+        // An applier given only keys doesn't need the metadata
+        let applier = ApplierConfig {
+            program: PathBuf::from("/bin/sh"),
+            args: vec!["-c".to_string(), "cat >/dev/null".to_string()],
+            keys_only: true,
+        };
+        // A directory can't be opened for appending, so every audit write fails
+        let dir = tempfile::tempdir().unwrap();
+        let audit_log = AuditLog {
+            path: dir.path().to_path_buf(),
+        };
+
+        let ApplyReportResponse(report) = apply_settings(
+            TestRequest::default().to_http_request(),
+            payload(r#"{"motd": "applied"}"#),
+            query(""),
+            data.clone(),
+            web::Data::new(InputLimits::default()),
+            web::Data::new(applier),
+            web::Data::new(Some(audit_log)),
+            web::Data::new(CommitEvents::default()),
+        )
+        .await
+        .unwrap();
+        let report = serde_json::to_value(report).unwrap();
+        assert_eq!(report["apply"], json!({"result": "applied"}));
+        assert_eq!(report["affected-services"], json!([]));
+        let warnings = report["warnings"].as_array().unwrap();
+        assert_eq!(warnings.len(), 3, "{:?}", warnings);
+        assert!(warnings[0].as_str().unwrap().contains("audit"));
+        assert!(warnings[1].as_str().unwrap().contains("affected services"));
+        assert!(warnings[2].as_str().unwrap().contains("publish"));
+        assert_eq!(
+            motd_and_seed(&data),
+            json!({"live": "applied", "pending": null, "seed": 42})
+        );
+    }
+
+    /// Returns the ETag of the live settings, as clients see it.
+    async fn live_etag(data: &web::Data<SharedDataStore<MemoryDataStore>>) -> String {
+        let request = TestRequest::default().to_http_request();
+        let response = get_settings(request, query(""), data.clone())
+            .await
+            .unwrap();
+        response_etag(&response)
+    }
+
+    /// Returns an applier script that waits for the given marker file to exist.
+    fn waiting_script(marker: &Path) -> String {
+        format!(
+            "cat >/dev/null; while [ ! -e '{}' ]; do sleep 0.05; done",
+            marker.display()
+        )
+    }
+
+    /// Stands in for a config applier calling back into the API while it runs: reads the settings
+    /// until they no longer have the ETag `before`, meaning the commit is live, then writes the
+    /// marker that the `waiting_script` applier is waiting for.
+    async fn call_back(
+        data: &web::Data<SharedDataStore<MemoryDataStore>>,
+        before: &str,
+        marker: &Path,
+    ) {
+        while live_etag(data).await == before {
+            actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        }
+        fs::write(marker, "").unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn commit_and_apply_serves_requests_while_applying() {
+        let data = pending_datastore();
+        let before = live_etag(&data).await;
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("called-back");
+        let applier = ApplierConfig {
+            program: PathBuf::from("/bin/sh"),
+            args: vec!["-c".to_string(), waiting_script(&marker)],
+            keys_only: false,
+        };
+
+        // The applier only finishes once the settings have been read back through the API
+        let apply = commit_transaction_and_apply(
+            TestRequest::default().to_http_request(),
+            query("wait=true"),
+            data.clone(),
+            web::Data::new(applier),
+            web::Data::new(None),
+            web::Data::new(CommitEvents::default()),
+        );
+        let (response, ()) = future::join(apply, call_back(&data, &before, &marker)).await;
+        let ChangedKeysResponse(changed) = response.unwrap();
+        assert_eq!(changed.len(), 2);
+        assert_eq!(
+            motd_and_seed(&data),
+            json!({"live": "new", "pending": null, "seed": null})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn audit_failure_still_applies() {
+        let data = pending_datastore();
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("applied");
+        let applier = ApplierConfig {
+            program: PathBuf::from("/bin/sh"),
+            args: vec![
+                "-c".to_string(),
+                format!("cat >/dev/null; touch '{}'", marker.display()),
+            ],
+            keys_only: false,
+        };
+        // A directory can't be opened for appending, so every audit write fails
+        let audit_log = AuditLog {
+            path: dir.path().to_path_buf(),
+        };
+
+        let ChangedKeysResponse(changed) = commit_transaction_and_apply(
+            TestRequest::default().to_http_request(),
+            query(""),
+            data.clone(),
+            web::Data::new(applier),
+            web::Data::new(Some(audit_log)),
+            web::Data::new(CommitEvents::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(changed.len(), 2);
+
+        // The applier runs in the background, so give it time to start
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while !marker.exists() {
+            assert!(Instant::now() < deadline, "applier didn't run");
+            actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[actix_rt::test]
+    async fn apply_settings_serves_requests_while_applying() {
+        let data = pending_datastore();
+        let before = live_etag(&data).await;
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("called-back");
+
+        // The applier only finishes once the settings have been read back through the API
+        let script = waiting_script(&marker);
+        let apply = post_apply(&data, r#"{"motd": "applied"}"#, &script);
+        let (report, ()) = future::join(apply, call_back(&data, &before, &marker)).await;
+        assert_eq!(report.unwrap()["apply"], json!({"result": "applied"}));
+        assert_eq!(
+            motd_and_seed(&data),
+            json!({"live": "applied", "pending": null, "seed": 42})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn apply_settings_stages_nothing_when_invalid() {
+        let data = pending_datastore();
+
+        for body in &[
+            r#"{"motd": "ok", "nope": 1}"#,
+            r#"{"updates": {"seed": "x"}}"#,
+        ] {
+            match post_apply(&data, body, "exit 1").await {
+                Err(e) => assert_eq!(e.error_response().status(), StatusCode::BAD_REQUEST),
+                Ok(report) => panic!("Invalid settings were applied: {}", report),
+            }
+            assert_eq!(
+                motd_and_seed(&data),
+                json!({"live": "old", "pending": "new", "seed": 42})
+            );
+        }
+    }
+
+    #[test]
+    fn concurrent_requests_see_whole_changes() {
+        // Each writer sets motd and updates.seed to the same number in one PATCH, then commits,
+        // so if requests are properly serialized, readers always see them match.
+        const WRITERS: u32 = 4;
+        const READERS: u32 = 4;
+        const ROUNDS: u32 = 25;
+        // Readers get an error until there are settings, so start with a matching pair no writer
+        // uses
+        let mut ds = MemoryDataStore::new();
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let seed = Key::new(KeyType::Data, "settings.updates.seed").unwrap();
+        ds.set_key(&motd, "\"999999\"", &Committed::Live).unwrap();
+        ds.set_key(&seed, "999999", &Committed::Live).unwrap();
+        let data = web::Data::new(SharedDataStore::new(ds));
+        let audit_log = web::Data::new(None);
+        let events = web::Data::new(CommitEvents::default());
+        let limits = web::Data::new(InputLimits::default());
+
+        let mut threads = Vec::new();
+        for writer in 0..WRITERS {
+            let (data, audit_log, events, limits) = (
+                data.clone(),
+                audit_log.clone(),
+                events.clone(),
+                limits.clone(),
+            );
+            threads.push(std::thread::spawn(move || {
+                let tx = format!("tx=writer-{}", writer);
+                actix_rt::System::new("writer").block_on(async move {
+                    for round in 0..ROUNDS {
+                        let n = writer * 1000 + round;
+                        let body = format!(r#"{{"motd": "{}", "updates": {{"seed": {}}}}}"#, n, n);
+                        let request = TestRequest::default().to_http_request();
+                        patch_settings(
+                            request,
+                            payload(&body),
+                            query(&tx),
+                            data.clone(),
+                            limits.clone(),
+                        )
+                        .await
+                        .unwrap();
+                        let request = TestRequest::default().to_http_request();
+                        commit_transaction(
+                            request,
+                            query(&tx),
+                            data.clone(),
+                            audit_log.clone(),
+                            events.clone(),
+                        )
+                        .await
+                        .unwrap();
+                    }
+                })
+            }));
+        }
+        for _ in 0..READERS {
+            let data = data.clone();
+            threads.push(std::thread::spawn(move || {
+                actix_rt::System::new("reader").block_on(async move {
+                    for _ in 0..ROUNDS * WRITERS {
+                        let request = TestRequest::default().to_http_request();
+                        let response = get_settings(request, query(""), data.clone())
+                            .await
+                            .unwrap();
+                        let settings = response_settings(&response);
+                        let motd = settings.motd.map(|m| m.to_string());
+                        let seed = settings.updates.and_then(|u| u.seed);
+                        assert!(motd.is_some(), "No motd");
+                        assert_eq!(motd, seed.map(|s| s.to_string()), "Torn settings");
+                    }
+                })
+            }));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Every commit changed both keys
+        let datastore = data.ds.read().unwrap();
+        assert_eq!(
+            controller::get_generation(&*datastore).unwrap(),
+            u64::from(WRITERS * ROUNDS)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn generation_counts_commits() {
+        let data = pending_datastore();
+        let GenerationResponse(before) = get_settings_generation(data.clone()).await.unwrap();
+        assert_eq!(before.generation, 0);
+
+        let request = TestRequest::default().to_http_request();
+        commit_transaction(
+            request,
+            query(""),
+            data.clone(),
+            web::Data::new(None),
+            web::Data::new(CommitEvents::default()),
+        )
+        .await
+        .unwrap();
+        let GenerationResponse(after) = get_settings_generation(data).await.unwrap();
+        assert_eq!(after.generation, 1);
+    }
+
+    #[actix_rt::test]
+    async fn health_reports_version() {
+        let started = web::Data::new(StartTime(Instant::now()));
+        let HealthResponse(health) = get_health(started).await.unwrap();
+        assert_eq!(health.version, env!("CARGO_PKG_VERSION"));
+        assert!(health.uptime_seconds < 60);
+    }
+
+    #[actix_rt::test]
+    async fn ready_fails_on_corrupt_datastore() {
+        let data = web::Data::new(SharedDataStore::new(ready_datastore()));
+
+        let response = get_ready(data.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // A service whose restart commands can't be read makes the server not ready
+        let commands = Key::new(KeyType::Data, "services.motd.restart-commands").unwrap();
+        data.ds
+            .write()
+            .unwrap()
+            .set_key(&commands, "\"not a list\"", &Committed::Live)
+            .unwrap();
+        let response = get_ready(data.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let readiness = controller::check_readiness(&*data.ds.read().unwrap());
+        let failed: Vec<_> = readiness
+            .checks
+            .iter()
+            .filter(|check| !check.ok)
+            .map(|check| check.name)
+            .collect();
+        assert_eq!(failed, vec!["services"]);
+    }
+
+    #[test]
+    fn every_error_has_status_and_code() {
+        use crate::datastore::{self, deserialization, serialization};
+        use error::Error::*;
+        use std::io;
+
+        let io_error = || io::Error::new(io::ErrorKind::Other, "oops");
+        let json_error = || serde_json::from_str::<Value>("{").unwrap_err();
+        let datastore_error = || datastore::Error::PathTraversal { name: "..".into() };
+        let s = |s: &str| s.to_string();
+
+        let cases = vec![
+            (MissingInput { input: s("keys") }, 400, "MISSING_INPUT"),
+            (EmptyInput { input: s("keys") }, 400, "EMPTY_INPUT"),
+            (
+                MissingKeys {
+                    keys: s("settings.a"),
+                },
+                400,
+                "KEYS_NOT_POPULATED",
+            ),
+            (
+                DeleteNonSettings { name: s("os") },
+                400,
+                "DELETE_NON_SETTINGS",
+            ),
+            (DryRunWithKeys, 400, "DRY_RUN_WITH_KEYS"),
+            (LenientWithKeys, 400, "LENIENT_WITH_KEYS"),
+            (NonSettingsKey { name: s("os") }, 400, "NOT_A_SETTING"),
+            (
+                SettingIsPrefix { key: s("settings") },
+                400,
+                "SETTING_IS_PREFIX",
+            ),
+            (
+                SettingsJson {
+                    source: json_error(),
+                },
+                400,
+                "INVALID_SETTINGS_JSON",
+            ),
+            (
+                SettingsToml {
+                    source: toml::from_str::<toml::Value>("=").unwrap_err(),
+                },
+                400,
+                "INVALID_SETTINGS_TOML",
+            ),
+            (
+                UnknownSettings {
+                    format: "JSON",
+                    settings: s("settings.a"),
+                },
+                400,
+                "UNKNOWN_SETTINGS",
+            ),
+            (
+                InvalidSetting {
+                    key: s("settings.a"),
+                    reason: s("no"),
+                },
+                400,
+                "INVALID_SETTING",
+            ),
+            (
+                InvalidBool {
+                    input: s("dry-run"),
+                    given: s("maybe"),
+                },
+                400,
+                "INVALID_BOOL",
+            ),
+            (
+                InvalidNumber {
+                    input: s("depth"),
+                    given: s("-1"),
+                },
+                400,
+                "INVALID_NUMBER",
+            ),
+            (InvalidState { given: s("staged") }, 400, "INVALID_STATE"),
+            (
+                UnknownFieldPaths { fields: s("motdd") },
+                400,
+                "UNKNOWN_FIELDS",
+            ),
+            (
+                InvalidMetadataName { name: s("../x") },
+                400,
+                "INVALID_METADATA_NAME",
+            ),
+            (
+                NewKey {
+                    key_type: s("data"),
+                    name: s(".."),
+                    source: datastore_error(),
+                },
+                400,
+                "INVALID_KEY",
+            ),
+            (DumpFormat { msg: s("no") }, 400, "INVALID_DUMP"),
+            (
+                DumpDeserialization {
+                    section: s("live"),
+                    source: json_error(),
+                },
+                400,
+                "INVALID_DUMP",
+            ),
+            (
+                InvalidHeader {
+                    name: s("If-Match"),
+                },
+                400,
+                "INVALID_HEADER",
+            ),
+            (
+                BodyRead {
+                    message: actix_web::error::PayloadError::Overflow.to_string(),
+                },
+                400,
+                "INVALID_BODY",
+            ),
+            (
+                BodyNotUtf8 {
+                    source: String::from_utf8(vec![0xff]).unwrap_err(),
+                },
+                400,
+                "INVALID_BODY",
+            ),
+            (
+                ImmutableKeys {
+                    keys: s("settings.a"),
+                },
+                403,
+                "IMMUTABLE_KEYS",
+            ),
+            (
+                Forbidden {
+                    method: "PATCH".to_string(),
+                    path: "/settings".to_string(),
+                },
+                403,
+                "FORBIDDEN",
+            ),
+            (
+                MissingData {
+                    prefix: s("settings"),
+                },
+                404,
+                "SETTINGS_NOT_FOUND",
+            ),
+            (
+                ListKeys {
+                    requested: s("settings"),
+                },
+                404,
+                "KEYS_NOT_FOUND",
+            ),
+            (
+                SnapshotNotFound { name: s("old") },
+                404,
+                "SNAPSHOT_NOT_FOUND",
+            ),
+            (
+                MissingMetadata {
+                    name: s("template"),
+                },
+                404,
+                "METADATA_NOT_FOUND",
+            ),
+            (
+                PreconditionFailed { etag: s("\"a\"") },
+                412,
+                "ETAG_MISMATCH",
+            ),
+            (BodyTooLarge { limit: 1 }, 413, "BODY_TOO_LARGE"),
+            (TooManyKeys { count: 2, limit: 1 }, 413, "TOO_MANY_KEYS"),
+            (CommitWithNoPending, 422, "NO_PENDING_CHANGES"),
+            (
+                KeysNotPending {
+                    transaction: s("default"),
+                    keys: s("settings.a"),
+                },
+                422,
+                "KEYS_NOT_PENDING",
+            ),
+            (
+                DumpConflicts {
+                    keys: s("settings.a"),
+                },
+                422,
+                "DUMP_CONFLICTS",
+            ),
+            (SnapshotExists { name: s("old") }, 422, "SNAPSHOT_EXISTS"),
+            (DataStoreLock, 500, "DATASTORE_LOCK_POISONED"),
+            (
+                ResponseSerialization {
+                    source: json_error(),
+                },
+                500,
+                "RESPONSE_SERIALIZATION",
+            ),
+            (
+                ListedKeyNotPresent {
+                    key: s("settings.a"),
+                },
+                500,
+                "DATASTORE_INCONSISTENT",
+            ),
+            (
+                DataStore {
+                    op: s("get_key"),
+                    source: datastore_error(),
+                },
+                500,
+                "DATASTORE_ERROR",
+            ),
+            (
+                Deserialization {
+                    given: s("settings"),
+                    source: deserialization::Error::Message { msg: s("no") },
+                },
+                500,
+                "DATASTORE_CORRUPT",
+            ),
+            (
+                InvalidData {
+                    key: s("settings.a"),
+                    source: json_error(),
+                },
+                500,
+                "DATASTORE_CORRUPT",
+            ),
+            (
+                DataStoreSerialization {
+                    given: s("settings"),
+                    source: serialization::Error::Message { msg: s("no") },
+                },
+                500,
+                "DATASTORE_SERIALIZATION",
+            ),
+            (
+                InvalidMetadata {
+                    key: s("affected-services"),
+                    source: json_error(),
+                },
+                500,
+                "METADATA_CORRUPT",
+            ),
+            (
+                InvalidPattern {
+                    key: s("settings.a"),
+                    pattern: s("("),
+                    source: regex::Regex::new("(").unwrap_err(),
+                },
+                500,
+                "METADATA_CORRUPT",
+            ),
+            (
+                DumpConflict {
+                    key: s("settings.a"),
+                },
+                500,
+                "DUMP_CONFLICT",
+            ),
+            (RestartCycle { cycle: s("a -> a") }, 500, "RESTART_CYCLE"),
+            (
+                InterruptedCommit {
+                    transaction: s("default"),
+                },
+                500,
+                "INTERRUPTED_COMMIT",
+            ),
+            (
+                CommandSerialization {
+                    given: s("keys"),
+                    source: json_error(),
+                },
+                500,
+                "APPLIER_ERROR",
+            ),
+            (
+                ConfigApplierStart { source: io_error() },
+                500,
+                "APPLIER_ERROR",
+            ),
+            (ConfigApplierStdin {}, 500, "APPLIER_ERROR"),
+            (
+                ConfigApplierWrite { source: io_error() },
+                500,
+                "APPLIER_ERROR",
+            ),
+            (
+                ConfigApplierWait { source: io_error() },
+                500,
+                "APPLIER_ERROR",
+            ),
+            (ConfigApplierTimeout { seconds: 1 }, 500, "APPLIER_TIMEOUT"),
+            (
+                ConfigApplierFailed {
+                    status: s("exit code 1"),
+                    stderr: s("no"),
+                },
+                500,
+                "APPLIER_FAILED",
+            ),
+            (ConfigApplierCanceled {}, 500, "APPLIER_ERROR"),
+            (
+                ApplyRolledBack {
+                    apply_error: s("no"),
+                },
+                500,
+                "APPLY_ROLLED_BACK",
+            ),
+            (
+                RollbackFailed {
+                    apply_error: s("no"),
+                    rollback_error: s("no"),
+                },
+                500,
+                "ROLLBACK_FAILED",
+            ),
+            (
+                RollbackConflict {
+                    keys: s("settings.motd"),
+                },
+                500,
+                "ROLLBACK_FAILED",
+            ),
+            (
+                AuditLogWrite {
+                    path: "/audit.log".into(),
+                    source: io_error(),
+                },
+                500,
+                "AUDIT_LOG_WRITE",
+            ),
+            (
+                ReleaseData {
+                    source: bottlerocket_release::Error::ReadReleaseFile {
+                        path: "/etc/os-release".into(),
+                        source: io_error(),
+                    },
+                },
+                500,
+                "OS_RELEASE_UNAVAILABLE",
+            ),
+            (
+                RoutesDocument {
+                    source: serde_yaml::from_str::<serde_yaml::Value>("[").unwrap_err(),
+                },
+                500,
+                "SCHEMA_UNAVAILABLE",
+            ),
+            (
+                BindSocket {
+                    path: "/api.sock".into(),
+                    source: io_error(),
+                },
+                500,
+                "SERVER_SETUP",
+            ),
+            (
+                BindAddress {
+                    address: s("127.0.0.1:4242"),
+                    source: io_error(),
+                },
+                500,
+                "SERVER_SETUP",
+            ),
+            (
+                SocketDirectory {
+                    path: "/run".into(),
+                    source: io_error(),
+                },
+                500,
+                "SERVER_SETUP",
+            ),
+            (
+                SocketPathInUse {
+                    path: "/api.sock".into(),
+                },
+                500,
+                "SERVER_SETUP",
+            ),
+            (
+                StaleSocket {
+                    path: "/api.sock".into(),
+                    source: io_error(),
+                },
+                500,
+                "SERVER_SETUP",
+            ),
+            (ServerStart { source: io_error() }, 500, "SERVER_SETUP"),
+            (SystemdNotify { source: io_error() }, 500, "SERVER_SETUP"),
+            (SystemdNotifyStatus {}, 500, "SERVER_SETUP"),
+            (
+                SetPermissions {
+                    mode: 0o660,
+                    source: io_error(),
+                },
+                500,
+                "SERVER_SETUP",
+            ),
+            (
+                SetGroup {
+                    gid: Gid::from_raw(0),
+                    source: nix::Error::Sys(nix::errno::Errno::EPERM),
+                },
+                500,
+                "SERVER_SETUP",
+            ),
+            (ShuttingDown, 503, "SHUTTING_DOWN"),
+        ];
+
+        for (e, status, code) in cases {
+            let response = e.error_response();
+            assert_eq!(response.status().as_u16(), status, "{}", e);
+            assert_eq!(e.error_body().code, code, "{}", e);
+        }
+    }
+
+    #[test]
+    fn error_body_format() {
+        let e = error::Error::MissingData {
+            prefix: "settings.motd".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(e.error_body()).unwrap(),
+            json!({
+                "code": "SETTINGS_NOT_FOUND",
+                "message": "Found no 'settings.motd' in datastore",
+                "details": {"prefix": "settings.motd"},
+            })
+        );
+
+        let e = error::Error::DataStoreLock;
+        assert_eq!(
+            serde_json::to_value(e.error_body()).unwrap(),
+            json!({
+                "code": "DATASTORE_LOCK_POISONED",
+                "message": "Another thread poisoned the data store lock by panicking",
+                "details": null,
+            })
+        );
+    }
+
+    /// Returns an example value for the given schema from the OpenAPI document, like a client
+    /// generated from the document might send.  Modeled types are only strings in the document,
+    /// so they're given values here that pass their validation.
+    fn example(document: &Value, schema: &Value) -> Value {
+        if let Some(r) = schema["$ref"].as_str() {
+            let name = r.trim_start_matches("#/components/schemas/");
+            return match name {
+                "Url" => json!("https://example.com/"),
+                "ValidBase64" => json!("aGk="),
+                "KubernetesTaintValue" => json!("value:NoSchedule"),
+                "SingleLineString"
+                | "Identifier"
+                | "KubernetesClusterName"
+                | "KubernetesLabelKey"
+                | "KubernetesLabelValue" => json!("example"),
+                _ => example(document, &document["components"]["schemas"][name]),
+            };
+        }
+        // Optional references may be wrapped so they can be marked nullable
+        if let Some(all_of) = schema["allOf"].as_array() {
+            return example(document, &all_of[0]);
+        }
+        match schema["type"].as_str() {
+            Some("object") => match schema["properties"].as_object() {
+                Some(properties) => Value::Object(
+                    properties
+                        .iter()
+                        .map(|(name, property)| (name.clone(), example(document, property)))
+                        .collect(),
+                ),
+                None => json!({ "example": example(document, &schema["additionalProperties"]) }),
+            },
+            Some("array") => json!([example(document, &schema["items"])]),
+            Some("string") if schema["format"] == "ipv4" => json!("10.0.0.1"),
+            Some("string") => json!("example"),
+            Some("integer") => json!(1),
+            Some("boolean") => json!(true),
+            other => panic!("No example for type {:?} in schema {}", other, schema),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn settings_from_schema_round_trip() {
+        let request = TestRequest::default().to_http_request();
+        let response = get_schema()
+            .await
+            .unwrap()
+            .respond_to(&request)
+            .await
+            .unwrap();
+        let document: Value = match response.body().as_ref() {
+            Some(Body::Bytes(bytes)) => serde_json::from_slice(bytes).unwrap(),
+            other => panic!("Unexpected response body: {:?}", other),
+        };
+        let settings = example(&document, &json!({"$ref": "#/components/schemas/Settings"}));
+        assert!(settings["updates"]["seed"].is_number(), "{}", settings);
+
+        // Every setting in the document is accepted, and comes back the same
+        let data = web::Data::new(SharedDataStore::new(MemoryDataStore::new()));
+        let response = patch_settings(
+            TestRequest::default().to_http_request(),
+            payload(&settings.to_string()),
+            query(""),
+            data.clone(),
+            web::Data::new(InputLimits::default()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let SettingsResponse(pending) = get_pending_settings(query(""), data).await.unwrap();
+        assert_eq!(serde_json::to_value(pending).unwrap(), settings);
+    }
+
+    #[test]
+    fn settings_round_trip_over_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let datastore_path = dir.path().join("datastore");
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        FilesystemDataStore::new(&datastore_path)
+            .set_key(&motd, "\"hi\"", &Committed::Live)
+            .unwrap();
+
+        // Leave a socket behind, as if from an earlier run, to make sure it's replaced
+        let socket_path = dir.path().join("run/api.sock");
+        fs::create_dir_all(socket_path.parent().unwrap()).unwrap();
+        drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+
+        let listener = Listener::Socket {
+            path: socket_path.clone(),
+            gid: None,
+        };
+        std::thread::spawn(move || {
+            actix_rt::System::new("test-server")
+                .block_on(serve(
+                    listener,
+                    datastore_path,
+                    1,
+                    RequestLogConfig::default(),
+                    InputLimits::default(),
+                    ApplierConfig::default(),
+                    None,
+                    AuthConfig::default(),
+                    ShutdownHook::default(),
+                ))
+                .unwrap()
+        });
+
+        // Wait for the server to start listening
+        let mut response = None;
+        for _ in 0..50 {
+            match apiclient::raw_request(&socket_path, "/settings", "GET", None) {
+                Ok(r) => {
+                    response = Some(r);
+                    break;
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+        let (status, body) = response.expect("Server didn't respond on socket");
+        assert_eq!(status.as_u16(), 200);
+        let settings: Settings = serde_json::from_str(&body).unwrap();
+        assert_eq!(settings.motd, Some("hi".try_into().unwrap()));
+
+        let mode = fs::metadata(&socket_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+    }
+
+    #[test]
+    fn socket_callers_authorized_by_peer_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let datastore_path = dir.path().join("datastore");
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        FilesystemDataStore::new(&datastore_path)
+            .set_key(&motd, "\"hi\"", &Committed::Live)
+            .unwrap();
+        let socket_path = dir.path().join("api.sock");
+
+        // We may change settings, but only someone else may commit
+        let me = nix::unistd::getuid().as_raw();
+        let auth = AuthConfig {
+            rules: vec![
+                AuthRule {
+                    methods: vec![Method::PATCH],
+                    path_prefix: "/settings".to_string(),
+                    allow: Allow {
+                        uids: vec![me],
+                        ..Default::default()
+                    },
+                },
+                AuthRule {
+                    methods: vec![],
+                    path_prefix: "/tx".to_string(),
+                    allow: Allow {
+                        uids: vec![me.wrapping_add(1)],
+                        ..Default::default()
+                    },
+                },
+            ],
+            token: None,
+        };
+        let listener = Listener::Socket {
+            path: socket_path.clone(),
+            gid: None,
+        };
+        std::thread::spawn(move || {
+            actix_rt::System::new("test-server")
+                .block_on(serve(
+                    listener,
+                    datastore_path,
+                    1,
+                    RequestLogConfig::default(),
+                    InputLimits::default(),
+                    ApplierConfig::default(),
+                    None,
+                    auth,
+                    ShutdownHook::default(),
+                ))
+                .unwrap()
+        });
+
+        let body = Some(r#"{"motd": "bye"}"#.to_string());
+        let mut response = None;
+        for _ in 0..50 {
+            match apiclient::raw_request(&socket_path, "/settings?tx=t", "PATCH", body.clone()) {
+                Ok(r) => {
+                    response = Some(r);
+                    break;
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+        let (status, _) = response.expect("Server didn't respond on socket");
+        assert_eq!(status.as_u16(), 204);
+
+        // apiclient returns non-2xx responses as errors
+        let body = match apiclient::raw_request(&socket_path, "/tx/commit?tx=t", "POST", None) {
+            Err(apiclient::Error::ResponseStatus { code, body, .. }) => {
+                assert_eq!(code.as_u16(), 403);
+                body
+            }
+            other => panic!("Expected a 403 response, got {:?}", other),
+        };
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "code": "FORBIDDEN",
+                "message": "Not allowed to POST /tx/commit",
+                "details": { "method": "POST", "path": "/tx/commit" },
+            })
+        );
+    }
+
+    /// Wraps a DataStore so tests can stop a commit partway through.  Each commit_transaction
+    /// waits at the barrier twice before committing: once to say it has started, with the commit
+    /// marker set, and once more to be released.
+    struct PausingDataStore<D> {
+        inner: D,
+        pause: Arc<Barrier>,
+    }
+
+    impl<D: DataStore> DataStore for PausingDataStore<D> {
+        fn key_populated(&self, key: &Key, committed: &Committed) -> datastore::Result<bool> {
+            self.inner.key_populated(key, committed)
+        }
+        fn list_populated_keys<S: AsRef<str>>(
+            &self,
+            prefix: S,
+            committed: &Committed,
+        ) -> datastore::Result<HashSet<Key>> {
+            self.inner.list_populated_keys(prefix, committed)
+        }
+        fn list_populated_metadata<S1, S2>(
+            &self,
+            prefix: S1,
+            metadata_key_name: &Option<S2>,
+            committed: &Committed,
+        ) -> datastore::Result<HashMap<Key, HashSet<Key>>>
+        where
+            S1: AsRef<str>,
+            S2: AsRef<str>,
+        {
+            self.inner
+                .list_populated_metadata(prefix, metadata_key_name, committed)
+        }
+        fn get_key(&self, key: &Key, committed: &Committed) -> datastore::Result<Option<String>> {
+            self.inner.get_key(key, committed)
+        }
+        fn set_key<S: AsRef<str>>(
+            &mut self,
+            key: &Key,
+            value: S,
+            committed: &Committed,
+        ) -> datastore::Result<()> {
+            self.inner.set_key(key, value, committed)
+        }
+        fn unset_key(&mut self, key: &Key, committed: &Committed) -> datastore::Result<()> {
+            self.inner.unset_key(key, committed)
+        }
+        fn get_key_mtime(
+            &self,
+            key: &Key,
+            committed: &Committed,
+        ) -> datastore::Result<Option<SystemTime>> {
+            self.inner.get_key_mtime(key, committed)
+        }
+        fn get_metadata_raw(
+            &self,
+            metadata_key: &Key,
+            data_key: &Key,
+            committed: &Committed,
+        ) -> datastore::Result<Option<String>> {
+            self.inner
+                .get_metadata_raw(metadata_key, data_key, committed)
+        }
+        fn set_metadata<S: AsRef<str>>(
+            &mut self,
+            metadata_key: &Key,
+            data_key: &Key,
+            value: S,
+            committed: &Committed,
+        ) -> datastore::Result<()> {
+            self.inner
+                .set_metadata(metadata_key, data_key, value, committed)
+        }
+        fn unset_metadata(
+            &mut self,
+            metadata_key: &Key,
+            data_key: &Key,
+            committed: &Committed,
+        ) -> datastore::Result<()> {
+            self.inner.unset_metadata(metadata_key, data_key, committed)
+        }
+        fn list_metadata(
+            &self,
+            data_key: &Key,
+            committed: &Committed,
+        ) -> datastore::Result<HashSet<Key>> {
+            self.inner.list_metadata(data_key, committed)
+        }
+        fn commit_transaction<S>(&mut self, transaction: S) -> datastore::Result<HashSet<Key>>
+        where
+            S: Into<String> + AsRef<str>,
+        {
+            self.pause.wait();
+            self.pause.wait();
+            self.inner.commit_transaction(transaction)
+        }
+        fn commit_keys<S>(
+            &mut self,
+            transaction: S,
+            keys: &HashSet<Key>,
+        ) -> datastore::Result<HashSet<Key>>
+        where
+            S: Into<String> + AsRef<str>,
+        {
+            self.inner.commit_keys(transaction, keys)
+        }
+        fn delete_transaction<S>(&mut self, transaction: S) -> datastore::Result<HashSet<Key>>
+        where
+            S: Into<String> + AsRef<str>,
+        {
+            self.inner.delete_transaction(transaction)
+        }
+        fn list_transactions(&self) -> datastore::Result<HashSet<String>> {
+            self.inner.list_transactions()
+        }
+        fn check_available(&self) -> datastore::Result<()> {
+            self.inner.check_available()
+        }
+    }
+
+    #[test]
+    fn shutdown_finishes_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let datastore_path = dir.path().join("datastore");
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        FilesystemDataStore::new(&datastore_path)
+            .set_key(&motd, "\"hi\"", &Committed::Live)
+            .unwrap();
+        let socket_path = dir.path().join("api.sock");
+
+        // No drain time, so the commit is still running when the workers are stopped
+        let shutdown = ShutdownHook::new(Duration::from_secs(0));
+        let pause = Arc::new(Barrier::new(2));
+        let server = {
+            let listener = Listener::Socket {
+                path: socket_path.clone(),
+                gid: None,
+            };
+            let datastore = PausingDataStore {
+                inner: FilesystemDataStore::new(&datastore_path),
+                pause: pause.clone(),
+            };
+            let shutdown = shutdown.clone();
+            std::thread::spawn(move || {
+                actix_rt::System::new("test-server").block_on(serve_datastore(
+                    listener,
+                    datastore,
+                    1,
+                    RequestLogConfig::default(),
+                    InputLimits::default(),
+                    ApplierConfig::default(),
+                    None,
+                    AuthConfig::default(),
+                    shutdown,
+                ))
+            })
+        };
+
+        let body = Some(r#"{"motd": "bye"}"#.to_string());
+        let mut patched = false;
+        for _ in 0..50 {
+            match apiclient::raw_request(&socket_path, "/settings?tx=t", "PATCH", body.clone()) {
+                Ok((status, _)) => {
+                    assert_eq!(status.as_u16(), 204);
+                    patched = true;
+                    break;
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+        assert!(patched, "Server didn't respond on socket");
+
+        // Start the commit, and shut down while it's paused with the commit marker set
+        let commit_socket = socket_path.clone();
+        std::thread::spawn(move || {
+            let _ = apiclient::raw_request(&commit_socket, "/tx/commit?tx=t", "POST", None);
+        });
+        pause.wait();
+        let observer = FilesystemDataStore::new(&datastore_path);
+        assert_eq!(
+            controller::get_interrupted_commit(&observer).unwrap(),
+            Some("t".to_string())
+        );
+        shutdown.shutdown();
+        pause.wait();
+        server.join().unwrap().unwrap();
+
+        // The commit finished before the server returned
+        assert_eq!(controller::get_interrupted_commit(&observer).unwrap(), None);
+        assert_eq!(
+            observer.get_key(&motd, &Committed::Live).unwrap(),
+            Some("\"bye\"".to_string())
+        );
+        assert!(observer.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn socket_path_not_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("api.sock");
+        fs::write(&path, "not a socket").unwrap();
+        assert!(prepare_socket_path(&path).is_err());
+        assert!(path.exists());
+    }
+
+    #[actix_rt::test]
+    async fn settings_input_limited() {
+        let data = pending_datastore();
+        let limits = web::Data::new(InputLimits {
+            max_body_bytes: 1024,
+            max_keys: 2,
+        });
+        let patch = |body: String| {
+            let (req, payload) = TestRequest::default().set_payload(body).to_http_parts();
+            patch_settings(
+                req,
+                web::Payload(payload),
+                query(""),
+                data.clone(),
+                limits.clone(),
+            )
+        };
+
+        // Oversized bodies are rejected before they're parsed, with the limit in the response
+        let motd = "x".repeat(1024);
+        match patch(format!(r#"{{"motd": "{}"}}"#, motd)).await {
+            Err(e @ error::Error::BodyTooLarge { .. }) => {
+                assert_eq!(e.error_response().status(), StatusCode::PAYLOAD_TOO_LARGE);
+                assert_eq!(e.details(), Some(json!({"limit": 1024})));
+            }
+            Err(e) => panic!("Expected BodyTooLarge, got {}", e),
+            Ok(_) => panic!("Oversized body was accepted"),
+        }
+
+        // Without a Content-Length header, the body is still only read up to the limit
+        let (req, _) = TestRequest::default().to_http_parts();
+        let oversized = payload(&(motd.clone() + &motd));
+        match read_body(&req, oversized, 1024).await {
+            Err(error::Error::BodyTooLarge { limit }) => assert_eq!(limit, 1024),
+            other => panic!("Expected BodyTooLarge, got {:?}", other),
+        }
+
+        // Input that's small, but turns into too many keys, is rejected too
+        let body = r#"{"motd": "hi", "ntp": null, "updates": {"seed": 1}}"#;
+        match patch(body.to_string()).await {
+            Err(e @ error::Error::TooManyKeys { .. }) => {
+                assert_eq!(e.error_response().status(), StatusCode::PAYLOAD_TOO_LARGE);
+                assert_eq!(e.details(), Some(json!({"count": 3, "limit": 2})));
+            }
+            Err(e) => panic!("Expected TooManyKeys, got {}", e),
+            Ok(_) => panic!("Too many keys were accepted"),
+        }
+
+        // Deeply nested input is rejected by the parser rather than exhausting the stack
+        let nested = "[".repeat(300) + &"]".repeat(300);
+        match patch(format!(r#"{{"motd": {}}}"#, nested)).await {
+            Err(e @ error::Error::SettingsJson { .. }) => {
+                assert_eq!(e.error_response().status(), StatusCode::BAD_REQUEST)
+            }
+            Err(e) => panic!("Expected SettingsJson, got {}", e),
+            Ok(_) => panic!("Deeply nested input was accepted"),
+        }
+
+        // Nothing was staged along the way
+        let SettingsResponse(pending) =
+            get_pending_settings(query(""), data.clone()).await.unwrap();
+        assert_eq!(pending.motd, Some("new".try_into().unwrap()));
+    }
+
+    #[actix_rt::test]
+    async fn get_settings_by_state() {
+        let data = pending_datastore();
+        let get = |query_str: &str| {
+            let request = TestRequest::default().to_http_request();
+            get_settings(request, query(query_str), data.clone())
+        };
+
+        // Live is the default
+        for query_str in &["", "state=live"] {
+            let response = get(query_str).await.unwrap();
+            assert!(response.headers().contains_key(header::ETAG));
+            let settings = response_settings(&response);
+            assert_eq!(settings.motd, Some("old".try_into().unwrap()));
+            assert!(settings.updates.is_none());
+        }
+        let response = get("state=pending").await.unwrap();
+        assert!(!response.headers().contains_key(header::ETAG));
+        let settings = response_settings(&response);
+        assert_eq!(settings.motd, Some("new".try_into().unwrap()));
+        assert_eq!(settings.updates.unwrap().seed, Some(42));
+
+        // Specific keys
+        let settings = response_settings(&get("keys=settings.motd").await.unwrap());
+        assert_eq!(settings.motd, Some("old".try_into().unwrap()));
+        let response = get("keys=settings.motd&state=pending").await.unwrap();
+        let settings = response_settings(&response);
+        assert_eq!(settings.motd, Some("new".try_into().unwrap()));
+        assert!(settings.updates.is_none());
+
+        // Prefixes
+        let settings = response_settings(&get("prefix=updates").await.unwrap());
+        assert!(settings.updates.is_none());
+        let settings = response_settings(&get("prefix=updates&state=pending").await.unwrap());
+        assert_eq!(settings.updates.unwrap().seed, Some(42));
+        assert!(settings.motd.is_none());
+
+        // Pending settings are read from the requested transaction
+        match get("state=pending&tx=other").await {
+            Err(error::Error::MissingData { .. }) => {}
+            Err(e) => panic!("Expected MissingData, got {}", e),
+            Ok(_) => panic!("Found settings pending in an empty transaction"),
+        }
+
+        match get("state=staged").await {
+            Err(e @ error::Error::InvalidState { .. }) => {
+                assert_eq!(e.error_response().status(), StatusCode::BAD_REQUEST)
+            }
+            Err(e) => panic!("Expected InvalidState, got {}", e),
+            Ok(_) => panic!("Invalid state was accepted"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn get_settings_lenient() {
+        let mut ds = MemoryDataStore::new();
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let seed = Key::new(KeyType::Data, "settings.updates.seed").unwrap();
+        ds.set_key(&motd, "\"hi\"", &Committed::Live).unwrap();
+        ds.set_key(&seed, "\"not a number\"", &Committed::Live)
+            .unwrap();
+        let data = web::Data::new(SharedDataStore::new(ds));
+        let get = |query_str: &str| {
+            let request = TestRequest::default().to_http_request();
+            get_settings(request, query(query_str), data.clone())
+        };
+        let body = |response: HttpResponse| match response.body().as_ref() {
+            Some(Body::Bytes(bytes)) => serde_json::from_slice::<Value>(bytes).unwrap(),
+            other => panic!("Unexpected response body: {:?}", other),
+        };
+
+        // Normally the bad value fails the whole request
+        assert!(get("").await.is_err());
+
+        let response = get("lenient=true").await.unwrap();
+        let body = body(response);
+        assert_eq!(body["settings"], json!({"motd": "hi"}));
+        assert_eq!(body["skipped"][0]["key"], json!("settings.updates.seed"));
+        assert!(body["skipped"][0]["error"]
+            .as_str()
+            .unwrap()
+            .contains("not a number"));
+
+        match get("lenient=true&prefix=updates").await {
+            Err(e) => assert_eq!(e.error_body().code, "LENIENT_WITH_KEYS"),
+            Ok(_) => panic!("Lenient read was limited to a prefix"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn get_settings_fields() {
+        let data = pending_datastore();
+        let get = |query_str: &str| {
+            let request = TestRequest::default().to_http_request();
+            get_settings(request, query(query_str), data.clone())
+        };
+        let body = |response: HttpResponse| match response.body().as_ref() {
+            Some(Body::Bytes(bytes)) => serde_json::from_slice::<Value>(bytes).unwrap(),
+            other => panic!("Unexpected response body: {:?}", other),
+        };
+
+        let response = get("state=pending&fields=updates.seed").await.unwrap();
+        assert_eq!(body(response), json!({"updates": {"seed": 42}}));
+
+        // Fields that are in the model but aren't set are left out, and combine with other filters
+        let response = get("state=pending&fields=motd,ntp.time-servers&prefix=motd")
+            .await
+            .unwrap();
+        assert_eq!(body(response), json!({"motd": "new"}));
+
+        // Fields that aren't in the model are listed in the error
+        match get("fields=motd,ntp.time-server,motdd,updates.").await {
+            Err(e @ error::Error::UnknownFieldPaths { .. }) => {
+                assert_eq!(e.error_response().status(), StatusCode::BAD_REQUEST);
+                assert_eq!(
+                    e.details(),
+                    Some(json!({"fields": "motdd, ntp.time-server, updates."}))
+                );
+            }
+            Err(e) => panic!("Expected UnknownFieldPaths, got {}", e),
+            Ok(_) => panic!("Unknown fields were accepted"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn get_settings_multiple_prefixes() {
+        let data = pending_datastore();
+        let get = |query_str: &str| {
+            let request = TestRequest::default().to_http_request();
+            get_settings(request, query(query_str), data.clone())
+        };
+
+        for query_str in &[
+            "prefix=motd,updates&state=pending",
+            "prefix=updates,motd,updates.seed&state=pending",
+            "keys=settings.motd&prefix=updates&state=pending",
+        ] {
+            let settings = response_settings(&get(query_str).await.unwrap());
+            assert_eq!(settings.motd, Some("new".try_into().unwrap()));
+            assert_eq!(settings.updates.unwrap().seed, Some(42));
+        }
+
+        // Only the live motd exists, so that's all we get
+        let response = get("keys=settings.motd&prefix=updates").await.unwrap();
+        assert!(response.headers().contains_key(header::ETAG));
+        let settings = response_settings(&response);
+        assert_eq!(settings.motd, Some("old".try_into().unwrap()));
+        assert!(settings.updates.is_none());
+
+        // Nothing matches
+        let settings = response_settings(&get("prefix=kubernetes,host").await.unwrap());
+        assert_eq!(settings, Settings::default());
+
+        match get("keys=settings.motd&prefix=").await {
+            Err(error::Error::EmptyInput { input }) if input == "prefix" => {}
+            Err(e) => panic!("Expected EmptyInput, got {}", e),
+            Ok(_) => panic!("Empty prefix was accepted"),
+        }
+    }
+
+    /// Returns a MemoryDataStore, shared the way handlers expect, with some live settings and
+    /// "affected-services" metadata.
+    fn metadata_datastore() -> web::Data<SharedDataStore<MemoryDataStore>> {
+        let mut ds = MemoryDataStore::new();
+        let md = Key::new(KeyType::Meta, "affected-services").unwrap();
+        for (name, value, services) in &[
+            ("settings.motd", "\"hi\"", Some("[\"motd\"]")),
+            ("settings.ntp.time-servers", "[]", Some("[\"chronyd\"]")),
+            ("settings.updates.seed", "42", None),
+        ] {
+            let key = Key::new(KeyType::Data, name).unwrap();
+            ds.set_key(&key, value, &Committed::Live).unwrap();
+            if let Some(services) = services {
+                ds.set_metadata(&md, &key, services, &Committed::Live)
+                    .unwrap();
+            }
+        }
+        web::Data::new(SharedDataStore::new(ds))
+    }
+
+    #[actix_rt::test]
+    async fn get_metadata_by_keys_and_prefix() {
+        let data = metadata_datastore();
+        let get = |md_key: &str, query_str: &str| {
+            get_metadata(
+                web::Path::from(md_key.to_string()),
+                query(query_str),
+                data.clone(),
+            )
+        };
+
+        let MetadataResponse(resp) = get(
+            "affected-services",
+            "keys=settings.motd,settings.updates.seed",
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp["settings.motd"], json!(["motd"]));
+
+        let MetadataResponse(resp) = get("affected-services", "prefix=settings.ntp.")
+            .await
+            .unwrap();
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp["settings.ntp.time-servers"], json!(["chronyd"]));
+
+        let MetadataResponse(resp) = get(
+            "affected-services",
+            "keys=settings.motd&prefix=settings.ntp.,settings.updates.",
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.len(), 2);
+
+        // Lenient mode returns whatever it finds, even nothing
+        let MetadataResponse(resp) = get("template", "prefix=settings.").await.unwrap();
+        assert!(resp.is_empty());
+
+        match get("affected-services", "keys=settings.bad!key").await {
+            Err(e @ error::Error::NewKey { .. }) => {
+                assert_eq!(e.error_response().status(), StatusCode::BAD_REQUEST)
+            }
+            Err(e) => panic!("Expected NewKey, got {}", e),
+            Ok(_) => panic!("Invalid key name was accepted"),
+        }
+        match get("affected-services", "").await {
+            Err(error::Error::MissingInput { .. }) => {}
+            Err(e) => panic!("Expected MissingInput, got {}", e),
+            Ok(_) => panic!("Metadata request without keys was accepted"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn get_all_metadata_by_keys() {
+        let data = metadata_datastore();
+
+        let AllMetadataResponse(resp) = get_all_metadata(
+            query("keys=settings.motd,settings.updates.seed"),
+            data.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp["settings.motd"]["affected-services"], json!(["motd"]));
+
+        match get_all_metadata(query(""), data.clone()).await {
+            Err(error::Error::MissingInput { .. }) => {}
+            Err(e) => panic!("Expected MissingInput, got {}", e),
+            Ok(_) => panic!("Metadata request without keys was accepted"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn get_metadata_strict() {
+        let data = metadata_datastore();
+        let get = |md_key: &str, query_str: &str| {
+            get_metadata(
+                web::Path::from(md_key.to_string()),
+                query(query_str),
+                data.clone(),
+            )
+        };
+
+        // Strict mode is fine as long as any requested key has the metadata
+        let MetadataResponse(resp) = get(
+            "affected-services",
+            "keys=settings.motd,settings.updates.seed&strict=true",
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.len(), 1);
+
+        for (md_key, query_str) in &[
+            (
+                "affected-services",
+                "keys=settings.updates.seed&strict=true",
+            ),
+            ("template", "prefix=settings.&strict=true"),
+        ] {
+            match get(md_key, query_str).await {
+                Err(e @ error::Error::MissingMetadata { .. }) => {
+                    assert_eq!(e.error_response().status(), StatusCode::NOT_FOUND)
+                }
+                Err(e) => panic!("Expected MissingMetadata, got {}", e),
+                Ok(_) => panic!("Found metadata for {} with {}", md_key, query_str),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn get_metadata_checks_name() {
+        let data = metadata_datastore();
+        for md_key in &["", "../affected-services", "a/b", "a.b", "a b"] {
+            let result = get_metadata(
+                web::Path::from(md_key.to_string()),
+                query("keys=settings.motd"),
+                data.clone(),
+            )
+            .await;
+            match result {
+                Err(e @ error::Error::InvalidMetadataName { .. }) => {
+                    assert_eq!(e.error_response().status(), StatusCode::BAD_REQUEST)
+                }
+                Err(e) => panic!("Expected InvalidMetadataName, got {}", e),
+                Ok(_) => panic!("Metadata name '{}' was accepted", md_key),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn raw_key_survives_corrupt_value() {
+        let data = pending_datastore();
+        {
+            let mut datastore = data.ds.write().unwrap();
+            let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+            let md_key = Key::new(KeyType::Meta, "affected-services").unwrap();
+            datastore
+                .set_key(&motd, "{not json", &Committed::Live)
+                .unwrap();
+            datastore
+                .set_metadata(&md_key, &motd, "[\"motd\"]", &Committed::Live)
+                .unwrap();
+        }
+
+        // The settings can't be read anymore
+        let request = TestRequest::default().to_http_request();
+        assert!(get_settings(request, query(""), data.clone())
+            .await
+            .is_err());
+
+        let RawKeyResponse(raw) = get_raw_key(query("name=settings.motd"), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            raw,
+            RawKey {
+                name: "settings.motd".to_string(),
+                value: Some("{not json".to_string()),
+                metadata: vec!["affected-services".to_string()],
+                pending: true,
+            }
+        );
+
+        let RawKeyResponse(raw) = get_raw_key(
+            query("name=settings.updates.seed&state=pending"),
+            data.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(raw.value, Some("42".to_string()));
+        assert!(raw.metadata.is_empty());
+        let RawKeyResponse(raw) = get_raw_key(query("name=settings.updates.seed"), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(raw.value, None);
+        assert!(raw.pending);
+
+        match get_raw_key(query("state=live"), data.clone()).await {
+            Err(error::Error::MissingInput { .. }) => {}
+            Err(e) => panic!("Expected MissingInput, got {}", e),
+            Ok(_) => panic!("Raw key was returned without a name"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn setting_value_resource() {
+        let data = pending_datastore();
+
+        let SettingValueResponse(value) =
+            get_setting_value(query("name=settings.motd"), data.clone())
+                .await
+                .unwrap();
+        assert_eq!(value, json!("old"));
+        let SettingValueResponse(value) =
+            get_setting_value(query("name=settings.motd&state=pending"), data.clone())
+                .await
+                .unwrap();
+        assert_eq!(value, json!("new"));
+
+        for (query_str, code) in &[
+            ("name=settings.updates.seed", "SETTINGS_NOT_FOUND"),
+            ("name=settings.updates&state=pending", "SETTING_IS_PREFIX"),
+            ("name=os.arch", "NOT_A_SETTING"),
+            ("", "MISSING_INPUT"),
+        ] {
+            match get_setting_value(query(query_str), data.clone()).await {
+                Err(e) => assert_eq!(e.error_body().code, *code, "{}", query_str),
+                Ok(SettingValueResponse(v)) => panic!("Got {} for '{}'", v, query_str),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn settings_keys_resource() {
+        let data = pending_datastore();
+
+        let SettingsKeysResponse(keys) = get_settings_keys(query(""), data.clone()).await.unwrap();
+        assert_eq!(keys, btreeset!("settings.motd".to_string()));
+        let SettingsKeysResponse(keys) =
+            get_settings_keys(query("state=pending&depth=1"), data.clone())
+                .await
+                .unwrap();
+        assert_eq!(
+            keys,
+            btreeset!("settings.motd".to_string(), "settings.updates".to_string())
+        );
+        let SettingsKeysResponse(keys) =
+            get_settings_keys(query("state=pending&prefix=settings.updates"), data.clone())
+                .await
+                .unwrap();
+        assert_eq!(keys, btreeset!("settings.updates.seed".to_string()));
+
+        for (query_str, code) in &[
+            ("depth=-1", "INVALID_NUMBER"),
+            ("prefix=os.", "NOT_A_SETTING"),
+        ] {
+            match get_settings_keys(query(query_str), data.clone()).await {
+                Err(e) => assert_eq!(e.error_body().code, *code, "{}", query_str),
+                Ok(SettingsKeysResponse(k)) => panic!("Got {:?} for '{}'", k, query_str),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn snapshots_resource() {
+        let data = pending_datastore();
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+
+        let SnapshotResponse(snapshot) = create_snapshot(query("name=before"), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(snapshot.name, "before");
+        let SnapshotListResponse(snapshots) = get_snapshots(data.clone()).await.unwrap();
+        assert_eq!(snapshots, vec![snapshot]);
+
+        // Restoring stages the snapshot's values over whatever was pending
+        data.write()
+            .unwrap()
+            .set_key(&motd, "\"changed\"", &Committed::Live)
+            .unwrap();
+        let request = TestRequest::default().to_http_request();
+        let ChangedKeysResponse(staged) =
+            restore_snapshot(request, query("name=before&tx=restore"), data.clone())
+                .await
+                .unwrap();
+        assert_eq!(staged, hashset!(motd.clone()));
+        let pending = Committed::Pending {
+            tx: "restore".into(),
+        };
+        assert_eq!(
+            data.read().unwrap().get_key(&motd, &pending).unwrap(),
+            Some("\"old\"".to_string())
+        );
+
+        let response = delete_snapshot(query("name=before"), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let SnapshotListResponse(snapshots) = get_snapshots(data.clone()).await.unwrap();
+        assert!(snapshots.is_empty());
+
+        for (query_str, code) in &[("name=before", "SNAPSHOT_NOT_FOUND"), ("", "MISSING_INPUT")] {
+            match delete_snapshot(query(query_str), data.clone()).await {
+                Err(e) => assert_eq!(e.error_body().code, *code, "{}", query_str),
+                Ok(_) => panic!("Deleted snapshot for '{}'", query_str),
+            }
+        }
+        match create_snapshot(query("name=bad%20name"), data.clone()).await {
+            Err(e) => assert_eq!(e.error_body().code, "INVALID_KEY"),
+            Ok(_) => panic!("Invalid snapshot name was accepted"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn settings_mtimes_resource() {
+        let data = pending_datastore();
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let expected = data
+            .ds
+            .read()
+            .unwrap()
+            .get_key_mtime(&motd, &Committed::Live)
+            .unwrap()
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let ModifiedSettingsResponse(live) =
+            get_settings_mtimes(query(""), data.clone()).await.unwrap();
+        assert_eq!(
+            live,
+            btreemap!("settings.motd".to_string() => ModifiedSetting {
+                value: json!("old"),
+                modified_millis: expected,
+            })
+        );
+
+        let ModifiedSettingsResponse(pending) = get_settings_mtimes(query("state=pending"), data)
+            .await
+            .unwrap();
+        assert_eq!(pending["settings.updates.seed"].value, json!(42));
+    }
+
+    #[actix_rt::test]
+    async fn dump_resource() {
+        let data = pending_datastore();
+
+        let DumpResponse(live) = get_dump(query(""), data.clone()).await.unwrap();
+        assert_eq!(live, json!({"settings": {"motd": "old"}}));
+        let DumpResponse(pending) = get_dump(query("state=pending"), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(pending["settings"]["updates"], json!({"seed": 42}));
+
+        match get_dump(query("state=bogus"), data.clone()).await {
+            Err(error::Error::InvalidState { .. }) => {}
+            Err(e) => panic!("Expected InvalidState, got {}", e),
+            Ok(_) => panic!("Dump was returned for an invalid state"),
+        }
+    }
+
+    async fn load(
+        data: &web::Data<SharedDataStore<MemoryDataStore>>,
+        query_str: &str,
+        body: &str,
+    ) -> Result<HashSet<Key>> {
+        let ChangedKeysResponse(written) = post_dump(
+            TestRequest::default().to_http_request(),
+            payload(body),
+            query(query_str),
+            data.clone(),
+            web::Data::new(InputLimits::default()),
+        )
+        .await?;
+        Ok(written)
+    }
+
+    #[actix_rt::test]
+    async fn load_dump_resource() {
+        let data = pending_datastore();
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+
+        // A dump loads into a transaction to be committed as usual
+        let written = load(
+            &data,
+            "state=pending&tx=restore",
+            r#"{"settings": {"motd": "x"}}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(written, hashset!(motd.clone()));
+        let DumpResponse(restored) = get_dump(query("state=pending&tx=restore"), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(restored["settings"]["motd"], "x");
+
+        // Live keys are only replaced when asked
+        match load(&data, "", r#"{"settings": {"motd": "x"}}"#).await {
+            Err(error::Error::DumpConflicts { keys }) => assert_eq!(keys, "settings.motd"),
+            other => panic!("Expected DumpConflicts, got {:?}", other),
+        }
+        load(&data, "overwrite=true", r#"{"settings": {"motd": "x"}}"#)
+            .await
+            .unwrap();
+        let DumpResponse(live) = get_dump(query(""), data.clone()).await.unwrap();
+        assert_eq!(live, json!({"settings": {"motd": "x"}}));
+
+        match load(&data, "", "{not json").await {
+            Err(error::Error::DumpFormat { .. }) => {}
+            other => panic!("Expected DumpFormat, got {:?}", other),
+        }
+    }
+}
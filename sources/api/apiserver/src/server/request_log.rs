@@ -0,0 +1,342 @@
+//! The request_log module provides actix-web middleware that logs a line for each request, with
+//! its method, path, status, and latency, plus the number of settings keys read and written for
+//! settings operations.  Each request is given an ID, which is returned in the X-Request-Id
+//! response header so clients can find the server's log line for their request.
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{HttpMessage, HttpRequest};
+use futures::future;
+use log::{log, warn, Level};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// The response header holding the ID of the request.
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Where and how requests are logged.
+#[derive(Debug, Clone)]
+pub struct RequestLogConfig {
+    /// The log target for request lines, so they can be filtered separately from other logs.
+    pub target: String,
+    /// The level at which each request is logged.
+    pub level: Level,
+    /// Requests that take longer than this are also logged as a warning.
+    pub slow_threshold: Duration,
+}
+
+impl Default for RequestLogConfig {
+    fn default() -> Self {
+        Self {
+            target: "apiserver::requests".to_string(),
+            level: Level::Info,
+            slow_threshold: Duration::from_secs(1),
+        }
+    }
+}
+
+/// The number of settings keys a request read and wrote.  Handlers record these with
+/// `record_keys`, and they're included in the request's log line.
+#[derive(Debug, Clone, Default)]
+struct KeyCounts {
+    read: Rc<Cell<Option<usize>>>,
+    written: Rc<Cell<Option<usize>>>,
+}
+
+/// Records the number of settings keys the given request read and wrote, for its log line.
+pub(crate) fn record_keys(req: &HttpRequest, read: usize, written: usize) {
+    if let Some(counts) = req.extensions().get::<KeyCounts>() {
+        counts.read.set(Some(counts.read.get().unwrap_or(0) + read));
+        counts
+            .written
+            .set(Some(counts.written.get().unwrap_or(0) + written));
+    }
+}
+
+/// Makes a random ID for a request.
+fn request_id() -> String {
+    let mut bytes = [0u8; 8];
+    // The ID is only for correlating logs, so if we can't get random bytes, zeroes will do.
+    let _ = SystemRandom::new().fill(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Middleware that logs each request; see the module documentation.
+pub(crate) struct RequestLogger {
+    config: Rc<RequestLogConfig>,
+}
+
+impl RequestLogger {
+    pub(crate) fn new(config: RequestLogConfig) -> Self {
+        Self {
+            config: Rc::new(config),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for RequestLogger
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = RequestLoggerService<S>;
+    type Future = future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ready(Ok(RequestLoggerService {
+            service,
+            config: self.config.clone(),
+        }))
+    }
+}
+
+/// The service created by RequestLogger for each worker.
+pub(crate) struct RequestLoggerService<S> {
+    service: S,
+    config: Rc<RequestLogConfig>,
+}
+
+impl<S, B> Service for RequestLoggerService<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let id = request_id();
+        let method = req.method().to_string();
+        let path = req.path().to_string();
+        let counts = KeyCounts::default();
+        req.extensions_mut().insert(counts.clone());
+
+        let config = self.config.clone();
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let result = response.await;
+            let status = match &result {
+                Ok(response) => response.status().as_u16(),
+                Err(e) => e.as_response_error().status_code().as_u16(),
+            };
+            let line = RequestLine {
+                id: &id,
+                method: &method,
+                path: &path,
+                status,
+                latency: start.elapsed(),
+                counts: &counts,
+            };
+            log!(target: &config.target, config.level, "{}", line);
+            if line.latency > config.slow_threshold {
+                warn!(target: &config.target, "Slow request: {}", line);
+            }
+
+            let mut response = result?;
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// The log line for a request, in key=value form so it's easy to search and parse.
+struct RequestLine<'a> {
+    id: &'a str,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    latency: Duration,
+    counts: &'a KeyCounts,
+}
+
+impl std::fmt::Display for RequestLine<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "request-id={} method={} path={} status={} latency-ms={}",
+            self.id,
+            self.method,
+            self.path,
+            self.status,
+            self.latency.as_millis()
+        )?;
+        // Only settings operations count keys, so leave the counts out for other requests.
+        if let Some(read) = self.counts.read.get() {
+            write!(f, " keys-read={}", read)?;
+        }
+        if let Some(written) = self.counts.written.get() {
+            write!(f, " keys-written={}", written)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+    use std::sync::{Mutex, Once};
+
+    /// A logger that keeps the lines logged to our test target, so tests can check them.
+    struct CaptureLogger {
+        lines: Mutex<Vec<String>>,
+    }
+
+    const TARGET: &str = "request-log-test";
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+            metadata.target() == TARGET
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            if self.enabled(record.metadata()) {
+                let line = format!("{} {}", record.level(), record.args());
+                self.lines.lock().unwrap().push(line);
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Installs the capturing logger, once for all tests, and returns it.
+    fn logger() -> &'static CaptureLogger {
+        static INIT: Once = Once::new();
+        static mut LOGGER: Option<&'static CaptureLogger> = None;
+        unsafe {
+            INIT.call_once(|| {
+                let logger = Box::leak(Box::new(CaptureLogger {
+                    lines: Mutex::new(Vec::new()),
+                }));
+                log::set_logger(logger).unwrap();
+                log::set_max_level(log::LevelFilter::Trace);
+                LOGGER = Some(logger);
+            });
+            LOGGER.unwrap()
+        }
+    }
+
+    /// Returns the captured lines for the given request ID.
+    fn lines_for(id: &str) -> Vec<String> {
+        let needle = format!("request-id={} ", id);
+        logger()
+            .lines
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|line| line.contains(&needle))
+            .cloned()
+            .collect()
+    }
+
+    async fn settings_handler(req: HttpRequest) -> HttpResponse {
+        record_keys(&req, 3, 1);
+        HttpResponse::Ok().finish()
+    }
+
+    async fn other_handler() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_rt::test]
+    async fn logs_each_request_with_id() {
+        logger();
+        let config = RequestLogConfig {
+            target: TARGET.to_string(),
+            level: Level::Info,
+            slow_threshold: Duration::from_secs(60),
+        };
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RequestLogger::new(config))
+                .route("/settings", web::patch().to(settings_handler))
+                .route("/os", web::get().to(other_handler)),
+        )
+        .await;
+
+        let req = test::TestRequest::patch().uri("/settings").to_request();
+        let response = test::call_service(&mut app, req).await;
+        let id = response
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert_eq!(id.len(), 16);
+        let lines = lines_for(id);
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        assert!(lines[0].starts_with("INFO "), "{}", lines[0]);
+        assert!(lines[0].contains(" method=PATCH path=/settings status=200 "));
+        assert!(lines[0].ends_with(" keys-read=3 keys-written=1"));
+
+        // Requests that aren't settings operations don't have key counts, and each request has
+        // its own ID
+        let req = test::TestRequest::get().uri("/os").to_request();
+        let response = test::call_service(&mut app, req).await;
+        let other_id = response
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert_ne!(other_id, id);
+        let lines = lines_for(other_id);
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        assert!(!lines[0].contains("keys-"), "{}", lines[0]);
+    }
+
+    #[actix_rt::test]
+    async fn warns_about_slow_requests() {
+        logger();
+        let config = RequestLogConfig {
+            target: TARGET.to_string(),
+            level: Level::Debug,
+            slow_threshold: Duration::from_secs(0),
+        };
+        let mut app = test::init_service(App::new().wrap(RequestLogger::new(config)).route(
+            "/",
+            web::get().to(|| async {
+                std::thread::sleep(Duration::from_millis(5));
+                HttpResponse::NotFound().finish()
+            }),
+        ))
+        .await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        let response = test::call_service(&mut app, req).await;
+        let id = response
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let lines = lines_for(id);
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines[0].starts_with("DEBUG "), "{}", lines[0]);
+        assert!(lines[0].contains(" status=404 "), "{}", lines[0]);
+        assert!(lines[1].starts_with("WARN Slow request: "), "{}", lines[1]);
+    }
+}
@@ -0,0 +1,184 @@
+//! The schema module builds the OpenAPI document served at `/schema`.  The routes are described
+//! by hand in openapi.yaml, next to the code, and the schemas of the model types they accept and
+//! return are generated from the model, so they can't drift from what the server accepts.
+
+use super::error::{self, Result};
+use crate::datastore::{Key, KeyType};
+use model::{ConfigurationFiles, Services, Settings};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{Map, Value};
+use snafu::ResultExt;
+
+/// The hand-written description of the routes.
+const ROUTES: &str = include_str!("../../../openapi.yaml");
+
+/// Returns the OpenAPI document describing the API, with the schemas of the model filled in
+/// under `components`, where the routes refer to them.
+pub(crate) fn openapi_document() -> Result<Value> {
+    // YAML allows keys that aren't strings, like our response codes; converting to JSON makes
+    // them strings, as OpenAPI expects.
+    let routes: serde_yaml::Value = serde_yaml::from_str(ROUTES).context(error::RoutesDocument)?;
+    let mut document = serde_json::to_value(routes).context(error::ResponseSerialization)?;
+
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let mut schemas = Map::new();
+    add_schema::<Settings>(&mut gen, &mut schemas, "Settings")?;
+    add_schema::<Services>(&mut gen, &mut schemas, "Services")?;
+    add_schema::<ConfigurationFiles>(&mut gen, &mut schemas, "ConfigurationFiles")?;
+    for (name, schema) in gen.definitions() {
+        let schema = serde_json::to_value(schema).context(error::ResponseSerialization)?;
+        schemas.insert(name.clone(), schema);
+    }
+
+    if let Some(document) = document.as_object_mut() {
+        let mut components = Map::new();
+        components.insert("schemas".to_string(), Value::Object(schemas));
+        document.insert("components".to_string(), Value::Object(components));
+    }
+    Ok(document)
+}
+
+/// Adds the schema of T to `schemas` under the given name.  Structs are named by the generator,
+/// and added with the rest of its definitions, so this only adds types it doesn't name, like our
+/// maps of services and configuration files.
+fn add_schema<T: JsonSchema>(
+    gen: &mut SchemaGenerator,
+    schemas: &mut Map<String, Value>,
+    name: &str,
+) -> Result<()> {
+    let schema = gen.subschema_for::<T>();
+    if !T::is_referenceable() {
+        let schema = serde_json::to_value(schema).context(error::ResponseSerialization)?;
+        schemas.insert(name.to_string(), schema);
+    }
+    Ok(())
+}
+
+/// Returns the keys of the settings that are maps, like "settings.host-containers", whose entries
+/// are named by the user rather than the model.  Maps inside the entries of another map aren't
+/// included.
+pub(crate) fn settings_map_keys() -> Result<Vec<Key>> {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let root = gen.subschema_for::<Settings>();
+    let root = serde_json::to_value(root).context(error::ResponseSerialization)?;
+    let definitions =
+        serde_json::to_value(gen.definitions()).context(error::ResponseSerialization)?;
+
+    let mut paths = Vec::new();
+    find_maps(
+        &root,
+        &definitions,
+        &mut vec!["settings".to_string()],
+        &mut paths,
+    );
+    paths
+        .iter()
+        .map(|path| {
+            Key::from_segments(KeyType::Data, path).context(error::NewKey {
+                key_type: "data",
+                name: path.join("."),
+            })
+        })
+        .collect()
+}
+
+/// Walks a schema, recording in `maps` the path of each object that allows properties beyond
+/// the ones it names.  Model structs deny unknown fields, so those are our maps.
+fn find_maps(
+    schema: &Value,
+    definitions: &Value,
+    path: &mut Vec<String>,
+    maps: &mut Vec<Vec<String>>,
+) {
+    if let Some(reference) = schema.get("$ref").and_then(|r| r.as_str()) {
+        if let Some(name) = reference.rsplit('/').next() {
+            if let Some(definition) = definitions.get(name) {
+                find_maps(definition, definitions, path, maps);
+            }
+        }
+    }
+    for combinator in &["allOf", "anyOf", "oneOf"] {
+        if let Some(Value::Array(schemas)) = schema.get(*combinator) {
+            for subschema in schemas {
+                find_maps(subschema, definitions, path, maps);
+            }
+        }
+    }
+
+    match schema.get("additionalProperties") {
+        None | Some(Value::Bool(false)) => {}
+        Some(_) => {
+            if !maps.contains(path) {
+                maps.push(path.clone());
+            }
+            return;
+        }
+    }
+    if let Some(Value::Object(properties)) = schema.get("properties") {
+        for (name, property) in properties {
+            path.push(name.clone());
+            find_maps(property, definitions, path, maps);
+            path.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Returns the names referred to by "$ref"s anywhere in the value.
+    fn refs(value: &Value, found: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                for (k, v) in map {
+                    match (k.as_str(), v) {
+                        ("$ref", Value::String(r)) => found.push(r.clone()),
+                        _ => refs(v, found),
+                    }
+                }
+            }
+            Value::Array(values) => values.iter().for_each(|v| refs(v, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn every_ref_resolves() {
+        let document = openapi_document().unwrap();
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        for name in &[
+            "Settings",
+            "Services",
+            "ConfigurationFiles",
+            "Service",
+            "Url",
+        ] {
+            assert!(schemas.contains_key(*name), "no schema for {}", name);
+        }
+
+        let mut found = Vec::new();
+        refs(&document, &mut found);
+        assert!(!found.is_empty());
+        for r in found {
+            let name = r.trim_start_matches("#/components/schemas/");
+            assert!(schemas.contains_key(name), "unresolved $ref {}", r);
+        }
+
+        // Response codes are strings, as in JSON OpenAPI documents
+        assert!(document["paths"]["/settings"]["get"]["responses"]["200"].is_object());
+    }
+
+    #[test]
+    fn map_settings_found() {
+        let maps = settings_map_keys().unwrap();
+        let host_containers = Key::new(KeyType::Data, "settings.host-containers").unwrap();
+        assert!(maps.contains(&host_containers));
+        // Structs aren't maps, and neither are their fields
+        for name in &["settings", "settings.updates", "settings.motd"] {
+            let key = Key::new(KeyType::Data, name).unwrap();
+            assert!(!maps.contains(&key), "{} is not a map", name);
+        }
+    }
+}
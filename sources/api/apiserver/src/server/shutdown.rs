@@ -0,0 +1,231 @@
+//! The shutdown module lets the server stop gracefully, for example when it gets SIGTERM during
+//! an update.  Once shutdown starts, the server stops accepting connections and refuses new
+//! writes, and requests already in flight get a drain timeout to finish.  A commit that's already
+//! changing live settings always runs to completion, because `serve` waits for the data store
+//! lock before returning; if the process is killed anyway, the commit marker lets the next start
+//! detect the interrupted commit.
+//!
+//! The server doesn't handle signals itself; the caller of `serve` passes a ShutdownHook and
+//! calls `shutdown` on it when it should stop.
+
+use super::error;
+use actix_web::dev::{Server, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::Method;
+use futures::future;
+use log::info;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Stops a running server gracefully; see the module documentation.  Clones control the same
+/// server.
+#[derive(Clone)]
+pub struct ShutdownHook {
+    state: Arc<ShutdownState>,
+}
+
+struct ShutdownState {
+    drain_timeout: Duration,
+    /// Set once shutdown starts; after that, writes are refused.
+    closing: AtomicBool,
+    /// The running server, once `serve` has started it.
+    server: Mutex<Option<Server>>,
+    /// The number of requests currently being handled.
+    in_flight: AtomicUsize,
+    /// The number of requests that were being handled when shutdown started.
+    in_flight_at_shutdown: AtomicUsize,
+}
+
+impl Default for ShutdownHook {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(30))
+    }
+}
+
+impl ShutdownHook {
+    /// Creates a hook that gives in-flight requests up to `drain_timeout` to finish.  The
+    /// timeout is in whole seconds; anything less is rounded down.
+    pub fn new(drain_timeout: Duration) -> Self {
+        Self {
+            state: Arc::new(ShutdownState {
+                drain_timeout,
+                closing: AtomicBool::new(false),
+                server: Mutex::new(None),
+                in_flight: AtomicUsize::new(0),
+                in_flight_at_shutdown: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Starts shutting down the server.  This returns right away; `serve` returns once requests
+    /// have drained.  Calling it again has no effect.
+    pub fn shutdown(&self) {
+        if self.state.closing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let in_flight = self.state.in_flight.load(Ordering::SeqCst);
+        self.state
+            .in_flight_at_shutdown
+            .store(in_flight, Ordering::SeqCst);
+        info!(
+            "Shutting down; waiting up to {} seconds for {} in-flight requests",
+            self.state.drain_timeout.as_secs(),
+            in_flight
+        );
+        self.stop_server();
+    }
+
+    /// Returns the drain timeout, in the form actix wants it.
+    pub(crate) fn drain_timeout_secs(&self) -> u64 {
+        self.state.drain_timeout.as_secs()
+    }
+
+    /// Records the running server so `shutdown` can stop it.  If shutdown already started, the
+    /// server is stopped right away.
+    pub(crate) fn started(&self, server: Server) {
+        if let Ok(mut guard) = self.state.server.lock() {
+            *guard = Some(server);
+        }
+        if self.state.closing.load(Ordering::SeqCst) {
+            self.stop_server();
+        }
+    }
+
+    /// Logs how many of the requests in flight at shutdown finished.
+    pub(crate) fn log_drained(&self) {
+        let started = self.state.in_flight_at_shutdown.load(Ordering::SeqCst);
+        let unfinished = self.state.in_flight.load(Ordering::SeqCst).min(started);
+        info!(
+            "Drained {} of {} in-flight requests",
+            started - unfinished,
+            started
+        );
+    }
+
+    fn stop_server(&self) {
+        let server = match self.state.server.lock() {
+            Ok(mut guard) => guard.take(),
+            Err(_) => None,
+        };
+        if let Some(server) = server {
+            // The stop command is sent right away; the returned future only reports when the
+            // server has stopped, which `serve` already waits for.
+            drop(server.stop(true));
+        }
+    }
+}
+
+/// Middleware that counts requests in flight, and refuses requests that could change anything
+/// once shutdown has started.
+pub(crate) struct ShutdownGuard {
+    hook: ShutdownHook,
+}
+
+impl ShutdownGuard {
+    pub(crate) fn new(hook: ShutdownHook) -> Self {
+        Self { hook }
+    }
+}
+
+impl<S, B> Transform<S> for ShutdownGuard
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = ShutdownGuardService<S>;
+    type Future = future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ready(Ok(ShutdownGuardService {
+            service,
+            hook: self.hook.clone(),
+        }))
+    }
+}
+
+/// The service created by ShutdownGuard for each worker.
+pub(crate) struct ShutdownGuardService<S> {
+    service: S,
+    hook: ShutdownHook,
+}
+
+impl<S, B> Service for ShutdownGuardService<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let read_only = req.method() == Method::GET || req.method() == Method::HEAD;
+        if !read_only && self.hook.state.closing.load(Ordering::SeqCst) {
+            let response = req.error_response(error::Error::ShuttingDown);
+            return Box::pin(future::ready(Ok(response)));
+        }
+
+        let state = self.hook.state.clone();
+        state.in_flight.fetch_add(1, Ordering::SeqCst);
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let result = response.await;
+            // Requests cut off by the drain timeout never get here, so they aren't counted as
+            // drained.
+            state.in_flight.fetch_sub(1, Ordering::SeqCst);
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_rt::test]
+    async fn writes_refused_after_shutdown() {
+        let hook = ShutdownHook::new(Duration::from_secs(0));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(ShutdownGuard::new(hook.clone()))
+                .route("/", web::get().to(|| async { HttpResponse::Ok().finish() }))
+                .route(
+                    "/",
+                    web::patch().to(|| async { HttpResponse::NoContent().finish() }),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::patch().uri("/").to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        hook.shutdown();
+        let req = test::TestRequest::patch().uri("/").to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Reads are still allowed while requests drain
+        let req = test::TestRequest::get().uri("/").to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(hook.state.in_flight.load(Ordering::SeqCst), 0);
+    }
+}
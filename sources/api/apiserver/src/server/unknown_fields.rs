@@ -0,0 +1,422 @@
+//! This module finds settings in user input that aren't part of the settings model.  serde would
+//! reject them anyway, because the model denies unknown fields, but it stops at the first one and
+//! doesn't say where it was.  Here we find every unrecognized setting, so the user can fix them
+//! all at once, and suggest the setting they probably meant.
+//!
+//! We learn the model's fields the same way serde does - by deserializing into it.  FieldChecker
+//! is a Deserializer over JSON input that, whenever a struct tells us its field names, compares
+//! them to the keys of the input object and records any that don't match.  Maps, like
+//! host-containers, can have any keys, so only the structs inside them are checked.
+
+use serde::de::value::MapDeserializer;
+use serde::de::{DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::{forward_to_deserialize_any, Deserializer};
+use serde_json::{Number, Value};
+use std::fmt;
+use std::iter;
+
+/// A setting given in input that isn't part of the settings model.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct UnknownSetting {
+    /// Key segments leading to the setting, below the top-level "settings".
+    pub(crate) path: Vec<String>,
+    /// The full name of the known setting at the same level with the most similar name, if it's
+    /// similar enough to be a likely typo.
+    pub(crate) suggestion: Option<String>,
+}
+
+impl fmt::Display for UnknownSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", setting_name(&self.path))?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, " (did you mean '{}'?)", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns every field in the input that the type T doesn't have, outer fields first.
+///
+/// Other problems, like a value of the wrong type, are ignored here; they're reported when the
+/// input is really deserialized.  (They can stop us from looking further into the input, though,
+/// so in that case the list may not be complete.)
+pub(crate) fn find<T: DeserializeOwned>(input: &Value) -> Vec<UnknownSetting> {
+    let mut unknown = Vec::new();
+    let checker = FieldChecker {
+        value: input,
+        path: Vec::new(),
+        unknown: &mut unknown,
+    };
+    let _ = T::deserialize(checker);
+    unknown
+}
+
+/// Removes the value at the given path from a JSON object, if it's there.
+pub(crate) fn remove_from_json(value: &mut Value, path: &[String]) {
+    if let Some((last, parents)) = path.split_last() {
+        let mut object = value.as_object_mut();
+        for segment in parents {
+            object = object
+                .and_then(|map| map.get_mut(segment))
+                .and_then(|inner| inner.as_object_mut());
+        }
+        if let Some(map) = object {
+            map.remove(last);
+        }
+    }
+}
+
+/// Removes the value at the given path from a TOML table, if it's there.
+pub(crate) fn remove_from_toml(value: &mut toml::Value, path: &[String]) {
+    if let Some((last, parents)) = path.split_last() {
+        let mut table = value.as_table_mut();
+        for segment in parents {
+            table = table
+                .and_then(|map| map.get_mut(segment))
+                .and_then(|inner| inner.as_table_mut());
+        }
+        if let Some(map) = table {
+            map.remove(last);
+        }
+    }
+}
+
+/// Converts TOML input to JSON so it can be checked with `find`.  Datetimes become strings; the
+/// model doesn't have any, so they'll be rejected as the wrong type either way.
+pub(crate) fn toml_to_json(value: &toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s.clone()),
+        toml::Value::Integer(i) => Value::Number((*i).into()),
+        toml::Value::Float(f) => Number::from_f64(*f).map_or(Value::Null, Value::Number),
+        toml::Value::Boolean(b) => Value::Bool(*b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(array) => Value::Array(array.iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .iter()
+                .map(|(k, v)| (k.clone(), toml_to_json(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// Input names at most this far from a known field name, or a third of the input name's length if
+/// that's larger, are treated as likely typos of it.
+const SUGGESTION_MIN_DISTANCE: usize = 2;
+
+/// Returns the known field most similar to the given unknown one, if it's similar enough.
+fn suggest<'a>(name: &str, fields: &[&'a str]) -> Option<&'a str> {
+    let max_distance = SUGGESTION_MIN_DISTANCE.max(name.chars().count() / 3);
+    fields
+        .iter()
+        .map(|field| (edit_distance(name, field), *field))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, field)| field)
+}
+
+/// Returns the Levenshtein distance between two strings - the number of single-character
+/// insertions, deletions, and substitutions it takes to turn one into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // row[j] is the distance between the part of `a` seen so far and the first j chars of `b`.
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = diagonal + if a_char == *b_char { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Returns the user-facing name of the setting at the given path, e.g. "settings.ntp.enabled".
+fn setting_name<S: AsRef<str>>(path: &[S]) -> String {
+    let mut name = "settings".to_string();
+    for segment in path {
+        name.push('.');
+        name.push_str(segment.as_ref());
+    }
+    name
+}
+
+/// Deserializes a JSON value, recording input fields that the target structs don't have.
+struct FieldChecker<'de, 'u> {
+    value: &'de Value,
+    /// Key segments leading to `value`.
+    path: Vec<String>,
+    unknown: &'u mut Vec<UnknownSetting>,
+}
+
+impl<'de, 'u> Deserializer<'de> for FieldChecker<'de, 'u> {
+    type Error = serde_json::Error;
+
+    /// Scalars and lists don't contain structs in the model, so we let serde_json handle them.
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.value.deserialize_any(visitor)
+    }
+
+    /// This is where we learn the field names the struct expects.  We record the input fields
+    /// that aren't among them, and leave them out of what we give the struct, so we can keep
+    /// looking for more.
+    fn deserialize_struct<V>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        let map = match self.value {
+            Value::Object(map) => map,
+            // A null asks for the setting to be removed, so there are no fields to check; we
+            // give the struct an empty map rather than fail and stop looking.
+            Value::Null => {
+                return visitor.visit_map(MapDeserializer::new(iter::empty::<((), ())>()))
+            }
+            _ => return self.value.deserialize_struct(name, fields, visitor),
+        };
+
+        for key in map.keys() {
+            if !fields.contains(&key.as_str()) {
+                let suggestion = suggest(key, fields).map(|field| {
+                    let mut path = self.path.clone();
+                    path.push(field.to_string());
+                    setting_name(&path)
+                });
+                let mut path = self.path.clone();
+                path.push(key.clone());
+                self.unknown.push(UnknownSetting { path, suggestion });
+            }
+        }
+
+        visitor.visit_map(CheckedMap {
+            entries: map.iter(),
+            fields: Some(fields),
+            path: self.path,
+            unknown: self.unknown,
+            pending: None,
+        })
+    }
+
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            Value::Object(map) => visitor.visit_map(CheckedMap {
+                entries: map.iter(),
+                fields: None,
+                path: self.path,
+                unknown: self.unknown,
+                pending: None,
+            }),
+            _ => self.value.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        if self.value.is_null() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.value.deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct identifier ignored_any
+    }
+}
+
+/// Gives the entries of a JSON object to a struct or map, checking the values in turn.  For
+/// structs, `fields` holds the known field names, and entries for any others are skipped.
+struct CheckedMap<'de, 'u> {
+    entries: serde_json::map::Iter<'de>,
+    fields: Option<&'static [&'static str]>,
+    path: Vec<String>,
+    unknown: &'u mut Vec<UnknownSetting>,
+    /// The entry whose key we just returned, so we can return its value next.
+    pending: Option<(&'de String, &'de Value)>,
+}
+
+impl<'de, 'u> MapAccess<'de> for CheckedMap<'de, 'u> {
+    type Error = serde_json::Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        let fields = self.fields;
+        let known = |key: &str| fields.map_or(true, |fields| fields.contains(&key));
+        match self.entries.find(|(key, _)| known(key)) {
+            Some((key, value)) => {
+                self.pending = Some((key, value));
+                seed.deserialize(key.as_str().into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        // serde only asks for a value after getting its key.
+        let (key, value) = self
+            .pending
+            .take()
+            .expect("next_value_seed called before next_key_seed");
+        let mut path = self.path.clone();
+        path.push(key.clone());
+        seed.deserialize(FieldChecker {
+            value,
+            path,
+            unknown: &mut *self.unknown,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields, rename_all = "kebab-case")]
+    struct Top {
+        motd: Option<String>,
+        time_servers: Option<Vec<String>>,
+        hosts: Option<HashMap<String, Inner>>,
+        inner: Option<Inner>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields, rename_all = "kebab-case")]
+    struct Inner {
+        enabled: Option<bool>,
+        source: Option<String>,
+    }
+
+    fn names(unknown: &[UnknownSetting]) -> Vec<String> {
+        unknown.iter().map(|u| u.to_string()).collect()
+    }
+
+    #[test]
+    fn known_fields_pass() {
+        let input = json!({
+            "motd": "hi",
+            "time-servers": ["a", "b"],
+            "hosts": {"admin": {"enabled": true}, "anything": {}},
+            "inner": {"source": "x"},
+        });
+        assert_eq!(find::<Top>(&input), vec![]);
+    }
+
+    #[test]
+    fn unknown_fields_found_everywhere() {
+        let input = json!({
+            "motdd": "hi",
+            "bogus": 1,
+            "hosts": {"admin": {"enabld": true}},
+            "inner": {"sourc": "x", "enabled": false},
+        });
+        assert_eq!(
+            names(&find::<Top>(&input)),
+            vec![
+                "settings.bogus",
+                "settings.motdd (did you mean 'settings.motd'?)",
+                "settings.hosts.admin.enabld (did you mean 'settings.hosts.admin.enabled'?)",
+                "settings.inner.sourc (did you mean 'settings.inner.source'?)",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_fields_found_past_nulls() {
+        let input = json!({
+            "inner": null,
+            "hosts": {"admin": null, "other": {"enabld": true}},
+            "bogus": null,
+        });
+        assert_eq!(
+            names(&find::<Top>(&input)),
+            vec![
+                "settings.bogus",
+                "settings.hosts.other.enabld (did you mean 'settings.hosts.other.enabled'?)",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_fields_removed() {
+        let mut input = json!({"motd": "hi", "inner": {"sourc": "x", "enabled": false}});
+        let unknown = find::<Top>(&input);
+        for setting in &unknown {
+            remove_from_json(&mut input, &setting.path);
+        }
+        assert_eq!(input, json!({"motd": "hi", "inner": {"enabled": false}}));
+
+        let mut input: toml::Value =
+            toml::from_str("motd = \"hi\"\n[inner]\nsourc = \"x\"").unwrap();
+        let unknown = find::<Top>(&toml_to_json(&input));
+        for setting in &unknown {
+            remove_from_toml(&mut input, &setting.path);
+        }
+        assert_eq!(
+            input,
+            toml::from_str::<toml::Value>("motd = \"hi\"\n[inner]").unwrap()
+        );
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("motd", "motd"), 0);
+        assert_eq!(edit_distance("motdd", "motd"), 1);
+        assert_eq!(edit_distance("hostnme", "hostname"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+
+        assert_eq!(
+            suggest("tme-servers", &["motd", "time-servers"]),
+            Some("time-servers")
+        );
+        assert_eq!(suggest("bogus", &["motd", "time-servers"]), None);
+    }
+}
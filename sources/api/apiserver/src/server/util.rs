@@ -0,0 +1,289 @@
+//! The util module has helpers for shaping JSON responses for the client: middleware that
+//! pretty-prints them when the request has `pretty=true`, and pruning of a response down to the
+//! fields a client asked for.
+
+use actix_web::dev::{Body, ResponseBody, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use actix_web::web;
+use futures::future;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Returns the parts of `value` at the given paths, keeping their place in its structure, or an
+/// empty object if none of them are in it.  Each path is a list of object keys; where a path
+/// reaches an array, the rest of it is applied to each element.  If one path is inside another,
+/// the whole of the outer one is kept.
+pub(crate) fn prune(value: &Value, paths: &[Vec<String>]) -> Value {
+    let paths: Vec<&[String]> = paths.iter().map(|path| path.as_slice()).collect();
+    prune_paths(value, &paths).unwrap_or_else(|| Value::Object(Map::new()))
+}
+
+/// Does the work of `prune`, returning None if none of the paths are in the value.
+fn prune_paths(value: &Value, paths: &[&[String]]) -> Option<Value> {
+    // A path that ends here selects the whole value.
+    if paths.iter().any(|path| path.is_empty()) {
+        return Some(value.clone());
+    }
+
+    match value {
+        Value::Object(map) => {
+            let mut pruned = Map::new();
+            for (key, inner) in map {
+                let rest: Vec<&[String]> = paths
+                    .iter()
+                    .filter(|path| path[0] == *key)
+                    .map(|path| &path[1..])
+                    .collect();
+                if rest.is_empty() {
+                    continue;
+                }
+                if let Some(inner) = prune_paths(inner, &rest) {
+                    pruned.insert(key.clone(), inner);
+                }
+            }
+            if pruned.is_empty() {
+                None
+            } else {
+                Some(Value::Object(pruned))
+            }
+        }
+        Value::Array(values) => {
+            let pruned: Vec<Value> = values
+                .iter()
+                .filter_map(|inner| prune_paths(inner, paths))
+                .collect();
+            if pruned.is_empty() {
+                None
+            } else {
+                Some(Value::Array(pruned))
+            }
+        }
+        // The path goes further, but there's nothing inside a scalar.
+        _ => None,
+    }
+}
+
+/// Middleware that pretty-prints JSON response bodies, including error responses, when the
+/// request has the `pretty=true` query parameter.  Handlers don't need to know about it.
+pub(crate) struct PrettyJson;
+
+impl<S> Transform<S> for PrettyJson
+where
+    S: Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse<Body>,
+        Error = actix_web::Error,
+    >,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = PrettyJsonService<S>;
+    type Future = future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ready(Ok(PrettyJsonService { service }))
+    }
+}
+
+/// The service created by PrettyJson for each worker.
+pub(crate) struct PrettyJsonService<S> {
+    service: S,
+}
+
+impl<S> Service for PrettyJsonService<S>
+where
+    S: Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse<Body>,
+        Error = actix_web::Error,
+    >,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = actix_web::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        // If the query string can't be parsed, the handler will say so.
+        let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok();
+        let pretty = match query
+            .as_ref()
+            .map(|query| super::bool_param(query, "pretty"))
+        {
+            Some(Ok(pretty)) => pretty,
+            Some(Err(e)) => return Box::pin(future::ok(req.error_response(e))),
+            None => false,
+        };
+        if !pretty {
+            return Box::pin(self.service.call(req));
+        }
+
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let response = response.await?;
+            Ok(response.map_body(|head, body| {
+                let is_json = head
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.starts_with("application/json"))
+                    .unwrap_or(false);
+                match body {
+                    ResponseBody::Body(Body::Bytes(bytes)) if is_json => {
+                        ResponseBody::Body(Body::Bytes(pretty_print(&bytes).unwrap_or(bytes)))
+                    }
+                    other => other,
+                }
+            }))
+        })
+    }
+}
+
+/// Reformats a JSON document for humans, or returns None if it isn't valid JSON.
+fn pretty_print(bytes: &[u8]) -> Option<web::Bytes> {
+    let value: Value = serde_json::from_slice(bytes).ok()?;
+    let mut pretty = serde_json::to_vec_pretty(&value).ok()?;
+    pretty.push(b'\n');
+    Some(pretty.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, HttpResponse};
+    use serde_json::json;
+
+    fn paths(fields: &[&str]) -> Vec<Vec<String>> {
+        fields
+            .iter()
+            .map(|field| field.split('.').map(String::from).collect())
+            .collect()
+    }
+
+    fn settings() -> Value {
+        json!({
+            "motd": "hi",
+            "ntp": {"time-servers": ["a", "b"]},
+            "host-containers": {
+                "admin": {"enabled": false, "source": "admin-image"},
+                "control": {"enabled": true, "source": "control-image"},
+            },
+        })
+    }
+
+    #[test]
+    fn prune_nested_paths() {
+        assert_eq!(
+            prune(
+                &settings(),
+                &paths(&["motd", "host-containers.admin.enabled"])
+            ),
+            json!({"motd": "hi", "host-containers": {"admin": {"enabled": false}}})
+        );
+        assert_eq!(
+            prune(&settings(), &paths(&["ntp"])),
+            json!({"ntp": {"time-servers": ["a", "b"]}})
+        );
+
+        // Paths that aren't there are left out
+        assert_eq!(
+            prune(&settings(), &paths(&["motd.x", "kubernetes.cluster-name"])),
+            json!({})
+        );
+    }
+
+    #[test]
+    fn prune_arrays() {
+        let value = json!({
+            "containers": [
+                {"name": "a", "image": "x"},
+                {"name": "b"},
+                {"image": "y"},
+            ],
+            "servers": ["a", "b"],
+        });
+        // The rest of the path applies to each element, and elements without it are left out
+        assert_eq!(
+            prune(&value, &paths(&["containers.name"])),
+            json!({"containers": [{"name": "a"}, {"name": "b"}]})
+        );
+        assert_eq!(
+            prune(&value, &paths(&["servers"])),
+            json!({"servers": ["a", "b"]})
+        );
+        assert_eq!(prune(&value, &paths(&["servers.a"])), json!({}));
+    }
+
+    #[test]
+    fn prune_overlapping_paths() {
+        // An outer path keeps everything under it, whatever order they're given in
+        for fields in &[
+            ["host-containers", "host-containers.admin.enabled"],
+            ["host-containers.admin.enabled", "host-containers"],
+        ] {
+            assert_eq!(
+                prune(&settings(), &paths(fields)),
+                json!({"host-containers": settings()["host-containers"]})
+            );
+        }
+
+        // Sibling paths are merged
+        assert_eq!(
+            prune(
+                &settings(),
+                &paths(&[
+                    "host-containers.admin.enabled",
+                    "host-containers.admin.source",
+                    "host-containers.control.enabled",
+                    "host-containers.admin.enabled",
+                ])
+            ),
+            json!({"host-containers": {
+                "admin": {"enabled": false, "source": "admin-image"},
+                "control": {"enabled": true},
+            }})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn pretty_json_on_request() {
+        let mut app = test::init_service(App::new().wrap(PrettyJson).route(
+            "/settings",
+            web::get().to(|| async {
+                HttpResponse::Ok()
+                    .content_type("application/json")
+                    .body(r#"{"motd":"hi"}"#)
+            }),
+        ))
+        .await;
+
+        for (uri, expected) in &[
+            ("/settings", "{\"motd\":\"hi\"}"),
+            ("/settings?pretty=false", "{\"motd\":\"hi\"}"),
+            ("/settings?pretty=true", "{\n  \"motd\": \"hi\"\n}\n"),
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let body = test::read_response(&mut app, req).await;
+            assert_eq!(std::str::from_utf8(&body).unwrap(), *expected);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/settings?pretty=yes")
+            .to_request();
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -1,10 +1,38 @@
 #![deny(rust_2018_idioms)]
 
 use rand::{thread_rng, Rng};
+use ring::digest::{digest, SHA256};
+use std::convert::TryInto;
+use std::fs;
+
+/// Seeds range from 0 up to, but not including, this.
+const SEED_RANGE: u32 = 2048;
+
+/// The file holding the host's machine ID, which the seed is derived from.
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
+
+/// Derives a seed from a host identifier: the first four bytes of its SHA-256, as a big-endian
+/// number, modulo the seed range.  The same host always gets the same seed, and operators can
+/// work out which seed, and so which update wave, a host has from its ID.
+fn seed_for(id: &str) -> u32 {
+    let hash = digest(&SHA256, id.trim().as_bytes());
+    let prefix: [u8; 4] = hash.as_ref()[..4]
+        .try_into()
+        .expect("SHA-256 is shorter than 4 bytes");
+    u32::from_be_bytes(prefix) % SEED_RANGE
+}
 
 fn main() {
-    let mut rng = thread_rng();
-    let val = rng.gen_range(0, 2048);
+    let val = match fs::read_to_string(MACHINE_ID_PATH) {
+        Ok(id) if !id.trim().is_empty() => seed_for(&id),
+        _ => {
+            eprintln!(
+                "Unable to read machine ID from {}, using a random seed",
+                MACHINE_ID_PATH
+            );
+            thread_rng().gen_range(0, SEED_RANGE)
+        }
+    };
 
     // sundog expects JSON-serialized output so that many types can be represented, allowing the
     // API model to use more accurate types.
@@ -12,3 +40,18 @@ fn main() {
 
     println!("{}", output);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seed_stable() {
+        let id = "4a1c7f5e0f6b4c0d9e3f2a1b0c9d8e7f\n";
+        assert_eq!(seed_for(id), seed_for(id));
+        assert_eq!(seed_for(id), seed_for(id.trim()));
+        // The SHA-256 of the ID starts with 9c729104, and 0x9c729104 % 2048 is 260.
+        assert_eq!(seed_for(id), 260);
+        assert_eq!(seed_for("0f9e8d7c6b5a49382716a5b4c3d2e1f0"), 793);
+    }
+}
@@ -35,25 +35,23 @@ pub(crate) fn get_input_data<D: DataStore>(
         data.insert(key_name.clone(), value);
     }
 
-    // Metadata isn't committed, it goes live immediately, so we only populate the metadata
-    // output for Committed::Live.
+    // Metadata can be staged in a transaction alongside data, so we pull it from the same
+    // place as the data.
     let mut metadata = HashMap::new();
-    if let Committed::Live = committed {
-        let raw_metadata = datastore
-            .get_metadata_prefix("", &None as &Option<&str>)
-            .context(error::GetMetadata)?;
-        for (data_key, meta_map) in raw_metadata.into_iter() {
-            // See notes above about storing key Strings and Values.
-            let data_key_name = data_key.name();
-            let data_entry = metadata
-                .entry(data_key_name.clone())
-                .or_insert_with(HashMap::new);
-            for (metadata_key, value_str) in meta_map.into_iter() {
-                let metadata_key_name = metadata_key.name();
-                let value = deserialize_scalar(&value_str)
-                    .context(error::Deserialize { input: value_str })?;
-                data_entry.insert(metadata_key_name.clone(), value);
-            }
+    let raw_metadata = datastore
+        .get_metadata_prefix("", &None as &Option<&str>, committed)
+        .context(error::GetMetadata)?;
+    for (data_key, meta_map) in raw_metadata.into_iter() {
+        // See notes above about storing key Strings and Values.
+        let data_key_name = data_key.name();
+        let data_entry = metadata
+            .entry(data_key_name.clone())
+            .or_insert_with(HashMap::new);
+        for (metadata_key, value_str) in meta_map.into_iter() {
+            let metadata_key_name = metadata_key.name();
+            let value =
+                deserialize_scalar(&value_str).context(error::Deserialize { input: value_str })?;
+            data_entry.insert(metadata_key_name.clone(), value);
         }
     }
 
@@ -101,7 +99,7 @@ pub(crate) fn set_output_data<D: DataStore>(
                 })?;
             let value = serialize_scalar(&raw_value).context(error::Serialize)?;
             datastore
-                .set_metadata(&metadata_key, &data_key, value)
+                .set_metadata(&metadata_key, &data_key, value, committed)
                 .context(error::DataStoreWrite)?;
         }
     }
@@ -45,6 +45,23 @@ mod error {
             template: String,
         },
 
+        #[snafu(display(
+            "Helper '{}' in template '{}' expected {} for parameter {}, got '{}'",
+            helper,
+            template,
+            expected,
+            position,
+            value,
+        ))]
+        InvalidParam {
+            helper: String,
+            template: String,
+            /// The parameter's position, counting from 1.
+            position: usize,
+            expected: &'static str,
+            value: handlebars::JsonValue,
+        },
+
         #[snafu(display(
             "Missing data and fail-if-missing was set; see given line/col in template '{}'",
             template,
@@ -80,6 +97,12 @@ mod error {
             template: String,
             source: std::io::Error,
         },
+
+        #[snafu(display("Unable to JSON encode value in template '{}': {}", template, source))]
+        JsonEncode {
+            template: String,
+            source: serde_json::Error,
+        },
     }
 
     // Handlebars helpers are required to return a RenderError.
@@ -422,6 +445,218 @@ pub fn default(
     Ok(())
 }
 
+/// `join` joins the values of a list with the given string, for example when you're writing a
+/// list of servers on one line of a configuration file.
+///
+/// The first parameter is the string to join values with; the second parameter is the list, whose
+/// values must be scalars.
+///
+/// Example:
+///    {{ join "," settings.ntp.time-servers }}
+///    ...where `time-servers` is: ["a.example.com", "b.example.com"]
+///    ...will produce: "a.example.com,b.example.com"
+pub fn join(
+    helper: &Helper<'_, '_>,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    trace!("Starting join helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, &template_name, 2)?;
+
+    let separator_val = get_param(helper, 0)?;
+    let separator = separator_val
+        .as_str()
+        .ok_or_else(|| invalid_param(helper, &template_name, 0, "string", separator_val))?;
+    trace!("String used to join values: {}", separator);
+
+    let list_val = get_param(helper, 1)?;
+    let list = list_val
+        .as_array()
+        .ok_or_else(|| invalid_param(helper, &template_name, 1, "list", list_val))?;
+    let mut values = Vec::new();
+    for value in list {
+        let value = scalar_string(value)
+            .ok_or_else(|| invalid_param(helper, &template_name, 1, "list of scalars", list_val))?;
+        values.push(value);
+    }
+    let joined = values.join(separator);
+    trace!("Joined output: {}", joined);
+
+    out.write(&joined).context(error::TemplateWrite {
+        template: template_name,
+    })?;
+    Ok(())
+}
+
+/// `base64_encode` base64 encodes text at template render time, for example to write a
+/// certificate bundle into a configuration file that expects it encoded.
+/// It takes a single variable as a parameter: {{base64_encode var}}
+pub fn base64_encode(
+    helper: &Helper<'_, '_>,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    trace!("Starting base64_encode helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, &template_name, 1)?;
+
+    let value = get_param(helper, 0)?;
+    let text = value
+        .as_str()
+        .ok_or_else(|| invalid_param(helper, &template_name, 0, "string", value))?;
+    let encoded = base64::encode(text);
+    trace!("Encoded base64: {}", encoded);
+
+    out.write(&encoded).context(error::TemplateWrite {
+        template: template_name,
+    })?;
+    Ok(())
+}
+
+/// `toml_escape` escapes text so it can be written inside a quoted TOML string, for example
+/// when a setting might contain quotes or backslashes.  The quotes aren't included, so they go in
+/// the template: key = "{{toml_escape var}}"
+pub fn toml_escape(
+    helper: &Helper<'_, '_>,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    trace!("Starting toml_escape helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, &template_name, 1)?;
+
+    let value = get_param(helper, 0)?;
+    let text = value
+        .as_str()
+        .ok_or_else(|| invalid_param(helper, &template_name, 0, "string", value))?;
+
+    // These are the escapes allowed in TOML basic strings; other control characters must be
+    // given by code point.
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\u{8}' => escaped.push_str("\\b"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\u{c}' => escaped.push_str("\\f"),
+            '\r' => escaped.push_str("\\r"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    trace!("Escaped output: {}", escaped);
+
+    out.write(&escaped).context(error::TemplateWrite {
+        template: template_name,
+    })?;
+    Ok(())
+}
+
+/// `json_encode` writes a value as JSON, for example to put a list or map of settings into a
+/// JSON configuration file.  Strings are written with their quotes.
+/// It takes a single variable as a parameter: {{json_encode var}}
+pub fn json_encode(
+    helper: &Helper<'_, '_>,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    trace!("Starting json_encode helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, &template_name, 1)?;
+
+    let value = get_param(helper, 0)?;
+    let encoded = serde_json::to_string(value).context(error::JsonEncode {
+        template: template_name.to_owned(),
+    })?;
+    trace!("Encoded JSON: {}", encoded);
+
+    out.write(&encoded).context(error::TemplateWrite {
+        template: template_name,
+    })?;
+    Ok(())
+}
+
+/// Returns the name of the template being rendered, to give context to errors.
+fn template_name(renderctx: &RenderContext<'_, '_>) -> String {
+    let template_name = renderctx
+        .get_root_template_name()
+        .map(|i| i.to_string())
+        .unwrap_or_else(|| "dynamic template".to_string());
+    trace!("Template name: {}", &template_name);
+    template_name
+}
+
+/// Fails unless the helper was given exactly the expected number of parameters.
+fn check_param_count(
+    helper: &Helper<'_, '_>,
+    template_name: &str,
+    expected: u8,
+) -> Result<(), RenderError> {
+    trace!("Number of params: {}", helper.params().len());
+    if helper.params().len() != usize::from(expected) {
+        return Err(RenderError::from(
+            error::TemplateHelperError::IncorrectNumberOfParams {
+                expected,
+                received: helper.params().len(),
+                helper: helper.name().to_string(),
+                template: template_name.to_string(),
+            },
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the value of the parameter at the given index, after its presence was confirmed by
+/// check_param_count.
+fn get_param<'a>(helper: &'a Helper<'_, '_>, index: usize) -> Result<&'a Value, RenderError> {
+    helper
+        .param(index)
+        .map(|v| v.value())
+        .context(error::Internal {
+            msg: "Missing param after confirming there are enough",
+        })
+        .map_err(RenderError::from)
+}
+
+/// Returns the error for a parameter of the wrong type, naming the helper and the parameter.
+fn invalid_param(
+    helper: &Helper<'_, '_>,
+    template_name: &str,
+    index: usize,
+    expected: &'static str,
+    value: &Value,
+) -> RenderError {
+    RenderError::from(error::TemplateHelperError::InvalidParam {
+        helper: helper.name().to_string(),
+        template: template_name.to_string(),
+        position: index + 1,
+        expected,
+        value: value.to_owned(),
+    })
+}
+
+/// Returns the Display form of a scalar, rather than its JSON form, so strings aren't quoted, or
+/// None if the value isn't a scalar.
+fn scalar_string(value: &Value) -> Option<String> {
+    match value {
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::String(s) => Some(s.to_string()),
+        Value::Null | Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
 #[cfg(test)]
 mod test_base64_decode {
     use super::*;
@@ -662,3 +897,183 @@ mod test_default {
         assert_eq!(result, "true")
     }
 }
+
+#[cfg(test)]
+mod test_registry {
+    use crate::build_template_registry;
+    use handlebars::TemplateRenderError;
+    use serde::Serialize;
+
+    // Renders the template with the registry the binaries use, so we test helpers as they're
+    // registered there.
+    pub(super) fn setup_and_render_template<T>(
+        tmpl: &str,
+        data: &T,
+    ) -> Result<String, TemplateRenderError>
+    where
+        T: Serialize,
+    {
+        let registry = build_template_registry().unwrap();
+        registry.render_template(tmpl, data)
+    }
+}
+
+#[cfg(test)]
+mod test_join {
+    use super::test_registry::setup_and_render_template;
+    use crate::build_template_registry;
+    use serde_json::json;
+
+    #[test]
+    fn strings() {
+        let result = setup_and_render_template(
+            "{{join \",\" servers}}",
+            &json!({"servers": ["a.example.com", "b.example.com"]}),
+        )
+        .unwrap();
+        assert_eq!(result, "a.example.com,b.example.com")
+    }
+
+    #[test]
+    fn scalars() {
+        let result =
+            setup_and_render_template("{{join \" \" list}}", &json!({"list": ["a", 1, true]}))
+                .unwrap();
+        assert_eq!(result, "a 1 true")
+    }
+
+    #[test]
+    fn empty() {
+        let result =
+            setup_and_render_template("{{join \",\" list}}", &json!({"list": []})).unwrap();
+        assert_eq!(result, "")
+    }
+
+    #[test]
+    fn not_a_list() {
+        for data in &[json!({"list": "a,b"}), json!({"list": {"a": "b"}})] {
+            setup_and_render_template("{{join \",\" list}}", data).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn nested_list() {
+        setup_and_render_template("{{join \",\" list}}", &json!({"list": [["a"]]})).unwrap_err();
+    }
+
+    #[test]
+    fn error_names_template_helper_and_param() {
+        let mut registry = build_template_registry().unwrap();
+        registry
+            .register_template_string("chrony-conf", "{{join \",\" list}}")
+            .unwrap();
+        let err = registry
+            .render("chrony-conf", &json!({"list": "a"}))
+            .unwrap_err()
+            .to_string();
+        for expected in &["'join'", "'chrony-conf'", "parameter 2", "list"] {
+            assert!(
+                err.contains(expected),
+                "'{}' not in error: {}",
+                expected,
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_base64_encode {
+    use super::test_registry::setup_and_render_template;
+    use serde_json::json;
+
+    #[test]
+    fn renders_encoded_base64() {
+        let result =
+            setup_and_render_template("{{base64_encode var}}", &json!({"var": "Hi"})).unwrap();
+        assert_eq!(result, "SGk=")
+    }
+
+    #[test]
+    fn round_trip() {
+        let result = setup_and_render_template(
+            "{{base64_decode (base64_encode var)}}",
+            &json!({"var": "-----BEGIN CERTIFICATE-----\n"}),
+        )
+        .unwrap();
+        assert_eq!(result, "-----BEGIN CERTIFICATE-----\n")
+    }
+
+    #[test]
+    fn not_a_string() {
+        setup_and_render_template("{{base64_encode var}}", &json!({"var": 42})).unwrap_err();
+    }
+
+    #[test]
+    fn extra_param() {
+        setup_and_render_template("{{base64_encode var var}}", &json!({"var": "Hi"})).unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod test_toml_escape {
+    use super::test_registry::setup_and_render_template;
+    use serde_json::json;
+
+    #[test]
+    fn plain() {
+        let result =
+            setup_and_render_template("key = \"{{toml_escape var}}\"", &json!({"var": "hi there"}))
+                .unwrap();
+        assert_eq!(result, "key = \"hi there\"")
+    }
+
+    #[test]
+    fn escapes() {
+        let result = setup_and_render_template(
+            "{{toml_escape var}}",
+            &json!({"var": "say \"hi\"\\\n\tend\u{1}"}),
+        )
+        .unwrap();
+        assert_eq!(result, r#"say \"hi\"\\\n\tend\u0001"#)
+    }
+
+    #[test]
+    fn not_a_string() {
+        setup_and_render_template("{{toml_escape var}}", &json!({"var": ["a"]})).unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod test_json_encode {
+    use super::test_registry::setup_and_render_template;
+    use serde_json::json;
+
+    #[test]
+    fn scalars() {
+        for (value, expected) in &[
+            (json!("say \"hi\""), r#""say \"hi\"""#),
+            (json!(42), "42"),
+            (json!(true), "true"),
+        ] {
+            let result =
+                setup_and_render_template("{{json_encode var}}", &json!({ "var": value })).unwrap();
+            assert_eq!(result, *expected)
+        }
+    }
+
+    #[test]
+    fn composites() {
+        let result = setup_and_render_template(
+            "{{json_encode var}}",
+            &json!({"var": {"servers": ["a", "b"]}}),
+        )
+        .unwrap();
+        assert_eq!(result, r#"{"servers":["a","b"]}"#)
+    }
+
+    #[test]
+    fn missing_param() {
+        setup_and_render_template("{{json_encode}}", &json!({"var": "a"})).unwrap_err();
+    }
+}
@@ -6,9 +6,11 @@
 extern crate log;
 
 mod helpers;
+mod retry;
 
 use handlebars::Handlebars;
 use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use snafu::ResultExt;
 use std::path::Path;
 
@@ -26,12 +28,19 @@ pub mod error {
             source: apiclient::Error,
         },
 
-        #[snafu(display("Error {} when {}ing to {}: {}", code, method, uri, response_body))]
+        #[snafu(display(
+            "Error {} when {}ing to {}: {}: {}",
+            status,
+            method,
+            uri,
+            error.code,
+            error.message
+        ))]
         APIResponse {
             method: String,
             uri: String,
-            code: StatusCode,
-            response_body: String,
+            status: StatusCode,
+            error: super::ErrorResponse,
         },
 
         #[snafu(display(
@@ -48,15 +57,54 @@ pub mod error {
     }
 }
 pub use error::Error;
+pub use retry::RetryPolicy;
 type Result<T> = std::result::Result<T, error::Error>;
 
+impl Error {
+    /// Returns the API's code for the error, if the API responded with one, so callers can tell
+    /// the kind of error, for example SETTINGS_NOT_FOUND or DATASTORE_CORRUPT.
+    pub fn api_error_code(&self) -> Option<&str> {
+        match self {
+            Error::APIResponse { error, .. } => Some(&error.code),
+            _ => None,
+        }
+    }
+}
+
+/// The body of an error response from the API.  `code` is a stable identifier for the kind of
+/// error, `message` describes it for people, and `details` has any structured data about it.
+#[derive(Debug, Deserialize)]
+pub struct ErrorResponse {
+    pub code: String,
+    pub message: String,
+    #[serde(default)]
+    pub details: Option<serde_json::Value>,
+}
+
+impl ErrorResponse {
+    /// The code used for error responses that don't have one, like those from older API servers.
+    pub const UNKNOWN_CODE: &'static str = "UNKNOWN";
+
+    /// Parses the body of an error response.  If the body isn't in the expected format, it's kept
+    /// as the message, with an unknown code.
+    pub fn parse(body: &str) -> Self {
+        serde_json::from_str(body).unwrap_or_else(|_| ErrorResponse {
+            code: Self::UNKNOWN_CODE.to_string(),
+            message: body.to_string(),
+            details: None,
+        })
+    }
+}
+
 /// Simple helper that extends the API client, abstracting the repeated request logic and
-/// deserialization from JSON.
+/// deserialization from JSON.  Requests that fail because the API couldn't be reached, or had a
+/// server error, are retried according to the given policy.
 pub fn get_json<T, P, S1, S2, S3>(
     socket_path: P,
     uri: S1,
     // Query parameter name, query parameter value
     query: Option<(S2, S3)>,
+    retry: &RetryPolicy,
 ) -> Result<T>
 where
     T: DeserializeOwned,
@@ -73,33 +121,49 @@ where
     }
 
     let method = "GET";
-    trace!("{}ing from {}", method, uri);
-    let (code, response_body) = apiclient::raw_request(socket_path, &uri, method, None)
-        .context(error::APIRequest { method, uri: &uri })?;
+    let response_body = retry.run(|| {
+        trace!("{}ing from {}", method, uri);
+        let (status, response_body) =
+            match apiclient::raw_request(socket_path.as_ref(), &uri, method, None) {
+                Ok(response) => response,
+                // The API responded, but with an error; keep what it told us about the error.
+                Err(apiclient::Error::ResponseStatus { code, body, .. }) => {
+                    return error::APIResponse {
+                        method,
+                        uri: &uri,
+                        status: code,
+                        error: ErrorResponse::parse(&body),
+                    }
+                    .fail();
+                }
+                Err(e) => return Err(e).context(error::APIRequest { method, uri: &uri }),
+            };
 
-    if !code.is_success() {
-        return error::APIResponse {
-            method,
-            uri,
-            code,
-            response_body,
+        if !status.is_success() {
+            return error::APIResponse {
+                method,
+                uri: &uri,
+                status,
+                error: ErrorResponse::parse(&response_body),
+            }
+            .fail();
         }
-        .fail();
-    }
+        Ok(response_body)
+    })?;
     trace!("JSON response: {}", response_body);
 
     serde_json::from_str(&response_body).context(error::ResponseJson { method, uri })
 }
 
 /// Requests all settings from the API so they can be used as the data source for a handlebars
-/// templating call.
-pub fn get_settings<P>(socket_path: P) -> Result<model::Model>
+/// templating call.  Failed requests are retried according to the given policy.
+pub fn get_settings<P>(socket_path: P, retry: &RetryPolicy) -> Result<model::Model>
 where
     P: AsRef<Path>,
 {
     debug!("Querying API for settings data");
     let settings: model::Model =
-        get_json(&socket_path, "/", None as Option<(String, String)>)?;
+        get_json(&socket_path, "/", None as Option<(String, String)>, retry)?;
     trace!("Model values: {:?}", settings);
 
     Ok(settings)
@@ -108,13 +172,158 @@ where
 /// Build a handlebars template registry with our common helper functions.
 pub fn build_template_registry() -> Result<handlebars::Handlebars<'static>> {
     let mut template_registry = Handlebars::new();
-    // Strict mode will panic if a key exists in the template
-    // but isn't provided in the data given to the renderer
+    // Strict mode makes rendering fail if the template refers to a key that isn't in the data
+    // given to the renderer, rather than silently rendering it as empty.  This only applies to
+    // bare references like {{settings.foo}}; helpers like `default` and block helpers like #if
+    // are still given missing values, so templates can handle optional settings.
     template_registry.set_strict_mode(true);
 
     template_registry.register_helper("base64_decode", Box::new(helpers::base64_decode));
     template_registry.register_helper("join_map", Box::new(helpers::join_map));
     template_registry.register_helper("default", Box::new(helpers::default));
+    template_registry.register_helper("join", Box::new(helpers::join));
+    template_registry.register_helper("base64_encode", Box::new(helpers::base64_encode));
+    template_registry.register_helper("toml_escape", Box::new(helpers::toml_escape));
+    template_registry.register_helper("json_encode", Box::new(helpers::json_encode));
 
     Ok(template_registry)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_error_response() {
+        let body = json!({
+            "code": "SETTINGS_NOT_FOUND",
+            "message": "Found no 'settings.motd' in datastore",
+            "details": {"prefix": "settings.motd"},
+        });
+        let error = ErrorResponse::parse(&body.to_string());
+        assert_eq!(error.code, "SETTINGS_NOT_FOUND");
+        assert_eq!(error.message, "Found no 'settings.motd' in datastore");
+        assert_eq!(error.details, Some(json!({"prefix": "settings.motd"})));
+
+        let error = ErrorResponse::parse("Another thread poisoned the data store lock");
+        assert_eq!(error.code, ErrorResponse::UNKNOWN_CODE);
+        assert_eq!(error.message, "Another thread poisoned the data store lock");
+        assert_eq!(error.details, None);
+    }
+
+    /// Serves a response with each of the given statuses in turn, one per connection, from a Unix
+    /// socket at the given path.  Returns a count of the requests served.
+    fn mock_api(socket_path: &Path, statuses: Vec<u16>) -> Arc<AtomicUsize> {
+        let listener = UnixListener::bind(socket_path).unwrap();
+        let served = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&served);
+        thread::spawn(move || {
+            for (status, stream) in statuses.into_iter().zip(listener.incoming()) {
+                let mut stream = stream.unwrap();
+
+                // Our requests are GETs, so the request ends with the headers.
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let count = stream.read(&mut buf).unwrap();
+                    if count == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..count]);
+                }
+
+                let body = json!({"motd": "hi"}).to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        served
+    }
+
+    fn quick_retries(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy::new(max_attempts, Duration::from_secs(10))
+            .backoff(Duration::from_millis(1), Duration::from_millis(10))
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("api.sock");
+        let served = mock_api(&socket_path, vec![503, 500, 200]);
+
+        let response: HashMap<String, String> = get_json(
+            &socket_path,
+            "/",
+            None as Option<(&str, &str)>,
+            &quick_retries(5),
+        )
+        .unwrap();
+        assert_eq!(response.get("motd").map(String::as_str), Some("hi"));
+        assert_eq!(served.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn no_retries_for_client_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("api.sock");
+        let served = mock_api(&socket_path, vec![400, 200]);
+
+        let result: Result<HashMap<String, String>> = get_json(
+            &socket_path,
+            "/",
+            None as Option<(&str, &str)>,
+            &quick_retries(5),
+        );
+        match result {
+            Err(Error::APIResponse { status, .. }) => assert_eq!(status.as_u16(), 400),
+            other => panic!("Expected APIResponse error, got {:?}", other),
+        }
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn retries_run_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("api.sock");
+        let served = mock_api(&socket_path, vec![503, 503, 200]);
+
+        let result: Result<HashMap<String, String>> = get_json(
+            &socket_path,
+            "/",
+            None as Option<(&str, &str)>,
+            &quick_retries(2),
+        );
+        match result {
+            Err(Error::APIResponse { status, .. }) => assert_eq!(status.as_u16(), 503),
+            other => panic!("Expected APIResponse error, got {:?}", other),
+        }
+        assert_eq!(served.load(Ordering::SeqCst), 2);
+
+        // Nothing's listening here, so each attempt fails to connect.
+        let result: Result<HashMap<String, String>> = get_json(
+            dir.path().join("missing.sock"),
+            "/",
+            None as Option<(&str, &str)>,
+            &quick_retries(3),
+        );
+        match result {
+            Err(Error::APIRequest { .. }) => {}
+            other => panic!("Expected APIRequest error, got {:?}", other),
+        }
+    }
+}
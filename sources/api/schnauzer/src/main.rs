@@ -146,7 +146,8 @@ fn run() -> Result<()> {
 
     let registry = schnauzer::build_template_registry().context(error::BuildTemplateRegistry)?;
     let template = get_metadata(&setting_name, "templates")?;
-    let settings = schnauzer::get_settings(DEFAULT_API_SOCKET).context(error::GetSettings)?;
+    let settings = schnauzer::get_settings(DEFAULT_API_SOCKET, &schnauzer::RetryPolicy::default())
+        .context(error::GetSettings)?;
 
     let setting =
         registry
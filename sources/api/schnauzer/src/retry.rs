@@ -0,0 +1,129 @@
+//! Retrying API requests that fail because the API server isn't ready, for example while it's
+//! still starting during boot, or is being restarted.
+
+use crate::error::Error;
+use crate::Result;
+use rand::{thread_rng, Rng};
+use std::cmp::min;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// RetryPolicy says how many times, and for how long, to retry API requests that fail because the
+/// API couldn't be reached or had a server error.  Requests that the API rejected, with a 4xx
+/// status, aren't retried, since they'd just fail again.
+///
+/// Delays between attempts grow exponentially, up to a limit, and are randomized so that callers
+/// started at the same time don't all retry at the same time.  Construct a policy once and share
+/// it between requests.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    deadline: Duration,
+    initial_delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// How many times a request is tried by the default policy, including the first.
+    pub const DEFAULT_MAX_ATTEMPTS: u32 = 6;
+    /// How long requests are retried by the default policy, in seconds.
+    pub const DEFAULT_DEADLINE_SECS: u64 = 30;
+
+    const DEFAULT_INITIAL_DELAY: Duration = Duration::from_millis(100);
+    const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+    /// Creates a policy that tries each request up to `max_attempts` times, including the first,
+    /// and doesn't start another attempt if it would begin after `deadline` has passed since the
+    /// first.
+    pub fn new(max_attempts: u32, deadline: Duration) -> Self {
+        Self {
+            max_attempts,
+            deadline,
+            initial_delay: Self::DEFAULT_INITIAL_DELAY,
+            max_delay: Self::DEFAULT_MAX_DELAY,
+        }
+    }
+
+    /// Creates a policy that tries each request once.
+    pub fn never() -> Self {
+        Self::new(1, Duration::from_secs(0))
+    }
+
+    /// Changes the delay before the first retry, which doubles for each later retry up to
+    /// `max_delay`.
+    pub fn backoff(mut self, initial_delay: Duration, max_delay: Duration) -> Self {
+        self.initial_delay = initial_delay;
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Calls `request` until it succeeds, it fails with an error that isn't worth retrying, or
+    /// the policy's attempts or deadline run out; returns the last result.
+    pub fn run<T, F>(&self, mut request: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let start = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let err = match request() {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+            if attempt >= self.max_attempts || !retryable(&err) {
+                return Err(err);
+            }
+            let delay = self.delay(attempt);
+            if start.elapsed() + delay > self.deadline {
+                debug!("Not retrying after attempt {}, deadline reached", attempt);
+                return Err(err);
+            }
+
+            debug!(
+                "Attempt {} of {} failed, retrying in {:?}: {}",
+                attempt, self.max_attempts, delay, err
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    /// Returns how long to wait after the given attempt: the initial delay, doubled for each
+    /// earlier retry and capped at the maximum, then reduced by a random amount of up to half.
+    fn delay(&self, attempt: u32) -> Duration {
+        // Past 2^16 times the initial delay, we're surely at the cap.
+        let factor = 1 << min(attempt - 1, 16);
+        let full = min(
+            self.initial_delay
+                .checked_mul(factor)
+                .unwrap_or(self.max_delay),
+            self.max_delay,
+        );
+        let full_ms = full.as_millis() as u64;
+        Duration::from_millis(thread_rng().gen_range(full_ms / 2, full_ms + 1))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(
+            Self::DEFAULT_MAX_ATTEMPTS,
+            Duration::from_secs(Self::DEFAULT_DEADLINE_SECS),
+        )
+    }
+}
+
+/// Determines whether a failed request is worth retrying: we couldn't talk to the API, or it had
+/// a server error.
+fn retryable(err: &Error) -> bool {
+    match err {
+        Error::APIRequest { source, .. } => match source {
+            apiclient::Error::RequestSend { .. } | apiclient::Error::ResponseBodyRead { .. } => {
+                true
+            }
+            _ => false,
+        },
+        Error::APIResponse { status, .. } => status.is_server_error(),
+        Error::ResponseJson { .. } => false,
+    }
+}
@@ -5,6 +5,11 @@ storewolf creates the filesystem datastore used by the API system.
 
 It creates the datastore at a provided path and populates any default
 settings given in the defaults.toml file, unless they already exist.
+
+The defaults are built in at compile time, but you can give a path to a
+defaults file with `--defaults-path` to use different defaults, for example
+when testing or for a specific variant.  The file has the same structure as
+defaults.toml, including the `metadata` table.
 */
 #![deny(rust_2018_idioms)]
 
@@ -58,23 +63,23 @@ mod error {
             source: bottlerocket_release::Error,
         },
 
+        #[snafu(display("Unable to read defaults file '{}': {}", path.display(), source))]
+        DefaultsRead { path: PathBuf, source: io::Error },
+
         #[snafu(display("{} is not valid TOML: {}", file, source))]
         DefaultsFormatting {
             file: String,
             source: toml::de::Error,
         },
 
-        #[snafu(display("defaults.toml is not a TOML table"))]
-        DefaultsNotTable {},
+        #[snafu(display("{} is not a TOML table", file))]
+        DefaultsNotTable { file: String },
 
-        #[snafu(display("defaults.toml's settings is not a TOML table"))]
-        DefaultSettingsNotTable {},
+        #[snafu(display("{}'s settings is not a TOML table", file))]
+        DefaultSettingsNotTable { file: String },
 
-        #[snafu(display("defaults.toml's metadata is not a TOML list of Metadata"))]
-        DefaultsMetadataNotTable { source: toml::de::Error },
-
-        #[snafu(display("defaults.toml's metadata has unexpected types"))]
-        DefaultsMetadataUnexpectedFormat {},
+        #[snafu(display("{}'s metadata has unexpected type at '{}'", file, path))]
+        DefaultsMetadataUnexpectedFormat { file: String, path: String },
 
         #[snafu(display("defaults.toml data types do not match types defined in current variant's override-defaults.toml"))]
         DefaultsVariantDoesNotMatch {},
@@ -110,9 +115,6 @@ mod error {
         #[snafu(display("Failed to create symlink at '{}': {}", path.display(), source))]
         LinkCreate { path: PathBuf, source: io::Error },
 
-        #[snafu(display("Data store link '{}' points to /", path.display()))]
-        DataStoreLinkToRoot { path: PathBuf },
-
         #[snafu(display("Logger setup error: {}", source))]
         Logger { source: simplelog::TermLogError },
 
@@ -211,7 +213,9 @@ fn create_new_datastore<P: AsRef<Path>>(base_path: P, version: Option<Version>)
 //   Metadata {key: "settings.motd", md: "affected-services", val: Array([ ... ])},
 //   Metadata { ... },
 // ]
-fn parse_metadata_toml(md_toml_val: toml::Value) -> Result<Vec<model::Metadata>> {
+//
+// The name of the defaults file is used in error messages.
+fn parse_metadata_toml(md_toml_val: toml::Value, file: &str) -> Result<Vec<model::Metadata>> {
     debug!("Parsing metadata toml");
     let mut def_metadatas: Vec<model::Metadata> = Vec::new();
 
@@ -285,7 +289,13 @@ fn parse_metadata_toml(md_toml_val: toml::Value) -> Result<Vec<model::Metadata>>
             }
 
             // We don't recognize any other values yet, something is awry
-            _ => return error::DefaultsMetadataUnexpectedFormat {}.fail(),
+            _ => {
+                return error::DefaultsMetadataUnexpectedFormat {
+                    file,
+                    path: format!("metadata.{}", path.join(".")),
+                }
+                .fail()
+            }
         };
     }
     Ok(def_metadatas)
@@ -341,15 +351,78 @@ fn merge_values<'a>(merge_into: &'a mut Value, merge_from: &'a Value) -> Result<
     Ok(())
 }
 
+/// Returns the defaults built in at compile time: defaults.toml, merged with the current variant's
+/// override-defaults.toml.
+fn compiled_defaults() -> Result<Value> {
+    // Read and parse shared defaults
+    let defaults_str = include_str!("../../../models/defaults.toml");
+    let mut defaults_val: toml::Value =
+        toml::from_str(defaults_str).context(error::DefaultsFormatting {
+            file: "defaults.toml",
+        })?;
+
+    // Merge in any defaults for the current variant
+    let variant_defaults_str =
+        include_str!("../../../models/src/variant/current/override-defaults.toml");
+    let variant_defaults_val: toml::Value =
+        toml::from_str(variant_defaults_str).context(error::DefaultsFormatting {
+            file: "override_defaults.toml",
+        })?;
+    merge_values(&mut defaults_val, &variant_defaults_val)?;
+
+    Ok(defaults_val)
+}
+
+/// Reads and parses defaults from the file at the given path.  The file has the same structure as
+/// defaults.toml.
+fn load_defaults_file<P: AsRef<Path>>(defaults_path: P) -> Result<Value> {
+    let defaults_path = defaults_path.as_ref();
+    let defaults_str = fs::read_to_string(defaults_path).context(error::DefaultsRead {
+        path: defaults_path,
+    })?;
+    toml::from_str(&defaults_str).context(error::DefaultsFormatting {
+        file: defaults_path.display().to_string(),
+    })
+}
+
 /// Creates a new FilesystemDataStore at the given path, with data and metadata coming from
 /// defaults.toml at compile time.
+///
+/// If the datastore already exists, defaults are merged in: only data and metadata keys that
+/// aren't already populated are written, so user settings are preserved and new default keys are
+/// added.  If `force` is true, all defaults are written, overwriting existing values.
 fn populate_default_datastore<P: AsRef<Path>>(
     base_path: P,
     version: Option<Version>,
+    force: bool,
 ) -> Result<()> {
-    // NOTE: Variables prefixed with "def" refer to values from defaults.
-    //
-    // Variables prefixed with "existing..." refer to values from the
+    let defaults_val = compiled_defaults()?;
+    populate_datastore(base_path, version, defaults_val, "defaults.toml", force)
+}
+
+/// Like populate_default_datastore, but with data and metadata coming from the defaults file at
+/// the given path, rather than the defaults built in at compile time.
+fn populate_from_file<P: AsRef<Path>>(
+    base_path: P,
+    defaults_path: P,
+    version: Option<Version>,
+    force: bool,
+) -> Result<()> {
+    let defaults_val = load_defaults_file(&defaults_path)?;
+    let defaults_name = defaults_path.as_ref().display().to_string();
+    populate_datastore(base_path, version, defaults_val, &defaults_name, force)
+}
+
+/// Creates the datastore at the given path if necessary, and writes the given defaults to it.  The
+/// name of the defaults is used in error messages.
+fn populate_datastore<P: AsRef<Path>>(
+    base_path: P,
+    version: Option<Version>,
+    defaults_val: Value,
+    defaults_name: &str,
+    force: bool,
+) -> Result<()> {
+    // NOTE: Variables prefixed with "existing..." refer to values from the
     // existing datastore.
 
     // There's a chain of symlinks that point to the directory where data
@@ -365,38 +438,64 @@ fn populate_default_datastore<P: AsRef<Path>>(
     let live_path = &datastore_path.join("live");
     if live_path.exists() {
         debug!("Gathering existing data from the datastore");
-        existing_metadata = datastore
-            .list_populated_metadata("", &None as &Option<&str>)
-            .context(error::QueryMetadata)?;
-        existing_data = datastore
-            .list_populated_keys("", &datastore::Committed::Live)
-            .context(error::QueryData)?;
+        let existing = existing_keys(&datastore)?;
+        existing_data = existing.0;
+        existing_metadata = existing.1;
     } else {
         info!("Creating datastore at: {}", &live_path.display());
         create_new_datastore(&base_path, version)?;
     }
 
-    // Read and parse shared defaults
-    let defaults_str = include_str!("../../../models/defaults.toml");
-    let mut defaults_val: toml::Value =
-        toml::from_str(defaults_str).context(error::DefaultsFormatting {
-            file: "defaults.toml",
-        })?;
+    write_defaults(
+        &mut datastore,
+        defaults_val,
+        defaults_name,
+        &existing_data,
+        &existing_metadata,
+        force,
+    )
+}
 
-    // Merge in any defaults for the current variant
-    let variant_defaults_str =
-        include_str!("../../../models/src/variant/current/override-defaults.toml");
-    let variant_defaults_val: toml::Value =
-        toml::from_str(variant_defaults_str).context(error::DefaultsFormatting {
-            file: "override_defaults.toml",
-        })?;
-    merge_values(&mut defaults_val, &variant_defaults_val)?;
+/// Populated metadata keys, grouped by the data key they describe.
+type MetadataKeys = HashMap<Key, HashSet<Key>>;
+
+/// Returns the data keys and metadata keys populated in the live datastore.
+fn existing_keys<D: DataStore>(datastore: &D) -> Result<(HashSet<Key>, MetadataKeys)> {
+    let existing_metadata = datastore
+        .list_populated_metadata("", &None as &Option<&str>, &datastore::Committed::Live)
+        .context(error::QueryMetadata)?;
+    let existing_data = datastore
+        .list_populated_keys("", &datastore::Committed::Live)
+        .context(error::QueryData)?;
+    Ok((existing_data, existing_metadata))
+}
+
+/// Writes the given defaults to the datastore.  Settings are written to the shared pending
+/// transaction, and everything else is written live.
+///
+/// Data and metadata keys in `existing_data` and `existing_metadata` are skipped, unless `force`
+/// is true, in which case every default is written.  The name of the defaults is used in error
+/// messages.
+fn write_defaults<D: DataStore>(
+    datastore: &mut D,
+    mut defaults_val: Value,
+    defaults_name: &str,
+    existing_data: &HashSet<Key>,
+    existing_metadata: &MetadataKeys,
+    force: bool,
+) -> Result<()> {
+    // NOTE: Variables prefixed with "def" refer to values from defaults.
+    if force {
+        info!("Forcing defaults to overwrite any existing values");
+    }
 
     // Check if we have metadata and settings. If so, pull them out
     // of `shared_defaults_val`
     let table = defaults_val
         .as_table_mut()
-        .context(error::DefaultsNotTable)?;
+        .context(error::DefaultsNotTable {
+            file: defaults_name,
+        })?;
     let maybe_metadata_val = table.remove("metadata");
     let maybe_settings_val = table.remove("settings");
 
@@ -407,7 +506,9 @@ fn populate_default_datastore<P: AsRef<Path>>(
         debug!("Serializing default settings and writing new ones to datastore");
         let def_settings_table = def_settings_val
             .as_table()
-            .context(error::DefaultSettingsNotTable)?;
+            .context(error::DefaultSettingsNotTable {
+                file: defaults_name,
+            })?;
 
         // The default settings were removed from the "settings" key of the
         // defaults table above. We still need them under a "settings" key
@@ -424,7 +525,7 @@ fn populate_default_datastore<P: AsRef<Path>>(
         // datastore. If not, add it to the map of settings to write
         let mut settings_to_write = HashMap::new();
         for (key, val) in def_settings {
-            if !existing_data.contains(&key) {
+            if force || !existing_data.contains(&key) {
                 settings_to_write.insert(key, val);
             }
         }
@@ -445,7 +546,7 @@ fn populate_default_datastore<P: AsRef<Path>>(
     if let Some(def_metadata_val) = maybe_metadata_val {
         debug!("Serializing metadata and writing new keys to datastore");
         // Create a Vec<Metadata> from the metadata toml::Value
-        let def_metadatas = parse_metadata_toml(def_metadata_val)?;
+        let def_metadatas = parse_metadata_toml(def_metadata_val, defaults_name)?;
 
         // Before this transformation, `existing_metadata` is a
         // map of data key to set of metadata keys:
@@ -476,7 +577,7 @@ fn populate_default_datastore<P: AsRef<Path>>(
             // Put the `data_key` and `md_key` tuple into a variable so we
             // can more easily read the subsequent `contains()` call
             let def_metadata_keypair = (&data_key, &md_key);
-            if !existing_metadata.contains(&def_metadata_keypair) {
+            if force || !existing_metadata.contains(&def_metadata_keypair) {
                 let value =
                     datastore::serialize_scalar::<_, ScalarError>(&val).with_context(|| {
                         error::SerializeScalar {
@@ -494,7 +595,7 @@ fn populate_default_datastore<P: AsRef<Path>>(
         for metadata in metadata_to_write {
             let (md, key, val) = metadata;
             datastore
-                .set_metadata(&md, &key, val)
+                .set_metadata(&md, &key, val, &datastore::Committed::Live)
                 .context(error::WriteMetadata)?;
         }
     }
@@ -509,7 +610,7 @@ fn populate_default_datastore<P: AsRef<Path>>(
     let mut other_defaults_to_write = HashMap::new();
     if !defaults.is_empty() {
         for (key, val) in defaults {
-            if !existing_data.contains(&key) {
+            if force || !existing_data.contains(&key) {
                 other_defaults_to_write.insert(key, val);
             }
         }
@@ -530,6 +631,8 @@ struct Args {
     data_store_base_path: String,
     log_level: LevelFilter,
     version: Option<Version>,
+    defaults_path: Option<String>,
+    force: bool,
 }
 
 /// Print a usage message in the event a bad arg is passed
@@ -539,10 +642,17 @@ fn usage() -> ! {
         r"Usage: {}
             --data-store-base-path PATH
             [ --version X.Y ]
+            [ --defaults-path PATH ]
             [ --log-level trace|debug|info|warn|error ]
+            [ --force ]
 
         If --version is not given, the version will be pulled from /etc/os-release.
         This is used to set up versioned symlinks in the data store base path.
+
+        If --defaults-path is not given, the defaults built into storewolf are used.
+
+        Defaults are only written for keys that aren't already populated, unless
+        --force is given, in which case existing values are overwritten.
         ",
         program_name
     );
@@ -560,6 +670,8 @@ fn parse_args(args: env::Args) -> Args {
     let mut data_store_base_path = None;
     let mut log_level = None;
     let mut version = None;
+    let mut defaults_path = None;
+    let mut force = false;
 
     let mut iter = args.skip(1);
     while let Some(arg) = iter.next() {
@@ -589,6 +701,15 @@ fn parse_args(args: env::Args) -> Args {
                 );
             }
 
+            "--defaults-path" => {
+                defaults_path = Some(
+                    iter.next()
+                        .unwrap_or_else(|| usage_msg("Did not give argument to --defaults-path")),
+                )
+            }
+
+            "--force" => force = true,
+
             _ => usage(),
         }
     }
@@ -597,6 +718,8 @@ fn parse_args(args: env::Args) -> Args {
         data_store_base_path: data_store_base_path.unwrap_or_else(|| usage()),
         log_level: log_level.unwrap_or_else(|| LevelFilter::Info),
         version,
+        defaults_path,
+        force,
     }
 }
 
@@ -624,7 +747,18 @@ fn run() -> Result<()> {
 
     // Create the datastore if it doesn't exist
     info!("Populating datastore at: {}", &args.data_store_base_path);
-    populate_default_datastore(&args.data_store_base_path, args.version)?;
+    match &args.defaults_path {
+        Some(defaults_path) => {
+            info!("Using defaults from: {}", defaults_path);
+            populate_from_file(
+                &args.data_store_base_path,
+                defaults_path,
+                args.version,
+                args.force,
+            )?;
+        }
+        None => populate_default_datastore(&args.data_store_base_path, args.version, args.force)?,
+    }
     info!("Datastore populated");
 
     Ok(())
@@ -642,9 +776,200 @@ fn main() {
 
 #[cfg(test)]
 mod test {
-    use super::merge_values;
+    use super::{
+        compiled_defaults, existing_keys, load_defaults_file, merge_values, parse_metadata_toml,
+        write_defaults, TRANSACTION,
+    };
+    use apiserver::datastore::memory::MemoryDataStore;
+    use apiserver::datastore::{Committed, DataStore, Key, KeyType};
+    use std::fs;
     use toml::toml;
 
+    fn data_key(name: &str) -> Key {
+        Key::new(KeyType::Data, name).unwrap()
+    }
+
+    fn meta_key(name: &str) -> Key {
+        Key::new(KeyType::Meta, name).unwrap()
+    }
+
+    // A datastore with a customized setting, service, and metadata.
+    fn customized_datastore() -> MemoryDataStore {
+        let mut ds = MemoryDataStore::new();
+        let live = &Committed::Live;
+        ds.set_key(&data_key("settings.motd"), "\"custom motd\"", live)
+            .unwrap();
+        ds.set_key(&data_key("services.foo.restart-commands"), "[\"custom\"]", live)
+            .unwrap();
+        ds.set_metadata(
+            &meta_key("affected-services"),
+            &data_key("settings.motd"),
+            "[\"custom\"]",
+            live,
+        )
+        .unwrap();
+        ds
+    }
+
+    fn defaults() -> toml::Value {
+        toml! {
+            [settings]
+            motd = "default motd"
+            hostname = "default hostname"
+
+            [services.foo]
+            restart-commands = ["default"]
+
+            [services.bar]
+            restart-commands = [["/bin/bar", "--restart"]]
+
+            [metadata.settings.motd]
+            affected-services = ["motd"]
+
+            [metadata.settings.hostname]
+            affected-services = ["hostname"]
+        }
+    }
+
+    #[test]
+    fn defaults_merge() {
+        let mut ds = customized_datastore();
+        let (existing_data, existing_metadata) = existing_keys(&ds).unwrap();
+        write_defaults(
+            &mut ds,
+            defaults(),
+            "test",
+            &existing_data,
+            &existing_metadata,
+            false,
+        )
+        .unwrap();
+
+        let live = &Committed::Live;
+        let pending = &Committed::Pending {
+            tx: TRANSACTION.to_string(),
+        };
+        // New keys are added
+        assert_eq!(
+            ds.get_key(&data_key("settings.hostname"), pending).unwrap(),
+            Some("\"default hostname\"".to_string())
+        );
+        assert_eq!(
+            ds.get_key(&data_key("services.bar.restart-commands"), live)
+                .unwrap(),
+            Some("[[\"/bin/bar\",\"--restart\"]]".to_string())
+        );
+        assert_eq!(
+            ds.get_metadata_raw(
+                &meta_key("affected-services"),
+                &data_key("settings.hostname"),
+                live
+            )
+            .unwrap(),
+            Some("[\"hostname\"]".to_string())
+        );
+
+        // Existing keys are preserved
+        assert_eq!(
+            ds.get_key(&data_key("settings.motd"), pending).unwrap(),
+            None
+        );
+        assert_eq!(
+            ds.get_key(&data_key("settings.motd"), live).unwrap(),
+            Some("\"custom motd\"".to_string())
+        );
+        assert_eq!(
+            ds.get_key(&data_key("services.foo.restart-commands"), live)
+                .unwrap(),
+            Some("[\"custom\"]".to_string())
+        );
+        assert_eq!(
+            ds.get_metadata_raw(
+                &meta_key("affected-services"),
+                &data_key("settings.motd"),
+                live
+            )
+            .unwrap(),
+            Some("[\"custom\"]".to_string())
+        );
+    }
+
+    #[test]
+    fn defaults_force() {
+        let mut ds = customized_datastore();
+        let (existing_data, existing_metadata) = existing_keys(&ds).unwrap();
+        write_defaults(
+            &mut ds,
+            defaults(),
+            "test",
+            &existing_data,
+            &existing_metadata,
+            true,
+        )
+        .unwrap();
+
+        let live = &Committed::Live;
+        let pending = &Committed::Pending {
+            tx: TRANSACTION.to_string(),
+        };
+        // Existing keys are overwritten; settings go through the pending transaction
+        assert_eq!(
+            ds.get_key(&data_key("settings.motd"), pending).unwrap(),
+            Some("\"default motd\"".to_string())
+        );
+        assert_eq!(
+            ds.get_key(&data_key("services.foo.restart-commands"), live)
+                .unwrap(),
+            Some("[\"default\"]".to_string())
+        );
+        assert_eq!(
+            ds.get_metadata_raw(
+                &meta_key("affected-services"),
+                &data_key("settings.motd"),
+                live
+            )
+            .unwrap(),
+            Some("[\"motd\"]".to_string())
+        );
+    }
+
+    #[test]
+    fn defaults_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("defaults.toml");
+        fs::write(&path, defaults().to_string()).unwrap();
+        assert_eq!(load_defaults_file(&path).unwrap(), defaults());
+
+        // The compiled-in defaults still parse
+        compiled_defaults().unwrap();
+    }
+
+    #[test]
+    fn defaults_file_errors_name_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("defaults.toml");
+        let path_str = path.display().to_string();
+
+        // Missing file
+        let err = load_defaults_file(&path).unwrap_err();
+        assert!(err.to_string().contains(&path_str));
+
+        // Bad TOML
+        fs::write(&path, "settings = [").unwrap();
+        let err = load_defaults_file(&path).unwrap_err();
+        assert!(err.to_string().contains(&path_str));
+
+        // Bad metadata names the file and the TOML path
+        let metadata = toml! {
+            [settings.motd]
+            affected-services = 42
+        };
+        let err = parse_metadata_toml(metadata, &path_str).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains(&path_str));
+        assert!(msg.contains("metadata.settings.motd.affected-services"));
+    }
+
     #[test]
     fn merge() {
         let mut left = toml! {
@@ -1,35 +1,29 @@
+use crate::owner::Owners;
 use crate::{error, Result};
-use itertools::join;
-use snafu::ResultExt;
-use std::collections::HashSet;
-use std::fs;
+use serde::Serialize;
+use serde_json::Value;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::panic;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tempfile::NamedTempFile;
 
-/// Query the API for ConfigurationFile data
-#[allow(clippy::implicit_hasher)]
-pub fn get_affected_config_files<P>(
-    socket_path: P,
-    files_limit: Option<HashSet<String>>,
-) -> Result<model::ConfigurationFiles>
-where
-    P: AsRef<Path>,
-{
-    // Only want a query parameter if we had specific affected files, otherwise we want all
-    let query = files_limit.map(|files| ("names", join(&files, ",")));
+/// The mode of newly created configuration files.
+const NEW_FILE_MODE: u32 = 0o644;
 
-    debug!("Querying API for configuration file metadata");
-    let uri = "/configuration-files";
-    let config_files: model::ConfigurationFiles =
-        schnauzer::get_json(socket_path, uri, query).context(error::GetJson { uri })?;
+/// How many unchanged lines to show around each change in a diff.
+const DIFF_CONTEXT: usize = 3;
 
-    Ok(config_files)
-}
-
-/// Given a map of Service objects, return a HashSet of
+/// Given a map of Service objects, return a sorted set of
 /// affected configuration file names
-pub fn get_config_file_names(services: &model::Services) -> HashSet<String> {
+pub fn get_config_file_names(services: &model::Services) -> BTreeSet<String> {
     debug!("Building set of affected configuration file names");
-    let mut config_file_set = HashSet::new();
+    let mut config_file_set = BTreeSet::new();
     for service in services.values() {
         for file in service.configuration_files.iter() {
             config_file_set.insert(file.to_string());
@@ -40,29 +34,204 @@ pub fn get_config_file_names(services: &model::Services) -> HashSet<String> {
     config_file_set
 }
 
+/// Registers the template of each configuration file, and the partials it lists, with the given
+/// registry.  Partials are registered under their file names, so a configuration file that lists
+/// "/usr/share/templates/proxy-env" can include it in its template with `{{> proxy-env}}`.
+///
+/// Handlebars renders a partial it doesn't know as empty, so we make sure that each template, and
+/// each partial it lists, only includes partials that its configuration file lists.  That way a
+/// template doesn't depend on which other configuration files happen to be rendered with it.
+///
+/// Templates are compiled as they're registered, so this finds broken templates before anything
+/// is rendered.  A configuration file whose template, or any of its partials, is missing, empty,
+/// invalid, or includes a partial that isn't listed, doesn't have its template registered; the
+/// errors for those files are returned, by name, so the caller can skip them.
+pub fn register_templates(
+    registry: &mut handlebars::Handlebars<'_>,
+    config_files: &model::ConfigurationFiles,
+) -> BTreeMap<String, error::Error> {
+    // The path and included partials of each partial we've registered, by name
+    let mut partials: HashMap<String, (PathBuf, BTreeSet<String>)> = HashMap::new();
+
+    let mut failed = BTreeMap::new();
+    for (name, metadata) in config_files {
+        if let Err(e) = register_template(registry, name, metadata, &mut partials) {
+            warn!(
+                "Template for configuration file '{}' is invalid: {}",
+                name, e
+            );
+            failed.insert(name.clone(), e);
+        }
+    }
+    failed
+}
+
+/// Registers the template of one configuration file, and the partials it lists that aren't in
+/// `partials` yet, for register_templates.  The template is only registered if it and its
+/// partials are valid.
+fn register_template(
+    registry: &mut handlebars::Handlebars<'_>,
+    name: &str,
+    metadata: &model::ConfigurationFile,
+    partials: &mut HashMap<String, (PathBuf, BTreeSet<String>)>,
+) -> Result<()> {
+    let mut listed = BTreeMap::new();
+    for partial_path in metadata.partials.iter().flatten() {
+        let path = Path::new(&**partial_path);
+        let partial = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context(error::PartialName { path })?;
+        if let Some((other, includes)) = partials.get(partial) {
+            ensure!(
+                other == path,
+                error::PartialConflict {
+                    name: partial,
+                    path,
+                    other,
+                }
+            );
+            listed.insert(partial.to_string(), includes.clone());
+            continue;
+        }
+
+        debug!(
+            "Registering partial {} at path '{}'",
+            partial,
+            path.display()
+        );
+        let source = read_template(partial, path)?;
+        registry
+            .register_partial(partial, &source)
+            .context(error::TemplateRegister {
+                name: partial,
+                path,
+            })?;
+        let includes = partial_references(&source);
+        partials.insert(partial.to_string(), (path.to_path_buf(), includes.clone()));
+        listed.insert(partial.to_string(), includes);
+    }
+
+    debug!(
+        "Registering {} at path '{}'",
+        &name, &metadata.template_path
+    );
+    let path = Path::new(&*metadata.template_path);
+    let source = read_template(name, path)?;
+    ensure!(
+        !source.trim().is_empty(),
+        error::EmptyTemplate { name, path }
+    );
+
+    // Check what the template includes, and what its partials include.
+    let template_includes = partial_references(&source);
+    let mut includes = vec![(name, &template_includes)];
+    includes.extend(
+        listed
+            .iter()
+            .map(|(partial, included)| (partial.as_str(), included)),
+    );
+    for (template, included) in includes {
+        for partial in included {
+            ensure!(
+                listed.contains_key(partial),
+                error::MissingPartial { template, partial }
+            );
+        }
+    }
+
+    registry
+        .register_template_string(name, &source)
+        .context(error::TemplateRegister { name, path })?;
+    Ok(())
+}
+
+/// Reads the source of a template or partial.
+fn read_template(name: &str, path: &Path) -> Result<String> {
+    fs::read_to_string(path).context(error::TemplateRead { name, path })
+}
+
+/// Returns the names of the partials included by the given template source, like "proxy-env" for
+/// `{{> proxy-env}}`.  Partials named by expressions, like `{{> (lookup ...)}}`, and the
+/// `@partial-block` of a partial block, aren't known ahead of time and aren't returned.
+fn partial_references(source: &str) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    let mut rest = source;
+    while let Some(start) = rest.find("{{") {
+        rest = &rest[start + 2..];
+        // Allow for whitespace control, like {{~> name}}, and partial blocks, like {{#> name}}.
+        let mut tag = rest.trim_start_matches('~');
+        if tag.starts_with('#') {
+            tag = &tag[1..];
+        }
+        if !tag.starts_with('>') {
+            continue;
+        }
+        let name: String = tag[1..]
+            .trim_start()
+            .chars()
+            .take_while(|c| !c.is_whitespace() && *c != '}' && *c != '~')
+            .collect();
+        if !name.is_empty() && !name.starts_with('(') && !name.starts_with('@') {
+            names.insert(name);
+        }
+    }
+    names
+}
+
 /// Render the configuration files
 // If strict is True, return an error if we fail to render any template.
 // If strict is False, ignore failures, always returning an Ok value
 // containing any successfully rendered templates.
-pub fn render_config_files(
+//
+// Templates that refer to a setting that isn't set fail to render, as long as the registry is in
+// strict mode, as it is from schnauzer::build_template_registry.  Settings that are null count as
+// unset.
+//
+// Each section of `extra_context`, like the `os` facts from the facts module, is added to the top
+// level of the template data, next to `settings`.  If the data already has a section by that name,
+// like `os` in the API's response, the extra section's fields are added to it, replacing any with
+// the same names.  `settings` itself can't be replaced, so templates always see real settings.
+//
+// Templates are rendered on up to `jobs` threads at once; the results are the same, and in the
+// same order, as rendering them one at a time.
+#[allow(clippy::implicit_hasher)]
+pub fn render_config_files<S>(
     registry: &handlebars::Handlebars<'_>,
     config_files: model::ConfigurationFiles,
-    settings: model::Model,
+    settings: S,
+    extra_context: HashMap<String, Value>,
     strict: bool,
-) -> Result<Vec<RenderedConfigFile>> {
+    jobs: usize,
+) -> Result<Vec<RenderedConfigFile>>
+where
+    S: Serialize,
+{
+    // Handlebars renders null as empty, even in strict mode, so we remove nulls to make
+    // references to them fail like references to any other unset setting.
+    let mut data = serde_json::to_value(settings).context(error::SettingsSerialize)?;
+    add_context(&mut data, extra_context);
+    remove_nulls(&mut data);
+
+    // Rendering only reads the registry and settings, so templates can render in parallel.
+    let config_files: Vec<_> = config_files.into_iter().collect();
+    let results = parallel_map(&config_files, jobs, |(name, _)| {
+        debug!("Rendering {}", name);
+        registry.render(name, &data)
+    });
+
     // Go write all the configuration files from template
     let mut rendered_configs = Vec::new();
-    for (name, metadata) in config_files {
-        debug!("Rendering {}", &name);
-
-        let try_rendered = registry.render(&name, &settings);
+    for ((name, metadata), try_rendered) in config_files.into_iter().zip(results) {
         if strict {
-            let rendered = try_rendered.context(error::TemplateRender { template: name })?;
-            rendered_configs.push(RenderedConfigFile::new(&metadata.path, rendered));
+            let rendered = try_rendered.context(error::TemplateRender {
+                template: name.as_str(),
+            })?;
+            rendered_configs.push(RenderedConfigFile::new(&name, &metadata, rendered));
         } else {
             match try_rendered {
                 Ok(rendered) => {
-                    rendered_configs.push(RenderedConfigFile::new(&metadata.path, rendered))
+                    rendered_configs.push(RenderedConfigFile::new(&name, &metadata, rendered))
                 }
                 Err(err) => warn!("Unable to render template '{}': {}", &name, err),
             }
@@ -72,68 +241,1401 @@ pub fn render_config_files(
     Ok(rendered_configs)
 }
 
-/// Write all the configuration files to disk
-pub fn write_config_files(rendered_config: Vec<RenderedConfigFile>) -> Result<()> {
+/// Calls `f` on each of the given items, using up to `jobs` threads, and returns the results in
+/// the same order as the items.
+fn parallel_map<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let jobs = jobs.min(items.len());
+    if jobs <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    // Each thread takes the next unclaimed item until there are none left.
+    let next = &AtomicUsize::new(0);
+    let f = &f;
+    let finished = crossbeam_utils::thread::scope(|scope| {
+        let mut workers = Vec::with_capacity(jobs);
+        for _ in 0..jobs {
+            workers.push(scope.spawn(move |_| {
+                let mut done = Vec::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= items.len() {
+                        return done;
+                    }
+                    done.push((i, f(&items[i])));
+                }
+            }));
+        }
+        workers
+            .into_iter()
+            .map(|worker| worker.join())
+            .collect::<std::thread::Result<Vec<_>>>()
+    });
+
+    // A panic in a thread is a bug in `f`; pass it along as if it happened here.
+    let mut finished: Vec<(usize, R)> = match finished {
+        Ok(Ok(finished)) => finished.into_iter().flatten().collect(),
+        Ok(Err(e)) | Err(e) => panic::resume_unwind(e),
+    };
+    finished.sort_by_key(|(i, _)| *i);
+    finished.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Adds the given sections to the top level of the template data; see render_config_files.
+fn add_context(data: &mut Value, extra_context: HashMap<String, Value>) {
+    let data = match data {
+        Value::Object(data) => data,
+        _ => {
+            if !extra_context.is_empty() {
+                warn!("Settings aren't an object, so other template data can't be added");
+            }
+            return;
+        }
+    };
+    for (name, section) in extra_context {
+        if name == "settings" {
+            warn!("Not replacing settings in template data");
+            continue;
+        }
+        match (data.get_mut(&name), section) {
+            (Some(Value::Object(existing)), Value::Object(fields)) => existing.extend(fields),
+            (_, section) => {
+                data.insert(name, section);
+            }
+        }
+    }
+}
+
+/// Removes null values from the objects in the given value, at any depth.  Nulls in lists are
+/// kept, so the list positions of other values don't change.
+fn remove_nulls(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            let nulls: Vec<String> = map
+                .iter()
+                .filter(|(_, inner)| inner.is_null())
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in nulls {
+                map.remove(&name);
+            }
+            map.values_mut().for_each(remove_nulls);
+        }
+        Value::Array(values) => values.iter_mut().for_each(remove_nulls),
+        _ => {}
+    }
+}
+
+/// Write all the configuration files to disk, skipping any whose contents, mode, and owners haven't
+/// changed.  If `backups` is Some, the existing files are backed up before they're replaced.
+/// Files that fail their check command aren't installed, but don't stop the others from being
+/// written; they're listed in the returned report, along with the files that were written.
+pub fn write_config_files<O: Owners>(
+    rendered_config: Vec<RenderedConfigFile>,
+    owners: &O,
+    backups: Option<&Backups>,
+) -> Result<WriteReport> {
+    let mut report = WriteReport::default();
     for cfg in rendered_config {
-        debug!("Writing {:?}", &cfg.path);
-        cfg.write_to_disk()?;
+        match cfg.write_to_disk(owners, backups) {
+            Ok(WriteOutcome::Written) => {
+                debug!("Wrote {:?}", &cfg.path);
+                report.written.insert(cfg.name);
+            }
+            Ok(WriteOutcome::Unchanged) => debug!("{:?} is unchanged, not writing", &cfg.path),
+            Err(e @ error::Error::CheckExecution { .. })
+            | Err(e @ error::Error::CheckFailed { .. }) => {
+                error!("Not installing {:?}: {}", &cfg.path, e);
+                report.failed_checks.insert(cfg.name, e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    trace!("Write report: {:?}", report);
+    Ok(report)
+}
+
+/// Returns a unified diff from `old` to `new`, with a few lines of context around each change, or
+/// None if they're the same.  `old` is None for a file that doesn't exist yet, in which case all
+/// of `new` is shown as added.  `path` is used to label both sides.
+pub fn unified_diff(path: &Path, old: Option<&str>, new: &str) -> Option<String> {
+    if old == Some(new) {
+        return None;
+    }
+    let old_lines = old.map(split_lines).unwrap_or_default();
+    let new_lines = split_lines(new);
+    let diff = diff_lines(&old_lines, &new_lines);
+
+    let mut out = match old {
+        Some(_) => format!("--- {}\n", path.display()),
+        None => "--- /dev/null\n".to_string(),
+    };
+    out.push_str(&format!("+++ {}\n", path.display()));
+
+    // Changes that are close enough to share context go in the same hunk.
+    let changes: Vec<usize> = (0..diff.len()).filter(|&i| diff[i].kind != ' ').collect();
+    let mut i = 0;
+    while i < changes.len() {
+        let start = changes[i].saturating_sub(DIFF_CONTEXT);
+        let mut last = changes[i];
+        i += 1;
+        while i < changes.len() && changes[i] - last - 1 <= 2 * DIFF_CONTEXT {
+            last = changes[i];
+            i += 1;
+        }
+        let end = (last + DIFF_CONTEXT + 1).min(diff.len());
+        push_hunk(&mut out, &diff[start..end], &old_lines, &new_lines);
+    }
+    Some(out)
+}
+
+/// One line of a diff: its kind, ' ' for a line in both files, '-' for a line only in the old
+/// file, or '+' for a line only in the new file, and its position in each file.
+#[derive(Debug)]
+struct DiffLine {
+    kind: char,
+    old: usize,
+    new: usize,
+}
+
+/// Splits text into lines, keeping their line endings, so that a missing newline at the end of
+/// the text counts as a difference.
+fn split_lines(text: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, _) in text.match_indices('\n') {
+        lines.push(&text[start..=i]);
+        start = i + 1;
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+    lines
+}
+
+/// Returns the shortest diff from `old` to `new`, based on their longest common subsequence of
+/// lines.  Configuration files are small, so the simple quadratic algorithm is fine.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<DiffLine> {
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..].
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        // Removals go before additions, as in other diff tools.
+        let kind = if i < old.len() && j < new.len() && old[i] == new[j] {
+            ' '
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            '-'
+        } else {
+            '+'
+        };
+        diff.push(DiffLine {
+            kind,
+            old: i,
+            new: j,
+        });
+        match kind {
+            ' ' => {
+                i += 1;
+                j += 1;
+            }
+            '-' => i += 1,
+            _ => j += 1,
+        }
+    }
+    diff
+}
+
+/// Adds a hunk with the given lines of a diff to `out`, with its header.
+fn push_hunk(out: &mut String, hunk: &[DiffLine], old: &[&str], new: &[&str]) {
+    let old_count = hunk.iter().filter(|line| line.kind != '+').count();
+    let new_count = hunk.iter().filter(|line| line.kind != '-').count();
+    // Line numbers start from 1, except that an empty range is given as the line before it.
+    let start = |position: usize, count: usize| if count == 0 { position } else { position + 1 };
+    out.push_str(&format!(
+        "@@ -{},{} +{},{} @@\n",
+        start(hunk[0].old, old_count),
+        old_count,
+        start(hunk[0].new, new_count),
+        new_count
+    ));
+
+    for line in hunk {
+        let text = match line.kind {
+            '+' => new[line.new],
+            _ => old[line.old],
+        };
+        out.push(line.kind);
+        out.push_str(text);
+        if !text.ends_with('\n') {
+            out.push_str("\n\\ No newline at end of file\n");
+        }
+    }
+}
+
+/// WriteReport lists the configuration files that were written, the ones that weren't installed
+/// because their check command failed, and the ones skipped because their templates were
+/// invalid, with the reasons.
+#[derive(Debug, Default)]
+pub struct WriteReport {
+    pub written: BTreeSet<String>,
+    pub failed_checks: BTreeMap<String, error::Error>,
+    pub failed_templates: BTreeMap<String, error::Error>,
+}
+
+/// Backups says where to save the previous contents of configuration files before they're
+/// replaced, and how many to keep for each file.  The most recent backup of a file is named after
+/// it, plus ".bak"; older ones have ".1", ".2", and so on after that.
+#[derive(Debug, Clone)]
+pub struct Backups {
+    /// The directory to save backups in, under the full path of each file.  If None, backups are
+    /// saved next to the file.
+    pub dir: Option<PathBuf>,
+    /// How many backups to keep for each file.
+    pub keep: usize,
+}
+
+impl Backups {
+    /// Returns the path of the nth most recent backup of the file at `path`, starting from 0.
+    fn backup_path(&self, path: &Path, n: usize) -> PathBuf {
+        let mut backup = match &self.dir {
+            // Keeping the full path means files with the same name in different directories don't
+            // share backups.
+            Some(dir) => dir
+                .join(path.strip_prefix("/").unwrap_or(path))
+                .into_os_string(),
+            None => path.as_os_str().to_os_string(),
+        };
+        backup.push(".bak");
+        if n > 0 {
+            backup.push(format!(".{}", n));
+        }
+        PathBuf::from(backup)
+    }
+
+    /// Saves a copy of the file at `path` as its most recent backup, after moving its older
+    /// backups along and removing any beyond the number we keep.  Does nothing if there's no
+    /// file at `path`.
+    fn save(&self, path: &Path) -> Result<()> {
+        if self.keep == 0 || !path.exists() {
+            return Ok(());
+        }
+
+        let oldest = self.backup_path(path, self.keep - 1);
+        match fs::remove_file(&oldest) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(error::Backup { path: &oldest }),
+        }
+        for n in (0..self.keep - 1).rev() {
+            let from = self.backup_path(path, n);
+            if from.exists() {
+                fs::rename(&from, self.backup_path(path, n + 1))
+                    .context(error::Backup { path: &from })?;
+            }
+        }
+
+        let newest = self.backup_path(path, 0);
+        if let Some(dirname) = newest.parent() {
+            fs::create_dir_all(dirname).context(error::Backup { path: dirname })?;
+        }
+        debug!("Backing up {:?} to {:?}", path, &newest);
+        fs::copy(path, &newest).context(error::Backup { path: &newest })?;
+        Ok(())
     }
-    Ok(())
 }
 
-/// RenderedConfigFile contains both the path to the config file
-/// and the rendered data to write.
+/// WriteOutcome says whether writing a configuration file changed anything on disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteOutcome {
+    Written,
+    Unchanged,
+}
+
+/// RenderedConfigFile contains the name of the config file, the path
+/// to the config file, the rendered data to write, and the mode and
+/// owners to give it, if any.
 #[derive(Debug)]
 pub struct RenderedConfigFile {
+    name: String,
     path: PathBuf,
     rendered: String,
+    mode: Option<u32>,
+    user: Option<String>,
+    group: Option<String>,
+    check_command: Option<String>,
 }
 
 impl RenderedConfigFile {
-    fn new(path: &str, rendered: String) -> RenderedConfigFile {
+    fn new(
+        name: &str,
+        metadata: &model::ConfigurationFile,
+        rendered: String,
+    ) -> RenderedConfigFile {
         RenderedConfigFile {
-            path: PathBuf::from(&path),
+            name: name.to_string(),
+            path: PathBuf::from(&*metadata.path),
             rendered,
+            mode: metadata.mode.as_ref().map(|mode| mode.mode()),
+            user: metadata.user.as_ref().map(|user| user.to_string()),
+            group: metadata.group.as_ref().map(|group| group.to_string()),
+            check_command: metadata.check_command.clone(),
         }
     }
 
-    /// Writes the rendered template at the proper location
-    fn write_to_disk(&self) -> Result<()> {
-        if let Some(dirname) = self.path.parent() {
-            fs::create_dir_all(dirname).context(error::TemplateWrite {
-                path: dirname,
-                pathtype: "directory",
-            })?;
+    /// Returns the name of the configuration file.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the rendered contents of the configuration file.
+    pub fn rendered(&self) -> &str {
+        &self.rendered
+    }
+
+    /// Returns a unified diff from the current contents of the file to the rendered contents, or
+    /// None if they're the same.  A file we can't read is shown as new, like a missing file.
+    pub fn diff(&self) -> Option<String> {
+        let current = fs::read(&self.path)
+            .ok()
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+        unified_diff(&self.path, current.as_deref(), &self.rendered)
+    }
+
+    /// Writes the rendered template at the proper location, with the requested mode and owners,
+    /// unless the file there already has the rendered contents, mode, and owners.  If `backups`
+    /// is Some, any existing file is backed up before it's replaced.
+    fn write_to_disk<O: Owners>(
+        &self,
+        owners: &O,
+        backups: Option<&Backups>,
+    ) -> Result<WriteOutcome> {
+        let uid = match &self.user {
+            Some(user) => Some(owners.uid(user)?),
+            None => None,
+        };
+        let gid = match &self.group {
+            Some(group) => Some(owners.gid(group)?),
+            None => None,
+        };
+        if self.is_current(uid, gid) {
+            return Ok(WriteOutcome::Unchanged);
+        }
+
+        let path = self.destination()?;
+        let dirname = match path.parent() {
+            Some(dirname) if !dirname.as_os_str().is_empty() => dirname,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dirname).context(error::TemplateWrite {
+            path: dirname,
+            pathtype: "directory",
+        })?;
+
+        // Without a requested mode, replacing a file keeps its permissions.
+        let permissions = match (self.mode, fs::metadata(&path)) {
+            (Some(mode), _) => Permissions::from_mode(mode),
+            (None, Ok(metadata)) => metadata.permissions(),
+            (None, Err(_)) => Permissions::from_mode(NEW_FILE_MODE),
+        };
+
+        // New files are owned by us, so we only need to change the owners that are different,
+        // and that takes root.
+        let (current_uid, current_gid) = owners.current();
+        let uid = uid.filter(|uid| *uid != current_uid);
+        let gid = gid.filter(|gid| *gid != current_gid);
+        let chown = uid.is_some() || gid.is_some();
+        ensure!(
+            !chown || current_uid == 0,
+            error::ChownNotRoot { path: &self.path }
+        );
+
+        // Write the new contents to a temporary file in the same directory, so that the rename
+        // over the destination is atomic, and services never see a partly written file.
+        let temp_context = || error::TemplateWrite {
+            path: dirname,
+            pathtype: "temporary file",
+        };
+        let mut temp = NamedTempFile::new_in(dirname).context(temp_context())?;
+        temp.write_all(self.rendered.as_bytes())
+            .context(temp_context())?;
+        temp.as_file().sync_all().context(temp_context())?;
+        // Changing owners can clear setuid and setgid bits, so it's done before setting the mode.
+        if chown {
+            owners.chown(temp.path(), uid, gid)?;
+        }
+        temp.as_file()
+            .set_permissions(permissions)
+            .context(temp_context())?;
+        // The temporary file is complete, in the same directory, and has its final mode, so it
+        // can be checked in place of the real one.
+        if let Some(command) = &self.check_command {
+            self.check(command, temp.path())?;
+        }
+
+        if let Some(backups) = backups {
+            backups.save(&self.path)?;
+        }
+        temp.persist(&path)
+            .context(error::TemplatePersist { path: &path })?;
+
+        Ok(WriteOutcome::Written)
+    }
+
+    /// Runs the check command on the rendered file at `rendered_path`, failing if it exits nonzero.
+    /// The path replaces any "{}" in the command's arguments, or is added as the last argument.
+    fn check(&self, command: &str, rendered_path: &Path) -> Result<()> {
+        // Split on space, assume the first item is the command
+        // and the rest are args, like restart commands.
+        debug!("Check command: {:?}", command);
+        let mut command_strings = command.split(' ');
+        let program = command_strings
+            .next()
+            .filter(|program| !program.is_empty())
+            .context(error::InvalidCheckCommand { command })?;
+
+        let rendered_path = rendered_path.to_string_lossy();
+        let mut args: Vec<String> = Vec::new();
+        let mut placeholder = false;
+        for arg in command_strings {
+            placeholder |= arg.contains("{}");
+            args.push(arg.replace("{}", &rendered_path));
+        }
+        if !placeholder {
+            args.push(rendered_path.to_string());
+        }
+        trace!("Check command args: {:?}", &args);
+
+        let result = process::Command::new(program)
+            .args(&args)
+            .output()
+            .context(error::CheckExecution { command })?;
+        ensure!(
+            result.status.success(),
+            error::CheckFailed {
+                command,
+                path: &self.path,
+                stderr: String::from_utf8_lossy(&result.stderr),
+            }
+        );
+        Ok(())
+    }
+
+    /// Returns whether the file at our path already has the rendered contents, and the mode and
+    /// owners we want, if any.  If we can't read the existing file, it's missing or not a file we
+    /// can compare against; writing it will either replace it or tell us why we can't.
+    fn is_current(&self, uid: Option<u32>, gid: Option<u32>) -> bool {
+        match (fs::read(&self.path), fs::metadata(&self.path)) {
+            (Ok(existing), Ok(metadata)) => {
+                existing == self.rendered.as_bytes()
+                    // Just the permission bits, not the file type
+                    && self.mode.map_or(true, |mode| metadata.mode() & 0o7777 == mode)
+                    && uid.map_or(true, |uid| metadata.uid() == uid)
+                    && gid.map_or(true, |gid| metadata.gid() == gid)
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the path to replace with the rendered template: the configuration file path
+    /// itself, or the file it links to, if it's a symlink.  Fails if that's a directory, or a
+    /// symlink to nothing, which we'd otherwise replace with a file.
+    fn destination(&self) -> Result<PathBuf> {
+        let metadata = match fs::symlink_metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(self.path.clone()),
+            Err(e) => {
+                return Err(e).context(error::TemplateWrite {
+                    path: &self.path,
+                    pathtype: "file",
+                })
+            }
         };
 
-        fs::write(&self.path, self.rendered.as_bytes()).context(error::TemplateWrite {
-            path: &self.path,
-            pathtype: "file",
-        })
+        let path = if metadata.file_type().is_symlink() {
+            match fs::canonicalize(&self.path) {
+                Ok(target) => target,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return error::DanglingSymlink { path: &self.path }.fail()
+                }
+                Err(e) => {
+                    return Err(e).context(error::TemplateWrite {
+                        path: &self.path,
+                        pathtype: "file",
+                    })
+                }
+            }
+        } else {
+            self.path.clone()
+        };
+        ensure!(
+            !path.is_dir(),
+            error::DestinationDirectory { path: &self.path }
+        );
+        Ok(path)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use maplit::{hashmap, hashset};
+    use crate::owner::SystemOwners;
+    use maplit::{btreemap, btreeset, hashmap};
+    use model::modeled_types::CommandSpec;
+    use serde_json::json;
+    use std::cell::RefCell;
     use std::convert::TryInto;
+    use std::io::Read;
 
     #[test]
     fn test_get_config_file_names() {
-        let input_map = hashmap!(
+        let input_map = btreemap!(
             "foo".to_string() => model::Service {
                 configuration_files: vec!["file1".try_into().unwrap()],
-                restart_commands: vec!["echo hi".to_string()]
+                restart_commands: vec![CommandSpec::Split("echo hi".to_string())],
+                restart_after: None,
+                restart_timeout: None,
             },
             "bar".to_string() => model::Service {
                 configuration_files: vec!["file1".try_into().unwrap(), "file2".try_into().unwrap()],
-                restart_commands: vec!["echo hi".to_string()]
+                restart_commands: vec![CommandSpec::Split("echo hi".to_string())],
+                restart_after: None,
+                restart_timeout: None,
             },
         );
 
-        let expected_output = hashset! {"file1".to_string(), "file2".to_string() };
+        let expected_output = btreeset! {"file1".to_string(), "file2".to_string() };
 
         assert_eq!(get_config_file_names(&input_map), expected_output)
     }
+
+    /// Renders the given template, named "test-file", with the registry thar-be-settings uses.
+    fn render(template: &str, settings: serde_json::Value) -> Result<String> {
+        render_with(template, settings, HashMap::new())
+    }
+
+    /// Renders the given template like `render`, with the given extra template data.
+    fn render_with(
+        template: &str,
+        settings: serde_json::Value,
+        extra_context: HashMap<String, Value>,
+    ) -> Result<String> {
+        let mut registry = schnauzer::build_template_registry().unwrap();
+        registry
+            .register_template_string("test-file", template)
+            .unwrap();
+        let config_files = btreemap!(
+            "test-file".to_string() => model::ConfigurationFile {
+                path: "/etc/test-file".try_into().unwrap(),
+                template_path: "/usr/share/templates/test-file".try_into().unwrap(),
+                mode: None,
+                user: None,
+                group: None,
+                check_command: None,
+                partials: None,
+            },
+        );
+        let mut rendered =
+            render_config_files(&registry, config_files, settings, extra_context, true, 1)?;
+        assert_eq!(rendered.len(), 1);
+        Ok(rendered.remove(0).rendered)
+    }
+
+    #[test]
+    fn extra_context_rendered() {
+        let settings = json!({"settings": {"motd": "hi"}});
+        let extra_context = || {
+            hashmap! {
+                "os".to_string() => json!({"version_id": "0.3.1", "variant_id": "aws-k8s"}),
+                "network".to_string() => json!({"primary-interface": "eth0"}),
+            }
+        };
+        assert_eq!(
+            render_with(
+                "{{os.version_id}} {{os.variant_id}} {{network.primary-interface}}",
+                settings.clone(),
+                extra_context(),
+            )
+            .unwrap(),
+            "0.3.1 aws-k8s eth0"
+        );
+        // Templates that only use settings render the same either way
+        assert_eq!(
+            render_with("{{settings.motd}}", settings.clone(), extra_context()).unwrap(),
+            render("{{settings.motd}}", settings.clone()).unwrap()
+        );
+        // Without the section, templates using it fail like templates using unset settings
+        assert!(render("{{os.version_id}}", settings).is_err());
+    }
+
+    #[test]
+    fn extra_context_merged() {
+        // Sections already in the data are extended, with the extra section's fields winning
+        let settings = json!({
+            "settings": {"motd": "hi"},
+            "os": {"pretty_name": "Bottlerocket OS", "version_id": "0.3.0"},
+        });
+        let extra_context = hashmap! {
+            "os".to_string() => json!({"version_id": "0.3.1"}),
+            "settings".to_string() => json!({"motd": "bye"}),
+        };
+        assert_eq!(
+            render_with(
+                "{{os.pretty_name}} {{os.version_id}} {{settings.motd}}",
+                settings,
+                extra_context,
+            )
+            .unwrap(),
+            "Bottlerocket OS 0.3.1 hi"
+        );
+    }
+
+    #[test]
+    fn unchanged_files_not_rewritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("etc").join("test-file");
+        let mut registry = schnauzer::build_template_registry().unwrap();
+        registry
+            .register_template_string("test-file", "motd={{settings.motd}}")
+            .unwrap();
+        let config_files = || {
+            btreemap!(
+                "test-file".to_string() => config_file(&path),
+            )
+        };
+        let services = || {
+            btreemap!(
+                "test-service".to_string() => model::Service {
+                    configuration_files: vec!["test-file".try_into().unwrap()],
+                    restart_commands: vec![CommandSpec::Split("echo hi".to_string())],
+                    restart_after: None,
+                    restart_timeout: None,
+                },
+            )
+        };
+        let write = |settings: Value| {
+            let rendered =
+                render_config_files(&registry, config_files(), settings, HashMap::new(), true, 1)
+                    .unwrap();
+            write_config_files(rendered, &SystemOwners, None)
+                .unwrap()
+                .written
+        };
+
+        // The first pass writes the file and restarts its service
+        let written = write(json!({"settings": {"motd": "hi"}}));
+        assert_eq!(written, btreeset! {"test-file".to_string()});
+        assert_eq!(fs::read_to_string(&path).unwrap(), "motd=hi");
+        assert_eq!(
+            crate::service::skip_unchanged(services(), &written).len(),
+            1
+        );
+
+        // Rendering the same settings again writes nothing and restarts nothing
+        let written = write(json!({"settings": {"motd": "hi"}}));
+        assert!(written.is_empty());
+        assert!(crate::service::skip_unchanged(services(), &written).is_empty());
+
+        // New settings change the file again
+        let written = write(json!({"settings": {"motd": "bye"}}));
+        assert_eq!(written, btreeset! {"test-file".to_string()});
+        assert_eq!(fs::read_to_string(&path).unwrap(), "motd=bye");
+    }
+
+    /// Returns a ConfigurationFile to be written at the given path, with no mode or owners.
+    fn config_file(path: &Path) -> model::ConfigurationFile {
+        model::ConfigurationFile {
+            path: path.to_str().unwrap().try_into().unwrap(),
+            template_path: "/usr/share/templates/test-file".try_into().unwrap(),
+            mode: None,
+            user: None,
+            group: None,
+            check_command: None,
+            partials: None,
+        }
+    }
+
+    /// Returns a RenderedConfigFile with the given contents, to be written at the given path.
+    fn rendered(path: &Path, rendered: &str) -> RenderedConfigFile {
+        RenderedConfigFile::new("test-file", &config_file(path), rendered.to_string())
+    }
+
+    /// Owners that knows a few users and groups, and records the owners it's asked to change,
+    /// rather than changing them.
+    struct FakeOwners {
+        current: (u32, u32),
+        chowned: RefCell<Vec<(Option<u32>, Option<u32>)>>,
+    }
+
+    impl FakeOwners {
+        fn new(current: (u32, u32)) -> Self {
+            FakeOwners {
+                current,
+                chowned: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl Owners for FakeOwners {
+        fn uid(&self, user: &str) -> Result<u32> {
+            match user {
+                "root" => Ok(0),
+                "kubelet" => Ok(1000),
+                _ => error::UnknownUser { user }.fail(),
+            }
+        }
+
+        fn gid(&self, group: &str) -> Result<u32> {
+            match group {
+                "root" => Ok(0),
+                "kubelet" => Ok(1000),
+                _ => error::UnknownGroup { group }.fail(),
+            }
+        }
+
+        fn current(&self) -> (u32, u32) {
+            self.current
+        }
+
+        fn chown(&self, _path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+            self.chowned.borrow_mut().push((uid, gid));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_applies_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test-file");
+        let owners = FakeOwners::new((1000, 1000));
+        let with_mode = |mode: &str| {
+            let mut file = config_file(&path);
+            file.mode = Some(mode.try_into().unwrap());
+            RenderedConfigFile::new("test-file", &file, "secret\n".to_string())
+        };
+        let mode = || fs::metadata(&path).unwrap().permissions().mode() & 0o7777;
+
+        assert_eq!(
+            with_mode("0600").write_to_disk(&owners, None).unwrap(),
+            WriteOutcome::Written
+        );
+        assert_eq!(mode(), 0o600);
+
+        // A different mode is applied even though the contents are the same
+        assert_eq!(
+            with_mode("0640").write_to_disk(&owners, None).unwrap(),
+            WriteOutcome::Written
+        );
+        assert_eq!(mode(), 0o640);
+        assert_eq!(
+            with_mode("0640").write_to_disk(&owners, None).unwrap(),
+            WriteOutcome::Unchanged
+        );
+        assert!(owners.chowned.borrow().is_empty());
+    }
+
+    #[test]
+    fn write_changes_owners() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test-file");
+        let owned_by = |user: &str, group: &str| {
+            let mut file = config_file(&path);
+            file.user = Some(user.try_into().unwrap());
+            file.group = Some(group.try_into().unwrap());
+            RenderedConfigFile::new("test-file", &file, "secret\n".to_string())
+        };
+
+        // Running as root, we change the owners that differ from ours
+        let owners = FakeOwners::new((0, 0));
+        owned_by("kubelet", "root")
+            .write_to_disk(&owners, None)
+            .unwrap();
+        assert_eq!(*owners.chowned.borrow(), vec![(Some(1000), None)]);
+        fs::remove_file(&path).unwrap();
+
+        // Otherwise we can only write files owned by us
+        let owners = FakeOwners::new((1000, 1000));
+        owned_by("kubelet", "kubelet")
+            .write_to_disk(&owners, None)
+            .unwrap();
+        assert!(owners.chowned.borrow().is_empty());
+        fs::remove_file(&path).unwrap();
+
+        match owned_by("root", "kubelet").write_to_disk(&owners, None) {
+            Err(error::Error::ChownNotRoot { .. }) => {}
+            other => panic!("Expected ChownNotRoot error, got {:?}", other),
+        }
+        assert!(!path.exists());
+
+        match owned_by("nobody-here", "root").write_to_disk(&owners, None) {
+            Err(error::Error::UnknownUser { .. }) => {}
+            other => panic!("Expected UnknownUser error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn write_replaces_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test-file");
+        fs::write(&path, "a longer, older file\n").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+
+        // Someone reading the old file keeps seeing all of it, since the new one is a new file
+        // renamed over it, rather than the old one rewritten in place.
+        let mut old = fs::File::open(&path).unwrap();
+
+        let outcome = rendered(&path, "new\n")
+            .write_to_disk(&SystemOwners, None)
+            .unwrap();
+        assert_eq!(outcome, WriteOutcome::Written);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        let mut old_contents = String::new();
+        old.read_to_string(&mut old_contents).unwrap();
+        assert_eq!(old_contents, "a longer, older file\n");
+
+        // The file keeps its permissions, and the temporary file is gone
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["test-file"]);
+    }
+
+    /// Writes "one", "two", and "three" to the given path in turn, returning what's in its first
+    /// three backup paths after each write.
+    fn backup_rotation(path: &Path, backups: &Backups) -> Vec<Vec<Option<String>>> {
+        let backup_paths: Vec<_> = (0..3).map(|n| backups.backup_path(path, n)).collect();
+        let mut found = Vec::new();
+        for contents in &["one", "two", "three"] {
+            let rendered = vec![rendered(path, contents)];
+            write_config_files(rendered, &SystemOwners, Some(backups)).unwrap();
+            assert_eq!(fs::read_to_string(path).unwrap(), *contents);
+            found.push(
+                backup_paths
+                    .iter()
+                    .map(|backup| fs::read_to_string(backup).ok())
+                    .collect(),
+            );
+        }
+        found
+    }
+
+    #[test]
+    fn backups_next_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test-file");
+        let backups = Backups { dir: None, keep: 2 };
+        assert_eq!(
+            backups.backup_path(&path, 0),
+            dir.path().join("test-file.bak")
+        );
+        assert_eq!(
+            backups.backup_path(&path, 1),
+            dir.path().join("test-file.bak.1")
+        );
+
+        // There's nothing to back up the first time, and we keep the last two after that
+        let one = Some("one".to_string());
+        let two = Some("two".to_string());
+        assert_eq!(
+            backup_rotation(&path, &backups),
+            vec![
+                vec![None, None, None],
+                vec![one.clone(), None, None],
+                vec![two, one, None],
+            ]
+        );
+    }
+
+    #[test]
+    fn backups_in_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("etc").join("test-file");
+        let backup_dir = dir.path().join("backups");
+        let backups = Backups {
+            dir: Some(backup_dir.clone()),
+            keep: 1,
+        };
+        // The file's full path is kept under the backup directory
+        let backup = backups.backup_path(&path, 0);
+        assert!(backup.starts_with(&backup_dir));
+        assert!(backup.ends_with("etc/test-file.bak"));
+
+        // Only the most recent backup is kept
+        assert_eq!(
+            backup_rotation(&path, &backups),
+            vec![
+                vec![None, None, None],
+                vec![Some("one".to_string()), None, None],
+                vec![Some("two".to_string()), None, None],
+            ]
+        );
+        assert!(!dir.path().join("etc").join("test-file.bak").exists());
+    }
+
+    /// Returns a RenderedConfigFile with the given contents and check command, to be written at
+    /// the given path.
+    fn checked(name: &str, path: &Path, rendered: &str, command: &str) -> RenderedConfigFile {
+        let mut file = config_file(path);
+        file.check_command = Some(command.to_string());
+        RenderedConfigFile::new(name, &file, rendered.to_string())
+    }
+
+    #[test]
+    fn check_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = dir.path().join("good");
+        let bad = dir.path().join("bad");
+        fs::write(&bad, "old\n").unwrap();
+
+        let report = write_config_files(
+            vec![
+                checked("good", &good, "new\n", "/bin/true"),
+                checked("bad", &bad, "new\n", "/bin/false"),
+            ],
+            &SystemOwners,
+            None,
+        )
+        .unwrap();
+
+        // The file that failed its check isn't installed, and the other one still is
+        assert_eq!(report.written, btreeset! {"good".to_string()});
+        assert_eq!(report.failed_checks.keys().collect::<Vec<_>>(), vec!["bad"]);
+        assert_eq!(fs::read_to_string(&good).unwrap(), "new\n");
+        assert_eq!(fs::read_to_string(&bad).unwrap(), "old\n");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        // Services that only use the file that failed aren't restarted
+        let services = btreemap!(
+            "good-service".to_string() => model::Service {
+                configuration_files: vec!["good".try_into().unwrap()],
+                restart_commands: vec![],
+                restart_after: None,
+                restart_timeout: None,
+            },
+            "bad-service".to_string() => model::Service {
+                configuration_files: vec!["bad".try_into().unwrap()],
+                restart_commands: vec![],
+                restart_after: None,
+                restart_timeout: None,
+            },
+        );
+        let services = crate::service::skip_unchanged(services, &report.written);
+        assert_eq!(services.keys().collect::<Vec<_>>(), vec!["good-service"]);
+    }
+
+    #[test]
+    fn check_command_args() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test-file");
+
+        // The rendered file's path replaces "{}", or is added at the end
+        for (command, ok) in &[
+            ("/bin/grep -q hello {}", true),
+            ("/bin/grep -q hello", true),
+            ("/bin/grep -q goodbye {}", false),
+            ("/bin/grep -q goodbye", false),
+        ] {
+            let result =
+                checked("test-file", &path, "hello\n", command).write_to_disk(&SystemOwners, None);
+            assert_eq!(result.is_ok(), *ok, "{}: {:?}", command, result);
+            fs::remove_file(&path).ok();
+        }
+
+        // Failures include what the check command said
+        match checked("test-file", &path, "hello\n", "/bin/ls /no-such-file-here")
+            .write_to_disk(&SystemOwners, None)
+        {
+            Err(e @ error::Error::CheckFailed { .. }) => {
+                let message = e.to_string();
+                assert!(message.contains("no-such-file-here"), "{}", message);
+            }
+            other => panic!("Expected CheckFailed error, got {:?}", other),
+        }
+
+        match checked("test-file", &path, "hello\n", " /bin/true")
+            .write_to_disk(&SystemOwners, None)
+        {
+            Err(error::Error::InvalidCheckCommand { .. }) => {}
+            other => panic!("Expected InvalidCheckCommand error, got {:?}", other),
+        }
+    }
+
+    /// Returns the numbered lines from 1 through `count`.
+    fn numbered_lines(count: usize) -> String {
+        (1..=count).map(|n| format!("{}\n", n)).collect()
+    }
+
+    #[test]
+    fn diff_new_file() {
+        let path = Path::new("/etc/motd");
+        assert_eq!(
+            unified_diff(path, None, "hello\nthere\n").unwrap(),
+            "--- /dev/null\n+++ /etc/motd\n@@ -0,0 +1,2 @@\n+hello\n+there\n"
+        );
+    }
+
+    #[test]
+    fn diff_unchanged_file() {
+        let path = Path::new("/etc/motd");
+        assert_eq!(unified_diff(path, Some("hello\n"), "hello\n"), None);
+        assert_eq!(unified_diff(path, Some(""), ""), None);
+    }
+
+    #[test]
+    fn diff_changed_file() {
+        let path = Path::new("/etc/test-file");
+        let old = numbered_lines(10);
+        let new = old.replace("5\n", "five\n");
+        assert_eq!(
+            unified_diff(path, Some(&old), &new).unwrap(),
+            "--- /etc/test-file\n+++ /etc/test-file\n\
+             @@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n"
+        );
+
+        // Changes far apart get their own hunks
+        let old = numbered_lines(20);
+        let new = old.replacen("1\n", "one\n", 1).replace("20\n", "twenty\n");
+        assert_eq!(
+            unified_diff(path, Some(&old), &new).unwrap(),
+            "--- /etc/test-file\n+++ /etc/test-file\n\
+             @@ -1,4 +1,4 @@\n-1\n+one\n 2\n 3\n 4\n\
+             @@ -17,4 +17,4 @@\n 17\n 18\n 19\n-20\n+twenty\n"
+        );
+
+        // A missing newline at the end is a change
+        assert_eq!(
+            unified_diff(path, Some("hello\n"), "hello").unwrap(),
+            "--- /etc/test-file\n+++ /etc/test-file\n\
+             @@ -1,1 +1,1 @@\n-hello\n+hello\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn diff_rendered_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test-file");
+        let file = rendered(&path, "hello\n");
+
+        let diff = file.diff().unwrap();
+        assert!(diff.starts_with("--- /dev/null\n"), "{}", diff);
+        assert!(diff.ends_with("@@ -0,0 +1,1 @@\n+hello\n"), "{}", diff);
+
+        file.write_to_disk(&SystemOwners, None).unwrap();
+        assert_eq!(file.diff(), None);
+    }
+
+    #[test]
+    fn write_new_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("new").join("dir").join("test-file");
+
+        rendered(&path, "new\n")
+            .write_to_disk(&SystemOwners, None)
+            .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, NEW_FILE_MODE);
+    }
+
+    #[test]
+    fn write_through_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        let link = dir.path().join("link");
+        fs::write(&target, "old\n").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        rendered(&link, "new\n")
+            .write_to_disk(&SystemOwners, None)
+            .unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new\n");
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+    }
+
+    #[test]
+    fn write_bad_destinations() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let link = dir.path().join("dangling");
+        std::os::unix::fs::symlink(dir.path().join("nothing"), &link).unwrap();
+        match rendered(&link, "new\n").write_to_disk(&SystemOwners, None) {
+            Err(error::Error::DanglingSymlink { .. }) => {}
+            other => panic!("Expected DanglingSymlink error, got {:?}", other),
+        }
+        assert!(!dir.path().join("nothing").exists());
+
+        let subdir = dir.path().join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        match rendered(&subdir, "new\n").write_to_disk(&SystemOwners, None) {
+            Err(error::Error::DestinationDirectory { .. }) => {}
+            other => panic!("Expected DestinationDirectory error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_setting_fails_render() {
+        let settings = json!({"settings": {"ntp": {"time-servers": ["a.example.com"]}}});
+        assert_eq!(
+            render("{{settings.ntp.time-servers.[0]}}", settings.clone()).unwrap(),
+            "a.example.com"
+        );
+
+        match render("server {{settings.ntp.time-serverz}}", settings) {
+            Err(e @ error::Error::TemplateRender { .. }) => {
+                let message = e.to_string();
+                assert!(message.contains("test-file"), "{}", message);
+                assert!(message.contains("settings.ntp.time-serverz"), "{}", message);
+            }
+            other => panic!("Expected TemplateRender error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn null_setting_fails_render() {
+        let settings = json!({"settings": {"motd": null}});
+        render("{{settings.motd}}", settings).unwrap_err();
+    }
+
+    #[test]
+    fn optional_settings_render() {
+        let template = "{{#if settings.motd}}motd={{settings.motd}}{{/if}}\
+                        pods={{default 110 settings.kubernetes.max-pods}}";
+        assert_eq!(
+            render(template, json!({"settings": {"motd": null}})).unwrap(),
+            "pods=110"
+        );
+        assert_eq!(
+            render(
+                template,
+                json!({"settings": {"motd": "hi", "kubernetes": {"max-pods": 29}}})
+            )
+            .unwrap(),
+            "motd=hipods=29"
+        );
+    }
+
+    #[test]
+    fn parallel_render() {
+        let names: Vec<String> = (0..50).map(|i| format!("file-{:02}", i)).collect();
+        let mut registry = schnauzer::build_template_registry().unwrap();
+        for (i, name) in names.iter().enumerate() {
+            registry
+                .register_template_string(name, format!("{}={{{{settings.motd}}}}", i))
+                .unwrap();
+        }
+        let config_files = || {
+            names
+                .iter()
+                .map(|name| (name.clone(), config_file(&Path::new("/etc").join(name))))
+                .collect::<model::ConfigurationFiles>()
+        };
+        let settings = json!({"settings": {"motd": "hi"}});
+
+        // Every template renders, in name order, however many threads render them.
+        let expected: Vec<(String, String)> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.clone(), format!("{}=hi", i)))
+            .collect();
+        for jobs in &[1, 4, 8, 100] {
+            for _ in 0..5 {
+                let rendered = render_config_files(
+                    &registry,
+                    config_files(),
+                    &settings,
+                    HashMap::new(),
+                    true,
+                    *jobs,
+                )
+                .unwrap();
+                let rendered: Vec<(String, String)> = rendered
+                    .into_iter()
+                    .map(|file| (file.name().to_string(), file.rendered))
+                    .collect();
+                assert_eq!(rendered, expected);
+            }
+        }
+
+        // The first failure in name order is the one reported.
+        registry
+            .register_template_string("file-07", "{{settings.missing}}")
+            .unwrap();
+        registry
+            .register_template_string("file-30", "{{settings.missing}}")
+            .unwrap();
+        match render_config_files(
+            &registry,
+            config_files(),
+            &settings,
+            HashMap::new(),
+            true,
+            8,
+        ) {
+            Err(error::Error::TemplateRender { template, .. }) => assert_eq!(template, "file-07"),
+            other => panic!("Expected TemplateRender error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn find_partial_references() {
+        let source = "{{> a}} {{~> b ~}} {{#> c}}x{{/c}} {{>d}}\n\
+                      {{> (lookup settings \"x\")}} {{> @partial-block}} {{settings.e}}";
+        let expected: BTreeSet<String> =
+            ["a", "b", "c", "d"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(partial_references(source), expected);
+    }
+
+    /// Writes the given template and partials, named by file name, into the directory, and
+    /// returns configuration files for the template, which lists `listed` partials.
+    fn partial_files(
+        dir: &Path,
+        template: &str,
+        partials: &[(&str, &str)],
+        listed: &[&str],
+    ) -> model::ConfigurationFiles {
+        for (name, source) in partials {
+            fs::write(dir.join(name), source).unwrap();
+        }
+        let template_path = dir.join("test-file.template");
+        fs::write(&template_path, template).unwrap();
+
+        let mut file = config_file(&dir.join("test-file"));
+        file.template_path = template_path.to_str().unwrap().try_into().unwrap();
+        file.partials = Some(
+            listed
+                .iter()
+                .map(|name| dir.join(name).to_str().unwrap().try_into().unwrap())
+                .collect(),
+        );
+        btreemap!("test-file".to_string() => file)
+    }
+
+    #[test]
+    fn partials_render() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_files = partial_files(
+            dir.path(),
+            "motd={{settings.motd}} {{> proxy-env}}",
+            &[
+                ("proxy-env", "proxy={{settings.proxy}}{{> no-proxy}}"),
+                ("no-proxy", " no-proxy={{join \",\" settings.no-proxy}}"),
+            ],
+            &["proxy-env", "no-proxy"],
+        );
+        let mut registry = schnauzer::build_template_registry().unwrap();
+        assert!(register_templates(&mut registry, &config_files).is_empty());
+
+        let settings = json!({"settings": {
+            "motd": "hi",
+            "proxy": "http://proxy.example.com",
+            "no-proxy": ["localhost", "example.com"],
+        }});
+        let rendered =
+            render_config_files(&registry, config_files, settings, HashMap::new(), true, 1)
+                .unwrap();
+        assert_eq!(
+            rendered[0].rendered,
+            "motd=hi proxy=http://proxy.example.com no-proxy=localhost,example.com"
+        );
+    }
+
+    #[test]
+    fn missing_partials() {
+        let check = |template: &str, listed: &[&str], expected: (&str, &str)| {
+            let dir = tempfile::tempdir().unwrap();
+            let config_files = partial_files(
+                dir.path(),
+                template,
+                &[("proxy-env", "{{> no-proxy}}"), ("no-proxy", "")],
+                listed,
+            );
+            let mut registry = schnauzer::build_template_registry().unwrap();
+            match register_templates(&mut registry, &config_files).remove("test-file") {
+                Some(e @ error::Error::MissingPartial { .. }) => {
+                    let message = e.to_string();
+                    assert!(message.contains(expected.0), "{}", message);
+                    assert!(message.contains(expected.1), "{}", message);
+                }
+                other => panic!("Expected MissingPartial error, got {:?}", other),
+            }
+        };
+
+        // The template includes a partial its configuration file doesn't list
+        check("{{> proxy-env}}", &[], ("test-file", "proxy-env"));
+        // A listed partial includes a partial that isn't listed
+        check("{{> proxy-env}}", &["proxy-env"], ("proxy-env", "no-proxy"));
+    }
+
+    #[test]
+    fn broken_templates_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config_files = model::ConfigurationFiles::new();
+        let templates = &[
+            ("good-1", Some("one={{settings.motd}}")),
+            ("bad-syntax", Some("{{#if settings.motd}}")),
+            ("good-2", Some("two={{settings.motd}}")),
+            ("empty", Some(" \n")),
+            ("missing", None),
+        ];
+        for (name, source) in templates {
+            let template_path = dir.path().join(format!("{}.template", name));
+            if let Some(source) = source {
+                fs::write(&template_path, source).unwrap();
+            }
+            let mut file = config_file(&dir.path().join(name));
+            file.template_path = template_path.to_str().unwrap().try_into().unwrap();
+            config_files.insert(name.to_string(), file);
+        }
+
+        let mut registry = schnauzer::build_template_registry().unwrap();
+        let failed = register_templates(&mut registry, &config_files);
+        assert_eq!(
+            failed.keys().map(String::as_str).collect::<Vec<_>>(),
+            vec!["bad-syntax", "empty", "missing"]
+        );
+        match &failed["bad-syntax"] {
+            error::Error::TemplateRegister { .. } => {}
+            other => panic!("Expected TemplateRegister error, got {:?}", other),
+        }
+        match &failed["empty"] {
+            error::Error::EmptyTemplate { .. } => {}
+            other => panic!("Expected EmptyTemplate error, got {:?}", other),
+        }
+        match &failed["missing"] {
+            error::Error::TemplateRead { .. } => {}
+            other => panic!("Expected TemplateRead error, got {:?}", other),
+        }
+
+        // The good templates still render.
+        for name in failed.keys() {
+            config_files.remove(name);
+        }
+        let settings = json!({"settings": {"motd": "hi"}});
+        let rendered =
+            render_config_files(&registry, config_files, settings, HashMap::new(), true, 1)
+                .unwrap();
+        let rendered: Vec<_> = rendered.iter().map(|cfg| cfg.rendered()).collect();
+        assert_eq!(rendered, vec!["one=hi", "two=hi"]);
+    }
 }
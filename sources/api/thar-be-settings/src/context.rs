@@ -0,0 +1,117 @@
+//! The data a run works from, fetched from the API once, so that every configuration file it
+//! renders and every service it restarts comes from the same committed settings.
+
+use crate::{config, error, service, Result};
+use schnauzer::RetryPolicy;
+use serde::Deserialize;
+use snafu::{ensure, ResultExt};
+use std::collections::{BTreeSet, HashSet};
+use std::path::Path;
+
+/// The response to GET /settings/generation
+#[derive(Debug, Deserialize)]
+struct Generation {
+    generation: u64,
+}
+
+/// A snapshot of what the API knows, taken at one settings generation.  The generation increases
+/// with each commit that changes live settings, so comparing it to the API's current generation
+/// tells us whether the snapshot is still current.
+#[derive(Debug)]
+pub struct RunContext {
+    /// The settings generation the snapshot was taken at
+    pub generation: u64,
+    /// The full model from the API, less its services and configuration files, which are moved
+    /// to the fields below; this is the data source for templates
+    pub settings: model::Model,
+    /// The services selected by name or affected by the changed settings, or all services
+    pub services: model::Services,
+    /// The configuration files of the selected services, or all configuration files
+    pub config_files: model::ConfigurationFiles,
+}
+
+impl RunContext {
+    /// Fetches the settings, services, and configuration files from the API.  If `service_names`
+    /// is given, only those services are kept, and it's an error if any of them doesn't exist.  If
+    /// `changed_settings` is given, only the services affected by those settings are kept.  When
+    /// either narrows the services, only their configuration files are kept; otherwise all
+    /// services and configuration files are.
+    ///
+    /// The settings generation is read before and after fetching, and if it changed in between,
+    /// the data may mix old and new settings, so this fails with SettingsChanged and the caller
+    /// can fetch again.
+    #[allow(clippy::implicit_hasher)]
+    pub fn fetch<P>(
+        socket_path: P,
+        changed_settings: Option<&HashSet<String>>,
+        service_names: Option<&BTreeSet<String>>,
+        retry: &RetryPolicy,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let socket_path = socket_path.as_ref();
+        let generation = get_generation(socket_path, retry)?;
+
+        debug!("Requesting settings, services, and configuration files");
+        let mut settings =
+            schnauzer::get_settings(socket_path, retry).context(error::GetJson { uri: "/" })?;
+        let mut services = settings.services.take().unwrap_or_default();
+        let mut config_files = settings.configuration_files.take().unwrap_or_default();
+
+        if let Some(service_names) = service_names {
+            services = service::select_services(services, service_names)?;
+        }
+        if let Some(changed_settings) = changed_settings {
+            let setting_to_service_map =
+                service::get_affected_service_map(socket_path, changed_settings, retry)?;
+            services = service::retain_affected(services, setting_to_service_map);
+        }
+        if service_names.is_some() || changed_settings.is_some() {
+            let config_file_names = config::get_config_file_names(&services);
+            config_files = config_files
+                .into_iter()
+                .filter(|(name, _)| config_file_names.contains(name))
+                .collect();
+        }
+        trace!("Found services: {:?}", services);
+        trace!("Found config files: {:?}", config_files);
+
+        let context = Self {
+            generation,
+            settings,
+            services,
+            config_files,
+        };
+        context.check_current(socket_path, retry)?;
+        Ok(context)
+    }
+
+    /// Fails with SettingsChanged if live settings have changed since the snapshot was taken,
+    /// meaning anything rendered from it may be out of date.
+    pub fn check_current<P>(&self, socket_path: P, retry: &RetryPolicy) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let current = get_generation(socket_path.as_ref(), retry)?;
+        ensure!(
+            current == self.generation,
+            error::SettingsChanged {
+                fetched: self.generation,
+                current,
+            }
+        );
+        Ok(())
+    }
+}
+
+/// Requests the current settings generation from the API.
+fn get_generation(socket_path: &Path, retry: &RetryPolicy) -> Result<u64> {
+    debug!("Requesting settings generation");
+    let uri = "/settings/generation";
+    let response: Generation =
+        schnauzer::get_json(socket_path, uri, None as Option<(String, String)>, retry)
+            .context(error::GetJson { uri })?;
+    trace!("Settings generation: {}", response.generation);
+    Ok(response.generation)
+}
@@ -20,6 +20,24 @@ pub enum Error {
         source: serde_json::error::Error,
     },
 
+    #[snafu(display("Failed to read changed settings from {}: {}", path.display(), source))]
+    ReadKeysFile { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Invalid settings key '{}': {}", key, source))]
+    InvalidKey {
+        key: String,
+        source: model::key_name::Error,
+    },
+
+    #[snafu(display("Key '{}' is not a settings key, like 'settings.motd'", key))]
+    NotSettingsKey { key: String },
+
+    #[snafu(display("No changed settings were given"))]
+    NoChangedSettings,
+
+    #[snafu(display("Unknown services: {}; available services: {}", names, available))]
+    UnknownServices { names: String, available: String },
+
     #[snafu(display("Failed to write template {} to disk at {}: {}", pathtype, path.display(), source))]
     TemplateWrite {
         path: PathBuf,
@@ -27,21 +45,110 @@ pub enum Error {
         source: io::Error,
     },
 
+    #[snafu(display("Failed to move configuration file into place at {}: {}", path.display(), source))]
+    TemplatePersist {
+        path: PathBuf,
+        source: tempfile::PersistError,
+    },
+
+    #[snafu(display("Configuration file path {} is a directory", path.display()))]
+    DestinationDirectory { path: PathBuf },
+
+    #[snafu(display("Configuration file path {} is a symlink to nothing", path.display()))]
+    DanglingSymlink { path: PathBuf },
+
+    #[snafu(display("Failed to back up configuration file at {}: {}", path.display(), source))]
+    Backup { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Check command is invalid (empty, space prefix, etc.) - {}", command))]
+    InvalidCheckCommand { command: String },
+
+    #[snafu(display("Failed to run check command - '{}': {}", command, source))]
+    CheckExecution { command: String, source: io::Error },
+
+    #[snafu(display("Check command '{}' rejected configuration file {}: {}", command, path.display(), stderr))]
+    CheckFailed {
+        command: String,
+        path: PathBuf,
+        stderr: String,
+    },
+
+    #[snafu(display("Failed to look up user '{}': {}", user, source))]
+    UserLookup { user: String, source: nix::Error },
+
+    #[snafu(display("No user named '{}'", user))]
+    UnknownUser { user: String },
+
+    #[snafu(display("Failed to look up group '{}': {}", group, source))]
+    GroupLookup { group: String, source: nix::Error },
+
+    #[snafu(display("No group named '{}'", group))]
+    UnknownGroup { group: String },
+
+    #[snafu(display("Must run as root to change the owner of configuration file {}", path.display()))]
+    ChownNotRoot { path: PathBuf },
+
+    #[snafu(display("Failed to change owner of {}: {}", path.display(), source))]
+    Chown { path: PathBuf, source: nix::Error },
+
     #[snafu(display("Failed to run restart command - '{}': {}", command, source))]
     CommandExecutionFailure { command: String, source: io::Error },
 
-    #[snafu(display("Restart command failed - '{}': {}", command, stderr))]
-    FailedRestartCommand { command: String, stderr: String },
-
     #[snafu(display("Restart command is invalid (empty, space prefix, etc.) - {}", command))]
     InvalidRestartCommand { command: String },
 
+    #[snafu(display("Service restart-after lists form a cycle: {}", cycle))]
+    RestartCycle { cycle: String },
+
+    #[snafu(display("Failed to serialize settings for rendering: {}", source))]
+    SettingsSerialize { source: serde_json::Error },
+
+    #[snafu(display("Failed to read template '{}' from '{}': {}", name, path.display(), source))]
+    TemplateRead {
+        name: String,
+        path: PathBuf,
+        source: io::Error,
+    },
+
+    #[snafu(display("Invalid template '{}' at '{}': {}", name, path.display(), source))]
+    TemplateRegister {
+        name: String,
+        path: PathBuf,
+        source: handlebars::TemplateError,
+    },
+
+    #[snafu(display("Template '{}' at '{}' is empty", name, path.display()))]
+    EmptyTemplate { name: String, path: PathBuf },
+
+    #[snafu(display("Partial path '{}' has no file name to register it under", path.display()))]
+    PartialName { path: PathBuf },
+
+    #[snafu(display("Partials '{}' and '{}' have the same name, '{}'", path.display(), other.display(), name))]
+    PartialConflict {
+        name: String,
+        path: PathBuf,
+        other: PathBuf,
+    },
+
+    #[snafu(display(
+        "Template '{}' includes partial '{}', which its configuration file doesn't list",
+        template,
+        partial
+    ))]
+    MissingPartial { template: String, partial: String },
+
     #[snafu(display("Configuration file '{}' failed to render: {}", template, source))]
     TemplateRender {
         template: String,
         source: handlebars::RenderError,
     },
 
+    #[snafu(display("Failed to read OS release info from '{}': {}", path.display(), source))]
+    ReadOsRelease { path: PathBuf, source: io::Error },
+
+    #[snafu(display("OS release info in '{}' is missing {}", path.display(), field))]
+    OsReleaseField { path: PathBuf, field: &'static str },
+
     #[snafu(display("Error sending {} to {}: {}", method, uri, source))]
     APIRequest {
         method: String,
@@ -74,4 +181,352 @@ pub enum Error {
         uri: String,
         source: schnauzer::Error,
     },
+
+    #[snafu(display(
+        "Settings changed from generation {} to {} while they were being applied",
+        fetched,
+        current
+    ))]
+    SettingsChanged { fetched: u64, current: u64 },
+}
+
+/// Failure says which step of applying settings an error came from, so callers can report
+/// different failures differently, for example with different exit codes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failure {
+    /// The changed settings given to us were missing or invalid
+    Input,
+    /// We couldn't get what we needed from the API
+    Api,
+    /// A template couldn't be loaded or rendered
+    Render,
+    /// A configuration file couldn't be written
+    Write,
+    /// A configuration file's check command couldn't be run, or rejected the file
+    Check,
+    /// A service couldn't be restarted
+    Restart,
+}
+
+impl Error {
+    /// Returns the API's code for the error, if it came from an API error response, so callers
+    /// can tell, for example, settings that don't exist from a corrupt data store.
+    pub fn api_error_code(&self) -> Option<&str> {
+        match self {
+            Error::GetJson { source, .. } => source.api_error_code(),
+            _ => None,
+        }
+    }
+
+    /// Returns the step of applying settings that failed.  Each variant is listed, rather than
+    /// using a catch-all, so that new variants have to be classified.
+    pub fn failure(&self) -> Failure {
+        match self {
+            Error::ReadInput { .. }
+            | Error::InvalidInput { .. }
+            | Error::ReadKeysFile { .. }
+            | Error::InvalidKey { .. }
+            | Error::NotSettingsKey { .. }
+            | Error::NoChangedSettings
+            | Error::UnknownServices { .. } => Failure::Input,
+
+            Error::APIRequest { .. }
+            | Error::APIResponse { .. }
+            | Error::ResponseJson { .. }
+            | Error::GetJson { .. }
+            | Error::SettingsChanged { .. } => Failure::Api,
+
+            Error::SettingsSerialize { .. }
+            | Error::TemplateRead { .. }
+            | Error::TemplateRegister { .. }
+            | Error::EmptyTemplate { .. }
+            | Error::PartialName { .. }
+            | Error::PartialConflict { .. }
+            | Error::MissingPartial { .. }
+            | Error::TemplateRender { .. }
+            | Error::ReadOsRelease { .. }
+            | Error::OsReleaseField { .. } => Failure::Render,
+
+            Error::TemplateWrite { .. }
+            | Error::TemplatePersist { .. }
+            | Error::DestinationDirectory { .. }
+            | Error::DanglingSymlink { .. }
+            | Error::Backup { .. }
+            | Error::UserLookup { .. }
+            | Error::UnknownUser { .. }
+            | Error::GroupLookup { .. }
+            | Error::UnknownGroup { .. }
+            | Error::ChownNotRoot { .. }
+            | Error::Chown { .. } => Failure::Write,
+
+            Error::InvalidCheckCommand { .. }
+            | Error::CheckExecution { .. }
+            | Error::CheckFailed { .. } => Failure::Check,
+
+            Error::CommandExecutionFailure { .. }
+            | Error::InvalidRestartCommand { .. }
+            | Error::RestartCycle { .. } => Failure::Restart,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use handlebars::Handlebars;
+    use model::key_name;
+
+    fn io_error() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "oops")
+    }
+
+    fn json_error() -> serde_json::Error {
+        serde_json::from_str::<serde_json::Value>("{").unwrap_err()
+    }
+
+    fn nix_error() -> nix::Error {
+        nix::Error::Sys(nix::errno::Errno::EPERM)
+    }
+
+    #[test]
+    fn every_failure() {
+        let path = || PathBuf::from("/etc/motd");
+        let s = || "motd".to_string();
+        let template_error = Handlebars::new()
+            .register_template_string("bad", "{{#if}}")
+            .unwrap_err();
+
+        let cases = vec![
+            (
+                Error::ReadInput {
+                    location: "stdin",
+                    source: io_error(),
+                },
+                Failure::Input,
+            ),
+            (
+                Error::InvalidInput {
+                    reason: "bad",
+                    input: s(),
+                    source: json_error(),
+                },
+                Failure::Input,
+            ),
+            (
+                Error::ReadKeysFile {
+                    path: path(),
+                    source: io_error(),
+                },
+                Failure::Input,
+            ),
+            (
+                Error::InvalidKey {
+                    key: s(),
+                    source: key_name::parse_name_segments("").unwrap_err(),
+                },
+                Failure::Input,
+            ),
+            (Error::NotSettingsKey { key: s() }, Failure::Input),
+            (Error::NoChangedSettings, Failure::Input),
+            (
+                Error::UnknownServices {
+                    names: s(),
+                    available: s(),
+                },
+                Failure::Input,
+            ),
+            (
+                Error::TemplateWrite {
+                    path: path(),
+                    pathtype: "file",
+                    source: io_error(),
+                },
+                Failure::Write,
+            ),
+            (
+                Error::TemplatePersist {
+                    path: path(),
+                    source: tempfile::PersistError {
+                        error: io_error(),
+                        file: tempfile::NamedTempFile::new().unwrap(),
+                    },
+                },
+                Failure::Write,
+            ),
+            (Error::DestinationDirectory { path: path() }, Failure::Write),
+            (Error::DanglingSymlink { path: path() }, Failure::Write),
+            (
+                Error::Backup {
+                    path: path(),
+                    source: io_error(),
+                },
+                Failure::Write,
+            ),
+            (Error::InvalidCheckCommand { command: s() }, Failure::Check),
+            (
+                Error::CheckExecution {
+                    command: s(),
+                    source: io_error(),
+                },
+                Failure::Check,
+            ),
+            (
+                Error::CheckFailed {
+                    command: s(),
+                    path: path(),
+                    stderr: s(),
+                },
+                Failure::Check,
+            ),
+            (
+                Error::UserLookup {
+                    user: s(),
+                    source: nix_error(),
+                },
+                Failure::Write,
+            ),
+            (Error::UnknownUser { user: s() }, Failure::Write),
+            (
+                Error::GroupLookup {
+                    group: s(),
+                    source: nix_error(),
+                },
+                Failure::Write,
+            ),
+            (Error::UnknownGroup { group: s() }, Failure::Write),
+            (Error::ChownNotRoot { path: path() }, Failure::Write),
+            (
+                Error::Chown {
+                    path: path(),
+                    source: nix_error(),
+                },
+                Failure::Write,
+            ),
+            (
+                Error::CommandExecutionFailure {
+                    command: s(),
+                    source: io_error(),
+                },
+                Failure::Restart,
+            ),
+            (
+                Error::InvalidRestartCommand { command: s() },
+                Failure::Restart,
+            ),
+            (Error::RestartCycle { cycle: s() }, Failure::Restart),
+            (
+                Error::SettingsSerialize {
+                    source: json_error(),
+                },
+                Failure::Render,
+            ),
+            (
+                Error::TemplateRead {
+                    name: s(),
+                    path: path(),
+                    source: io_error(),
+                },
+                Failure::Render,
+            ),
+            (
+                Error::TemplateRegister {
+                    name: s(),
+                    path: path(),
+                    source: template_error,
+                },
+                Failure::Render,
+            ),
+            (
+                Error::EmptyTemplate {
+                    name: s(),
+                    path: path(),
+                },
+                Failure::Render,
+            ),
+            (Error::PartialName { path: path() }, Failure::Render),
+            (
+                Error::PartialConflict {
+                    name: s(),
+                    path: path(),
+                    other: path(),
+                },
+                Failure::Render,
+            ),
+            (
+                Error::MissingPartial {
+                    template: s(),
+                    partial: s(),
+                },
+                Failure::Render,
+            ),
+            (
+                Error::TemplateRender {
+                    template: s(),
+                    source: handlebars::RenderError::new("oops"),
+                },
+                Failure::Render,
+            ),
+            (
+                Error::ReadOsRelease {
+                    path: path(),
+                    source: io_error(),
+                },
+                Failure::Render,
+            ),
+            (
+                Error::OsReleaseField {
+                    path: path(),
+                    field: "VERSION_ID",
+                },
+                Failure::Render,
+            ),
+            (
+                Error::APIRequest {
+                    method: "GET".to_string(),
+                    uri: s(),
+                    source: apiclient::Error::ClientSetup { source: io_error() },
+                },
+                Failure::Api,
+            ),
+            (
+                Error::APIResponse {
+                    method: "GET".to_string(),
+                    uri: s(),
+                    code: StatusCode::SERVICE_UNAVAILABLE,
+                    response_body: s(),
+                },
+                Failure::Api,
+            ),
+            (
+                Error::ResponseJson {
+                    method: "GET",
+                    uri: s(),
+                    source: json_error(),
+                },
+                Failure::Api,
+            ),
+            (
+                Error::GetJson {
+                    uri: s(),
+                    source: schnauzer::Error::ResponseJson {
+                        method: "GET",
+                        uri: s(),
+                        source: json_error(),
+                    },
+                },
+                Failure::Api,
+            ),
+            (
+                Error::SettingsChanged {
+                    fetched: 1,
+                    current: 2,
+                },
+                Failure::Api,
+            ),
+        ];
+
+        for (error, failure) in cases {
+            assert_eq!(error.failure(), failure, "{}", error);
+        }
+    }
 }
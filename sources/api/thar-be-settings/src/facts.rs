@@ -0,0 +1,134 @@
+//! The facts module gathers facts about the host that aren't settings, like the OS version, so
+//! templates can use them next to settings, as in `{{os.version_id}}`.  It's behind a trait so
+//! tests can give fixed facts rather than the host's.
+
+use crate::{error, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+use snafu::{OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// The file describing the running OS.
+const OS_RELEASE: &str = "/etc/os-release";
+
+/// Facts gives facts about the host for templates.
+pub trait Facts {
+    /// Returns facts about the running OS.
+    fn os(&self) -> Result<OsFacts>;
+}
+
+/// The `os` section of the template data.  The field names match the API's `os` data, which
+/// these add to.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OsFacts {
+    /// The OS version, like 0.3.1
+    pub version_id: String,
+    /// The variant, like aws-k8s
+    pub variant_id: String,
+    /// The architecture, like x86_64
+    pub arch: String,
+}
+
+/// SystemFacts reads facts from the running system.
+pub struct SystemFacts;
+
+impl Facts for SystemFacts {
+    fn os(&self) -> Result<OsFacts> {
+        let path = Path::new(OS_RELEASE);
+        let contents = fs::read_to_string(path).context(error::ReadOsRelease { path })?;
+        parse_os_release(&contents, path)
+    }
+}
+
+/// Parses os-release contents, made of lines like `VERSION_ID=0.3.1`, where values may be quoted.
+fn parse_os_release(contents: &str, path: &Path) -> Result<OsFacts> {
+    let fields: HashMap<&str, &str> = contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next()?.trim();
+            let value = parts.next()?.trim().trim_matches(|c| c == '"' || c == '\'');
+            Some((key, value))
+        })
+        .collect();
+    let field = |field: &'static str| {
+        fields
+            .get(field)
+            .map(|value| value.to_string())
+            .context(error::OsReleaseField { path, field })
+    };
+
+    Ok(OsFacts {
+        version_id: field("VERSION_ID")?,
+        variant_id: field("VARIANT_ID")?,
+        arch: env::consts::ARCH.to_string(),
+    })
+}
+
+/// Returns the sections of template data made from the given facts, by name, to pass to
+/// config::render_config_files.  Facts that can't be gathered are left out, with a warning, so
+/// templates that don't use them still render; templates that do fail to render, like templates
+/// that use unset settings.
+pub fn template_context(facts: &dyn Facts) -> HashMap<String, Value> {
+    let mut context = HashMap::new();
+    match facts.os() {
+        Ok(os) => {
+            context.insert("os".to_string(), json!(os));
+        }
+        Err(e) => warn!("Unable to gather OS facts for templates: {}", e),
+    }
+    context
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn os_release_parsed() {
+        let contents = "NAME=Bottlerocket\n\
+                        # a comment\n\
+                        VERSION_ID=0.3.1\n\
+                        PRETTY_NAME=\"Bottlerocket OS 0.3.1\"\n\
+                        VARIANT_ID='aws-k8s'\n";
+        let os = parse_os_release(contents, Path::new("os-release")).unwrap();
+        assert_eq!(
+            os,
+            OsFacts {
+                version_id: "0.3.1".to_string(),
+                variant_id: "aws-k8s".to_string(),
+                arch: env::consts::ARCH.to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn os_release_missing_field() {
+        let contents = "NAME=Bottlerocket\nVARIANT_ID=aws-k8s\n";
+        match parse_os_release(contents, Path::new("os-release")) {
+            Err(error::Error::OsReleaseField { field, .. }) => assert_eq!(field, "VERSION_ID"),
+            other => panic!("Expected missing VERSION_ID, got {:?}", other),
+        }
+    }
+
+    struct NoFacts;
+
+    impl Facts for NoFacts {
+        fn os(&self) -> Result<OsFacts> {
+            error::OsReleaseField {
+                path: OS_RELEASE,
+                field: "VERSION_ID",
+            }
+            .fail()
+        }
+    }
+
+    #[test]
+    fn missing_facts_left_out() {
+        assert!(template_context(&NoFacts).is_empty());
+    }
+}
@@ -5,13 +5,46 @@ thar-be-settings is a simple configuration applier.
 Its job is to update configuration files and restart services, as necessary, to make the system reflect any changes to settings.
 
 In the normal ("specific keys") mode, it's intended to be called by the Bottlerocket API server after a settings commit.
-It's told the keys that changed, and then queries metadata APIs to determine which services and configuration files are affected by changes to those keys.
-Detailed data is then fetched for the relevant services and configuration files.
+It's told the keys that changed, as JSON on stdin, and then queries metadata APIs to determine which services and configuration files are affected by changes to those keys.
+Settings, services, and configuration files are fetched once per run, along with the settings generation, which the API server increases with each commit that changes settings.
+Just before writing configuration files, it checks that the generation hasn't changed; if it has, it fetches everything again and renders again, up to three times, so that the files it writes all reflect the same settings.
+It talks to the API over its Unix-domain socket, `/run/api.sock` by default, which can be given as a path or a `unix://` URI with `--socket-path` or the `API_SOCKET` environment variable.
+Requests to the API are retried with increasing, randomized delays if the API can't be reached, for example while it's starting, or has a server error; `--api-attempts` and `--api-deadline` limit how long it keeps trying.
 Configuration file data from the API includes paths to template files for each configuration file, along with the final path to write.
-It then renders the templates and rewrites the affected configuration files.
+It can also list paths to partials, shared template fragments registered under their file names, which the template can include like `{{> proxy-env}}`; a template that includes a partial its configuration file doesn't list is invalid.
+Besides settings, templates can use facts about the host that aren't settings, like `{{os.version_id}}`, `{{os.variant_id}}`, and `{{os.arch}}`, read from `/etc/os-release`; they're added to the API's `os` data.
+If they can't be read, a warning is logged, and only templates that use them fail to render.
+All templates are checked before anything is rendered; configuration files whose templates are missing, empty, or invalid are skipped and listed in the final error, while the others are still written, unless `--strict` is given, in which case nothing is written.
+It then renders the templates and rewrites the affected configuration files whose contents changed.
 Service data from the API includes any commands needed to restart services affected by configuration file changes, which are run here.
+Each command is either a string, which is split on spaces, or an array of the program and its arguments, which are passed as given; neither is run through a shell.
+Services whose configuration files were all left unchanged aren't restarted.
+Affected services are restarted one at a time, each once, after any affected services named in their `restart-after` lists; if those lists form a cycle, nothing is restarted.
+With `--jobs N`, up to N services that don't depend on each other are restarted at once, and each service's results and output are logged together when it finishes.
+Configuration files are rendered in parallel.
+If a service's restart commands fail, the other services are still restarted, and thar-be-settings exits with an error naming the services that failed; with `--json`, it prints a summary of each command it ran.
+With `--output json`, it instead prints progress events to stdout, one JSON object per line, as it goes: the start of the run, each configuration file rendered, written, unchanged, or failed, each service's restart starting and finishing, with how long it took, and a final summary; logging all goes to stderr.
+The events are defined in the models crate's `apply_progress` module, so the API server can read them.
+Restart commands that run longer than the service's `restart-timeout`, or `--restart-timeout` seconds (two minutes by default), are killed along with anything they started, and count as failures.
+Configuration files can also give the mode, user, and group of the rendered file; changing its owner requires running as root.
+A configuration file can also have a check command, which is run on the rendered file before it's installed.
+If the check fails, the file isn't installed, services that only use it aren't restarted, and thar-be-settings exits with an error after handling everything else.
 
-In the standalone ("all keys") mode, it queries the API for all services and configuration files, then renders and rewrites all configuration files and restarts all services.
+Changed keys can also be given with `--keys settings.motd,settings.ntp`, or as the same JSON in a file with `--keys-file PATH`, which is handy when running it by hand; each must be a settings key, and at least one is required.
+
+In the standalone ("all keys") mode, started with `--all`, it doesn't read changed keys from stdin; it queries the API for all configuration files, then renders and rewrites all of them.
+With `--restart`, it also queries the API for all services and restarts them, in `restart-after` order; otherwise no services are restarted, which suits first boot or recovery.
+
+To work on just some services, for example when debugging one, `--services motd,chronyd` limits a run to the named services and their configuration files.
+With `--all`, only their files are written, and with `--restart`, only they are restarted, in `restart-after` order; with changed keys, only the named services that the keys affect are applied.
+Naming a service the API doesn't know is an error, which lists the known services.
+
+For debugging a template, `thar-be-settings render --file NAME` prints a single configuration file, rendered with the current settings, without writing anything or restarting any services.
+It uses the same template helpers and rendering as a normal run, so the output matches what would be written.
+`--template PATH` renders the given template instead of the configuration file's own, and `--settings-json PATH` reads settings from a file, in the same form as the API's response for `/`; with both, the API isn't needed.
+
+When it fails, its exit code says why: 3 for invalid input, like changed settings or a settings file, 4 if the API couldn't be reached or returned an error, 5 if templates failed to render, 6 if configuration files couldn't be written, 7 if check commands failed, 8 if services failed to restart after changes were applied, 9 if they failed and nothing was applied, and 10 if `--check` found files that would change.
+The code and its meaning are printed with the error.
 */
 
 #![deny(rust_2018_idioms)]
@@ -19,33 +52,176 @@ In the standalone ("all keys") mode, it queries the API for all services and con
 #[macro_use]
 extern crate log;
 
-use snafu::ResultExt;
+use model::key_name;
+use snafu::{ensure, ResultExt};
 use std::collections::HashSet;
+use std::fs;
 use std::io::{self, Read};
+use std::path::PathBuf;
 
 pub mod config;
+pub mod context;
 pub mod error;
+pub mod facts;
+pub mod owner;
 pub mod service;
 
-pub use error::Error;
+pub use error::{Error, Failure};
 type Result<T> = std::result::Result<T, Error>;
 
-/// Read stdin and parse into JSON
-pub fn get_changed_settings() -> Result<HashSet<String>> {
-    let mut input = String::new();
-    io::stdin()
-        .read_to_string(&mut input)
-        .context(error::ReadInput { location: "stdin" })?;
-    trace!("Raw input from stdin: {}", &input);
+/// KeySource says where to find the settings keys that changed.
+#[derive(Debug, PartialEq)]
+pub enum KeySource {
+    /// JSON on stdin, as sent by the API server
+    Stdin,
+    /// A comma-separated list of keys, like "settings.motd,settings.ntp.time-servers"
+    List(String),
+    /// A file containing the same JSON the API server sends on stdin
+    File(PathBuf),
+}
+
+/// Read the changed settings from the given source.  Each must be a valid settings key, and
+/// there must be at least one.
+pub fn get_changed_settings(source: &KeySource) -> Result<HashSet<String>> {
+    let changed_settings = match source {
+        KeySource::Stdin => {
+            let mut input = String::new();
+            io::stdin()
+                .read_to_string(&mut input)
+                .context(error::ReadInput { location: "stdin" })?;
+            trace!("Raw input from stdin: {}", &input);
+
+            debug!("Parsing stdin as JSON");
+            parse_changed_settings(&input)?
+        }
+        KeySource::List(list) => parse_key_list(list),
+        KeySource::File(path) => {
+            let input = fs::read_to_string(path).context(error::ReadKeysFile { path })?;
+            trace!("Raw input from {}: {}", path.display(), &input);
+
+            debug!("Parsing {} as JSON", path.display());
+            parse_changed_settings(&input)?
+        }
+    };
+
+    check_changed_settings(&changed_settings)?;
+    Ok(changed_settings)
+}
+
+/// Parses a comma-separated list of changed settings, like "settings.motd,settings.ntp".
+/// Whitespace around each key is ignored.
+fn parse_key_list(list: &str) -> HashSet<String> {
+    list.split(',')
+        .map(|key| key.trim())
+        .filter(|key| !key.is_empty())
+        .map(|key| key.to_string())
+        .collect()
+}
+
+/// Makes sure there's at least one changed setting, and that each is a valid settings key.
+fn check_changed_settings(changed_settings: &HashSet<String>) -> Result<()> {
+    ensure!(!changed_settings.is_empty(), error::NoChangedSettings);
+    for key in changed_settings {
+        let segments = key_name::parse_name_segments(key).context(error::InvalidKey { key })?;
+        key_name::check_name_segments(key, &segments).context(error::InvalidKey { key })?;
+        ensure!(
+            segments.len() > 1 && segments[0] == "settings",
+            error::NotSettingsKey { key }
+        );
+    }
+    Ok(())
+}
 
-    // Settings should be a vec of strings
-    debug!("Parsing stdin as JSON");
+/// Parses the changed settings sent by the API server.  This is either a JSON object whose "keys"
+/// are the changed settings, alongside the services they affect, or just an array of the changed
+/// settings, as older API servers send.
+fn parse_changed_settings(input: &str) -> Result<HashSet<String>> {
+    let reason = "Input must be a JSON array of strings, or an object with a 'keys' array";
+    let parsed: serde_json::Value =
+        serde_json::from_str(input).context(error::InvalidInput { reason, input })?;
+    let keys = match parsed {
+        serde_json::Value::Object(mut object) => {
+            object.remove("keys").unwrap_or(serde_json::Value::Null)
+        }
+        other => other,
+    };
     let changed_settings: HashSet<String> =
-        serde_json::from_str(&input).context(error::InvalidInput {
-            reason: "Input must be a JSON array of strings",
-            input,
-        })?;
+        serde_json::from_value(keys).context(error::InvalidInput { reason, input })?;
     trace!("Parsed input: {:?}", &changed_settings);
 
     Ok(changed_settings)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maplit::hashset;
+
+    #[test]
+    fn changed_settings_formats() {
+        let expected = hashset!("settings.motd".to_string());
+        assert_eq!(
+            parse_changed_settings(r#"{"keys": ["settings.motd"], "services": ["motd"]}"#).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_changed_settings(r#"["settings.motd"]"#).unwrap(),
+            expected
+        );
+        assert!(parse_changed_settings(r#"{"services": ["motd"]}"#).is_err());
+    }
+
+    #[test]
+    fn changed_settings_list() {
+        let expected = hashset!("settings.motd".to_string(), "settings.a.b".to_string());
+        let source = KeySource::List(" settings.motd, settings.a.b,,settings.motd ".to_string());
+        assert_eq!(get_changed_settings(&source).unwrap(), expected);
+    }
+
+    #[test]
+    fn changed_settings_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.json");
+
+        fs::write(
+            &path,
+            r#"{"keys": ["settings.motd"], "services": ["motd"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            get_changed_settings(&KeySource::File(path.clone())).unwrap(),
+            hashset!("settings.motd".to_string())
+        );
+
+        fs::write(&path, r#"["settings.motd""#).unwrap();
+        match get_changed_settings(&KeySource::File(path.clone())) {
+            Err(Error::InvalidInput { .. }) => {}
+            other => panic!("Expected InvalidInput error, got {:?}", other),
+        }
+
+        match get_changed_settings(&KeySource::File(dir.path().join("missing"))) {
+            Err(Error::ReadKeysFile { .. }) => {}
+            other => panic!("Expected ReadKeysFile error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bad_changed_settings() {
+        for list in &["", " , ", "settings.", "settings..motd", "settings.mo^td"] {
+            get_changed_settings(&KeySource::List(list.to_string())).unwrap_err();
+        }
+        for list in &["services.motd", "settings", "settings.motd,motd"] {
+            match get_changed_settings(&KeySource::List(list.to_string())) {
+                Err(Error::NotSettingsKey { .. }) => {}
+                other => panic!(
+                    "Expected NotSettingsKey error for {}, got {:?}",
+                    list, other
+                ),
+            }
+        }
+        match get_changed_settings(&KeySource::List("".to_string())) {
+            Err(Error::NoChangedSettings) => {}
+            other => panic!("Expected NoChangedSettings error, got {:?}", other),
+        }
+    }
+}
@@ -1,20 +1,52 @@
 #[macro_use]
 extern crate log;
 
+use itertools::join;
 use simplelog::{Config as LogConfig, LevelFilter, TermLogger, TerminalMode};
-use snafu::ResultExt;
-use std::collections::HashSet;
+use snafu::{ensure, ResultExt};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::convert::TryFrom;
 use std::env;
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
 use std::process;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use thar_be_settings::{config, get_changed_settings, service};
+use model::apply_progress::{
+    ApplyEvent, FileProgress, FileStatus, RestartStatus, RunStarted, Summary,
+};
+use model::modeled_types::SingleLineString;
+use schnauzer::RetryPolicy;
+use thar_be_settings::context::RunContext;
+use thar_be_settings::facts::{self, Facts, SystemFacts};
+use thar_be_settings::owner::SystemOwners;
+use thar_be_settings::{config, get_changed_settings, service, Failure, KeySource};
 
 // FIXME Get from configuration in the future
 const DEFAULT_API_SOCKET: &str = "/run/api.sock";
 
+/// Environment variable that can give the API socket, if --socket-path isn't given
+const API_SOCKET_ENV: &str = "API_SOCKET";
+
+/// The API is only served on a Unix-domain socket, which can be given as a URI with this scheme
+const UNIX_SCHEME: &str = "unix://";
+
+/// How many times to fetch settings, if they keep changing before we can write configuration
+/// files from them, before giving up
+const SNAPSHOT_ATTEMPTS: u32 = 3;
+
+/// How many backups of each configuration file to keep, if backups are requested
+const BACKUP_COUNT: usize = 3;
+
+/// How long to wait for each restart command, in seconds, if the service doesn't say
+const DEFAULT_RESTART_TIMEOUT: u64 = 120;
+
 mod error {
     use snafu::Snafu;
+    use std::io;
     use std::path::PathBuf;
 
     #[derive(Debug, Snafu)]
@@ -23,22 +55,158 @@ mod error {
         #[snafu(display("Logger setup error: {}", source))]
         Logger { source: simplelog::TermLogError },
 
-        #[snafu(display("Failure to read template '{}' from '{}': {}", name, path.display(), source))]
-        TemplateRegister {
-            name: String,
+        #[snafu(display("{}", source))]
+        Apply { source: thar_be_settings::Error },
+
+        #[snafu(display("Failed to build template registry: {}", source))]
+        TemplateRegistry { source: schnauzer::Error },
+
+        // `applied` says whether any configuration files were written before the failure.
+        #[snafu(display("{}", source))]
+        Restart {
+            source: thar_be_settings::Error,
+            applied: bool,
+        },
+
+        #[snafu(display("Failed to serialize restart report: {}", source))]
+        ReportSerialize { source: serde_json::Error },
+
+        #[snafu(display(
+            "Configuration files failed their check commands and weren't installed: {}",
+            files
+        ))]
+        FailedChecks { files: String },
+
+        #[snafu(display(
+            "Configuration files have invalid templates and weren't written: {}",
+            files
+        ))]
+        FailedTemplates { files: String },
+
+        #[snafu(display("Configuration files would change: {}", files))]
+        WouldChange { files: String },
+
+        // `applied` says whether any configuration files were written, or services restarted.
+        #[snafu(display("Failed to restart services: {}", services))]
+        FailedRestarts { services: String, applied: bool },
+
+        #[snafu(display(
+            "No configuration file named '{}'; available configuration files: {}",
+            name,
+            available
+        ))]
+        UnknownConfigFile { name: String, available: String },
+
+        #[snafu(display("Invalid template path '{}': {}", path.display(), source))]
+        TemplatePath {
+            path: PathBuf,
+            source: model::modeled_types::error::Error,
+        },
+
+        #[snafu(display("Failed to read settings from '{}': {}", path.display(), source))]
+        ReadSettings { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Invalid settings JSON in '{}': {}", path.display(), source))]
+        ParseSettings {
             path: PathBuf,
-            source: handlebars::TemplateFileError,
+            source: serde_json::Error,
         },
     }
 }
 
+/// ExitCode is what thar-be-settings exits with when it fails, so that automation running it can
+/// tell why.  It exits 0 on success.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExitCode {
+    Internal = 1,
+    Usage = 2,
+    InvalidInput = 3,
+    ApiFailed = 4,
+    RenderFailed = 5,
+    WriteFailed = 6,
+    CheckFailed = 7,
+    RestartsFailed = 8,
+    NothingApplied = 9,
+    WouldChange = 10,
+}
+
+impl ExitCode {
+    /// Describes what the exit code means, for the summary printed on failure.
+    fn meaning(self) -> &'static str {
+        match self {
+            ExitCode::Internal => "internal error",
+            ExitCode::Usage => "invalid arguments",
+            ExitCode::InvalidInput => "settings or keys given as input were missing or invalid",
+            ExitCode::ApiFailed => "the API couldn't be reached or returned an error",
+            ExitCode::RenderFailed => "configuration templates failed to render",
+            ExitCode::WriteFailed => "configuration files couldn't be written",
+            ExitCode::CheckFailed => "configuration files failed their check commands",
+            ExitCode::RestartsFailed => "services failed to restart after changes were applied",
+            ExitCode::NothingApplied => "services failed to restart and nothing was applied",
+            ExitCode::WouldChange => "configuration files would change",
+        }
+    }
+}
+
+/// Returns the exit code for the given error.  Each variant is listed, rather than using a
+/// catch-all, so that new variants have to be given a code.
+fn exit_code(error: &error::Error) -> ExitCode {
+    let restart_code = |applied: bool| {
+        if applied {
+            ExitCode::RestartsFailed
+        } else {
+            ExitCode::NothingApplied
+        }
+    };
+    match error {
+        error::Error::Logger { .. } | error::Error::ReportSerialize { .. } => ExitCode::Internal,
+        error::Error::Apply { source } => match source.failure() {
+            Failure::Input => ExitCode::InvalidInput,
+            Failure::Api => ExitCode::ApiFailed,
+            Failure::Render => ExitCode::RenderFailed,
+            Failure::Write => ExitCode::WriteFailed,
+            Failure::Check => ExitCode::CheckFailed,
+            // Restart errors after anything is applied are wrapped in Restart instead.
+            Failure::Restart => ExitCode::NothingApplied,
+        },
+        error::Error::TemplateRegistry { .. } => ExitCode::RenderFailed,
+        error::Error::Restart { applied, .. } => restart_code(*applied),
+        error::Error::FailedChecks { .. } => ExitCode::CheckFailed,
+        error::Error::FailedTemplates { .. } => ExitCode::RenderFailed,
+        error::Error::WouldChange { .. } => ExitCode::WouldChange,
+        error::Error::FailedRestarts { applied, .. } => restart_code(*applied),
+        error::Error::UnknownConfigFile { .. } => ExitCode::Usage,
+        error::Error::TemplatePath { .. }
+        | error::Error::ReadSettings { .. }
+        | error::Error::ParseSettings { .. } => ExitCode::InvalidInput,
+    }
+}
+
 /// RunMode represents how thar-be-settings was requested to be run, either handling all
 /// configuration files and services, or handling configuration files and services based on
-/// specific keys given by the user.
-#[derive(Debug)]
+/// specific keys given by the user, and where to read them, or rendering a single configuration
+/// file for debugging.
+#[derive(Debug, PartialEq)]
 enum RunMode {
     All,
-    SpecificKeys,
+    SpecificKeys(KeySource),
+    Render {
+        /// The name of the configuration file to render
+        file: String,
+        /// A template to render instead of the configuration file's own
+        template: Option<PathBuf>,
+        /// A file with the settings to render, instead of the current settings from the API
+        settings: Option<PathBuf>,
+    },
+}
+
+/// DryRun represents whether thar-be-settings was asked to only show the changes it would make,
+/// rather than making them, and if so, whether to fail if there are any.
+#[derive(Debug, PartialEq)]
+enum DryRun {
+    Off,
+    Show,
+    Check,
 }
 
 /// Store the args we receive on the command line
@@ -46,6 +214,113 @@ struct Args {
     log_level: LevelFilter,
     mode: RunMode,
     socket_path: String,
+    backup_dir: Option<PathBuf>,
+    dry_run: DryRun,
+    json: bool,
+    restart_timeout: Duration,
+    jobs: usize,
+    restart: bool,
+    strict: bool,
+    retry: RetryPolicy,
+    services: Option<BTreeSet<String>>,
+    progress: Progress,
+    /// Where facts about the host, like the OS version, come from for templates
+    facts: Box<dyn Facts>,
+}
+
+impl Args {
+    /// Whether stdout is kept for JSON output, with --json or --output json.
+    fn json_stdout(&self) -> bool {
+        self.json || self.progress.sink.is_some()
+    }
+}
+
+/// Progress sends an event for each step of a run, with --output json, and keeps track of what
+/// they reported, for the summary event that ends the run.
+struct Progress {
+    sink: Option<ProgressSink>,
+    started: Instant,
+    summary: RefCell<Summary>,
+}
+
+/// Where progress events go.
+enum ProgressSink {
+    /// Printed to stdout, one JSON object per line
+    Stdout,
+    /// Kept, as the lines that would be printed, so tests can check them
+    #[cfg(test)]
+    Lines(RefCell<Vec<String>>),
+}
+
+impl Progress {
+    fn new(sink: Option<ProgressSink>) -> Self {
+        Self {
+            sink,
+            started: Instant::now(),
+            summary: RefCell::new(Summary::default()),
+        }
+    }
+
+    fn send(&self, event: ApplyEvent) {
+        let sink = match &self.sink {
+            Some(sink) => sink,
+            None => return,
+        };
+
+        {
+            let mut summary = self.summary.borrow_mut();
+            match &event {
+                // A run that starts over only reports what happens after that.
+                ApplyEvent::RunStarted(_) => *summary = Summary::default(),
+                ApplyEvent::File(file) => match file.status {
+                    FileStatus::Written => summary.written.push(file.name.clone()),
+                    FileStatus::Failed => summary.failed_files.push(file.name.clone()),
+                    FileStatus::Rendered | FileStatus::Unchanged => {}
+                },
+                ApplyEvent::Restart(restart) => match restart.status {
+                    RestartStatus::Succeeded => summary.restarted.push(restart.service.clone()),
+                    RestartStatus::Failed => summary.failed_services.push(restart.service.clone()),
+                    RestartStatus::Started => {}
+                },
+                ApplyEvent::Summary(_) => {}
+            }
+        }
+
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Unable to serialize progress event {:?}: {}", event, e);
+                return;
+            }
+        };
+        match sink {
+            ProgressSink::Stdout => println!("{}", line),
+            #[cfg(test)]
+            ProgressSink::Lines(lines) => lines.borrow_mut().push(line),
+        }
+    }
+
+    /// Sends a progress event for the named configuration file.
+    fn file(&self, name: &str, status: FileStatus, error: Option<String>) {
+        self.send(ApplyEvent::File(FileProgress {
+            name: name.to_string(),
+            status,
+            error,
+        }));
+    }
+
+    /// Sends the summary event for a run that ended with the given result.
+    fn finish(&self, result: &Result<(), error::Error>, dry_run: bool) {
+        let mut summary = self.summary.borrow().clone();
+        summary.success = result.is_ok();
+        if let Err(e) = result {
+            summary.exit_code = exit_code(e) as i32;
+            summary.error = Some(e.to_string());
+        }
+        summary.dry_run = dry_run;
+        summary.duration_ms = self.started.elapsed().as_millis() as u64;
+        self.send(ApplyEvent::Summary(summary));
+    }
 }
 
 /// Print a usage message in the event a bad arg is passed
@@ -53,19 +328,104 @@ fn usage() -> ! {
     let program_name = env::args().next().unwrap_or_else(|| "program".to_string());
     eprintln!(
         r"Usage: {}
-            [ --all ]
-            [ --socket-path PATH ]
+            [ --all [ --restart ] | --keys KEY[,KEY...] | --keys-file PATH ]
+            [ --services NAME[,NAME...] ]
+            [ --socket-path PATH | unix://PATH ]
+            [ --backup-dir PATH ]
+            [ --dry-run | --check ]
+            [ --strict ]
+            [ --json | --output text|json ]
+            [ --restart-timeout SECONDS ]
+            [ --jobs N ]
+            [ --api-attempts N ] [ --api-deadline SECONDS ]
+            [ --log-level trace|debug|info|warn|error ]
+
+       {} render --file NAME
+            [ --template PATH ]
+            [ --settings-json PATH ]
+            [ --socket-path PATH | unix://PATH ]
             [ --log-level trace|debug|info|warn|error ]
 
-    If --all is given, all configuration files will be written and all
-    services will have their restart-commands run.  Otherwise, settings keys
-    will be read from stdin; only files related to those keys will be written,
-    and only services related to those keys will be restarted.
+    If --all is given, all configuration files will be written, and with
+    --restart, all services will have their restart-commands run.  Otherwise,
+    only files related to the changed settings keys will be written, and only
+    services related to those keys will be restarted.  The keys are given as
+    a comma-separated list with --keys, or as JSON in the file given by
+    --keys-file; by default, the JSON is read from stdin.  The JSON is a list
+    of keys, or an object with a 'keys' list, like the API server sends.
+
+    If --services is given, only the named services, and their configuration
+    files, are applied: with --all, those services' files are written, and
+    with --restart, only those services are restarted; with changed keys,
+    only the named services that the keys affect are applied.  It's an error
+    to name a service the API doesn't know.
+
+    If --backup-dir is given, the previous contents of each configuration
+    file are saved there, under the file's full path, before it's replaced.
+    The last {} backups of each file are kept.
 
-    Socket path defaults to {}",
-        program_name, DEFAULT_API_SOCKET,
+    If --dry-run is given, nothing is written or restarted; instead, a diff
+    is printed for each configuration file that would change, along with the
+    restart-commands that would be run.  --check does the same, but exits
+    nonzero if any configuration file would change.  With --json or --output
+    json, these are printed to stderr, so stdout is just the JSON.
+
+    Templates are checked before anything is rendered.  Configuration files
+    with missing, empty, or invalid templates are skipped, and the others
+    are still written, but thar-be-settings exits nonzero, listing the
+    skipped files.  With --strict, nothing is written if any template is
+    invalid, or, with --all, if any template fails to render.
+
+    If --json is given, a summary of the restart-commands run for each
+    service, with the exit status and end of stderr of any that failed, is
+    printed to stdout as JSON.
+
+    With --output json, an event is printed to stdout, as a line of JSON, as
+    each step happens: the start of the run, with the keys and services
+    being applied; each configuration file rendered, written, unchanged, or
+    failed; each service's restart starting and succeeding or failing; and a
+    summary at the end.  Log messages all go to stderr.  The default is
+    --output text, which only logs.
+
+    Each restart-command is killed, along with anything it started, if it
+    runs longer than the service's restart-timeout, or --restart-timeout
+    seconds if the service doesn't have one.  The default is {} seconds.
+
+    If --jobs is given, up to N services are restarted at once, though each
+    still waits for the services in its restart-after list.  By default,
+    services are restarted one at a time.
+
+    The render command prints the named configuration file, rendered with
+    the current settings, without writing anything or restarting any
+    services.  With --template, the given template is rendered instead of
+    the configuration file's own, without any partials; with --settings-json,
+    the settings are read from the given file, in the same form as the API's
+    response for '/', rather than from the API.  With both, the API isn't
+    used at all.
+
+    Requests to the API that fail because it can't be reached, or has a
+    server error, are retried with increasing delays.  Each request is tried
+    up to --api-attempts times, default {}, for up to --api-deadline
+    seconds, default {}.
+
+    The API socket can be given as a path or a unix:// URI, with
+    --socket-path or the {} environment variable.  It defaults to {}
+
+    Exit codes: 0 success, 1 internal error, 2 invalid arguments, 3 invalid
+    input settings or keys, 4 API failure, 5 template render failure, 6 file
+    write failure, 7 check command failure, 8 restarts failed after changes
+    were applied, 9 restarts failed and nothing was applied, 10 files would
+    change with --check",
+        program_name,
+        program_name,
+        BACKUP_COUNT,
+        DEFAULT_RESTART_TIMEOUT,
+        RetryPolicy::DEFAULT_MAX_ATTEMPTS,
+        RetryPolicy::DEFAULT_DEADLINE_SECS,
+        API_SOCKET_ENV,
+        DEFAULT_API_SOCKET,
     );
-    process::exit(2);
+    process::exit(ExitCode::Usage as i32);
 }
 
 /// Prints a more specific message before exiting through usage().
@@ -77,13 +437,87 @@ fn usage_msg<S: AsRef<str>>(msg: S) -> ! {
 /// Parse the args to the program and return an Args struct
 fn parse_args(args: env::Args) -> Args {
     let mut log_level = None;
-    let mut mode = RunMode::SpecificKeys;
+    let mut all = false;
+    let mut key_sources = Vec::new();
     let mut socket_path = None;
+    let mut backup_dir = None;
+    let mut dry_run = DryRun::Off;
+    let mut json = false;
+    let mut restart_timeout = None;
+    let mut jobs = 1;
+    let mut restart = false;
+    let mut strict = false;
+    let mut api_attempts = RetryPolicy::DEFAULT_MAX_ATTEMPTS;
+    let mut api_deadline = RetryPolicy::DEFAULT_DEADLINE_SECS;
+    let mut file = None;
+    let mut template = None;
+    let mut settings_json = None;
+    let mut services = None;
+    let mut output_json = false;
 
-    let mut iter = args.skip(1);
+    let mut iter = args.skip(1).peekable();
+    let render = iter.peek().map_or(false, |arg| arg == "render");
+    if render {
+        iter.next();
+    }
     while let Some(arg) = iter.next() {
         match arg.as_ref() {
-            "--all" => mode = RunMode::All,
+            "--all" => all = true,
+
+            "--keys" => {
+                let keys = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --keys"));
+                key_sources.push(KeySource::List(keys));
+            }
+
+            "--keys-file" => {
+                let path = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --keys-file"));
+                key_sources.push(KeySource::File(PathBuf::from(path)));
+            }
+
+            "--services" => {
+                let list = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --services"));
+                let names: BTreeSet<String> = list
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(String::from)
+                    .collect();
+                if names.is_empty() {
+                    usage_msg(format!("No services given in '{}'", list));
+                }
+                services = Some(names);
+            }
+
+            "--restart" => restart = true,
+
+            "--strict" => strict = true,
+
+            "--dry-run" => {
+                if dry_run == DryRun::Off {
+                    dry_run = DryRun::Show
+                }
+            }
+
+            "--check" => dry_run = DryRun::Check,
+
+            "--json" => json = true,
+
+            "--output" => {
+                let output = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --output"));
+                output_json = match output.as_ref() {
+                    "text" => false,
+                    "json" => true,
+                    _ => usage_msg(format!("Invalid output format '{}'", output)),
+                };
+            }
 
             "--log-level" => {
                 let log_level_str = iter
@@ -101,110 +535,602 @@ fn parse_args(args: env::Args) -> Args {
                 )
             }
 
+            "--backup-dir" => {
+                let dir = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --backup-dir"));
+                backup_dir = Some(PathBuf::from(dir));
+            }
+
+            "--restart-timeout" => {
+                let timeout_str = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --restart-timeout"));
+                restart_timeout = Some(u64::from_str(&timeout_str).unwrap_or_else(|_| {
+                    usage_msg(format!("Invalid restart timeout '{}'", timeout_str))
+                }));
+            }
+
+            "--jobs" => {
+                let jobs_str = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --jobs"));
+                jobs = usize::from_str(&jobs_str)
+                    .ok()
+                    .filter(|jobs| *jobs > 0)
+                    .unwrap_or_else(|| usage_msg(format!("Invalid number of jobs '{}'", jobs_str)));
+            }
+
+            "--file" => {
+                file = Some(
+                    iter.next()
+                        .unwrap_or_else(|| usage_msg("Did not give argument to --file")),
+                )
+            }
+
+            "--template" => {
+                let path = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --template"));
+                template = Some(PathBuf::from(path));
+            }
+
+            "--settings-json" => {
+                let path = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --settings-json"));
+                settings_json = Some(PathBuf::from(path));
+            }
+
+            "--api-attempts" => {
+                let attempts_str = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --api-attempts"));
+                api_attempts = u32::from_str(&attempts_str)
+                    .ok()
+                    .filter(|attempts| *attempts > 0)
+                    .unwrap_or_else(|| {
+                        usage_msg(format!("Invalid number of API attempts '{}'", attempts_str))
+                    });
+            }
+
+            "--api-deadline" => {
+                let deadline_str = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --api-deadline"));
+                api_deadline = u64::from_str(&deadline_str).unwrap_or_else(|_| {
+                    usage_msg(format!("Invalid API deadline '{}'", deadline_str))
+                });
+            }
+
             _ => usage(),
         }
     }
 
+    let mode = if render {
+        if all || !key_sources.is_empty() || services.is_some() || output_json {
+            usage_msg(
+                "render doesn't take --all, --keys, --keys-file, --services, or --output json",
+            );
+        }
+        RunMode::Render {
+            file: file.unwrap_or_else(|| usage_msg("render requires --file")),
+            template,
+            settings: settings_json,
+        }
+    } else {
+        if file.is_some() || template.is_some() || settings_json.is_some() {
+            usage_msg("--file, --template, and --settings-json are only used with render");
+        }
+        choose_mode(all, key_sources).unwrap_or_else(|msg| usage_msg(msg))
+    };
+
+    let socket_path = socket_path
+        .or_else(|| env::var(API_SOCKET_ENV).ok())
+        .unwrap_or_else(|| DEFAULT_API_SOCKET.to_string());
+    let socket_path = parse_socket_path(&socket_path).unwrap_or_else(|msg| usage_msg(msg));
+
+    if json && output_json {
+        usage_msg("--json can't be used with --output json");
+    }
+
+    if restart && !matches!(mode, RunMode::All) {
+        usage_msg("--restart is only used with --all; otherwise affected services are restarted");
+    }
+
     Args {
         mode,
         log_level: log_level.unwrap_or_else(|| LevelFilter::Info),
-        socket_path: socket_path.unwrap_or_else(|| DEFAULT_API_SOCKET.to_string()),
+        socket_path,
+        backup_dir,
+        dry_run,
+        json,
+        restart_timeout: Duration::from_secs(restart_timeout.unwrap_or(DEFAULT_RESTART_TIMEOUT)),
+        jobs,
+        restart,
+        strict,
+        retry: RetryPolicy::new(api_attempts, Duration::from_secs(api_deadline)),
+        services,
+        progress: Progress::new(if output_json {
+            Some(ProgressSink::Stdout)
+        } else {
+            None
+        }),
+        facts: Box::new(SystemFacts),
+    }
+}
+
+/// Decides the run mode from --all and any --keys or --keys-file arguments.  Changed keys come
+/// from exactly one place, so it's an error to give more than one of these; with none of them,
+/// keys are read from stdin.
+fn choose_mode(all: bool, mut key_sources: Vec<KeySource>) -> Result<RunMode, &'static str> {
+    match (all, key_sources.len()) {
+        (true, 0) => Ok(RunMode::All),
+        (true, _) => Err("--all can't be used with --keys or --keys-file"),
+        (false, 0) => Ok(RunMode::SpecificKeys(KeySource::Stdin)),
+        (false, 1) => Ok(RunMode::SpecificKeys(key_sources.remove(0))),
+        (false, _) => Err("Only one of --keys or --keys-file may be given, and only once"),
+    }
+}
+
+/// Returns the path to the API socket given as either a path or a unix:// URI, like
+/// unix:///run/api.sock.  Other schemes are rejected, since the API is only served on a socket.
+fn parse_socket_path(socket: &str) -> Result<String, String> {
+    let path = if socket.starts_with(UNIX_SCHEME) {
+        &socket[UNIX_SCHEME.len()..]
+    } else if socket.contains("://") {
+        return Err(format!(
+            "API socket '{}' must be a path or a {} URI",
+            socket, UNIX_SCHEME
+        ));
+    } else {
+        socket
+    };
+    if path.is_empty() {
+        return Err(format!("API socket '{}' has no path", socket));
+    }
+    Ok(path.to_string())
+}
+
+/// Fetches a RunContext for the given changed settings, or all settings, and any services named
+/// with --services, and writes its configuration files.  If settings change while we fetch or
+/// render them, we start over with a fresh context, up to SNAPSHOT_ATTEMPTS times, so that the
+/// files we write all come from the same settings.  Returns the context's services and the report
+/// from write_config_files.
+fn write_snapshot(
+    args: &Args,
+    changed_settings: Option<&HashSet<String>>,
+) -> Result<(model::Services, config::WriteReport), error::Error> {
+    let mut attempt = 1;
+    loop {
+        info!("Requesting settings, services, and configuration files");
+        let result = RunContext::fetch(
+            &args.socket_path,
+            changed_settings,
+            args.services.as_ref(),
+            &args.retry,
+        )
+        .context(error::Apply)
+        .and_then(|context| {
+            let mut keys: Vec<_> = changed_settings.into_iter().flatten().cloned().collect();
+            keys.sort();
+            args.progress.send(ApplyEvent::RunStarted(RunStarted {
+                keys,
+                services: context.services.keys().cloned().collect(),
+            }));
+            write_config_files(args, context)
+        });
+        match result {
+            Err(error::Error::Apply {
+                source: thar_be_settings::Error::SettingsChanged { fetched, current },
+            }) if attempt < SNAPSHOT_ATTEMPTS => {
+                warn!(
+                    "Settings changed from generation {} to {} while applying them, starting over",
+                    fetched, current
+                );
+                attempt += 1;
+            }
+            result => return result,
+        }
     }
 }
 
-/// Render and write config files to disk.  If `files_limit` is Some, only
-/// write those files, otherwise write all known files.
+/// Render and write the context's config files to disk, after checking that
+/// the context is still current.  Returns the context's services, and a report
+/// of the files that were written, leaving out any whose contents didn't
+/// change, and the files that failed their check commands.
 fn write_config_files(
     args: &Args,
-    files_limit: Option<HashSet<String>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Create a vec of ConfigFile structs from the list of changed services
-    info!("Requesting configuration file data for affected services");
-    let config_files = config::get_affected_config_files(&args.socket_path, files_limit)?;
-    trace!("Found config files: {:?}", config_files);
-
-    // Build the template registry from config file metadata
-    debug!("Building template registry");
-    let mut template_registry = schnauzer::build_template_registry()?;
-    for (name, metadata) in &config_files {
-        debug!(
-            "Registering {} at path '{}'",
-            &name, &metadata.template_path
-        );
-        template_registry
-            .register_template_file(&name, metadata.template_path.as_ref())
-            .context(error::TemplateRegister {
-                name: name.as_str(),
-                path: metadata.template_path.as_ref(),
-            })?;
+    mut context: RunContext,
+) -> Result<(model::Services, config::WriteReport), error::Error> {
+    let services = mem::take(&mut context.services);
+    let mut config_files = mem::take(&mut context.config_files);
+    if config_files.is_empty() {
+        debug!("No configuration files to write");
+        return Ok((services, config::WriteReport::default()));
     }
 
-    // Get all settings values for config file templates
-    debug!("Requesting settings values");
-    let settings = schnauzer::get_settings(&args.socket_path)?;
+    // Build the template registry from config file metadata, skipping files with broken templates
+    debug!("Building template registry");
+    let mut template_registry =
+        schnauzer::build_template_registry().context(error::TemplateRegistry)?;
+    let failed_templates = config::register_templates(&mut template_registry, &config_files);
+    for (name, err) in &failed_templates {
+        args.progress
+            .file(name, FileStatus::Failed, Some(err.to_string()));
+    }
+    ensure!(
+        !args.strict || failed_templates.is_empty(),
+        error::FailedTemplates {
+            files: describe_failures(&failed_templates),
+        }
+    );
+    for name in failed_templates.keys() {
+        config_files.remove(name);
+    }
 
     // Ensure all files render properly
     info!("Rendering config files...");
     let strict = match &args.mode {
-        RunMode::SpecificKeys => true,
-        RunMode::All => false,
+        RunMode::SpecificKeys(_) | RunMode::Render { .. } => true,
+        RunMode::All => args.strict,
     };
-    let rendered = config::render_config_files(&template_registry, config_files, settings, strict)?;
+    let names: Vec<String> = config_files.keys().cloned().collect();
+    // Rendering doesn't change anything, so it always uses every CPU.
+    let rendered = config::render_config_files(
+        &template_registry,
+        config_files,
+        &context.settings,
+        facts::template_context(&*args.facts),
+        strict,
+        num_cpus::get(),
+    )
+    .context(error::Apply)?;
+    // Files that failed to render were logged, and left out.
+    let names: Vec<String> = names
+        .into_iter()
+        .filter(|name| {
+            if rendered.iter().any(|cfg| cfg.name() == name) {
+                args.progress.file(name, FileStatus::Rendered, None);
+                true
+            } else {
+                args.progress.file(
+                    name,
+                    FileStatus::Failed,
+                    Some("Template failed to render".to_string()),
+                );
+                false
+            }
+        })
+        .collect();
+
+    if args.dry_run != DryRun::Off {
+        info!("Showing changes to config files...");
+        let mut report = show_changes(args, &rendered);
+        report.failed_templates = failed_templates;
+        report_writes(&args.progress, &names, &report);
+        return Ok((services, report));
+    }
 
-    // If all the config renders properly, write it to disk
+    // If all the config renders properly, and the settings it was rendered
+    // from are still current, write it to disk
+    context
+        .check_current(&args.socket_path, &args.retry)
+        .context(error::Apply)?;
     info!("Writing config files to disk...");
-    config::write_config_files(rendered)?;
+    let backups = args.backup_dir.as_ref().map(|dir| config::Backups {
+        dir: Some(dir.clone()),
+        keep: BACKUP_COUNT,
+    });
+    let mut report = config::write_config_files(rendered, &SystemOwners, backups.as_ref())
+        .context(error::Apply)?;
+    report.failed_templates = failed_templates;
+    report_writes(&args.progress, &names, &report);
+
+    Ok((services, report))
+}
+
+/// Sends a progress event for each of the named rendered configuration files, saying whether it
+/// was written, failed its check command, or was unchanged, according to the report.
+fn report_writes(progress: &Progress, names: &[String], report: &config::WriteReport) {
+    for name in names {
+        if report.written.contains(name) {
+            progress.file(name, FileStatus::Written, None);
+        } else if let Some(err) = report.failed_checks.get(name) {
+            progress.file(name, FileStatus::Failed, Some(err.to_string()));
+        } else {
+            progress.file(name, FileStatus::Unchanged, None);
+        }
+    }
+}
+
+/// Describes the failures of the given configuration files for an error message, like
+/// "motd (Template 'motd' at '/x' is empty)".
+fn describe_failures(failures: &BTreeMap<String, thar_be_settings::Error>) -> String {
+    join(
+        failures
+            .iter()
+            .map(|(name, err)| format!("{} ({})", name, err)),
+        ", ",
+    )
+}
+
+/// Renders the named configuration file, for the render command, and returns its contents.  The
+/// template registry and rendering are the same as when writing configuration files, so the
+/// result matches what would be written.
+fn render_file(
+    args: &Args,
+    name: &str,
+    template: Option<&PathBuf>,
+    settings_json: Option<&PathBuf>,
+) -> Result<String, error::Error> {
+    // Whatever isn't given on the command line comes from the API, in one snapshot.
+    let mut context = if template.is_none() || settings_json.is_none() {
+        info!("Requesting settings and configuration files");
+        Some(RunContext::fetch(&args.socket_path, None, None, &args.retry).context(error::Apply)?)
+    } else {
+        None
+    };
+
+    let config_file = match template {
+        // Nothing is written, so the template's path stands in for the file's path.
+        Some(path) => {
+            let path_str = path.to_string_lossy();
+            let template_path = SingleLineString::try_from(path_str.as_ref())
+                .context(error::TemplatePath { path })?;
+            model::ConfigurationFile {
+                path: template_path.clone(),
+                template_path,
+                mode: None,
+                user: None,
+                group: None,
+                check_command: None,
+                partials: None,
+            }
+        }
+        // Without a template, we fetched the configuration files above.
+        None => {
+            let mut config_files = context
+                .as_mut()
+                .map(|context| mem::take(&mut context.config_files))
+                .unwrap_or_default();
+            match config_files.remove(name) {
+                Some(config_file) => config_file,
+                None => {
+                    return error::UnknownConfigFile {
+                        name,
+                        available: join(config_files.keys(), ", "),
+                    }
+                    .fail()
+                }
+            }
+        }
+    };
+    let mut config_files = model::ConfigurationFiles::new();
+    config_files.insert(name.to_string(), config_file);
+
+    let mut template_registry =
+        schnauzer::build_template_registry().context(error::TemplateRegistry)?;
+    if let Some((_, err)) = config::register_templates(&mut template_registry, &config_files)
+        .into_iter()
+        .next()
+    {
+        return Err(err).context(error::Apply);
+    }
+
+    let mut rendered = match settings_json {
+        Some(path) => {
+            let settings = fs::read_to_string(path).context(error::ReadSettings { path })?;
+            let settings: serde_json::Value =
+                serde_json::from_str(&settings).context(error::ParseSettings { path })?;
+            config::render_config_files(
+                &template_registry,
+                config_files,
+                settings,
+                facts::template_context(&*args.facts),
+                true,
+                1,
+            )
+        }
+        // Without a settings file, we fetched the settings above.
+        None => {
+            let settings = context.map(|context| context.settings);
+            config::render_config_files(
+                &template_registry,
+                config_files,
+                settings,
+                facts::template_context(&*args.facts),
+                true,
+                1,
+            )
+        }
+    }
+    .context(error::Apply)?;
 
+    // We gave it one file, so we get one back.
+    Ok(rendered.remove(0).rendered().to_string())
+}
+
+/// Print the changes the rendered config files would make on disk, instead of
+/// writing them.  Returns a report listing the files that would be written.
+fn show_changes(args: &Args, rendered: &[config::RenderedConfigFile]) -> config::WriteReport {
+    let mut report = config::WriteReport::default();
+    for cfg in rendered {
+        if let Some(diff) = cfg.diff() {
+            print_text(args, &diff);
+            report.written.insert(cfg.name().to_string());
+        }
+    }
+    report
+}
+
+/// Prints text meant for people, like dry-run diffs, to stdout, or to stderr if stdout is kept for
+/// JSON, with --json or --output json.
+fn print_text(args: &Args, text: &str) {
+    if args.json_stdout() {
+        eprint!("{}", text);
+    } else {
+        print!("{}", text);
+    }
+}
+
+/// Restart the given services in dependency order, or in a dry run, print the restart commands
+/// that would be run, in that order.  Returns a report of the restart commands that were run.
+/// With --json, the report is also printed.  `applied` says whether any configuration files were
+/// written beforehand, for the exit code if restarting fails.
+fn restart_services(
+    args: &Args,
+    services: model::Services,
+    applied: bool,
+) -> Result<service::RestartReport, error::Error> {
+    let report = if args.dry_run == DryRun::Off {
+        service::restart_services(services, args.restart_timeout, args.jobs, &mut |event| {
+            args.progress.send(ApplyEvent::Restart(event))
+        })
+        .context(error::Restart { applied })?
+    } else {
+        let affected = services.keys().cloned().collect();
+        let plan =
+            service::plan_restarts(&services, &affected).context(error::Restart { applied })?;
+        for name in plan {
+            for command in &services[&name].restart_commands {
+                print_text(
+                    args,
+                    &format!("Would run restart command for {}: {}\n", name, command),
+                );
+            }
+        }
+        service::RestartReport::default()
+    };
+
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).context(error::ReportSerialize)?
+        );
+    }
+    Ok(report)
+}
+
+/// Fails if any configuration files had invalid templates or failed their
+/// check commands, or any service failed to restart, or, with --check, if any
+/// configuration file would change, so we exit nonzero.  The reasons for the failures were logged
+/// as they happened.
+fn check_reports(
+    args: &Args,
+    write_report: &config::WriteReport,
+    restart_report: &service::RestartReport,
+) -> Result<(), error::Error> {
+    ensure!(
+        write_report.failed_templates.is_empty(),
+        error::FailedTemplates {
+            files: describe_failures(&write_report.failed_templates),
+        }
+    );
+    ensure!(
+        write_report.failed_checks.is_empty(),
+        error::FailedChecks {
+            files: join(write_report.failed_checks.keys(), ", "),
+        }
+    );
+    let failed_services = restart_report.failed_services();
+    ensure!(
+        failed_services.is_empty(),
+        error::FailedRestarts {
+            services: join(&failed_services, ", "),
+            // Something was applied if files were written, or any service restarted.
+            applied: !write_report.written.is_empty()
+                || restart_report.services.len() > failed_services.len(),
+        }
+    );
+    ensure!(
+        args.dry_run != DryRun::Check || write_report.written.is_empty(),
+        error::WouldChange {
+            files: join(&write_report.written, ", "),
+        }
+    );
     Ok(())
 }
 
-fn run() -> Result<(), Box<dyn std::error::Error>> {
+fn run() -> Result<(), error::Error> {
     // Parse and store the args passed to the program
     let args = parse_args(env::args());
 
-    // TerminalMode::Mixed will send errors to stderr and anything less to stdout.
-    TermLogger::init(args.log_level, LogConfig::default(), TerminalMode::Mixed)
-        .context(error::Logger)?;
+    // TerminalMode::Mixed will send errors to stderr and anything less to stdout.  With --json or
+    // --output json, everything goes to stderr, so stdout is just the JSON.
+    let terminal_mode = if args.json_stdout() {
+        TerminalMode::Stderr
+    } else {
+        TerminalMode::Mixed
+    };
+    TermLogger::init(args.log_level, LogConfig::default(), terminal_mode).context(error::Logger)?;
 
     info!("thar-be-settings started");
+    apply_with_summary(&args)
+}
 
-    match args.mode {
-        RunMode::SpecificKeys => {
-            // Get the settings that changed via stdin
-            info!("Parsing stdin for updated settings");
-            let changed_settings = get_changed_settings()?;
+/// Applies the args like apply, then sends the summary event that ends the run's progress.
+fn apply_with_summary(args: &Args) -> Result<(), error::Error> {
+    let result = apply(args);
+    args.progress.finish(&result, args.dry_run != DryRun::Off);
+    result
+}
 
-            // Create a HashSet of affected services
+/// Writes configuration files and restarts services, or renders a single configuration file, as
+/// requested in the args.
+fn apply(args: &Args) -> Result<(), error::Error> {
+    match &args.mode {
+        RunMode::Render {
+            file,
+            template,
+            settings,
+        } => {
+            let rendered = render_file(args, file, template.as_ref(), settings.as_ref())?;
+            print!("{}", rendered);
+        }
+        RunMode::SpecificKeys(key_source) => {
+            // Get the settings that changed
+            info!("Reading updated settings from {:?}", key_source);
+            let changed_settings = get_changed_settings(key_source).context(error::Apply)?;
+
+            // Write the configuration files of the services affected by those settings
             info!(
-                "Requesting affected services for settings: {:?}",
+                "Applying changes to affected services for settings: {:?}",
                 &changed_settings
             );
-            let services =
-                service::get_affected_services(&args.socket_path, Some(changed_settings))?;
-            trace!("Found services: {:?}", services);
+            let (services, report) = write_snapshot(args, Some(&changed_settings))?;
             if services.is_empty() {
                 info!("No services are affected, exiting...");
-                process::exit(0)
-            }
-
-            // Create a HashSet of configuration file names
-            let config_file_names = config::get_config_file_names(&services);
-
-            if !config_file_names.is_empty() {
-                write_config_files(&args, Some(config_file_names))?;
+                return Ok(());
             }
 
-            // Now go bounce the affected services
+            // Now go bounce the affected services, unless none of their files changed
+            let services = service::skip_unchanged(services, &report.written);
             info!("Restarting affected services...");
-            service::restart_services(services)?;
+            let applied = args.dry_run == DryRun::Off && !report.written.is_empty();
+            let restart_report = restart_services(args, services, applied)?;
+            check_reports(args, &report, &restart_report)?;
         }
         RunMode::All => {
-            write_config_files(&args, None)?;
+            let (services, report) = write_snapshot(args, None)?;
+            let applied = args.dry_run == DryRun::Off && !report.written.is_empty();
 
-            info!("Restarting all services...");
-            let services = service::get_affected_services(&args.socket_path, None)?;
-            trace!("Found services: {:?}", services);
-            service::restart_services(services)?;
+            let restart_report = if args.restart {
+                info!("Restarting all services...");
+                let failed = report
+                    .failed_checks
+                    .keys()
+                    .chain(report.failed_templates.keys())
+                    .cloned()
+                    .collect();
+                let services = service::skip_failed(services, &failed);
+                restart_services(args, services, applied)?
+            } else {
+                // Nothing to restart, but --json still gets its report.
+                info!("Not restarting services without --restart");
+                restart_services(args, model::Services::new(), applied)?
+            };
+            check_reports(args, &report, &restart_report)?;
         }
     }
 
@@ -217,6 +1143,707 @@ fn run() -> Result<(), Box<dyn std::error::Error>> {
 fn main() {
     if let Err(e) = run() {
         eprintln!("{}", e);
-        process::exit(1);
+        let code = exit_code(&e);
+        eprintln!("Exiting with code {}: {}", code as i32, code.meaning());
+        process::exit(code as i32);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+    use std::thread;
+    use thar_be_settings::facts::OsFacts;
+
+    /// Serves the given JSON responses, by request path, from a Unix socket at the given path, like
+    /// a tiny API server.  Each request for a path gets the path's next response, or once they run
+    /// out, its last one again.  Other paths get a 404.
+    fn mock_api(socket_path: &Path, responses: HashMap<&'static str, Vec<serde_json::Value>>) {
+        let listener = UnixListener::bind(socket_path).unwrap();
+        thread::spawn(move || {
+            let mut served = HashMap::new();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+
+                // Our requests are all GETs, so the request ends with the headers.
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let count = stream.read(&mut buf).unwrap();
+                    if count == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..count]);
+                }
+
+                // The request line is like "GET /services?names=a,b HTTP/1.1"
+                let request = String::from_utf8_lossy(&request);
+                let target = request.split_whitespace().nth(1).unwrap_or_default();
+                let path = target.split('?').next().unwrap_or_default();
+                let (status, body) = match responses.get(path) {
+                    Some(bodies) => {
+                        let count = served.entry(path.to_string()).or_insert(0);
+                        let body = &bodies[(*count).min(bodies.len() - 1)];
+                        *count += 1;
+                        ("200 OK", body.to_string())
+                    }
+                    None => ("404 Not Found", "{}".to_string()),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+    }
+
+    /// Sets up templates for two configuration files in the given directory, and an API that
+    /// describes them, and a service for each of them; changes to settings.motd only affect the
+    /// motd service.  Returns the Args for a full run.
+    fn full_run(dir: &Path, restart: bool) -> Args {
+        full_run_changing(dir, restart, &["hi"], &[1])
+    }
+
+    /// Like full_run, but each request for settings gets the next of the given motds, and each
+    /// request for the settings generation gets the next of the given generations.
+    fn full_run_changing(dir: &Path, restart: bool, motds: &[&str], generations: &[u64]) -> Args {
+        let mut config_files = serde_json::Map::new();
+        for name in &["motd", "issue"] {
+            let template_path = dir.join(format!("{}.template", name));
+            fs::write(&template_path, format!("{}={{{{settings.motd}}}}", name)).unwrap();
+            config_files.insert(
+                name.to_string(),
+                json!({
+                    "path": dir.join(name),
+                    "template-path": template_path,
+                }),
+            );
+        }
+        let restarted = dir.join("restarted");
+        let script = format!("echo restarted > {}", restarted.display());
+        let issue_restarted = dir.join("issue-restarted");
+        let issue_script = format!("echo restarted > {}", issue_restarted.display());
+
+        let services = json!({
+            "motd": {
+                "configuration-files": ["motd"],
+                "restart-commands": [["/bin/sh", "-c", script]],
+            },
+            "issue": {
+                "configuration-files": ["issue"],
+                "restart-commands": [["/bin/sh", "-c", issue_script]],
+            },
+        });
+        let models = motds
+            .iter()
+            .map(|motd| {
+                json!({
+                    "settings": {"motd": motd},
+                    "services": services,
+                    "configuration-files": config_files,
+                })
+            })
+            .collect();
+        let generations = generations
+            .iter()
+            .map(|generation| json!({ "generation": generation }))
+            .collect();
+
+        let socket_path = dir.join("api.sock");
+        mock_api(
+            &socket_path,
+            maplit::hashmap! {
+                "/" => models,
+                "/settings/generation" => generations,
+                "/metadata/affected-services" => vec![json!({"settings.motd": ["motd"]})],
+            },
+        );
+
+        Args {
+            log_level: LevelFilter::Off,
+            mode: RunMode::All,
+            socket_path: socket_path.to_str().unwrap().to_string(),
+            backup_dir: None,
+            dry_run: DryRun::Off,
+            json: false,
+            restart_timeout: Duration::from_secs(60),
+            jobs: 1,
+            restart,
+            strict: false,
+            retry: RetryPolicy::never(),
+            services: None,
+            progress: Progress::new(None),
+            facts: Box::new(FakeFacts),
+        }
+    }
+
+    /// Fixed facts about the host, so tests don't depend on the OS they run on.
+    struct FakeFacts;
+
+    impl Facts for FakeFacts {
+        fn os(&self) -> std::result::Result<OsFacts, thar_be_settings::Error> {
+            Ok(OsFacts {
+                version_id: "0.3.1".to_string(),
+                variant_id: "aws-k8s".to_string(),
+                arch: "x86_64".to_string(),
+            })
+        }
+    }
+
+    #[test]
+    fn all_writes_every_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = full_run(dir.path(), false);
+        apply(&args).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("motd")).unwrap(),
+            "motd=hi"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("issue")).unwrap(),
+            "issue=hi"
+        );
+        // Services aren't restarted without --restart
+        assert!(!dir.path().join("restarted").exists());
+    }
+
+    #[test]
+    fn broken_templates_skipped() {
+        for strict in &[false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut args = full_run(dir.path(), false);
+            args.strict = *strict;
+            fs::write(dir.path().join("issue.template"), "{{#if settings.motd}}").unwrap();
+
+            match apply(&args) {
+                Err(e @ error::Error::FailedTemplates { .. }) => {
+                    assert!(e.to_string().contains("issue"), "{}", e);
+                    assert_eq!(exit_code(&e), ExitCode::RenderFailed);
+                }
+                other => panic!("Expected FailedTemplates error, got {:?}", other),
+            }
+
+            // The good template is still written, unless we're strict.
+            assert!(!dir.path().join("issue").exists());
+            assert_eq!(dir.path().join("motd").exists(), !strict);
+        }
+    }
+
+    #[test]
+    fn all_restart_runs_restart_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = full_run(dir.path(), true);
+        apply(&args).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("motd")).unwrap(),
+            "motd=hi"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("issue")).unwrap(),
+            "issue=hi"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("restarted")).unwrap(),
+            "restarted\n"
+        );
+    }
+
+    #[test]
+    fn specific_keys_write_affected_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut args = full_run(dir.path(), false);
+        args.mode = RunMode::SpecificKeys(KeySource::List("settings.motd".to_string()));
+        apply(&args).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("motd")).unwrap(),
+            "motd=hi"
+        );
+        // No service uses issue, so it's not affected
+        assert!(!dir.path().join("issue").exists());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("restarted")).unwrap(),
+            "restarted\n"
+        );
+    }
+
+    #[test]
+    fn services_unknown() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut args = full_run(dir.path(), true);
+        args.services = Some(maplit::btreeset! {"motd".to_string(), "nope".to_string()});
+
+        match apply(&args) {
+            Err(
+                e @ error::Error::Apply {
+                    source: thar_be_settings::Error::UnknownServices { .. },
+                },
+            ) => {
+                assert!(
+                    e.to_string()
+                        .ends_with("Unknown services: nope; available services: issue, motd"),
+                    "{}",
+                    e
+                );
+                assert_eq!(exit_code(&e), ExitCode::InvalidInput);
+            }
+            other => panic!("Expected UnknownServices error, got {:?}", other),
+        }
+        assert!(!dir.path().join("motd").exists());
+        assert!(!dir.path().join("restarted").exists());
+    }
+
+    #[test]
+    fn services_with_all() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut args = full_run(dir.path(), true);
+        args.services = Some(maplit::btreeset! {"issue".to_string()});
+        apply(&args).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("issue")).unwrap(),
+            "issue=hi"
+        );
+        assert!(dir.path().join("issue-restarted").exists());
+        assert!(!dir.path().join("motd").exists());
+        assert!(!dir.path().join("restarted").exists());
+    }
+
+    #[test]
+    fn services_intersect_keys() {
+        let keys = || RunMode::SpecificKeys(KeySource::List("settings.motd".to_string()));
+
+        // Both services are named, but only motd is affected by the changed key.
+        let dir = tempfile::tempdir().unwrap();
+        let mut args = full_run(dir.path(), false);
+        args.mode = keys();
+        args.services = Some(maplit::btreeset! {"motd".to_string(), "issue".to_string()});
+        apply(&args).unwrap();
+        assert!(dir.path().join("motd").exists());
+        assert!(dir.path().join("restarted").exists());
+        assert!(!dir.path().join("issue").exists());
+        assert!(!dir.path().join("issue-restarted").exists());
+
+        // The changed key affects motd, but only issue is named, so nothing is applied.
+        let dir = tempfile::tempdir().unwrap();
+        let mut args = full_run(dir.path(), false);
+        args.mode = keys();
+        args.services = Some(maplit::btreeset! {"issue".to_string()});
+        apply(&args).unwrap();
+        for name in &["motd", "restarted", "issue", "issue-restarted"] {
+            assert!(!dir.path().join(name).exists(), "{} exists", name);
+        }
+    }
+
+    /// Applies the args, collecting their progress events, and returns the events, less their
+    /// durations, which vary, along with the result.
+    fn progress_events(mut args: Args) -> (Vec<serde_json::Value>, Result<(), error::Error>) {
+        args.progress = Progress::new(Some(ProgressSink::Lines(RefCell::new(Vec::new()))));
+        let result = apply_with_summary(&args);
+        let lines = match &args.progress.sink {
+            Some(ProgressSink::Lines(lines)) => lines.borrow().clone(),
+            _ => unreachable!(),
+        };
+
+        let mut events = Vec::new();
+        for line in lines {
+            // Every line is an event the API server can read
+            serde_json::from_str::<ApplyEvent>(&line).unwrap();
+            let mut event: serde_json::Value = serde_json::from_str(&line).unwrap();
+            if let Some(duration) = event.as_object_mut().unwrap().remove("duration-ms") {
+                assert!(duration.is_u64(), "{}", line);
+            }
+            events.push(event);
+        }
+        (events, result)
+    }
+
+    #[test]
+    fn progress_for_full_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let (events, result) = progress_events(full_run(dir.path(), true));
+        result.unwrap();
+        assert_eq!(
+            events,
+            vec![
+                json!({"event": "run-started", "keys": [], "services": ["issue", "motd"]}),
+                json!({"event": "file", "name": "issue", "status": "rendered"}),
+                json!({"event": "file", "name": "motd", "status": "rendered"}),
+                json!({"event": "file", "name": "issue", "status": "written"}),
+                json!({"event": "file", "name": "motd", "status": "written"}),
+                json!({"event": "restart", "service": "issue", "status": "started"}),
+                json!({"event": "restart", "service": "issue", "status": "succeeded"}),
+                json!({"event": "restart", "service": "motd", "status": "started"}),
+                json!({"event": "restart", "service": "motd", "status": "succeeded"}),
+                json!({
+                    "event": "summary",
+                    "success": true,
+                    "exit-code": 0,
+                    "dry-run": false,
+                    "written": ["issue", "motd"],
+                    "failed-files": [],
+                    "restarted": ["issue", "motd"],
+                    "failed-services": [],
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn progress_for_failed_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut args = full_run(dir.path(), false);
+        args.mode = RunMode::SpecificKeys(KeySource::List("settings.motd".to_string()));
+        fs::write(dir.path().join("motd.template"), "{{#if settings.motd}}").unwrap();
+
+        let (events, result) = progress_events(args);
+        let err = result.unwrap_err();
+        assert_eq!(
+            events[0],
+            json!({"event": "run-started", "keys": ["settings.motd"], "services": ["motd"]})
+        );
+        assert_eq!(events[1]["name"], "motd");
+        assert_eq!(events[1]["status"], "failed");
+        assert!(events[1]["error"].as_str().unwrap().contains("motd"));
+        let summary = events.last().unwrap();
+        assert_eq!(summary["event"], "summary");
+        assert_eq!(summary["success"], false);
+        assert_eq!(summary["exit-code"], exit_code(&err) as i32);
+        assert_eq!(summary["error"], err.to_string());
+        assert_eq!(summary["failed-files"], json!(["motd"]));
+    }
+
+    #[test]
+    fn settings_change_before_write() {
+        let dir = tempfile::tempdir().unwrap();
+        // Settings are committed after the first snapshot is rendered, but before it's written,
+        // so the second snapshot is written instead.
+        let args = full_run_changing(dir.path(), false, &["old", "new"], &[1, 1, 2]);
+        apply(&args).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("motd")).unwrap(),
+            "motd=new"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("issue")).unwrap(),
+            "issue=new"
+        );
+    }
+
+    #[test]
+    fn settings_keep_changing() {
+        let dir = tempfile::tempdir().unwrap();
+        let generations: Vec<_> = (1..=10).collect();
+        let args = full_run_changing(dir.path(), false, &["hi"], &generations);
+
+        match apply(&args) {
+            Err(
+                e @ error::Error::Apply {
+                    source: thar_be_settings::Error::SettingsChanged { .. },
+                },
+            ) => assert_eq!(exit_code(&e), ExitCode::ApiFailed),
+            other => panic!("Expected SettingsChanged error, got {:?}", other),
+        }
+        // Nothing is written from a snapshot that was out of date
+        assert!(!dir.path().join("motd").exists());
+        assert!(!dir.path().join("issue").exists());
+    }
+
+    #[test]
+    fn key_source_matrix() {
+        let list = || KeySource::List("settings.motd".to_string());
+        let file = || KeySource::File(PathBuf::from("/tmp/keys.json"));
+
+        assert_eq!(choose_mode(true, vec![]), Ok(RunMode::All));
+        assert_eq!(
+            choose_mode(false, vec![]),
+            Ok(RunMode::SpecificKeys(KeySource::Stdin))
+        );
+        assert_eq!(
+            choose_mode(false, vec![list()]),
+            Ok(RunMode::SpecificKeys(list()))
+        );
+        assert_eq!(
+            choose_mode(false, vec![file()]),
+            Ok(RunMode::SpecificKeys(file()))
+        );
+
+        choose_mode(true, vec![list()]).unwrap_err();
+        choose_mode(true, vec![file()]).unwrap_err();
+        choose_mode(false, vec![list(), file()]).unwrap_err();
+        choose_mode(false, vec![list(), list()]).unwrap_err();
+        choose_mode(true, vec![file(), list()]).unwrap_err();
+    }
+
+    #[test]
+    fn socket_paths() {
+        assert_eq!(parse_socket_path("/run/api.sock").unwrap(), "/run/api.sock");
+        assert_eq!(
+            parse_socket_path("unix:///run/api.sock").unwrap(),
+            "/run/api.sock"
+        );
+        assert_eq!(parse_socket_path("unix://api.sock").unwrap(), "api.sock");
+
+        parse_socket_path("").unwrap_err();
+        parse_socket_path("unix://").unwrap_err();
+        parse_socket_path("http://localhost:4242").unwrap_err();
+    }
+
+    #[test]
+    fn exit_code_for_every_error() {
+        let json_error = || serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        let schnauzer_error = || schnauzer::Error::ResponseJson {
+            method: "GET",
+            uri: "/".to_string(),
+            source: json_error(),
+        };
+        // One error from the library for each kind of failure
+        let restart_error = || thar_be_settings::Error::RestartCycle {
+            cycle: "a -> a".to_string(),
+        };
+        let lib_errors = vec![
+            (
+                thar_be_settings::Error::NoChangedSettings,
+                ExitCode::InvalidInput,
+            ),
+            (
+                thar_be_settings::Error::GetJson {
+                    uri: "/".to_string(),
+                    source: schnauzer_error(),
+                },
+                ExitCode::ApiFailed,
+            ),
+            (
+                thar_be_settings::Error::MissingPartial {
+                    template: "motd".to_string(),
+                    partial: "header".to_string(),
+                },
+                ExitCode::RenderFailed,
+            ),
+            (
+                thar_be_settings::Error::DestinationDirectory {
+                    path: PathBuf::from("/etc"),
+                },
+                ExitCode::WriteFailed,
+            ),
+            (
+                thar_be_settings::Error::InvalidCheckCommand {
+                    command: "".to_string(),
+                },
+                ExitCode::CheckFailed,
+            ),
+            (restart_error(), ExitCode::NothingApplied),
+        ];
+
+        let mut cases: Vec<_> = lib_errors
+            .into_iter()
+            .map(|(source, code)| (error::Error::Apply { source }, code))
+            .collect();
+        cases.extend(vec![
+            (
+                error::Error::Logger {
+                    source: simplelog::TermLogError::Term,
+                },
+                ExitCode::Internal,
+            ),
+            (
+                error::Error::TemplateRegistry {
+                    source: schnauzer_error(),
+                },
+                ExitCode::RenderFailed,
+            ),
+            (
+                error::Error::Restart {
+                    source: restart_error(),
+                    applied: true,
+                },
+                ExitCode::RestartsFailed,
+            ),
+            (
+                error::Error::Restart {
+                    source: restart_error(),
+                    applied: false,
+                },
+                ExitCode::NothingApplied,
+            ),
+            (
+                error::Error::ReportSerialize {
+                    source: json_error(),
+                },
+                ExitCode::Internal,
+            ),
+            (
+                error::Error::FailedChecks {
+                    files: "motd".to_string(),
+                },
+                ExitCode::CheckFailed,
+            ),
+            (
+                error::Error::FailedTemplates {
+                    files: "motd".to_string(),
+                },
+                ExitCode::RenderFailed,
+            ),
+            (
+                error::Error::WouldChange {
+                    files: "motd".to_string(),
+                },
+                ExitCode::WouldChange,
+            ),
+            (
+                error::Error::FailedRestarts {
+                    services: "motd".to_string(),
+                    applied: true,
+                },
+                ExitCode::RestartsFailed,
+            ),
+            (
+                error::Error::FailedRestarts {
+                    services: "motd".to_string(),
+                    applied: false,
+                },
+                ExitCode::NothingApplied,
+            ),
+            (
+                error::Error::UnknownConfigFile {
+                    name: "motd".to_string(),
+                    available: "issue".to_string(),
+                },
+                ExitCode::Usage,
+            ),
+            (
+                error::Error::TemplatePath {
+                    path: PathBuf::from("motd\n"),
+                    source: SingleLineString::try_from("motd\n").unwrap_err(),
+                },
+                ExitCode::InvalidInput,
+            ),
+            (
+                error::Error::ReadSettings {
+                    path: PathBuf::from("settings.json"),
+                    source: std::io::Error::new(std::io::ErrorKind::Other, "oops"),
+                },
+                ExitCode::InvalidInput,
+            ),
+            (
+                error::Error::ParseSettings {
+                    path: PathBuf::from("settings.json"),
+                    source: json_error(),
+                },
+                ExitCode::InvalidInput,
+            ),
+        ]);
+
+        for (error, code) in cases {
+            assert_eq!(exit_code(&error), code, "{}", error);
+        }
+    }
+
+    #[test]
+    fn failed_restarts_applied() {
+        let failed = || service::CommandResult {
+            command: "/bin/false".to_string(),
+            success: false,
+            timed_out: false,
+            exit_status: Some(1),
+            error: None,
+            stdout: String::new(),
+            stderr: String::new(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let args = full_run(dir.path(), true);
+        let mut restart_report = service::RestartReport::default();
+        restart_report
+            .services
+            .insert("motd".to_string(), vec![failed()]);
+
+        // Nothing written and nothing restarted
+        let mut write_report = config::WriteReport::default();
+        let err = check_reports(&args, &write_report, &restart_report).unwrap_err();
+        assert_eq!(exit_code(&err), ExitCode::NothingApplied);
+
+        // Another service restarted
+        let mut succeeded = failed();
+        succeeded.success = true;
+        restart_report
+            .services
+            .insert("issue".to_string(), vec![succeeded]);
+        let err = check_reports(&args, &write_report, &restart_report).unwrap_err();
+        assert_eq!(exit_code(&err), ExitCode::RestartsFailed);
+
+        // Files written
+        restart_report.services.remove("issue");
+        write_report.written.insert("motd".to_string());
+        let err = check_reports(&args, &write_report, &restart_report).unwrap_err();
+        assert_eq!(exit_code(&err), ExitCode::RestartsFailed);
+    }
+
+    #[test]
+    fn render_from_api() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = full_run(dir.path(), false);
+
+        assert_eq!(render_file(&args, "issue", None, None).unwrap(), "issue=hi");
+        assert!(!dir.path().join("issue").exists());
+
+        match render_file(&args, "nope", None, None) {
+            Err(e @ error::Error::UnknownConfigFile { .. }) => assert!(
+                e.to_string()
+                    .ends_with("available configuration files: issue, motd"),
+                "{}",
+                e
+            ),
+            other => panic!("Expected UnknownConfigFile error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn render_offline() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = dir.path().join("test.template");
+        fs::write(&template, "motd={{settings.motd}}").unwrap();
+        let settings = dir.path().join("settings.json");
+        fs::write(&settings, r#"{"settings": {"motd": "offline"}}"#).unwrap();
+
+        // Nothing is listening on the socket, so this only works without the API.
+        let mut args = full_run(dir.path(), false);
+        args.socket_path = dir.path().join("missing.sock").display().to_string();
+
+        let rendered = render_file(&args, "test", Some(&template), Some(&settings)).unwrap();
+        assert_eq!(rendered, "motd=offline");
+
+        fs::write(&settings, r#"{"settings": "#).unwrap();
+        match render_file(&args, "test", Some(&template), Some(&settings)) {
+            Err(error::Error::ParseSettings { .. }) => {}
+            other => panic!("Expected ParseSettings error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn render_os_facts() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = full_run(dir.path(), false);
+        let template = dir.path().join("test.template");
+        fs::write(
+            &template,
+            "{{os.version_id}} {{os.variant_id}} {{os.arch}} {{settings.motd}}",
+        )
+        .unwrap();
+
+        let rendered = render_file(&args, "test", Some(&template), None).unwrap();
+        assert_eq!(rendered, "0.3.1 aws-k8s x86_64 hi");
     }
 }
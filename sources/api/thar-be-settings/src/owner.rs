@@ -0,0 +1,70 @@
+//! The owner module looks up the users and groups that should own configuration files, and
+//! changes the owners of files.  It's behind a trait so the decisions about ownership can be
+//! tested without running as root.
+
+use crate::{error, Result};
+use nix::unistd::{self, Gid, Group, Uid, User};
+use snafu::{OptionExt, ResultExt};
+use std::path::Path;
+
+/// Owners gives the IDs of users and groups, and changes the owners of files.
+pub trait Owners {
+    /// Returns the ID of the named user.
+    fn uid(&self, user: &str) -> Result<u32>;
+
+    /// Returns the ID of the named group.
+    fn gid(&self, group: &str) -> Result<u32>;
+
+    /// Returns the effective user and group IDs we're running as, which own the files we create.
+    fn current(&self) -> (u32, u32);
+
+    /// Changes the user and/or group that own the file at the given path.
+    fn chown(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<()>;
+}
+
+/// SystemOwners looks up users and groups in the system's databases, and really changes owners.
+pub struct SystemOwners;
+
+impl Owners for SystemOwners {
+    fn uid(&self, user: &str) -> Result<u32> {
+        let found = User::from_name(user)
+            .context(error::UserLookup { user })?
+            .context(error::UnknownUser { user })?;
+        Ok(found.uid.as_raw())
+    }
+
+    fn gid(&self, group: &str) -> Result<u32> {
+        let found = Group::from_name(group)
+            .context(error::GroupLookup { group })?
+            .context(error::UnknownGroup { group })?;
+        Ok(found.gid.as_raw())
+    }
+
+    fn current(&self) -> (u32, u32) {
+        (unistd::geteuid().as_raw(), unistd::getegid().as_raw())
+    }
+
+    fn chown(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        unistd::chown(path, uid.map(Uid::from_raw), gid.map(Gid::from_raw))
+            .context(error::Chown { path })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn system_lookups() {
+        assert_eq!(SystemOwners.uid("root").unwrap(), 0);
+        assert_eq!(SystemOwners.gid("root").unwrap(), 0);
+        match SystemOwners.uid("no-such-user-here") {
+            Err(error::Error::UnknownUser { .. }) => {}
+            other => panic!("Expected UnknownUser error, got {:?}", other),
+        }
+        match SystemOwners.gid("no-such-group-here") {
+            Err(error::Error::UnknownGroup { .. }) => {}
+            other => panic!("Expected UnknownGroup error, got {:?}", other),
+        }
+    }
+}
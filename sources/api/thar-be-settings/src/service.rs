@@ -1,65 +1,87 @@
+use nix::sys::signal::{self, Signal};
+use nix::unistd::{self, Pid};
+use serde::Serialize;
 use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
-use std::process;
+use std::process::{self, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use itertools::join;
+use model::apply_progress::{RestartProgress, RestartStatus};
+use model::modeled_types::CommandSpec;
+use schnauzer::RetryPolicy;
 
 use crate::{error, Result};
 
-/// Wrapper for the multiple functions needed to go from
-/// a list of changed settings to a Services map
+/// Gather the services affected by each of the given settings into a map
 #[allow(clippy::implicit_hasher)]
-pub fn get_affected_services<P>(
+pub(crate) fn get_affected_service_map<P>(
     socket_path: P,
-    settings_limit: Option<HashSet<String>>,
-) -> Result<model::Services>
-where
-    P: AsRef<Path>,
-{
-    let service_limit = if let Some(settings_limit) = settings_limit {
-        let setting_to_service_map =
-            get_affected_service_map(socket_path.as_ref(), settings_limit)?;
-        if setting_to_service_map.is_empty() {
-            return Ok(HashMap::new());
-        }
-
-        let service_names = get_affected_service_names(setting_to_service_map);
-        Some(service_names)
-    } else {
-        // No limit, we want all services.
-        None
-    };
-
-    let services = get_service_metadata(socket_path.as_ref(), service_limit)?;
-
-    Ok(services)
-}
-
-/// Gather the services affected for each setting into a map, or if `settings_limit` is None, all
-/// services
-#[allow(clippy::implicit_hasher)]
-fn get_affected_service_map<P>(
-    socket_path: P,
-    settings: HashSet<String>,
+    settings: &HashSet<String>,
+    retry: &RetryPolicy,
 ) -> Result<HashMap<String, Vec<String>>>
 where
     P: AsRef<Path>,
 {
-    let query = ("keys", join(&settings, ","));
+    let query = ("keys", join(settings, ","));
 
     // Query the API for affected services
     debug!("Querying API for affected services names");
     let uri = "/metadata/affected-services";
 
     let setting_to_services_map: HashMap<String, Vec<String>> =
-        schnauzer::get_json(socket_path, uri, Some(query)).context(error::GetJson { uri })?;
+        schnauzer::get_json(socket_path, uri, Some(query), retry)
+            .context(error::GetJson { uri })?;
     trace!("API response: {:?}", &setting_to_services_map);
 
     Ok(setting_to_services_map)
 }
 
+/// Keep only the services with the given names, failing if any of them isn't a known service
+pub(crate) fn select_services(
+    services: model::Services,
+    names: &BTreeSet<String>,
+) -> Result<model::Services> {
+    let unknown: Vec<_> = names
+        .iter()
+        .filter(|name| !services.contains_key(*name))
+        .collect();
+    ensure!(
+        unknown.is_empty(),
+        error::UnknownServices {
+            names: join(unknown, ", "),
+            available: join(services.keys(), ", "),
+        }
+    );
+    Ok(services
+        .into_iter()
+        .filter(|(name, _)| names.contains(name))
+        .collect())
+}
+
+/// Keep the services named in the given map of setting to affected service names
+#[allow(clippy::implicit_hasher)]
+pub(crate) fn retain_affected(
+    services: model::Services,
+    setting_to_service_map: HashMap<String, Vec<String>>,
+) -> model::Services {
+    let service_names = get_affected_service_names(setting_to_service_map);
+    services
+        .into_iter()
+        .filter(|(name, _)| service_names.contains(name))
+        .collect()
+}
+
 /// Given a map of Setting to affected Service name, return a
 /// HashSet of affected service names
 fn get_affected_service_names(
@@ -79,91 +101,492 @@ fn get_affected_service_names(
     service_set
 }
 
-/// Gather the metadata for each Service affected
-fn get_service_metadata<P>(
-    socket_path: P,
-    services_limit: Option<HashSet<String>>,
-) -> Result<model::Services>
+/// Remove the services that have configuration files but had none of them written, given the
+/// names of the configuration files that were written.  Services without configuration files are
+/// kept, since they're affected by settings directly.
+pub fn skip_unchanged(services: model::Services, written: &BTreeSet<String>) -> model::Services {
+    retain_services(services, "are unchanged", |file| written.contains(file))
+}
+
+/// Remove the services that have configuration files but had all of them fail, either their check
+/// commands or because their templates were invalid, given the names of the configuration files
+/// that failed.  Services without configuration files are kept, since they're affected by
+/// settings directly.
+pub fn skip_failed(services: model::Services, failed: &BTreeSet<String>) -> model::Services {
+    retain_services(services, "failed", |file| !failed.contains(file))
+}
+
+/// Keep the services that have no configuration files, or any configuration file for which `keep`
+/// returns true.  `reason` describes the files of the services that aren't kept, for logging.
+fn retain_services<F>(services: model::Services, reason: &str, keep: F) -> model::Services
 where
-    P: AsRef<Path>,
+    F: Fn(&str) -> bool,
 {
-    // Only want a query parameter if we had specific affected services, otherwise we want all
-    let query = services_limit.map(|services| ("names", join(&services, ",")));
+    services
+        .into_iter()
+        .filter(|(name, service)| {
+            let retain = service.configuration_files.is_empty()
+                || service.configuration_files.iter().any(|file| keep(file));
+            if !retain {
+                debug!("Configuration files for {} {}, skipping", name, reason);
+            }
+            retain
+        })
+        .collect()
+}
+
+/// How many lines from the end of a failed restart command's stderr to report
+const STDERR_TAIL_LINES: usize = 10;
+
+/// How often to check whether a restart command has finished
+const WAIT_INTERVAL: Duration = Duration::from_millis(50);
 
-    // Query the API for affected service metadata
-    debug!("Querying API for affected service metadata");
-    let uri = "/services";
-    let service_map: model::Services =
-        schnauzer::get_json(socket_path, uri, query).context(error::GetJson { uri })?;
-    trace!("Service metadata: {:?}", &service_map);
+/// How long to wait for a restart command's output once its process group has been killed
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
 
-    Ok(service_map)
+/// RestartReport records how the restart commands of each service went.
+#[derive(Debug, Default, Serialize)]
+pub struct RestartReport {
+    /// The restart commands run for each service, in order.  After one of a service's commands
+    /// fails, its remaining commands aren't run.
+    pub services: BTreeMap<String, Vec<CommandResult>>,
 }
 
-/// Call the `restart()` method on each Service in a Services object
-pub fn restart_services(services: model::Services) -> Result<()> {
-    for (name, service) in services {
-        debug!("Checking for restart-commands for {}", name);
-        service.restart()?;
+impl RestartReport {
+    /// Returns the names of the services with a restart command that failed.
+    pub fn failed_services(&self) -> Vec<&str> {
+        self.services
+            .iter()
+            .filter(|(_, results)| results.iter().any(|result| !result.success))
+            .map(|(name, _)| name.as_str())
+            .collect()
     }
+}
+
+/// CommandResult records how a restart command went.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CommandResult {
+    pub command: String,
+    pub success: bool,
+    /// Whether the command took too long, and was killed
+    pub timed_out: bool,
+    /// The exit status of the command, unless it couldn't be run, or was killed by a signal
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_status: Option<i32>,
+    /// For a failed command, the end of its stderr, or why it couldn't be run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// What the command wrote to stdout and stderr, kept to be logged along with the result, so
+    /// output from services restarted at the same time doesn't interleave
+    #[serde(skip)]
+    pub stdout: String,
+    #[serde(skip)]
+    pub stderr: String,
+}
+
+impl fmt::Display for CommandResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' ", self.command)?;
+        match (self.success, self.exit_status) {
+            (true, _) => write!(f, "succeeded")?,
+            (false, _) if self.timed_out => write!(f, "timed out")?,
+            (false, Some(status)) => write!(f, "failed with exit status {}", status)?,
+            (false, None) => write!(f, "failed")?,
+        }
+        match &self.error {
+            Some(error) if !error.is_empty() => write!(f, ": {}", error),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns the names of the affected services in the order they should be restarted, each once,
+/// so that each service comes after the affected services named in its restart-after list.
+/// Services are otherwise kept in name order.  Names in restart-after that aren't affected are
+/// ignored, since only the order of the services being restarted matters.  Returns a RestartCycle
+/// error naming the services if they depend on each other in a loop.
+#[allow(clippy::implicit_hasher)]
+pub fn plan_restarts(
+    services: &model::Services,
+    affected: &HashSet<String>,
+) -> Result<Vec<String>> {
+    for name in affected {
+        if !services.contains_key(name) {
+            warn!("Affected service '{}' not found, not restarting it", name);
+        }
+    }
+
+    // Services are kept in name order, so the plan is the same every time.
+    let mut plan = Vec::with_capacity(affected.len());
+    let mut done = HashSet::new();
+    let mut path = Vec::new();
+    for name in services.keys().filter(|name| affected.contains(*name)) {
+        visit_restart_after(services, affected, name, &mut done, &mut path, &mut plan)?;
+    }
+    Ok(plan)
+}
+
+/// Depth-first helper for plan_restarts; adds the named service to `plan` after the affected
+/// services it restarts after.  `path` holds the services we're in the middle of visiting, so we
+/// can tell when we've looped back to one of them.
+fn visit_restart_after<'a>(
+    services: &'a model::Services,
+    affected: &HashSet<String>,
+    name: &'a str,
+    done: &mut HashSet<&'a str>,
+    path: &mut Vec<&'a str>,
+    plan: &mut Vec<String>,
+) -> Result<()> {
+    if done.contains(name) {
+        return Ok(());
+    }
+    if let Some(start) = path.iter().position(|&visiting| visiting == name) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(name);
+        return error::RestartCycle {
+            cycle: cycle.join(" -> "),
+        }
+        .fail();
+    }
+
+    path.push(name);
+    if let Some(after) = services.get(name).and_then(|s| s.restart_after.as_ref()) {
+        for dependency in after {
+            if affected.contains(dependency) && services.contains_key(dependency) {
+                visit_restart_after(services, affected, dependency, done, path, plan)?;
+            }
+        }
+    }
+    path.pop();
+
+    done.insert(name);
+    plan.push(name.to_string());
     Ok(())
 }
 
+/// Call the `restart()` method on each Service in a Services object, in the
+/// order given by `plan_restarts`.  Up to `jobs` services are restarted at
+/// once, but never before the services they restart after have finished, so
+/// with one job, they're restarted one at a time in plan order.  A failure to
+/// restart one service doesn't stop the others from being restarted; the
+/// returned report says how each went.  Restart commands that run longer than
+/// the service's restart-timeout, or `default_timeout` if it doesn't have one,
+/// are killed.  `progress` is called as each service's restart starts and
+/// finishes.
+pub fn restart_services(
+    services: model::Services,
+    default_timeout: Duration,
+    jobs: usize,
+    progress: &mut dyn FnMut(RestartProgress),
+) -> Result<RestartReport> {
+    let affected = services.keys().cloned().collect();
+    let plan = plan_restarts(&services, &affected)?;
+    debug!("Restart plan: {:?}", plan);
+
+    // The services each service has to wait for, among those being restarted.
+    let waits_for: HashMap<&str, Vec<&str>> = plan
+        .iter()
+        .map(|name| {
+            let after = services[name]
+                .restart_after
+                .iter()
+                .flatten()
+                .map(|dependency| dependency.as_str())
+                .filter(|dependency| services.contains_key(*dependency))
+                .collect();
+            (name.as_str(), after)
+        })
+        .collect();
+
+    let mut report = RestartReport::default();
+    let mut started = HashMap::new();
+    let mut pending: Vec<&str> = plan.iter().map(|name| name.as_str()).collect();
+    let (sender, receiver) = mpsc::channel();
+    let restarted = crossbeam_utils::thread::scope(|scope| {
+        let mut running = 0;
+        loop {
+            // Start whatever's ready, in plan order, until we're running as many as we can.
+            let mut i = 0;
+            while running < jobs.max(1) && i < pending.len() {
+                let name = pending[i];
+                if !waits_for[name]
+                    .iter()
+                    .all(|dependency| report.services.contains_key(*dependency))
+                {
+                    i += 1;
+                    continue;
+                }
+                pending.remove(i);
+                running += 1;
+
+                debug!("Checking for restart-commands for {}", name);
+                progress(RestartProgress {
+                    service: name.to_string(),
+                    status: RestartStatus::Started,
+                    duration_ms: None,
+                    error: None,
+                });
+                started.insert(name, Instant::now());
+                let service = &services[name];
+                let sender = sender.clone();
+                scope.spawn(move |_| {
+                    // Send back a panic, too, so we don't wait forever for the results.
+                    let results =
+                        panic::catch_unwind(AssertUnwindSafe(|| service.restart(default_timeout)));
+                    // The receiver waits for every service it starts.
+                    let _ = sender.send((name, results));
+                });
+            }
+
+            // The plan has no cycles, so something's ready or running until everything's done.
+            if running == 0 {
+                break;
+            }
+            let (name, results) = match receiver.recv() {
+                Ok(received) => received,
+                // We hold a sender, so this can't happen.
+                Err(_) => break,
+            };
+            running -= 1;
+            let results = results.unwrap_or_else(|e| panic::resume_unwind(e));
+            log_results(name, &results);
+            let failure = results.iter().find(|result| !result.success);
+            progress(RestartProgress {
+                service: name.to_string(),
+                status: if failure.is_some() {
+                    RestartStatus::Failed
+                } else {
+                    RestartStatus::Succeeded
+                },
+                duration_ms: Some(started[name].elapsed().as_millis() as u64),
+                error: failure.map(|result| result.to_string()),
+            });
+            report.services.insert(name.to_string(), results);
+        }
+    });
+    if let Err(e) = restarted {
+        panic::resume_unwind(e);
+    }
+    Ok(report)
+}
+
+/// Logs the results of a service's restart commands all together, along with their output.
+fn log_results(name: &str, results: &[CommandResult]) {
+    for result in results {
+        if result.success {
+            info!("Restart of {}: {}", name, result);
+        } else {
+            error!("Restart of {}: {}", name, result);
+        }
+        trace!("Command stdout: {}", result.stdout);
+        trace!("Command stderr: {}", result.stderr);
+    }
+}
+
 /// This trait is primarily meant to extend the Service model.  It uses the metadata
 /// inside the Service struct to restart the service.
 trait ServiceRestart {
-    /// Restart the service
-    fn restart(&self) -> Result<()>;
+    /// Restart the service, stopping at the first restart command that fails
+    fn restart(&self, default_timeout: Duration) -> Vec<CommandResult>;
 }
 
 impl ServiceRestart for model::Service {
-    fn restart(&self) -> Result<()> {
+    fn restart(&self, default_timeout: Duration) -> Vec<CommandResult> {
+        let timeout = self
+            .restart_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(default_timeout);
+
+        let mut results = Vec::new();
         for restart_command in self.restart_commands.iter() {
-            // Split on space, assume the first item is the command
-            // and the rest are args.
-            debug!("Restart command: {:?}", &restart_command);
-            let mut command_strings = restart_command.split(' ');
-            let command = command_strings
-                .next()
-                .context(error::InvalidRestartCommand {
-                    command: restart_command.as_str(),
-                })?;
-            trace!("Command: {}", &command);
-            trace!("Args: {:?}", &command_strings);
-
-            // Go execute the restart command
-            let result = process::Command::new(command)
-                .args(command_strings)
-                .output()
-                .context(error::CommandExecutionFailure {
-                    command: restart_command.as_str(),
-                })?;
-
-            // If the restart command exited nonzero, call it a failure
-            ensure!(
-                result.status.success(),
-                error::FailedRestartCommand {
-                    command: restart_command.as_str(),
-                    stderr: String::from_utf8_lossy(&result.stderr),
+            let result = match run_restart_command(restart_command, timeout) {
+                Ok(output) => {
+                    // If the restart command exited nonzero, or didn't finish, call it a failure
+                    let success = output.status.map_or(false, |status| status.success());
+                    let full_stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+                    let stderr = tail(&full_stderr, STDERR_TAIL_LINES);
+                    let error = match output.status {
+                        Some(_) if success => None,
+                        Some(_) => Some(stderr),
+                        None if stderr.is_empty() => {
+                            Some(format!("killed after {} seconds", timeout.as_secs()))
+                        }
+                        None => Some(format!(
+                            "killed after {} seconds: {}",
+                            timeout.as_secs(),
+                            stderr
+                        )),
+                    };
+                    CommandResult {
+                        command: restart_command.to_string(),
+                        success,
+                        timed_out: output.status.is_none(),
+                        exit_status: output.status.and_then(|status| status.code()),
+                        error,
+                        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+                        stderr: full_stderr,
+                    }
                 }
+                Err(e) => CommandResult {
+                    command: restart_command.to_string(),
+                    success: false,
+                    timed_out: false,
+                    exit_status: None,
+                    error: Some(e.to_string()),
+                    stdout: String::new(),
+                    stderr: String::new(),
+                },
+            };
+            let success = result.success;
+            results.push(result);
+            if !success {
+                break;
+            }
+        }
+        results
+    }
+}
+
+/// What a restart command did: its exit status, or None if it timed out and was
+/// killed, and what it wrote.
+struct CommandOutput {
+    status: Option<process::ExitStatus>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+/// Run a restart command, returning its output.  If it runs longer than
+/// `timeout`, it's killed, along with anything it started.  Neither form of
+/// command is run through a shell.
+fn run_restart_command(restart_command: &CommandSpec, timeout: Duration) -> Result<CommandOutput> {
+    debug!("Restart command: {:?}", &restart_command);
+    let command_string = restart_command.to_string();
+    let argv: Vec<&str> = match restart_command {
+        // Split on space, assume the first item is the command
+        // and the rest are args.
+        CommandSpec::Split(command) => command.split(' ').collect(),
+        // Exec-form commands are already split, and their args may contain spaces.
+        CommandSpec::Exec(argv) => argv.iter().map(|arg| arg.as_str()).collect(),
+    };
+    let (command, command_strings) = argv
+        .split_first()
+        .filter(|(command, _)| !command.is_empty())
+        .context(error::InvalidRestartCommand {
+            command: command_string.as_str(),
+        })?;
+    trace!("Command: {}", &command);
+    trace!("Args: {:?}", &command_strings);
+
+    // Go execute the restart command, in its own process group, so that if it
+    // times out we can kill it and anything it started, like shell children.
+    let mut command = process::Command::new(command);
+    command
+        .args(command_strings)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Safety: setpgid is async-signal-safe, and we don't allocate, as required between fork and
+    // exec.
+    unsafe {
+        command.pre_exec(|| {
+            unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0))
+                .map_err(|_| io::Error::last_os_error())
+        });
+    }
+    let execution_context = || error::CommandExecutionFailure {
+        command: command_string.as_str(),
+    };
+    let mut child = command.spawn().context(execution_context())?;
+
+    // Read the output as it comes, so the command can't block on a full pipe while we wait.
+    let stdout_reader = read_in_background(child.stdout.take());
+    let stderr_reader = read_in_background(child.stderr.take());
+
+    // The command leads its process group, so the group has its ID.
+    let group = Pid::from_raw(child.id() as i32);
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().context(execution_context())? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            warn!(
+                "Restart command '{}' didn't finish in {} seconds, killing it",
+                restart_command,
+                timeout.as_secs()
             );
-            trace!(
-                "Command stdout: {}",
-                String::from_utf8_lossy(&result.stdout)
-            );
-            trace!(
-                "Command stderr: {}",
-                String::from_utf8_lossy(&result.stderr)
-            );
+            if let Err(e) = signal::killpg(group, Signal::SIGKILL) {
+                warn!(
+                    "Failed to kill restart command '{}': {}",
+                    restart_command, e
+                );
+            }
+            child.wait().context(execution_context())?;
+            break None;
         }
-        Ok(())
+        thread::sleep(WAIT_INTERVAL);
+    };
+
+    // Something the command left running in the background can hold its output open after it
+    // exits, so the reads have the same deadline, with a little grace for reading what's left.
+    let remaining = || {
+        deadline
+            .saturating_duration_since(Instant::now())
+            .max(OUTPUT_GRACE)
+    };
+    let mut stdout = stdout_reader.recv_timeout(remaining());
+    let mut stderr = stderr_reader.recv_timeout(remaining());
+    if stdout.is_err() || stderr.is_err() {
+        warn!(
+            "Restart command '{}' left processes holding its output open, killing them",
+            restart_command
+        );
+        // The group may be gone already, in which case there's nothing to kill.
+        let _ = signal::killpg(group, Signal::SIGKILL);
+        // If the output still isn't closed, something left the group; we go without the rest.
+        stdout = stdout.or_else(|_| stdout_reader.recv_timeout(OUTPUT_GRACE));
+        stderr = stderr.or_else(|_| stderr_reader.recv_timeout(OUTPUT_GRACE));
     }
+    Ok(CommandOutput {
+        status,
+        stdout: stdout.unwrap_or_default(),
+        stderr: stderr.unwrap_or_default(),
+    })
+}
+
+/// Reads everything from the given stream, if any, in a new thread, and sends it on the
+/// returned channel once the stream is closed.
+fn read_in_background<R>(stream: Option<R>) -> mpsc::Receiver<Vec<u8>>
+where
+    R: Read + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut stream) = stream {
+            // A read error just means we have less output to report.
+            let _ = stream.read_to_end(&mut output);
+        }
+        // The receiver may have stopped waiting for us.
+        let _ = sender.send(output);
+    });
+    receiver
+}
+
+/// Returns the last `count` lines of the given text.
+fn tail(text: &str, count: usize) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    lines[lines.len().saturating_sub(count)..].join("\n")
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use maplit::{hashmap, hashset};
+    use maplit::{btreemap, btreeset, hashmap, hashset};
+    use std::convert::TryInto;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
 
     #[test]
     fn test_get_affected_service_names() {
@@ -182,4 +605,300 @@ mod test {
 
         assert_eq!(get_affected_service_names(input_map), expected_output)
     }
+
+    #[test]
+    fn test_skip_unchanged() {
+        let service = |files: &[&str]| model::Service {
+            configuration_files: files.iter().map(|f| (*f).try_into().unwrap()).collect(),
+            restart_commands: vec![CommandSpec::Split("echo hi".to_string())],
+            restart_after: None,
+            restart_timeout: None,
+        };
+        let services = btreemap!(
+            "changed".to_string() => service(&["file1", "file2"]),
+            "unchanged".to_string() => service(&["file2"]),
+            "no-files".to_string() => service(&[]),
+        );
+
+        let kept = skip_unchanged(services, &btreeset! {"file1".to_string()});
+        assert_eq!(kept.keys().collect::<Vec<_>>(), vec!["changed", "no-files"]);
+    }
+
+    #[test]
+    fn test_skip_failed() {
+        let service = |files: &[&str]| model::Service {
+            configuration_files: files.iter().map(|f| (*f).try_into().unwrap()).collect(),
+            restart_commands: vec![CommandSpec::Split("echo hi".to_string())],
+            restart_after: None,
+            restart_timeout: None,
+        };
+        let services = btreemap!(
+            "some-failed".to_string() => service(&["file1", "file2"]),
+            "all-failed".to_string() => service(&["file2"]),
+            "no-files".to_string() => service(&[]),
+        );
+
+        let kept = skip_failed(services, &btreeset! {"file2".to_string()});
+        assert_eq!(
+            kept.keys().collect::<Vec<_>>(),
+            vec!["no-files", "some-failed"]
+        );
+    }
+
+    #[test]
+    fn test_tail() {
+        assert_eq!(tail("a\nb\nc\n", 2), "b\nc");
+        assert_eq!(tail("a\nb\n", 5), "a\nb");
+        assert_eq!(tail("", 5), "");
+    }
+
+    #[test]
+    fn test_restart_report() {
+        let service = |commands: &[&str]| model::Service {
+            configuration_files: vec![],
+            restart_commands: commands
+                .iter()
+                .map(|c| CommandSpec::Split(c.to_string()))
+                .collect(),
+            restart_after: None,
+            restart_timeout: None,
+        };
+        let services = btreemap!(
+            "good".to_string() => service(&["/bin/true", "/bin/true"]),
+            "bad".to_string() => service(&["/bin/false", "/bin/true"]),
+            "noisy".to_string() => service(&["/bin/ls /no-such-file-here"]),
+            "missing".to_string() => service(&["/no/such/command"]),
+            "invalid".to_string() => service(&[" /bin/true"]),
+            // Exec-form args are passed as given, rather than split on spaces
+            "exec".to_string() => model::Service {
+                configuration_files: vec![],
+                restart_commands: vec![CommandSpec::Exec(vec![
+                    "/bin/sh".to_string(),
+                    "-c".to_string(),
+                    "exit 3".to_string(),
+                ])],
+                restart_after: None,
+                restart_timeout: None,
+            },
+        );
+
+        // Failures don't stop other services from being restarted
+        let mut events = Vec::new();
+        let report = restart_services(services, Duration::from_secs(60), 1, &mut |event| {
+            events.push(event)
+        })
+        .unwrap();
+        assert_eq!(
+            report.failed_services(),
+            vec!["bad", "exec", "invalid", "missing", "noisy"]
+        );
+        let good = &report.services["good"];
+        assert_eq!(good.len(), 2);
+        assert!(good.iter().all(|r| r.success && r.exit_status == Some(0)));
+
+        // A service's remaining commands aren't run after a failure
+        let bad = &report.services["bad"];
+        assert_eq!(bad.len(), 1);
+        assert_eq!(bad[0].exit_status, Some(1));
+        let exec = &report.services["exec"][0];
+        assert_eq!(exec.exit_status, Some(3));
+        assert_eq!(exec.command, r#"/bin/sh -c "exit 3""#);
+
+        // Failures say why
+        let noisy = &report.services["noisy"][0];
+        assert!(noisy.exit_status.unwrap() > 0);
+        assert!(noisy.error.as_ref().unwrap().contains("no-such-file-here"));
+        let missing = &report.services["missing"][0];
+        assert_eq!(missing.exit_status, None);
+        assert!(missing.error.as_ref().unwrap().contains("/no/such/command"));
+        assert!(noisy.to_string().contains("failed with exit status"));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["services"]["bad"][0]["command"], "/bin/false");
+        assert_eq!(json["services"]["bad"][0]["exit-status"], 1);
+        assert_eq!(json["services"]["good"][0]["success"], true);
+        assert!(json["services"]["good"][0].get("error").is_none());
+
+        // Each restart's start and finish is reported, one at a time with one job
+        let statuses: Vec<_> = events
+            .iter()
+            .map(|event| (event.service.as_str(), event.status))
+            .collect();
+        assert_eq!(
+            &statuses[..4],
+            &[
+                ("bad", RestartStatus::Started),
+                ("bad", RestartStatus::Failed),
+                ("exec", RestartStatus::Started),
+                ("exec", RestartStatus::Failed),
+            ]
+        );
+        assert_eq!(events.len(), 12);
+        let good = events
+            .iter()
+            .find(|event| event.service == "good" && event.status == RestartStatus::Succeeded)
+            .unwrap();
+        assert!(good.duration_ms.is_some());
+        assert!(good.error.is_none());
+        assert_eq!(
+            events[1].error.as_deref(),
+            Some("'/bin/false' failed with exit status 1")
+        );
+    }
+
+    #[test]
+    fn test_restart_timeout() {
+        // The script starts a child in the background, which has to be killed with it for its
+        // output pipes to close, and for us to finish.
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("hang");
+        fs::write(
+            &script,
+            "#!/bin/sh\necho stopping >&2\nsleep 600 &\nsleep 600\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let service = |command: &str, timeout: Option<u64>| model::Service {
+            configuration_files: vec![],
+            restart_commands: vec![
+                CommandSpec::Split(command.to_string()),
+                CommandSpec::Split("/bin/true".to_string()),
+            ],
+            restart_after: None,
+            restart_timeout: timeout,
+        };
+        let services = btreemap!(
+            "hung".to_string() => service(script.to_str().unwrap(), Some(1)),
+            "sleepy".to_string() => service("sleep 600", None),
+            "quick".to_string() => service("/bin/true", Some(1)),
+        );
+
+        let start = Instant::now();
+        let report = restart_services(services, Duration::from_secs(1), 4, &mut |_| {}).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        assert_eq!(report.failed_services(), vec!["hung", "sleepy"]);
+        for name in &["hung", "sleepy"] {
+            let results = &report.services[*name];
+            assert_eq!(results.len(), 1);
+            assert!(results[0].timed_out);
+            assert_eq!(results[0].exit_status, None);
+            assert!(results[0].to_string().contains("timed out"));
+        }
+        let hung = report.services["hung"][0].error.as_ref().unwrap();
+        assert!(hung.contains("stopping"), "{}", hung);
+        assert_eq!(report.services["quick"].len(), 2);
+    }
+
+    #[test]
+    fn test_restart_background_output() {
+        // The command exits right away, but leaves a child in the background holding its output
+        // open, which has to be killed at the deadline for us to finish.
+        let command = CommandSpec::Exec(vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            "echo started; sleep 600 &".to_string(),
+        ]);
+        let start = Instant::now();
+        let output = run_restart_command(&command, Duration::from_secs(1)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        assert!(output.status.unwrap().success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "started\n");
+    }
+
+    /// Builds a Services from (name, restart-after) pairs, with no files or commands.
+    fn ordered_services(list: &[(&str, &[&str])]) -> model::Services {
+        list.iter()
+            .map(|(name, after)| {
+                let service = model::Service {
+                    configuration_files: vec![],
+                    restart_commands: vec![],
+                    restart_after: Some(after.iter().map(|s| s.to_string()).collect()),
+                    restart_timeout: None,
+                };
+                (name.to_string(), service)
+            })
+            .collect()
+    }
+
+    fn names(list: &[&str]) -> HashSet<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn plan_diamond() {
+        // d needs b and c, which both need a; each is restarted once, after what it needs.
+        let services =
+            ordered_services(&[("a", &[]), ("b", &["a"]), ("c", &["a"]), ("d", &["c", "b"])]);
+        let plan = plan_restarts(&services, &names(&["d", "c", "b", "a"])).unwrap();
+        assert_eq!(plan, vec!["a", "b", "c", "d"]);
+
+        // Dependencies on unaffected services are ignored.
+        let plan = plan_restarts(&services, &names(&["d", "c"])).unwrap();
+        assert_eq!(plan, vec!["c", "d"]);
+    }
+
+    #[test]
+    fn plan_isolated() {
+        let services = ordered_services(&[
+            ("containerd", &[]),
+            ("kubelet", &["containerd"]),
+            ("motd", &[]),
+        ]);
+        let plan = plan_restarts(&services, &names(&["motd"])).unwrap();
+        assert_eq!(plan, vec!["motd"]);
+
+        let plan = plan_restarts(&services, &names(&["motd", "kubelet", "containerd"])).unwrap();
+        assert_eq!(plan, vec!["containerd", "kubelet", "motd"]);
+
+        // Unknown services are left out.
+        let plan = plan_restarts(&services, &names(&["motd", "missing"])).unwrap();
+        assert_eq!(plan, vec!["motd"]);
+    }
+
+    #[test]
+    fn plan_cycle() {
+        let services = ordered_services(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"]), ("d", &[])]);
+        match plan_restarts(&services, &names(&["a", "b", "c", "d"])) {
+            Err(error::Error::RestartCycle { cycle }) => assert_eq!(cycle, "a -> b -> c -> a"),
+            other => panic!("Expected restart cycle, got {:?}", other),
+        }
+
+        // The cycle doesn't matter if part of it isn't being restarted.
+        let plan = plan_restarts(&services, &names(&["a", "b", "d"])).unwrap();
+        assert_eq!(plan, vec!["b", "a", "d"]);
+    }
+
+    #[test]
+    fn parallel_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let service = |script: &str, after: &[&str]| model::Service {
+            configuration_files: vec![],
+            restart_commands: vec![CommandSpec::Exec(vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                script.replace("LOG", log.to_str().unwrap()),
+            ])],
+            restart_after: Some(after.iter().map(|s| s.to_string()).collect()),
+            restart_timeout: None,
+        };
+        // Independent services restart together; "second" still waits for "first".
+        let services = btreemap!(
+            "first".to_string() => service("sleep 1; echo first >> LOG", &[]),
+            "second".to_string() => service("echo second >> LOG", &["first"]),
+            "x".to_string() => service("sleep 1", &[]),
+            "y".to_string() => service("sleep 1", &[]),
+            "z".to_string() => service("sleep 1", &[]),
+        );
+
+        let start = Instant::now();
+        let report = restart_services(services, Duration::from_secs(60), 4, &mut |_| {}).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert!(report.failed_services().is_empty());
+        assert_eq!(report.services.len(), 5);
+        assert_eq!(fs::read_to_string(&log).unwrap(), "first\nsecond\n");
+    }
 }
@@ -22,6 +22,10 @@ All structs must serde-`Serializable` and -`Deserializable`, and comparable via
 `Debug` is added for convenience.
 `Default` can also be added by specifying the argument `impl_default = true`.
 
+`JsonSchema` is derived too, so the API can describe the model to clients.
+Each struct's schema is named after the struct itself rather than its serde name, because several structs share the serde name "".
+If you set your own derives, none of these are added, and the struct has no schema.
+
 ## Serde
 
 Structs have a `#[serde(...)]` attribute added to deny unknown fields and rename fields to kebab-case.
@@ -126,11 +130,18 @@ impl VisitMut for ModelHelper {
         if !is_attr_set("derive", &node.attrs) {
             // Derive Default, if the user requested
             let attr = if self.impl_default {
-                parse_quote!(#[derive(Debug, Default, PartialEq, Serialize, Deserialize)])
+                parse_quote!(
+                    #[derive(Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+                )
             } else {
-                parse_quote!(#[derive(Debug, PartialEq, Serialize, Deserialize)])
+                parse_quote!(#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)])
             };
             node.attrs.push(attr);
+
+            // Name the schema after the struct; see the module docs.
+            let schema_name = node.ident.to_string();
+            node.attrs
+                .push(parse_quote!(#[schemars(rename = #schema_name)]));
         }
 
         // Let the default implementation do its thing, recursively.
@@ -0,0 +1,192 @@
+//! Progress events from the config applier.
+//!
+//! With `--output json`, thar-be-settings writes an event to stdout as each step of applying
+//! settings happens, one JSON object per line, ending with a summary of the run.  Each event has
+//! an "event" field saying what kind it is, for example:
+//!
+//! ```text
+//! {"event":"file","name":"motd","status":"written"}
+//! ```
+//!
+//! The events are defined here, rather than in thar-be-settings, so the API server can read them
+//! without depending on the applier.
+
+use serde::{Deserialize, Serialize};
+
+/// One line of the config applier's progress output.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum ApplyEvent {
+    /// Settings were fetched and the run is starting.  If settings change before the applier
+    /// writes anything, it starts over with another run-started event.
+    RunStarted(RunStarted),
+    /// A configuration file was rendered, written, left unchanged, or failed.
+    File(FileProgress),
+    /// A service's restart started or finished.
+    Restart(RestartProgress),
+    /// The run finished; this is always the last event.
+    Summary(Summary),
+}
+
+/// What a run is applying.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RunStarted {
+    /// The changed settings keys being applied; empty when all settings are applied
+    pub keys: Vec<String>,
+    /// The services being applied
+    pub services: Vec<String>,
+}
+
+/// A step in applying a configuration file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileProgress {
+    pub name: String,
+    pub status: FileStatus,
+    /// Why the file failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileStatus {
+    /// The template rendered; it's followed by written, unchanged, or failed
+    Rendered,
+    /// The file was written, or in a dry run, would be
+    Written,
+    /// The file already had the rendered contents
+    Unchanged,
+    /// The template was invalid or failed to render, or the file failed its check command
+    Failed,
+}
+
+/// A step in restarting a service.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RestartProgress {
+    pub service: String,
+    pub status: RestartStatus,
+    /// How long the service's restart commands took, once they're finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// Why the restart failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartStatus {
+    Started,
+    Succeeded,
+    Failed,
+}
+
+/// How a run went, overall.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Summary {
+    pub success: bool,
+    /// The applier's exit code
+    pub exit_code: i32,
+    /// Why the run failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Whether this was a dry run, in which case nothing was written or restarted
+    pub dry_run: bool,
+    /// The configuration files written, or in a dry run, that would be
+    pub written: Vec<String>,
+    pub failed_files: Vec<String>,
+    pub restarted: Vec<String>,
+    pub failed_services: Vec<String>,
+    /// How long the run took
+    pub duration_ms: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(event: ApplyEvent) {
+        let line = serde_json::to_string(&event).unwrap();
+        assert!(!line.contains('\n'), "{}", line);
+        let parsed: ApplyEvent = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed, event, "{}", line);
+    }
+
+    #[test]
+    fn events_round_trip() {
+        round_trip(ApplyEvent::RunStarted(RunStarted {
+            keys: vec!["settings.motd".to_string()],
+            services: vec!["motd".to_string()],
+        }));
+        for status in &[
+            FileStatus::Rendered,
+            FileStatus::Written,
+            FileStatus::Unchanged,
+        ] {
+            round_trip(ApplyEvent::File(FileProgress {
+                name: "motd".to_string(),
+                status: *status,
+                error: None,
+            }));
+        }
+        round_trip(ApplyEvent::File(FileProgress {
+            name: "motd".to_string(),
+            status: FileStatus::Failed,
+            error: Some("check failed".to_string()),
+        }));
+        round_trip(ApplyEvent::Restart(RestartProgress {
+            service: "motd".to_string(),
+            status: RestartStatus::Started,
+            duration_ms: None,
+            error: None,
+        }));
+        round_trip(ApplyEvent::Restart(RestartProgress {
+            service: "motd".to_string(),
+            status: RestartStatus::Failed,
+            duration_ms: Some(12),
+            error: Some("'/bin/false' failed with exit status 1".to_string()),
+        }));
+        round_trip(ApplyEvent::Summary(Summary {
+            success: false,
+            exit_code: 8,
+            error: Some("Failed to restart services: motd".to_string()),
+            dry_run: false,
+            written: vec!["motd".to_string()],
+            failed_files: vec![],
+            restarted: vec![],
+            failed_services: vec!["motd".to_string()],
+            duration_ms: 34,
+        }));
+    }
+
+    #[test]
+    fn event_format() {
+        let event = ApplyEvent::File(FileProgress {
+            name: "motd".to_string(),
+            status: FileStatus::Written,
+            error: None,
+        });
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"file","name":"motd","status":"written"}"#
+        );
+
+        let event: ApplyEvent = serde_json::from_str(
+            r#"{"event":"restart","service":"motd","status":"succeeded","duration-ms":5}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            event,
+            ApplyEvent::Restart(RestartProgress {
+                service: "motd".to_string(),
+                status: RestartStatus::Succeeded,
+                duration_ms: Some(5),
+                error: None,
+            })
+        );
+    }
+}
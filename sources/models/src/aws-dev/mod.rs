@@ -1,4 +1,5 @@
 use model_derive::model;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -0,0 +1,208 @@
+//! Rules for the names of data store keys, like "settings.motd".
+//!
+//! Names are dotted strings, with the dots implying hierarchy.  A segment that needs to include a
+//! dot can be quoted, so the name a."b.c".d has three segments: "a", "b.c", and "d".
+//!
+//! The rules are defined here so the API server's data store and programs that are only given key
+//! names to check, like the config applier, agree on what's valid.
+
+use snafu::ensure;
+
+pub const KEY_SEPARATOR: char = '.';
+// String refs are more convenient for some Rust functions
+pub const KEY_SEPARATOR_STR: &str = ".";
+
+/// Maximum length of a full key name, including separators and quotes.  Each segment becomes a
+/// path component on the filesystem, so this keeps us well under the maximum path length of 4096.
+pub const MAX_KEY_NAME_LENGTH: usize = 1024;
+
+/// Maximum length of a single key segment.  Segments become file names on the filesystem, which
+/// are limited to 255 bytes, and may grow when special characters are encoded.
+pub const MAX_KEY_SEGMENT_LENGTH: usize = 128;
+
+pub mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility = "pub(super)")]
+    pub enum Error {
+        #[snafu(display("Key name '{}' has invalid format: {}", name, msg))]
+        InvalidKey { name: String, msg: String },
+
+        #[snafu(display("Key name beyond maximum length {}: {}", name, max))]
+        KeyTooLong { name: String, max: usize },
+
+        #[snafu(display("Key name '{}' has an empty segment", name))]
+        EmptyKeySegment { name: String },
+
+        #[snafu(display(
+            "Key name '{}' has segment '{}' beyond maximum segment length {}",
+            name,
+            segment,
+            max
+        ))]
+        KeySegmentTooLong {
+            name: String,
+            segment: String,
+            max: usize,
+        },
+    }
+}
+
+pub use error::Error;
+type Result<T> = std::result::Result<T, Error>;
+
+/// Determines whether a character is acceptable within a segment of a key name.  This is
+/// separate from quoting; if a character isn't valid, it isn't valid quoted, either.
+pub fn valid_character(c: char) -> bool {
+    match c {
+        'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '/' => true,
+        _ => false,
+    }
+}
+
+/// Given a key name, returns a list of its name segments, separated by KEY_SEPARATOR.
+/// Respects quoting of segments so they can contain dots.  The segments aren't checked against
+/// the length limits; see check_name_segments.
+///
+/// Examples:
+/// * a.b.c -> ["a", "b", "c"]
+/// * "a.b".c -> ["a.b", "c"]
+pub fn parse_name_segments<S: AsRef<str>>(name: S) -> Result<Vec<String>> {
+    let name = name.as_ref();
+
+    ensure!(
+        !name.is_empty(),
+        error::InvalidKey {
+            name,
+            msg: "cannot be empty",
+        }
+    );
+
+    // The full list of name segments we're going to return.
+    let mut segments = Vec::new();
+    // The current name segment we're checking.
+    let mut segment = String::new();
+    // Track whether we're inside a quoted section of the key name
+    let mut in_quotes = false;
+
+    // Walk through each character, looking for quotes or separators to update state
+    for c in name.chars() {
+        if c == '"' {
+            // Quotes don't go into the name segments, so we just flip the flag.
+            in_quotes = !in_quotes;
+        } else if c == KEY_SEPARATOR {
+            if in_quotes {
+                // If we see a separator inside quotes, it's just like any other character.
+                segment.push(c);
+            } else {
+                // If we see a separator outside quotes, it should be ending a segment.
+                // Segments can't be empty.
+                ensure!(!segment.is_empty(), error::EmptyKeySegment { name });
+                // Save the segment we just saw and start a new one.
+                segments.push(segment);
+                segment = String::new();
+            }
+        } else {
+            // Not a special character; make sure it's a valid part of a name segment.
+            if valid_character(c) {
+                segment.push(c);
+            } else {
+                return error::InvalidKey {
+                    name,
+                    msg: format!("invalid character in key: '{}'", c),
+                }
+                .fail();
+            }
+        }
+    }
+
+    ensure!(
+        !in_quotes,
+        error::InvalidKey {
+            name,
+            msg: "unbalanced quotes",
+        }
+    );
+    ensure!(!segment.is_empty(), error::EmptyKeySegment { name });
+
+    // Push final segment (keys don't end with a dot, which is when we normally push)
+    segments.push(segment);
+
+    Ok(segments)
+}
+
+/// Checks a key name and its segments against the length limits, and makes sure no segment is
+/// empty.
+pub fn check_name_segments<S1, S2>(name: S1, segments: &[S2]) -> Result<()>
+where
+    S1: AsRef<str>,
+    S2: AsRef<str>,
+{
+    let name = name.as_ref();
+
+    ensure!(
+        name.len() <= MAX_KEY_NAME_LENGTH,
+        error::KeyTooLong {
+            name,
+            max: MAX_KEY_NAME_LENGTH,
+        }
+    );
+
+    for segment in segments {
+        let segment = segment.as_ref();
+        ensure!(!segment.is_empty(), error::EmptyKeySegment { name });
+        ensure!(
+            segment.len() <= MAX_KEY_SEGMENT_LENGTH,
+            error::KeySegmentTooLong {
+                name,
+                segment,
+                max: MAX_KEY_SEGMENT_LENGTH,
+            }
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_segments() {
+        assert_eq!(parse_name_segments("a").unwrap(), vec!["a"]);
+        assert_eq!(parse_name_segments("a.b.c").unwrap(), vec!["a", "b", "c"]);
+        assert_eq!(parse_name_segments("\"a.b\".c").unwrap(), vec!["a.b", "c"]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for name in &["", "a.", "a..b", "\"a.b", "a!b"] {
+            assert!(parse_name_segments(name).is_err(), "{}", name);
+        }
+        match parse_name_segments("a..b") {
+            Err(Error::EmptyKeySegment { .. }) => {}
+            other => panic!("expected EmptyKeySegment, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_lengths() {
+        let segment = "a".repeat(MAX_KEY_SEGMENT_LENGTH);
+        check_name_segments(&segment, &[&segment]).unwrap();
+
+        let long_segment = "a".repeat(MAX_KEY_SEGMENT_LENGTH + 1);
+        match check_name_segments(&long_segment, &[&long_segment]) {
+            Err(Error::KeySegmentTooLong { .. }) => {}
+            other => panic!("expected KeySegmentTooLong, got {:?}", other),
+        }
+
+        let segments = vec![segment.as_str(); MAX_KEY_NAME_LENGTH / MAX_KEY_SEGMENT_LENGTH + 1];
+        let name = segments.join(KEY_SEPARATOR_STR);
+        match check_name_segments(&name, &segments) {
+            Err(Error::KeyTooLong { .. }) => {}
+            other => panic!("expected KeyTooLong, got {:?}", other),
+        }
+    }
+}
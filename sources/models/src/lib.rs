@@ -48,6 +48,12 @@ Rust needs a `mod.rs` file to understand that a directory is part of the module
 Note: all models share the same `Cargo.toml`.
 */
 
+// Progress events from the config applier, which the API server reads.
+pub mod apply_progress;
+
+// The rules for data store key names, shared by the data store and programs given key names.
+pub mod key_name;
+
 // "Modeled types" are types with special ser/de behavior used for validation.
 pub mod modeled_types;
 
@@ -61,13 +67,14 @@ pub use variant::*;
 // are in subdirectories and linked into place by build.rs at variant/current.)
 
 use model_derive::model;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::Ipv4Addr;
 
 use crate::modeled_types::{
-    KubernetesClusterName, KubernetesLabelKey, KubernetesLabelValue, KubernetesTaintValue,
-    SingleLineString, Url, ValidBase64,
+    CommandSpec, FileMode, KubernetesClusterName, KubernetesLabelKey, KubernetesLabelValue,
+    KubernetesTaintValue, SingleLineString, Url, ValidBase64,
 };
 
 // Kubernetes related settings. The dynamic settings are retrieved from
@@ -125,25 +132,54 @@ struct AwsSettings {
 // rename="" so they don't add an extra prefix to the datastore path that doesn't actually exist.
 // This is important because we have APIs that can return those sub-structures directly.
 
-pub type Services = HashMap<String, Service>;
+// Services and configuration files are kept in name order so that anything iterating over them,
+// like restarting services or rendering files, does so in the same order every time.
+pub type Services = BTreeMap<String, Service>;
 
 #[model(add_option = false, rename = "")]
 struct Service {
     configuration_files: Vec<SingleLineString>,
-    restart_commands: Vec<String>,
+    // Each command is either a string, split on spaces, or an array of the program and its
+    // arguments, which are passed as given.
+    restart_commands: Vec<CommandSpec>,
+    // Names of other services that must be restarted before this one, when both are restarted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    restart_after: Option<Vec<String>>,
+    // Seconds to wait for each restart command before killing it, if not the applier's default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    restart_timeout: Option<u64>,
 }
 
-pub type ConfigurationFiles = HashMap<String, ConfigurationFile>;
+pub type ConfigurationFiles = BTreeMap<String, ConfigurationFile>;
 
 #[model(add_option = false, rename = "")]
 struct ConfigurationFile {
     path: SingleLineString,
     template_path: SingleLineString,
+    // Permissions, and owning user and group names, for the rendered file.  Without them, new
+    // files are 0644 and owned by whoever writes them, and replaced files keep their permissions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mode: Option<FileMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<SingleLineString>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    group: Option<SingleLineString>,
+    // Command that validates the rendered file before it's installed, like "sshd -t -f {}".  The
+    // path of the rendered file replaces "{}", or is added as the last argument if there's no "{}".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    check_command: Option<String>,
+    // Paths of shared template fragments the template includes, like "{{> proxy-env}}".  Each is
+    // registered as a partial under its file name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    partials: Option<Vec<SingleLineString>>,
 }
 
 ///// Metadata
 
+// toml::Value has no schema, so we set our own derives to leave out JsonSchema; metadata isn't
+// part of the settings schema anyway.
 #[model(add_option = false, rename = "metadata")]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct Metadata {
     key: SingleLineString,
     md: SingleLineString,
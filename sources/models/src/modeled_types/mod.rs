@@ -28,6 +28,12 @@ pub mod error {
         #[snafu(display("Given invalid URL '{}'", input))]
         InvalidUrl { input: String },
 
+        #[snafu(display("File modes must be 3 or 4 octal digits, like '0644', given: {}", input))]
+        InvalidFileMode { input: String },
+
+        #[snafu(display("Exec-form commands must start with a program to run"))]
+        EmptyCommand,
+
         #[snafu(display("{} must match '{}', given: {}", thing, pattern, input))]
         Pattern {
             thing: String,
@@ -116,6 +122,18 @@ macro_rules! string_impls_for {
                 x.inner
             }
         }
+
+        /// Modeled types are strings in the schema; their validation isn't described there, but
+        /// the schema is named after the type so clients can tell what's expected.
+        impl JsonSchema for $for {
+            fn schema_name() -> String {
+                $for_str.to_string()
+            }
+
+            fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+                String::json_schema(gen)
+            }
+        }
     };
 }
 
@@ -1,3 +1,6 @@
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 // Just need serde's Error in scope to get its trait methods
 use serde::de::Error as _;
@@ -250,3 +253,210 @@ mod test_url {
         }
     }
 }
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// FileMode represents Unix file permission bits, and can only be created from 3 or 4 octal
+/// digits, like "644" or "0600".  It stores the original form, so it's shown the same way it was
+/// given, and the permission bits are available through `mode`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct FileMode {
+    inner: String,
+    mode: u32,
+}
+
+impl FileMode {
+    /// Returns the permission bits, ready to be applied to a file.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+}
+
+impl TryFrom<&str> for FileMode {
+    type Error = error::Error;
+
+    fn try_from(input: &str) -> Result<Self, Self::Error> {
+        ensure!(
+            (input.len() == 3 || input.len() == 4) && input.chars().all(|c| c.is_digit(8)),
+            error::InvalidFileMode { input }
+        );
+        // Checked above, so it fits, and it's octal.
+        let mode = u32::from_str_radix(input, 8).unwrap_or_default();
+        Ok(FileMode {
+            inner: input.to_string(),
+            mode,
+        })
+    }
+}
+
+string_impls_for!(FileMode, "FileMode");
+
+#[cfg(test)]
+mod test_file_mode {
+    use super::FileMode;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn good_file_modes() {
+        for (ok, mode) in &[
+            ("0600", 0o600),
+            ("644", 0o644),
+            ("4755", 0o4755),
+            ("000", 0),
+        ] {
+            assert_eq!(FileMode::try_from(*ok).unwrap().mode(), *mode);
+        }
+        assert_eq!(FileMode::try_from("0640").unwrap().to_string(), "0640");
+    }
+
+    #[test]
+    fn bad_file_modes() {
+        for err in &[
+            "",
+            "64",
+            "00644",
+            "0800",
+            "rw-r--r--",
+            "0o644",
+            "-644",
+            " 644",
+        ] {
+            FileMode::try_from(*err).unwrap_err();
+        }
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// CommandSpec is a command to run, like a service's restart command.  It can be given as a single
+/// string, the legacy form, which the runner splits on spaces into a program and its arguments, or
+/// as an array of the program and its arguments, which are used as given.  The array ("exec")
+/// form means arguments never need quoting, and a value that ends up in an argument can't turn
+/// into extra arguments.  An exec-form command must have at least a program to run.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum CommandSpec {
+    /// A command line to split on spaces, without a shell.
+    Split(String),
+    Exec(Vec<String>),
+}
+
+/// Validate an exec-form command before we accept the input.
+impl TryFrom<Vec<String>> for CommandSpec {
+    type Error = error::Error;
+
+    fn try_from(argv: Vec<String>) -> Result<Self, Self::Error> {
+        ensure!(
+            argv.first().map_or(false, |program| !program.is_empty()),
+            error::EmptyCommand
+        );
+        Ok(CommandSpec::Exec(argv))
+    }
+}
+
+impl<'de> Deserialize<'de> for CommandSpec {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        // Accept either form, then check the exec form's argv.
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum AnyForm {
+            Split(String),
+            Exec(Vec<String>),
+        }
+
+        match AnyForm::deserialize(deserializer)? {
+            AnyForm::Split(command) => Ok(CommandSpec::Split(command)),
+            AnyForm::Exec(argv) => CommandSpec::try_from(argv).map_err(D::Error::custom),
+        }
+    }
+}
+
+/// Shows the command the way it would be typed; exec-form arguments that are empty or contain
+/// whitespace are quoted, so they're distinguishable.
+impl fmt::Display for CommandSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandSpec::Split(command) => write!(f, "{}", command),
+            CommandSpec::Exec(argv) => {
+                for (i, arg) in argv.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    if arg.is_empty() || arg.contains(char::is_whitespace) {
+                        write!(f, "{:?}", arg)?;
+                    } else {
+                        write!(f, "{}", arg)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_command_spec {
+    use super::CommandSpec;
+
+    #[test]
+    fn both_forms() {
+        let commands: Vec<CommandSpec> =
+            serde_json::from_str(r#"["systemctl restart foo", ["/bin/echo", "hi there"]]"#)
+                .unwrap();
+        assert_eq!(
+            commands,
+            vec![
+                CommandSpec::Split("systemctl restart foo".to_string()),
+                CommandSpec::Exec(vec!["/bin/echo".to_string(), "hi there".to_string()]),
+            ]
+        );
+        assert_eq!(commands[0].to_string(), "systemctl restart foo");
+        assert_eq!(commands[1].to_string(), r#"/bin/echo "hi there""#);
+
+        // Each form serializes back the way it was given.
+        assert_eq!(
+            serde_json::to_string(&commands).unwrap(),
+            r#"["systemctl restart foo",["/bin/echo","hi there"]]"#
+        );
+    }
+
+    #[test]
+    fn service_restart_commands() {
+        // Services are defined in TOML defaults, where either form can be used, and commands
+        // can be left out entirely.
+        let service: crate::Service = toml::from_str(
+            r#"
+            configuration-files = ["motd"]
+            restart-commands = ["systemctl restart foo", ["/bin/echo", "hi there"]]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            service.restart_commands,
+            vec![
+                CommandSpec::Split("systemctl restart foo".to_string()),
+                CommandSpec::Exec(vec!["/bin/echo".to_string(), "hi there".to_string()]),
+            ]
+        );
+
+        let service: crate::Service = toml::from_str(
+            r#"
+            configuration-files = ["motd"]
+            restart-commands = []
+            "#,
+        )
+        .unwrap();
+        assert!(service.restart_commands.is_empty());
+        assert!(service.restart_after.is_none());
+    }
+
+    #[test]
+    fn bad_commands() {
+        for bad in &["[]", r#"[""]"#, r#"["/bin/echo", 1]"#, "1", "{}"] {
+            serde_json::from_str::<CommandSpec>(bad).unwrap_err();
+        }
+    }
+}
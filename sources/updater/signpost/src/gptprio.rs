@@ -1,11 +1,14 @@
 use bit_field::BitField;
 use std::fmt;
 
+/// The Chrome OS-style GPT attribute bits of a boot partition, which GRUB uses to pick the
+/// partition set to boot.
 #[derive(Debug, Clone, Copy)]
-pub(crate) struct GptPrio(u64);
+pub struct GptPrio(u64);
 
 impl GptPrio {
-    pub(crate) fn priority(self) -> u64 {
+    /// The set with the higher priority is booted first; 0 means the set isn't tried.
+    pub fn priority(self) -> u64 {
         self.0.get_bits(48..52)
     }
 
@@ -14,7 +17,8 @@ impl GptPrio {
         self.0.set_bits(48..52, priority);
     }
 
-    pub(crate) fn tries_left(self) -> u64 {
+    /// How many more times the set may be booted before it has booted successfully.
+    pub fn tries_left(self) -> u64 {
         self.0.get_bits(52..56)
     }
 
@@ -23,7 +27,8 @@ impl GptPrio {
         self.0.set_bits(52..56, tries_left);
     }
 
-    pub(crate) fn successful(self) -> bool {
+    /// Whether the set has booted successfully.
+    pub fn successful(self) -> bool {
         self.0.get_bit(56)
     }
 
@@ -31,7 +36,8 @@ impl GptPrio {
         self.0.set_bit(56, successful);
     }
 
-    pub(crate) fn will_boot(self) -> bool {
+    /// Whether GRUB would consider booting the set.
+    pub fn will_boot(self) -> bool {
         (self.priority() > 0 && self.tries_left() > 0) || self.successful()
     }
 }
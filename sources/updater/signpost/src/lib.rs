@@ -5,6 +5,7 @@ mod set;
 mod state;
 
 pub use error::{Error, GPTError};
+pub use gptprio::GptPrio;
 pub use guid::uuid_to_guid;
 pub use set::PartitionSet;
-pub use state::State;
+pub use state::{State, ROOT_MOUNT};
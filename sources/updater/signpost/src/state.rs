@@ -14,6 +14,9 @@ const BOTTLEROCKET_BOOT: [u8; 16] = uuid_to_guid(hex!("6b636168 7420 6568 2070 6
 const BOTTLEROCKET_ROOT: [u8; 16] = uuid_to_guid(hex!("5526016a 1a97 4ea4 b39a b7c8c6ca4502"));
 const BOTTLEROCKET_HASH: [u8; 16] = uuid_to_guid(hex!("598f10af c955 4456 6a99 7720068a6cea"));
 
+/// The mount whose backing disk is taken to be the OS disk.
+pub const ROOT_MOUNT: &str = "/";
+
 #[derive(Debug, Clone)]
 pub struct State {
     os_disk: PathBuf,
@@ -42,8 +45,8 @@ impl State {
     pub fn load() -> Result<Self, Error> {
         // The root filesystem is a dm-verity device. We want to determine what disk and partition
         // the backing data is part of. Look up the device major and minor via stat(2):
-        let root_fs = BlockDevice::from_device_path("/")
-            .context(error::BlockDeviceFromPath { device: "/" })?;
+        let root_fs = BlockDevice::from_device_path(ROOT_MOUNT)
+            .context(error::BlockDeviceFromPath { device: ROOT_MOUNT })?;
         // Get the first lower device from this one, and determine what disk it belongs to.
         let active_partition = root_fs
             .lower_devices()
@@ -137,7 +140,7 @@ impl State {
         })
     }
 
-    pub(crate) fn os_disk(&self) -> &Path {
+    pub fn os_disk(&self) -> &Path {
         &self.os_disk
     }
 
@@ -166,6 +169,16 @@ impl State {
         &self.sets[self.inactive().idx()]
     }
 
+    /// Returns the priority flags of the active partition set.
+    pub fn active_flags(&self) -> GptPrio {
+        self.gptprio(self.active())
+    }
+
+    /// Returns the priority flags of the inactive partition set.
+    pub fn inactive_flags(&self) -> GptPrio {
+        self.gptprio(self.inactive())
+    }
+
     pub(crate) fn next(&self) -> Option<SetSelect> {
         let gptprio_a = self.gptprio(SetSelect::A);
         let gptprio_b = self.gptprio(SetSelect::B);
@@ -277,6 +290,24 @@ impl State {
         Ok(())
     }
 
+    /// Prioritizes the inactive partition even if it isn't bootable, but **does not write to the
+    /// disk**.
+    ///
+    /// If the inactive partition hasn't booted successfully, it's given one try, so if it fails to
+    /// boot, GRUB falls back to the active partition.
+    pub fn force_rollback_to_inactive(&mut self) {
+        let mut inactive_flags = self.gptprio(self.inactive());
+        inactive_flags.set_priority(2);
+        if !inactive_flags.successful() {
+            inactive_flags.set_tries_left(1);
+        }
+        self.set_gptprio(self.inactive(), inactive_flags);
+
+        let mut active_flags = self.gptprio(self.active());
+        active_flags.set_priority(1);
+        self.set_gptprio(self.active(), active_flags);
+    }
+
     /// Writes the partition table to the OS disk.
     pub fn write(&mut self) -> Result<(), Error> {
         self.table
@@ -6,7 +6,6 @@ mod se;
 
 use chrono::{DateTime, Duration, Utc};
 use migrator::MIGRATION_FILENAME_RE;
-use rand::{thread_rng, Rng};
 use semver::Version;
 use serde::{Deserialize, Serialize};
 use snafu::{ensure, OptionExt, ResultExt};
@@ -57,6 +56,10 @@ pub struct Images {
     pub boot: String,
     pub root: String,
     pub hash: String,
+    /// The compression format of the image targets, like "lz4" or "zstd".  Manifests from before
+    /// it was added leave it out, in which case it's taken from the target names, or is lz4.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -68,6 +71,11 @@ pub struct Update {
     #[serde(deserialize_with = "de::deserialize_bound")]
     pub waves: BTreeMap<u32, DateTime<Utc>>,
     pub images: Images,
+    /// When the update was released, which is when hosts in the first wave start becoming
+    /// eligible.  Manifests from before it was added leave it out, in which case hosts in the first
+    /// wave are eligible right away.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub released: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -150,6 +158,7 @@ impl Manifest {
         arch: String,
         variant: String,
         images: Images,
+        released: DateTime<Utc>,
     ) -> Result<()> {
         let max_version = if let Some(version) = max_version {
             version
@@ -168,6 +177,7 @@ impl Manifest {
             max_version: max_version.clone(),
             images,
             waves: BTreeMap::new(),
+            released: Some(released),
         };
         self.update_max_version(
             &update.max_version,
@@ -302,23 +312,48 @@ impl Update {
         true
     }
 
-    pub fn jitter(&self, seed: u32) -> Option<DateTime<Utc>> {
-        if let Some(wave) = self.update_wave(seed) {
-            if wave.has_passed() {
-                return None;
+    /// Returns when a host with the given seed becomes eligible for the update.  In a bounded
+    /// wave, that's as far into the wave as the seed is between the wave's bounds, so hosts are
+    /// spread evenly across the wave, and a host always gets the same time for the same seed.  The
+    /// first wave is bounded by the update's release, and hosts in it are spread the same way.  In
+    /// the last wave, it's the start of the wave.  Hosts in the first wave of an update without a
+    /// release time, or updates without waves, are eligible right away, so this returns None.
+    pub fn eligible_at(&self, seed: u32) -> Option<DateTime<Utc>> {
+        let start = self.waves.range((Included(0), Excluded(seed))).last();
+        let end = self
+            .waves
+            .range((Included(seed), Included(MAX_SEED)))
+            .next();
+
+        match (start, end) {
+            (Some((start_bound, start)), Some((end_bound, end))) => {
+                // start_bound < seed <= end_bound, so this can't divide by zero.
+                let offset = (end.timestamp() - start.timestamp()) * i64::from(seed - start_bound)
+                    / i64::from(end_bound - start_bound);
+                Some(*start + Duration::seconds(offset))
             }
-            let bounds = match self.update_wave(seed) {
-                Some(Wave::Initial { end }) => Some((Utc::now(), end)),
-                Some(Wave::General { start, end }) => Some((start, end)),
-                Some(Wave::Last { start: _ }) | None => None,
-            };
-            if let Some((start, end)) = bounds {
-                let mut rng = thread_rng();
-                if let Some(range) = end.timestamp().checked_sub(start.timestamp()) {
-                    return Some(start + Duration::seconds(rng.gen_range(1, range)));
+            (None, Some((end_bound, end))) => {
+                let released = self.released?;
+                if *end_bound == 0 {
+                    return Some(released);
                 }
+                // seed <= end_bound, so the first wave runs from release at seed 0 to end.
+                let offset = (end.timestamp() - released.timestamp()) * i64::from(seed)
+                    / i64::from(*end_bound);
+                Some(released + Duration::seconds(offset))
             }
+            (Some((_, start)), None) => Some(*start),
+            _ => None,
+        }
+    }
+
+    /// Returns when a host with the given seed becomes eligible for the update, if it's in a
+    /// bounded wave, including the first wave of a released update, and that time is still to
+    /// come.
+    pub fn jitter(&self, seed: u32) -> Option<DateTime<Utc>> {
+        match self.update_wave(seed) {
+            Some(Wave::Last { .. }) | None => None,
+            Some(_) => self.eligible_at(seed).filter(|time| *time > Utc::now()),
         }
-        None
     }
 }
@@ -55,6 +55,16 @@ struct AddUpdateArgs {
     // verity "hash" image target name
     #[structopt(short = "h", long = "hash")]
     hash: String,
+
+    // compression format of the image targets, eg. 'zstd'; if not given, updog uses the
+    // target name's extension, or lz4
+    #[structopt(long = "compression")]
+    compression: Option<String>,
+
+    // time the update is released, when hosts in its first wave start becoming eligible;
+    // defaults to now
+    #[structopt(long = "released")]
+    released: Option<DateTime<Utc>>,
 }
 
 impl AddUpdateArgs {
@@ -73,7 +83,9 @@ impl AddUpdateArgs {
                 root: self.root,
                 boot: self.boot,
                 hash: self.hash,
+                compression: self.compression,
             },
+            self.released.unwrap_or_else(Utc::now),
         )?;
         update_metadata::write_file(&self.file, &manifest)?;
         Ok(())
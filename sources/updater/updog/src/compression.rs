@@ -0,0 +1,124 @@
+use crate::error::{self, Result};
+use snafu::ResultExt;
+use std::io::Read;
+use std::path::Path;
+
+/// The compression formats updog can decode, by the names used in the manifest
+const SUPPORTED: &str = "lz4, zstd";
+
+/// How a target is compressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    /// Works out how the target is compressed: from the format named in the manifest, if any,
+    /// otherwise from the target's extension, otherwise lz4, which every target used before other
+    /// formats were supported.
+    pub fn for_target(target: &str, declared: Option<&str>) -> Result<Self> {
+        match declared {
+            Some("lz4") => Ok(Compression::Lz4),
+            Some("zstd") => Ok(Compression::Zstd),
+            Some(format) => error::UnsupportedCompression {
+                target,
+                format,
+                supported: SUPPORTED,
+            }
+            .fail(),
+            None => Ok(Self::from_extension(target).unwrap_or(Compression::Lz4)),
+        }
+    }
+
+    /// Returns the format named by the target's extension, if it names one.
+    pub fn from_extension(target: &str) -> Option<Self> {
+        match Path::new(target).extension()?.to_str()? {
+            "lz4" => Some(Compression::Lz4),
+            "zst" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Wraps the reader of a target in a decoder for this format.
+    pub fn decoder<'a, R: Read + 'a>(self, reader: R, target: &str) -> Result<Box<dyn Read + 'a>> {
+        let context = error::Decompress {
+            target,
+            format: self.name(),
+        };
+        let decoder: Box<dyn Read + 'a> = match self {
+            Compression::Lz4 => Box::new(lz4::Decoder::new(reader).context(context)?),
+            Compression::Zstd => Box::new(zstd::Decoder::new(reader).context(context)?),
+        };
+        Ok(decoder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn decode(target: &str, declared: Option<&str>) -> String {
+        let compression = Compression::for_target(target, declared).unwrap();
+        let file = File::open(format!("tests/data/{}", target)).unwrap();
+        let mut decoded = String::new();
+        compression
+            .decoder(file, target)
+            .unwrap()
+            .read_to_string(&mut decoded)
+            .unwrap();
+        decoded
+    }
+
+    #[test]
+    fn formats_decoded() {
+        let expected = "Bottlerocket update target fixture\n".repeat(20);
+        assert_eq!(decode("target.lz4", None), expected);
+        assert_eq!(decode("target.zst", None), expected);
+        // The manifest's format is used over the extension.
+        assert_eq!(decode("target.zst", Some("zstd")), expected);
+    }
+
+    #[test]
+    fn format_detected() {
+        assert_eq!(
+            Compression::for_target("root.ext4.zst", None).unwrap(),
+            Compression::Zstd
+        );
+        assert_eq!(
+            Compression::for_target("root.ext4.zst", Some("lz4")).unwrap(),
+            Compression::Lz4
+        );
+        // Targets named without an extension were always lz4.
+        assert_eq!(
+            Compression::for_target("root.ext4", None).unwrap(),
+            Compression::Lz4
+        );
+    }
+
+    #[test]
+    fn unknown_format() {
+        match Compression::for_target("root.ext4.xz", Some("xz")) {
+            Err(error::Error::UnsupportedCompression { target, format, .. }) => {
+                assert_eq!(target, "root.ext4.xz");
+                assert_eq!(format, "xz");
+            }
+            other => panic!("Expected UnsupportedCompression, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn corrupt_target() {
+        let corrupt: &[u8] = b"not compressed";
+        let mut reader = Compression::Zstd.decoder(corrupt, "target.zst").unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+}
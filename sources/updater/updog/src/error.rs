@@ -4,6 +4,7 @@ use semver::Version;
 use snafu::{Backtrace, Snafu};
 use std::path::PathBuf;
 use update_metadata::error::Error as update_metadata_error;
+use url::Url;
 
 pub(crate) type Result<T> = std::result::Result<T, Error>;
 
@@ -37,6 +38,28 @@ pub(crate) enum Error {
         backtrace: Backtrace,
     },
 
+    #[snafu(display("Failed to read data store link {}: {}", link.display(), source))]
+    DatastoreLinkRead {
+        link: PathBuf,
+        source: std::io::Error,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Invalid data store version {}: {}", path.display(), source))]
+    DatastoreVersion {
+        path: PathBuf,
+        source: semver::SemVerError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Failed to decode {}-compressed target {}: {}", format, target, source))]
+    Decompress {
+        target: String,
+        format: &'static str,
+        source: std::io::Error,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Failed to create directory: {:?}", path))]
     DirCreate {
         backtrace: Backtrace,
@@ -44,9 +67,72 @@ pub(crate) enum Error {
         path: PathBuf,
     },
 
+    #[snafu(display(
+        "Refusing to downgrade from {} to {} without --allow-downgrade",
+        current,
+        target
+    ))]
+    DowngradeNotAllowed {
+        current: Version,
+        target: Version,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Download of {} ended after {} of {} bytes", url, bytes, total))]
+    DownloadIncomplete {
+        url: Url,
+        bytes: u64,
+        total: u64,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display(
+        "Download of {} asked for byte {} on, but the server sent a range starting at {:?}",
+        url,
+        offset,
+        start
+    ))]
+    DownloadRange {
+        url: Url,
+        offset: u64,
+        start: Option<u64>,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Download of {} interrupted after {} bytes: {}", url, bytes, source))]
+    DownloadInterrupted {
+        url: Url,
+        bytes: u64,
+        source: std::io::Error,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Failed to request {}: {}", url, source))]
+    DownloadRequest {
+        url: Url,
+        source: reqwest::Error,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Failed to download {}: {}", url, source))]
+    DownloadStatus {
+        url: Url,
+        source: reqwest::Error,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Failed to create HTTP client: {}", source))]
+    HttpClient {
+        source: reqwest::Error,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Logger setup error: {}", source))]
     Logger { source: simplelog::TermLogError },
 
+    #[snafu(display("Could not roll back to inactive partition: {}", source))]
+    InactivePartitionRollback { source: signpost::Error },
+
     #[snafu(display("Could not mark inactive partition for boot: {}", source))]
     InactivePartitionUpgrade { source: signpost::Error },
 
@@ -71,13 +157,6 @@ pub(crate) enum Error {
     #[snafu(display("Could not determine loop device path"))]
     LoopNameFailed { backtrace: Backtrace },
 
-    #[snafu(display("Failed to decode LZ4-compressed target {}: {}", target, source))]
-    Lz4Decode {
-        target: String,
-        source: std::io::Error,
-        backtrace: Backtrace,
-    },
-
     #[snafu(display("Failed to parse updates manifest: {}", source))]
     ManifestParse {
         source: serde_json::Error,
@@ -90,6 +169,13 @@ pub(crate) enum Error {
         backtrace: Backtrace,
     },
 
+    #[snafu(display("Failed to fetch metadata {}: {}", url, source))]
+    MetadataRequest {
+        url: Url,
+        source: reqwest::Error,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Failed to copy migration from image: {}", name))]
     MigrationCopyFailed {
         backtrace: Backtrace,
@@ -147,8 +233,34 @@ pub(crate) enum Error {
         backtrace: Backtrace,
     },
 
-    #[snafu(display("Failed to read OS disk partition table: {}", source))]
+    #[snafu(display("Failed to open partial download {}: {}", path.display(), source))]
+    PartialOpen {
+        path: PathBuf,
+        source: std::io::Error,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Failed to remove partial download {}: {}", path.display(), source))]
+    PartialRemove {
+        path: PathBuf,
+        source: std::io::Error,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Failed to write partial download {}: {}", path.display(), source))]
+    PartialWrite {
+        path: PathBuf,
+        source: std::io::Error,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display(
+        "Failed to read partition table of the OS disk under {}: {}",
+        device.display(),
+        source
+    ))]
     PartitionTableRead {
+        device: PathBuf,
         // signpost::Error triggers clippy::large_enum_variant
         #[snafu(source(from(signpost::Error, Box::new)))]
         source: Box<signpost::Error>,
@@ -181,6 +293,27 @@ pub(crate) enum Error {
         source: bottlerocket_release::Error,
     },
 
+    #[snafu(display("Gave up on {} after {} attempts: {}", url, attempts, source))]
+    RetriesExhausted {
+        url: Url,
+        attempts: u32,
+        // Boxed because the last failure is one of our own errors
+        #[snafu(source(from(Error, Box::new)))]
+        source: Box<Error>,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display(
+        "Inactive partition set has not booted successfully (priority={} tries_left={}), so it may not hold a working image; use --force to roll back anyway",
+        priority,
+        tries_left
+    ))]
+    RollbackInvalid {
+        priority: u64,
+        tries_left: u64,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Failed setting permissions of '{}': {}", path.display(), source))]
     SetPermissions {
         path: PathBuf,
@@ -193,6 +326,13 @@ pub(crate) enum Error {
         backtrace: Backtrace,
     },
 
+    #[snafu(display("Target {} doesn't match its metadata: {}", target, source))]
+    TargetVerify {
+        target: String,
+        source: std::io::Error,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Failed to create tmpfile for root mount"))]
     TmpFileCreate {
         backtrace: Backtrace,
@@ -205,15 +345,6 @@ pub(crate) enum Error {
         source: std::cell::BorrowMutError,
     },
 
-    #[snafu(display("No update available"))]
-    UpdateNotAvailable { backtrace: Backtrace },
-
-    #[snafu(display("Update {} exists but wave in the future", version))]
-    UpdateNotReady {
-        backtrace: Backtrace,
-        version: Version,
-    },
-
     #[snafu(display("Failed to serialize update information: {}", source))]
     UpdateSerialize {
         source: serde_json::Error,
@@ -229,6 +360,46 @@ pub(crate) enum Error {
         backtrace: Backtrace,
     },
 
+    #[snafu(display(
+        "Target {} is compressed with unsupported format '{}'; updog supports {}",
+        target,
+        format,
+        supported
+    ))]
+    UnsupportedCompression {
+        target: String,
+        format: String,
+        supported: &'static str,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Failed to drop cached data for partition {}: {}", path.display(), source))]
+    VerifyDropCache {
+        path: PathBuf,
+        source: nix::Error,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display(
+        "Partition {} doesn't match the image written to it: expected SHA-256 {}, read back {}",
+        partition.display(),
+        expected,
+        actual
+    ))]
+    VerifyFailed {
+        partition: PathBuf,
+        expected: String,
+        actual: String,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Failed to read back partition {}: {}", path.display(), source))]
+    VerifyRead {
+        path: PathBuf,
+        source: std::io::Error,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("--start-time <time> required to add wave to update"))]
     WaveStartArg { backtrace: Backtrace },
 
@@ -1,11 +1,24 @@
 #![deny(rust_2018_idioms)]
 #![warn(clippy::pedantic)]
 
+#[macro_use]
+extern crate log;
+
+mod compression;
 mod error;
+mod partition;
+mod retry;
+mod rollback;
+mod status;
+mod throttle;
 mod transport;
 
-use crate::error::Result;
-use crate::transport::{HttpQueryRepo, HttpQueryTransport};
+use crate::compression::Compression;
+use crate::error::{Error, Result};
+use crate::partition::Written;
+use crate::retry::RetryPolicy;
+use crate::status::HostStatus;
+use crate::transport::{HttpQueryRepo, HttpQueryTransport, TargetDownloads};
 use bottlerocket_release::BottlerocketRelease;
 use chrono::{DateTime, Utc};
 use semver::Version;
@@ -13,14 +26,14 @@ use serde::{Deserialize, Serialize};
 use signpost::State;
 use simplelog::{Config as LogConfig, LevelFilter, TermLogger, TerminalMode};
 use snafu::{ensure, ErrorCompat, OptionExt, ResultExt};
-use std::fs::{self, File, OpenOptions, Permissions};
+use std::fs::{self, File, Permissions};
 use std::io;
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::process;
 use std::str::FromStr;
 use tough::{Limits, Repository, Settings};
-use update_metadata::{Manifest, Update};
+use update_metadata::{Manifest, Update, Wave, MAX_SEED};
 
 #[cfg(target_arch = "x86_64")]
 const TARGET_ARCH: &str = "x86_64";
@@ -28,8 +41,19 @@ const TARGET_ARCH: &str = "x86_64";
 const TARGET_ARCH: &str = "aarch64";
 
 const TRUSTED_ROOT_PATH: &str = "/usr/share/updog/root.json";
+const METADATA_CACHE_PATH: &str = "/var/lib/bottlerocket/updog";
 const MIGRATION_PATH: &str = "/var/lib/bottlerocket-migrations";
 
+// Exit codes for check-update, so scripts can tell whether there's an update to apply.  Errors
+// exit with 1, like every other subcommand.
+const EXIT_UPDATE_AVAILABLE: i32 = 0;
+const EXIT_UP_TO_DATE: i32 = 2;
+const EXIT_UPDATE_WAITING: i32 = 3;
+
+// Any subcommand exits with this if a request to the update repository kept failing in a way that
+// might be temporary, rather than 1, so callers can tell it's worth trying again later.
+const EXIT_RETRIES_EXHAUSTED: i32 = 4;
+
 #[derive(Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 enum Command {
@@ -39,6 +63,8 @@ enum Command {
     Update,
     UpdateImage,
     UpdateApply,
+    Status,
+    Rollback,
 }
 
 #[derive(Debug, Deserialize)]
@@ -46,6 +72,11 @@ struct Config {
     metadata_base_url: String,
     targets_base_url: String,
     seed: u32,
+    /// How requests to the update repository are retried
+    #[serde(flatten)]
+    retry: RetryPolicy,
+    /// The most bytes per second to download images and migrations at, if they're limited
+    max_download_rate: Option<u64>,
     // TODO API sourced configuration, eg.
     // blacklist: Option<Vec<Version>>,
     // mode: Option<{Automatic, Managed, Disabled}>
@@ -64,7 +95,9 @@ USAGE:
     updog <SUBCOMMAND> <OPTIONS>
 
 SUBCOMMANDS:
-    check-update            Show if an update is available
+    check-update            Show if an update is available, without downloading it;
+                            exits 0 if one is, 2 if already up to date, and 3 if
+                            one is waiting for this host's wave
         [ -a | --all ]                Output all applicable updates
         [ --ignore-waves ]            Ignore release schedule when checking
                                       for a new update
@@ -72,21 +105,47 @@ SUBCOMMANDS:
     prepare                 Download update files and migration targets
 
     update                  Perform an update if available
-        [ -i | --image version ]      Update to a specfic image version
-        [ -n | --now ]                Update immediately, ignoring any release schedule
+        [ -i | --image | --version version ]
+                                      Update to a specific image version, ignoring any
+                                      release schedule
+        [ --allow-downgrade ]         Allow the requested version to be older than the
+                                      running version
+        [ -n | --now | --ignore-waves ]
+                                      Update immediately, ignoring any release schedule
         [ -r | --reboot ]             Reboot into new update on success
         [ -t | --timestamp time ]     The timestamp from which to execute an update
+        [ --progress ]                Show a progress bar while downloading
+        [ --max-download-rate bytes ] Download no faster than this many bytes per second
+        [ --skip-verify ]             Don't read back written images to verify them
 
     update-image            Download & write an update but do not update flags
-        [ -i | --image version ]      Update to a specfic image version
-        [ -n | --now ]                Update immediately, ignoring wave limits
+        [ -i | --image | --version version ]
+                                      Update to a specific image version, ignoring any
+                                      release schedule
+        [ --allow-downgrade ]         Allow the requested version to be older than the
+                                      running version
+        [ -n | --now | --ignore-waves ]
+                                      Update immediately, ignoring wave limits
         [ -t | --timestamp time ]     The timestamp to execute an update from
+        [ --progress ]                Show a progress bar while downloading
+        [ --max-download-rate bytes ] Download no faster than this many bytes per second
+        [ --skip-verify ]             Don't read back written images to verify them
 
     update-apply            Update boot flags (after having called update-image)
         [ -r | --reboot ]             Reboot after updating boot flags
 
+    status                  Show the running version, the partition sets, and
+                            whether an update is waiting to be booted
+
+    rollback                Boot the previous version from the inactive partition set
+                            on the next reboot, or cancel an update that's waiting there
+        [ --force ]                   Roll back even if the inactive set has never
+                                      booted successfully
+
 GLOBAL OPTIONS:
     [ -j | --json ]               JSON-formatted output
+    [ --wave-seed seed ]          Pick this host's wave with the given seed, from 0 to
+                                  2047, instead of the one in the config file
     [ --log-level trace|debug|info|warn|error ]  Set logging verbosity");
     std::process::exit(1)
 }
@@ -102,14 +161,14 @@ fn load_repository<'a>(
     transport: &'a HttpQueryTransport,
     config: &'a Config,
 ) -> Result<HttpQueryRepo<'a>> {
-    fs::create_dir_all("/var/lib/bottlerocket/updog").context(error::CreateMetadataCache)?;
+    fs::create_dir_all(METADATA_CACHE_PATH).context(error::CreateMetadataCache)?;
     Repository::load(
         transport,
         Settings {
             root: File::open(TRUSTED_ROOT_PATH).context(error::OpenRoot {
                 path: TRUSTED_ROOT_PATH,
             })?,
-            datastore: Path::new("/var/lib/bottlerocket/updog"),
+            datastore: Path::new(METADATA_CACHE_PATH),
             metadata_base_url: &config.metadata_base_url,
             target_base_url: &config.targets_base_url,
             limits: Limits {
@@ -179,27 +238,88 @@ fn update_required<'a>(
     None
 }
 
+/// Finds the update to the version requested with --version, which must be in the manifest for
+/// this variant and arch.  Moving to an older version is refused unless `allow_downgrade` is set,
+/// and then only if the data store can be migrated back to it.
+fn requested_update<'a>(
+    manifest: &'a Manifest,
+    current_version: &Version,
+    variant: &str,
+    version: &Version,
+    allow_downgrade: bool,
+) -> Result<&'a Update> {
+    let update = applicable_updates(manifest, variant)
+        .into_iter()
+        .find(|u| u.version == *version)
+        .context(error::MissingVersion {
+            version: version.to_string(),
+        })?;
+
+    if update.version < *current_version {
+        ensure!(
+            allow_downgrade,
+            error::DowngradeNotAllowed {
+                current: current_version.clone(),
+                target: update.version.clone(),
+            }
+        );
+        // Migrator runs the migrations between the versions backward to downgrade the data store,
+        // so they must all be there, or the data store would be left newer than the OS.
+        if let Err(Error::MissingMigration { .. }) =
+            migration_targets(&update.version, current_version, manifest)
+        {
+            return error::MigrationNotPresent {
+                from: current_version.clone(),
+                to: update.version.clone(),
+            }
+            .fail();
+        }
+    }
+    Ok(update)
+}
+
+/// Downloads the named target, continuing any earlier partial download, and checks it against the
+/// TUF metadata before it's used.  A download that doesn't match is discarded, so the next try
+/// starts over.
+fn download_target(
+    repository: &HttpQueryRepo<'_>,
+    transport: &HttpQueryTransport,
+    target: &str,
+) -> Result<()> {
+    let mut reader = repository
+        .read_target(target)
+        .context(error::Metadata)?
+        .context(error::TargetNotFound { target })?;
+    if let Err(e) = io::copy(&mut reader, &mut io::sink()) {
+        transport.remove_partial(target)?;
+        return Err(e).context(error::TargetVerify { target });
+    }
+    Ok(())
+}
+
+/// Writes the decompressed target to the given path, returning the length and SHA-256 of what was
+/// written.  The target is checked against its TUF metadata as it's read, so a target that doesn't
+/// match fails with WriteUpdate, and what was written matches the verified target.
 fn write_target_to_disk<P: AsRef<Path>>(
     repository: &HttpQueryRepo<'_>,
+    transport: &HttpQueryTransport,
     target: &str,
+    compression: Compression,
     disk_path: P,
-) -> Result<()> {
+) -> Result<Written> {
+    download_target(repository, transport, target)?;
+    // The download is finished, so this reads it from the partial file, without another request,
+    // checking it again as it's written.
     let reader = repository
         .read_target(target)
         .context(error::Metadata)?
         .context(error::TargetNotFound { target })?;
     // Note: the file extension for the compression type we're using should be removed in
     // retrieve_migrations below.
-    let mut reader = lz4::Decoder::new(reader).context(error::Lz4Decode { target })?;
-    let mut f = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(disk_path.as_ref())
-        .context(error::OpenPartition {
-            path: disk_path.as_ref(),
-        })?;
-    io::copy(&mut reader, &mut f).context(error::WriteUpdate)?;
-    Ok(())
+    let mut reader = compression.decoder(reader, target)?;
+    let written = partition::write_image(&mut reader, disk_path.as_ref())?;
+    transport.remove_partial(target)?;
+    Ok(written)
 }
 
 fn migration_targets(from: &Version, to: &Version, manifest: &Manifest) -> Result<Vec<String>> {
@@ -234,6 +354,21 @@ fn migration_targets(from: &Version, to: &Version, manifest: &Manifest) -> Resul
     Ok(targets)
 }
 
+/// The data store version an update migrates to.  Data store versions follow the OS version, and
+/// migrations are keyed by OS version, so this is the update's version, as long as the manifest's
+/// migrations lead to it from the current version, or back from it, for a downgrade.
+fn datastore_version<'a>(
+    current: &Version,
+    update: &'a Version,
+    manifest: &Manifest,
+) -> Option<&'a Version> {
+    let start = std::cmp::min(current, update);
+    let target = std::cmp::max(current, update);
+    migration_targets(start, target, manifest)
+        .ok()
+        .map(|_| update)
+}
+
 /// Store required migrations for an update in persistent storage. All intermediate migrations
 /// between the current version and the target version must be retrieved.
 fn retrieve_migrations(
@@ -261,10 +396,11 @@ fn retrieve_migrations(
     targets.sort();
     for name in &targets {
         let mut destination = dir.join(&name);
-        if destination.extension() == Some("lz4".as_ref()) {
+        if Compression::from_extension(name).is_some() {
             destination.set_extension("");
         }
-        write_target_to_disk(repository, &name, &destination)?;
+        let compression = Compression::for_target(name, None)?;
+        write_target_to_disk(repository, transport, &name, compression, &destination)?;
         fs::set_permissions(&destination, Permissions::from_mode(0o755))
             .context(error::SetPermissions { path: destination })?;
     }
@@ -278,8 +414,18 @@ fn retrieve_migrations(
     Ok(())
 }
 
-fn update_image(update: &Update, repository: &HttpQueryRepo<'_>) -> Result<()> {
-    let mut gpt_state = State::load().context(error::PartitionTableRead)?;
+/// Writes the update's images to the inactive partition set and marks it valid.  With `verify`,
+/// each partition is read back first, and the set isn't marked valid unless it holds exactly what
+/// was written.
+fn update_image(
+    update: &Update,
+    repository: &HttpQueryRepo<'_>,
+    transport: &HttpQueryTransport,
+    verify: bool,
+) -> Result<()> {
+    let mut gpt_state = State::load().context(error::PartitionTableRead {
+        device: signpost::ROOT_MOUNT,
+    })?;
     gpt_state.clear_inactive();
     // Write out the clearing of the inactive partition immediately, because we're about to
     // overwrite the partition set with update data and don't want it to be used until we
@@ -289,9 +435,21 @@ fn update_image(update: &Update, repository: &HttpQueryRepo<'_>) -> Result<()> {
     let inactive = gpt_state.inactive_set();
 
     // TODO Do we want to recover the inactive side on an error?
-    write_target_to_disk(repository, &update.images.root, &inactive.root)?;
-    write_target_to_disk(repository, &update.images.boot, &inactive.boot)?;
-    write_target_to_disk(repository, &update.images.hash, &inactive.hash)?;
+    let images = [
+        (&update.images.root, &inactive.root),
+        (&update.images.boot, &inactive.boot),
+        (&update.images.hash, &inactive.hash),
+    ];
+    if !verify {
+        warn!("Not reading back images written to the inactive partitions to verify them");
+    }
+    for (target, path) in &images {
+        let compression = Compression::for_target(target, update.images.compression.as_deref())?;
+        let written = write_target_to_disk(repository, transport, target, compression, path)?;
+        if verify {
+            partition::verify(path, &written)?;
+        }
+    }
 
     gpt_state.mark_inactive_valid();
     gpt_state.write().context(error::PartitionTableWrite)?;
@@ -299,7 +457,9 @@ fn update_image(update: &Update, repository: &HttpQueryRepo<'_>) -> Result<()> {
 }
 
 fn update_flags() -> Result<()> {
-    let mut gpt_state = State::load().context(error::PartitionTableRead)?;
+    let mut gpt_state = State::load().context(error::PartitionTableRead {
+        device: signpost::ROOT_MOUNT,
+    })?;
     gpt_state
         .upgrade_to_inactive()
         .context(error::InactivePartitionUpgrade)?;
@@ -307,6 +467,21 @@ fn update_flags() -> Result<()> {
     Ok(())
 }
 
+/// Reads the partition table and the running versions for the status subcommand.
+fn host_status() -> Result<HostStatus> {
+    let state = State::load().context(error::PartitionTableRead {
+        device: signpost::ROOT_MOUNT,
+    })?;
+    let (running_version, variant) = running_version()?;
+    let datastore_version = status::datastore_version(status::DATASTORE_PATH)?;
+    Ok(HostStatus::new(
+        &state,
+        running_version,
+        variant,
+        datastore_version,
+    ))
+}
+
 fn set_common_query_params(
     transport: &HttpQueryTransport,
     current_version: &Version,
@@ -338,6 +513,180 @@ fn list_updates(manifest: &Manifest, variant: &str, json: bool) -> Result<()> {
     Ok(())
 }
 
+/// Whether there's an update for this host.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum UpdateStatus {
+    /// There's an update, and this host's wave has started, or waves are being ignored
+    Available,
+    /// There's an update, but this host's wave hasn't started yet
+    Waiting,
+    /// There's no update for this host
+    UpToDate,
+}
+
+impl UpdateStatus {
+    fn exit_code(self) -> i32 {
+        match self {
+            Self::Available => EXIT_UPDATE_AVAILABLE,
+            Self::Waiting => EXIT_UPDATE_WAITING,
+            Self::UpToDate => EXIT_UP_TO_DATE,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::Available => "update available",
+            Self::Waiting => "update waiting for this host's wave",
+            Self::UpToDate => "up to date",
+        }
+    }
+}
+
+/// The times bounding this host's wave of an update; the first wave has no start, and the last
+/// wave has no end.
+#[derive(Debug, PartialEq, Serialize)]
+struct WaveWindow {
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+}
+
+impl From<Wave> for WaveWindow {
+    fn from(wave: Wave) -> Self {
+        match wave {
+            Wave::Initial { end } => Self {
+                start: None,
+                end: Some(end),
+            },
+            Wave::General { start, end } => Self {
+                start: Some(start),
+                end: Some(end),
+            },
+            Wave::Last { start } => Self {
+                start: Some(start),
+                end: None,
+            },
+        }
+    }
+}
+
+/// The update check-update found for this host.
+#[derive(Debug, Serialize)]
+struct Candidate<'a> {
+    version: &'a Version,
+    /// The data store is versioned along with the OS, so this is the version it's migrated to;
+    /// None if the manifest's migrations don't reach the update from the current version
+    datastore_version: Option<&'a Version>,
+    max_version: &'a Version,
+    /// This host's wave, if the update has waves
+    wave: Option<WaveWindow>,
+    /// Whether this host's wave has started, or the update has no waves
+    in_wave: bool,
+    /// When this host becomes eligible for the update, within its wave; None if it's eligible as
+    /// soon as the update is released
+    eligible_at: Option<DateTime<Utc>>,
+}
+
+/// What check-update found; this is what it prints with --json.
+#[derive(Debug, Serialize)]
+struct UpdateCheck<'a> {
+    status: UpdateStatus,
+    variant: &'a str,
+    arch: &'a str,
+    current_version: &'a Version,
+    /// The seed that picked this host's wave
+    seed: u32,
+    update: Option<Candidate<'a>>,
+}
+
+impl UpdateCheck<'_> {
+    /// Formats the check as a table for people.
+    fn table(&self) -> String {
+        let mut rows = vec![
+            ("Variant", self.variant.to_string()),
+            ("Arch", self.arch.to_string()),
+            ("Current version", self.current_version.to_string()),
+            ("Seed", self.seed.to_string()),
+        ];
+        match &self.update {
+            Some(update) => {
+                let wave = match &update.wave {
+                    Some(wave) => format!(
+                        "{} to {}",
+                        wave.start
+                            .map_or_else(|| "first wave".to_string(), |t| t.to_string()),
+                        wave.end
+                            .map_or_else(|| "last wave".to_string(), |t| t.to_string())
+                    ),
+                    None => "none".to_string(),
+                };
+                rows.push(("Update version", update.version.to_string()));
+                rows.push((
+                    "Datastore version",
+                    update
+                        .datastore_version
+                        .map_or_else(|| "missing migrations".to_string(), |v| v.to_string()),
+                ));
+                rows.push(("Wave", wave));
+                rows.push((
+                    "In wave",
+                    if update.in_wave { "yes" } else { "no" }.to_string(),
+                ));
+                rows.push((
+                    "Eligible at",
+                    update
+                        .eligible_at
+                        .map_or_else(|| "on release".to_string(), |t| t.to_string()),
+                ));
+            }
+            None => rows.push(("Update version", "none".to_string())),
+        }
+        rows.push(("Status", self.status.description().to_string()));
+
+        rows.iter()
+            .map(|(name, value)| format!("{:<19}{}", format!("{}:", name), value))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Looks for an update for this host, including whether its wave has started, without
+/// downloading or writing anything.  With `ignore_waves`, the wave is still reported, but an
+/// update is available even if the wave hasn't started.
+fn check_update<'a>(
+    config: &Config,
+    manifest: &'a Manifest,
+    current_version: &'a Version,
+    variant: &'a str,
+    force_version: Option<Version>,
+    ignore_waves: bool,
+) -> UpdateCheck<'a> {
+    let update =
+        update_required(config, manifest, current_version, variant, force_version).map(|update| {
+            Candidate {
+                version: &update.version,
+                datastore_version: datastore_version(current_version, &update.version, manifest),
+                max_version: &update.max_version,
+                wave: update.update_wave(config.seed).map(WaveWindow::from),
+                in_wave: update.update_ready(config.seed),
+                eligible_at: update.eligible_at(config.seed),
+            }
+        });
+    let status = match &update {
+        None => UpdateStatus::UpToDate,
+        Some(update) if update.in_wave || ignore_waves => UpdateStatus::Available,
+        Some(_) => UpdateStatus::Waiting,
+    };
+    UpdateCheck {
+        status,
+        variant,
+        arch: TARGET_ARCH,
+        current_version,
+        seed: config.seed,
+        update,
+    }
+}
+
 /// Struct to hold the specified command line argument values
 struct Arguments {
     subcommand: String,
@@ -348,6 +697,12 @@ struct Arguments {
     all: bool,
     reboot: bool,
     timestamp: Option<DateTime<Utc>>,
+    progress: bool,
+    max_download_rate: Option<u64>,
+    force: bool,
+    skip_verify: bool,
+    allow_downgrade: bool,
+    wave_seed: Option<u32>,
 }
 
 /// Parse the command line arguments to get the user-specified values
@@ -360,6 +715,12 @@ fn parse_args(args: std::env::Args) -> Arguments {
     let mut all = false;
     let mut reboot = false;
     let mut timestamp = None;
+    let mut progress = false;
+    let mut max_download_rate = None;
+    let mut force = false;
+    let mut allow_downgrade = false;
+    let mut wave_seed = None;
+    let mut skip_verify = false;
 
     let mut iter = args.skip(1);
     while let Some(arg) = iter.next() {
@@ -372,7 +733,7 @@ fn parse_args(args: std::env::Args) -> Arguments {
                     usage_msg(format!("Invalid log level '{}'", log_level_str))
                 }));
             }
-            "-i" | "--image" => match iter.next() {
+            "-i" | "--image" | "--version" => match iter.next() {
                 Some(v) => match Version::parse(&v) {
                     Ok(v) => update_version = Some(v),
                     _ => usage(),
@@ -398,6 +759,31 @@ fn parse_args(args: std::env::Args) -> Arguments {
             "-a" | "--all" => {
                 all = true;
             }
+            "--progress" => {
+                progress = true;
+            }
+            "--force" => {
+                force = true;
+            }
+            "--allow-downgrade" => {
+                allow_downgrade = true;
+            }
+            "--wave-seed" => match iter.next().map(|seed| seed.parse::<u32>()) {
+                Some(Ok(seed)) if seed < MAX_SEED => wave_seed = Some(seed),
+                _ => usage_msg(format!(
+                    "--wave-seed requires a seed from 0 to {}",
+                    MAX_SEED - 1
+                )),
+            },
+            "--skip-verify" => {
+                skip_verify = true;
+            }
+            "--max-download-rate" => match iter.next().map(|rate| rate.parse::<u64>()) {
+                Some(Ok(rate)) if rate > 0 => max_download_rate = Some(rate),
+                _ => {
+                    usage_msg("--max-download-rate requires a positive number of bytes per second")
+                }
+            },
             // Assume any arguments not prefixed with '-' is a subcommand
             s if !s.starts_with('-') => {
                 if subcommand.is_some() {
@@ -418,6 +804,12 @@ fn parse_args(args: std::env::Args) -> Arguments {
         all,
         reboot,
         timestamp,
+        progress,
+        max_download_rate,
+        force,
+        skip_verify,
+        allow_downgrade,
+        wave_seed,
     }
 }
 
@@ -437,8 +829,9 @@ fn output<T: Serialize>(json: bool, object: T, string: &str) -> Result<()> {
     Ok(())
 }
 
+/// Runs the subcommand, returning the exit code for a successful run.
 #[allow(clippy::too_many_lines)]
-fn main_inner() -> Result<()> {
+fn main_inner() -> Result<i32> {
     // Parse and store the arguments passed to the program
     let arguments = parse_args(std::env::args());
 
@@ -453,9 +846,45 @@ fn main_inner() -> Result<()> {
     let command =
         serde_plain::from_str::<Command>(&arguments.subcommand).unwrap_or_else(|_| usage());
 
-    let config = load_config()?;
+    // Status and rollback only use local state, so they work without a config or a reachable
+    // repository.
+    match command {
+        Command::Status => {
+            let status = host_status()?;
+            output(arguments.json, &status, &status.table())?;
+            return Ok(0);
+        }
+        Command::Rollback => {
+            let mut gpt_state = State::load().context(error::PartitionTableRead {
+                device: signpost::ROOT_MOUNT,
+            })?;
+            let action = rollback::rollback(&mut gpt_state, arguments.force)?;
+            output(arguments.json, &action, action.description())?;
+            return Ok(0);
+        }
+        _ => {}
+    }
+
+    let mut config = load_config()?;
+    if let Some(seed) = arguments.wave_seed {
+        config.seed = seed;
+    }
     let (current_version, variant) = running_version()?;
-    let transport = HttpQueryTransport::new();
+    let transport = HttpQueryTransport::new(
+        TargetDownloads {
+            // Zero in the config file means no limit, like leaving it out.
+            max_rate: arguments
+                .max_download_rate
+                .or(config.max_download_rate)
+                .filter(|rate| *rate > 0),
+            ..TargetDownloads::new(
+                config.targets_base_url.as_str(),
+                METADATA_CACHE_PATH,
+                arguments.progress,
+            )
+        },
+        config.retry.clone(),
+    );
     set_common_query_params(&transport, &current_version, &config)?;
     let repository = load_repository(&transport, &config)?;
     let manifest = load_manifest(&repository)?;
@@ -463,40 +892,44 @@ fn main_inner() -> Result<()> {
     match command {
         Command::CheckUpdate | Command::Whats => {
             if arguments.all {
-                return list_updates(&manifest, &variant, arguments.json);
+                list_updates(&manifest, &variant, arguments.json)?;
+                return Ok(0);
             }
 
-            let update = update_required(
+            let check = check_update(
                 &config,
                 &manifest,
                 &current_version,
                 &variant,
                 arguments.force_version,
-            )
-            .context(error::UpdateNotAvailable)?;
-
-            if !arguments.ignore_waves {
-                ensure!(
-                    update.update_ready(config.seed),
-                    error::UpdateNotReady {
-                        version: update.version.clone()
-                    }
-                );
-            }
-            output(arguments.json, &update, &fmt_full_version(&update))?;
+                arguments.ignore_waves,
+            );
+            output(arguments.json, &check, &check.table())?;
+            return Ok(check.status.exit_code());
         }
         Command::Update | Command::UpdateImage => {
-            if let Some(u) = update_required(
-                &config,
-                &manifest,
-                &current_version,
-                &variant,
-                arguments.force_version,
-            ) {
-                if u.update_ready(config.seed) || arguments.ignore_waves {
+            // A requested version is updated to right away, regardless of its waves.
+            let (update, ignore_waves) = match &arguments.force_version {
+                Some(version) => (
+                    Some(requested_update(
+                        &manifest,
+                        &current_version,
+                        &variant,
+                        version,
+                        arguments.allow_downgrade,
+                    )?),
+                    true,
+                ),
+                None => (
+                    update_required(&config, &manifest, &current_version, &variant, None),
+                    arguments.ignore_waves,
+                ),
+            };
+            if let Some(u) = update {
+                if u.update_ready(config.seed) || ignore_waves {
                     eprintln!("Starting update to {}", u.version);
 
-                    if arguments.ignore_waves {
+                    if ignore_waves {
                         eprintln!("** Updating immediately **");
                     } else {
                         let jitter = match arguments.timestamp {
@@ -508,7 +941,7 @@ fn main_inner() -> Result<()> {
                             if j > Utc::now() {
                                 // not yet!
                                 output(arguments.json, &j, &format!("{}", j))?;
-                                return Ok(());
+                                return Ok(0);
                             }
                         }
                     }
@@ -519,7 +952,7 @@ fn main_inner() -> Result<()> {
                         .push((String::from("target"), u.version.to_string()));
 
                     retrieve_migrations(&repository, &transport, &manifest, u)?;
-                    update_image(u, &repository)?;
+                    update_image(u, &repository, &transport, !arguments.skip_verify)?;
                     if command == Command::Update {
                         update_flags()?;
                         if arguments.reboot {
@@ -560,14 +993,29 @@ fn main_inner() -> Result<()> {
         Command::Prepare => {
             // TODO unimplemented
         }
+        // Handled above, before loading the config and repository.
+        Command::Status | Command::Rollback => {}
     }
 
-    Ok(())
+    Ok(0)
+}
+
+/// Whether the error, or any error that caused it, is RetriesExhausted.  Requests made by tough
+/// fail with its own errors, which wrap ours.
+fn retries_exhausted(err: &Error) -> bool {
+    let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(err);
+    while let Some(e) = cause {
+        if let Some(Error::RetriesExhausted { .. }) = e.downcast_ref::<Error>() {
+            return true;
+        }
+        cause = e.source();
+    }
+    false
 }
 
 fn main() -> ! {
     std::process::exit(match main_inner() {
-        Ok(()) => 0,
+        Ok(code) => code,
         Err(err) => {
             eprintln!("{}", err);
             if let Some(var) = std::env::var_os("RUST_BACKTRACE") {
@@ -577,7 +1025,11 @@ fn main() -> ! {
                     }
                 }
             }
-            1
+            if retries_exhausted(&err) {
+                EXIT_RETRIES_EXHAUSTED
+            } else {
+                1
+            }
         }
     })
 }
@@ -586,6 +1038,7 @@ fn main() -> ! {
 mod tests {
     use super::*;
     use chrono::Duration as TestDuration;
+    use serde_json::json;
     use std::collections::BTreeMap;
     use update_metadata::{Images, Wave};
 
@@ -614,6 +1067,29 @@ mod tests {
         assert!(migration[0] == "migrate_1.12.0_foo");
     }
 
+    #[test]
+    fn image_compression() {
+        // Manifests from before the compression field was added still parse, and their images
+        // are decoded as lz4.
+        let path = "tests/data/compression.json";
+        let manifest: Manifest = serde_json::from_reader(File::open(path).unwrap()).unwrap();
+        let zstd = &manifest.updates[0].images;
+        let lz4 = &manifest.updates[1].images;
+        assert_eq!(zstd.compression, Some("zstd".to_string()));
+        assert_eq!(lz4.compression, None);
+        assert_eq!(
+            Compression::for_target(&zstd.root, zstd.compression.as_deref()).unwrap(),
+            Compression::Zstd
+        );
+        assert_eq!(
+            Compression::for_target(&lz4.root, lz4.compression.as_deref()).unwrap(),
+            Compression::Lz4
+        );
+        assert!(!serde_json::to_string(&manifest.updates[1])
+            .unwrap()
+            .contains("compression"));
+    }
+
     #[test]
     fn test_serde_reader() {
         // A basic manifest with a single update, no migrations, and two
@@ -635,7 +1111,9 @@ mod tests {
                 boot: String::from("boot"),
                 root: String::from("root"),
                 hash: String::from("hash"),
+                compression: None,
             },
+            released: None,
         };
 
         let seed = 123;
@@ -676,7 +1154,9 @@ mod tests {
                 boot: String::from("boot"),
                 root: String::from("root"),
                 hash: String::from("hash"),
+                compression: None,
             },
+            released: None,
         };
         let seed = 1024;
 
@@ -703,6 +1183,8 @@ mod tests {
             metadata_base_url: String::from("foo"),
             targets_base_url: String::from("bar"),
             seed: 123,
+            retry: RetryPolicy::default(),
+            max_download_rate: None,
         };
         let version = Version::parse("1.18.0").unwrap();
         let variant = String::from("bottlerocket-aws-eks");
@@ -722,6 +1204,8 @@ mod tests {
             metadata_base_url: String::from("foo"),
             targets_base_url: String::from("bar"),
             seed: 1487,
+            retry: RetryPolicy::default(),
+            max_download_rate: None,
         };
 
         let version = Version::parse("0.1.3").unwrap();
@@ -747,6 +1231,8 @@ mod tests {
             metadata_base_url: String::from("foo"),
             targets_base_url: String::from("bar"),
             seed: 123,
+            retry: RetryPolicy::default(),
+            max_download_rate: None,
         };
 
         let version = Version::parse("1.10.0").unwrap();
@@ -776,6 +1262,8 @@ mod tests {
             metadata_base_url: String::from("foo"),
             targets_base_url: String::from("bar"),
             seed: 123,
+            retry: RetryPolicy::default(),
+            max_download_rate: None,
         };
 
         let version = Version::parse("1.10.0").unwrap();
@@ -828,6 +1316,70 @@ mod tests {
         assert!(i.next().unwrap() == "migration_1.5.0_shortcut");
     }
 
+    fn downgrade_manifest() -> Manifest {
+        let path = "tests/data/downgrade.json";
+        serde_json::from_reader(File::open(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn requested_version_selected() {
+        let manifest = downgrade_manifest();
+        let current = Version::parse("0.3.1").unwrap();
+        let requested = Version::parse("0.3.2").unwrap();
+        let update = requested_update(&manifest, &current, "aws-k8s", &requested, false).unwrap();
+        assert_eq!(update.version, requested);
+        // It's selected even though its waves haven't started; update skips them when a version
+        // is requested.
+        assert!(!update.update_ready(1500));
+    }
+
+    #[test]
+    fn requested_version_missing() {
+        let manifest = downgrade_manifest();
+        let current = Version::parse("0.3.1").unwrap();
+        // Not in the manifest at all, and only for another variant
+        for version in &["0.9.9", "0.3.5"] {
+            let requested = Version::parse(version).unwrap();
+            match requested_update(&manifest, &current, "aws-k8s", &requested, true) {
+                Err(Error::MissingVersion {
+                    version: missing, ..
+                }) => {
+                    assert_eq!(missing, *version)
+                }
+                other => panic!("Expected MissingVersion, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn downgrade_needs_flag() {
+        let manifest = downgrade_manifest();
+        let current = Version::parse("0.3.2").unwrap();
+        let requested = Version::parse("0.3.0").unwrap();
+        match requested_update(&manifest, &current, "aws-k8s", &requested, false) {
+            Err(Error::DowngradeNotAllowed { .. }) => {}
+            other => panic!("Expected DowngradeNotAllowed, got {:?}", other),
+        }
+
+        let update = requested_update(&manifest, &current, "aws-k8s", &requested, true).unwrap();
+        assert_eq!(update.version, requested);
+    }
+
+    #[test]
+    fn downgrade_needs_migrations() {
+        // There are no migrations between 0.3.2 and 0.4.0 to run backward.
+        let manifest = downgrade_manifest();
+        let current = Version::parse("0.4.0").unwrap();
+        let requested = Version::parse("0.3.1").unwrap();
+        match requested_update(&manifest, &current, "aws-k8s", &requested, true) {
+            Err(Error::MigrationNotPresent { from, to, .. }) => {
+                assert_eq!(from, current);
+                assert_eq!(to, requested);
+            }
+            other => panic!("Expected MigrationNotPresent, got {:?}", other),
+        }
+    }
+
     #[test]
     fn serialize_metadata() {
         // A basic manifest with a single update
@@ -850,7 +1402,9 @@ mod tests {
                 boot: String::from("boot"),
                 root: String::from("root"),
                 hash: String::from("hash"),
+                compression: None,
             },
+            released: None,
         };
 
         // | ---- (100, "now") ---
@@ -905,6 +1459,44 @@ mod tests {
         assert!(u.jitter(201).is_none(), "Expected immediate update");
     }
 
+    #[test]
+    fn initial_wave_spread() {
+        // The first wave runs from the update's release to its bound, and hosts in it are spread
+        // across it by seed, as in later waves.
+        let released = Utc::now() - TestDuration::minutes(30);
+        let end = released + TestDuration::hours(1);
+        let mut u = Update {
+            variant: String::from("bottlerocket"),
+            arch: String::from("test"),
+            version: Version::parse("1.0.0").unwrap(),
+            max_version: Version::parse("1.1.0").unwrap(),
+            waves: BTreeMap::new(),
+            images: Images {
+                boot: String::from("boot"),
+                root: String::from("root"),
+                hash: String::from("hash"),
+                compression: None,
+            },
+            released: Some(released),
+        };
+        u.waves.insert(1024, end);
+
+        assert_eq!(u.eligible_at(0), Some(released));
+        assert_eq!(
+            u.eligible_at(512),
+            Some(released + TestDuration::minutes(30))
+        );
+        assert_eq!(u.eligible_at(1024), Some(end));
+        // Hosts early in the wave are eligible already; later ones wait their turn.
+        assert!(u.jitter(256).is_none(), "Expected immediate update");
+        assert_eq!(u.jitter(768), Some(released + TestDuration::minutes(45)));
+
+        // Without a release time, the whole first wave is eligible right away.
+        u.released = None;
+        assert!(u.eligible_at(768).is_none());
+        assert!(u.jitter(768).is_none(), "Expected immediate update");
+    }
+
     #[test]
     /// Make sure that update_ready() doesn't return true unless the client's
     /// wave is also ready.
@@ -920,7 +1512,9 @@ mod tests {
                 boot: String::from("boot"),
                 root: String::from("boot"),
                 hash: String::from("boot"),
+                compression: None,
             },
+            released: None,
         };
 
         let current_version = Version::parse("1.0.0").unwrap();
@@ -929,6 +1523,8 @@ mod tests {
             metadata_base_url: String::from("foo"),
             targets_base_url: String::from("bar"),
             seed: 512,
+            retry: RetryPolicy::default(),
+            max_download_rate: None,
         };
 
         // Two waves; the 0th wave, and the final wave which starts in one hour
@@ -949,4 +1545,159 @@ mod tests {
             "Later wave incorrectly sees update"
         );
     }
+
+    #[test]
+    fn check_update_available() {
+        // Both waves of 0.1.2 have passed, and seed 1487 is between them
+        let path = "tests/data/example_3.json";
+        let manifest: Manifest = serde_json::from_reader(File::open(path).unwrap()).unwrap();
+        let config = Config {
+            metadata_base_url: String::from("foo"),
+            targets_base_url: String::from("bar"),
+            seed: 1487,
+            retry: RetryPolicy::default(),
+            max_download_rate: None,
+        };
+        let version = Version::parse("0.1.1").unwrap();
+        let check = check_update(&config, &manifest, &version, "aws-k8s-1.15", None, false);
+
+        assert_eq!(check.status, UpdateStatus::Available);
+        assert_eq!(check.status.exit_code(), 0);
+        let update = check.update.as_ref().unwrap();
+        assert_eq!(*update.version, Version::parse("0.1.2").unwrap());
+        assert_eq!(update.datastore_version, Some(update.version));
+        assert!(update.in_wave);
+
+        let json = serde_json::to_value(&check).unwrap();
+        assert_eq!(json["status"], "available");
+        assert_eq!(json["update"]["version"], "0.1.2");
+        assert_eq!(json["update"]["datastore_version"], "0.1.2");
+        assert_eq!(json["update"]["wave"]["start"], "2019-09-27T17:55:03Z");
+        assert_eq!(json["update"]["wave"]["end"], "2019-09-27T18:55:03Z");
+        assert_eq!(json["update"]["in_wave"], true);
+        // The seed is 1487/2047 of the way through the hour-long wave.
+        assert_eq!(json["update"]["eligible_at"], "2019-09-27T18:38:38Z");
+        assert_eq!(json["seed"], 1487);
+        assert!(check.table().contains("Update version:    0.1.2"));
+    }
+
+    #[test]
+    fn waves_deterministic() {
+        // Every seed maps to the same wave and time each time the manifest is read, and later
+        // seeds never become eligible before earlier ones.
+        let path = "tests/data/example_3.json";
+        let read = || -> Manifest { serde_json::from_reader(File::open(path).unwrap()).unwrap() };
+        let (first, second) = (read(), read());
+        let (first, second) = (&first.updates[1], &second.updates[1]);
+
+        let mut last = None;
+        for seed in 0..MAX_SEED {
+            assert_eq!(first.update_wave(seed), second.update_wave(seed));
+            let eligible = first.eligible_at(seed);
+            assert_eq!(eligible, second.eligible_at(seed));
+            assert!(
+                eligible >= last,
+                "seed {} eligible before seed {}",
+                seed,
+                seed - 1
+            );
+            last = eligible;
+        }
+    }
+
+    #[test]
+    fn check_update_missing_migrations() {
+        // There are no migrations from 0.1.0, so the data store can't be migrated to 0.1.2
+        let path = "tests/data/example_3.json";
+        let manifest: Manifest = serde_json::from_reader(File::open(path).unwrap()).unwrap();
+        let config = Config {
+            metadata_base_url: String::from("foo"),
+            targets_base_url: String::from("bar"),
+            seed: 1487,
+            retry: RetryPolicy::default(),
+            max_download_rate: None,
+        };
+        let version = Version::parse("0.1.0").unwrap();
+        let check = check_update(&config, &manifest, &version, "aws-k8s-1.15", None, false);
+
+        let update = check.update.as_ref().unwrap();
+        assert_eq!(*update.version, Version::parse("0.1.2").unwrap());
+        assert_eq!(update.datastore_version, None);
+        assert_eq!(
+            serde_json::to_value(&check).unwrap()["update"]["datastore_version"],
+            json!(null)
+        );
+        assert!(check
+            .table()
+            .contains("Datastore version: missing migrations"));
+    }
+
+    #[test]
+    fn check_update_up_to_date() {
+        let path = "tests/data/example_3.json";
+        let manifest: Manifest = serde_json::from_reader(File::open(path).unwrap()).unwrap();
+        let config = Config {
+            metadata_base_url: String::from("foo"),
+            targets_base_url: String::from("bar"),
+            seed: 1487,
+            retry: RetryPolicy::default(),
+            max_download_rate: None,
+        };
+        let version = Version::parse("0.1.2").unwrap();
+        let check = check_update(&config, &manifest, &version, "aws-k8s-1.15", None, false);
+
+        assert_eq!(check.status, UpdateStatus::UpToDate);
+        assert_eq!(check.status.exit_code(), 2);
+        assert!(check.update.is_none());
+        assert_eq!(serde_json::to_value(&check).unwrap()["update"], json!(null));
+    }
+
+    #[test]
+    fn check_update_waiting() {
+        // One wave, ending in an hour; seeds past it are in the last wave, which hasn't started
+        let mut manifest = Manifest::default();
+        let mut update = Update {
+            variant: String::from("aws-k8s-1.15"),
+            arch: String::from(TARGET_ARCH),
+            version: Version::parse("1.1.1").unwrap(),
+            max_version: Version::parse("1.1.1").unwrap(),
+            waves: BTreeMap::new(),
+            images: Images {
+                boot: String::from("boot"),
+                root: String::from("root"),
+                hash: String::from("hash"),
+                compression: None,
+            },
+            released: None,
+        };
+        let wave_end = Utc::now() + TestDuration::hours(1);
+        update.waves.insert(1024, wave_end);
+        manifest.updates.push(update);
+        let version = Version::parse("1.0.0").unwrap();
+        let config = Config {
+            metadata_base_url: String::from("foo"),
+            targets_base_url: String::from("bar"),
+            seed: 2000,
+            retry: RetryPolicy::default(),
+            max_download_rate: None,
+        };
+
+        let check = check_update(&config, &manifest, &version, "aws-k8s-1.15", None, false);
+        assert_eq!(check.status, UpdateStatus::Waiting);
+        assert_eq!(check.status.exit_code(), 3);
+        let update = check.update.unwrap();
+        assert!(!update.in_wave);
+        assert_eq!(
+            update.wave,
+            Some(WaveWindow {
+                start: Some(wave_end),
+                end: None
+            })
+        );
+
+        // Ignoring waves makes the update available, but still reports the wave
+        let check = check_update(&config, &manifest, &version, "aws-k8s-1.15", None, true);
+        assert_eq!(check.status, UpdateStatus::Available);
+        assert!(!check.update.unwrap().in_wave);
+    }
 }
@@ -0,0 +1,166 @@
+use crate::error::{self, Result};
+use nix::errno::Errno;
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+use ring::digest::{Context, SHA256};
+use snafu::{ensure, ResultExt};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// What was written to a partition, to check against what's read back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Written {
+    pub len: u64,
+    pub sha256: Vec<u8>,
+}
+
+/// Hashing passes writes through to the inner writer, hashing and counting what's written.
+struct Hashing<W> {
+    inner: W,
+    context: Context,
+    len: u64,
+}
+
+impl<W> Hashing<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            context: Context::new(&SHA256),
+            len: 0,
+        }
+    }
+
+    fn finish(self) -> (W, Written) {
+        let written = Written {
+            len: self.len,
+            sha256: self.context.finish().as_ref().to_vec(),
+        };
+        (self.inner, written)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.context.update(&buf[..count]);
+        self.len += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes an image to the start of a partition, returning the length and SHA-256 of what was
+/// written.  The data is synced to disk before returning, so write errors surface here.
+pub fn write_image<R: Read>(reader: &mut R, partition: &Path) -> Result<Written> {
+    let f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(partition)
+        .context(error::OpenPartition { path: partition })?;
+    let mut hashing = Hashing::new(f);
+    io::copy(reader, &mut hashing).context(error::WriteUpdate)?;
+    let (f, written) = hashing.finish();
+    f.sync_all().context(error::WriteUpdate)?;
+    Ok(written)
+}
+
+/// Reads back as much of the partition as was written and checks it has the same SHA-256, so a
+/// bad disk or short write is caught before the partition is marked bootable.
+///
+/// The partition's cached pages are dropped first, so we read what's on the disk rather than what
+/// we just wrote to the page cache.  write_image syncs before returning, so the pages are clean
+/// and can be dropped.
+pub fn verify(partition: &Path, written: &Written) -> Result<()> {
+    let f = File::open(partition).context(error::OpenPartition { path: partition })?;
+    drop_cache(&f).context(error::VerifyDropCache { path: partition })?;
+    let mut hashing = Hashing::new(io::sink());
+    io::copy(&mut f.take(written.len), &mut hashing)
+        .context(error::VerifyRead { path: partition })?;
+    let (_, read) = hashing.finish();
+    ensure!(
+        read.sha256 == written.sha256,
+        error::VerifyFailed {
+            partition,
+            expected: hex::encode(&written.sha256),
+            actual: hex::encode(&read.sha256),
+        }
+    );
+    debug!(
+        "Verified {} bytes written to {}",
+        read.len,
+        partition.display()
+    );
+    Ok(())
+}
+
+/// Asks the kernel to drop its cached pages for the whole file.
+fn drop_cache(f: &File) -> nix::Result<()> {
+    // posix_fadvise returns an error number rather than setting errno.
+    match posix_fadvise(f.as_raw_fd(), 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED)? {
+        0 => Ok(()),
+        code => Err(nix::Error::Sys(Errno::from_i32(code))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn image() -> Vec<u8> {
+        (0..=250).cycle().take(100_000).collect()
+    }
+
+    #[test]
+    fn written_image_verified() {
+        let partition = tempfile::NamedTempFile::new().unwrap();
+        // A partition is usually bigger than its image; only the image is read back.
+        fs::write(partition.path(), vec![0xff; 200_000]).unwrap();
+
+        let written = write_image(&mut &image()[..], partition.path()).unwrap();
+        assert_eq!(written.len, 100_000);
+        assert_eq!(
+            written.sha256,
+            ring::digest::digest(&SHA256, &image()).as_ref()
+        );
+        verify(partition.path(), &written).unwrap();
+    }
+
+    #[test]
+    fn corrupt_write_detected() {
+        let partition = tempfile::NamedTempFile::new().unwrap();
+        let written = write_image(&mut &image()[..], partition.path()).unwrap();
+
+        let mut contents = fs::read(partition.path()).unwrap();
+        contents[5_000] ^= 0xff;
+        fs::write(partition.path(), contents).unwrap();
+
+        match verify(partition.path(), &written) {
+            Err(error::Error::VerifyFailed {
+                expected, actual, ..
+            }) => {
+                assert_eq!(expected, hex::encode(&written.sha256));
+                assert_ne!(expected, actual);
+            }
+            other => panic!("Expected VerifyFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn short_write_detected() {
+        let partition = tempfile::NamedTempFile::new().unwrap();
+        let written = write_image(&mut &image()[..], partition.path()).unwrap();
+        partition.as_file().set_len(50_000).unwrap();
+
+        match verify(partition.path(), &written) {
+            Err(error::Error::VerifyFailed { partition: p, .. }) => {
+                assert_eq!(p, partition.path())
+            }
+            other => panic!("Expected VerifyFailed, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::error::{self, Error, Result};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use snafu::ResultExt;
+use std::thread;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// The longest we wait between two tries of a request, in milliseconds
+const MAX_BACKOFF_MS: u64 = 60_000;
+
+/// How requests to the update repository are retried.  Each setting can be given in updog's
+/// config file; any that aren't get the defaults below.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+#[allow(clippy::module_name_repetitions)]
+pub struct RetryPolicy {
+    /// How many times to try each request, including the first
+    pub fetch_attempts: u32,
+    /// How long to wait before the first retry; the wait doubles with each retry, up to a minute,
+    /// and is randomly shortened by up to half so that hosts don't retry in lockstep
+    pub fetch_backoff_ms: u64,
+    /// How long each request for metadata may take, or for targets, which take longer to
+    /// download, how long connecting may take
+    pub fetch_timeout_secs: u64,
+    /// How long after the first try to give up retrying a request
+    pub fetch_deadline_secs: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            fetch_attempts: 5,
+            fetch_backoff_ms: 1000,
+            fetch_timeout_secs: 30,
+            fetch_deadline_secs: 600,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.fetch_timeout_secs)
+    }
+
+    /// Calls `f`, which requests the given URL, until it succeeds, fails in a way that retrying
+    /// won't fix, or the policy runs out of attempts or time, in which case it fails with
+    /// RetriesExhausted, wrapping the last failure.
+    pub fn run<T, F>(&self, url: &Url, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let deadline = Instant::now() + Duration::from_secs(self.fetch_deadline_secs);
+        let mut attempt = 1;
+        loop {
+            let e = match f() {
+                Ok(value) => return Ok(value),
+                Err(e) if !transient(&e) => return Err(e),
+                Err(e) => e,
+            };
+
+            let delay = self.backoff(attempt);
+            if attempt >= self.fetch_attempts || Instant::now() + delay > deadline {
+                return Err(e).context(error::RetriesExhausted {
+                    url: url.clone(),
+                    attempts: attempt,
+                });
+            }
+            warn!(
+                "Attempt {} of {} for {} failed, retrying in {}ms: {}",
+                attempt,
+                self.fetch_attempts,
+                url,
+                delay.as_millis(),
+                e
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    /// Returns how long to wait after the given failed attempt.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let longest = self
+            .fetch_backoff_ms
+            .saturating_mul(2_u64.saturating_pow(attempt - 1))
+            .min(MAX_BACKOFF_MS);
+        let wait = if longest > 1 {
+            thread_rng().gen_range(longest / 2, longest + 1)
+        } else {
+            longest
+        };
+        Duration::from_millis(wait)
+    }
+}
+
+/// Whether a request that failed with the given error might work if it's tried again: the
+/// connection failed, dropped, or timed out, or the server had an error.
+fn transient(error: &Error) -> bool {
+    match error {
+        Error::MetadataRequest { source, .. }
+        | Error::DownloadRequest { source, .. }
+        | Error::DownloadStatus { source, .. } => match source.status() {
+            Some(status) => status.is_server_error(),
+            None => !(source.is_builder() || source.is_redirect()),
+        },
+        Error::DownloadInterrupted { .. }
+        | Error::DownloadIncomplete { .. }
+        | Error::DownloadRange { .. } => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_with_jitter() {
+        let policy = RetryPolicy {
+            fetch_backoff_ms: 100,
+            ..RetryPolicy::default()
+        };
+        for (attempt, longest) in &[(1, 100), (2, 200), (3, 400), (20, MAX_BACKOFF_MS)] {
+            let wait = policy.backoff(*attempt);
+            assert!(
+                wait >= Duration::from_millis(longest / 2)
+                    && wait <= Duration::from_millis(*longest),
+                "attempt {} waited {:?}",
+                attempt,
+                wait
+            );
+        }
+    }
+
+    #[test]
+    fn config_defaults() {
+        let policy: RetryPolicy = toml::from_str("fetch_attempts = 2").unwrap();
+        assert_eq!(policy.fetch_attempts, 2);
+        assert_eq!(
+            policy.fetch_deadline_secs,
+            RetryPolicy::default().fetch_deadline_secs
+        );
+    }
+}
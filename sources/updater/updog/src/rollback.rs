@@ -0,0 +1,205 @@
+use crate::error::{self, Result};
+use crate::status::Pending;
+use serde::Serialize;
+use signpost::{GptPrio, State};
+use snafu::{ensure, ResultExt};
+
+/// The partition table operations rollback needs, so it can be tested without a disk.
+pub trait PartitionTable {
+    fn active_flags(&self) -> GptPrio;
+    fn inactive_flags(&self) -> GptPrio;
+    fn cancel_upgrade(&mut self);
+    fn rollback_to_inactive(&mut self) -> Result<()>;
+    fn force_rollback_to_inactive(&mut self);
+    fn write(&mut self) -> Result<()>;
+}
+
+impl PartitionTable for State {
+    fn active_flags(&self) -> GptPrio {
+        State::active_flags(self)
+    }
+
+    fn inactive_flags(&self) -> GptPrio {
+        State::inactive_flags(self)
+    }
+
+    fn cancel_upgrade(&mut self) {
+        State::cancel_upgrade(self)
+    }
+
+    fn rollback_to_inactive(&mut self) -> Result<()> {
+        State::rollback_to_inactive(self).context(error::InactivePartitionRollback)
+    }
+
+    fn force_rollback_to_inactive(&mut self) {
+        State::force_rollback_to_inactive(self)
+    }
+
+    fn write(&mut self) -> Result<()> {
+        State::write(self).context(error::PartitionTableWrite)
+    }
+}
+
+/// What rollback did; this is what it prints with --json.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// An update was written or applied but not booted, so it was cleared and the running
+    /// version stays
+    CancelledUpdate,
+    /// The inactive set, which has booted successfully before, will be booted next
+    RolledBack,
+    /// The inactive set, which hasn't booted successfully, will be tried once next
+    Forced,
+}
+
+impl Action {
+    pub fn description(self) -> &'static str {
+        match self {
+            Action::CancelledUpdate => {
+                "Cancelled the update waiting on the inactive partition set; the running version will keep booting"
+            }
+            Action::RolledBack => {
+                "Rolled back to the inactive partition set; reboot to boot the previous version"
+            }
+            Action::Forced => {
+                "Rolled back to the inactive partition set without a prior successful boot; reboot to try it once"
+            }
+        }
+    }
+}
+
+/// Makes the inactive partition set the one to boot, unless an update is waiting there, in
+/// which case the update is cleared instead.  Fails if the inactive set has never booted
+/// successfully, unless `force` is given.  Writes the partition table on success.
+pub fn rollback(table: &mut dyn PartitionTable, force: bool) -> Result<Action> {
+    let inactive = table.inactive_flags();
+    let action = match Pending::from_flags(table.active_flags(), inactive) {
+        Pending::Written | Pending::Reboot => {
+            table.cancel_upgrade();
+            Action::CancelledUpdate
+        }
+        _ if inactive.successful() => {
+            table.rollback_to_inactive()?;
+            Action::RolledBack
+        }
+        _ => {
+            ensure!(
+                force,
+                error::RollbackInvalid {
+                    priority: inactive.priority(),
+                    tries_left: inactive.tries_left(),
+                }
+            );
+            table.force_rollback_to_inactive();
+            Action::Forced
+        }
+    };
+    table.write()?;
+    Ok(action)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(priority: u64, tries_left: u64, successful: bool) -> GptPrio {
+        GptPrio::from((priority << 48) | (tries_left << 52) | (u64::from(successful) << 56))
+    }
+
+    /// Records the calls made to it, rather than changing flags.
+    struct FakeTable {
+        active: GptPrio,
+        inactive: GptPrio,
+        calls: Vec<&'static str>,
+    }
+
+    impl FakeTable {
+        fn new(active: GptPrio, inactive: GptPrio) -> Self {
+            Self {
+                active,
+                inactive,
+                calls: Vec::new(),
+            }
+        }
+    }
+
+    impl PartitionTable for FakeTable {
+        fn active_flags(&self) -> GptPrio {
+            self.active
+        }
+
+        fn inactive_flags(&self) -> GptPrio {
+            self.inactive
+        }
+
+        fn cancel_upgrade(&mut self) {
+            self.calls.push("cancel_upgrade");
+        }
+
+        fn rollback_to_inactive(&mut self) -> Result<()> {
+            self.calls.push("rollback_to_inactive");
+            Ok(())
+        }
+
+        fn force_rollback_to_inactive(&mut self) {
+            self.calls.push("force_rollback_to_inactive");
+        }
+
+        fn write(&mut self) -> Result<()> {
+            self.calls.push("write");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn staged_update_cancelled() {
+        // Applied, waiting for a reboot
+        let mut table = FakeTable::new(flags(1, 0, true), flags(2, 1, false));
+        assert_eq!(
+            rollback(&mut table, false).unwrap(),
+            Action::CancelledUpdate
+        );
+        assert_eq!(table.calls, vec!["cancel_upgrade", "write"]);
+
+        // Written, but not applied
+        let mut table = FakeTable::new(flags(2, 0, true), flags(0, 1, false));
+        assert_eq!(
+            rollback(&mut table, false).unwrap(),
+            Action::CancelledUpdate
+        );
+        assert_eq!(table.calls, vec!["cancel_upgrade", "write"]);
+    }
+
+    #[test]
+    fn previous_version_booted() {
+        let mut table = FakeTable::new(flags(2, 0, true), flags(1, 0, true));
+        assert_eq!(rollback(&mut table, false).unwrap(), Action::RolledBack);
+        assert_eq!(table.calls, vec!["rollback_to_inactive", "write"]);
+    }
+
+    #[test]
+    fn never_booted_refused() {
+        let mut table = FakeTable::new(flags(2, 0, true), flags(0, 0, false));
+        match rollback(&mut table, false) {
+            Err(error::Error::RollbackInvalid { .. }) => {}
+            other => panic!("Expected RollbackInvalid, got {:?}", other),
+        }
+        assert!(table.calls.is_empty());
+    }
+
+    #[test]
+    fn never_booted_forced() {
+        let mut table = FakeTable::new(flags(2, 0, true), flags(0, 0, false));
+        assert_eq!(rollback(&mut table, true).unwrap(), Action::Forced);
+        assert_eq!(table.calls, vec!["force_rollback_to_inactive", "write"]);
+    }
+
+    #[test]
+    fn action_json() {
+        assert_eq!(
+            serde_json::to_value(Action::RolledBack).unwrap(),
+            serde_json::json!({"action": "rolled_back"})
+        );
+    }
+}
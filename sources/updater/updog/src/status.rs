@@ -0,0 +1,259 @@
+use crate::error::{self, Result};
+use semver::Version;
+use serde::Serialize;
+use signpost::{GptPrio, PartitionSet, State};
+use snafu::ResultExt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The directory holding the data store versions, with a `current` link to the one in use
+pub const DATASTORE_PATH: &str = "/var/lib/bottlerocket/datastore";
+
+/// What's waiting on the inactive partition set for the next boot, if anything.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pending {
+    /// The inactive set won't be booted
+    None,
+    /// An update has been written to the inactive set, but it won't be booted until it's applied
+    Written,
+    /// An update has been applied, and will be booted on the next reboot
+    Reboot,
+    /// The host will roll back to the inactive set, which has booted before, on the next reboot
+    Rollback,
+}
+
+impl Pending {
+    /// Works out what's pending from the flags of each partition set.  GRUB boots the set with
+    /// the higher priority among those it would try, so the inactive set is booted next only if
+    /// it outranks the active set.
+    pub fn from_flags(active: GptPrio, inactive: GptPrio) -> Self {
+        if inactive.will_boot() && inactive.priority() > active.priority() {
+            if inactive.successful() {
+                Pending::Rollback
+            } else {
+                Pending::Reboot
+            }
+        } else if inactive.priority() == 0 && inactive.tries_left() > 0 && !inactive.successful() {
+            Pending::Written
+        } else {
+            Pending::None
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Pending::None => "nothing pending",
+            Pending::Written => "update written to the inactive set; run update-apply to boot it",
+            Pending::Reboot => "update applied; reboot to boot it",
+            Pending::Rollback => "rollback applied; reboot to boot the previous version",
+        }
+    }
+}
+
+/// A partition set and its priority flags.
+#[derive(Debug, Serialize)]
+pub struct SetStatus {
+    boot: PathBuf,
+    root: PathBuf,
+    hash: PathBuf,
+    priority: u64,
+    tries_left: u64,
+    successful: bool,
+}
+
+impl SetStatus {
+    pub fn new(set: &PartitionSet, flags: GptPrio) -> Self {
+        Self {
+            boot: set.boot.clone(),
+            root: set.root.clone(),
+            hash: set.hash.clone(),
+            priority: flags.priority(),
+            tries_left: flags.tries_left(),
+            successful: flags.successful(),
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} (priority {}, tries left {}, {})",
+            self.root.display(),
+            self.priority,
+            self.tries_left,
+            if self.successful {
+                "booted successfully"
+            } else {
+                "not booted successfully"
+            }
+        )
+    }
+}
+
+/// What the status subcommand reports; this is what it prints with --json.
+#[derive(Debug, Serialize)]
+#[allow(clippy::module_name_repetitions)]
+pub struct HostStatus {
+    pub variant: String,
+    pub running_version: Version,
+    pub datastore_version: Version,
+    pub os_disk: PathBuf,
+    pub active: SetStatus,
+    pub inactive: SetStatus,
+    pub pending: Pending,
+}
+
+impl HostStatus {
+    /// Gathers the status of the partition sets from the partition table.
+    pub fn new(
+        state: &State,
+        running_version: Version,
+        variant: String,
+        datastore_version: Version,
+    ) -> Self {
+        Self {
+            variant,
+            running_version,
+            datastore_version,
+            os_disk: state.os_disk().to_path_buf(),
+            active: SetStatus::new(state.active_set(), state.active_flags()),
+            inactive: SetStatus::new(state.inactive_set(), state.inactive_flags()),
+            pending: Pending::from_flags(state.active_flags(), state.inactive_flags()),
+        }
+    }
+
+    /// Formats the status as a table for people.
+    pub fn table(&self) -> String {
+        let rows = [
+            ("Variant", self.variant.clone()),
+            ("Running version", self.running_version.to_string()),
+            ("Datastore version", self.datastore_version.to_string()),
+            ("OS disk", self.os_disk.display().to_string()),
+            ("Active set", self.active.summary()),
+            ("Inactive set", self.inactive.summary()),
+            ("Pending", self.pending.description().to_string()),
+        ];
+        rows.iter()
+            .map(|(name, value)| format!("{:<19}{}", format!("{}:", name), value))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Returns the version of the data store in use, following the `current` link through the
+/// major and minor version links to the patch version, the way migrator does.
+pub fn datastore_version<P: AsRef<Path>>(datastore_dir: P) -> Result<Version> {
+    let datastore_dir = datastore_dir.as_ref();
+    let mut link = datastore_dir.join("current");
+    for _ in 0..3 {
+        link = datastore_dir
+            .join(fs::read_link(&link).context(error::DatastoreLinkRead { link: &link })?);
+    }
+
+    // Links are named like v0.3.1, so strip the 'v' to get the version.
+    let name = link
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let version = name.trim_start_matches('v');
+    Version::parse(version).context(error::DatastoreVersion { path: &link })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn flags(priority: u64, tries_left: u64, successful: bool) -> GptPrio {
+        GptPrio::from((priority << 48) | (tries_left << 52) | (u64::from(successful) << 56))
+    }
+
+    #[test]
+    fn nothing_pending() {
+        // After a normal boot, the active set is the only one GRUB would try.
+        assert_eq!(
+            Pending::from_flags(flags(2, 0, true), flags(0, 0, false)),
+            Pending::None
+        );
+        // A previous version that's been booted, but outranked by the active set.
+        assert_eq!(
+            Pending::from_flags(flags(2, 0, true), flags(1, 0, true)),
+            Pending::None
+        );
+    }
+
+    #[test]
+    fn update_pending() {
+        // update-image marks the inactive set valid without giving it a priority.
+        assert_eq!(
+            Pending::from_flags(flags(2, 0, true), flags(0, 1, false)),
+            Pending::Written
+        );
+        // update-apply gives it the higher priority.
+        assert_eq!(
+            Pending::from_flags(flags(1, 0, true), flags(2, 1, false)),
+            Pending::Reboot
+        );
+    }
+
+    #[test]
+    fn rollback_pending() {
+        assert_eq!(
+            Pending::from_flags(flags(1, 0, true), flags(2, 0, true)),
+            Pending::Rollback
+        );
+    }
+
+    #[test]
+    fn set_status_reported() {
+        let set = PartitionSet {
+            boot: PathBuf::from("/dev/xvda2"),
+            root: PathBuf::from("/dev/xvda3"),
+            hash: PathBuf::from("/dev/xvda4"),
+        };
+        let status = HostStatus {
+            variant: "aws-k8s".to_string(),
+            running_version: Version::new(0, 3, 1),
+            datastore_version: Version::new(0, 3, 1),
+            os_disk: PathBuf::from("/dev/xvda"),
+            active: SetStatus::new(&set, flags(1, 0, true)),
+            inactive: SetStatus::new(&set, flags(2, 1, false)),
+            pending: Pending::Reboot,
+        };
+
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["running_version"], "0.3.1");
+        assert_eq!(json["active"]["priority"], 1);
+        assert_eq!(json["inactive"]["tries_left"], 1);
+        assert_eq!(json["inactive"]["successful"], false);
+        assert_eq!(json["pending"], "reboot");
+        assert!(status
+            .table()
+            .contains("Inactive set:      /dev/xvda3 (priority 2, tries left 1"));
+    }
+
+    #[test]
+    fn datastore_version_from_links() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("v0.3.1_0123456789abcdef")).unwrap();
+        symlink("v0.3.1_0123456789abcdef", dir.path().join("v0.3.1")).unwrap();
+        symlink("v0.3.1", dir.path().join("v0.3")).unwrap();
+        symlink("v0.3", dir.path().join("v0")).unwrap();
+        symlink("v0", dir.path().join("current")).unwrap();
+
+        assert_eq!(
+            datastore_version(dir.path()).unwrap(),
+            Version::new(0, 3, 1)
+        );
+    }
+
+    #[test]
+    fn datastore_link_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        match datastore_version(dir.path()) {
+            Err(error::Error::DatastoreLinkRead { link, .. }) => {
+                assert_eq!(link, dir.path().join("current"))
+            }
+            other => panic!("Expected missing link, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::io::{self, Read};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How much a throttled reader can read at once, in seconds' worth at its rate; a pause in reading
+/// only saves up this much, so it can't be followed by a long burst
+const BURST_SECONDS: f64 = 0.1;
+
+/// Throttled limits how fast a reader can be read, using a token bucket: tokens, each allowing
+/// one byte, are added at the given rate, and reads wait until there are enough.
+pub struct Throttled<R> {
+    inner: R,
+    /// Bytes per second
+    rate: f64,
+    /// How many bytes can be read now
+    tokens: f64,
+    /// The most tokens that can build up
+    capacity: f64,
+    refilled: Instant,
+}
+
+impl<R> Throttled<R> {
+    /// Wraps the reader so it's read no faster than the given rate, in bytes per second, which
+    /// must be positive.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(inner: R, bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second as f64;
+        Self {
+            inner,
+            rate,
+            // Start empty, so the rate holds from the first read.
+            tokens: 0.0,
+            capacity: (rate * BURST_SECONDS).max(1.0),
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Adds the tokens earned since the last refill.
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + earned).min(self.capacity);
+        self.refilled = now;
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return self.inner.read(buf);
+        }
+
+        // Wait until we can read as much as the buffer holds, or a full bucket.
+        self.refill();
+        let wanted = (buf.len() as f64).min(self.capacity);
+        if self.tokens < wanted {
+            thread::sleep(Duration::from_secs_f64((wanted - self.tokens) / self.rate));
+            self.refill();
+        }
+
+        let allowed = (self.tokens as usize).max(1).min(buf.len());
+        let count = self.inner.read(&mut buf[..allowed])?;
+        self.tokens -= count as f64;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limited() {
+        // 20 KB at 10 KB/s should take two seconds.
+        let payload: Vec<u8> = (0..=250).cycle().take(20_000).collect();
+        let mut reader = Throttled::new(&payload[..], 10_000);
+
+        let started = Instant::now();
+        let mut read = Vec::new();
+        io::copy(&mut reader, &mut read).unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(read, payload);
+        // Only the lower bound is checked; a busy test machine can make it take longer.
+        assert!(
+            elapsed >= Duration::from_millis(1_800),
+            "Took {:?}",
+            elapsed
+        );
+    }
+
+    #[test]
+    fn pause_does_not_allow_burst() {
+        // Waiting before reading only saves up a tenth of a second's worth.
+        let payload = vec![0; 10_000];
+        let mut reader = Throttled::new(&payload[..], 10_000);
+        thread::sleep(Duration::from_millis(500));
+
+        let started = Instant::now();
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(800), "Took {:?}", elapsed);
+    }
+}
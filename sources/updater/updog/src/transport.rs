@@ -1,19 +1,80 @@
+use crate::error::{self, Error};
+use crate::retry::RetryPolicy;
+use crate::throttle::Throttled;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use snafu::{ensure, ResultExt};
 use std::cell::{BorrowMutError, RefCell};
-use tough::{HttpTransport, Repository, Transport};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tough::{Repository, Transport};
 use url::Url;
 
+/// How often download progress is logged, without a progress bar
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the progress bar is redrawn
+const PROGRESS_BAR_INTERVAL: Duration = Duration::from_millis(500);
+/// The width of the progress bar, in characters
+const PROGRESS_BAR_WIDTH: u64 = 30;
+
+/// Where and how targets are downloaded.  Targets, like images and migrations, can be hundreds of
+/// megabytes, so they're downloaded to a `.partial` file first, and if the connection drops, the
+/// download continues from where it stopped with a Range request, when it's retried or in a later
+/// run.
+#[derive(Debug, Clone)]
+pub struct TargetDownloads {
+    /// Targets are fetched from URLs starting with this
+    pub base_url: String,
+    /// Partial downloads are kept in this directory
+    pub dir: PathBuf,
+    /// Whether to draw a progress bar on stderr, rather than logging progress now and then
+    pub progress_bar: bool,
+    /// The most bytes per second to download, if downloads are limited
+    pub max_rate: Option<u64>,
+}
+
+impl TargetDownloads {
+    pub fn new<S, P>(base_url: S, dir: P, progress_bar: bool) -> Self
+    where
+        S: Into<String>,
+        P: Into<PathBuf>,
+    {
+        Self {
+            base_url: base_url.into(),
+            dir: dir.into(),
+            progress_bar,
+            max_rate: None,
+        }
+    }
+
+    /// Returns the path of the partial download of the named target.
+    fn partial_path(&self, target: &str) -> PathBuf {
+        self.dir.join(format!("{}.partial", target))
+    }
+}
+
 #[derive(Debug)]
 #[allow(clippy::module_name_repetitions)]
 pub struct HttpQueryTransport {
-    pub inner: HttpTransport,
     parameters: RefCell<Vec<(String, String)>>,
+    downloads: TargetDownloads,
+    retry: RetryPolicy,
+    /// Partial downloads that have all of their target, so reading the target again doesn't ask
+    /// the server for the rest of it
+    complete: RefCell<HashSet<PathBuf>>,
 }
 
 impl HttpQueryTransport {
-    pub fn new() -> Self {
+    pub fn new(downloads: TargetDownloads, retry: RetryPolicy) -> Self {
         Self {
-            inner: HttpTransport::new(),
             parameters: RefCell::new(vec![]),
+            downloads,
+            retry,
+            complete: RefCell::new(HashSet::new()),
         }
     }
 
@@ -47,15 +108,572 @@ impl HttpQueryTransport {
 
         url
     }
+
+    /// Removes the partial download of the named target, once it's been used, or if it didn't
+    /// match its metadata, so that the next download starts over.
+    pub fn remove_partial(&self, target: &str) -> error::Result<()> {
+        let path = self.downloads.partial_path(target);
+        self.complete.borrow_mut().remove(&path);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).context(error::PartialRemove { path })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the path of the partial download for the URL, if it's a target.
+    fn target_partial_path(&self, url: &Url) -> Option<PathBuf> {
+        if self.downloads.base_url.is_empty() || !url.as_str().starts_with(&self.downloads.base_url)
+        {
+            return None;
+        }
+        let target = url.path_segments()?.last()?;
+        Some(self.downloads.partial_path(target))
+    }
+
+    /// Fetches the metadata at the URL, retrying as the retry policy says.
+    fn fetch_metadata(&self, url: &Url) -> error::Result<Response> {
+        let client = Client::builder()
+            .timeout(self.retry.timeout())
+            .build()
+            .context(error::HttpClient)?;
+        self.retry.run(url, || {
+            client
+                .get(url.clone())
+                .send()
+                .and_then(Response::error_for_status)
+                .context(error::MetadataRequest { url: url.clone() })
+        })
+    }
+
+    /// Downloads the target at the URL to the partial file at the path, continuing any earlier
+    /// download, and retrying as the retry policy says, resuming each time where the last try
+    /// stopped.  The whole download can take much longer than the policy's timeout, so that only
+    /// limits connecting.
+    fn download(&self, url: &Url, path: &Path) -> error::Result<()> {
+        let client = Client::builder()
+            .timeout(None::<Duration>)
+            .connect_timeout(self.retry.timeout())
+            .build()
+            .context(error::HttpClient)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(error::PartialOpen { path })?;
+
+        self.retry
+            .run(url, || self.download_rest(&client, url, path, &mut file))
+    }
+
+    /// Makes one request for the part of the target that isn't in the partial file yet, and
+    /// appends what it gets to the file.
+    fn download_rest(
+        &self,
+        client: &Client,
+        url: &Url,
+        path: &Path,
+        file: &mut File,
+    ) -> error::Result<()> {
+        let mut offset = file.metadata().context(error::PartialWrite { path })?.len();
+        let mut request = client.get(url.clone());
+        if offset > 0 {
+            debug!("Resuming download of {} at byte {}", url, offset);
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+        let mut response = request
+            .send()
+            .context(error::DownloadRequest { url: url.clone() })?;
+
+        match response.status() {
+            // The partial file already has all of the target; if it's wrong, it fails to verify.
+            StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => return Ok(()),
+            StatusCode::PARTIAL_CONTENT if offset > 0 => {
+                let start = content_range_start(&response);
+                if start != Some(offset) {
+                    // What was sent doesn't continue the partial file, so start over next try.
+                    file.set_len(0).context(error::PartialWrite { path })?;
+                    return error::DownloadRange {
+                        url: url.clone(),
+                        offset,
+                        start,
+                    }
+                    .fail();
+                }
+            }
+            _ => {
+                response = response
+                    .error_for_status()
+                    .context(error::DownloadStatus { url: url.clone() })?;
+                if offset > 0 {
+                    debug!("Server sent all of {}, so starting over", url);
+                    file.set_len(0).context(error::PartialWrite { path })?;
+                    offset = 0;
+                }
+            }
+        }
+
+        let name = url
+            .path_segments()
+            .and_then(Iterator::last)
+            .unwrap_or_else(|| url.as_str());
+        let total = response.content_length().map(|length| length + offset);
+        let mut body: Box<dyn Read> = match self.downloads.max_rate {
+            Some(rate) => Box::new(Throttled::new(response, rate)),
+            None => Box::new(response),
+        };
+        let mut progress = Progress::new(name, offset, total, self.downloads.progress_bar);
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let count = match body.read(&mut buf) {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    progress.finish();
+                    return Err(e).context(error::DownloadInterrupted {
+                        url: url.clone(),
+                        bytes: progress.done,
+                    });
+                }
+            };
+            file.write_all(&buf[..count])
+                .context(error::PartialWrite { path })?;
+            progress.add(count);
+        }
+        progress.finish();
+
+        // A connection that closes cleanly but early looks like the end of the target.
+        if let Some(total) = total {
+            ensure!(
+                progress.done == total,
+                error::DownloadIncomplete {
+                    url: url.clone(),
+                    bytes: progress.done,
+                    total,
+                }
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Reports how far along a download is, by drawing a progress bar on stderr, or by logging a line
+/// now and then.
+struct Progress<'a> {
+    name: &'a str,
+    done: u64,
+    total: Option<u64>,
+    /// How much was already downloaded, which doesn't count toward the rate
+    resumed_at: u64,
+    started: Instant,
+    reported: Instant,
+    bar: bool,
+}
+
+impl<'a> Progress<'a> {
+    fn new(name: &'a str, resumed_at: u64, total: Option<u64>, bar: bool) -> Self {
+        Self {
+            name,
+            done: resumed_at,
+            total,
+            resumed_at,
+            started: Instant::now(),
+            reported: Instant::now(),
+            bar,
+        }
+    }
+
+    fn add(&mut self, bytes: usize) {
+        self.done += bytes as u64;
+        let interval = if self.bar {
+            PROGRESS_BAR_INTERVAL
+        } else {
+            PROGRESS_LOG_INTERVAL
+        };
+        if self.reported.elapsed() >= interval {
+            self.report();
+            self.reported = Instant::now();
+        }
+    }
+
+    fn finish(&self) {
+        self.report();
+        if self.bar {
+            eprintln!();
+        }
+    }
+
+    fn report(&self) {
+        if self.bar {
+            eprint!("\r{}", self.line());
+        } else {
+            info!("{}", self.line());
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+    fn line(&self) -> String {
+        let seconds = self.started.elapsed().as_secs_f64();
+        let rate = if seconds > 0.0 {
+            (self.done - self.resumed_at) as f64 / seconds
+        } else {
+            0.0
+        };
+        match self.total {
+            Some(total) => {
+                let filled = self.done.min(total) * PROGRESS_BAR_WIDTH / total.max(1);
+                let bar = if self.bar {
+                    format!(
+                        "[{}{}] ",
+                        "#".repeat(filled as usize),
+                        " ".repeat((PROGRESS_BAR_WIDTH - filled) as usize)
+                    )
+                } else {
+                    String::new()
+                };
+                format!(
+                    "{}: {}{} of {}, {}/s",
+                    self.name,
+                    bar,
+                    mib(self.done as f64),
+                    mib(total as f64),
+                    mib(rate)
+                )
+            }
+            None => format!("{}: {}, {}/s", self.name, mib(self.done as f64), mib(rate)),
+        }
+    }
+}
+
+fn mib(bytes: f64) -> String {
+    format!("{:.1} MiB", bytes / (1024.0 * 1024.0))
+}
+
+/// What a fetch returns: metadata, read straight from the server, or a target, read from its
+/// finished download.
+#[derive(Debug)]
+pub enum FetchStream {
+    Http(Response),
+    File(File),
+}
+
+/// Returns the first byte of the response's Content-Range, if it has one we can read.
+fn content_range_start(response: &Response) -> Option<u64> {
+    let range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let prefix = "bytes ";
+    if !range.starts_with(prefix) {
+        return None;
+    }
+    range[prefix.len()..].split('-').next()?.parse().ok()
+}
+
+impl Read for FetchStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Http(response) => response.read(buf),
+            Self::File(file) => file.read(buf),
+        }
+    }
 }
 
 pub type HttpQueryRepo<'a> = Repository<'a, HttpQueryTransport>;
 
 impl Transport for HttpQueryTransport {
-    type Stream = reqwest::blocking::Response;
-    type Error = reqwest::Error;
+    type Stream = FetchStream;
+    type Error = Error;
+
+    fn fetch(&self, url: Url) -> error::Result<Self::Stream> {
+        let url = self.set_query_string(url);
+        match self.target_partial_path(&url) {
+            Some(path) => {
+                // Once a target is downloaded, it's read again from the partial file to be used.
+                if !self.complete.borrow().contains(&path) {
+                    self.download(&url, &path)?;
+                    self.complete.borrow_mut().insert(path.clone());
+                }
+                let file = File::open(&path).context(error::PartialOpen { path })?;
+                Ok(FetchStream::File(file))
+            }
+            None => Ok(FetchStream::Http(self.fetch_metadata(&url)?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
 
-    fn fetch(&self, url: Url) -> Result<Self::Stream, Self::Error> {
-        self.inner.fetch(self.set_query_string(url))
+    /// A local HTTP server for tests, which can fail the way real servers do.
+    struct Server {
+        body: Vec<u8>,
+        /// Status lines for the first requests, like "503 Service Unavailable"; the requests
+        /// after them get the body
+        errors: Vec<&'static str>,
+        /// If given, the first response with the body stops after this many bytes, and the
+        /// connection is closed, as if it dropped
+        cut: Option<usize>,
+        /// Whether Range requests are honored
+        ranges: bool,
+        /// Whether 206 responses claim to start at byte 0, whatever was asked for
+        bad_range: bool,
+    }
+
+    impl Server {
+        fn new(body: Vec<u8>) -> Self {
+            Self {
+                body,
+                errors: Vec::new(),
+                cut: None,
+                ranges: true,
+                bad_range: false,
+            }
+        }
+
+        /// Serves on a local port, returning the server's URL and the start of the Range of each
+        /// request it gets.
+        fn serve(self) -> (String, Arc<Mutex<Vec<usize>>>) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let seen = Arc::clone(&requests);
+            thread::spawn(move || {
+                let mut errors = self.errors.into_iter();
+                let mut cut = self.cut;
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let start = read_range(&mut stream);
+                    seen.lock().unwrap().push(start);
+                    if let Some(error) = errors.next() {
+                        let _ = write!(
+                            stream,
+                            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                            error
+                        );
+                        continue;
+                    }
+
+                    let start = if self.ranges { start } else { 0 };
+                    let rest = &self.body[start..];
+                    let status = if start > 0 {
+                        format!(
+                            "206 Partial Content\r\nContent-Range: bytes {}-{}/{}",
+                            if self.bad_range { 0 } else { start },
+                            self.body.len() - 1,
+                            self.body.len()
+                        )
+                    } else {
+                        "200 OK".to_string()
+                    };
+                    let sent = match cut.take() {
+                        Some(cut) => &rest[..cut],
+                        None => rest,
+                    };
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        status,
+                        rest.len()
+                    );
+                    let _ = stream.write_all(sent);
+                }
+            });
+            (url, requests)
+        }
+    }
+
+    /// Reads a request's headers, returning the start of its Range, or 0 if it has none.
+    fn read_range(stream: &mut TcpStream) -> usize {
+        let mut request = Vec::new();
+        let mut byte = [0; 1];
+        while !request.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+            request.push(byte[0]);
+        }
+        let prefix = "range: bytes=";
+        String::from_utf8(request)
+            .unwrap()
+            .lines()
+            .map(str::to_lowercase)
+            .find(|line| line.starts_with(prefix))
+            .map_or(0, |line| {
+                line[prefix.len()..].trim_end_matches('-').parse().unwrap()
+            })
+    }
+
+    fn body() -> Vec<u8> {
+        (0..=250).cycle().take(100_000).collect()
+    }
+
+    /// Returns a transport that downloads targets from the server at the URL, and retries up to
+    /// three times without waiting.
+    fn transport(url: &str, dir: &Path) -> HttpQueryTransport {
+        HttpQueryTransport::new(
+            TargetDownloads::new(format!("{}/targets/", url), dir, false),
+            RetryPolicy {
+                fetch_attempts: 3,
+                fetch_backoff_ms: 0,
+                ..RetryPolicy::default()
+            },
+        )
+    }
+
+    fn try_fetch(transport: &HttpQueryTransport, url: &str) -> error::Result<Vec<u8>> {
+        let mut stream = transport.fetch(Url::parse(url).unwrap())?;
+        let mut fetched = Vec::new();
+        stream.read_to_end(&mut fetched).unwrap();
+        Ok(fetched)
+    }
+
+    fn fetch(transport: &HttpQueryTransport, url: &str) -> Vec<u8> {
+        try_fetch(transport, url).unwrap()
+    }
+
+    #[test]
+    fn resumes_after_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, requests) = Server {
+            cut: Some(30_000),
+            ..Server::new(body())
+        }
+        .serve();
+        let transport = transport(&url, dir.path());
+
+        let fetched = fetch(&transport, &format!("{}/targets/image.lz4", url));
+        assert_eq!(fetched, body());
+        assert_eq!(*requests.lock().unwrap(), vec![0, 30_000]);
+
+        let partial = dir.path().join("image.lz4.partial");
+        assert!(partial.exists());
+        transport.remove_partial("image.lz4").unwrap();
+        assert!(!partial.exists());
+    }
+
+    #[test]
+    fn resumes_earlier_download() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("image.lz4.partial"), &body()[..5_000]).unwrap();
+        let (url, requests) = Server::new(body()).serve();
+        let transport = transport(&url, dir.path());
+
+        let fetched = fetch(&transport, &format!("{}/targets/image.lz4", url));
+        assert_eq!(fetched, body());
+        assert_eq!(*requests.lock().unwrap(), vec![5_000]);
+    }
+
+    #[test]
+    fn starts_over_without_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("image.lz4.partial"), b"stale").unwrap();
+        let (url, requests) = Server {
+            ranges: false,
+            ..Server::new(body())
+        }
+        .serve();
+        let transport = transport(&url, dir.path());
+
+        let fetched = fetch(&transport, &format!("{}/targets/image.lz4", url));
+        assert_eq!(fetched, body());
+        assert_eq!(*requests.lock().unwrap(), vec![5]);
+    }
+
+    #[test]
+    fn finished_download_read_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, requests) = Server {
+            ranges: false,
+            ..Server::new(body())
+        }
+        .serve();
+        let transport = transport(&url, dir.path());
+
+        let target = format!("{}/targets/image.lz4", url);
+        assert_eq!(fetch(&transport, &target), body());
+        assert_eq!(fetch(&transport, &target), body());
+        assert_eq!(*requests.lock().unwrap(), vec![0]);
+
+        // Once the partial file is removed, the target is downloaded again.
+        transport.remove_partial("image.lz4").unwrap();
+        assert_eq!(fetch(&transport, &target), body());
+        assert_eq!(*requests.lock().unwrap(), vec![0, 0]);
+    }
+
+    #[test]
+    fn starts_over_with_wrong_range() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("image.lz4.partial"), &body()[..5_000]).unwrap();
+        let (url, requests) = Server {
+            bad_range: true,
+            ..Server::new(body())
+        }
+        .serve();
+        let transport = transport(&url, dir.path());
+
+        let fetched = fetch(&transport, &format!("{}/targets/image.lz4", url));
+        assert_eq!(fetched, body());
+        assert_eq!(*requests.lock().unwrap(), vec![5_000, 0]);
+    }
+
+    #[test]
+    fn metadata_not_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, _) = Server::new(body()).serve();
+        let transport = transport(&url, dir.path());
+
+        let fetched = fetch(&transport, &format!("{}/metadata/timestamp.json", url));
+        assert_eq!(fetched, body());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn server_errors_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, requests) = Server {
+            errors: vec!["503 Service Unavailable", "502 Bad Gateway"],
+            ..Server::new(body())
+        }
+        .serve();
+        let transport = transport(&url, dir.path());
+
+        let fetched = fetch(&transport, &format!("{}/metadata/timestamp.json", url));
+        assert_eq!(fetched, body());
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn retries_exhausted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, requests) = Server {
+            errors: vec!["503 Service Unavailable"; 5],
+            ..Server::new(body())
+        }
+        .serve();
+        let transport = transport(&url, dir.path());
+
+        match try_fetch(&transport, &format!("{}/targets/image.lz4", url)) {
+            Err(Error::RetriesExhausted { attempts, .. }) => assert_eq!(attempts, 3),
+            other => panic!("Expected RetriesExhausted, got {:?}", other),
+        }
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn client_errors_not_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let (url, requests) = Server {
+            errors: vec!["404 Not Found"],
+            ..Server::new(body())
+        }
+        .serve();
+        let transport = transport(&url, dir.path());
+
+        match try_fetch(&transport, &format!("{}/metadata/2.root.json", url)) {
+            Err(Error::MetadataRequest { .. }) => {}
+            other => panic!("Expected MetadataRequest error, got {:?}", other),
+        }
+        assert_eq!(requests.lock().unwrap().len(), 1);
     }
 }